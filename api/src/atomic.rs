@@ -0,0 +1,131 @@
+// api/src/atomic.rs
+
+//! `?atomic=true` on `/upload`: the files of the request are kept and processed together or not
+//! at all. Each file is checked and stored as usual, but its message is held back until the last
+//! file is stored, and only then are the jobs queued. If a file fails, for any reason an upload
+//! would refuse it, the files stored before it are deleted again, their bytes given back to the
+//! tenant's storage quota, and the answer has the failing file's status with a report of every
+//! file read, see [`RolledBackUpload`]. Should queueing fail partway, the jobs already queued are
+//! cancelled as `DELETE /jobs/{name}` would, and the upload is rolled back all the same. A file whose blob already exists fails the upload with 409,
+//! since deleting it again couldn't bring back what it replaced.
+
+use image_resize_core::{
+    failover::Location,
+    job_status,
+    message::ImageMessage,
+    models::{FileOutcome, FileReport, RolledBackUpload},
+    storage, telemetry,
+};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+use warp::{http::StatusCode, reply::Response, Reply};
+
+use crate::{container_client_at, count_upload, error::ApiError, queue_upload, quota, tenant::Tenant, UploadPlan};
+
+/// An original stored by the upload.
+struct Stored {
+    container: String,
+    blob: String,
+    location: Location,
+    bytes: u64,
+}
+
+/// What an atomic upload has done so far, to be queued or undone.
+#[derive(Default)]
+pub struct Transaction {
+    /// Files taken so far, in order, repeats of a stored file among them.
+    files: Vec<String>,
+    stored: Vec<Stored>,
+    /// Messages held back until every file is stored, with the job id answered for each.
+    pending: Vec<(ImageMessage, Uuid)>,
+}
+
+impl Transaction {
+    /// Records a file stored as `blob`, whose message is held back.
+    pub fn stored(&mut self, filename: String, image: ImageMessage, job_id: Uuid, location: Location, bytes: u64) {
+        self.stored.push(Stored {
+            container: image.image_container.clone(),
+            blob: image.filename.clone(),
+            location,
+            bytes,
+        });
+        self.pending.push((image, job_id));
+        self.files.push(filename);
+    }
+
+    /// Records a file repeating one stored before it, which shares its blob.
+    pub fn repeated(&mut self, filename: String) {
+        self.files.push(filename);
+    }
+
+    /// Queues the held-back messages now that every file is stored. If one can't be sent, the jobs
+    /// queued before it are cancelled and the upload rolled back, whose answer is returned.
+    pub async fn commit(self, plan: &UploadPlan, tenant: Option<&Tenant>) -> Result<(), Response> {
+        for (index, (image, job_id)) in self.pending.iter().enumerate() {
+            if let Err(e) = queue_upload(plan, image.clone(), *job_id).await {
+                error!("Error enqueueing {} of an atomic upload: {:?}", image.filename, e);
+                for (queued, _) in &self.pending[..index] {
+                    if let Err(e) = job_status::cancel(&queued.image_container, &queued.filename).await {
+                        warn!("Failed to cancel the job of {}: {:?}", queued.filename, e);
+                    }
+                }
+                let error = ApiError::new(StatusCode::BAD_GATEWAY, "Failed to queue the image for processing");
+                return Err(self.roll_back(tenant, None, &error).await);
+            }
+        }
+        for ((image, _), stored) in self.pending.iter().zip(&self.stored) {
+            telemetry::track_event("ImageUploaded", &[("filename", image.filename.clone())]);
+            count_upload("upload", stored.bytes);
+        }
+        Ok(())
+    }
+
+    /// Deletes what was stored and answers with `error`, which `failed` was refused with, if a file
+    /// was being read when it came up.
+    pub async fn roll_back(self, tenant: Option<&Tenant>, failed: Option<String>, error: &ApiError) -> Response {
+        let mut refund = 0;
+        for stored in &self.stored {
+            let backend = storage::from_env(&container_client_at(&stored.container, stored.location));
+            match backend.delete(&stored.blob).await {
+                Ok(()) => refund += stored.bytes,
+                Err(e) => warn!("Failed to roll back {} in {}: {:?}", stored.blob, stored.container, e),
+            }
+        }
+        quota::refund(tenant, refund).await;
+        info!("Rolled back an atomic upload of {} files: {}", self.files.len(), error.message);
+        telemetry::track_event(
+            "UploadRolledBack",
+            &[("files", self.files.len().to_string()), ("error", error.message.clone())],
+        );
+
+        let mut files: Vec<FileReport> = self
+            .files
+            .into_iter()
+            .map(|filename| FileReport {
+                filename,
+                outcome: FileOutcome::RolledBack,
+                error: None,
+            })
+            .collect();
+        if let Some(filename) = failed {
+            files.push(FileReport {
+                filename,
+                outcome: FileOutcome::Failed,
+                error: Some(error.message.clone()),
+            });
+        }
+        let report = RolledBackUpload {
+            error: error.message.clone(),
+            files,
+        };
+        warp::reply::with_status(warp::reply::json(&report), error.code).into_response()
+    }
+}
+
+/// The rejection of a file whose blob already exists.
+pub fn exists(filename: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::CONFLICT,
+        format!("'{}' already exists, and an atomic upload can't replace it", filename),
+    )
+}
@@ -0,0 +1,239 @@
+// api/src/backfill.rs
+
+//! `POST /admin/backfill`: finds the originals that lack the rendition of a preset and enqueues
+//! only those, e.g. after a new template or publish target is introduced. Renditions are matched
+//! by the names the worker gives them: `resized_<name>` for `resize`, `<template>_<name>` for
+//! `render:<template>`, and `resized_<name>` in the target container for `publish:<container>`.
+
+use azure_storage_blobs::prelude::ContainerClient;
+use futures::StreamExt;
+use image_resize_core::{failover::Location, image_checks, naming, pipeline};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::Arc,
+};
+use uuid::Uuid;
+use warp::{http::StatusCode, Rejection, Reply};
+use tracing::{error, info};
+
+use crate::{
+    container_client, container_client_for,
+    error::ApiError,
+    metadata::{self, TENANT_KEY},
+    notify::Notifier,
+    paging::PageQuery,
+    progress::{self, ProgressRegistry, ProgressState},
+    send_message_to_queue, ImageMessage, Stage, DEFAULT_SIZE,
+};
+
+const TEMPLATE_PREFIX: &str = "templates/";
+
+#[derive(Deserialize, Debug)]
+pub struct BackfillRequest {
+    /// `resize`, `render:<template>` or `publish:<container>`.
+    preset: String,
+    /// Only originals whose name starts with this prefix are considered.
+    #[serde(default)]
+    prefix: String,
+    /// Size of resized renditions that have to be produced.
+    width: Option<u32>,
+    height: Option<u32>,
+    /// Extra addresses emailed when the operation finishes or starts failing.
+    #[serde(default)]
+    notify: Vec<String>,
+}
+
+/// An original as found in the listing, with what its queue message needs.
+struct Original {
+    name: String,
+    tenant: Option<String>,
+    tags: Vec<String>,
+    metadata: BTreeMap<String, String>,
+}
+
+fn storage_error(e: &azure_core::Error) -> Rejection {
+    warp::reject::custom(ApiError::storage(e, "Failed to reach blob storage"))
+}
+
+pub async fn start_backfill(request: BackfillRequest, registry: ProgressRegistry, notifier: Arc<Notifier>) -> Result<impl Reply, Rejection> {
+    let preset: Stage = request
+        .preset
+        .parse()
+        .map_err(|e| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, e)))?;
+    if request.width == Some(0) || request.height == Some(0) {
+        return Err(warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, "Width and height must be positive")));
+    }
+    image_checks::check_output(request.width.unwrap_or(DEFAULT_SIZE), request.height.unwrap_or(DEFAULT_SIZE), None)
+        .map_err(|e| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, e)))?;
+    let container_client = container_client();
+
+    if let Stage::Render { template } = &preset {
+        let template_blob = format!("{}{}.json", TEMPLATE_PREFIX, template);
+        match container_client.blob_client(&template_blob).exists().await {
+            Ok(true) => {}
+            Ok(false) => {
+                return Err(warp::reject::custom(ApiError::new(
+                    StatusCode::NOT_FOUND,
+                    format!("Unknown template: {}", template),
+                )))
+            }
+            Err(e) => {
+                error!("Error checking template {}: {:?}", template_blob, e);
+                return Err(storage_error(&e));
+            }
+        }
+    }
+
+    let id = registry.start("backfill");
+    info!("Starting backfill {} of {} for {}*", id, request.preset, request.prefix);
+
+    tokio::spawn(run_backfill(id, container_client, preset, request, registry, notifier));
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "id": id })),
+        StatusCode::ACCEPTED,
+    ))
+}
+
+pub async fn backfill_status(id: Uuid, page: PageQuery, registry: ProgressRegistry) -> Result<impl Reply, Rejection> {
+    match registry.get(&id) {
+        Some(progress) => progress::status_reply(progress, page),
+        None => Err(warp::reject::custom(ApiError::new(StatusCode::NOT_FOUND, "Unknown backfill id"))),
+    }
+}
+
+/// Every blob name in the container, plus the originals among them, told apart from worker
+/// output by the version metadata the worker stamps on everything it writes.
+async fn list_container(container_client: &ContainerClient, prefix: &str) -> azure_core::Result<(HashSet<String>, Vec<Original>)> {
+    let mut names = HashSet::new();
+    let mut originals = Vec::new();
+    let mut pages = container_client.list_blobs().include_metadata(true).into_stream();
+    while let Some(page) = pages.next().await {
+        for blob in page?.blobs.blobs() {
+            names.insert(blob.name.clone());
+            let blob_metadata = blob.metadata.clone().unwrap_or_default();
+            if !blob.name.starts_with(prefix)
+                || blob.name.starts_with(TEMPLATE_PREFIX)
+                || blob.name.starts_with(pipeline::PRESETS_PREFIX)
+                || blob_metadata.contains_key("worker_version")
+            {
+                continue;
+            }
+            let (tags, metadata) = metadata::user_metadata(&blob_metadata);
+            originals.push(Original {
+                name: blob.name.clone(),
+                tenant: blob_metadata.get(TENANT_KEY).cloned(),
+                tags,
+                metadata,
+            });
+        }
+    }
+    Ok((names, originals))
+}
+
+async fn list_names(container_client: &ContainerClient) -> azure_core::Result<HashSet<String>> {
+    let mut names = HashSet::new();
+    let mut pages = container_client.list_blobs().into_stream();
+    while let Some(page) = pages.next().await {
+        names.extend(page?.blobs.blobs().map(|blob| blob.name.clone()));
+    }
+    Ok(names)
+}
+
+/// The stages that produce `original`'s rendition of `preset`, or nothing if it already exists.
+/// Publishing copies the resized rendition, so that is produced first when it is missing too.
+fn missing_stages(preset: &Stage, original: &str, names: &HashSet<String>, published: &HashSet<String>) -> Vec<Stage> {
+    let resized = naming::resized(original);
+    match preset {
+        Stage::Resize if !names.contains(&resized) => vec![Stage::Resize],
+        Stage::Render { template } if !names.contains(&format!("{}_{}", template, original)) => vec![preset.clone()],
+        Stage::Pages { .. } if !names.contains(&format!("pages_{}.json", original)) => vec![preset.clone()],
+        Stage::Publish { .. } if !published.contains(&resized) => {
+            if names.contains(&resized) {
+                vec![preset.clone()]
+            } else {
+                vec![Stage::Resize, preset.clone()]
+            }
+        }
+        _ => Vec::new(),
+    }
+}
+
+async fn run_backfill(id: Uuid, container_client: ContainerClient, preset: Stage, request: BackfillRequest, registry: ProgressRegistry, notifier: Arc<Notifier>) {
+    let listing = async {
+        let (names, originals) = list_container(&container_client, &request.prefix).await?;
+        let published = match &preset {
+            Stage::Publish { container } => list_names(&container_client_for(container)).await?,
+            _ => HashSet::new(),
+        };
+        Ok::<_, azure_core::Error>((names, originals, published))
+    };
+    let (names, originals, published) = match listing.await {
+        Ok(listing) => listing,
+        Err(e) => {
+            error!("Backfill {} failed to list blobs: {:?}", id, e);
+            registry.record_error(&id, format!("Listing blobs failed: {}", e));
+            registry.finish(&id, ProgressState::Failed);
+            if let Some(progress) = registry.get(&id) {
+                notifier.operation_finished(&progress, &request.notify).await;
+            }
+            return;
+        }
+    };
+
+    let missing: Vec<(Original, Vec<Stage>)> = originals
+        .into_iter()
+        .map(|original| {
+            let stages = missing_stages(&preset, &original.name, &names, &published);
+            (original, stages)
+        })
+        .filter(|(_, stages)| !stages.is_empty())
+        .collect();
+    info!("Backfill {} found {} originals missing their {} rendition", id, missing.len(), request.preset);
+    registry.add_discovered(&id, missing.len());
+
+    for (original, mut stages) in missing {
+        let image = ImageMessage {
+            filename: original.name.clone(),
+            image_container: container_client.container_name().to_string(),
+            auto_enhance: false,
+            stage: stages.remove(0),
+            then: stages,
+            width: request.width.unwrap_or(DEFAULT_SIZE),
+            height: request.height.unwrap_or(DEFAULT_SIZE),
+            tenant: original.tenant,
+            tags: original.tags,
+            metadata: original.metadata,
+            target_size: None,
+            resize: None,
+            fit: None,
+            filter: None,
+            variants: Vec::new(),
+            output_format: None,
+            crop: None,
+            focal_point: None,
+            storage: Location::Primary,
+            queued_at: None,
+            job_id: None,
+            ..Default::default()
+        };
+
+        match send_message_to_queue(image).await {
+            Ok(_) => registry.record_success(&id, &original.name),
+            Err(e) => {
+                error!("Backfill {} failed to enqueue {}: {:?}", id, original.name, e);
+                registry.record_failure(&id, &original.name, e.to_string());
+                if let Some(progress) = registry.get(&id) {
+                    notifier.check_failure_rate(&progress, &request.notify).await;
+                }
+            }
+        }
+    }
+
+    info!("Backfill {} finished", id);
+    registry.finish(&id, ProgressState::Completed);
+    if let Some(progress) = registry.get(&id) {
+        notifier.operation_finished(&progress, &request.notify).await;
+    }
+}
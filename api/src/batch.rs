@@ -0,0 +1,152 @@
+// api/src/batch.rs
+
+use azure_storage_blobs::prelude::ContainerClient;
+use futures::StreamExt;
+use image_resize_core::failover::Location;
+use serde::Deserialize;
+use std::{collections::BTreeMap, sync::Arc};
+use uuid::Uuid;
+use warp::{http::StatusCode, Rejection, Reply};
+use tracing::{error, info};
+
+use crate::{
+    container_client,
+    error::ApiError,
+    notify::Notifier,
+    paging::PageQuery,
+    progress::{self, ProgressRegistry, ProgressState},
+    reconcile, send_message_to_queue,
+    tenant::Tenant,
+    ImageMessage, Stage, DEFAULT_SIZE,
+};
+
+/// Templates are stored as `templates/<name>.json` in the source container.
+const TEMPLATE_PREFIX: &str = "templates/";
+
+#[derive(Deserialize, Debug)]
+pub struct TemplateBatchRequest {
+    template: String,
+    /// Only blobs whose name starts with this prefix are rendered.
+    #[serde(default)]
+    prefix: String,
+    /// Extra addresses emailed when the operation finishes or starts failing.
+    #[serde(default)]
+    notify: Vec<String>,
+}
+
+pub async fn start_template_batch(request: TemplateBatchRequest, registry: ProgressRegistry, notifier: Arc<Notifier>) -> Result<impl Reply, Rejection> {
+    let container_client = container_client();
+
+    let template_blob = format!("{}{}.json", TEMPLATE_PREFIX, request.template);
+    match container_client.blob_client(&template_blob).exists().await {
+        Ok(true) => {}
+        Ok(false) => {
+            return Err(warp::reject::custom(ApiError::new(
+                StatusCode::NOT_FOUND,
+                format!("Unknown template: {}", request.template),
+            )))
+        }
+        Err(e) => {
+            error!("Error checking template {}: {:?}", template_blob, e);
+            return Err(warp::reject::custom(ApiError::storage(&e, "Failed to reach blob storage")));
+        }
+    }
+
+    let id = registry.start("template_batch");
+    info!("Starting template batch {} for {}* with template {}", id, request.prefix, request.template);
+
+    tokio::spawn(run_template_batch(id, container_client, request, registry, notifier));
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "id": id })),
+        StatusCode::ACCEPTED,
+    ))
+}
+
+/// `GET /batch/{id}`: a template batch, or a ZIP upload shown only to the tenant that sent it.
+pub async fn batch_status(id: Uuid, tenant: Option<Tenant>, page: PageQuery, registry: ProgressRegistry) -> Result<impl Reply, Rejection> {
+    let tenant_id = tenant.map(|tenant| tenant.id);
+    match registry.get(&id) {
+        Some(progress) if progress.owner.is_none() || progress.owner == tenant_id => progress::status_reply(progress, page),
+        _ => Err(warp::reject::custom(ApiError::new(StatusCode::NOT_FOUND, "Unknown batch id"))),
+    }
+}
+
+async fn run_template_batch(id: Uuid, container_client: ContainerClient, request: TemplateBatchRequest, registry: ProgressRegistry, notifier: Arc<Notifier>) {
+    // skip the templates themselves, presets and anything the worker wrote, this template's renders among it
+    let rendered_prefix = format!("{}_", request.template);
+
+    let mut pages = container_client
+        .list_blobs()
+        .prefix(request.prefix.clone())
+        .include_metadata(true)
+        .into_stream();
+    while let Some(page) = pages.next().await {
+        let page = match page {
+            Ok(page) => page,
+            Err(e) => {
+                error!("Template batch {} failed to list blobs: {:?}", id, e);
+                registry.record_error(&id, format!("Listing blobs failed: {}", e));
+                registry.finish(&id, ProgressState::Failed);
+                if let Some(progress) = registry.get(&id) {
+                    notifier.operation_finished(&progress, &request.notify).await;
+                }
+                return;
+            }
+        };
+
+        let names: Vec<String> = page
+            .blobs
+            .blobs()
+            .filter(|blob| reconcile::is_original(blob) && !blob.name.starts_with(&rendered_prefix))
+            .map(|blob| blob.name.clone())
+            .collect();
+        registry.add_discovered(&id, names.len());
+
+        for name in names {
+            let image = ImageMessage {
+                filename: name.clone(),
+                image_container: container_client.container_name().to_string(),
+                auto_enhance: false,
+                stage: Stage::Render {
+                    template: request.template.clone(),
+                },
+                then: Vec::new(),
+                width: DEFAULT_SIZE,
+                height: DEFAULT_SIZE,
+                tenant: None,
+                tags: Vec::new(),
+                metadata: BTreeMap::new(),
+                target_size: None,
+                resize: None,
+                fit: None,
+                filter: None,
+                variants: Vec::new(),
+                output_format: None,
+                crop: None,
+                focal_point: None,
+                storage: Location::Primary,
+                queued_at: None,
+                job_id: None,
+                ..Default::default()
+            };
+
+            match send_message_to_queue(image).await {
+                Ok(job_id) => registry.record_pending(&id, &name, job_id),
+                Err(e) => {
+                    error!("Template batch {} failed to enqueue {}: {:?}", id, name, e);
+                    registry.record_failure(&id, &name, e.to_string());
+                    if let Some(progress) = registry.get(&id) {
+                        notifier.check_failure_rate(&progress, &request.notify).await;
+                    }
+                }
+            }
+        }
+    }
+
+    info!("Template batch {} finished", id);
+    registry.finish(&id, ProgressState::Completed);
+    if let Some(progress) = registry.get(&id) {
+        notifier.operation_finished(&progress, &request.notify).await;
+    }
+}
@@ -0,0 +1,178 @@
+// api/src/compare.rs
+
+use image::{imageops::FilterType, DynamicImage, GenericImageView, ImageFormat};
+use serde::{Deserialize, Serialize};
+use warp::{http::StatusCode, Rejection, Reply};
+use tracing::error;
+
+use crate::{container_client_holding, error::ApiError, read_blob};
+
+/// Images larger than this are not compared synchronously on the request path.
+const MAX_COMPARE_BYTES: u64 = 8 * 1024 * 1024;
+
+#[derive(Deserialize, Debug)]
+pub struct CompareRequest {
+    a: String,
+    b: String,
+}
+
+#[derive(Serialize, Debug)]
+struct ImageSummary {
+    blob: String,
+    bytes: usize,
+    format: Option<String>,
+    width: u32,
+    height: u32,
+}
+
+#[derive(Serialize, Debug)]
+struct CompareReport {
+    a: ImageSummary,
+    b: ImageSummary,
+    same_dimensions: bool,
+    same_format: bool,
+    /// Mean structural similarity of the luma channels, 1.0 means identical.
+    ssim: f64,
+    /// Peak signal-to-noise ratio over RGB in dB, `None` when the images are identical.
+    psnr: Option<f64>,
+    /// True when `b` had to be scaled to `a`'s dimensions before computing the metrics.
+    b_rescaled: bool,
+}
+
+pub async fn compare_images(request: CompareRequest) -> Result<impl Reply, Rejection> {
+    let mut sources = Vec::with_capacity(2);
+    for name in [&request.a, &request.b] {
+        let blob_client = container_client_holding(name).await.blob_client(name);
+        let properties = blob_client.get_properties().await.map_err(|e| {
+            error!("Error reading properties of {}: {:?}", name, e);
+            warp::reject::custom(ApiError::new(StatusCode::NOT_FOUND, format!("Blob not found: {}", name)))
+        })?;
+        if properties.blob.properties.content_length > MAX_COMPARE_BYTES {
+            return Err(warp::reject::custom(ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("{} is too large to compare synchronously", name),
+            )));
+        }
+
+        let bytes = read_blob(&blob_client).await.map_err(|e| {
+            error!("Error downloading {}: {:?}", name, e);
+            warp::reject::custom(ApiError::storage(&e, "Failed to download blob"))
+        })?;
+        sources.push((name.clone(), bytes));
+    }
+    let (b_name, b_bytes) = sources.pop().unwrap();
+    let (a_name, a_bytes) = sources.pop().unwrap();
+
+    // decoding and the metrics are CPU bound, keep them off the reactor threads
+    let report = tokio::task::spawn_blocking(move || build_report(a_name, a_bytes, b_name, b_bytes))
+        .await
+        .map_err(|_| warp::reject::reject())?
+        .map_err(|e| warp::reject::custom(ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, e)))?;
+
+    Ok(warp::reply::json(&report))
+}
+
+fn decode(name: &str, bytes: &[u8]) -> Result<(DynamicImage, Option<ImageFormat>), String> {
+    let format = image::guess_format(bytes).ok();
+    let img = image::load_from_memory(bytes).map_err(|e| format!("Failed to decode {}: {}", name, e))?;
+    Ok((img, format))
+}
+
+fn build_report(a_name: String, a_bytes: Vec<u8>, b_name: String, b_bytes: Vec<u8>) -> Result<CompareReport, String> {
+    let (a_img, a_format) = decode(&a_name, &a_bytes)?;
+    let (b_img, b_format) = decode(&b_name, &b_bytes)?;
+
+    let summary = |blob: String, bytes: usize, format: Option<ImageFormat>, img: &DynamicImage| ImageSummary {
+        blob,
+        bytes,
+        format: format.map(|f| format!("{:?}", f).to_lowercase()),
+        width: img.width(),
+        height: img.height(),
+    };
+    let a = summary(a_name, a_bytes.len(), a_format, &a_img);
+    let b = summary(b_name, b_bytes.len(), b_format, &b_img);
+
+    let same_dimensions = a_img.dimensions() == b_img.dimensions();
+    let b_img = if same_dimensions {
+        b_img
+    } else {
+        b_img.resize_exact(a_img.width(), a_img.height(), FilterType::Triangle)
+    };
+
+    Ok(CompareReport {
+        same_format: a_format == b_format,
+        ssim: ssim(&a_img, &b_img),
+        psnr: psnr(&a_img, &b_img),
+        b_rescaled: !same_dimensions,
+        same_dimensions,
+        a,
+        b,
+    })
+}
+
+/// SSIM over 8x8 windows with a stride of 4, on the luma channel.
+fn ssim(a: &DynamicImage, b: &DynamicImage) -> f64 {
+    const WINDOW: u32 = 8;
+    const STRIDE: u32 = 4;
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let a = a.to_luma8();
+    let b = b.to_luma8();
+    let (width, height) = a.dimensions();
+    if width < WINDOW || height < WINDOW {
+        return if a == b { 1.0 } else { 0.0 };
+    }
+
+    let n = (WINDOW * WINDOW) as f64;
+    let mut total = 0.0;
+    let mut windows = 0;
+    for y in (0..=height - WINDOW).step_by(STRIDE as usize) {
+        for x in (0..=width - WINDOW).step_by(STRIDE as usize) {
+            let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+            for dy in 0..WINDOW {
+                for dx in 0..WINDOW {
+                    let pa = a.get_pixel(x + dx, y + dy)[0] as f64;
+                    let pb = b.get_pixel(x + dx, y + dy)[0] as f64;
+                    sum_a += pa;
+                    sum_b += pb;
+                    sum_aa += pa * pa;
+                    sum_bb += pb * pb;
+                    sum_ab += pa * pb;
+                }
+            }
+            let mean_a = sum_a / n;
+            let mean_b = sum_b / n;
+            let var_a = sum_aa / n - mean_a * mean_a;
+            let var_b = sum_bb / n - mean_b * mean_b;
+            let covariance = sum_ab / n - mean_a * mean_b;
+
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            windows += 1;
+        }
+    }
+
+    total / windows as f64
+}
+
+fn psnr(a: &DynamicImage, b: &DynamicImage) -> Option<f64> {
+    let a = a.to_rgb8();
+    let b = b.to_rgb8();
+
+    let squared_error: f64 = a
+        .as_raw()
+        .iter()
+        .zip(b.as_raw())
+        .map(|(&pa, &pb)| {
+            let d = pa as f64 - pb as f64;
+            d * d
+        })
+        .sum();
+    let mse = squared_error / a.as_raw().len() as f64;
+    if mse == 0.0 {
+        return None;
+    }
+
+    Some(10.0 * (255.0 * 255.0 / mse).log10())
+}
@@ -0,0 +1,60 @@
+// api/src/config_check.rs
+
+//! The API's `--check-config`, see `core/src/config_check.rs`. Besides the shared settings,
+//! accounts and queues, it reads the settings the routes are built from and checks the public
+//! access of the containers it writes to, the way startup does (see `container_access.rs`).
+
+use image_resize_core::{
+    config_check::{self, ConfigReport},
+    variants,
+};
+use std::sync::Arc;
+
+use crate::{auth, container_access, content, i18n, ip_filter, limit, notify, retention, s3, tenant, timeout, upload_token};
+
+pub async fn run(args: &[String]) -> ! {
+    let mut report = ConfigReport::new();
+    if config_check::check_shared(&mut report).await {
+        report.setting("notifications", || {
+            notify::Notifier::from_env();
+        });
+        report.setting("limits", || {
+            timeout::request_timeout();
+            limit::RuntimeLimits::from_env();
+        });
+        report.setting("ip filter", || {
+            ip_filter::IpPolicy::from_env();
+        });
+        let mut tenants = None;
+        report.setting("tenants", || tenants = Some(Arc::new(tenant::TenantStore::from_env())));
+        if let Some(tenants) = tenants {
+            report.setting("authentication", || {
+                auth::Authenticator::from_env(tenants, limit::RuntimeLimits::from_env());
+            });
+        }
+        report.setting("upload tokens", || {
+            upload_token::TokenIssuer::from_env();
+        });
+        report.setting("s3", || {
+            s3::S3Config::from_env();
+        });
+        report.setting("resize variants", || {
+            variants::from_env();
+        });
+        report.setting("retention", || {
+            retention::RetentionPolicy::from_env();
+        });
+        report.setting("error language", || {
+            i18n::default_language();
+        });
+        report.setting("image delivery", || {
+            content::Delivery::from_env();
+        });
+        let mut access_policy = None;
+        report.setting("container access", || access_policy = Some(container_access::AccessPolicy::from_env()));
+        if let Some(access_policy) = access_policy {
+            access_policy.check_config(&mut report).await;
+        }
+    }
+    report.exit(args)
+}
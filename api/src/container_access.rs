@@ -0,0 +1,214 @@
+// api/src/container_access.rs
+
+//! Public-access checks of the containers the API writes to. Containers holding originals, the
+//! source container (`AZURE_STORAGE_CONTAINER`), `UPLOAD_TOKEN_CONTAINERS` and `S3_BUCKETS`, must
+//! be private. Containers renditions are published to may allow anonymous reads of their blobs,
+//! but never listing, when named in `PUBLIC_CONTAINERS`. The failover account is checked too when
+//! configured.
+//!
+//! The API refuses to start while a container breaks this policy, unless `CONTAINER_ACCESS_CHECK`
+//! is `warn` (log it and start anyway) or `off`. `GET /admin/containers/access` reports each
+//! container's access and `POST /admin/containers/access/enforce` tightens the ones out of policy.
+//! Tightening rewrites the container's ACL, which drops its stored access policies.
+
+use azure_storage_blobs::prelude::PublicAccess;
+use image_resize_core::{
+    config,
+    config_check::ConfigReport,
+    failover::{self, Location},
+    health::Check,
+};
+use serde::Serialize;
+use std::{env, sync::Arc};
+use warp::{Rejection, Reply};
+use tracing::{error, info, warn};
+
+use crate::{container_client_at, error::ApiError};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CheckMode {
+    Enforce,
+    Warn,
+    Off,
+}
+
+pub struct AccessPolicy {
+    private: Vec<String>,
+    public: Vec<String>,
+    mode: CheckMode,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ContainerAccess {
+    container: String,
+    location: Location,
+    /// `none`, `blob` or `container`, as storage reports it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    access: Option<&'static str>,
+    /// Most open access the policy allows.
+    allowed: &'static str,
+    compliant: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+fn env_list(key: &str) -> Vec<String> {
+    env::var(key)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+impl AccessPolicy {
+    pub fn from_env() -> Self {
+        let mut private = vec![config::get().container().to_string()];
+        private.extend(env_list("UPLOAD_TOKEN_CONTAINERS"));
+        private.extend(env_list("S3_BUCKETS"));
+        private.sort();
+        private.dedup();
+        let public = env_list("PUBLIC_CONTAINERS");
+        if let Some(both) = public.iter().find(|container| private.contains(container)) {
+            panic!("Container '{}' is in PUBLIC_CONTAINERS but holds originals, which must stay private", both);
+        }
+        let mode = match env::var("CONTAINER_ACCESS_CHECK").as_deref() {
+            Err(_) | Ok("enforce") => CheckMode::Enforce,
+            Ok("warn") => CheckMode::Warn,
+            Ok("off") => CheckMode::Off,
+            Ok(other) => panic!("Unknown CONTAINER_ACCESS_CHECK '{}', use enforce, warn or off", other),
+        };
+        AccessPolicy { private, public, mode }
+    }
+
+    /// Each container with the most open access the policy allows it.
+    fn expected(&self) -> impl Iterator<Item = (&String, PublicAccess)> {
+        let private = self.private.iter().map(|container| (container, PublicAccess::None));
+        let public = self.public.iter().map(|container| (container, PublicAccess::Blob));
+        private.chain(public)
+    }
+
+    /// The access of every container the policy covers, in every account.
+    pub async fn audit(&self) -> Vec<ContainerAccess> {
+        let mut report = Vec::new();
+        for location in failover::locations() {
+            for (container, allowed) in self.expected() {
+                let properties = container_client_at(container, location).get_properties().await;
+                let (access, error) = match properties {
+                    Ok(properties) => (Some(properties.container.public_access), None),
+                    Err(e) => (None, Some(e.to_string())),
+                };
+                report.push(ContainerAccess {
+                    container: container.clone(),
+                    location,
+                    access: access.map(<&str>::from),
+                    allowed: allowed.into(),
+                    compliant: access.is_some_and(|access| is_within(access, allowed)),
+                    error,
+                });
+            }
+        }
+        report
+    }
+
+    /// Refuses to go on while a container is more open than the policy allows; containers that
+    /// can't be read are only logged, since a missing publish target is created on first use.
+    pub async fn check_at_startup(&self) {
+        if self.mode == CheckMode::Off {
+            return;
+        }
+        let mut violations = Vec::new();
+        for entry in self.audit().await {
+            match (&entry.error, entry.compliant) {
+                (Some(e), _) => warn!("Can't check the public access of container {}: {}", entry.container, e),
+                (None, false) => violations.push(format!(
+                    "{} ({:?}) allows {} access, at most {} is allowed",
+                    entry.container,
+                    entry.location,
+                    entry.access.unwrap_or_default(),
+                    entry.allowed
+                )),
+                (None, true) => {}
+            }
+        }
+        if violations.is_empty() {
+            info!("Container public access is within policy");
+        } else if self.mode == CheckMode::Warn {
+            warn!("Container public access breaks policy: {}", violations.join("; "));
+        } else {
+            panic!(
+                "Container public access breaks policy: {}. Fix it with POST /admin/containers/access/enforce \
+                 after starting with CONTAINER_ACCESS_CHECK=warn",
+                violations.join("; ")
+            );
+        }
+    }
+
+    /// Adds a check of each container to `report`, failing it when the container can't be read, or
+    /// breaks the policy and the API would refuse to start over it.
+    pub async fn check_config(&self, report: &mut ConfigReport) {
+        if self.mode == CheckMode::Off {
+            return;
+        }
+        for entry in self.audit().await {
+            let name = format!("container {} ({:?})", entry.container, entry.location);
+            let check = match entry.error {
+                Some(e) => Check::failed(e),
+                None if !entry.compliant && self.mode == CheckMode::Enforce => Check::failed(format!(
+                    "Allows {} access, at most {} is allowed",
+                    entry.access.unwrap_or_default(),
+                    entry.allowed
+                )),
+                None => Check {
+                    ok: true,
+                    error: None,
+                    duration_ms: 0,
+                },
+            };
+            report.add(name, check);
+        }
+    }
+
+    /// Tightens every readable container that's more open than allowed to what the policy allows.
+    async fn enforce(&self) -> azure_core::Result<Vec<String>> {
+        let mut changed = Vec::new();
+        for location in failover::locations() {
+            for (container, allowed) in self.expected() {
+                let container_client = container_client_at(container, location);
+                let Ok(properties) = container_client.get_properties().await else {
+                    continue;
+                };
+                if is_within(properties.container.public_access, allowed) {
+                    continue;
+                }
+                container_client.set_acl(allowed).await?;
+                info!("Set public access of container {} ({:?}) to {:?}", container, location, allowed);
+                changed.push(container.clone());
+            }
+        }
+        Ok(changed)
+    }
+}
+
+/// Whether `access` is no more open than `allowed`.
+fn is_within(access: PublicAccess, allowed: PublicAccess) -> bool {
+    let openness = |access| match access {
+        PublicAccess::None => 0,
+        PublicAccess::Blob => 1,
+        PublicAccess::Container => 2,
+    };
+    openness(access) <= openness(allowed)
+}
+
+pub async fn access_report(policy: Arc<AccessPolicy>) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&policy.audit().await))
+}
+
+pub async fn enforce_access(policy: Arc<AccessPolicy>) -> Result<impl Reply, Rejection> {
+    let changed = policy.enforce().await.map_err(|e| {
+        error!("Error enforcing container public access: {:?}", e);
+        warp::reject::custom(ApiError::storage(&e, "Failed to update container access"))
+    })?;
+    Ok(warp::reply::json(&serde_json::json!({ "changed": changed, "containers": policy.audit().await })))
+}
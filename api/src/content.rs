@@ -0,0 +1,267 @@
+// api/src/content.rs
+
+//! `GET` and `HEAD /images/{name}/content`: the bytes of a stored original or rendition, e.g.
+//! `resized_photo.jpg`, for clients that can't use the signed blob URLs. Both answer with the
+//! blob's size, content type, `ETag` and `Last-Modified`, and `Accept-Ranges: bytes`, so CDNs and
+//! download managers can probe a blob with `HEAD` before fetching it. `GET` serves a single
+//! `Range` with `206`, or `416` when it lies past the end; a request for several ranges gets the
+//! whole blob. A matching `If-None-Match` gets `304`. Blobs belonging to another tenant are
+//! reported as missing, as on `/metadata`.
+//!
+//! How the bytes are delivered is set by `IMAGE_DELIVERY`: `proxy` (the default) streams them
+//! through the API, for clients that can't reach storage, as on private networks; `redirect`
+//! answers with a `302` to a signed URL of the blob, see `core/src/sas.rs`, so storage serves them
+//! instead. A request can pick its own with `?delivery=`. Either way the caller's access to the
+//! blob is checked first. Blobs encrypted with a tenant's own key are always proxied, since a signed
+//! URL alone can't read them.
+
+use azure_core::{date, request_options::Range};
+use futures::StreamExt;
+use image_resize_core::{customer_keys, filenames, sas};
+use serde::Deserialize;
+use std::{env, str::FromStr};
+use warp::{
+    http::{header, HeaderMap, Method, Response, StatusCode},
+    hyper::Body,
+    Rejection,
+};
+use tracing::error;
+
+use crate::{container_client_holding, error::ApiError, metadata::TENANT_KEY, tenant::Tenant};
+
+/// How blob bytes reach the client.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Delivery {
+    #[default]
+    Proxy,
+    Redirect,
+}
+
+impl FromStr for Delivery {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "proxy" => Ok(Delivery::Proxy),
+            "redirect" => Ok(Delivery::Redirect),
+            _ => Err(format!("Unknown delivery '{}', use proxy or redirect", s)),
+        }
+    }
+}
+
+impl Delivery {
+    /// `IMAGE_DELIVERY`, `proxy` when unset. Panics if it is invalid.
+    pub fn from_env() -> Self {
+        env::var("IMAGE_DELIVERY")
+            .ok()
+            .map(|v| v.parse().unwrap_or_else(|e| panic!("Invalid IMAGE_DELIVERY: {}", e)))
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ContentQuery {
+    /// Overrides `IMAGE_DELIVERY` for the request.
+    delivery: Option<Delivery>,
+}
+
+/// What a `Range` header asks of a blob of a given size.
+#[derive(Debug, PartialEq)]
+enum Requested {
+    Whole,
+    /// Bytes `start` to `end`, inclusive.
+    Part { start: u64, end: u64 },
+    Unsatisfiable,
+}
+
+/// Reads a `Range` header against a blob of `size` bytes. Anything but a single byte range is
+/// answered with the whole blob, as RFC 9110 allows.
+fn requested(range: Option<&str>, size: u64) -> Requested {
+    let Some(spec) = range.and_then(|range| range.trim().strip_prefix("bytes=")) else {
+        return Requested::Whole;
+    };
+    if spec.contains(',') {
+        return Requested::Whole;
+    }
+    let Some((start, end)) = spec.split_once('-') else {
+        return Requested::Whole;
+    };
+    let (start, end) = (start.trim(), end.trim());
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // the last `end` bytes
+        _ if start.is_empty() => match end.parse::<u64>() {
+            Ok(0) => return Requested::Unsatisfiable,
+            Ok(suffix) => (size.saturating_sub(suffix), size.saturating_sub(1)),
+            Err(_) => return Requested::Whole,
+        },
+        (Ok(start), _) if end.is_empty() => (start, size.saturating_sub(1)),
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+        _ => return Requested::Whole,
+    };
+    if size == 0 || start >= size {
+        return Requested::Unsatisfiable;
+    }
+    Requested::Part { start, end }
+}
+
+/// Serves the blob `name`, or only its headers for `HEAD`, delivered as `query` asks or `delivery`.
+pub async fn serve(
+    name: String,
+    method: Method,
+    tenant: Option<Tenant>,
+    headers: HeaderMap,
+    query: ContentQuery,
+    delivery: Delivery,
+) -> Result<Response<Body>, Rejection> {
+    let name = filenames::from_path(&name)
+        .ok_or_else(|| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, "Invalid blob name")))?;
+    let not_found = || warp::reject::custom(ApiError::new(StatusCode::NOT_FOUND, format!("No image named {}", name)));
+    let storage_error = |e: &azure_core::Error| {
+        error!("Error reading {}: {:?}", name, e);
+        warp::reject::custom(ApiError::storage(e, "Failed to reach blob storage"))
+    };
+
+    let blob_client = container_client_holding(&name).await.blob_client(&name);
+    let properties = match blob_client.get_properties().await {
+        Ok(properties) => properties,
+        Err(e) if e.as_http_error().is_some_and(|e| e.status() == azure_core::StatusCode::NotFound) => {
+            return Err(not_found())
+        }
+        Err(e) => return Err(storage_error(&e)),
+    };
+    if properties.blob.metadata.as_ref().and_then(|m| m.get(TENANT_KEY)) != tenant.as_ref().map(|t| &t.id) {
+        return Err(not_found());
+    }
+    let customer_key = customer_keys::customer_key(tenant.as_ref().map(|tenant| tenant.id.as_str()));
+    if query.delivery.unwrap_or(delivery) == Delivery::Redirect && customer_key.is_none() {
+        let url = sas::read_url(&blob_client).await.map_err(|e| {
+            error!("Error signing the URL of {}: {:?}", name, e);
+            warp::reject::custom(ApiError::storage(&e, "Failed to sign the blob URL"))
+        })?;
+        return Ok(Response::builder()
+            .status(StatusCode::FOUND)
+            .header(header::LOCATION, url)
+            // the signed URL expires, so the redirect mustn't outlive it in shared caches
+            .header(header::CACHE_CONTROL, "private, no-store")
+            .body(Body::empty())
+            .expect("Failed to build response"));
+    }
+
+    let blob_properties = &properties.blob.properties;
+    let size = blob_properties.content_length;
+    let etag = blob_properties.etag.to_string();
+    let etag = if etag.starts_with('"') { etag } else { format!("\"{}\"", etag) };
+    let response = Response::builder()
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::ETAG, &etag)
+        .header(header::LAST_MODIFIED, date::to_rfc1123(&blob_properties.last_modified));
+
+    let if_none_match = headers.get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok());
+    if if_none_match.is_some_and(|tags| tags.split(',').any(|tag| tag.trim() == etag || tag.trim() == "*")) {
+        return Ok(response.status(StatusCode::NOT_MODIFIED).body(Body::empty()).expect("Failed to build response"));
+    }
+    let response = response.header(header::CONTENT_TYPE, &blob_properties.content_type);
+    if method == Method::HEAD {
+        return Ok(response
+            .header(header::CONTENT_LENGTH, size)
+            .body(Body::empty())
+            .expect("Failed to build response"));
+    }
+
+    let range = headers.get(header::RANGE).and_then(|v| v.to_str().ok());
+    let (status, start, end) = match requested(range, size) {
+        Requested::Whole => (StatusCode::OK, 0, size),
+        Requested::Part { start, end } => (StatusCode::PARTIAL_CONTENT, start, end + 1),
+        Requested::Unsatisfiable => {
+            return Ok(response
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", size))
+                .body(Body::empty())
+                .expect("Failed to build response"));
+        }
+    };
+
+    // streamed a chunk at a time; a read failing midway cuts the response short
+    let body = if end > start {
+        let mut get = blob_client.get().range(Range::new(start, end));
+        if let Some(customer_key) = customer_key {
+            get = get.encryption_key(customer_key);
+        }
+        let chunks = get.into_stream().then(|chunk| async move { chunk?.data.collect().await });
+        Body::wrap_stream(chunks.inspect(move |chunk| {
+            if let Err(e) = chunk {
+                error!("Error streaming {}: {:?}", name, e);
+            }
+        }))
+    } else {
+        Body::empty()
+    };
+    let response = match status {
+        StatusCode::PARTIAL_CONTENT => response.header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end - 1, size)),
+        _ => response,
+    };
+    Ok(response
+        .status(status)
+        .header(header::CONTENT_LENGTH, end - start)
+        .body(body)
+        .expect("Failed to build response"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn part(start: u64, end: u64) -> Requested {
+        Requested::Part { start, end }
+    }
+
+    #[test]
+    fn reads_single_ranges() {
+        assert_eq!(requested(Some("bytes=0-499"), 10_000), part(0, 499));
+        assert_eq!(requested(Some("bytes=500-999"), 10_000), part(500, 999));
+        assert_eq!(requested(Some("bytes=9500-"), 10_000), part(9500, 9999));
+        assert_eq!(requested(Some("bytes=-500"), 10_000), part(9500, 9999));
+        assert_eq!(requested(Some("bytes=0-0"), 10_000), part(0, 0));
+        assert_eq!(requested(Some("bytes=9999-9999"), 10_000), part(9999, 9999));
+        assert_eq!(requested(Some(" bytes= 10 - 20 "), 10_000), part(10, 20));
+    }
+
+    #[test]
+    fn clamps_ranges_past_the_end() {
+        assert_eq!(requested(Some("bytes=9000-20000"), 10_000), part(9000, 9999));
+        assert_eq!(requested(Some("bytes=0-18446744073709551615"), 10), part(0, 9));
+        // a suffix longer than the blob is all of it
+        assert_eq!(requested(Some("bytes=-20000"), 10_000), part(0, 9999));
+    }
+
+    #[test]
+    fn refuses_unsatisfiable_ranges() {
+        assert_eq!(requested(Some("bytes=10000-"), 10_000), Requested::Unsatisfiable);
+        assert_eq!(requested(Some("bytes=10000-10005"), 10_000), Requested::Unsatisfiable);
+        assert_eq!(requested(Some("bytes=-0"), 10_000), Requested::Unsatisfiable);
+        assert_eq!(requested(Some("bytes=0-"), 0), Requested::Unsatisfiable);
+        assert_eq!(requested(Some("bytes=-5"), 0), Requested::Unsatisfiable);
+    }
+
+    #[test]
+    fn ignores_what_it_cant_serve() {
+        for range in [
+            "bytes=0-1,5-6",
+            "bytes=500-400",
+            "bytes=abc-def",
+            "bytes=-",
+            "bytes=--5",
+            "bytes=-x",
+            "bytes=5",
+            "bytes=0x10-",
+            "bytes=-18446744073709551616",
+            "items=0-5",
+            "0-5",
+            "",
+        ] {
+            assert_eq!(requested(Some(range), 10_000), Requested::Whole, "{}", range);
+        }
+        assert_eq!(requested(None, 10_000), Requested::Whole);
+    }
+}
@@ -0,0 +1,279 @@
+// api/src/copy.rs
+
+//! `POST /images/{name}/copy`: copies a stored original to another name or container without its
+//! bytes passing through the API. Storage reads the source through a short-lived SAS URL and writes
+//! the copy itself, metadata included, so the copy belongs to the same tenant. The copy goes to the
+//! source container or one of `UPLOAD_TOKEN_CONTAINERS`, the containers holding originals, and never
+//! replaces an existing blob. With `process`, the copy is queued for processing with the options of
+//! the query, as on `/upload`. Only the Azure backend copies server-side.
+//!
+//! Sources up to [`SYNC_COPY_MAX_BYTES`] are copied within the request, which answers once the copy
+//! is written. Larger ones, and any with `async`, are started as an asynchronous copy instead: the
+//! answer is `202` with an operation id, and a background task polls the copy's status and then
+//! queues its processing. Clients follow it on `GET /operations/{id}`, see `progress.rs`.
+
+use image_resize_core::{config, failover, filenames, sas, storage, telemetry};
+use serde::Deserialize;
+use azure_storage_blobs::{blob::CopyStatus, prelude::BlobClient};
+use image_resize_core::{failover::Location, message::ImageMessage};
+use std::{env, sync::Arc, time::Duration};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+use warp::{http::StatusCode, Rejection, Reply};
+
+use crate::{
+    container_client, container_client_at,
+    error::ApiError,
+    metadata,
+    notify::Notifier,
+    plan_upload,
+    progress::{ProgressRegistry, ProgressState, Transfer},
+    quota, send_message_to_queue,
+    tenant::Tenant,
+    UploadOptions,
+};
+
+/// The most storage copies from a URL in one call.
+const SYNC_COPY_MAX_BYTES: u64 = 256 * 1024 * 1024;
+const COPY_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Deserialize, Debug, Default)]
+pub struct CopyRequest {
+    /// Container of the copy, the source's by default.
+    #[serde(default)]
+    container: Option<String>,
+    /// Name of the copy, the source's by default.
+    #[serde(default)]
+    name: Option<String>,
+    /// Queue the copy for processing with the options of the query.
+    #[serde(default)]
+    process: bool,
+    /// Copy in the background whatever the size of the source.
+    #[serde(default, rename = "async")]
+    background: bool,
+}
+
+fn storage_error(e: &azure_core::Error, service: &str) -> Rejection {
+    warp::reject::custom(ApiError::storage(e, &format!("Failed to reach {}", service)))
+}
+
+/// Containers a copy may be written to.
+fn allowed_containers() -> Vec<String> {
+    let mut containers = vec![config::get().container().to_string()];
+    containers.extend(
+        env::var("UPLOAD_TOKEN_CONTAINERS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::to_string),
+    );
+    containers
+}
+
+pub async fn copy(
+    name: String,
+    options: UploadOptions,
+    tenant: Option<Tenant>,
+    request: CopyRequest,
+    notifier: Arc<Notifier>,
+    registry: ProgressRegistry,
+) -> Result<impl Reply, Rejection> {
+    if storage::kind() != storage::BackendKind::Azure {
+        return Err(warp::reject::custom(ApiError::new(
+            StatusCode::NOT_IMPLEMENTED,
+            "Copies are only made server-side in Azure blob storage",
+        )));
+    }
+    // planned first, so options that can't be honoured refuse the copy before anything is written
+    let plan = match request.process {
+        true => Some(plan_upload(&options, tenant.as_ref()).await?),
+        false => None,
+    };
+    let name = filenames::from_path(&name)
+        .ok_or_else(|| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, "Invalid blob name")))?;
+    let source_container = container_client().container_name().to_string();
+    let target_container = request.container.clone().unwrap_or_else(|| source_container.clone());
+    let target_name = match &request.name {
+        Some(target_name) => {
+            filenames::normalize(target_name).map_err(|reason| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, reason)))?
+        }
+        None => name.clone(),
+    };
+    if !allowed_containers().contains(&target_container) {
+        return Err(warp::reject::custom(ApiError::new(
+            StatusCode::FORBIDDEN,
+            format!("Copies into container {} are not allowed", target_container),
+        )));
+    }
+    if target_container == source_container && target_name == name {
+        return Err(warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, "A blob can't be copied onto itself")));
+    }
+
+    let source_location = failover::location_of(&source_container, &name).await;
+    let source = container_client_at(&source_container, source_location).blob_client(&name);
+    let not_found = || warp::reject::custom(ApiError::new(StatusCode::NOT_FOUND, format!("No image named {}", name)));
+    let properties = match source.get_properties().await {
+        Ok(properties) => properties,
+        Err(e) if e.as_http_error().is_some_and(|e| e.status() == azure_core::StatusCode::NotFound) => return Err(not_found()),
+        Err(e) => {
+            error!("Error reading properties of {}: {:?}", name, e);
+            return Err(storage_error(&e, "blob storage"));
+        }
+    };
+    let owner = properties.blob.metadata.as_ref().and_then(|m| m.get(metadata::TENANT_KEY));
+    if owner != tenant.as_ref().map(|t| &t.id) {
+        return Err(not_found());
+    }
+    let bytes = properties.blob.properties.content_length;
+
+    let target_location = failover::location_of(&target_container, &target_name).await;
+    let exists = storage::from_env(&container_client_at(&target_container, target_location))
+        .exists(&target_name)
+        .await
+        .map_err(|e| {
+            error!("Error checking {} in {}: {:?}", target_name, target_container, e);
+            storage_error(&e, "blob storage")
+        })?;
+    if exists {
+        return Err(warp::reject::custom(ApiError::new(
+            StatusCode::CONFLICT,
+            format!("'{}' already exists in {}", target_name, target_container),
+        )));
+    }
+    quota::charge(tenant.as_ref(), false, bytes, &notifier).await?;
+
+    let source_url = sas::read_url(&source).await.map_err(|e| {
+        error!("Error signing {}: {:?}", name, e);
+        storage_error(&e, "blob storage")
+    })?;
+    let source_url = azure_core::Url::parse(&source_url).expect("Signed URLs are valid");
+    let location = failover::write_location();
+    let target = container_client_at(&target_container, location).blob_client(&target_name);
+    let plan = plan.map(|plan| plan.message(target_name.clone(), target_container.clone(), location));
+    if request.background || bytes > SYNC_COPY_MAX_BYTES {
+        let started = telemetry::dependency("Azure blob", &target_container, "copy", target.copy(source_url).into_future()).await;
+        failover::record_write(location, started.is_ok());
+        if let Err(e) = started {
+            error!("Error starting the copy of {} to {}/{}: {:?}", name, target_container, target_name, e);
+            quota::refund(tenant.as_ref(), bytes).await;
+            return Err(warp::reject::custom(ApiError::storage(&e, &format!("Failed to copy '{}'", name))));
+        }
+        let id = registry.start_for("copy", tenant.as_ref().map(|tenant| tenant.id.clone()));
+        registry.add_discovered(&id, 1);
+        registry.update(&id, |p| p.transfer = Some(Transfer { copied: 0, total: bytes }));
+        info!("Copy {} of {} to {}/{} started", id, name, target_container, target_name);
+        tokio::spawn(follow(id, registry, target, location, plan, tenant, bytes));
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({
+                "id": id,
+                "source": name,
+                "container": target_container,
+                "blob": target_name,
+                "bytes": bytes,
+            })),
+            StatusCode::ACCEPTED,
+        ));
+    }
+    let copied = telemetry::dependency("Azure blob", &target_container, "copy_from_url", target.copy_from_url(source_url).into_future()).await;
+    failover::record_write(location, copied.is_ok());
+    if let Err(e) = copied {
+        error!("Error copying {} to {}/{}: {:?}", name, target_container, target_name, e);
+        quota::refund(tenant.as_ref(), bytes).await;
+        return Err(warp::reject::custom(ApiError::storage(&e, &format!("Failed to copy '{}'", name))));
+    }
+    if let Err(e) = failover::record(&target_container, &target_name, location).await {
+        error!("Error recording the location of {}: {:?}", target_name, e);
+    }
+    info!("Copied {} to {}/{}", name, target_container, target_name);
+    telemetry::track_event(
+        "ImageCopied",
+        &[("source", name.clone()), ("container", target_container.clone()), ("blob", target_name.clone())],
+    );
+
+    let job_id = match plan {
+        Some(image) => {
+            let job_id = send_message_to_queue(image)
+                .await
+                .map_err(|e| {
+                    error!("Error queueing {}: {:?}", target_name, e);
+                    storage_error(&e, "the queue")
+                })?;
+            Some(job_id.to_string())
+        }
+        None => None,
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({
+            "source": name,
+            "container": target_container,
+            "blob": target_name,
+            "bytes": bytes,
+            "job_id": job_id,
+        })),
+        StatusCode::CREATED,
+    ))
+}
+
+/// Polls the asynchronous copy to `target` until storage ends it, then queues `image`, if any.
+async fn follow(
+    id: Uuid,
+    registry: ProgressRegistry,
+    target: BlobClient,
+    location: Location,
+    image: Option<ImageMessage>,
+    tenant: Option<Tenant>,
+    bytes: u64,
+) {
+    let container = target.container_client().container_name().to_string();
+    let blob = target.blob_name().to_string();
+    let outcome = loop {
+        tokio::time::sleep(COPY_POLL_INTERVAL).await;
+        let properties = match target.get_properties().await {
+            Ok(properties) => properties.blob.properties,
+            // a blip while polling doesn't end the copy, storage carries on with it
+            Err(e) => {
+                warn!("Copy {} failed to read the status of {}: {:?}", id, blob, e);
+                continue;
+            }
+        };
+        if let Some(progress) = properties.copy_progress {
+            registry.update(&id, |p| p.transfer = Some(Transfer { copied: progress.bytes_copied, total: progress.bytes_total }));
+        }
+        match properties.copy_status {
+            Some(CopyStatus::Pending) => continue,
+            Some(CopyStatus::Success) | None => break Ok(()),
+            Some(status) => {
+                let description = properties.copy_status_description.unwrap_or_default();
+                break Err(format!("The copy ended {:?}: {}", status, description));
+            }
+        }
+    };
+    if let Err(e) = outcome {
+        error!("Copy {} to {}/{} failed: {}", id, container, blob, e);
+        quota::refund(tenant.as_ref(), bytes).await;
+        registry.record_failure(&id, &blob, e);
+        registry.finish(&id, ProgressState::Failed);
+        return;
+    }
+    if let Err(e) = failover::record(&container, &blob, location).await {
+        error!("Error recording the location of {}: {:?}", blob, e);
+    }
+    info!("Copy {} to {}/{} finished", id, container, blob);
+    telemetry::track_event("ImageCopied", &[("container", container.clone()), ("blob", blob.clone())]);
+
+    let job_id = match image {
+        Some(image) => match send_message_to_queue(image).await {
+            Ok(job_id) => Some(job_id.to_string()),
+            Err(e) => {
+                error!("Copy {} failed to queue {}: {:?}", id, blob, e);
+                registry.record_failure(&id, &blob, format!("Copied, but queueing failed: {}", e));
+                registry.finish(&id, ProgressState::Failed);
+                return;
+            }
+        },
+        None => None,
+    };
+    registry.record_queued(&id, &blob, job_id);
+    registry.finish(&id, ProgressState::Completed);
+}
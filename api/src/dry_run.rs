@@ -0,0 +1,224 @@
+// api/src/dry_run.rs
+
+//! `dry_run` on `/upload` and `/process`: validates the request as usual, then answers with the
+//! outputs the worker would produce instead of storing or queueing anything. Dimensions come from
+//! the image header alone and sizes are estimated from the pixel count, so nothing is decoded or
+//! encoded; `/process` only reads the first [`HEADER_BYTES`] of the stored blob. Uploads with
+//! hints are answered with the same outputs, see `hints.rs`.
+
+use azure_storage_blobs::prelude::{BlobClient, ContainerClient};
+use futures::StreamExt;
+use image::ImageReader;
+use image_resize_core::{geo_read, naming, pdf, pipeline, resize_spec::{Fit, ResizeSpec}, svg, video::VideoFormat};
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+use tracing::error;
+use warp::{http::StatusCode, Rejection};
+
+use crate::{error::ApiError, read_blob, Stage, UploadPlan, DEFAULT_SIZE};
+
+/// Enough for the header of common formats, including JPEGs with a full EXIF segment.
+pub const HEADER_BYTES: u64 = 256 * 1024;
+/// Rough size of the worker's JPEG output per pixel for photographic content.
+const JPEG_BYTES_PER_PIXEL: f64 = 0.3;
+const TEMPLATE_PREFIX: &str = "templates/";
+
+#[derive(Serialize, Debug)]
+pub struct Estimate {
+    pub name: String,
+    pub format: String,
+    pub width: u32,
+    pub height: u32,
+    pub bytes: u64,
+    pub outputs: Vec<Output>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Output {
+    /// `resize`, `publish:<container>` or `render:<template>`.
+    pub stage: String,
+    pub container: String,
+    pub blob: String,
+    pub width: u32,
+    pub height: u32,
+    pub estimated_bytes: u64,
+}
+
+/// The size fields of a template, mirrored from `functions/src/template.rs`.
+#[derive(Deserialize)]
+struct TemplateSize {
+    #[serde(default = "default_size")]
+    width: u32,
+    #[serde(default = "default_size")]
+    height: u32,
+    #[serde(default)]
+    match_orientation: bool,
+    #[serde(default)]
+    fit: Fit,
+    resize: Option<ResizeSpec>,
+}
+
+/// The size field of the resize preset, mirrored from `functions/src/resize.rs`.
+#[derive(Deserialize, Default)]
+struct ResizePresetSize {
+    #[serde(default)]
+    match_orientation: bool,
+    #[serde(default)]
+    fit: Fit,
+}
+
+fn default_size() -> u32 {
+    DEFAULT_SIZE
+}
+
+fn rejection(code: StatusCode, message: impl Into<String>) -> Rejection {
+    warp::reject::custom(ApiError::new(code, message))
+}
+
+/// The size `DynamicImage::resize` gives a `width` x `height` image fitted into `max_width` x
+/// `max_height`, keeping its aspect ratio, with the box turned to the image's orientation if
+/// `match_orientation` is set. Filling the box with `cover` or `exact` gives the box itself.
+fn fit(width: u32, height: u32, max_width: u32, max_height: u32, match_orientation: bool, mode: Fit) -> (u32, u32) {
+    let (max_width, max_height) =
+        if match_orientation && ((height > width && max_width > max_height) || (width > height && max_height > max_width)) {
+            (max_height, max_width)
+        } else {
+            (max_width, max_height)
+        };
+    if matches!(mode, Fit::Cover | Fit::Exact) {
+        return (max_width, max_height);
+    }
+    let ratio = f64::min(max_width as f64 / width as f64, max_height as f64 / height as f64);
+    let scale = |side: u32| ((side as f64 * ratio).round() as u32).max(1);
+    (scale(width), scale(height))
+}
+
+/// Renditions are estimated from their pixel count, capped at the upload's target size.
+fn output(plan: &UploadPlan, stage: String, container: &str, blob: String, (width, height): (u32, u32)) -> Output {
+    let estimate = (width as f64 * height as f64 * JPEG_BYTES_PER_PIXEL).ceil() as u64;
+    Output {
+        stage,
+        container: container.to_string(),
+        blob,
+        width,
+        height,
+        estimated_bytes: plan.target_size.map_or(estimate, |target| estimate.min(target)),
+    }
+}
+
+/// Reads the first [`HEADER_BYTES`] of a stored blob.
+pub async fn read_header(blob_client: &BlobClient) -> azure_core::Result<Vec<u8>> {
+    geo_read::read(blob_client, |blob_client| async move {
+        let mut header = Vec::new();
+        let mut stream = blob_client.get().range(0..HEADER_BYTES).into_stream();
+        while let Some(value) = stream.next().await {
+            header.extend(&value?.data.collect().await?);
+        }
+        Ok(header)
+    })
+    .await
+}
+
+/// Plans `plan` for the image `name` in `container_client`'s container, given its leading bytes
+/// and total size.
+pub async fn estimate(plan: &UploadPlan, container_client: &ContainerClient, name: &str, header: &[u8], bytes: u64) -> Result<Estimate, Rejection> {
+    if VideoFormat::sniff(header).is_some() || pdf::is_pdf(header) || svg::is_svg(header) {
+        return Err(rejection(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("'{}' is a video, PDF or SVG, whose size is only known once it's rendered", name),
+        ));
+    }
+    let reader = ImageReader::new(Cursor::new(header))
+        .with_guessed_format()
+        .map_err(|_| rejection(StatusCode::UNPROCESSABLE_ENTITY, format!("Failed to read {}", name)))?;
+    let format = reader
+        .format()
+        .ok_or_else(|| rejection(StatusCode::UNSUPPORTED_MEDIA_TYPE, format!("'{}' is not a recognised image", name)))?;
+    let (width, height) = reader.into_dimensions().map_err(|_| {
+        rejection(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("Failed to read the dimensions of {} from its header", name),
+        )
+    })?;
+
+    Ok(Estimate {
+        name: name.to_string(),
+        format: format!("{:?}", format).to_lowercase(),
+        width,
+        height,
+        bytes,
+        outputs: outputs(plan, container_client, name, (width, height)).await?,
+    })
+}
+
+/// The outputs `plan` makes of the `width` x `height` image `name` in `container_client`'s
+/// container, named as the worker names them.
+pub async fn outputs(plan: &UploadPlan, container_client: &ContainerClient, name: &str, (width, height): (u32, u32)) -> Result<Vec<Output>, Rejection> {
+    // renditions are made from the crop
+    let (width, height) = plan
+        .crop
+        .and_then(|crop| crop.pixels(width, height))
+        .map_or((width, height), |(_, _, width, height)| (width, height));
+    let container = container_client.container_name();
+    let resize_preset: ResizePresetSize = match read_blob(&container_client.blob_client(pipeline::RESIZE_PRESET)).await {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map_err(|_| rejection(StatusCode::UNPROCESSABLE_ENTITY, "The resize preset is not valid JSON"))?,
+        Err(e) if e.as_http_error().is_some_and(|e| e.status() == azure_core::StatusCode::NotFound) => ResizePresetSize::default(),
+        Err(e) => {
+            error!("Error reading {}: {:?}", pipeline::RESIZE_PRESET, e);
+            return Err(warp::reject::custom(ApiError::storage(&e, "Failed to reach blob storage")));
+        }
+    };
+    let resized = match plan.resize {
+        Some(spec) => spec.dimensions(width, height),
+        None => fit(
+            width,
+            height,
+            plan.width,
+            plan.height,
+            resize_preset.match_orientation,
+            plan.fit.unwrap_or(resize_preset.fit),
+        ),
+    };
+    let mut outputs = vec![output(plan, "resize".to_string(), container, naming::resized(name), resized)];
+    for variant in &plan.variants {
+        let blob = variant.blob_name(name);
+        outputs.push(output(plan, format!("variant:{}", variant.name), container, blob, variant.dimensions(width, height)));
+    }
+    for stage in &plan.then {
+        outputs.push(match stage {
+            // only PDFs have pages, and those aren't estimated
+            Stage::Pages { .. } => continue,
+            Stage::Resize => output(plan, "resize".to_string(), container, naming::resized(name), resized),
+            Stage::Publish { container: target } => {
+                output(plan, format!("publish:{}", target), target, naming::resized(name), resized)
+            }
+            Stage::Render { template } => {
+                let template_blob = format!("{}{}.json", TEMPLATE_PREFIX, template);
+                let size: TemplateSize = match read_blob(&container_client.blob_client(&template_blob)).await {
+                    Ok(bytes) => serde_json::from_slice(&bytes).map_err(|_| {
+                        rejection(StatusCode::UNPROCESSABLE_ENTITY, format!("Template {} is not valid JSON", template))
+                    })?,
+                    Err(e) if e.as_http_error().is_some_and(|e| e.status() == azure_core::StatusCode::NotFound) => {
+                        return Err(rejection(StatusCode::NOT_FOUND, format!("Unknown template: {}", template)))
+                    }
+                    Err(e) => {
+                        error!("Error reading template {}: {:?}", template_blob, e);
+                        return Err(warp::reject::custom(ApiError::storage(&e, "Failed to reach blob storage")));
+                    }
+                };
+                output(
+                    plan,
+                    format!("render:{}", template),
+                    container,
+                    format!("{}_{}", template, name),
+                    match size.resize {
+                        Some(spec) => spec.dimensions(width, height),
+                        None => fit(width, height, size.width, size.height, size.match_orientation, size.fit),
+                    },
+                )
+            }
+        });
+    }
+    Ok(outputs)
+}
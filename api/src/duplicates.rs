@@ -0,0 +1,93 @@
+// api/src/duplicates.rs
+
+//! Duplicate upload detection for tenants whose policy sets `duplicates`. Originals they upload
+//! are stamped with the SHA-256 of their content and recorded in the content table of
+//! `core/src/content_store.rs`; a later upload with the same content, once the original has its
+//! resized rendition, is answered with the existing blobs instead of being stored and queued again.
+
+use azure_core::request_options::Metadata;
+use azure_storage_blobs::prelude::ContainerClient;
+use image_resize_core::{
+    content_store,
+    models::{Existing, ExistingBlob},
+    naming, pipeline, sas,
+};
+use tracing::warn;
+
+use crate::Stage;
+
+/// `blob` with a signed URL reading it, see `core/src/sas.rs`.
+async fn existing_blob(container_client: &ContainerClient, blob: String) -> ExistingBlob {
+    let url = sas::read_url(&container_client.blob_client(&blob)).await.unwrap_or_else(|e| {
+        warn!("Failed to sign the URL of {}: {:?}", blob, e);
+        String::new()
+    });
+    ExistingBlob { blob, url }
+}
+
+/// Whether `blob` exists; lookup errors count as missing, so a duplicate is stored rather than lost.
+async fn exists(container_client: &ContainerClient, blob: &str) -> bool {
+    container_client.blob_client(blob).exists().await.unwrap_or(false)
+}
+
+/// The processed original in `container_client`'s container whose content hashes to `hash`, if
+/// any, with its resized rendition and whichever of those `then` asks for exist.
+pub async fn find(container_client: &ContainerClient, hash: &str, then: &[Stage]) -> Option<Existing> {
+    let container = container_client.container_name();
+    let partition = content_store::originals_partition(container);
+    let holders = match content_store::holders(&partition, hash).await {
+        Ok(holders) => holders,
+        Err(e) => {
+            warn!("Failed to look up content {}: {:?}", hash, e);
+            return None;
+        }
+    };
+
+    for holder in holders {
+        // the original may have been deleted or overwritten since it was recorded
+        let current = match container_client.blob_client(&holder).get_properties().await {
+            Ok(properties) => properties.blob.metadata.and_then(|m| m.get(pipeline::CONTENT_HASH_KEY).cloned()),
+            Err(_) => None,
+        };
+        if current.as_deref() != Some(hash) {
+            if let Err(e) = content_store::release(&partition, hash, &holder).await {
+                warn!("Failed to release content {} of {}: {:?}", hash, holder, e);
+            }
+            continue;
+        }
+        let resized = naming::resized(&holder);
+        if !exists(container_client, &resized).await {
+            continue;
+        }
+
+        let mut renditions = vec![existing_blob(container_client, resized).await];
+        for stage in then {
+            let rendition = match stage {
+                Stage::Render { template } => format!("{}_{}", template, holder),
+                Stage::Pages { .. } => format!("pages_{}.json", holder),
+                Stage::Resize | Stage::Publish { .. } => continue,
+            };
+            if exists(container_client, &rendition).await {
+                renditions.push(existing_blob(container_client, rendition).await);
+            }
+        }
+        return Some(Existing {
+            original: existing_blob(container_client, holder).await,
+            renditions,
+        });
+    }
+    None
+}
+
+/// Stamps an original's metadata with the hash of its content.
+pub fn stamp(metadata: &mut Metadata, hash: &str) {
+    metadata.insert(pipeline::CONTENT_HASH_KEY, hash.to_string());
+}
+
+/// Records that `blob` in `container` now holds the content hashing to `hash`.
+pub async fn record(container: &str, hash: &str, blob: &str) {
+    let partition = content_store::originals_partition(container);
+    if let Err(e) = content_store::add_reference(&partition, hash, blob).await {
+        warn!("Failed to record content {} of {}: {:?}", hash, blob, e);
+    }
+}
@@ -0,0 +1,118 @@
+// api/src/error.rs
+
+use azure_core::error::ErrorKind;
+use warp::http::StatusCode;
+
+/// A rejection carrying the status code and message that `handle_rejection`
+/// should send back to the client.
+#[derive(Debug)]
+pub struct ApiError {
+    pub code: StatusCode,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(code: StatusCode, message: impl Into<String>) -> Self {
+        ApiError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// The rejection for a failed storage call. Errors a client can act on or should know apart get
+    /// their own status: a missing container or blob (404), a body too large for storage (413),
+    /// throttling that outlasted the SDK's retries (429) and storage refusing the API's own
+    /// credentials (503). Anything else is a 502 saying `action` failed.
+    pub fn storage(e: &azure_core::Error, action: &str) -> Self {
+        let Some(http_error) = e.as_http_error() else {
+            return match e.kind() {
+                ErrorKind::Credential => ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "Storage credentials are unavailable"),
+                _ => ApiError::new(StatusCode::BAD_GATEWAY, action),
+            };
+        };
+        match (u16::from(http_error.status()), http_error.error_code()) {
+            (_, Some("ContainerNotFound")) => ApiError::new(StatusCode::NOT_FOUND, "Container not found"),
+            (_, Some("BlobNotFound")) => ApiError::new(StatusCode::NOT_FOUND, "Blob not found"),
+            (_, Some("TableNotFound")) => ApiError::new(StatusCode::NOT_FOUND, "Table not found"),
+            (_, Some("RequestBodyTooLarge" | "BlockCountExceedsLimit" | "BlockListTooLong")) | (413, _) => {
+                ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "Too large for blob storage")
+            }
+            (_, Some("ServerBusy")) | (429, _) => {
+                ApiError::new(StatusCode::TOO_MANY_REQUESTS, "Storage is throttling requests, try again later")
+            }
+            (_, Some("AuthenticationFailed" | "AuthorizationFailure" | "AuthorizationPermissionMismatch"))
+            | (401 | 403, _) => ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "Storage refused the API's credentials"),
+            _ => ApiError::new(StatusCode::BAD_GATEWAY, action),
+        }
+    }
+}
+
+impl warp::reject::Reject for ApiError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use azure_core::{
+        error::{Error, HttpError},
+        headers::{self, Headers},
+        Response,
+    };
+    use bytes::Bytes;
+
+    /// The error of a storage response with `status` and, as storage sends it, `x-ms-error-code`.
+    async fn http_error(status: u16, code: Option<&'static str>) -> Error {
+        let status = azure_core::StatusCode::try_from(status).unwrap();
+        let mut headers = Headers::new();
+        if let Some(code) = code {
+            headers.insert(headers::ERROR_CODE, code);
+        }
+        let body = Box::pin(futures::stream::once(async { Ok(Bytes::new()) }));
+        let http_error = HttpError::new(Response::new(status, headers, body)).await;
+        Error::new(ErrorKind::http_response(status, code.map(str::to_string)), http_error)
+    }
+
+    async fn status(status: u16, code: Option<&'static str>) -> StatusCode {
+        ApiError::storage(&http_error(status, code).await, "Failed to store").code
+    }
+
+    #[tokio::test]
+    async fn missing_resources_are_not_found() {
+        for code in ["ContainerNotFound", "BlobNotFound", "TableNotFound"] {
+            assert_eq!(status(404, Some(code)).await, StatusCode::NOT_FOUND, "{}", code);
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_bodies_are_too_large() {
+        for code in ["RequestBodyTooLarge", "BlockCountExceedsLimit", "BlockListTooLong"] {
+            assert_eq!(status(400, Some(code)).await, StatusCode::PAYLOAD_TOO_LARGE, "{}", code);
+        }
+        assert_eq!(status(413, None).await, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn throttling_is_too_many_requests() {
+        assert_eq!(status(503, Some("ServerBusy")).await, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(status(429, None).await, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn refused_credentials_are_unavailable() {
+        for code in ["AuthenticationFailed", "AuthorizationFailure", "AuthorizationPermissionMismatch"] {
+            assert_eq!(status(403, Some(code)).await, StatusCode::SERVICE_UNAVAILABLE, "{}", code);
+        }
+        assert_eq!(status(401, None).await, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(status(403, None).await, StatusCode::SERVICE_UNAVAILABLE);
+        let credential = Error::message(ErrorKind::Credential, "no token");
+        assert_eq!(ApiError::storage(&credential, "Failed to store").code, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn anything_else_is_a_bad_gateway() {
+        let e = ApiError::storage(&http_error(500, Some("InternalError")).await, "Failed to store");
+        assert_eq!((e.code, e.message.as_str()), (StatusCode::BAD_GATEWAY, "Failed to store"));
+        assert_eq!(status(409, Some("BlobAlreadyExists")).await, StatusCode::BAD_GATEWAY);
+        let io = Error::message(ErrorKind::Io, "connection reset");
+        assert_eq!(ApiError::storage(&io, "Failed to store").code, StatusCode::BAD_GATEWAY);
+    }
+}
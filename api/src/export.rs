@@ -0,0 +1,120 @@
+// api/src/export.rs
+
+use async_zip::{base::write::ZipFileWriter, Compression, ZipEntryBuilder};
+use azure_storage_blobs::prelude::ContainerClient;
+use futures::{AsyncWriteExt, StreamExt};
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::io::DuplexStream;
+use tokio_util::io::ReaderStream;
+use warp::{http::StatusCode, hyper::Body, Rejection, Reply};
+use tracing::error;
+
+use crate::{container_client, error::ApiError, timeout};
+
+/// Upper bound on the number of images a single export may request.
+const MAX_EXPORT_BLOBS: usize = 500;
+
+#[derive(Deserialize, Debug)]
+pub struct ExportRequest {
+    /// Names of the original blobs, as returned by `/upload`.
+    blobs: Vec<String>,
+    /// Rendition prefixes to include; `original` selects the source blob itself.
+    #[serde(default = "default_renditions")]
+    renditions: Vec<String>,
+}
+
+fn default_renditions() -> Vec<String> {
+    vec!["resized".to_string()]
+}
+
+/// Maps an original blob name to the name of one of its renditions.
+fn rendition_blob_name(rendition: &str, blob: &str) -> String {
+    if rendition == "original" {
+        blob.to_string()
+    } else {
+        format!("{}_{}", rendition, blob)
+    }
+}
+
+pub async fn export_renditions(request: ExportRequest) -> Result<impl Reply, Rejection> {
+    if request.blobs.is_empty() {
+        return Err(warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, "No blobs requested")));
+    }
+    if request.blobs.len() > MAX_EXPORT_BLOBS {
+        return Err(warp::reject::custom(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("At most {} blobs can be exported at once", MAX_EXPORT_BLOBS),
+        )));
+    }
+
+    let container_client = container_client();
+
+    let entries: Vec<String> = request
+        .blobs
+        .iter()
+        .flat_map(|blob| request.renditions.iter().map(move |r| rendition_blob_name(r, blob)))
+        .collect();
+
+    // check everything exists up front, once the zip starts streaming we can no longer send an error status
+    let mut missing = Vec::new();
+    for name in &entries {
+        match container_client.blob_client(name).exists().await {
+            Ok(true) => {}
+            Ok(false) => missing.push(name.clone()),
+            Err(e) => {
+                error!("Error checking blob {}: {:?}", name, e);
+                return Err(warp::reject::custom(ApiError::storage(&e, "Failed to reach blob storage")));
+            }
+        }
+    }
+    if !missing.is_empty() {
+        return Err(warp::reject::custom(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("Missing blobs: {}", missing.join(", ")),
+        )));
+    }
+
+    // the zip writer fills one end of the pipe while hyper drains the other
+    let (writer, reader) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(async move {
+        if let Err(e) = write_zip(container_client, entries, writer, timeout::request_timeout()).await {
+            error!("Error writing export archive: {:?}", e);
+        }
+    });
+
+    let body = Body::wrap_stream(ReaderStream::new(reader));
+    let reply = warp::reply::with_header(
+        warp::reply::Response::new(body),
+        "content-disposition",
+        "attachment; filename=\"export.zip\"",
+    );
+    Ok(warp::reply::with_header(reply, "content-type", "application/zip"))
+}
+
+async fn write_zip(
+    container_client: ContainerClient,
+    entries: Vec<String>,
+    writer: DuplexStream,
+    stall_timeout: Duration,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut zip = ZipFileWriter::with_tokio(writer);
+
+    for name in entries {
+        // images are already compressed, deflating them again only costs CPU
+        let entry = ZipEntryBuilder::new(name.clone().into(), Compression::Stored);
+        let mut entry_writer = zip.write_entry_stream(entry).await?;
+
+        let mut stream = container_client.blob_client(&name).get().chunk_size(0x2000u64).into_stream();
+        // the response is already streaming, so a stalled read aborts the archive instead of failing the request
+        while let Some(value) = tokio::time::timeout(stall_timeout, stream.next()).await? {
+            let data = value?.data.collect().await?;
+            entry_writer.write_all(&data).await?;
+        }
+
+        entry_writer.close().await?;
+    }
+
+    zip.close().await?;
+    Ok(())
+}
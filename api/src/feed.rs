@@ -0,0 +1,220 @@
+// api/src/feed.rs
+
+use azure_core::date;
+use futures::StreamExt;
+use image_resize_core::{blob_tags, sas};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use warp::{http::StatusCode, Rejection, Reply};
+use tracing::{error, warn};
+
+use crate::{
+    container_client,
+    error::ApiError,
+    metadata,
+    paging::{self, Defaults, OrderBy, PageQuery},
+    tenant::Tenant,
+};
+
+const PAGING: Defaults = Defaults {
+    limit: 50,
+    max_limit: 200,
+    orderings: &[OrderBy::Modified, OrderBy::Name, OrderBy::Size],
+};
+const PROPERTIES_CONCURRENCY: usize = 16;
+
+#[derive(Deserialize, Debug, Default)]
+pub struct FeedQuery {
+    /// `atom` (default) or `json` for JSON Feed 1.1.
+    format: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+enum Format {
+    Atom,
+    Json,
+}
+
+struct Entry {
+    name: String,
+    /// Plain URL, the entry's stable id.
+    url: String,
+    /// Signed URL reading the rendition, see `core/src/sas.rs`.
+    link: String,
+    content_type: String,
+    size: u64,
+    updated: time::OffsetDateTime,
+    tags: Vec<String>,
+    metadata: BTreeMap<String, String>,
+}
+
+/// `GET /feed`: the renditions written by the worker, newest first unless paged otherwise. Callers
+/// with an `X-Api-Key` see their tenant's renditions, others only those of uploads made without a key.
+pub async fn recent_renditions(tenant: Option<Tenant>, query: FeedQuery, page: PageQuery) -> Result<impl Reply, Rejection> {
+    let page = paging::parse(&page, &PAGING)?;
+    let format = match query.format.as_deref() {
+        None | Some("atom") => Format::Atom,
+        Some("json") => Format::Json,
+        Some(other) => {
+            return Err(warp::reject::custom(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                format!("Unknown feed format {}, use atom or json", other),
+            )))
+        }
+    };
+    let tenant_id = tenant.map(|t| t.id);
+    let container_client = container_client();
+
+    // only the tenant's finished worker output, selected by the storage service from index tags
+    let expression = blob_tags::expression(
+        container_client.container_name(),
+        &[
+            (blob_tags::TENANT, tenant_id.as_deref().unwrap_or_default()),
+            (blob_tags::STATUS, blob_tags::READY),
+        ],
+    );
+    let mut names = Vec::new();
+    let mut pages = container_client.service_client().find_blobs_by_tags(expression).into_stream();
+    while let Some(page) = pages.next().await {
+        let page = page.map_err(|e| {
+            error!("Error finding blobs for the feed: {:?}", e);
+            warp::reject::custom(ApiError::storage(&e, "Failed to reach blob storage"))
+        })?;
+        names.extend(page.blobs.into_iter().map(|blob| blob.name));
+    }
+
+    // the find results carry no properties, so they are read for the matches alone
+    let entries: Vec<Entry> = futures::stream::iter(names)
+        .map(|name| {
+            let blob_client = container_client.blob_client(&name);
+            async move {
+                let properties = match blob_client.get_properties().await {
+                    Ok(properties) => properties.blob,
+                    Err(e) => {
+                        warn!("Error reading properties of {} for the feed: {:?}", name, e);
+                        return None;
+                    }
+                };
+                // skips the analysis JSON written next to the renditions
+                if !properties.properties.content_type.starts_with("image/") {
+                    return None;
+                }
+                let (tags, user_metadata) = metadata::user_metadata(&properties.metadata.unwrap_or_default());
+                let link = match sas::read_url(&blob_client).await {
+                    Ok(link) => link,
+                    Err(e) => {
+                        warn!("Error signing the URL of {} for the feed: {:?}", name, e);
+                        return None;
+                    }
+                };
+                Some(Entry {
+                    url: blob_client.url().map(|url| url.to_string()).unwrap_or_default(),
+                    link,
+                    name,
+                    content_type: properties.properties.content_type,
+                    size: properties.properties.content_length,
+                    updated: properties.properties.last_modified,
+                    tags,
+                    metadata: user_metadata,
+                })
+            }
+        })
+        .buffer_unordered(PROPERTIES_CONCURRENCY)
+        .filter_map(|entry| async move { entry })
+        .collect()
+        .await;
+    let entries = page.paginate(entries, |entry| {
+        paging::sort_key(page.order_by, &entry.name, entry.size, Some(entry.updated))
+    });
+    let next = entries.next_cursor.as_ref().map(|cursor| {
+        let format = match format {
+            Format::Atom => "atom",
+            Format::Json => "json",
+        };
+        format!("/feed?format={}&{}", format, page.next_query(cursor))
+    });
+
+    let title = match &tenant_id {
+        Some(id) => format!("Recent renditions for {}", id),
+        None => "Recent renditions".to_string(),
+    };
+    let reply = match format {
+        Format::Json => warp::reply::with_header(
+            json_feed(&title, &entries.items, next.as_deref()),
+            "content-type",
+            "application/feed+json",
+        ),
+        Format::Atom => warp::reply::with_header(
+            atom_feed(&title, &entries.items, next.as_deref()),
+            "content-type",
+            "application/atom+xml",
+        ),
+    };
+    Ok(reply)
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn atom_feed(title: &str, entries: &[Entry], next: Option<&str>) -> String {
+    let updated = entries
+        .first()
+        .map(|e| date::to_rfc3339(&e.updated))
+        .unwrap_or_else(|| date::to_rfc3339(&time::OffsetDateTime::now_utc()));
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<feed xmlns=\"http://www.w3.org/2005/Atom\">\n<id>urn:image-resize:feed</id>\n<title>{}</title>\n<updated>{}</updated>\n",
+        escape(title),
+        updated
+    );
+    if let Some(next) = next {
+        xml.push_str(&format!("<link rel=\"next\" href=\"{}\"/>\n", escape(next)));
+    }
+    for entry in entries {
+        xml.push_str(&format!(
+            "<entry>\n<id>{url}</id>\n<title>{name}</title>\n<updated>{updated}</updated>\n<link rel=\"enclosure\" type=\"{content_type}\" href=\"{link}\"/>\n{categories}</entry>\n",
+            url = escape(&entry.url),
+            link = escape(&entry.link),
+            name = escape(&entry.name),
+            updated = date::to_rfc3339(&entry.updated),
+            content_type = escape(&entry.content_type),
+            categories = entry
+                .tags
+                .iter()
+                .map(|tag| format!("<category term=\"{}\"/>\n", escape(tag)))
+                .collect::<String>(),
+        ));
+    }
+    xml.push_str("</feed>\n");
+    xml
+}
+
+fn json_feed(title: &str, entries: &[Entry], next: Option<&str>) -> String {
+    let items: Vec<_> = entries
+        .iter()
+        .map(|entry| {
+            serde_json::json!({
+                "id": entry.url,
+                "url": entry.link,
+                "title": entry.name,
+                "image": entry.link,
+                "date_modified": date::to_rfc3339(&entry.updated),
+                "attachments": [{ "url": entry.link, "mime_type": entry.content_type }],
+                "tags": entry.tags,
+                "_image_resize": { "metadata": entry.metadata },
+            })
+        })
+        .collect();
+    let mut feed = serde_json::json!({
+        "version": "https://jsonfeed.org/version/1.1",
+        "title": title,
+        "items": items,
+    });
+    if let Some(next) = next {
+        feed["next_url"] = next.into();
+    }
+    feed.to_string()
+}
@@ -0,0 +1,85 @@
+// api/src/hints.rs
+
+//! Client hints on `/upload`: a `hints` field, before the files like `operations`, holding a JSON
+//! object of what the client claims each file is, by filename, e.g.
+//! `{"cat.jpg": {"width": 4000, "height": 3000, "format": "jpeg"}}`. For every stored file with a
+//! hint, the answer lists the renditions the job will make under `renditions`, with the names the
+//! worker gives them, signed URLs, and their sizes. Clients can show them right away and retry on
+//! 404 until the worker writes them.
+//!
+//! The claims are checked against the file's header, and a file that doesn't match is refused with
+//! a 422, so a layout built from the hint doesn't go wrong silently. The predictions are planned
+//! like a dry run, see `dry_run.rs`. Videos, PDFs and SVGs have no size in their header to plan
+//! from, so they get none.
+
+use image::ImageFormat;
+use image_resize_core::{filenames, models::PredictedRendition, sas};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use tracing::warn;
+
+use crate::{container_client_for, dry_run::Output};
+
+/// At most this many files can carry hints.
+const MAX_HINTS: usize = 100;
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Hint {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// The source's format, e.g. `jpeg` or `png`.
+    pub format: Option<String>,
+}
+
+/// Parses the `hints` field.
+pub fn parse(json: &str) -> Result<BTreeMap<String, Hint>, String> {
+    let hints: BTreeMap<String, Hint> = serde_json::from_str(json).map_err(|e| format!("Invalid hints: {}", e))?;
+    // keyed like the parts' filenames once normalized
+    let hints: BTreeMap<String, Hint> = hints.into_iter().map(|(filename, hint)| (filenames::nfc(&filename), hint)).collect();
+    if hints.len() > MAX_HINTS {
+        return Err(format!("Hints are accepted for at most {} files", MAX_HINTS));
+    }
+    Ok(hints)
+}
+
+impl Hint {
+    /// Checks the claims against the file's header: `dimensions` read from it, `None` for videos,
+    /// PDFs and SVGs, and its leading bytes.
+    pub fn check(&self, filename: &str, dimensions: Option<(u32, u32)>, header: &[u8]) -> Result<(), String> {
+        if let (Some(format), Ok(actual)) = (&self.format, image::guess_format(header)) {
+            let claimed = ImageFormat::from_extension(format.trim().to_ascii_lowercase());
+            if claimed != Some(actual) {
+                let actual = format!("{:?}", actual).to_lowercase();
+                return Err(format!("'{}' is {}, not {} as hinted", filename, actual, format));
+            }
+        }
+        if let Some((width, height)) = dimensions {
+            if self.width.is_some_and(|w| w != width) || self.height.is_some_and(|h| h != height) {
+                return Err(format!("'{}' is {}x{}, not the hinted size", filename, width, height));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The renditions in `outputs`, each with a signed URL that reads it once it's written.
+pub async fn predicted(outputs: Vec<Output>) -> Vec<PredictedRendition> {
+    let mut predicted = Vec::with_capacity(outputs.len());
+    for output in outputs {
+        let blob_client = container_client_for(&output.container).blob_client(&output.blob);
+        let url = sas::read_url(&blob_client).await.unwrap_or_else(|e| {
+            warn!("Failed to sign the URL of {}: {:?}", output.blob, e);
+            String::new()
+        });
+        predicted.push(PredictedRendition {
+            stage: output.stage,
+            container: output.container,
+            blob: output.blob,
+            url,
+            width: output.width,
+            height: output.height,
+        });
+    }
+    predicted
+}
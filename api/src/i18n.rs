@@ -0,0 +1,231 @@
+// api/src/i18n.rs
+
+//! Error messages in the client's language. The answer to a rejection, see `handle_rejection`,
+//! keeps its English message, and [`localize`] replaces it with a translation from [`CATALOG`] in
+//! the language `Accept-Language` prefers, setting `Content-Language` to say which. English,
+//! German, French and Spanish are offered; messages missing from the catalog stay in English.
+//! `ERROR_LANGUAGE` (default `en`) is used when a request has no `Accept-Language` or accepts none
+//! of them.
+//!
+//! Catalog entries match messages with values filled in: `{}` stands for a value, which is carried
+//! over into the translation as it is.
+
+use std::{env, sync::OnceLock};
+use warp::{
+    http::{header, HeaderValue},
+    hyper::Body,
+    reply::Response,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Language {
+    English,
+    German,
+    French,
+    Spanish,
+}
+
+impl Language {
+    const ALL: [Language; 4] = [Language::English, Language::German, Language::French, Language::Spanish];
+
+    fn tag(self) -> &'static str {
+        match self {
+            Language::English => "en",
+            Language::German => "de",
+            Language::French => "fr",
+            Language::Spanish => "es",
+        }
+    }
+
+    /// The language of a tag like `de` or `de-CH`.
+    fn of(tag: &str) -> Option<Self> {
+        let primary = tag.split('-').next().unwrap_or_default();
+        Language::ALL.into_iter().find(|language| primary.eq_ignore_ascii_case(language.tag()))
+    }
+}
+
+/// English messages with their German, French and Spanish translations.
+const CATALOG: &[[&str; 4]] = &[
+    ["Not Found", "Nicht gefunden", "Introuvable", "No encontrado"],
+    ["Payload too large", "Anfrage zu groß", "Requête trop volumineuse", "Carga demasiado grande"],
+    ["Internal Server Error", "Interner Serverfehler", "Erreur interne du serveur", "Error interno del servidor"],
+    ["Forbidden", "Verboten", "Interdit", "Prohibido"],
+    ["Invalid blob name", "Ungültiger Blob-Name", "Nom de blob invalide", "Nombre de blob no válido"],
+    ["No image named {}", "Kein Bild namens {}", "Aucune image nommée {}", "No hay ninguna imagen llamada {}"],
+    ["No report for {}", "Kein Bericht für {}", "Aucun rapport pour {}", "No hay informe para {}"],
+    ["Container not found", "Container nicht gefunden", "Conteneur introuvable", "Contenedor no encontrado"],
+    ["Blob not found", "Blob nicht gefunden", "Blob introuvable", "Blob no encontrado"],
+    ["Table not found", "Tabelle nicht gefunden", "Table introuvable", "Tabla no encontrada"],
+    [
+        "Too large for blob storage",
+        "Zu groß für den Blob-Speicher",
+        "Trop volumineux pour le stockage de blobs",
+        "Demasiado grande para el almacenamiento de blobs",
+    ],
+    [
+        "Storage is throttling requests, try again later",
+        "Der Speicher drosselt Anfragen, bitte später erneut versuchen",
+        "Le stockage limite les requêtes, réessayez plus tard",
+        "El almacenamiento está limitando las solicitudes, inténtelo de nuevo más tarde",
+    ],
+    [
+        "Storage refused the API's credentials",
+        "Der Speicher hat die Zugangsdaten der API abgelehnt",
+        "Le stockage a refusé les identifiants de l'API",
+        "El almacenamiento rechazó las credenciales de la API",
+    ],
+    [
+        "Storage credentials are unavailable",
+        "Die Zugangsdaten des Speichers sind nicht verfügbar",
+        "Les identifiants du stockage sont indisponibles",
+        "Las credenciales del almacenamiento no están disponibles",
+    ],
+    [
+        "Failed to reach blob storage",
+        "Der Blob-Speicher ist nicht erreichbar",
+        "Impossible de joindre le stockage de blobs",
+        "No se pudo acceder al almacenamiento de blobs",
+    ],
+    [
+        "Failed to reach table storage",
+        "Der Tabellenspeicher ist nicht erreichbar",
+        "Impossible de joindre le stockage de tables",
+        "No se pudo acceder al almacenamiento de tablas",
+    ],
+    ["Failed to store '{}'", "'{}' konnte nicht gespeichert werden", "Impossible d'enregistrer '{}'", "No se pudo guardar '{}'"],
+    ["Malformed multipart body", "Fehlerhafter Multipart-Body", "Corps multipart mal formé", "Cuerpo multipart mal formado"],
+    [
+        "Part #{} ('{}') is missing a filename",
+        "Teil #{} ('{}') fehlt ein Dateiname",
+        "Il manque un nom de fichier à la partie #{} ('{}')",
+        "A la parte #{} ('{}') le falta un nombre de archivo",
+    ],
+    ["'{}' is not a recognised image", "'{}' ist kein erkanntes Bild", "'{}' n'est pas une image reconnue", "'{}' no es una imagen reconocida"],
+    [
+        "'{}' duplicates an existing image",
+        "'{}' ist ein Duplikat eines vorhandenen Bildes",
+        "'{}' est un doublon d'une image existante",
+        "'{}' duplica una imagen existente",
+    ],
+    ["Width and height must be positive", "Breite und Höhe müssen positiv sein", "La largeur et la hauteur doivent être positives", "El ancho y el alto deben ser positivos"],
+    ["Unknown template: {}", "Unbekannte Vorlage: {}", "Modèle inconnu : {}", "Plantilla desconocida: {}"],
+    ["A blob can't be copied onto itself", "Ein Blob kann nicht auf sich selbst kopiert werden", "Un blob ne peut pas être copié sur lui-même", "Un blob no se puede copiar sobre sí mismo"],
+    ["Unknown API key", "Unbekannter API-Schlüssel", "Clé d'API inconnue", "Clave de API desconocida"],
+    ["An API key is required", "Ein API-Schlüssel ist erforderlich", "Une clé d'API est requise", "Se requiere una clave de API"],
+    [
+        "An API key or bearer token is required",
+        "Ein API-Schlüssel oder Bearer-Token ist erforderlich",
+        "Une clé d'API ou un jeton bearer est requis",
+        "Se requiere una clave de API o un token bearer",
+    ],
+    ["The {} role is required", "Die Rolle {} ist erforderlich", "Le rôle {} est requis", "Se requiere el rol {}"],
+    ["Unknown job id", "Unbekannte Job-ID", "Identifiant de tâche inconnu", "ID de trabajo desconocido"],
+    ["Unknown operation id", "Unbekannte Vorgangs-ID", "Identifiant d'opération inconnu", "ID de operación desconocido"],
+    [
+        "Too many concurrent uploads, retry later",
+        "Zu viele gleichzeitige Uploads, bitte später erneut versuchen",
+        "Trop de téléversements simultanés, réessayez plus tard",
+        "Demasiadas subidas simultáneas, inténtelo de nuevo más tarde",
+    ],
+    [
+        "The tenant's {} quota of {} is used up",
+        "Das {}-Kontingent des Mandanten von {} ist aufgebraucht",
+        "Le quota {} du locataire, de {}, est épuisé",
+        "La cuota {} del inquilino, de {}, está agotada",
+    ],
+    [
+        "The server is short of memory ({} of {} MiB in use), retry later",
+        "Dem Server fehlt Speicher ({} von {} MiB belegt), bitte später erneut versuchen",
+        "Le serveur manque de mémoire ({} sur {} Mio utilisés), réessayez plus tard",
+        "Al servidor le falta memoria ({} de {} MiB en uso), inténtelo de nuevo más tarde",
+    ],
+];
+
+/// The English message of an error answer, kept on the response for [`localize`].
+#[derive(Debug, Clone)]
+pub struct ErrorMessage(pub String);
+
+/// The language from `ERROR_LANGUAGE`.
+pub fn default_language() -> Language {
+    static DEFAULT: OnceLock<Language> = OnceLock::new();
+    *DEFAULT.get_or_init(|| match env::var("ERROR_LANGUAGE") {
+        Ok(tag) => Language::of(&tag).unwrap_or_else(|| panic!("Unsupported ERROR_LANGUAGE '{}', use en, de, fr or es", tag)),
+        Err(_) => Language::English,
+    })
+}
+
+/// The offered language `accept_language` ranks highest, `*` standing for the default.
+pub fn negotiate(accept_language: Option<&str>) -> Language {
+    let mut ranges: Vec<(&str, f32)> = accept_language
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';');
+            let tag = params.next()?.trim();
+            let quality = params
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse().ok())?;
+            (!tag.is_empty() && quality > 0.0).then_some((tag, quality))
+        })
+        .collect();
+    // stable, so equally ranked languages keep the client's order
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+    ranges
+        .into_iter()
+        .find_map(|(tag, _)| if tag == "*" { Some(default_language()) } else { Language::of(tag) })
+        .unwrap_or_else(default_language)
+}
+
+/// The values `template` leaves to `{}` in `message`, if it matches.
+fn values<'a>(template: &str, message: &'a str) -> Option<Vec<&'a str>> {
+    let mut pieces = template.split("{}");
+    let first = pieces.next().unwrap_or_default();
+    let mut rest = message.strip_prefix(first)?;
+    let pieces: Vec<&str> = pieces.collect();
+    let mut values = Vec::new();
+    for (i, piece) in pieces.iter().enumerate() {
+        let at = if i == pieces.len() - 1 {
+            rest.strip_suffix(piece).map(str::len)?
+        } else {
+            rest.find(piece)?
+        };
+        values.push(&rest[..at]);
+        rest = &rest[at + piece.len()..];
+    }
+    rest.is_empty().then_some(values)
+}
+
+/// `message` in `language`, if the catalog has it.
+pub fn translate(message: &str, language: Language) -> Option<String> {
+    let column = Language::ALL.iter().position(|l| *l == language)?;
+    CATALOG.iter().find_map(|entry| {
+        let values = values(entry[0], message)?;
+        let mut translated = String::new();
+        let mut values = values.into_iter();
+        for (i, piece) in entry[column].split("{}").enumerate() {
+            if i > 0 {
+                translated.push_str(values.next().unwrap_or_default());
+            }
+            translated.push_str(piece);
+        }
+        Some(translated)
+    })
+}
+
+/// Translates an error answer into the language `accept_language` prefers; other answers are
+/// passed through.
+pub fn localize(accept_language: Option<String>, mut response: Response) -> Response {
+    let Some(ErrorMessage(message)) = response.extensions_mut().remove::<ErrorMessage>() else {
+        return response;
+    };
+    let language = negotiate(accept_language.as_deref());
+    let (language, message) = match translate(&message, language) {
+        Some(translated) if language != Language::English => (language, translated),
+        _ => (Language::English, message),
+    };
+    *response.body_mut() = Body::from(message);
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_LANGUAGE, HeaderValue::from_static(language.tag()));
+    headers.insert(header::VARY, HeaderValue::from_static("accept-language"));
+    response
+}
@@ -0,0 +1,148 @@
+// api/src/images.rs
+
+use azure_core::date;
+use futures::StreamExt;
+use image_resize_core::{
+    config,
+    image_index::{self, SearchFilter},
+    models::ImageSummary,
+};
+use serde::Deserialize;
+use time::{format_description::FormatItem, macros::format_description, Date, Duration, PrimitiveDateTime};
+use warp::{http::StatusCode, Rejection, Reply};
+use tracing::error;
+
+use crate::{
+    container_client,
+    error::ApiError,
+    paging::{self, Defaults, OrderBy, Page, PageQuery},
+    tenant::Tenant,
+};
+
+const PAGING: Defaults = Defaults {
+    limit: 100,
+    max_limit: 1000,
+    orderings: &[OrderBy::Name, OrderBy::Size, OrderBy::Modified],
+};
+/// Index entries read per search; pages are cut from these.
+const MAX_SCANNED: usize = 5000;
+const DATE_FORMAT: &[FormatItem<'static>] = format_description!("[year]-[month]-[day]");
+const DATE_TIME_FORMAT: &[FormatItem<'static>] = format_description!("[year]-[month]-[day]T[hour]:[minute]:[second]");
+
+#[derive(Deserialize, Debug, Default)]
+pub struct SearchQuery {
+    /// Earliest capture time, `YYYY-MM-DD` or `YYYY-MM-DDTHH:MM:SS`.
+    from: Option<String>,
+    /// Latest capture time, inclusive; a bare date covers the whole day.
+    to: Option<String>,
+    /// Exact camera model, as written in the EXIF `Model` tag.
+    camera: Option<String>,
+    /// Defaults to `AZURE_STORAGE_CONTAINER`.
+    container: Option<String>,
+}
+
+fn bad_request(message: String) -> Rejection {
+    warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, message))
+}
+
+/// Parses a bound, returning it in the index's format; `end` bounds are moved just past the
+/// instant or day they name so they can be compared exclusively.
+fn bound(name: &str, value: &str, end: bool) -> Result<String, Rejection> {
+    let moment = match PrimitiveDateTime::parse(value, DATE_TIME_FORMAT) {
+        Ok(moment) if end => moment + Duration::seconds(1),
+        Ok(moment) => moment,
+        Err(_) => {
+            let date = Date::parse(value, DATE_FORMAT).map_err(|_| {
+                bad_request(format!("{} must be YYYY-MM-DD or YYYY-MM-DDTHH:MM:SS", name))
+            })?;
+            let date = if end { date.next_day().unwrap_or(date) } else { date };
+            date.midnight()
+        }
+    };
+    Ok(moment.format(DATE_TIME_FORMAT).expect("Failed to format capture time"))
+}
+
+/// `GET /images`: the caller's stored originals, read from the container listing. Worker output is
+/// left out; it is listed by `/feed`.
+pub async fn list(tenant: Option<Tenant>, page: PageQuery) -> Result<impl Reply, Rejection> {
+    let page = paging::parse(&page, &PAGING)?;
+    let tenant_id = tenant.map(|t| t.id);
+    let container_client = container_client();
+
+    let mut originals = Vec::new();
+    let mut pages = container_client.list_blobs().include_metadata(true).into_stream();
+    while let Some(listing) = pages.next().await {
+        let listing = listing.map_err(|e| {
+            error!("Error listing blobs: {:?}", e);
+            warp::reject::custom(ApiError::storage(&e, "Failed to reach blob storage"))
+        })?;
+        for blob in listing.blobs.blobs() {
+            let metadata = blob.metadata.clone().unwrap_or_default();
+            // worker output is stamped with its version, see output_metadata in the worker
+            if metadata.contains_key("worker_version") || metadata.get("tenant") != tenant_id.as_ref() {
+                continue;
+            }
+            let (tags, user_metadata) = crate::metadata::user_metadata(&metadata);
+            let original = ImageSummary {
+                name: blob.name.clone(),
+                content_type: blob.properties.content_type.clone(),
+                size: blob.properties.content_length,
+                last_modified: date::to_rfc3339(&blob.properties.last_modified),
+                tags,
+                metadata: user_metadata,
+            };
+            originals.push((original, blob.properties.last_modified));
+        }
+    }
+
+    let page = page.paginate(originals, |(original, modified)| {
+        paging::sort_key(page.order_by, &original.name, original.size, Some(*modified))
+    });
+    Ok(warp::reply::json(&Page {
+        items: page.items.into_iter().map(|(original, _)| original).collect::<Vec<_>>(),
+        next_cursor: page.next_cursor,
+    }))
+}
+
+/// `GET /images/search`: originals indexed by the worker from their EXIF, filtered by capture
+/// time and camera. Callers see their tenant's images, or those uploaded without a key.
+pub async fn search(tenant: Option<Tenant>, query: SearchQuery, page: PageQuery) -> Result<impl Reply, Rejection> {
+    let page = paging::parse(&page, &PAGING)?;
+    let filter = SearchFilter {
+        tenant: tenant.map(|t| t.id),
+        from: query.from.as_deref().map(|v| bound("from", v, false)).transpose()?,
+        until: query.to.as_deref().map(|v| bound("to", v, true)).transpose()?,
+        camera_model: query.camera.filter(|c| !c.is_empty()),
+    };
+    let container = query
+        .container
+        .unwrap_or_else(|| config::get().container().to_string());
+
+    let records = image_index::search(&container, &filter, MAX_SCANNED).await.map_err(|e| {
+        error!("Error searching the image index: {:?}", e);
+        warp::reject::custom(ApiError::storage(&e, "Failed to reach table storage"))
+    })?;
+    let page = page.paginate(records, |record| {
+        paging::sort_key(page.order_by, &record.blob, record.size.unwrap_or_default(), record.indexed_at())
+    });
+    let images: Vec<_> = page
+        .items
+        .into_iter()
+        .map(|record| {
+            serde_json::json!({
+                "container": record.container,
+                "blob": record.blob,
+                "captured_at": record.captured_at,
+                "camera_make": record.camera_make,
+                "camera_model": record.camera_model,
+                "latitude": record.latitude,
+                "longitude": record.longitude,
+                "size": record.size,
+            })
+        })
+        .collect();
+    Ok(warp::reply::json(&Page {
+        items: images,
+        next_cursor: page.next_cursor,
+    }))
+}
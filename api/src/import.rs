@@ -0,0 +1,155 @@
+// api/src/import.rs
+
+use azure_storage::StorageCredentials;
+use azure_storage_blobs::prelude::{ClientBuilder, ContainerClient};
+use futures::StreamExt;
+use image_resize_core::{azure, failover::Location};
+use serde::Deserialize;
+use std::{collections::BTreeMap, sync::Arc};
+use uuid::Uuid;
+use warp::{http::StatusCode, Rejection, Reply};
+use tracing::{error, info};
+
+use crate::{
+    container_client,
+    error::ApiError,
+    notify::Notifier,
+    paging::PageQuery,
+    progress::{self, ProgressRegistry, ProgressState},
+    send_message_to_queue, ImageMessage, Stage, DEFAULT_SIZE,
+};
+
+#[derive(Deserialize, Debug)]
+pub struct ImportRequest {
+    /// Storage account holding the blobs to import.
+    source_account: String,
+    source_container: String,
+    #[serde(default)]
+    prefix: Option<String>,
+    /// SAS token granting read and list access on the source container, without the leading `?`.
+    sas_token: String,
+    /// Extra addresses emailed when the operation finishes or starts failing.
+    #[serde(default)]
+    notify: Vec<String>,
+}
+
+pub async fn start_import(request: ImportRequest, registry: ProgressRegistry, notifier: Arc<Notifier>) -> Result<impl Reply, Rejection> {
+    let credentials = StorageCredentials::sas_token(request.sas_token.trim_start_matches('?'))
+        .map_err(|_| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, "Invalid SAS token")))?;
+    let source = ClientBuilder::new(request.source_account.clone(), credentials)
+        .client_options(azure::client_options())
+        .container_client(request.source_container.clone());
+
+    let id = registry.start("import");
+    info!(
+        "Starting import {} from {}/{}",
+        id, request.source_account, request.source_container
+    );
+
+    tokio::spawn(run_import(id, source, request, registry, notifier));
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "id": id })),
+        StatusCode::ACCEPTED,
+    ))
+}
+
+pub async fn import_status(id: Uuid, page: PageQuery, registry: ProgressRegistry) -> Result<impl Reply, Rejection> {
+    match registry.get(&id) {
+        Some(progress) => progress::status_reply(progress, page),
+        None => Err(warp::reject::custom(ApiError::new(StatusCode::NOT_FOUND, "Unknown import id"))),
+    }
+}
+
+async fn run_import(id: Uuid, source: ContainerClient, request: ImportRequest, registry: ProgressRegistry, notifier: Arc<Notifier>) {
+    let destination = container_client();
+    let sas_token = request.sas_token.trim_start_matches('?');
+
+    let mut list = source.list_blobs();
+    if let Some(prefix) = request.prefix.clone() {
+        list = list.prefix(prefix);
+    }
+    let mut pages = list.into_stream();
+
+    while let Some(page) = pages.next().await {
+        let page = match page {
+            Ok(page) => page,
+            Err(e) => {
+                error!("Import {} failed to list source blobs: {:?}", id, e);
+                registry.record_error(&id, format!("Listing source blobs failed: {}", e));
+                registry.finish(&id, ProgressState::Failed);
+                if let Some(progress) = registry.get(&id) {
+                    notifier.operation_finished(&progress, &request.notify).await;
+                }
+                return;
+            }
+        };
+
+        let names: Vec<String> = page.blobs.blobs().map(|blob| blob.name.clone()).collect();
+        registry.add_discovered(&id, names.len());
+
+        for name in names {
+            let result = match copy_blob(&source, &destination, &name, sas_token).await {
+                Ok(()) => {
+                    let image = ImageMessage {
+                        filename: name.clone(),
+                        image_container: destination.container_name().to_string(),
+                        auto_enhance: false,
+                        stage: Stage::Resize,
+                        then: Vec::new(),
+                        width: DEFAULT_SIZE,
+                        height: DEFAULT_SIZE,
+                        tenant: None,
+                        tags: Vec::new(),
+                        metadata: BTreeMap::new(),
+                        target_size: None,
+                        resize: None,
+                        fit: None,
+                        filter: None,
+                        variants: Vec::new(),
+                        output_format: None,
+                        crop: None,
+                        focal_point: None,
+                        storage: Location::Primary,
+                        queued_at: None,
+                        job_id: None,
+                        ..Default::default()
+                    };
+                    send_message_to_queue(image).await
+                }
+                Err(e) => Err(e),
+            };
+
+            match result {
+                Ok(_) => registry.record_success(&id, &name),
+                Err(e) => {
+                    error!("Import {} failed for {}: {:?}", id, name, e);
+                    registry.record_failure(&id, &name, e.to_string());
+                    if let Some(progress) = registry.get(&id) {
+                        notifier.check_failure_rate(&progress, &request.notify).await;
+                    }
+                }
+            }
+        }
+    }
+
+    info!("Import {} finished", id);
+    registry.finish(&id, ProgressState::Completed);
+    if let Some(progress) = registry.get(&id) {
+        notifier.operation_finished(&progress, &request.notify).await;
+    }
+}
+
+/// Server-side copy, so the bytes never pass through the API process.
+async fn copy_blob(
+    source: &ContainerClient,
+    destination: &ContainerClient,
+    name: &str,
+    sas_token: &str,
+) -> azure_core::Result<()> {
+    let mut source_url = source.blob_client(name).url()?;
+    source_url.set_query(Some(sas_token));
+
+    destination.blob_client(name).copy_from_url(source_url).await?;
+    Ok(())
+}
@@ -0,0 +1,55 @@
+// api/src/ingest/folder.rs
+
+use std::{io, path::PathBuf};
+
+use super::{BoxFuture, IngestSource, RemoteFile};
+
+/// A local directory, or a remote share mounted into one. Finished files are moved into its
+/// `processed/` or `failed/` subdirectory.
+pub struct FolderSource {
+    dir: PathBuf,
+}
+
+impl FolderSource {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        FolderSource { dir: dir.into() }
+    }
+}
+
+impl IngestSource for FolderSource {
+    fn describe(&self) -> String {
+        format!("folder {}", self.dir.display())
+    }
+
+    fn list(&self) -> BoxFuture<'_, io::Result<Vec<RemoteFile>>> {
+        Box::pin(async move {
+            let mut files = Vec::new();
+            let mut entries = tokio::fs::read_dir(&self.dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = entry.metadata().await?;
+                if !metadata.is_file() {
+                    continue;
+                }
+                if let Ok(name) = entry.file_name().into_string() {
+                    files.push(RemoteFile {
+                        name,
+                        size: metadata.len(),
+                    });
+                }
+            }
+            Ok(files)
+        })
+    }
+
+    fn fetch<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<Vec<u8>>> {
+        Box::pin(tokio::fs::read(self.dir.join(name)))
+    }
+
+    fn finish<'a>(&'a self, name: &'a str, succeeded: bool) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let target = self.dir.join(if succeeded { "processed" } else { "failed" });
+            tokio::fs::create_dir_all(&target).await?;
+            tokio::fs::rename(self.dir.join(name), target.join(name)).await
+        })
+    }
+}
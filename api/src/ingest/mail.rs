@@ -0,0 +1,196 @@
+// api/src/ingest/mail.rs
+
+use azure_core::request_options::Metadata;
+use serde::Deserialize;
+use std::{
+    env,
+    time::{Duration, Instant},
+};
+use tracing::{error, info, warn};
+
+use super::{is_image_name, store_and_enqueue};
+
+const GRAPH: &str = "https://graph.microsoft.com/v1.0";
+const PAGE_SIZE: u32 = 25;
+
+/// Polls a mailbox through Microsoft Graph for unread messages with image attachments,
+/// ingests each attachment with the sender recorded as blob metadata, then marks the message read.
+///
+/// Configured by `MAIL_INGEST_MAILBOX` and the app registration in `MAIL_GRAPH_TENANT_ID`,
+/// `MAIL_GRAPH_CLIENT_ID` and `MAIL_GRAPH_CLIENT_SECRET`, which needs the `Mail.ReadWrite`
+/// application permission.
+pub struct MailSource {
+    mailbox: String,
+    tenant_id: String,
+    client_id: String,
+    client_secret: String,
+    max_bytes: usize,
+    client: reqwest::Client,
+    token: Option<(String, Instant)>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+#[derive(Deserialize)]
+struct Page<T> {
+    value: Vec<T>,
+}
+
+#[derive(Deserialize)]
+struct Message {
+    id: String,
+    from: Option<Recipient>,
+}
+
+#[derive(Deserialize)]
+struct Recipient {
+    #[serde(rename = "emailAddress")]
+    email_address: EmailAddress,
+}
+
+#[derive(Deserialize)]
+struct EmailAddress {
+    address: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Attachment {
+    #[serde(rename = "@odata.type")]
+    kind: String,
+    name: String,
+    #[serde(rename = "contentType", default)]
+    content_type: Option<String>,
+    #[serde(default)]
+    size: usize,
+    #[serde(rename = "contentBytes", default)]
+    content_bytes: Option<String>,
+}
+
+impl MailSource {
+    pub fn from_env(max_bytes: usize) -> Option<Self> {
+        let mailbox = env::var("MAIL_INGEST_MAILBOX").ok()?;
+        Some(MailSource {
+            mailbox,
+            tenant_id: env::var("MAIL_GRAPH_TENANT_ID").expect("Missing MAIL_GRAPH_TENANT_ID env var"),
+            client_id: env::var("MAIL_GRAPH_CLIENT_ID").expect("Missing MAIL_GRAPH_CLIENT_ID env var"),
+            client_secret: env::var("MAIL_GRAPH_CLIENT_SECRET").expect("Missing MAIL_GRAPH_CLIENT_SECRET env var"),
+            max_bytes,
+            client: reqwest::Client::new(),
+            token: None,
+        })
+    }
+
+    pub async fn watch(mut self, poll: Duration) {
+        info!("Watching mailbox {} for image attachments", self.mailbox);
+        let mut interval = tokio::time::interval(poll);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.poll_once().await {
+                error!("Error polling mailbox {}: {:?}", self.mailbox, e);
+            }
+        }
+    }
+
+    async fn access_token(&mut self) -> reqwest::Result<String> {
+        if let Some((token, expires)) = &self.token {
+            if Instant::now() < *expires {
+                return Ok(token.clone());
+            }
+        }
+        let response: TokenResponse = self
+            .client
+            .post(format!("https://login.microsoftonline.com/{}/oauth2/v2.0/token", self.tenant_id))
+            .form(&[
+                ("client_id", self.client_id.as_str()),
+                ("client_secret", self.client_secret.as_str()),
+                ("scope", "https://graph.microsoft.com/.default"),
+                ("grant_type", "client_credentials"),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        // renew a minute early so a token never expires mid-poll
+        let expires = Instant::now() + Duration::from_secs(response.expires_in.saturating_sub(60));
+        self.token = Some((response.access_token.clone(), expires));
+        Ok(response.access_token)
+    }
+
+    async fn poll_once(&mut self) -> reqwest::Result<()> {
+        let token = self.access_token().await?;
+        let messages_url = format!("{}/users/{}/mailFolders/inbox/messages", GRAPH, self.mailbox);
+        let messages: Page<Message> = self
+            .client
+            .get(&messages_url)
+            .bearer_auth(&token)
+            .query(&[
+                ("$filter", "isRead eq false and hasAttachments eq true"),
+                ("$select", "id,from"),
+                ("$top", &PAGE_SIZE.to_string()),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        for message in messages.value {
+            let sender = message
+                .from
+                .and_then(|from| from.email_address.address)
+                .unwrap_or_default();
+            let attachments: Page<Attachment> = self
+                .client
+                .get(format!("{}/users/{}/messages/{}/attachments", GRAPH, self.mailbox, message.id))
+                .bearer_auth(&token)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+
+            for attachment in attachments.value {
+                self.ingest_attachment(attachment, &sender).await;
+            }
+
+            // mark it read whatever happened to its attachments, so a bad one isn't retried forever
+            self.client
+                .patch(format!("{}/users/{}/messages/{}", GRAPH, self.mailbox, message.id))
+                .bearer_auth(&token)
+                .json(&serde_json::json!({ "isRead": true }))
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+        Ok(())
+    }
+
+    async fn ingest_attachment(&self, attachment: Attachment, sender: &str) {
+        let is_image = attachment.content_type.as_deref().is_some_and(|t| t.starts_with("image/"))
+            || is_image_name(&attachment.name);
+        if attachment.kind != "#microsoft.graph.fileAttachment" || !is_image {
+            return;
+        }
+        if attachment.size > self.max_bytes {
+            warn!("Skipping attachment {} from {}: {} bytes is over the limit", attachment.name, sender, attachment.size);
+            return;
+        }
+        let Some(bytes) = attachment.content_bytes.and_then(|b| azure_core::base64::decode(b).ok()) else {
+            warn!("Skipping attachment {} from {}: no content", attachment.name, sender);
+            return;
+        };
+
+        // attachments from different mails often share names like image001.png
+        let short_id = uuid::Uuid::new_v4().simple().to_string();
+        let name = format!("{}_{}", &short_id[..8], attachment.name);
+        let mut metadata = Metadata::new();
+        metadata.insert("sender", sender.to_string());
+        metadata.insert("source", "email");
+        store_and_enqueue(&name, bytes, metadata).await;
+    }
+}
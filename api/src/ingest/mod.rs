@@ -0,0 +1,153 @@
+// api/src/ingest/mod.rs
+
+//! Background ingestion: watchers that pull image files from elsewhere into the container and
+//! enqueue them exactly like `/upload` does. Each source is polled every `INGEST_POLL_SECS`
+//! (default 30) and only picks up a file once its size has held still for one poll, so files
+//! still being written are left alone.
+
+mod folder;
+mod mail;
+#[cfg(feature = "sftp")]
+mod sftp;
+
+use azure_core::request_options::Metadata;
+use image_resize_core::{failover::Location, trailing_data};
+use std::{collections::HashMap, env, future::Future, io, pin::Pin, time::Duration};
+use tracing::{error, info};
+
+use crate::{container_client, count_upload, limit::{self, BodyLimits}, original_content_type, plan_upload, send_message_to_queue, UploadOptions};
+
+const DEFAULT_POLL_SECS: u64 = 30;
+const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "gif", "webp", "bmp", "tif", "tiff"];
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+pub struct RemoteFile {
+    pub name: String,
+    pub size: u64,
+}
+
+/// Somewhere new files show up.
+pub trait IngestSource: Send + Sync {
+    /// For log lines, e.g. `folder /mnt/drop`.
+    fn describe(&self) -> String;
+    /// Files waiting to be ingested, not including ones already finished.
+    fn list(&self) -> BoxFuture<'_, io::Result<Vec<RemoteFile>>>;
+    fn fetch<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<Vec<u8>>>;
+    /// Moves the file out of the way so it is not picked up again.
+    fn finish<'a>(&'a self, name: &'a str, succeeded: bool) -> BoxFuture<'a, io::Result<()>>;
+}
+
+pub fn is_image_name(name: &str) -> bool {
+    name.rsplit_once('.')
+        .is_some_and(|(_, ext)| IMAGE_EXTENSIONS.iter().any(|e| e.eq_ignore_ascii_case(ext)))
+}
+
+/// Starts a watcher for each configured source: `INGEST_DIR` for a local or mounted directory,
+/// `INGEST_SFTP_HOST` (with the `sftp` feature) for an SFTP server, `MAIL_INGEST_MAILBOX` for
+/// image attachments mailed in.
+pub fn spawn_from_env(limits: BodyLimits) {
+    let poll = Duration::from_secs(limit::env_or("INGEST_POLL_SECS", DEFAULT_POLL_SECS));
+
+    if let Some(mail) = mail::MailSource::from_env(limits.max_part_bytes) {
+        tokio::spawn(mail.watch(poll));
+    }
+
+    if let Ok(dir) = env::var("INGEST_DIR") {
+        tokio::spawn(watch(Box::new(folder::FolderSource::new(dir)), poll));
+    }
+
+    #[cfg(feature = "sftp")]
+    if let Some(source) = sftp::SftpSource::from_env() {
+        tokio::spawn(watch(Box::new(source), poll));
+    }
+    #[cfg(not(feature = "sftp"))]
+    if env::var("INGEST_SFTP_HOST").is_ok() {
+        tracing::warn!("INGEST_SFTP_HOST is set but this build lacks the sftp feature");
+    }
+}
+
+async fn watch(source: Box<dyn IngestSource>, poll: Duration) {
+    info!("Watching {} for new images", source.describe());
+    // sizes seen on the previous poll; a file is ready once it shows the same size twice
+    let mut previous: HashMap<String, u64> = HashMap::new();
+    let mut interval = tokio::time::interval(poll);
+
+    loop {
+        interval.tick().await;
+        let files = match source.list().await {
+            Ok(files) => files,
+            Err(e) => {
+                error!("Error listing {}: {:?}", source.describe(), e);
+                continue;
+            }
+        };
+
+        let mut current = HashMap::new();
+        for file in files.into_iter().filter(|f| is_image_name(&f.name)) {
+            if previous.get(&file.name) == Some(&file.size) {
+                let succeeded = ingest(source.as_ref(), &file.name, Metadata::new()).await;
+                if let Err(e) = source.finish(&file.name, succeeded).await {
+                    error!("Error moving {} aside on {}: {:?}", file.name, source.describe(), e);
+                }
+            } else {
+                current.insert(file.name, file.size);
+            }
+        }
+        previous = current;
+    }
+}
+
+/// Fetches one file, stores it and enqueues it, logging rather than returning failures.
+async fn ingest(source: &dyn IngestSource, name: &str, metadata: Metadata) -> bool {
+    let bytes = match source.fetch(name).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            error!("Error fetching {} from {}: {:?}", name, source.describe(), e);
+            return false;
+        }
+    };
+    store_and_enqueue(name, bytes, metadata).await
+}
+
+/// Stores an ingested file under `name` with default processing options and enqueues it.
+pub async fn store_and_enqueue(name: &str, mut bytes: Vec<u8>, metadata: Metadata) -> bool {
+    if let Err(e) = trailing_data::check(name, &mut bytes) {
+        error!("Refused ingested file: {}", e);
+        return false;
+    }
+    let plan = match plan_upload(&UploadOptions::default(), None).await {
+        Ok(plan) => plan,
+        Err(e) => {
+            error!("Ingestion is misconfigured: {:?}", e);
+            return false;
+        }
+    };
+
+    let container_client = container_client();
+    let content_type = original_content_type(&bytes);
+    let size = bytes.len() as u64;
+    let stored = container_client
+        .blob_client(name)
+        .put_block_blob(bytes)
+        .content_type(content_type)
+        .metadata(metadata)
+        .tags(plan.blob_tags())
+        .await;
+    if let Err(e) = stored {
+        error!("Error storing ingested file {}: {:?}", name, e);
+        return false;
+    }
+
+    match send_message_to_queue(plan.message(name.to_string(), container_client.container_name().to_string(), Location::Primary)).await {
+        Ok(_) => {
+            count_upload("ingest", size);
+            info!("Ingested {}", name);
+            true
+        }
+        Err(e) => {
+            error!("Error enqueueing ingested file {}: {:?}", name, e);
+            false
+        }
+    }
+}
@@ -0,0 +1,125 @@
+// api/src/ingest/sftp.rs
+
+use ssh2::{Session, Sftp};
+use std::{
+    env,
+    io::{self, Read},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use super::{BoxFuture, IngestSource, RemoteFile};
+
+/// A directory on an SFTP server, configured by `INGEST_SFTP_HOST` (`host:port`, port 22 by
+/// default), `INGEST_SFTP_USER`, `INGEST_SFTP_PASSWORD` or `INGEST_SFTP_KEY_FILE`, and
+/// `INGEST_SFTP_DIR`. Finished files are renamed into `processed/` or `failed/` next to them,
+/// which must already exist on the server.
+///
+/// libssh2 is blocking, so every call runs on the blocking pool, reconnecting when needed.
+pub struct SftpSource {
+    config: Arc<SftpConfig>,
+    session: Arc<Mutex<Option<Sftp>>>,
+}
+
+struct SftpConfig {
+    host: String,
+    user: String,
+    password: Option<String>,
+    key_file: Option<PathBuf>,
+    dir: PathBuf,
+}
+
+impl SftpSource {
+    pub fn from_env() -> Option<Self> {
+        let host = env::var("INGEST_SFTP_HOST").ok()?;
+        let config = SftpConfig {
+            host: if host.contains(':') { host } else { format!("{}:22", host) },
+            user: env::var("INGEST_SFTP_USER").expect("Missing INGEST_SFTP_USER env var"),
+            password: env::var("INGEST_SFTP_PASSWORD").ok(),
+            key_file: env::var("INGEST_SFTP_KEY_FILE").ok().map(PathBuf::from),
+            dir: PathBuf::from(env::var("INGEST_SFTP_DIR").unwrap_or_else(|_| ".".to_string())),
+        };
+        Some(SftpSource {
+            config: Arc::new(config),
+            session: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    /// Runs `f` against a connected session on the blocking pool, dropping the session on error
+    /// so the next call reconnects.
+    fn with_sftp<'a, T: Send + 'static>(
+        &'a self,
+        f: impl FnOnce(&Sftp, &Path) -> io::Result<T> + Send + 'static,
+    ) -> BoxFuture<'a, io::Result<T>> {
+        let config = self.config.clone();
+        let session = self.session.clone();
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || {
+                let mut session = session.lock().unwrap();
+                if session.is_none() {
+                    *session = Some(connect(&config)?);
+                }
+                let result = f(session.as_ref().expect("connected above"), &config.dir);
+                if result.is_err() {
+                    *session = None;
+                }
+                result
+            })
+            .await
+            .map_err(io::Error::other)?
+        })
+    }
+}
+
+fn connect(config: &SftpConfig) -> io::Result<Sftp> {
+    let tcp = TcpStream::connect(&config.host)?;
+    let mut session = Session::new()?;
+    session.set_tcp_stream(tcp);
+    session.handshake()?;
+    match (&config.key_file, &config.password) {
+        (Some(key_file), _) => session.userauth_pubkey_file(&config.user, None, key_file, config.password.as_deref())?,
+        (None, Some(password)) => session.userauth_password(&config.user, password)?,
+        (None, None) => session.userauth_agent(&config.user)?,
+    }
+    Ok(session.sftp()?)
+}
+
+impl IngestSource for SftpSource {
+    fn describe(&self) -> String {
+        format!("sftp://{}/{}", self.config.host, self.config.dir.display())
+    }
+
+    fn list(&self) -> BoxFuture<'_, io::Result<Vec<RemoteFile>>> {
+        self.with_sftp(|sftp, dir| {
+            Ok(sftp
+                .readdir(dir)?
+                .into_iter()
+                .filter(|(_, stat)| stat.is_file())
+                .filter_map(|(path, stat)| {
+                    Some(RemoteFile {
+                        name: path.file_name()?.to_str()?.to_string(),
+                        size: stat.size.unwrap_or_default(),
+                    })
+                })
+                .collect())
+        })
+    }
+
+    fn fetch<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<Vec<u8>>> {
+        let name = name.to_string();
+        self.with_sftp(move |sftp, dir| {
+            let mut bytes = Vec::new();
+            sftp.open(dir.join(&name))?.read_to_end(&mut bytes)?;
+            Ok(bytes)
+        })
+    }
+
+    fn finish<'a>(&'a self, name: &'a str, succeeded: bool) -> BoxFuture<'a, io::Result<()>> {
+        let name = name.to_string();
+        self.with_sftp(move |sftp, dir| {
+            let target = dir.join(if succeeded { "processed" } else { "failed" }).join(&name);
+            Ok(sftp.rename(&dir.join(&name), &target, None)?)
+        })
+    }
+}
@@ -0,0 +1,266 @@
+// api/src/inline_upload.rs
+
+//! `POST /upload-inline`: one image sent as base64 in a JSON body, `{"filename": "a.jpg", "data":
+//! "..."}`, for clients that can't build a multipart body, such as webhook senders and serverless
+//! functions. `data` may also be a `data:` URL. The query options are those of `/upload`, and the
+//! image goes through the same checks, tenant policy, duplicate handling and pipeline as an
+//! `/upload` part; the answer is the same report. The decoded image is held to `MAX_INLINE_BYTES`
+//! (default 1 MiB), since base64 makes it a third larger and the whole body is held in memory.
+
+use azure_core::base64;
+use image_resize_core::{
+    customer_keys,
+    failover::{self, Location},
+    filenames,
+    image_checks::{self, Invalid},
+    models::{Duplicate, PartReport, UploadReport},
+    output_format::OutputFormat,
+    storage, telemetry, trailing_data,
+    warnings::{self, Warning},
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeMap, sync::Arc};
+use uuid::Uuid;
+use warp::{http::StatusCode, Rejection, Reply};
+use tracing::{error, info};
+
+use crate::{
+    container_client, container_client_at, container_client_for, count_upload, dry_run, duplicates,
+    error::ApiError,
+    fan_out,
+    limit::BodyLimits,
+    naming,
+    notify::Notifier,
+    original_content_type, plan_upload, queue_upload, quota,
+    tenant::{DuplicatePolicy, Tenant},
+    upload_token::{self, UploadClaims},
+    UploadOptions,
+};
+
+/// Room in the body for the filename and the JSON around the data.
+const ENVELOPE_BYTES: u64 = 4096;
+
+/// Body of `POST /upload-inline`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct InlineUpload {
+    filename: String,
+    /// The image in standard base64, or a `data:` URL holding it.
+    data: String,
+}
+
+fn reject(status: StatusCode, message: impl Into<String>) -> Rejection {
+    warp::reject::custom(ApiError::new(status, message))
+}
+
+/// Largest body taking an image of `max_inline_bytes`, for the `Content-Length` check.
+pub fn max_body_bytes(limits: &BodyLimits) -> u64 {
+    (limits.max_inline_bytes as u64).div_ceil(3) * 4 + ENVELOPE_BYTES
+}
+
+/// The bytes `data` encodes, of at most `max_bytes`.
+fn decode(data: &str, max_bytes: usize) -> Result<Vec<u8>, Rejection> {
+    let encoded = match data.strip_prefix("data:") {
+        Some(url) => match url.split_once(',') {
+            Some((media_type, encoded)) if media_type.ends_with(";base64") => encoded,
+            _ => return Err(reject(StatusCode::BAD_REQUEST, "data must be a base64 data: URL")),
+        },
+        None => data,
+    };
+    // the decoded size is known before decoding, so an oversized image is never allocated
+    let encoded = encoded.trim();
+    if encoded.len() / 4 * 3 > max_bytes + 2 {
+        return Err(reject(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("The image is larger than the limit of {} bytes", max_bytes),
+        ));
+    }
+    let bytes = base64::decode(encoded).map_err(|_| reject(StatusCode::BAD_REQUEST, "data is not valid base64"))?;
+    if bytes.len() > max_bytes {
+        return Err(reject(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("The image is larger than the limit of {} bytes", max_bytes),
+        ));
+    }
+    Ok(bytes)
+}
+
+pub async fn upload_inline(
+    options: UploadOptions,
+    tenant: Option<Tenant>,
+    token: Option<UploadClaims>,
+    mut limits: BodyLimits,
+    upload: InlineUpload,
+    notifier: Arc<Notifier>,
+) -> Result<impl Reply, Rejection> {
+    let tenant = tenant.or_else(|| token.as_ref().map(upload_token::UploadClaims::as_tenant));
+    if let Some(token) = &token {
+        limits.max_inline_bytes = limits.max_inline_bytes.min(token.max_bytes);
+    }
+    let filename = filenames::normalize(&upload.filename)
+        .map_err(|reason| reject(StatusCode::BAD_REQUEST, format!("Invalid filename '{}': {}", upload.filename, reason)))?;
+    let mut bytes = decode(&upload.data, limits.max_inline_bytes)?;
+    drop(upload);
+
+    let plan = plan_upload(&options, tenant.as_ref()).await?;
+    let duplicate_policy = match tenant.as_ref().map_or(DuplicatePolicy::Process, |tenant| tenant.policy.duplicates) {
+        DuplicatePolicy::Process if naming::content_addressed() => DuplicatePolicy::Existing,
+        policy => policy,
+    };
+
+    let invalid = |e: Invalid| {
+        let status = match e {
+            Invalid::Unsupported(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Invalid::TooLarge(_) | Invalid::Empty(_) | Invalid::Truncated(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        };
+        reject(status, e.to_string())
+    };
+    let source_format = OutputFormat::of_source(&bytes);
+    let dimensions = image_checks::check(&filename, &bytes).map_err(invalid)?;
+    if let Some(tenant) = &tenant {
+        tenant
+            .policy
+            .check_format(&filename, &bytes)
+            .map_err(|e| reject(StatusCode::UNSUPPORTED_MEDIA_TYPE, e))?;
+    }
+    image_checks::check_complete(&filename, &bytes).map_err(invalid)?;
+    let received = bytes.len() as u64;
+    let mut part_warnings = Vec::new();
+    trailing_data::check(&filename, &mut bytes).map_err(|e| reject(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    if (bytes.len() as u64) < received {
+        let message = format!("{} bytes after the end of the image were dropped", received - bytes.len() as u64);
+        part_warnings.push(Warning::new(warnings::TRAILING_DATA_STRIPPED, message));
+    }
+    let read = bytes.len();
+    image_checks::strip_exif(&mut bytes);
+    if bytes.len() < read {
+        part_warnings.push(Warning::new(warnings::EXIF_STRIPPED, "The EXIF segment was removed"));
+    }
+    let content_type = original_content_type(&bytes);
+    let part_report = |blob: &str, job_id: Option<String>, warnings: Vec<Warning>| PartReport {
+        filename: filename.clone(),
+        blob: blob.to_string(),
+        bytes: received,
+        content_type: content_type.to_string(),
+        width: dimensions.map(|(width, _)| width),
+        height: dimensions.map(|(_, height)| height),
+        job_id,
+        warnings,
+    };
+
+    let container_client = match &token {
+        Some(token) => container_client_for(&token.container),
+        None => container_client(),
+    };
+    if options.dry_run {
+        let estimate = dry_run::estimate(&plan, &container_client, &filename, &bytes, received).await?;
+        return Ok(warp::reply::json(&serde_json::json!({ "dry_run": true, "plans": [estimate] })).into_response());
+    }
+    quota::charge(tenant.as_ref(), true, received, &notifier).await?;
+    let container_name = container_client.container_name().to_string();
+
+    // identical content already processed is answered with what was made of it, as on `/upload`
+    let mut metadata = plan.blob_metadata();
+    let content_hash = hex::encode(Sha256::digest(&bytes));
+    if duplicate_policy != DuplicatePolicy::Process {
+        if let Some(existing) = duplicates::find(&container_client, &content_hash, &plan.then).await {
+            info!("{} duplicates {}", filename, existing.original.blob);
+            telemetry::track_event("DuplicateUpload", &[("filename", filename.clone())]);
+            if duplicate_policy == DuplicatePolicy::Conflict {
+                let body = serde_json::json!({
+                    "error": format!("'{}' duplicates an existing image", filename),
+                    "filename": filename,
+                    "existing": existing,
+                    "uploaded": Vec::<String>::new(),
+                });
+                return Ok(warp::reply::with_status(warp::reply::json(&body), StatusCode::CONFLICT).into_response());
+            }
+            let report = UploadReport {
+                parts: vec![part_report(&existing.original.blob, None, part_warnings)],
+                duplicates: vec![Duplicate { filename: filename.clone(), existing }],
+                ..Default::default()
+            };
+            return Ok(warp::reply::json(&report).into_response());
+        }
+        duplicates::stamp(&mut metadata, &content_hash);
+    }
+    let blob_name = naming::original_name(&container_client, &filename, Some(&content_hash))
+        .await
+        .map_err(warp::reject::custom)?;
+
+    let (blob_name, location) = if storage::kind() == storage::BackendKind::Azure {
+        let customer_key = customer_keys::customer_key(tenant.as_ref().map(|tenant| tenant.id.as_str()));
+        let (blob_name, (_, location)) = naming::store(&container_client, &filename, Some(&content_hash), blob_name, |blob_name, exclusive| {
+            let mut metadata = metadata.clone();
+            if blob_name != filename {
+                naming::stamp(&mut metadata, &filename);
+            }
+            let (container_name, bytes, customer_key, plan) = (&container_name, &bytes, &customer_key, &plan);
+            failover::write(move |location| {
+                let mut upload = container_client_at(container_name, location)
+                    .blob_client(&blob_name)
+                    .put_block_blob(bytes.clone())
+                    .content_type(content_type)
+                    .metadata(metadata.clone())
+                    .tags(plan.blob_tags());
+                if let Some(customer_key) = customer_key {
+                    upload = upload.encryption_key(customer_key.clone());
+                }
+                if exclusive {
+                    upload = upload.if_match(storage::if_none());
+                }
+                let upload = upload.into_future();
+                telemetry::dependency("Azure blob", container_name, "put_block_blob", upload)
+            })
+        })
+        .await
+        .map_err(warp::reject::custom)?;
+        if let Err(e) = failover::record(&container_name, &blob_name, location).await {
+            error!("Error recording the location of {}: {:?}", blob_name, e);
+        }
+        (blob_name, location)
+    } else {
+        let backend = storage::from_env(&container_client);
+        let (blob_name, ()) = naming::store(&container_client, &filename, Some(&content_hash), blob_name, |blob_name, exclusive| {
+            let (backend, bytes) = (&backend, bytes.clone());
+            async move {
+                match exclusive {
+                    true => backend.put_new(&blob_name, bytes, content_type).await,
+                    false => backend.put(&blob_name, bytes, content_type).await,
+                }
+            }
+        })
+        .await
+        .map_err(warp::reject::custom)?;
+        (blob_name, Location::Primary)
+    };
+    if duplicate_policy != DuplicatePolicy::Process {
+        duplicates::record(&container_name, &content_hash, &blob_name).await;
+    }
+    info!("Stored inline upload {} as {}", filename, blob_name);
+
+    let mut image = plan.message(blob_name.clone(), container_name, location);
+    image.pixels = dimensions.map(|(width, height)| width as u64 * height as u64);
+    image.content_hash = Some(content_hash);
+    fan_out(&mut image, source_format);
+    let job_id = queue_upload(&plan, image, Uuid::new_v4()).await.map_err(|e| {
+        error!("Error enqueueing {}: {:?}", blob_name, e);
+        reject(StatusCode::BAD_GATEWAY, "Failed to queue the image for processing")
+    })?;
+    telemetry::track_event("ImageUploaded", &[("filename", filename.clone())]);
+    count_upload("upload-inline", received);
+
+    let mut blobs = BTreeMap::new();
+    if blob_name != filename {
+        blobs.insert(filename.clone(), blob_name.clone());
+    }
+    let report = UploadReport {
+        uploaded: vec![filename.clone()],
+        jobs: BTreeMap::from([(filename.clone(), job_id.to_string())]),
+        blobs,
+        parts: vec![part_report(&blob_name, Some(job_id.to_string()), part_warnings)],
+        ..Default::default()
+    };
+    Ok(warp::reply::json(&report).into_response())
+}
@@ -0,0 +1,182 @@
+// api/src/ip_filter.rs
+
+use ipnet::IpNet;
+use std::{env, net::IpAddr, net::SocketAddr, sync::Arc};
+use warp::{http::StatusCode, Filter, Rejection};
+use tracing::warn;
+
+use crate::error::ApiError;
+
+/// CIDR based access rules for the admin and upload routes.
+#[derive(Debug, Default)]
+pub struct IpPolicy {
+    /// When non-empty, only these networks may call the guarded routes.
+    allow: Vec<IpNet>,
+    /// Always refused, checked before `allow`.
+    deny: Vec<IpNet>,
+    /// Proxies whose `X-Forwarded-For` header is believed.
+    trusted_proxies: Vec<IpNet>,
+}
+
+impl IpPolicy {
+    /// Reads comma separated CIDR lists from `IP_ALLOW`, `IP_DENY` and `TRUSTED_PROXIES`.
+    pub fn from_env() -> Self {
+        IpPolicy {
+            allow: cidr_list("IP_ALLOW"),
+            deny: cidr_list("IP_DENY"),
+            trusted_proxies: cidr_list("TRUSTED_PROXIES"),
+        }
+    }
+
+    fn is_trusted_proxy(&self, ip: &IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
+
+    /// Resolves the client address: the peer itself, unless the peer is a trusted proxy, in which
+    /// case the right-most `X-Forwarded-For` entry that isn't another trusted proxy.
+    fn client_ip(&self, peer: IpAddr, forwarded_for: Option<&str>) -> IpAddr {
+        if !self.is_trusted_proxy(&peer) {
+            return peer;
+        }
+
+        let mut client = peer;
+        if let Some(forwarded_for) = forwarded_for {
+            for hop in forwarded_for.rsplit(',') {
+                match hop.trim().parse::<IpAddr>() {
+                    Ok(ip) => {
+                        client = ip;
+                        if !self.is_trusted_proxy(&ip) {
+                            break;
+                        }
+                    }
+                    // an unparsable hop means we can't trust anything further left
+                    Err(_) => break,
+                }
+            }
+        }
+        client
+    }
+
+    fn permits(&self, ip: &IpAddr) -> bool {
+        if self.deny.iter().any(|net| net.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip))
+    }
+}
+
+fn cidr_list(key: &str) -> Vec<IpNet> {
+    parse_cidrs(key, &env::var(key).unwrap_or_default())
+}
+
+/// Reads the comma separated networks `list` of the setting `key`, panicking on an invalid one.
+fn parse_cidrs(key: &str, list: &str) -> Vec<IpNet> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            // accept bare addresses as single-host networks
+            s.parse::<IpNet>()
+                .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+                .unwrap_or_else(|_| panic!("Invalid CIDR '{}' in {}", s, key))
+        })
+        .collect()
+}
+
+/// Rejects with 403 when the caller's address isn't permitted by `policy`.
+pub fn guard(policy: Arc<IpPolicy>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and_then(move |remote: Option<SocketAddr>, forwarded_for: Option<String>| {
+            let policy = policy.clone();
+            async move {
+                let Some(remote) = remote else {
+                    return Err(warp::reject::custom(ApiError::new(StatusCode::FORBIDDEN, "Unknown client address")));
+                };
+
+                let client = policy.client_ip(remote.ip(), forwarded_for.as_deref());
+                if policy.permits(&client) {
+                    Ok(())
+                } else {
+                    warn!("Refused request from {}", client);
+                    Err(warp::reject::custom(ApiError::new(StatusCode::FORBIDDEN, "Forbidden")))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn policy(allow: &str, deny: &str, trusted_proxies: &str) -> IpPolicy {
+        IpPolicy {
+            allow: parse_cidrs("IP_ALLOW", allow),
+            deny: parse_cidrs("IP_DENY", deny),
+            trusted_proxies: parse_cidrs("TRUSTED_PROXIES", trusted_proxies),
+        }
+    }
+
+    #[test]
+    fn parses_networks_and_bare_addresses() {
+        let nets = parse_cidrs("IP_ALLOW", " 10.0.0.0/8, 192.168.1.7 ,,2001:db8::/32, ::1 ");
+        let expected: Vec<IpNet> = ["10.0.0.0/8", "192.168.1.7/32", "2001:db8::/32", "::1/128"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        assert_eq!(nets, expected);
+        assert!(parse_cidrs("IP_ALLOW", "").is_empty());
+        assert!(parse_cidrs("IP_ALLOW", " , ").is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid CIDR '10.0.0.0/33' in IP_ALLOW")]
+    fn refuses_prefixes_too_long() {
+        parse_cidrs("IP_ALLOW", "10.0.0.0/33");
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid CIDR 'example.com' in IP_DENY")]
+    fn refuses_names() {
+        parse_cidrs("IP_DENY", "10.0.0.0/8,example.com");
+    }
+
+    #[test]
+    fn matches_networks_by_prefix() {
+        let policy = policy("10.0.0.0/8, 2001:db8::/32", "10.1.0.0/16", "");
+        assert!(policy.permits(&ip("10.0.0.1")));
+        assert!(policy.permits(&ip("10.255.255.255")));
+        assert!(policy.permits(&ip("2001:db8::1")));
+        assert!(!policy.permits(&ip("11.0.0.0")));
+        assert!(!policy.permits(&ip("2001:db9::1")));
+        // deny wins over allow
+        assert!(!policy.permits(&ip("10.1.2.3")));
+        // an empty allow list lets everyone not denied in
+        let open = self::policy("", "192.168.0.0/16", "");
+        assert!(open.permits(&ip("8.8.8.8")));
+        assert!(!open.permits(&ip("192.168.4.4")));
+    }
+
+    #[test]
+    fn believes_forwarded_for_only_from_trusted_proxies() {
+        let policy = policy("", "", "10.0.0.0/8");
+        // a peer that isn't a trusted proxy is the client, whatever it claims
+        assert_eq!(policy.client_ip(ip("203.0.113.9"), Some("198.51.100.1")), ip("203.0.113.9"));
+        // the right-most hop that isn't a trusted proxy
+        assert_eq!(policy.client_ip(ip("10.0.0.1"), Some("198.51.100.1")), ip("198.51.100.1"));
+        assert_eq!(policy.client_ip(ip("10.0.0.1"), Some("1.1.1.1, 198.51.100.1, 10.0.0.2")), ip("198.51.100.1"));
+        assert_eq!(policy.client_ip(ip("10.0.0.1"), Some(" 2001:db8::7 ,10.0.0.3")), ip("2001:db8::7"));
+        // no header, or only trusted proxies in it
+        assert_eq!(policy.client_ip(ip("10.0.0.1"), None), ip("10.0.0.1"));
+        assert_eq!(policy.client_ip(ip("10.0.0.1"), Some("10.0.0.2, 10.0.0.3")), ip("10.0.0.2"));
+        // nothing left of an unparsable hop is believed
+        assert_eq!(policy.client_ip(ip("10.0.0.1"), Some("1.1.1.1, unknown, 10.0.0.2")), ip("10.0.0.2"));
+        assert_eq!(policy.client_ip(ip("10.0.0.1"), Some("1.1.1.1, 198.51.100.1:443")), ip("10.0.0.1"));
+        assert_eq!(policy.client_ip(ip("10.0.0.1"), Some("")), ip("10.0.0.1"));
+    }
+}
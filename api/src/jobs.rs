@@ -0,0 +1,167 @@
+// api/src/jobs.rs
+
+//! `GET /jobs/{id}`: the state of the job an upload's reply named, and signed URLs of the blobs it
+//! wrote so far. A tenant's job is only found by that tenant.
+//!
+//! `GET /jobs/{id}/events`: the same as server-sent events, a `rendition` event with the signed
+//! `url` of each blob as it shows up in the job's outputs and a `state` event each time its state
+//! changes. The job is read every `JOB_EVENTS_POLL_MS` (default 1000), and the stream ends once
+//! the job is done, partially complete or failed, or after `JOB_EVENTS_MAX_SECS` (default 600).
+//! Each `rendition` event's id is the number of outputs sent so far, so a client reconnecting with
+//! `Last-Event-ID` is sent only the ones it missed. Uploaded with `incremental=true`, a job adds
+//! each rendition as soon as it's stored, the variants smallest first, so a UI can show the
+//! smallest thumbnail before the others are done; otherwise they come when the stage ends.
+//!
+//! `DELETE /jobs/{name}`: cancels the processing queued for the original `name`. The cancellation
+//! is recorded in the job status table, see `core/src/job_status.rs`; the worker checks it before
+//! running each stage and between the steps of one, abandons the job's messages queued before it
+//! and enqueues none of its remaining stages. Renditions already written are kept.
+
+use futures::{stream, Stream, StreamExt};
+use image_resize_core::{
+    filenames,
+    job_status::{self, JobRecord, JobState},
+    models::JobCancellation,
+    sas, telemetry,
+};
+use std::{
+    convert::Infallible,
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+use tracing::{error, info, warn};
+use warp::{http::StatusCode, sse::Event, Rejection, Reply};
+
+use crate::{error::ApiError, limit, report, tenant::Tenant};
+
+const DEFAULT_POLL_MS: u64 = 1000;
+const DEFAULT_MAX_SECS: u64 = 600;
+
+pub async fn cancel_job(name: String, tenant: Option<Tenant>) -> Result<impl Reply, Rejection> {
+    let name = filenames::from_path(&name)
+        .ok_or_else(|| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, "Invalid blob name")))?;
+    let (container_client, _) = report::find_original(&name, tenant.as_ref()).await?;
+
+    let cancellation = job_status::cancel(container_client.container_name(), &name).await.map_err(|e| {
+        error!("Error cancelling the jobs of {}: {:?}", name, e);
+        warp::reject::custom(ApiError::storage(&e, "Failed to reach table storage"))
+    })?;
+    info!("Cancelled the jobs of {}", name);
+    telemetry::track_event("JobCancellationRequested", &[("filename", name.clone())]);
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&JobCancellation {
+            name,
+            cancelled_at: cancellation.cancelled_at,
+        }),
+        StatusCode::ACCEPTED,
+    ))
+}
+
+/// Job `id`, if `tenant` may see it.
+async fn find_job(id: Uuid, tenant: Option<&Tenant>) -> Result<JobRecord, Rejection> {
+    let record = job_status::job(&id.to_string()).await.map_err(|e| {
+        error!("Error reading job {}: {:?}", id, e);
+        warp::reject::custom(ApiError::storage(&e, "Failed to reach table storage"))
+    })?;
+    // a tenant's job is as unknown as a missing one to anyone but the tenant, as in `content.rs`
+    match record {
+        Some(record) if record.tenant.as_deref() == tenant.map(|tenant| tenant.id.as_str()) => Ok(record),
+        _ => Err(warp::reject::custom(ApiError::new(StatusCode::NOT_FOUND, "Unknown job id"))),
+    }
+}
+
+/// `output` of job `id` with a signature to read it, or as it is if it can't be signed.
+async fn signed(id: &str, output: &str) -> String {
+    sas::sign_url(output).await.unwrap_or_else(|e| {
+        error!("Error signing output {} of job {}: {:?}", output, id, e);
+        output.to_string()
+    })
+}
+
+pub async fn get_job(id: Uuid, tenant: Option<Tenant>) -> Result<impl Reply, Rejection> {
+    let mut job = find_job(id, tenant.as_ref()).await?.to_job();
+    for output in &mut job.outputs {
+        *output = signed(&job.id, output).await;
+    }
+    Ok(warp::reply::json(&job))
+}
+
+/// Where a stream of job events stands.
+struct Follower {
+    id: String,
+    /// Outputs sent so far.
+    sent: usize,
+    state: Option<JobState>,
+    poll: Duration,
+    deadline: Instant,
+    first: bool,
+    ended: bool,
+}
+
+impl Follower {
+    /// The events since the last poll, waiting for some; `None` once the stream ends.
+    async fn next(mut self) -> Option<(Vec<Event>, Self)> {
+        loop {
+            if self.ended || Instant::now() >= self.deadline {
+                return None;
+            }
+            if !self.first {
+                tokio::time::sleep(self.poll).await;
+            }
+            self.first = false;
+            let record = match job_status::job(&self.id).await {
+                Ok(Some(record)) => record,
+                Ok(None) => return None,
+                Err(e) => {
+                    warn!("Error reading job {} for its events: {:?}", self.id, e);
+                    continue;
+                }
+            };
+            let mut events = Vec::new();
+            for output in record.outputs().iter().skip(self.sent) {
+                self.sent += 1;
+                let url = signed(&self.id, output).await;
+                events.push(
+                    Event::default()
+                        .id(self.sent.to_string())
+                        .event("rendition")
+                        .json_data(serde_json::json!({ "url": url }))
+                        .expect("Failed to serialize event"),
+                );
+            }
+            if self.state != Some(record.state) {
+                self.state = Some(record.state);
+                let error = (!record.error.is_empty()).then_some(record.error.as_str());
+                events.push(
+                    Event::default()
+                        .event("state")
+                        .json_data(serde_json::json!({ "state": record.state, "error": error }))
+                        .expect("Failed to serialize event"),
+                );
+            }
+            self.ended = matches!(record.state, JobState::Done | JobState::PartiallyComplete | JobState::Failed);
+            if !events.is_empty() {
+                return Some((events, self));
+            }
+        }
+    }
+}
+
+fn events(follower: Follower) -> impl Stream<Item = Result<Event, Infallible>> {
+    stream::unfold(follower, Follower::next).flat_map(|events| stream::iter(events.into_iter().map(Ok)))
+}
+
+pub async fn job_events(id: Uuid, tenant: Option<Tenant>, last_event_id: Option<usize>) -> Result<impl Reply, Rejection> {
+    let record = find_job(id, tenant.as_ref()).await?;
+    let follower = Follower {
+        id: record.id,
+        sent: last_event_id.unwrap_or_default(),
+        state: None,
+        poll: Duration::from_millis(limit::env_or("JOB_EVENTS_POLL_MS", DEFAULT_POLL_MS).max(1)),
+        deadline: Instant::now() + Duration::from_secs(limit::env_or("JOB_EVENTS_MAX_SECS", DEFAULT_MAX_SECS)),
+        first: true,
+        ended: false,
+    };
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(events(follower))))
+}
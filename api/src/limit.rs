@@ -0,0 +1,251 @@
+// api/src/limit.rs
+
+//! Limits on what requests may take: the size of bodies and files, the uploads buffered at once and
+//! each caller's requests per minute. They start from the environment and can be changed while the
+//! API runs through `PUT /admin/limits`, which takes any of the fields `GET /admin/limits` shows
+//! except `block_bytes`. An update is checked as a whole and applied at once, so requests see
+//! either the old limits or the new ones, never a mix; requests already running keep the limits
+//! they started with. Changes last until the API restarts.
+
+use image_resize_core::telemetry;
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+};
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+use tracing::info;
+
+use crate::{error::ApiError, memory::{self, Reservation}};
+
+const DEFAULT_UPLOAD_CONCURRENCY: usize = 16;
+const DEFAULT_MAX_REQUEST_BYTES: u64 = 100 * 1024 * 1024;
+const DEFAULT_MAX_PART_BYTES: usize = 5 * 1024 * 1024;
+const DEFAULT_MAX_STREAMED_PART_BYTES: usize = 100 * 1024 * 1024;
+const DEFAULT_BLOCK_BYTES: usize = 4 * 1024 * 1024;
+const DEFAULT_MAX_PARTS: usize = 10;
+const DEFAULT_MAX_ZIP_BYTES: u64 = 50 * 1024 * 1024;
+const DEFAULT_MAX_ZIP_ENTRIES: usize = 500;
+const DEFAULT_MAX_INLINE_BYTES: usize = 1024 * 1024;
+
+/// Size limits applied while streaming a multipart upload.
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct BodyLimits {
+    /// Whole request body, from `MAX_REQUEST_BYTES`.
+    pub max_request_bytes: u64,
+    /// Single file held in memory, from `MAX_PART_BYTES`.
+    pub max_part_bytes: usize,
+    /// Single `/upload` part streamed into storage block by block, from `MAX_STREAMED_PART_BYTES`.
+    pub max_streamed_part_bytes: usize,
+    /// Blocks `/upload` parts are streamed in, from `UPLOAD_BLOCK_BYTES`.
+    pub block_bytes: usize,
+    /// Number of parts, from `MAX_PARTS`.
+    pub max_parts: usize,
+    /// Whole archive sent to `/upload/zip`, from `MAX_ZIP_BYTES`; its files are held to `max_part_bytes`.
+    pub max_zip_bytes: u64,
+    /// Files in one archive, from `MAX_ZIP_ENTRIES`.
+    pub max_zip_entries: usize,
+    /// Image sent as base64 to `/upload-inline`, once decoded, from `MAX_INLINE_BYTES`.
+    pub max_inline_bytes: usize,
+}
+
+impl BodyLimits {
+    pub fn from_env() -> Self {
+        BodyLimits {
+            max_request_bytes: env_or("MAX_REQUEST_BYTES", DEFAULT_MAX_REQUEST_BYTES),
+            max_part_bytes: env_or("MAX_PART_BYTES", DEFAULT_MAX_PART_BYTES),
+            max_streamed_part_bytes: env_or("MAX_STREAMED_PART_BYTES", DEFAULT_MAX_STREAMED_PART_BYTES),
+            block_bytes: env_or("UPLOAD_BLOCK_BYTES", DEFAULT_BLOCK_BYTES).max(1),
+            max_parts: env_or("MAX_PARTS", DEFAULT_MAX_PARTS),
+            max_zip_bytes: env_or("MAX_ZIP_BYTES", DEFAULT_MAX_ZIP_BYTES),
+            max_zip_entries: env_or("MAX_ZIP_ENTRIES", DEFAULT_MAX_ZIP_ENTRIES),
+            max_inline_bytes: env_or("MAX_INLINE_BYTES", DEFAULT_MAX_INLINE_BYTES),
+        }
+    }
+}
+
+pub fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    env::var(key).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+/// The limits in force, see [`RuntimeLimits`].
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct Tunables {
+    #[serde(flatten)]
+    pub body: BodyLimits,
+    /// Requests buffering whole images at once, from `UPLOAD_CONCURRENCY` (default 16).
+    pub upload_concurrency: usize,
+    /// Requests per minute of each key or token subject, from `RATE_LIMIT_PER_MINUTE`; 0 for none.
+    pub rate_limit_per_minute: u32,
+}
+
+/// Body of `PUT /admin/limits`; fields left out keep their value.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsUpdate {
+    max_request_bytes: Option<u64>,
+    max_part_bytes: Option<usize>,
+    max_streamed_part_bytes: Option<usize>,
+    max_parts: Option<usize>,
+    max_zip_bytes: Option<u64>,
+    max_zip_entries: Option<usize>,
+    max_inline_bytes: Option<usize>,
+    upload_concurrency: Option<usize>,
+    rate_limit_per_minute: Option<u32>,
+}
+
+/// The limits shared by every request, and the uploads buffered right now.
+pub struct RuntimeLimits {
+    current: RwLock<Tunables>,
+    uploads: AtomicUsize,
+}
+
+impl RuntimeLimits {
+    pub fn from_env() -> Arc<Self> {
+        let upload_concurrency = env_or("UPLOAD_CONCURRENCY", DEFAULT_UPLOAD_CONCURRENCY);
+        info!("Limiting buffered requests to {} at a time", upload_concurrency);
+        Arc::new(RuntimeLimits {
+            current: RwLock::new(Tunables {
+                body: BodyLimits::from_env(),
+                upload_concurrency,
+                rate_limit_per_minute: env_or("RATE_LIMIT_PER_MINUTE", 0),
+            }),
+            uploads: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn get(&self) -> Tunables {
+        *self.current.read().unwrap()
+    }
+
+    pub fn body(&self) -> BodyLimits {
+        self.get().body
+    }
+
+    /// Applies `update` if every value in it is acceptable, returning the limits now in force.
+    pub fn update(&self, update: LimitsUpdate) -> Result<Tunables, String> {
+        let positive = [
+            ("max_request_bytes", update.max_request_bytes.map(|v| v as usize)),
+            ("max_part_bytes", update.max_part_bytes),
+            ("max_streamed_part_bytes", update.max_streamed_part_bytes),
+            ("max_parts", update.max_parts),
+            ("max_zip_bytes", update.max_zip_bytes.map(|v| v as usize)),
+            ("max_zip_entries", update.max_zip_entries),
+            ("max_inline_bytes", update.max_inline_bytes),
+        ];
+        if let Some((name, _)) = positive.iter().find(|(_, value)| *value == Some(0)) {
+            return Err(format!("{} must be positive", name));
+        }
+        let mut current = self.current.write().unwrap();
+        let mut limits = *current;
+        let body = &mut limits.body;
+        body.max_request_bytes = update.max_request_bytes.unwrap_or(body.max_request_bytes);
+        body.max_part_bytes = update.max_part_bytes.unwrap_or(body.max_part_bytes);
+        body.max_streamed_part_bytes = update.max_streamed_part_bytes.unwrap_or(body.max_streamed_part_bytes);
+        body.max_parts = update.max_parts.unwrap_or(body.max_parts);
+        body.max_zip_bytes = update.max_zip_bytes.unwrap_or(body.max_zip_bytes);
+        body.max_zip_entries = update.max_zip_entries.unwrap_or(body.max_zip_entries);
+        body.max_inline_bytes = update.max_inline_bytes.unwrap_or(body.max_inline_bytes);
+        limits.upload_concurrency = update.upload_concurrency.unwrap_or(limits.upload_concurrency);
+        limits.rate_limit_per_minute = update.rate_limit_per_minute.unwrap_or(limits.rate_limit_per_minute);
+        *current = limits;
+        Ok(limits)
+    }
+
+    /// Takes one of the `upload_concurrency` slots, if one is free.
+    fn try_acquire(self: &Arc<Self>) -> Option<Slot> {
+        let limit = self.get().upload_concurrency;
+        self.uploads
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |uploads| (uploads < limit).then_some(uploads + 1))
+            .ok()
+            .map(|_| Slot(self.clone()))
+    }
+}
+
+/// The size limits in force when a request comes in.
+pub fn body_limits(limits: Arc<RuntimeLimits>) -> impl Filter<Extract = (BodyLimits,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || limits.body())
+}
+
+/// Refuses bodies without a `Content-Length` or longer than the limit `max` picks, like
+/// `warp::body::content_length_limit` but reading the limit in force.
+pub fn content_length(
+    limits: Arc<RuntimeLimits>,
+    max: fn(&BodyLimits) -> u64,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<u64>("content-length")
+        .and_then(move |content_length: Option<u64>| {
+            let max = max(&limits.body());
+            async move {
+                match content_length {
+                    None => Err(warp::reject::custom(ApiError::new(StatusCode::LENGTH_REQUIRED, "A Content-Length is required"))),
+                    Some(length) if length > max => Err(warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, "Payload too large"))),
+                    Some(_) => Ok(()),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// `GET /admin/limits`.
+pub async fn get_limits(limits: Arc<RuntimeLimits>) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&limits.get()))
+}
+
+/// `PUT /admin/limits`.
+pub async fn put_limits(update: LimitsUpdate, limits: Arc<RuntimeLimits>) -> Result<impl Reply, Rejection> {
+    let requested = format!("{:?}", update);
+    let updated = limits
+        .update(update)
+        .map_err(|e| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, e)))?;
+    info!("Changed the limits with {}, now {:?}", requested, updated);
+    telemetry::track_event("LimitsChanged", &[("update", requested)]);
+    Ok(warp::reply::json(&updated))
+}
+
+/// One of the buffered uploads counted against `upload_concurrency`, given back on drop.
+struct Slot(Arc<RuntimeLimits>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.uploads.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// A slot of the upload concurrency and the memory reserved for the request's body.
+pub struct Permit {
+    _slot: Slot,
+    _reservation: Reservation,
+}
+
+/// Takes a permit without waiting, rejecting with 503 when the limit is reached so
+/// queued requests can't pile up their bodies in memory, or when memory is short, see `memory.rs`.
+pub fn permit(limits: Arc<RuntimeLimits>) -> impl Filter<Extract = (Permit,), Error = Rejection> + Clone {
+    warp::header::optional::<u64>("content-length").and_then(move |content_length: Option<u64>| {
+        let limits = limits.clone();
+        async move {
+            let slot = limits.try_acquire().ok_or_else(|| {
+                warp::reject::custom(ApiError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Too many concurrent uploads, retry later",
+                ))
+            })?;
+            let reservation = memory::reserve(content_length.unwrap_or(0)).map_err(warp::reject::custom)?;
+            Ok::<_, Rejection>(Permit {
+                _slot: slot,
+                _reservation: reservation,
+            })
+        }
+    })
+}
+
+/// Keeps `permit` alive until `handler` completes.
+pub async fn hold<T>(permit: Permit, handler: impl Future<Output = T>) -> T {
+    let result = handler.await;
+    drop(permit);
+    result
+}
@@ -1,132 +1,1534 @@
 // api/src/main.rs
 
-use azure_messaging_servicebus::service_bus::QueueClient;
-use azure_storage::StorageCredentials;
-use azure_storage_blobs::prelude::ClientBuilder;
-use bytes::BufMut;
-use futures::TryStreamExt;
-use serde::{Deserialize, Serialize};
+// the chain of `or`ed routes outgrows the default when checking that the server future is `Send`
+#![recursion_limit = "256"]
+
+mod atomic;
+mod auth;
+mod backfill;
+mod batch;
+mod compare;
+mod config_check;
+mod container_access;
+mod content;
+mod copy;
+mod dry_run;
+mod duplicates;
+mod error;
+mod export;
+mod feed;
+mod hints;
+mod i18n;
+mod images;
+mod import;
+mod inline_upload;
+mod ingest;
+mod ip_filter;
+mod jobs;
+mod limit;
+mod memory;
+mod metadata;
+mod naming;
+mod notify;
+mod paging;
+mod part_stream;
+mod progress;
+mod provenance;
+mod quota;
+mod reconcile;
+mod regenerate;
+mod report;
+mod reprocess;
+mod retention;
+mod review;
+mod s3;
+mod search;
+mod tenant;
+mod timeout;
+mod tus;
+mod upload_gc;
+mod upload_token;
+mod usage;
+mod webhook_verify;
+mod zip_upload;
+
+use azure_core::date;
+use azure_storage_blobs::prelude::{BlobBlockType, BlobClient, BlockList, ContainerClient};
+use futures::{StreamExt, TryStreamExt};
+use sha2::{Digest, Sha256};
 use warp::{
     http::StatusCode,
-    multipart::{FormData, Part},
+    multipart::FormData,
     Filter, Rejection, Reply,
 };
-use std::{convert::Infallible, env};
+use std::{collections::{BTreeMap, HashMap}, convert::Infallible, env, sync::Arc};
+use error::ApiError;
+use image_resize_core::{blob_tags, build_info, capabilities, chaos, crop::{Crop, FocalPoint}, clients, config, customer_keys, failover::{self, Location}, features, geo_read, health, image_checks::{self, Invalid}, job_status, logging, metrics, migrations, message::{ImageMessage, Priority, Stage, DEFAULT_SIZE}, models::{Duplicate, PartReport, ReviewDecision, UploadOptions, UploadReport}, operations::{self, Operation}, output_format::OutputFormat, pdf, queue::QueueSender, resize_spec::{self, Fit, ResizeSpec}, storage, svg, telemetry, tiers, trace, trailing_data, variants::{self, Variant}, video, warnings::{self, Warning}, webhook};
+use limit::BodyLimits;
+use part_stream::PartStream;
+use progress::ProgressRegistry;
+use tenant::DuplicatePolicy;
+use time::OffsetDateTime;
+use uuid::Uuid;
+use tracing::{error, info};
+
+const MAX_TAGS: usize = 16;
+const MAX_TAG_LEN: usize = 32;
+/// Largest form field read from an `/upload` body, e.g. its `operations`.
+const MAX_FIELD_BYTES: usize = 64 * 1024;
+
+/// Parsing of the options an upload was sent with, see `core/src/models.rs`.
+trait ParseOptions {
+    fn follow_up_stages(&self) -> Result<Vec<Stage>, String>;
+    fn tags(&self) -> Result<Vec<String>, String>;
+    fn target_size(&self) -> Result<Option<u64>, String>;
+    fn resize_spec(&self) -> Result<Option<ResizeSpec>, String>;
+    fn fit(&self) -> Result<Option<Fit>, String>;
+    fn filter(&self) -> Result<Option<resize_spec::Filter>, String>;
+    fn variants(&self) -> Result<Vec<Variant>, String>;
+    fn output_format(&self) -> Result<Option<OutputFormat>, String>;
+    fn crop(&self) -> Result<Option<Crop>, String>;
+    fn focal_point(&self) -> Result<Option<FocalPoint>, String>;
+    fn metadata(&self) -> Result<BTreeMap<String, String>, String>;
+    fn callback_url(&self) -> Result<Option<String>, String>;
+    fn priority(&self) -> Result<Priority, String>;
+}
+
+impl ParseOptions for UploadOptions {
+    fn follow_up_stages(&self) -> Result<Vec<Stage>, String> {
+        match &self.then {
+            Some(then) => then.split(',').map(|s| s.trim().parse()).collect(),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Lowercased, de-duplicated tags made of letters, digits, `-` and `_`.
+    fn tags(&self) -> Result<Vec<String>, String> {
+        let mut tags: Vec<String> = Vec::new();
+        for tag in self.tags.iter().flat_map(|t| t.split(',')).map(|t| t.trim().to_lowercase()) {
+            if tag.is_empty() || tags.contains(&tag) {
+                continue;
+            }
+            if tag.len() > MAX_TAG_LEN || !tag.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+                return Err(format!(
+                    "Invalid tag '{}', use up to {} letters, digits, '-' or '_'",
+                    tag, MAX_TAG_LEN
+                ));
+            }
+            tags.push(tag);
+        }
+        if tags.len() > MAX_TAGS {
+            return Err(format!("At most {} tags are allowed", MAX_TAGS));
+        }
+        Ok(tags)
+    }
+
+    fn target_size(&self) -> Result<Option<u64>, String> {
+        let Some(value) = &self.target_size else {
+            return Ok(None);
+        };
+        let value = value.trim().to_ascii_uppercase();
+        let (number, unit) = if let Some(number) = value.strip_suffix("KB") {
+            (number, 1024)
+        } else if let Some(number) = value.strip_suffix("MB") {
+            (number, 1024 * 1024)
+        } else {
+            (value.as_str(), 1)
+        };
+        match number.trim().parse::<u64>() {
+            Ok(number) if number > 0 => Ok(Some(number * unit)),
+            _ => Err(format!("Invalid target_size '{}', use e.g. 150KB", value)),
+        }
+    }
+
+    /// The `scale`, `longest_edge` or `shortest_edge` given instead of a width and height, if any.
+    fn resize_spec(&self) -> Result<Option<ResizeSpec>, String> {
+        let specs: Vec<ResizeSpec> = [
+            self.scale.map(ResizeSpec::Scale),
+            self.longest_edge.map(ResizeSpec::LongestEdge),
+            self.shortest_edge.map(ResizeSpec::ShortestEdge),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        match specs[..] {
+            [] => Ok(None),
+            [_] if self.width.is_some() || self.height.is_some() => {
+                Err("Use either width and height or one of scale, longest_edge and shortest_edge".to_string())
+            }
+            [spec] => spec.validate().map(|()| Some(spec)),
+            _ => Err("Use only one of scale, longest_edge and shortest_edge".to_string()),
+        }
+    }
+
+    fn fit(&self) -> Result<Option<Fit>, String> {
+        self.fit.as_deref().map(str::parse).transpose()
+    }
+
+    fn filter(&self) -> Result<Option<resize_spec::Filter>, String> {
+        self.filter.as_deref().map(str::parse).transpose()
+    }
+
+    /// The `variants` asked for, or the default ones from `RESIZE_VARIANTS`.
+    fn variants(&self) -> Result<Vec<Variant>, String> {
+        match &self.variants {
+            Some(list) => variants::parse(list),
+            None => Ok(variants::from_env().to_vec()),
+        }
+    }
+
+    fn output_format(&self) -> Result<Option<OutputFormat>, String> {
+        self.output_format.as_deref().map(str::parse).transpose()
+    }
+
+    fn crop(&self) -> Result<Option<Crop>, String> {
+        self.crop
+            .as_deref()
+            .map(|crop| Crop::parse(crop, self.crop_normalized))
+            .transpose()
+    }
+
+    fn focal_point(&self) -> Result<Option<FocalPoint>, String> {
+        self.focal_point.as_deref().map(FocalPoint::parse).transpose()
+    }
+
+    fn metadata(&self) -> Result<BTreeMap<String, String>, String> {
+        match &self.metadata {
+            Some(json) => metadata::parse(json),
+            None => Ok(BTreeMap::new()),
+        }
+    }
+
+    fn callback_url(&self) -> Result<Option<String>, String> {
+        let Some(url) = &self.callback_url else {
+            return Ok(None);
+        };
+        webhook::check_callback_url(url)?;
+        Ok(Some(url.clone()))
+    }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Image {
-    filename: String,
-    image_container: String,
+    fn priority(&self) -> Result<Priority, String> {
+        self.priority.as_deref().map(str::parse).transpose().map(Option::unwrap_or_default)
+    }
 }
 
 #[tokio::main]
 async fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if image_resize_core::config_check::requested(&args) {
+        config_check::run(&args).await;
+    }
+    logging::init();
+    telemetry::init("api");
+    trace::init("api");
+    config::get();
+    storage::kind();
+    customer_keys::keys();
+    variants::from_env();
+    chaos::faults();
+    migrations::run().await.unwrap_or_else(|e| panic!("Failed to migrate the job status table: {}", e));
+
+    let registry = ProgressRegistry::default();
+    let with_registry = warp::any().map(move || registry.clone());
+    let notifier = Arc::new(notify::Notifier::from_env());
+    let with_notifier = warp::any().map(move || notifier.clone());
+    let request_timeout = timeout::request_timeout();
+    let limits = limit::RuntimeLimits::from_env();
+    memory::init();
+    let ip_policy = Arc::new(ip_filter::IpPolicy::from_env());
+    let tenants = Arc::new(tenant::TenantStore::from_env());
+    let authenticator = Arc::new(auth::Authenticator::from_env(tenants.clone(), limits.clone()));
+    let token_issuer = upload_token::TokenIssuer::from_env().map(Arc::new);
+    let access_policy = Arc::new(container_access::AccessPolicy::from_env());
+    access_policy.check_at_startup().await;
+    let retention_policy = retention::RetentionPolicy::from_env();
+    let image_delivery = content::Delivery::from_env();
+    let with_access_policy = warp::any().map(move || access_policy.clone());
+    let with_tenants = {
+        let tenants = tenants.clone();
+        warp::any().map(move || tenants.clone())
+    };
+
     let upload_route = warp::path("upload")
         .and(warp::post())
-        .and(warp::multipart::form().max_length(5 * 1024 * 1024)) // Max image size: 5MB
-        .and_then(upload_file);
+        .and(ip_filter::guard(ip_policy.clone()))
+        .and(auth::identify_uploader(authenticator.clone(), token_issuer.clone()))
+        .and(limit::permit(limits.clone()))
+        .and(warp::query::<UploadOptions>())
+        .and(limit::content_length(limits.clone(), |body| body.max_request_bytes))
+        .and(limit::body_limits(limits.clone()))
+        .and(warp::multipart::form().max_length(None))
+        .and(with_notifier.clone())
+        .and_then(move |tenant, token, permit, options, body_limits, form, notifier| {
+            limit::hold(
+                permit,
+                timeout::with_timeout(request_timeout, upload_file(options, tenant, token, body_limits, form, notifier)),
+            )
+        });
+
+    let zip_upload_route = warp::path!("upload" / "zip")
+        .and(warp::post())
+        .and(ip_filter::guard(ip_policy.clone()))
+        .and(auth::identify_uploader(authenticator.clone(), token_issuer.clone()))
+        .and(limit::permit(limits.clone()))
+        .and(warp::query::<UploadOptions>())
+        .and(limit::content_length(limits.clone(), |body| body.max_zip_bytes))
+        .and(limit::body_limits(limits.clone()))
+        .and(warp::body::bytes())
+        .and(with_registry.clone())
+        .and(with_notifier.clone())
+        .and_then(move |tenant, token, permit, options, body_limits, body, registry, notifier| {
+            limit::hold(
+                permit,
+                timeout::with_timeout(
+                    request_timeout,
+                    zip_upload::upload_zip(options, tenant, token, body_limits, body, registry, notifier),
+                ),
+            )
+        });
+
+    let inline_upload_route = warp::path("upload-inline")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(ip_filter::guard(ip_policy.clone()))
+        .and(auth::identify_uploader(authenticator.clone(), token_issuer.clone()))
+        .and(limit::permit(limits.clone()))
+        .and(warp::query::<UploadOptions>())
+        .and(limit::content_length(limits.clone(), inline_upload::max_body_bytes))
+        .and(limit::body_limits(limits.clone()))
+        .and(warp::body::json())
+        .and(with_notifier.clone())
+        .and_then(move |tenant, token, permit, options, body_limits, upload, notifier| {
+            limit::hold(
+                permit,
+                timeout::with_timeout(
+                    request_timeout,
+                    inline_upload::upload_inline(options, tenant, token, body_limits, upload, notifier),
+                ),
+            )
+        });
+
+    let tus_registry = tus::TusRegistry::default();
+    upload_gc::spawn(upload_gc::UploadGc::from_env(), tus_registry.clone());
+    let with_tus = warp::any().map(move || tus_registry.clone());
+
+    let tus_options_route = warp::path("files")
+        .and(warp::path::end())
+        .and(warp::options())
+        .and(limit::body_limits(limits.clone()))
+        .map(tus::capabilities);
+
+    let tus_create_route = warp::path("files")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(ip_filter::guard(ip_policy.clone()))
+        .and(auth::identify_uploader(authenticator.clone(), token_issuer.clone()))
+        .and(warp::header::optional::<String>("tus-resumable"))
+        .and(warp::header::optional::<u64>("upload-length"))
+        .and(warp::header::optional::<String>("upload-metadata"))
+        .and(limit::body_limits(limits.clone()))
+        .and(with_tus.clone())
+        .and(with_notifier.clone())
+        .and_then(move |tenant, token, tus_resumable, upload_length, upload_metadata, body_limits, registry, notifier| {
+            tus::create(tenant, token, tus_resumable, upload_length, upload_metadata, body_limits, registry, notifier)
+        });
+
+    let tus_head_route = warp::path!("files" / Uuid)
+        .and(warp::head())
+        .and(warp::header::optional::<String>("tus-resumable"))
+        .and(with_tus.clone())
+        .and_then(tus::status);
+
+    let tus_patch_route = warp::path!("files" / Uuid)
+        .and(warp::patch())
+        .and(limit::permit(limits.clone()))
+        .and(warp::header::optional::<String>("tus-resumable"))
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::header::optional::<u64>("upload-offset"))
+        .and(limit::content_length(limits.clone(), |body| body.max_part_bytes as u64))
+        .and(warp::body::bytes())
+        .and(with_tus.clone())
+        .and_then(move |id, permit, tus_resumable, content_type, upload_offset, chunk, registry| {
+            limit::hold(
+                permit,
+                timeout::with_timeout(
+                    request_timeout,
+                    tus::append(id, tus_resumable, content_type, upload_offset, chunk, registry),
+                ),
+            )
+        });
+
+    // S3 clients address objects as /{bucket}/{key}, so this catches any PUT left over by the routes above
+    let s3_config = Arc::new(s3::S3Config::from_env());
+    let s3_put_route = warp::put()
+        .and(warp::path::param::<String>())
+        .and(warp::path::tail())
+        .and(ip_filter::guard(ip_policy.clone()))
+        .and(warp::path::full())
+        .and(warp::query::<BTreeMap<String, String>>())
+        .and(warp::header::headers_cloned())
+        .and(limit::content_length(limits.clone(), |body| body.max_part_bytes as u64))
+        .and(warp::body::bytes())
+        .then(move |_bucket: String, _key, path, query, headers, body| {
+            let s3_config = s3_config.clone();
+            async move { s3::put_object(&s3_config, path, query, headers, body).await }
+        });
+
+    let upload_token_route = warp::path("upload-tokens")
+        .and(warp::post())
+        .and(auth::identify(authenticator.clone()))
+        .and(warp::body::json())
+        .and(limit::body_limits(limits.clone()))
+        .and_then(move |tenant, request, body_limits| {
+            upload_token::issue_token(tenant, request, token_issuer.clone(), body_limits)
+        });
+
+    let export_route = warp::path("export")
+        .and(warp::post())
+        .and(auth::require(authenticator.clone()))
+        .and(warp::body::json())
+        .and_then(move |request| timeout::with_timeout(request_timeout, export::export_renditions(request)));
+
+    let compare_route = warp::path("compare")
+        .and(warp::post())
+        .and(auth::require(authenticator.clone()))
+        .and(limit::permit(limits.clone()))
+        .and(warp::body::json())
+        .and_then(move |permit, request| {
+            limit::hold(permit, timeout::with_timeout(request_timeout, compare::compare_images(request)))
+        });
+
+    let import_route = warp::path!("admin" / "import")
+        .and(warp::post())
+        .and(ip_filter::guard(ip_policy.clone()))
+        .and(auth::require(authenticator.clone()))
+        .and(warp::body::json())
+        .and(with_registry.clone())
+        .and(with_notifier.clone())
+        .and_then(move |request, registry, notifier| {
+            timeout::with_timeout(request_timeout, import::start_import(request, registry, notifier))
+        });
+
+    let import_status_route = warp::path!("admin" / "import" / Uuid)
+        .and(warp::get())
+        .and(ip_filter::guard(ip_policy.clone()))
+        .and(auth::require(authenticator.clone()))
+        .and(warp::query::<paging::PageQuery>())
+        .and(with_registry.clone())
+        .and_then(import::import_status);
+
+    let backfill_route = warp::path!("admin" / "backfill")
+        .and(warp::post())
+        .and(ip_filter::guard(ip_policy.clone()))
+        .and(auth::require(authenticator.clone()))
+        .and(warp::body::json())
+        .and(with_registry.clone())
+        .and(with_notifier.clone())
+        .and_then(move |request, registry, notifier| {
+            timeout::with_timeout(request_timeout, backfill::start_backfill(request, registry, notifier))
+        });
+
+    let backfill_status_route = warp::path!("admin" / "backfill" / Uuid)
+        .and(warp::get())
+        .and(ip_filter::guard(ip_policy.clone()))
+        .and(auth::require(authenticator.clone()))
+        .and(warp::query::<paging::PageQuery>())
+        .and(with_registry.clone())
+        .and_then(backfill::backfill_status);
+
+    let regenerate_route = warp::path!("admin" / "regenerate")
+        .and(warp::post())
+        .and(ip_filter::guard(ip_policy.clone()))
+        .and(auth::require(authenticator.clone()))
+        .and(warp::body::json())
+        .and(with_registry.clone())
+        .and(with_notifier.clone())
+        .and_then(move |request, registry, notifier| {
+            timeout::with_timeout(request_timeout, regenerate::start_regenerate(request, registry, notifier))
+        });
+
+    let regenerate_status_route = warp::path!("admin" / "regenerate" / Uuid)
+        .and(warp::get())
+        .and(ip_filter::guard(ip_policy.clone()))
+        .and(auth::require(authenticator.clone()))
+        .and(warp::query::<paging::PageQuery>())
+        .and(with_registry.clone())
+        .and_then(regenerate::regenerate_status);
+
+    let template_batch_route = warp::path!("batch" / "template")
+        .and(warp::post())
+        .and(ip_filter::guard(ip_policy.clone()))
+        .and(auth::require(authenticator.clone()))
+        .and(warp::body::json())
+        .and(with_registry.clone())
+        .and(with_notifier.clone())
+        .and_then(move |request, registry, notifier| {
+            timeout::with_timeout(request_timeout, batch::start_template_batch(request, registry, notifier))
+        });
+
+    let batch_status_route = warp::path!("batch" / Uuid)
+        .and(warp::get())
+        .and(ip_filter::guard(ip_policy.clone()))
+        .and(auth::identify(authenticator.clone()))
+        .and(warp::query::<paging::PageQuery>())
+        .and(with_registry.clone())
+        .and_then(batch::batch_status);
+
+    let get_tenant_policy_route = warp::path!("admin" / "tenants" / String / "policy")
+        .and(warp::get())
+        .and(ip_filter::guard(ip_policy.clone()))
+        .and(auth::require(authenticator.clone()))
+        .and(with_tenants.clone())
+        .and_then(tenant::get_policy);
+
+    let put_tenant_policy_route = warp::path!("admin" / "tenants" / String / "policy")
+        .and(warp::put())
+        .and(ip_filter::guard(ip_policy.clone()))
+        .and(auth::require(authenticator.clone()))
+        .and(warp::body::json())
+        .and(with_tenants.clone())
+        .and_then(tenant::put_policy);
+
+    let container_access_route = warp::path!("admin" / "containers" / "access")
+        .and(warp::get())
+        .and(ip_filter::guard(ip_policy.clone()))
+        .and(auth::require(authenticator.clone()))
+        .and(with_access_policy.clone())
+        .and_then(container_access::access_report);
+
+    let enforce_container_access_route = warp::path!("admin" / "containers" / "access" / "enforce")
+        .and(warp::post())
+        .and(ip_filter::guard(ip_policy.clone()))
+        .and(auth::require(authenticator.clone()))
+        .and(with_access_policy.clone())
+        .and_then(container_access::enforce_access);
+
+    let usage_route = warp::path!("admin" / "usage")
+        .and(warp::get())
+        .and(ip_filter::guard(ip_policy.clone()))
+        .and(auth::require(authenticator.clone()))
+        .and(warp::query::<usage::UsageQuery>())
+        .and_then(usage::get_usage);
+
+    let with_limits = {
+        let limits = limits.clone();
+        warp::any().map(move || limits.clone())
+    };
+
+    let get_limits_route = warp::path!("admin" / "limits")
+        .and(warp::get())
+        .and(ip_filter::guard(ip_policy.clone()))
+        .and(auth::require(authenticator.clone()))
+        .and(with_limits.clone())
+        .and_then(limit::get_limits);
+
+    let put_limits_route = warp::path!("admin" / "limits")
+        .and(warp::put())
+        .and(ip_filter::guard(ip_policy.clone()))
+        .and(auth::require(authenticator.clone()))
+        .and(warp::body::json())
+        .and(with_limits.clone())
+        .and_then(limit::put_limits);
+
+    let reviews_route = warp::path!("admin" / "reviews")
+        .and(warp::get())
+        .and(ip_filter::guard(ip_policy.clone()))
+        .and(auth::require(authenticator.clone()))
+        .and(warp::query::<review::ReviewQuery>())
+        .and_then(review::list_reviews);
+
+    let approve_review_route = warp::path!("admin" / "reviews" / Uuid / "approve")
+        .and(warp::post())
+        .and(ip_filter::guard(ip_policy.clone()))
+        .and(auth::require(authenticator.clone()))
+        .and_then(|job_id| review::decide(job_id, ReviewDecision::Approved));
+
+    let reject_review_route = warp::path!("admin" / "reviews" / Uuid / "reject")
+        .and(warp::post())
+        .and(ip_filter::guard(ip_policy.clone()))
+        .and(auth::require(authenticator.clone()))
+        .and_then(|job_id| review::decide(job_id, ReviewDecision::Rejected));
+
+    let webhook_verify_route = warp::path!("webhooks" / "verify")
+        .and(warp::post())
+        .and(ip_filter::guard(ip_policy.clone()))
+        .and(auth::identify(authenticator.clone()))
+        .and(warp::query::<webhook_verify::VerifyQuery>())
+        .and(warp::header::headers_cloned())
+        .and(warp::body::content_length_limit(webhook_verify::MAX_BODY_BYTES))
+        .and(warp::body::bytes())
+        .and_then(webhook_verify::verify);
+
+    let feed_route = warp::path("feed")
+        .and(warp::get())
+        .and(auth::identify(authenticator.clone()))
+        .and(warp::query::<feed::FeedQuery>())
+        .and(warp::query::<paging::PageQuery>())
+        .and_then(feed::recent_renditions);
+
+    let image_search_route = warp::path!("images" / "search")
+        .and(warp::get())
+        .and(auth::identify(authenticator.clone()))
+        .and(warp::query::<images::SearchQuery>())
+        .and(warp::query::<paging::PageQuery>())
+        .and_then(images::search);
+
+    let image_list_route = warp::path("images")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(auth::identify(authenticator.clone()))
+        .and(warp::query::<paging::PageQuery>())
+        .and_then(images::list);
+
+    let image_metadata_route = warp::path!("images" / String / "metadata")
+        .and(warp::get())
+        .and(auth::identify(authenticator.clone()))
+        .and_then(metadata::get_metadata);
+
+    let image_content_route = warp::path!("images" / String / "content")
+        .and(warp::get().or(warp::head()).unify())
+        .and(warp::method())
+        .and(auth::identify(authenticator.clone()))
+        .and(warp::header::headers_cloned())
+        .and(warp::query::<content::ContentQuery>())
+        .and(warp::any().map(move || image_delivery))
+        .and_then(content::serve);
+
+    let image_copy_route = warp::path!("images" / String / "copy")
+        .and(warp::post())
+        .and(ip_filter::guard(ip_policy.clone()))
+        .and(warp::query::<UploadOptions>())
+        .and(auth::identify(authenticator.clone()))
+        .and(warp::body::json())
+        .and(with_notifier.clone())
+        .and(with_registry.clone())
+        .and_then(copy::copy);
+
+    let operation_route = warp::path!("operations" / Uuid)
+        .and(warp::get())
+        .and(auth::identify(authenticator.clone()))
+        .and(warp::query::<paging::PageQuery>())
+        .and(with_registry.clone())
+        .and_then(progress::operation_status);
+
+    let process_route = warp::path("process")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(ip_filter::guard(ip_policy.clone()))
+        .and(warp::query::<UploadOptions>())
+        .and(auth::identify(authenticator.clone()))
+        .and(warp::body::json())
+        .and_then(reprocess::process);
+
+    let image_report_route = warp::path!("images" / String / "report")
+        .and(warp::get())
+        .and(auth::identify(authenticator.clone()))
+        .and_then(report::get_report);
 
-    let routes = upload_route
-        .recover(handle_rejection);
+    let image_status_route = warp::path!("images" / String / "status")
+        .and(warp::get())
+        .and(auth::identify(authenticator.clone()))
+        .and_then(report::get_status);
 
-    println!("Server started at http://localhost:3030");
+    let image_provenance_route = warp::path!("images" / String / "provenance")
+        .and(warp::get())
+        .and(auth::identify(authenticator.clone()))
+        .and_then(provenance::get_provenance);
+
+    let job_route = warp::path!("jobs" / Uuid)
+        .and(warp::get())
+        .and(auth::identify(authenticator.clone()))
+        .and_then(jobs::get_job);
+
+    let job_events_route = warp::path!("jobs" / Uuid / "events")
+        .and(warp::get())
+        .and(auth::identify(authenticator.clone()))
+        .and(warp::header::optional::<usize>("last-event-id"))
+        .and_then(jobs::job_events);
+
+    let cancel_job_route = warp::path!("jobs" / String)
+        .and(warp::delete())
+        .and(auth::identify(authenticator.clone()))
+        .and_then(jobs::cancel_job);
+
+    let search_route = warp::path("search")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(auth::identify(authenticator.clone()))
+        .and(warp::query::<search::SearchQuery>())
+        .and(warp::query::<paging::PageQuery>())
+        .and_then(search::search);
+
+    let version = build_info();
+    info!("Starting {} {} ({}), {}", version.name, version.version, version.git_sha, version.cpu);
+    let version_route = warp::path("version")
+        .and(warp::get())
+        .map(move || warp::reply::json(&version));
+
+    let capabilities_route = warp::path("capabilities")
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(|| warp::reply::json(&capabilities::current()));
+
+    let metrics_route = warp::path("metrics")
+        .and(warp::get())
+        .map(|| warp::reply::with_header(metrics::render(), "content-type", "text/plain; version=0.0.4"));
+    let healthz_route = warp::path("healthz")
+        .and(warp::get())
+        .map(|| warp::reply::json(&serde_json::json!({ "status": "ok" })));
+    let readyz_route = warp::path("readyz").and(warp::get()).then(|| async {
+        let readiness = health::readiness().await;
+        let status = if readiness.ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+        warp::reply::with_status(warp::reply::json(&readiness), status)
+    });
+
+    let routes = zip_upload_route
+        .or(upload_route)
+        .or(inline_upload_route)
+        .or(upload_token_route)
+        .or(tus_options_route)
+        .or(tus_create_route)
+        .or(tus_head_route)
+        .or(tus_patch_route)
+        .or(export_route)
+        .or(compare_route)
+        .or(import_route)
+        .or(import_status_route)
+        .or(backfill_route)
+        .or(backfill_status_route)
+        .or(regenerate_route)
+        .or(regenerate_status_route)
+        .or(template_batch_route)
+        .or(batch_status_route)
+        .or(get_tenant_policy_route)
+        .or(put_tenant_policy_route)
+        .or(container_access_route)
+        .or(enforce_container_access_route)
+        .or(usage_route)
+        .or(get_limits_route)
+        .or(put_limits_route)
+        .or(reviews_route)
+        .or(approve_review_route)
+        .or(reject_review_route)
+        .or(webhook_verify_route)
+        .or(feed_route)
+        .or(image_list_route)
+        .or(image_search_route)
+        .or(search_route)
+        .or(image_metadata_route)
+        .or(image_content_route)
+        .or(image_copy_route)
+        .or(operation_route)
+        .or(image_report_route)
+        .or(image_status_route)
+        .or(image_provenance_route)
+        .or(job_route)
+        .or(job_events_route)
+        .or(cancel_job_route)
+        .or(process_route)
+        .or(version_route)
+        .or(capabilities_route)
+        .or(metrics_route)
+        .or(healthz_route)
+        .or(readyz_route)
+        .or(s3_put_route)
+        .recover(handle_rejection)
+        .map(Reply::into_response);
+    // fail at startup rather than on the first error
+    i18n::default_language();
+    // read leniently, as an unreadable Accept-Language only means English
+    let accept_language = warp::header::headers_cloned().map(|headers: warp::http::HeaderMap| {
+        headers.get("accept-language").and_then(|value| value.to_str().ok()).map(str::to_string)
+    });
+    let routes = accept_language.and(routes).map(i18n::localize);
+    let routes = match upload_token::cors() {
+        Some(cors) => routes.with(cors).map(Reply::into_response).boxed(),
+        None => routes.boxed(),
+    };
+    let routes = routes
+        .with(warp::trace(|info| {
+            let traceparent = info.request_headers().get(trace::TRACEPARENT_HEADER).and_then(|v| v.to_str().ok());
+            trace::span(&format!("{} {}", info.method(), route_label(info.path())), traceparent)
+        }))
+        .with(warp::log::custom(|info| {
+            let route = route_label(info.path());
+            metrics::increment(
+                "http_requests_total",
+                &[("method", info.method().as_str()), ("route", route), ("status", info.status().as_str())],
+            );
+            metrics::observe("http_request_duration_seconds", &[("method", info.method().as_str()), ("route", route)], info.elapsed());
+            telemetry::track_request(
+                &format!("{} {}", info.method(), info.path()),
+                info.path(),
+                info.elapsed(),
+                info.status().as_str(),
+                !info.status().is_server_error(),
+            );
+        }));
+
+    ingest::spawn_from_env(limits.body());
+    retention::spawn(retention_policy);
+    usage::spawn();
+    review::spawn();
+    reconcile::spawn();
+
+    info!("Server started at http://localhost:3030");
     warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
 }
 
-async fn upload_file(form: FormData) -> Result<impl Reply, Rejection> {
-    let uploaded_files: Vec<_> = form
-        .and_then(|mut part: Part| async move {
-            let mut bytes: Vec<u8> = Vec::new();
+/// First segment of a request path as a metrics label, e.g. `/images` for `/images/a.jpg/report`.
+/// Paths outside the API's own routes, like S3 buckets, all count as `other`.
+fn route_label(path: &str) -> &'static str {
+    const ROUTES: &[&str] = &[
+        "/admin", "/batch", "/capabilities", "/compare", "/export", "/feed", "/files", "/healthz", "/images", "/jobs", "/metrics", "/operations", "/process",
+        "/readyz", "/search", "/upload", "/upload-inline", "/upload-tokens", "/version", "/webhooks",
+    ];
+    let segment = path.split('/').nth(1).unwrap_or_default();
+    ROUTES.iter().find(|route| route[1..] == *segment).copied().unwrap_or("other")
+}
+
+async fn upload_file(
+    options: UploadOptions,
+    tenant: Option<tenant::Tenant>,
+    token: Option<upload_token::UploadClaims>,
+    mut limits: BodyLimits,
+    mut form: FormData,
+    notifier: Arc<notify::Notifier>,
+) -> Result<impl Reply, Rejection> {
+    // a browser upload token stands in for the tenant's key and narrows the limits further
+    let tenant = tenant.or_else(|| token.as_ref().map(upload_token::UploadClaims::as_tenant));
+    if let Some(token) = &token {
+        limits.max_part_bytes = limits.max_part_bytes.min(token.max_bytes);
+        limits.max_streamed_part_bytes = limits.max_streamed_part_bytes.min(token.max_bytes);
+    }
+
+    let mut plan = plan_upload(&options, tenant.as_ref()).await?;
+    let duplicate_policy = match tenant.as_ref().map_or(DuplicatePolicy::Process, |tenant| tenant.policy.duplicates) {
+        // content that's already stored is found under its own name, see `naming.rs`
+        DuplicatePolicy::Process if naming::content_addressed() => DuplicatePolicy::Existing,
+        policy => policy,
+    };
+    if !options.dry_run {
+        quota::charge(tenant.as_ref(), true, 0, &notifier).await?;
+    }
 
-            // read the part stream
-            while let Some(content) = part.data().await {
-                let content = content.unwrap();
-                bytes.put(content);
+    let mut uploaded_files = Vec::new();
+    let mut duplicates = Vec::new();
+    let mut jobs = BTreeMap::new();
+    let mut blobs = BTreeMap::new();
+    let mut hints = BTreeMap::new();
+    let mut renditions = BTreeMap::new();
+    let mut parts = Vec::new();
+    // blob and job of each content stored by this request, so a part repeating one is stored once
+    let mut stored: HashMap<String, (String, String)> = HashMap::new();
+    let mut estimates = Vec::new();
+    let mut part_count = 0;
+    let mut files_read = false;
+    // an atomic upload holds its messages back, see `atomic.rs`
+    let mut transaction = options.atomic.then(atomic::Transaction::default);
+    let mut current = None;
+    let outcome: Result<Option<warp::reply::Response>, Rejection> = async {
+        while let Some(part) = form.try_next().await.map_err(|e| {
+            error!("Error reading multipart form: {:?}", e);
+            warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, "Malformed multipart body"))
+        })? {
+            current = None;
+            part_count += 1;
+            if part_count > limits.max_parts {
+                return Err(warp::reject::custom(ApiError::new(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    format!("Too many parts, at most {} are accepted per request", limits.max_parts),
+                )));
             }
 
-            if !bytes.is_empty() {
-                // Azure Blob Storage credentials
-                let storage_account = env::var("AZURE_STORAGE_ACCOUNT").expect("Missing AZURE_STORAGE_ACCOUNT env var");
-                let storage_access_key = env::var("AZURE_STORAGE_ACCESS_KEY").expect("Missing AZURE_STORAGE_ACCESS_KEY env var");
-                let container_name = env::var("AZURE_STORAGE_CONTAINER").expect("Missing AZURE_STORAGE_CONTAINER env var");
-                let blob_name = part.filename().unwrap().to_string(); 
+            // the operations field applies to the files after it
+            if part.name() == "operations" && part.filename().is_none() {
+                if files_read {
+                    return Err(warp::reject::custom(ApiError::new(
+                        StatusCode::BAD_REQUEST,
+                        "The operations field must come before the files",
+                    )));
+                }
+                let json = PartStream::read_field(part_count, part, MAX_FIELD_BYTES).await?;
+                plan.operations = operations::parse(&json)
+                    .map_err(|e| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, e)))?;
+                if let Some(tenant) = &tenant {
+                    tenant
+                        .policy
+                        .check_operations(&plan.operations)
+                        .map_err(|e| warp::reject::custom(ApiError::new(StatusCode::FORBIDDEN, e)))?;
+                }
+                continue;
+            }
+            // so do the hints, see `hints.rs`
+            if part.name() == "hints" && part.filename().is_none() {
+                if files_read {
+                    return Err(warp::reject::custom(ApiError::new(
+                        StatusCode::BAD_REQUEST,
+                        "The hints field must come before the files",
+                    )));
+                }
+                let json = PartStream::read_field(part_count, part, MAX_FIELD_BYTES).await?;
+                hints = hints::parse(&json).map_err(|e| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, e)))?;
+                continue;
+            }
+            files_read = true;
 
-                // create Azure Blob Storage client
-                let storage_credentials = StorageCredentials::access_key(storage_account.clone(), storage_access_key);
-                let blob_client = ClientBuilder::new(storage_account, storage_credentials).blob_client(&container_name, blob_name);
+            // only the first block is read up front; larger parts are streamed into their blob below
+            let mut stream = PartStream::new(part_count, part, limits.max_streamed_part_bytes, limits.block_bytes)?;
+            let filename = stream.filename.clone();
+            current = Some(filename.clone());
+            let mut bytes = stream.next_block().await?;
+            // staged blocks are specific to Azure, other backends take the whole file, and so do
+            // tenants with their own encryption key since blocks can't be written with it, and
+            // content-addressed names since the blob is named after the whole content
+            let customer_key = customer_keys::customer_key(tenant.as_ref().map(|tenant| tenant.id.as_str()));
+            let buffered = storage::kind() != storage::BackendKind::Azure || customer_key.is_some() || naming::content_addressed();
+            if !stream.is_done() && !options.dry_run && buffered {
+                stream.limit(limits.max_part_bytes)?;
+                stream.read_to_end(&mut bytes).await?;
+            }
+            if stream.is_done() {
+                stream.limit(limits.max_part_bytes)?;
+            }
 
-                // upload file to Azure Blob Storage
-                match blob_client
-                    .put_block_blob(bytes.clone())
-                    .content_type("image/jpeg")
-                    .await {
-                        Ok(_) => println!("Blob uploaded successfully"),
-                        Err(e) => println!("Error uploading blob: {:?}", e),
-                    }
+            let source_format = OutputFormat::of_source(&bytes);
+            let invalid = |e: Invalid| {
+                let status = match e {
+                    Invalid::Unsupported(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    Invalid::TooLarge(_) | Invalid::Empty(_) | Invalid::Truncated(_) => StatusCode::UNPROCESSABLE_ENTITY,
+                };
+                warp::reject::custom(ApiError::new(status, e.to_string()))
+            };
+            let dimensions = image_checks::check(&filename, &bytes).map_err(invalid)?;
+            let hint = hints.get(&filename);
+            if let Some(hint) = hint {
+                hint.check(&filename, dimensions, &bytes)
+                    .map_err(|e| warp::reject::custom(ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, e)))?;
+            }
+            if let Some(tenant) = &tenant {
+                tenant
+                    .policy
+                    .check_format(&filename, &bytes)
+                    .map_err(|e| warp::reject::custom(ApiError::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, e)))?;
+            }
+            // the end of a streamed part is never in memory with its start, so only buffered ones are checked
+            let mut part_warnings = Vec::new();
+            if stream.is_done() {
+                image_checks::check_complete(&filename, &bytes).map_err(invalid)?;
+                let read = bytes.len();
+                trailing_data::check(&filename, &mut bytes)
+                    .map_err(|e| warp::reject::custom(ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, e)))?;
+                if bytes.len() < read {
+                    let message = format!("{} bytes after the end of the image were dropped", read - bytes.len());
+                    part_warnings.push(Warning::new(warnings::TRAILING_DATA_STRIPPED, message));
+                }
+            }
+            // the metadata of a JPEG precedes its image data, so it's within the first block
+            let read = bytes.len();
+            image_checks::strip_exif(&mut bytes);
+            if bytes.len() < read {
+                part_warnings.push(Warning::new(warnings::EXIF_STRIPPED, "The EXIF segment was removed"));
+            }
+            let content_type = original_content_type(&bytes);
+            let part_report = |blob: &str, job_id: Option<&String>, warnings: Vec<Warning>, bytes: u64| PartReport {
+                filename: filename.clone(),
+                blob: blob.to_string(),
+                bytes,
+                content_type: content_type.to_string(),
+                width: dimensions.map(|(width, _)| width),
+                height: dimensions.map(|(_, height)| height),
+                job_id: job_id.cloned(),
+                warnings,
+            };
+
+            let mut blob_name = filename.clone();
+
+            // create Azure Blob Storage client
+            let container_client = match &token {
+                Some(token) => container_client_for(&token.container),
+                None => container_client(),
+            };
+            if options.dry_run {
+                stream.skip_to_end().await?;
+                estimates.push(dry_run::estimate(&plan, &container_client, &filename, &bytes, stream.bytes_read() as u64).await?);
+                continue;
+            }
+            let container_name = container_client.container_name().to_string();
+            // named before anything is staged, unless the name is made from the content, see `naming.rs`
+            if !naming::content_addressed() {
+                blob_name = naming::original_name(&container_client, &filename, None)
+                    .await
+                    .map_err(warp::reject::custom)?;
+            }
+
+            // a part larger than a block is staged as it arrives, to be committed once it's checked
+            let mut hasher = Some(Sha256::new());
+            let staged = if stream.is_done() {
+                None
+            } else {
+                let location = failover::write_location();
+                let blob_client = container_client_at(&container_name, location).blob_client(&blob_name);
+                let first = std::mem::take(&mut bytes);
+                let blocks = stream.stage(&blob_client, location, first, &mut hasher).await?;
+                Some((blob_client, location, blocks))
+            };
 
-                println!("Uploaded file url: {}", blob_client.url().expect("Failed to get blob url"));
+            // identical content already processed is answered with what was made of it
+            let mut metadata = plan.blob_metadata();
+            let content_hash = hasher.map(|mut hasher| {
+                if staged.is_none() {
+                    hasher.update(&bytes);
+                }
+                hex::encode(hasher.finalize())
+            });
+            if let Some((stored_blob, job_id)) = content_hash.as_ref().and_then(|hash| stored.get(hash)) {
+                // the blocks of a staged repeat are left uncommitted, and storage discards them
+                info!("{} repeats {} of the same request", filename, stored_blob);
+                telemetry::track_event("RepeatedPart", &[("filename", filename.clone())]);
+                jobs.insert(filename.clone(), job_id.clone());
+                if *stored_blob != filename {
+                    blobs.insert(filename.clone(), stored_blob.clone());
+                }
+                if let Some(transaction) = &mut transaction {
+                    transaction.repeated(filename.clone());
+                }
+                parts.push(part_report(stored_blob, Some(job_id), part_warnings, stream.bytes_read() as u64));
+                uploaded_files.push(filename);
+                continue;
+            }
+            if let Some(hash) = content_hash.as_ref().filter(|_| duplicate_policy != DuplicatePolicy::Process) {
+                if let Some(existing) = duplicates::find(&container_client, hash, &plan.then).await {
+                    info!("{} duplicates {}", filename, existing.original.blob);
+                    telemetry::track_event("DuplicateUpload", &[("filename", filename.clone())]);
+                    if duplicate_policy == DuplicatePolicy::Conflict && options.atomic {
+                        let message = format!("'{}' duplicates an existing image", filename);
+                        return Err(warp::reject::custom(ApiError::new(StatusCode::CONFLICT, message)));
+                    }
+                    if duplicate_policy == DuplicatePolicy::Conflict {
+                        let body = serde_json::json!({
+                            "error": format!("'{}' duplicates an existing image", filename),
+                            "filename": filename,
+                            "existing": existing,
+                            "uploaded": uploaded_files,
+                        });
+                        return Ok(Some(warp::reply::with_status(warp::reply::json(&body), StatusCode::CONFLICT).into_response()));
+                    }
+                    parts.push(part_report(&existing.original.blob, None, part_warnings, stream.bytes_read() as u64));
+                    duplicates.push(Duplicate { filename: filename.clone(), existing });
+                    continue;
+                }
+                duplicates::stamp(&mut metadata, hash);
+                if naming::content_addressed() {
+                    blob_name = naming::original_name(&container_client, &filename, Some(hash))
+                        .await
+                        .map_err(warp::reject::custom)?;
+                }
+            }
+            // planned before the file is stored, so a preset or template that can't be read refuses it
+            let predicted = match (hint, dimensions) {
+                (Some(_), Some(dimensions)) => {
+                    let outputs = dry_run::outputs(&plan, &container_client, &blob_name, dimensions).await?;
+                    Some(hints::predicted(outputs).await)
+                }
+                _ => None,
+            };
+            if options.atomic {
+                let location = failover::location_of(&container_name, &blob_name).await;
+                let exists = storage::from_env(&container_client_at(&container_name, location)).exists(&blob_name).await;
+                if exists.unwrap_or(false) {
+                    return Err(warp::reject::custom(atomic::exists(&filename)));
+                }
+            }
+            quota::charge(tenant.as_ref(), false, stream.bytes_read() as u64, &notifier).await?;
 
-                let image = Image {
-                    filename: part.filename().unwrap().to_string(),
-                    image_container: container_name,
+            let location = if let Some((blob_client, location, blocks)) = staged {
+                let block_list = BlockList {
+                    blocks: blocks.into_iter().map(BlobBlockType::new_uncommitted).collect(),
                 };
+                if blob_name != filename {
+                    naming::stamp(&mut metadata, &filename);
+                }
+                let mut commit = blob_client
+                    .put_block_list(block_list)
+                    .content_type(content_type)
+                    .metadata(metadata)
+                    .tags(plan.blob_tags());
+                if naming::exclusive() {
+                    commit = commit.if_match(storage::if_none());
+                }
+                let committed = telemetry::dependency("Azure blob", &container_name, "put_block_list", commit.into_future()).await;
+                let taken = committed.as_ref().is_err_and(storage::is_taken);
+                failover::record_write(location, committed.is_ok() || taken);
+                if taken {
+                    let message = format!("'{}' was taken by another upload while '{}' was uploading", blob_name, filename);
+                    return Err(warp::reject::custom(ApiError::new(StatusCode::CONFLICT, message)));
+                }
+                if let Err(e) = committed {
+                    error!("Error committing the blocks of {}: {:?}", blob_name, e);
+                    return Err(warp::reject::custom(ApiError::storage(&e, &format!("Failed to store '{}'", filename))));
+                }
+                if let Err(e) = failover::record(&container_name, &blob_name, location).await {
+                    error!("Error recording the location of {}: {:?}", blob_name, e);
+                }
+                info!("Uploaded file url: {}", blob_client.url().expect("Failed to get blob url"));
+                location
+            } else if storage::kind() == storage::BackendKind::Azure {
+                // upload file to Azure Blob Storage, or the failover account while the primary is down
+                let hash = content_hash.as_deref();
+                let upload = naming::store(&container_client, &filename, hash, blob_name.clone(), |blob_name, exclusive| {
+                    let mut metadata = metadata.clone();
+                    if blob_name != filename {
+                        naming::stamp(&mut metadata, &filename);
+                    }
+                    let (container_name, bytes, customer_key, plan) = (&container_name, &bytes, &customer_key, &plan);
+                    failover::write(move |location| {
+                        let mut upload = container_client_at(container_name, location)
+                            .blob_client(&blob_name)
+                            .put_block_blob(bytes.clone())
+                            .content_type(content_type)
+                            .metadata(metadata.clone())
+                            .tags(plan.blob_tags());
+                        if let Some(customer_key) = customer_key {
+                            upload = upload.encryption_key(customer_key.clone());
+                        }
+                        if exclusive {
+                            upload = upload.if_match(storage::if_none());
+                        }
+                        let upload = upload.into_future();
+                        telemetry::dependency("Azure blob", container_name, "put_block_blob", upload)
+                    })
+                });
+                let location = match upload.await {
+                    Ok((stored_name, (_, location))) => {
+                        info!("Blob uploaded successfully");
+                        blob_name = stored_name;
+                        location
+                    }
+                    Err(e) => return Err(warp::reject::custom(e)),
+                };
+                if let Err(e) = failover::record(&container_name, &blob_name, location).await {
+                    error!("Error recording the location of {}: {:?}", blob_name, e);
+                }
+
+                let blob_client = container_client_at(&container_name, location).blob_client(&blob_name);
+                info!("Uploaded file url: {}", blob_client.url().expect("Failed to get blob url"));
+                location
+            } else {
+                // environments without Azure access keep originals in S3 or a local directory
+                let backend = storage::from_env(&container_client);
+                let hash = content_hash.as_deref();
+                let upload = naming::store(&container_client, &filename, hash, blob_name.clone(), |blob_name, exclusive| {
+                    let (backend, bytes) = (&backend, bytes.clone());
+                    async move {
+                        match exclusive {
+                            true => backend.put_new(&blob_name, bytes, content_type).await,
+                            false => backend.put(&blob_name, bytes, content_type).await,
+                        }
+                    }
+                });
+                match upload.await {
+                    Ok((stored_name, ())) => {
+                        blob_name = stored_name;
+                        info!("Uploaded file url: {}", backend.url(&blob_name).unwrap_or_default());
+                    }
+                    Err(e) => return Err(warp::reject::custom(e)),
+                }
+                Location::Primary
+            };
+            if let Some(hash) = content_hash.as_ref().filter(|_| duplicate_policy != DuplicatePolicy::Process) {
+                duplicates::record(&container_name, hash, &blob_name).await;
+            }
+
+            let mut image = plan.message(blob_name.clone(), container_name, location);
+            image.pixels = dimensions.map(|(width, height)| width as u64 * height as u64);
+            image.content_hash = content_hash.clone();
+            fan_out(&mut image, source_format);
 
-                send_message_to_queue(image).await;
+            let job_id = Uuid::new_v4();
+            match &mut transaction {
+                Some(transaction) => transaction.stored(filename.clone(), image, job_id, location, stream.bytes_read() as u64),
+                None => {
+                    queue_upload(&plan, image, job_id).await.map_err(|e| {
+                        error!("Error enqueueing {}: {:?}", filename, e);
+                        warp::reject::custom(ApiError::new(StatusCode::BAD_GATEWAY, "Failed to queue the image for processing"))
+                    })?;
+                    telemetry::track_event("ImageUploaded", &[("filename", filename.clone())]);
+                    count_upload("upload", stream.bytes_read() as u64);
+                }
+            }
+            jobs.insert(filename.clone(), job_id.to_string());
+            parts.push(part_report(&blob_name, Some(&job_id.to_string()), part_warnings, stream.bytes_read() as u64));
+            if let Some(hash) = content_hash {
+                stored.insert(hash, (blob_name.clone(), job_id.to_string()));
+            }
+            if let Some(predicted) = predicted {
+                renditions.insert(filename.clone(), predicted);
             }
+            if blob_name != filename {
+                blobs.insert(filename.clone(), blob_name);
+            }
+
+            uploaded_files.push(filename);
+        }
+        Ok(None)
+    }
+    .await;
+    let outcome = match (outcome, transaction) {
+        (Ok(None), Some(transaction)) => match transaction.commit(&plan, tenant.as_ref()).await {
+            Ok(()) => Ok(None),
+            Err(response) => return Ok(response),
+        },
+        (Err(rejection), Some(transaction)) => {
+            let error = match rejection.find::<ApiError>() {
+                Some(e) => ApiError::new(e.code, e.message.clone()),
+                None => ApiError::new(StatusCode::BAD_REQUEST, "Malformed multipart body"),
+            };
+            return Ok(transaction.roll_back(tenant.as_ref(), current, &error).await);
+        }
+        (outcome, _) => outcome,
+    };
+    if let Some(response) = outcome? {
+        return Ok(response);
+    }
+
+    if options.dry_run {
+        return Ok(warp::reply::json(&serde_json::json!({ "dry_run": true, "plans": estimates })).into_response());
+    }
+    let report = UploadReport {
+        uploaded: uploaded_files,
+        duplicates,
+        jobs,
+        blobs,
+        renditions,
+        parts,
+    };
+    Ok(warp::reply::json(&report).into_response())
+}
+
+/// Spells out the output format the worker would pick for the source, so the message can be routed
+/// to the format's queue when `FORMAT_QUEUES` is set, or when the tenant's tier doesn't offer it.
+fn fan_out(image: &mut ImageMessage, source_format: OutputFormat) {
+    if image.output_format.is_some() {
+        return;
+    }
+    let output_format = tiers::output_format(image, source_format);
+    if !config::get().service_bus.format_queues.is_empty() || output_format != source_format {
+        image.output_format = Some(output_format);
+    }
+}
+
+/// Processing requested for an upload, already checked against the tenant's policy and the feature flags.
+struct UploadPlan {
+    auto_enhance: bool,
+    then: Vec<Stage>,
+    width: u32,
+    height: u32,
+    tenant: Option<String>,
+    tags: Vec<String>,
+    metadata: BTreeMap<String, String>,
+    /// Largest acceptable size of each rendition in bytes.
+    target_size: Option<u64>,
+    /// Sizes the resized rendition instead of `width` and `height`.
+    resize: Option<ResizeSpec>,
+    /// How the resized rendition fills its box and is resampled, the preset's when not given.
+    fit: Option<Fit>,
+    filter: Option<resize_spec::Filter>,
+    /// Sizes made alongside the resized rendition.
+    variants: Vec<Variant>,
+    /// Format of the renditions, the source's where possible when not given.
+    output_format: Option<OutputFormat>,
+    crop: Option<Crop>,
+    focal_point: Option<FocalPoint>,
+    /// Edits applied in order before scaling, from the form's `operations` field.
+    operations: Vec<Operation>,
+    /// Notified by the worker when the job ends.
+    callback_url: Option<String>,
+    priority: Priority,
+    /// Tier of the tenant, see `core/src/tiers.rs`.
+    tier: Option<String>,
+    /// Adds each rendition to the job as soon as it's stored.
+    incremental: bool,
+    /// Holds the stored files for review instead of queueing them.
+    review: Option<review::ReviewPolicy>,
+}
+
+impl UploadPlan {
+    /// The queue message that starts processing of one stored file.
+    fn message(&self, filename: String, image_container: String, storage: Location) -> ImageMessage {
+        ImageMessage {
+            filename,
+            image_container,
+            auto_enhance: self.auto_enhance,
+            stage: Stage::Resize,
+            then: self.then.clone(),
+            width: self.width,
+            height: self.height,
+            tenant: self.tenant.clone(),
+            tags: self.tags.clone(),
+            metadata: self.metadata.clone(),
+            target_size: self.target_size,
+            resize: self.resize,
+            fit: self.fit,
+            filter: self.filter,
+            variants: self.variants.clone(),
+            output_format: self.output_format,
+            crop: self.crop,
+            focal_point: self.focal_point,
+            operations: self.operations.clone(),
+            callback_url: self.callback_url.clone(),
+            priority: self.priority,
+            tier: self.tier.clone(),
+            incremental: self.incremental,
+            storage,
+            queued_at: None,
+            job_id: None,
+            ..Default::default()
+        }
+    }
+
+    /// Blob metadata for the stored original, carrying the tenant, tags, custom metadata and focal point.
+    fn blob_metadata(&self) -> azure_core::request_options::Metadata {
+        let mut blob_metadata = metadata::blob_metadata(self.tenant.as_deref(), &self.tags, &self.metadata);
+        if let Some(focal_point) = &self.focal_point {
+            blob_metadata.insert(metadata::FOCAL_POINT_KEY, focal_point.to_string());
+        }
+        blob_metadata
+    }
+
+    /// Index tags for the stored original, see `core/src/blob_tags.rs`.
+    fn blob_tags(&self) -> azure_storage_blobs::prelude::Tags {
+        blob_tags::tags(self.tenant.as_deref(), blob_tags::ORIGINAL, blob_tags::UPLOADED)
+    }
+}
+
+/// Content type to store an original under, known from its first bytes: that of a PDF, an SVG, a
+/// video or the image format, see `core/src/video.rs`.
+fn original_content_type(bytes: &[u8]) -> &'static str {
+    if pdf::is_pdf(bytes) {
+        pdf::CONTENT_TYPE
+    } else if svg::is_svg(bytes) {
+        svg::CONTENT_TYPE
+    } else {
+        video::content_type(bytes)
+    }
+}
+
+async fn plan_upload(options: &UploadOptions, tenant: Option<&tenant::Tenant>) -> Result<UploadPlan, Rejection> {
+    let then = options
+        .follow_up_stages()
+        .map_err(|e| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, e)))?;
+    let tags = options
+        .tags()
+        .map_err(|e| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, e)))?;
+    let metadata = options
+        .metadata()
+        .map_err(|e| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, e)))?;
+    let target_size = options
+        .target_size()
+        .map_err(|e| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, e)))?;
+    let resize = options
+        .resize_spec()
+        .map_err(|e| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, e)))?;
+    let fit = options
+        .fit()
+        .map_err(|e| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, e)))?;
+    let filter = options
+        .filter()
+        .map_err(|e| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, e)))?;
+    let variants = options
+        .variants()
+        .map_err(|e| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, e)))?;
+    let output_format = options
+        .output_format()
+        .map_err(|e| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, e)))?;
+    let crop = options
+        .crop()
+        .map_err(|e| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, e)))?;
+    let focal_point = options
+        .focal_point()
+        .map_err(|e| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, e)))?;
+    let callback_url = options
+        .callback_url()
+        .map_err(|e| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, e)))?;
+    let priority = options
+        .priority()
+        .map_err(|e| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, e)))?;
+    let width = options.width.unwrap_or(DEFAULT_SIZE);
+    let height = options.height.unwrap_or(DEFAULT_SIZE);
+    if width == 0 || height == 0 {
+        return Err(warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, "Width and height must be positive")));
+    }
+    image_checks::check_output(width, height, resize).map_err(|e| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, e)))?;
+    if let Some(tenant) = tenant {
+        tenant
+            .policy
+            .check_request(width, height, resize, &then)
+            .and_then(|()| tenant.policy.check_variants(&variants))
+            // the resized rendition is one of the sizes
+            .and_then(|()| tenant.policy.tier().map_or(Ok(()), |tier| tier.check(output_format, 1 + variants.len())))
+            .map_err(|e| warp::reject::custom(ApiError::new(StatusCode::FORBIDDEN, e)))?;
+    }
+    let review = tenant.and_then(|tenant| tenant.policy.review);
+    let tier = tenant.and_then(|tenant| tenant.policy.tier.clone());
+    let priority = tenant.and_then(|tenant| tenant.policy.tier()).and_then(|tier| tier.priority).unwrap_or(priority);
+    let tenant = tenant.map(|t| t.id.clone());
+    if then.iter().any(|stage| matches!(stage, Stage::Render { .. }))
+        && !features::is_enabled(features::RENDER, tenant.as_deref()).await
+    {
+        return Err(warp::reject::custom(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "Template rendering is disabled in this environment",
+        )));
+    }
+
+    Ok(UploadPlan {
+        auto_enhance: options.enhance,
+        then,
+        width,
+        height,
+        tenant,
+        tags,
+        metadata,
+        target_size,
+        resize,
+        fit,
+        filter,
+        variants,
+        output_format,
+        crop,
+        focal_point,
+        operations: Vec::new(),
+        callback_url,
+        priority,
+        tier,
+        incremental: options.incremental,
+        review,
+    })
+}
+
+/// Version, commit and capabilities of this build, served on `/version`.
+fn build_info() -> build_info::BuildInfo {
+    let mut backends = vec!["azure_blob", "azure_service_bus"];
+    if telemetry::enabled() {
+        backends.push("application_insights");
+    }
+    if trace::enabled() {
+        backends.push("otlp");
+    }
+    build_info::build_info(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), backends)
+}
+
+/// Builds a client for the source container from the `AZURE_STORAGE_*` env vars.
+fn container_client() -> ContainerClient {
+    container_client_for(config::get().container())
+}
+
+/// Builds a client for another container in the same storage account.
+fn container_client_for(container_name: &str) -> ContainerClient {
+    container_client_at(container_name, Location::Primary)
+}
+
+/// Builds a client for a container in the primary or the failover storage account.
+fn container_client_at(container_name: &str, location: Location) -> ContainerClient {
+    clients::container_client(container_name, location)
+}
+
+/// Builds a client for the default container in whichever account `blob` was last written to.
+async fn container_client_holding(blob: &str) -> ContainerClient {
+    let container_name = config::get().container();
+    let location = failover::location_of(container_name, blob).await;
+    container_client_at(container_name, location)
+}
 
-            // return the part name, filename and bytes as a tuple
-            Ok((
-                part.name().to_string(),
-                part.filename().unwrap().to_string(),
-                String::from_utf8_lossy(&*bytes).to_string(),
-            ))
-        })
-        .try_collect()
-        .await
-        .map_err(|_| warp::reject::reject())?;
+/// Downloads a whole blob into memory, 8KB at a time, from the secondary endpoint if the primary fails.
+async fn read_blob(blob_client: &BlobClient) -> azure_core::Result<Vec<u8>> {
+    geo_read::read(blob_client, |blob_client| async move {
+        let mut bytes: Vec<u8> = Vec::new();
+        let mut stream = blob_client.get().chunk_size(0x2000u64).into_stream();
+        while let Some(value) = stream.next().await {
+            let data = value?.data.collect().await?;
+            bytes.extend(&data);
+        }
+        chaos::truncate(&mut bytes, "read");
+        Ok(bytes)
+    })
+    .await
+}
+
+/// Counts a file stored by the upload route `route`, for `GET /metrics`.
+fn count_upload(route: &str, bytes: u64) {
+    metrics::increment("uploaded_files_total", &[("route", route)]);
+    metrics::add("uploaded_bytes_total", &[("route", route)], bytes as f64);
+}
+
+/// Queues `image` as a new job, recorded as queued in the job status table, and returns its id.
+async fn send_message_to_queue(image: ImageMessage) -> azure_core::Result<Uuid> {
+    send_job(image, Uuid::new_v4()).await
+}
 
-    Ok(format!("Uploaded files: {:?}", uploaded_files))
+/// Queues an uploaded file as the job `job_id`, or holds it if `plan` asks for review.
+async fn queue_upload(plan: &UploadPlan, image: ImageMessage, job_id: Uuid) -> azure_core::Result<Uuid> {
+    match &plan.review {
+        Some(policy) => review::hold(image, job_id, policy).await,
+        None => send_job(image, job_id).await,
+    }
 }
 
-async fn send_message_to_queue(image: Image) {
-    let service_bus_namespace = env::var("AZURE_SERVICE_BUS_NAMESPACE").expect("Please set AZURE_SERVICE_BUS_NAMESPACE env variable first!");
-    let queue_name = env::var("AZURE_QUEUE_NAME").expect("Please set AZURE_QUEUE_NAME env variable first!");
-    let policy_name = env::var("AZURE_POLICY_NAME").expect("Please set AZURE_POLICY_NAME env variable first!");
-    let policy_key = env::var("AZURE_POLICY_KEY").expect("Please set AZURE_POLICY_KEY env variable first!");
-    
-    let http_client = azure_core::new_http_client();
+/// Queues `image` as the job `job_id`.
+async fn send_job(mut image: ImageMessage, job_id: Uuid) -> azure_core::Result<Uuid> {
+    // with `FORMAT_QUEUES` set, each output format may have its own queue and worker pool, and with
+    // `QUEUE_SHARDS` the job goes to the shard of its image or tenant, see `core/src/shards.rs`
+    let sender = QueueSender::for_format(image.output_format).for_image(&image);
 
-    let client = QueueClient::new(
-        http_client, 
-        service_bus_namespace, 
-        queue_name, 
-        policy_name, 
-        policy_key
-    ).expect("Failed to create client");
+    image.job_id = Some(job_id.to_string());
+    // a job that can't be recorded still runs, it just can't be polled
+    if let Err(e) = job_status::create_job(&job_id.to_string(), &image).await {
+        error!("Error recording job {} for {}: {:?}", job_id, image.filename, e);
+    }
 
+    image.queued_at = Some(date::to_rfc3339(&OffsetDateTime::now_utc()));
+    // the worker continues the trace of the request, see `core/src/trace.rs`
+    image.traceparent = trace::traceparent();
     let message_to_send = serde_json::to_string(&image).expect("Failed to serialize image");
 
-    client
-        .send_message(message_to_send.as_str())
-        .await
-        .expect("Failed to send message");
+    let sent = telemetry::dependency(
+        "Azure Service Bus",
+        sender.queue_name(),
+        "send_message",
+        sender.send(&image, &message_to_send),
+    )
+    .await;
+    if sent.is_err() {
+        metrics::increment("queue_send_failures_total", &[("queue", sender.queue_name())]);
+    }
+    sent?;
+    metrics::increment("jobs_queued_total", &[("queue", sender.queue_name())]);
 
-    println!("Message sent to Azure Service Bus queue successfully!");
-    println!("Message: {}", message_to_send);
+    info!("Message {} sent to Azure Service Bus queue successfully!", image.message_id());
+    info!("Message: {}", message_to_send);
+    Ok(job_id)
 }
 
-async fn handle_rejection(err: Rejection) -> std::result::Result<impl Reply, Infallible> {
-    let (code, message) = if err.is_not_found() {
+async fn handle_rejection(err: Rejection) -> std::result::Result<warp::reply::Response, Infallible> {
+    let (code, message) = if let Some(e) = err.find::<ApiError>() {
+        (e.code, e.message.clone())
+    } else if err.is_not_found() {
         (StatusCode::NOT_FOUND, "Not Found".to_string())
     } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
         (StatusCode::BAD_REQUEST, "Payload too large".to_string())
     } else {
-        eprintln!("unhandled error: {:?}", err);
+        error!("unhandled error: {:?}", err);
+        telemetry::track_exception("Rejection", &format!("{:?}", err));
         (
             StatusCode::INTERNAL_SERVER_ERROR,
             "Internal Server Error".to_string(),
         )
     };
 
-    Ok(warp::reply::with_status(message, code))
+    // the English message, see `i18n::localize`
+    let message = logging::redact(&message).into_owned();
+    let mut response = warp::reply::with_status(message.clone(), code).into_response();
+    response.extensions_mut().insert(i18n::ErrorMessage(message));
+    Ok(response)
 }
\ No newline at end of file
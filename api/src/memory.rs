@@ -0,0 +1,108 @@
+// api/src/memory.rs
+
+//! Load shedding on memory pressure, so a burst of large uploads gets 503s instead of the process
+//! being OOM-killed with every request in flight. The routes that buffer request bodies, see
+//! `limit::permit`, reserve the `Content-Length` each request declares until it's answered.
+//!
+//! - With `MAX_BUFFERED_UPLOAD_BYTES` set, a request whose body would take the reserved bytes past
+//!   it is refused. A request alone is always let through, so one larger than the budget isn't
+//!   refused for good; `MAX_REQUEST_BYTES` bounds that.
+//! - With `MAX_RSS_BYTES` set, new requests are refused while the resident set of the process is
+//!   at or over it, read from `/proc/self/status`. It is ignored where that file doesn't exist.
+//!
+//! Both are off by default. Refusals are counted in `uploads_shed_total` by reason. S3 `PUT`s
+//! answer in S3's own error format and aren't shed.
+
+use image_resize_core::metrics;
+use std::{
+    fs,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+};
+use tracing::{info, warn};
+use warp::http::StatusCode;
+
+use crate::{error::ApiError, limit};
+
+const MIB: u64 = 1024 * 1024;
+
+/// Body bytes reserved by the requests in flight.
+static BUFFERED: AtomicU64 = AtomicU64::new(0);
+
+struct MemoryBudget {
+    max_buffered: u64,
+    max_rss: u64,
+}
+
+fn budget() -> &'static MemoryBudget {
+    static BUDGET: OnceLock<MemoryBudget> = OnceLock::new();
+    BUDGET.get_or_init(|| {
+        let budget = MemoryBudget {
+            max_buffered: limit::env_or("MAX_BUFFERED_UPLOAD_BYTES", 0),
+            max_rss: limit::env_or("MAX_RSS_BYTES", 0),
+        };
+        if budget.max_buffered > 0 {
+            info!("Refusing uploads over {} MiB of buffered bodies", budget.max_buffered / MIB);
+        }
+        if budget.max_rss > 0 {
+            info!("Refusing uploads while the resident set is over {} MiB", budget.max_rss / MIB);
+        }
+        budget
+    })
+}
+
+/// The resident set of the process, `None` where `/proc` can't tell.
+fn rss() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kib: u64 = line["VmRSS:".len()..].trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kib * 1024)
+}
+
+/// Bytes reserved for a request's body, given back when it's dropped.
+pub struct Reservation(u64);
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        BUFFERED.fetch_sub(self.0, Ordering::Relaxed);
+    }
+}
+
+fn shed(reason: &str, message: String) -> ApiError {
+    warn!("{}", message);
+    metrics::increment("uploads_shed_total", &[("reason", reason)]);
+    ApiError::new(StatusCode::SERVICE_UNAVAILABLE, message)
+}
+
+/// Reserves `bytes` for a request's body, or refuses it if memory is short.
+pub fn reserve(bytes: u64) -> Result<Reservation, ApiError> {
+    let budget = budget();
+    if budget.max_rss > 0 {
+        if let Some(rss) = rss().filter(|rss| *rss >= budget.max_rss) {
+            return Err(shed(
+                "rss",
+                format!("The server is short of memory ({} of {} MiB in use), retry later", rss / MIB, budget.max_rss / MIB),
+            ));
+        }
+    }
+    let before = BUFFERED.fetch_add(bytes, Ordering::Relaxed);
+    let reservation = Reservation(bytes);
+    if budget.max_buffered > 0 && before > 0 && before + bytes > budget.max_buffered {
+        return Err(shed(
+            "buffered",
+            format!(
+                "The server is buffering {} MiB of uploads and can't take {} MiB more, retry later",
+                before / MIB,
+                bytes.div_ceil(MIB)
+            ),
+        ));
+    }
+    Ok(reservation)
+}
+
+/// Reads the budget, so it's logged at startup.
+pub fn init() {
+    budget();
+}
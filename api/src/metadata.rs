@@ -0,0 +1,124 @@
+// api/src/metadata.rs
+
+//! Custom key/value metadata supplied with uploads. It is stored on the original blob under a
+//! `meta_` prefix, so it can't collide with what the pipeline writes itself (`tenant`,
+//! `worker_version`, ...), carried to the worker in the queue message and copied onto every
+//! rendition. Upload tags are kept next to it as a comma separated `tags` entry.
+
+use azure_core::request_options::Metadata;
+use image_resize_core::filenames;
+use std::collections::{BTreeMap, HashMap};
+use warp::{http::StatusCode, Rejection, Reply};
+use tracing::error;
+
+use crate::{container_client_holding, error::ApiError, tenant::Tenant};
+
+pub const USER_PREFIX: &str = "meta_";
+const TAGS_KEY: &str = "tags";
+pub const TENANT_KEY: &str = "tenant";
+/// The upload's focal point as `x,y`, see `core/src/crop.rs`.
+pub const FOCAL_POINT_KEY: &str = "focal_point";
+const MAX_ENTRIES: usize = 16;
+const MAX_KEY_LEN: usize = 64;
+const MAX_VALUE_LEN: usize = 256;
+/// Azure allows 8KB of metadata per blob; the rest is left to the pipeline.
+const MAX_TOTAL_BYTES: usize = 2048;
+
+/// Keys are lowercase identifiers, values printable ASCII, since both travel as HTTP headers.
+pub fn validate(metadata: &BTreeMap<String, String>) -> Result<(), String> {
+    if metadata.len() > MAX_ENTRIES {
+        return Err(format!("At most {} metadata entries are allowed", MAX_ENTRIES));
+    }
+    let mut total = 0;
+    for (key, value) in metadata {
+        let valid_key = key.len() <= MAX_KEY_LEN
+            && key.starts_with(|c: char| c.is_ascii_lowercase())
+            && key.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if !valid_key {
+            return Err(format!(
+                "Invalid metadata key '{}', use up to {} lowercase letters, digits or '_', starting with a letter",
+                key, MAX_KEY_LEN
+            ));
+        }
+        if value.len() > MAX_VALUE_LEN || !value.chars().all(|c| c.is_ascii_graphic() || c == ' ') {
+            return Err(format!(
+                "Invalid value for metadata key '{}', use up to {} printable ASCII characters",
+                key, MAX_VALUE_LEN
+            ));
+        }
+        total += key.len() + value.len();
+    }
+    if total > MAX_TOTAL_BYTES {
+        return Err(format!("Metadata may not exceed {} bytes in total", MAX_TOTAL_BYTES));
+    }
+    Ok(())
+}
+
+/// Parses and validates the JSON object given as the `metadata` upload option.
+pub fn parse(json: &str) -> Result<BTreeMap<String, String>, String> {
+    let metadata: BTreeMap<String, String> = serde_json::from_str(json)
+        .map_err(|_| "metadata must be a JSON object of string values".to_string())?;
+    validate(&metadata)?;
+    Ok(metadata)
+}
+
+/// Blob metadata for a stored original.
+pub fn blob_metadata(tenant: Option<&str>, tags: &[String], metadata: &BTreeMap<String, String>) -> Metadata {
+    let mut blob_metadata = Metadata::new();
+    if let Some(tenant) = tenant {
+        blob_metadata.insert(TENANT_KEY, tenant.to_string());
+    }
+    if !tags.is_empty() {
+        blob_metadata.insert(TAGS_KEY, tags.join(","));
+    }
+    for (key, value) in metadata {
+        blob_metadata.insert(format!("{}{}", USER_PREFIX, key), value.clone());
+    }
+    blob_metadata
+}
+
+/// Upload tags and custom metadata read back from a blob's metadata.
+pub fn user_metadata(blob_metadata: &HashMap<String, String>) -> (Vec<String>, BTreeMap<String, String>) {
+    let tags = blob_metadata
+        .get(TAGS_KEY)
+        .map(|tags| tags.split(',').filter(|t| !t.is_empty()).map(str::to_string).collect())
+        .unwrap_or_default();
+    let metadata = blob_metadata
+        .iter()
+        .filter_map(|(key, value)| key.strip_prefix(USER_PREFIX).map(|key| (key.to_string(), value.clone())))
+        .collect();
+    (tags, metadata)
+}
+
+/// `GET /images/{name}/metadata`: size, content type, tags and custom metadata of a stored blob.
+/// Blobs belonging to another tenant are reported as missing.
+pub async fn get_metadata(name: String, tenant: Option<Tenant>) -> Result<impl Reply, Rejection> {
+    let name = filenames::from_path(&name)
+        .ok_or_else(|| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, "Invalid blob name")))?;
+    let not_found = || warp::reject::custom(ApiError::new(StatusCode::NOT_FOUND, format!("No image named {}", name)));
+
+    let properties = match container_client_holding(&name).await.blob_client(&name).get_properties().await {
+        Ok(properties) => properties,
+        Err(e) if e.as_http_error().is_some_and(|e| e.status() == azure_core::StatusCode::NotFound) => {
+            return Err(not_found())
+        }
+        Err(e) => {
+            error!("Error reading properties of {}: {:?}", name, e);
+            return Err(warp::reject::custom(ApiError::storage(&e, "Failed to reach blob storage")));
+        }
+    };
+
+    let blob_metadata = properties.blob.metadata.unwrap_or_default();
+    if blob_metadata.get(TENANT_KEY) != tenant.as_ref().map(|t| &t.id) {
+        return Err(not_found());
+    }
+    let (tags, metadata) = user_metadata(&blob_metadata);
+    Ok(warp::reply::json(&serde_json::json!({
+        "name": name,
+        "content_type": properties.blob.properties.content_type,
+        "size": properties.blob.properties.content_length,
+        "last_modified": azure_core::date::to_rfc3339(&properties.blob.properties.last_modified),
+        "tags": tags,
+        "metadata": metadata,
+    })))
+}
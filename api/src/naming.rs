@@ -0,0 +1,271 @@
+// api/src/naming.rs
+
+//! Names `/upload`, `/upload/zip` and `/upload-inline` store originals under, by the strategy of
+//! `core/src/naming.rs`. An original stored under another name than its filename keeps the
+//! filename as its `original_filename` metadata. With `BLOB_NAMING=content`, a re-upload of an
+//! original that was already processed is answered with its renditions as under the `existing`
+//! duplicates policy, see `duplicates.rs`, and parts are held in memory whole to be hashed before
+//! they're named, rather than staged block by block. tus uploads and S3 objects are named the
+//! same way, see `tus.rs` and `s3.rs`; ingested uploads keep the names they were given.
+//!
+//! Unless `BLOB_NAME_COLLISIONS=replace`, an original is written only if its name is still free
+//! (`If-None-Match: *`), since another upload may have taken it after it was looked up. A write
+//! refused with `409` or `412` moves on to the next free name, see [`store`], or is refused with
+//! `409` when names aren't suffixed. A large part staged block by block can't move, and is refused
+//! with `409` when its name is taken before its blocks are committed.
+
+use azure_core::request_options::Metadata;
+use std::future::Future;
+use azure_storage_blobs::prelude::ContainerClient;
+use image_resize_core::{
+    naming::{self, Collisions},
+    pipeline, storage,
+};
+use tracing::{error, info};
+use warp::http::StatusCode;
+
+use crate::error::ApiError;
+
+/// The filename an original was uploaded with, percent-encoded where it isn't printable ASCII.
+pub const ORIGINAL_FILENAME_KEY: &str = "original_filename";
+
+/// Whether originals are named by their content.
+pub fn content_addressed() -> bool {
+    naming::strategy().needs_content()
+}
+
+/// The name to store an original uploaded as `filename` under in `container_client`, `sha256`
+/// being the hash of its content when the strategy names by content. A name that's taken is
+/// handled as `BLOB_NAME_COLLISIONS` says.
+pub async fn original_name(container_client: &ContainerClient, filename: &str, sha256: Option<&str>) -> Result<String, ApiError> {
+    let strategy = naming::strategy();
+    let name = strategy.original(filename, sha256);
+    let collisions = naming::collisions();
+    if !strategy.may_collide() || collisions == Collisions::Replace {
+        return Ok(name);
+    }
+    let backend = storage::from_env(container_client);
+    let is_taken = |candidate: String| {
+        let backend = &backend;
+        async move { backend.exists(&candidate).await }
+    };
+    let free = match collisions {
+        Collisions::Suffix => naming::free_name(&name, is_taken).await,
+        _ => is_taken(name.clone()).await.map(|taken| (!taken).then(|| name.clone())),
+    }
+    .map_err(|e| {
+        error!("Error looking up {}: {:?}", name, e);
+        ApiError::storage(&e, &format!("Failed to name '{}'", filename))
+    })?;
+    match free {
+        Some(free) => {
+            if free != name {
+                info!("{} is taken, storing {} as {}", name, filename, free);
+            }
+            Ok(free)
+        }
+        None => Err(ApiError::new(StatusCode::CONFLICT, format!("'{}' is already taken", name))),
+    }
+}
+
+/// Whether originals are written only where nothing is stored yet: their names may collide and a
+/// taken one isn't replaced.
+pub fn exclusive() -> bool {
+    naming::strategy().may_collide() && naming::collisions() != Collisions::Replace
+}
+
+/// Most times an original is named again after losing its name to another upload.
+const MAX_RACES: usize = 5;
+
+/// Writes an original uploaded as `filename` under `name`, picked by [`original_name`], with
+/// `write`, which is given the name and whether it must only write it if it's free. A write that
+/// loses its name to another upload is tried again under the next one. Returns the name written
+/// and what `write` returned.
+pub async fn store<T, F, Fut>(
+    container_client: &ContainerClient,
+    filename: &str,
+    sha256: Option<&str>,
+    name: String,
+    write: F,
+) -> Result<(String, T), ApiError>
+where
+    F: FnMut(String, bool) -> Fut,
+    Fut: Future<Output = azure_core::Result<T>>,
+{
+    let rename = || original_name(container_client, filename, sha256);
+    store_as(exclusive(), naming::collisions(), filename, name, write, rename).await
+}
+
+/// [`store`] with the settings given, and `rename` naming the original again after a lost race.
+async fn store_as<T, F, Fut, R, RFut>(
+    exclusive: bool,
+    collisions: Collisions,
+    filename: &str,
+    mut name: String,
+    mut write: F,
+    mut rename: R,
+) -> Result<(String, T), ApiError>
+where
+    F: FnMut(String, bool) -> Fut,
+    Fut: Future<Output = azure_core::Result<T>>,
+    R: FnMut() -> RFut,
+    RFut: Future<Output = Result<String, ApiError>>,
+{
+    let mut races = 0;
+    loop {
+        match write(name.clone(), exclusive).await {
+            Ok(value) => return Ok((name, value)),
+            Err(e) if exclusive && storage::is_taken(&e) && collisions == Collisions::Suffix && races < MAX_RACES => {
+                races += 1;
+                let taken = name;
+                name = rename().await?;
+                info!("{} was taken by another upload, storing {} as {}", taken, filename, name);
+            }
+            Err(e) if exclusive && storage::is_taken(&e) => {
+                return Err(ApiError::new(StatusCode::CONFLICT, format!("'{}' is already taken", name)));
+            }
+            Err(e) => {
+                error!("Error uploading {}: {:?}", name, e);
+                return Err(ApiError::storage(&e, &format!("Failed to store '{}'", filename)));
+            }
+        }
+    }
+}
+
+/// Records `filename` on the metadata of an original stored under another name.
+pub fn stamp(metadata: &mut Metadata, filename: &str) {
+    metadata.insert(ORIGINAL_FILENAME_KEY, pipeline::metadata_name(filename));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use azure_core::error::{Error, ErrorKind};
+    use std::{
+        collections::HashSet,
+        sync::{Arc, Mutex},
+    };
+
+    /// A container holding `names`, written to as storage would.
+    #[derive(Clone, Default)]
+    struct Container {
+        names: Arc<Mutex<HashSet<String>>>,
+        writes: Arc<Mutex<Vec<(String, bool)>>>,
+    }
+
+    impl Container {
+        fn holding(names: &[&str]) -> Self {
+            let container = Container::default();
+            container.names.lock().unwrap().extend(names.iter().map(|name| name.to_string()));
+            container
+        }
+
+        async fn write(&self, name: String, exclusive: bool) -> azure_core::Result<()> {
+            self.writes.lock().unwrap().push((name.clone(), exclusive));
+            let mut names = self.names.lock().unwrap();
+            if exclusive && names.contains(&name) {
+                let kind = ErrorKind::http_response(azure_core::StatusCode::Conflict, Some("BlobAlreadyExists".to_string()));
+                return Err(Error::message(kind, "The specified blob already exists."));
+            }
+            names.insert(name);
+            Ok(())
+        }
+
+        /// The first free name, as `original_name` finds it.
+        async fn free(&self, name: &str) -> Result<String, ApiError> {
+            let free = naming::free_name(name, |candidate| {
+                let taken = self.names.lock().unwrap().contains(&candidate);
+                async move { Ok::<_, ()>(taken) }
+            })
+            .await;
+            Ok(free.unwrap().unwrap())
+        }
+
+        fn writes(&self) -> Vec<(String, bool)> {
+            self.writes.lock().unwrap().clone()
+        }
+    }
+
+    async fn store_in(container: &Container, exclusive: bool, collisions: Collisions, name: &str) -> Result<String, ApiError> {
+        let write = |name, exclusive| container.write(name, exclusive);
+        let rename = || container.free("photo.jpg");
+        store_as(exclusive, collisions, "photo.jpg", name.to_string(), write, rename)
+            .await
+            .map(|(name, ())| name)
+    }
+
+    #[tokio::test]
+    async fn writes_a_free_name_only_if_still_free() {
+        let container = Container::holding(&[]);
+        assert_eq!(store_in(&container, true, Collisions::Suffix, "photo.jpg").await.unwrap(), "photo.jpg");
+        assert_eq!(container.writes(), [("photo.jpg".to_string(), true)]);
+    }
+
+    #[tokio::test]
+    async fn takes_the_next_name_after_losing_a_race() {
+        // another upload took `photo.jpg` after it was found free
+        let container = Container::holding(&["photo.jpg"]);
+        assert_eq!(store_in(&container, true, Collisions::Suffix, "photo.jpg").await.unwrap(), "photo-1.jpg");
+        assert_eq!(container.writes(), [("photo.jpg".to_string(), true), ("photo-1.jpg".to_string(), true)]);
+
+        // and then `photo-1.jpg`, while `photo-2.jpg` was being picked
+        let container = Container::holding(&["photo.jpg", "photo-1.jpg"]);
+        assert_eq!(store_in(&container, true, Collisions::Suffix, "photo-1.jpg").await.unwrap(), "photo-2.jpg");
+    }
+
+    #[tokio::test]
+    async fn refuses_a_name_lost_under_reject() {
+        let container = Container::holding(&["photo.jpg"]);
+        let e = store_in(&container, true, Collisions::Reject, "photo.jpg").await.unwrap_err();
+        assert_eq!((e.code, e.message.as_str()), (StatusCode::CONFLICT, "'photo.jpg' is already taken"));
+        assert_eq!(container.writes().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_losing_too_many_races() {
+        let container = Container::holding(&["photo.jpg"]);
+        // every name it picks is taken by the time it's written
+        let write = |name: String, exclusive| {
+            let container = container.clone();
+            async move {
+                container.names.lock().unwrap().insert(name.clone());
+                container.write(name, exclusive).await
+            }
+        };
+        let rename = || container.free("photo.jpg");
+        let e = store_as(true, Collisions::Suffix, "photo.jpg", "photo.jpg".to_string(), write, rename).await.unwrap_err();
+        assert_eq!(e.code, StatusCode::CONFLICT);
+        assert_eq!(container.writes().len(), MAX_RACES + 1);
+    }
+
+    #[tokio::test]
+    async fn overwrites_when_names_are_replaced() {
+        let container = Container::holding(&["photo.jpg"]);
+        assert_eq!(store_in(&container, false, Collisions::Replace, "photo.jpg").await.unwrap(), "photo.jpg");
+        assert_eq!(container.writes(), [("photo.jpg".to_string(), false)]);
+    }
+
+    #[tokio::test]
+    async fn doesnt_retry_other_failures() {
+        let write = |_, _| async { Err::<(), _>(Error::message(ErrorKind::Io, "connection reset")) };
+        let rename = || async { panic!("renamed after a failure that isn't a lost race") };
+        let e = store_as(true, Collisions::Suffix, "photo.jpg", "photo.jpg".to_string(), write, rename).await.unwrap_err();
+        assert_eq!((e.code, e.message.as_str()), (StatusCode::BAD_GATEWAY, "Failed to store 'photo.jpg'"));
+
+        // a precondition failing is a lost race too
+        let container = Container::holding(&[]);
+        let write = |name: String, exclusive| {
+            let container = container.clone();
+            async move {
+                if name == "photo.jpg" {
+                    let kind = ErrorKind::http_response(azure_core::StatusCode::PreconditionFailed, None);
+                    return Err(Error::message(kind, "At least one of the conditions specified was not met."));
+                }
+                container.write(name, exclusive).await
+            }
+        };
+        let rename = || async { Ok("photo-1.jpg".to_string()) };
+        let (name, ()) = store_as(true, Collisions::Suffix, "photo.jpg", "photo.jpg".to_string(), write, rename).await.unwrap();
+        assert_eq!(name, "photo-1.jpg");
+    }
+}
@@ -0,0 +1,145 @@
+// api/src/notify.rs
+
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport, AsyncTransport, Message,
+    Tokio1Executor,
+};
+use std::{collections::HashSet, env, sync::Mutex};
+use uuid::Uuid;
+use tracing::{error, info, warn};
+
+use crate::progress::Progress;
+
+const DEFAULT_FAILURE_RATE_THRESHOLD: f64 = 0.2;
+/// Failure rate alerts wait for this many finished items so the first failure doesn't trip them.
+const MIN_ITEMS_FOR_FAILURE_RATE: usize = 10;
+
+/// Sends summary emails for batch operations over SMTP. Does nothing unless `SMTP_HOST` is set.
+pub struct Notifier {
+    mailer: Option<AsyncSmtpTransport<Tokio1Executor>>,
+    from: Option<Mailbox>,
+    /// Always notified, from the comma separated `NOTIFY_EMAIL_TO`.
+    default_recipients: Vec<String>,
+    failure_rate_threshold: f64,
+    /// Operations already alerted about, so a failing batch sends one alert rather than one per item.
+    alerted: Mutex<HashSet<Uuid>>,
+}
+
+impl Notifier {
+    pub fn from_env() -> Self {
+        let mailer = env::var("SMTP_HOST").ok().map(|host| {
+            let mut builder = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host)
+                .expect("Invalid SMTP_HOST");
+            if let Ok(port) = env::var("SMTP_PORT") {
+                builder = builder.port(port.parse().expect("Invalid SMTP_PORT"));
+            }
+            if let (Ok(username), Ok(password)) = (env::var("SMTP_USERNAME"), env::var("SMTP_PASSWORD")) {
+                builder = builder.credentials(Credentials::new(username, password));
+            }
+            builder.build()
+        });
+        let from = mailer.as_ref().map(|_| {
+            env::var("NOTIFY_EMAIL_FROM")
+                .expect("Missing NOTIFY_EMAIL_FROM env var")
+                .parse()
+                .expect("Invalid NOTIFY_EMAIL_FROM")
+        });
+
+        Notifier {
+            mailer,
+            from,
+            default_recipients: env::var("NOTIFY_EMAIL_TO")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(str::to_string)
+                .collect(),
+            failure_rate_threshold: env::var("NOTIFY_FAILURE_RATE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_FAILURE_RATE_THRESHOLD),
+            alerted: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Summary email once a batch operation has finished.
+    pub async fn operation_finished(&self, progress: &Progress, recipients: &[String]) {
+        let subject = format!("{} {} finished: {} succeeded, {} failed", progress.kind, progress.id, progress.succeeded, progress.failed);
+        self.send(&subject, &summary(progress), recipients).await;
+    }
+
+    /// Alerts once per operation when the share of failed items goes over the threshold.
+    pub async fn check_failure_rate(&self, progress: &Progress, recipients: &[String]) {
+        let finished = progress.succeeded + progress.failed;
+        if finished < MIN_ITEMS_FOR_FAILURE_RATE {
+            return;
+        }
+        let rate = progress.failed as f64 / finished as f64;
+        if rate <= self.failure_rate_threshold || !self.alerted.lock().unwrap().insert(progress.id) {
+            return;
+        }
+
+        let subject = format!("{} {} failure rate at {:.0}%", progress.kind, progress.id, rate * 100.0);
+        self.send(&subject, &summary(progress), recipients).await;
+    }
+
+    /// Warns that a tenant has crossed one of the alert thresholds of a quota.
+    pub async fn quota_warning(&self, tenant: &str, meter: &str, percent: i64, used: i64, limit: i64, recipients: &[String]) {
+        let subject = format!("Tenant {} has used {}% of its {} quota", tenant, percent, meter);
+        let body = format!(
+            "Tenant: {}\nQuota: {}\nUsed: {} of {}\n\nUploads are rejected once the quota is used up.\n",
+            tenant, meter, used, limit
+        );
+        self.send(&subject, &body, recipients).await;
+    }
+
+    async fn send(&self, subject: &str, body: &str, recipients: &[String]) {
+        let (Some(mailer), Some(from)) = (&self.mailer, &self.from) else {
+            return;
+        };
+
+        let mut builder = Message::builder().from(from.clone()).subject(subject);
+        let mut any_recipient = false;
+        for recipient in self.default_recipients.iter().chain(recipients) {
+            match recipient.parse::<Mailbox>() {
+                Ok(mailbox) => {
+                    builder = builder.to(mailbox);
+                    any_recipient = true;
+                }
+                Err(e) => warn!("Skipping invalid notification address {}: {:?}", recipient, e),
+            }
+        }
+        if !any_recipient {
+            return;
+        }
+
+        let message = match builder.body(body.to_string()) {
+            Ok(message) => message,
+            Err(e) => {
+                error!("Error building notification email: {:?}", e);
+                return;
+            }
+        };
+        match mailer.send(message).await {
+            Ok(_) => info!("Sent notification: {}", subject),
+            Err(e) => error!("Error sending notification email: {:?}", e),
+        }
+    }
+}
+
+fn summary(progress: &Progress) -> String {
+    let mut body = format!(
+        "Operation: {} ({})\nState: {:?}\nTotal: {}\nSucceeded: {}\nFailed: {}\n",
+        progress.id, progress.kind, progress.state, progress.total, progress.succeeded, progress.failed
+    );
+    if !progress.errors.is_empty() {
+        body.push_str("\nErrors:\n");
+        for error in &progress.errors {
+            body.push_str("  ");
+            body.push_str(error);
+            body.push('\n');
+        }
+    }
+    body
+}
@@ -0,0 +1,175 @@
+// api/src/paging.rs
+
+//! Cursor pagination shared by the list endpoints. Every list takes `limit`, `order_by` (`name`,
+//! `size` or `modified`, where the endpoint has them), `direction` (`asc` or `desc`, by default
+//! ascending for names and descending otherwise) and `cursor`, and answers with its items plus a
+//! `next_cursor` to pass back for the following page.
+//!
+//! A cursor names the sort key of the last item returned rather than an offset, so paging stays
+//! consistent while items are added or removed in between requests.
+
+use azure_core::base64;
+pub use image_resize_core::models::{Page, PageQuery};
+use warp::{http::StatusCode, Rejection};
+
+use crate::error::ApiError;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OrderBy {
+    Name,
+    Size,
+    Modified,
+    /// Search score, only offered by `/search`.
+    Relevance,
+}
+
+impl OrderBy {
+    fn as_str(self) -> &'static str {
+        match self {
+            OrderBy::Name => "name",
+            OrderBy::Size => "size",
+            OrderBy::Modified => "modified",
+            OrderBy::Relevance => "relevance",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Asc,
+    Desc,
+}
+
+/// Position of an item in a listing: a numeric key for size, time or score (zero when ordering by
+/// name), then the name, which also breaks ties.
+pub type SortKey = (i128, String);
+
+/// A validated [`PageQuery`].
+#[derive(Debug)]
+pub struct PageRequest {
+    pub limit: usize,
+    pub order_by: OrderBy,
+    pub direction: Direction,
+    after: Option<SortKey>,
+}
+
+fn bad_request(message: impl Into<String>) -> Rejection {
+    warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, message))
+}
+
+/// Listing defaults of one endpoint.
+pub struct Defaults {
+    pub limit: usize,
+    pub max_limit: usize,
+    /// Orderings the endpoint supports, the first one being its default.
+    pub orderings: &'static [OrderBy],
+}
+
+/// Validates the paging parameters of a request against its endpoint's `defaults`.
+pub fn parse(query: &PageQuery, defaults: &Defaults) -> Result<PageRequest, Rejection> {
+    let order_by = match query.order_by.as_deref() {
+        None => defaults.orderings[0],
+        Some(value) => *defaults
+            .orderings
+            .iter()
+            .find(|o| o.as_str() == value)
+            .ok_or_else(|| {
+                let names: Vec<_> = defaults.orderings.iter().map(|o| o.as_str()).collect();
+                bad_request(format!("order_by must be one of {}", names.join(", ")))
+            })?,
+    };
+    let direction = match query.direction.as_deref() {
+        None if order_by == OrderBy::Name => Direction::Asc,
+        None => Direction::Desc,
+        Some("asc") => Direction::Asc,
+        Some("desc") => Direction::Desc,
+        Some(_) => return Err(bad_request("direction must be asc or desc")),
+    };
+    let after = query
+        .cursor
+        .as_deref()
+        .map(|cursor| decode_cursor(cursor, order_by, direction).ok_or_else(|| bad_request("Invalid cursor")))
+        .transpose()?;
+    Ok(PageRequest {
+        limit: query.limit.unwrap_or(defaults.limit).clamp(1, defaults.max_limit),
+        order_by,
+        direction,
+        after,
+    })
+}
+
+fn direction_str(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Asc => "asc",
+        Direction::Desc => "desc",
+    }
+}
+
+/// Cursors are tied to the ordering they were issued for, so one can't be replayed under another.
+fn encode_cursor(key: &SortKey, order_by: OrderBy, direction: Direction) -> String {
+    base64::encode_url_safe(format!(
+        "{}:{}:{}:{}",
+        order_by.as_str(),
+        direction_str(direction),
+        key.0,
+        key.1
+    ))
+}
+
+fn decode_cursor(cursor: &str, order_by: OrderBy, direction: Direction) -> Option<SortKey> {
+    let decoded = String::from_utf8(base64::decode_url_safe(cursor).ok()?).ok()?;
+    let mut parts = decoded.splitn(4, ':');
+    if parts.next()? != order_by.as_str() || parts.next()? != direction_str(direction) {
+        return None;
+    }
+    let number = parts.next()?.parse().ok()?;
+    Some((number, parts.next()?.to_string()))
+}
+
+impl PageRequest {
+    /// Query string asking for the page after `cursor` under the same ordering and limit.
+    pub fn next_query(&self, cursor: &str) -> String {
+        format!(
+            "limit={}&order_by={}&direction={}&cursor={}",
+            self.limit,
+            self.order_by.as_str(),
+            direction_str(self.direction),
+            cursor.replace('=', "%3D")
+        )
+    }
+
+    /// Sorts `items` by `key`, drops those up to the cursor and keeps one page of the rest.
+    pub fn paginate<T>(&self, items: Vec<T>, key: impl Fn(&T) -> SortKey) -> Page<T> {
+        let mut keyed: Vec<(SortKey, T)> = items.into_iter().map(|item| (key(&item), item)).collect();
+        keyed.sort_by(|(a, _), (b, _)| a.cmp(b));
+        if self.direction == Direction::Desc {
+            keyed.reverse();
+        }
+        if let Some(after) = &self.after {
+            keyed.retain(|(k, _)| match self.direction {
+                Direction::Asc => k > after,
+                Direction::Desc => k < after,
+            });
+        }
+
+        let has_more = keyed.len() > self.limit;
+        keyed.truncate(self.limit);
+        let next_cursor = has_more
+            .then(|| keyed.last().map(|(k, _)| encode_cursor(k, self.order_by, self.direction)))
+            .flatten();
+        Page {
+            items: keyed.into_iter().map(|(_, item)| item).collect(),
+            next_cursor,
+        }
+    }
+}
+
+/// The usual key for items with a name, a size and a modification time.
+pub fn sort_key(order_by: OrderBy, name: &str, size: u64, modified: Option<time::OffsetDateTime>) -> SortKey {
+    let number = match order_by {
+        OrderBy::Name | OrderBy::Relevance => 0,
+        OrderBy::Size => size as i128,
+        OrderBy::Modified => modified.map(|m| m.unix_timestamp_nanos()).unwrap_or_default(),
+    };
+    (number, name.to_string())
+}
@@ -0,0 +1,178 @@
+// api/src/part_stream.rs
+
+//! Reading `/upload` parts a block at a time, so a large file goes into its blob as staged blocks
+//! (`put_block`, then `put_block_list` once it's checked) instead of being held in memory whole.
+//! Blocks are `UPLOAD_BLOCK_BYTES` long, 4 MiB by default; the first one is kept to sniff the
+//! file's format, and a part that fits in it is still stored with a single write. Blocks staged for
+//! a part that's then refused are never committed, and storage discards them after a week.
+
+use azure_storage_blobs::prelude::{BlobClient, BlockId};
+use bytes::{Buf, BufMut};
+use image_resize_core::{
+    failover::{self, Location},
+    filenames,
+};
+use sha2::{Digest, Sha256};
+use warp::{http::StatusCode, multipart::Part, Rejection};
+use tracing::error;
+
+use crate::error::ApiError;
+
+pub struct PartStream {
+    pub index: usize,
+    pub name: String,
+    pub filename: String,
+    part: Part,
+    max_bytes: usize,
+    block_bytes: usize,
+    read: usize,
+    done: bool,
+}
+
+impl PartStream {
+    pub fn new(index: usize, part: Part, max_bytes: usize, block_bytes: usize) -> Result<Self, Rejection> {
+        let name = part.name().to_string();
+        let filename = part.filename().ok_or_else(|| {
+            warp::reject::custom(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                format!("Part #{} ('{}') is missing a filename", index, name),
+            ))
+        })?;
+        let filename = filenames::normalize(filename).map_err(|reason| {
+            warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, format!("Part #{} ('{}'): {}", index, name, reason)))
+        })?;
+        Ok(PartStream {
+            index,
+            name,
+            filename,
+            part,
+            max_bytes,
+            block_bytes,
+            read: 0,
+            done: false,
+        })
+    }
+
+    /// Reads a form field, a part without a filename, as text of at most `max_bytes`.
+    pub async fn read_field(index: usize, mut part: Part, max_bytes: usize) -> Result<String, Rejection> {
+        let name = part.name().to_string();
+        let invalid = |reason: &str| {
+            warp::reject::custom(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                format!("Part #{} ('{}') {}", index, name, reason),
+            ))
+        };
+        let mut text = Vec::new();
+        while let Some(content) = part.data().await {
+            let content = content.map_err(|e| {
+                error!("Error reading part {}: {:?}", name, e);
+                invalid("could not be read")
+            })?;
+            text.put(content);
+            if text.len() > max_bytes {
+                return Err(invalid(&format!("exceeds the {} byte limit for fields", max_bytes)));
+            }
+        }
+        String::from_utf8(text).map_err(|_| invalid("is not UTF-8 text"))
+    }
+
+    /// Whether the whole part has been read.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Bytes read so far, the part's size once it's done.
+    pub fn bytes_read(&self) -> usize {
+        self.read
+    }
+
+    /// Changes the size the part is held to, for parts that have to be buffered after all.
+    pub fn limit(&mut self, max_bytes: usize) -> Result<(), Rejection> {
+        self.max_bytes = max_bytes;
+        self.check_size()
+    }
+
+    fn check_size(&self) -> Result<(), Rejection> {
+        if self.read > self.max_bytes {
+            return Err(warp::reject::custom(ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!(
+                    "Part #{} ('{}', file '{}') exceeds the {} byte limit per part",
+                    self.index, self.name, self.filename, self.max_bytes
+                ),
+            )));
+        }
+        Ok(())
+    }
+
+    /// The next block of the part, empty once it's all been read. A block runs over
+    /// `block_bytes` by at most the chunk that filled it.
+    pub async fn next_block(&mut self) -> Result<Vec<u8>, Rejection> {
+        let mut block = Vec::new();
+        while !self.done && block.len() < self.block_bytes {
+            let Some(content) = self.part.data().await else {
+                self.done = true;
+                break;
+            };
+            let content = content.map_err(|e| {
+                error!("Error reading part {}: {:?}", self.name, e);
+                warp::reject::custom(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    format!("Failed to read part #{} ('{}')", self.index, self.name),
+                ))
+            })?;
+            self.read += content.remaining();
+            self.check_size()?;
+            block.put(content);
+        }
+        Ok(block)
+    }
+
+    /// Appends the rest of the part to `bytes`.
+    pub async fn read_to_end(&mut self, bytes: &mut Vec<u8>) -> Result<(), Rejection> {
+        loop {
+            let block = self.next_block().await?;
+            if block.is_empty() {
+                return Ok(());
+            }
+            bytes.extend(block);
+        }
+    }
+
+    /// Reads the rest of the part without keeping it, for dry runs that only need its size.
+    pub async fn skip_to_end(&mut self) -> Result<(), Rejection> {
+        while !self.next_block().await?.is_empty() {}
+        Ok(())
+    }
+
+    /// Stages `first` and the rest of the part as blocks of `blob_client`, hashing them into
+    /// `hasher`, and returns their ids for the block list.
+    pub async fn stage(
+        &mut self,
+        blob_client: &BlobClient,
+        location: Location,
+        first: Vec<u8>,
+        hasher: &mut Option<Sha256>,
+    ) -> Result<Vec<BlockId>, Rejection> {
+        let mut blocks = Vec::new();
+        let mut block = first;
+        while !block.is_empty() {
+            if let Some(hasher) = hasher {
+                hasher.update(&block);
+            }
+            let block_id = BlockId::new(format!("{:010}", blocks.len()));
+            let staged = blob_client.put_block(block_id.clone(), block).await;
+            failover::record_write(location, staged.is_ok());
+            if let Err(e) = staged {
+                error!("Error staging a block of {}: {:?}", self.filename, e);
+                return Err(warp::reject::custom(ApiError::storage(
+                    &e,
+                    &format!("Failed to store '{}'", self.filename),
+                )));
+            }
+            blocks.push(block_id);
+            block = self.next_block().await?;
+        }
+        Ok(blocks)
+    }
+}
@@ -0,0 +1,256 @@
+// api/src/progress.rs
+
+//! Operations run in the background: imports, backfills, regenerations, template batches, ZIP
+//! uploads and server-side copies. Each is polled on `GET /operations/{id}` whatever its kind, and
+//! the admin ones also on their own status routes. An operation started for a tenant is only shown
+//! to that tenant. A finished operation is forgotten `OPERATION_RETENTION_SECS` (default 3600)
+//! after it ended, so the registry doesn't grow for the life of the process.
+
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use time::OffsetDateTime;
+use uuid::Uuid;
+use warp::{http::StatusCode, Rejection, Reply};
+
+use crate::{
+    error::ApiError,
+    paging::{self, Defaults, OrderBy, PageQuery},
+    tenant::Tenant,
+};
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProgressState {
+    /// An item handed to the worker, whose outcome is on `GET /jobs/{id}`.
+    Queued,
+    Running,
+    Completed,
+    Failed,
+}
+
+/// Progress of a long-running admin operation, polled by clients while it runs in the background.
+#[derive(Serialize, Clone, Debug)]
+pub struct Progress {
+    pub id: Uuid,
+    pub kind: &'static str,
+    pub state: ProgressState,
+    /// Number of items discovered so far; final once listing is done.
+    pub total: usize,
+    pub succeeded: usize,
+    /// Items queued as jobs of their own, counted neither as succeeded nor failed.
+    pub queued: usize,
+    pub failed: usize,
+    pub errors: Vec<String>,
+    /// Outcome per item, keyed by blob name.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub items: BTreeMap<String, ItemStatus>,
+    /// Bytes moved so far by a server-side copy.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transfer: Option<Transfer>,
+    /// Tenant the operation was started for.
+    #[serde(skip)]
+    pub owner: Option<String>,
+    /// When the operation ended, to be evicted once it's been kept long enough.
+    #[serde(skip)]
+    pub finished_at: Option<Instant>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct Transfer {
+    pub copied: u64,
+    pub total: u64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ItemStatus {
+    pub state: ProgressState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Job queued for the item, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<String>,
+    #[serde(skip)]
+    pub finished: OffsetDateTime,
+}
+
+/// Keeps at most this many error messages per operation so a bad batch can't grow without bound.
+const MAX_RECORDED_ERRORS: usize = 100;
+/// Same for per-item outcomes; counts keep going past the cap.
+const MAX_RECORDED_ITEMS: usize = 10_000;
+
+const DEFAULT_RETENTION_SECS: u64 = 3600;
+
+/// How long a finished operation can still be polled.
+fn retention() -> Duration {
+    Duration::from_secs(
+        env::var("OPERATION_RETENTION_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_RETENTION_SECS),
+    )
+}
+
+const ITEM_PAGING: Defaults = Defaults {
+    limit: 1000,
+    max_limit: MAX_RECORDED_ITEMS,
+    orderings: &[OrderBy::Name, OrderBy::Modified],
+};
+
+#[derive(Clone, Default)]
+pub struct ProgressRegistry {
+    inner: Arc<Mutex<HashMap<Uuid, Progress>>>,
+}
+
+impl ProgressRegistry {
+    pub fn start(&self, kind: &'static str) -> Uuid {
+        self.start_for(kind, None)
+    }
+
+    /// Starts an operation only `owner` may poll.
+    pub fn start_for(&self, kind: &'static str, owner: Option<String>) -> Uuid {
+        let id = Uuid::new_v4();
+        let progress = Progress {
+            id,
+            kind,
+            state: ProgressState::Running,
+            total: 0,
+            succeeded: 0,
+            queued: 0,
+            failed: 0,
+            errors: Vec::new(),
+            items: BTreeMap::new(),
+            transfer: None,
+            owner,
+            finished_at: None,
+        };
+        let mut operations = self.inner.lock().unwrap();
+        evict(&mut operations);
+        operations.insert(id, progress);
+        id
+    }
+
+    pub fn get(&self, id: &Uuid) -> Option<Progress> {
+        let mut operations = self.inner.lock().unwrap();
+        evict(&mut operations);
+        operations.get(id).cloned()
+    }
+
+    pub fn update(&self, id: &Uuid, f: impl FnOnce(&mut Progress)) {
+        if let Some(progress) = self.inner.lock().unwrap().get_mut(id) {
+            f(progress);
+        }
+    }
+
+    pub fn add_discovered(&self, id: &Uuid, count: usize) {
+        self.update(id, |p| p.total += count);
+    }
+
+    pub fn record_success(&self, id: &Uuid, item: &str) {
+        self.record_queued(id, item, None);
+    }
+
+    /// Records an item done, with the job it was queued as.
+    pub fn record_queued(&self, id: &Uuid, item: &str, job_id: Option<String>) {
+        self.update(id, |p| {
+            p.succeeded += 1;
+            if p.items.len() < MAX_RECORDED_ITEMS {
+                let status = ItemStatus {
+                    state: ProgressState::Completed,
+                    error: None,
+                    job_id,
+                    finished: OffsetDateTime::now_utc(),
+                };
+                p.items.insert(item.to_string(), status);
+            }
+        });
+    }
+
+    /// Records an item queued as the job `job_id`, which reports how it went.
+    pub fn record_pending(&self, id: &Uuid, item: &str, job_id: Uuid) {
+        self.update(id, |p| {
+            p.queued += 1;
+            if p.items.len() < MAX_RECORDED_ITEMS {
+                let status = ItemStatus {
+                    state: ProgressState::Queued,
+                    error: None,
+                    job_id: Some(job_id.to_string()),
+                    finished: OffsetDateTime::now_utc(),
+                };
+                p.items.insert(item.to_string(), status);
+            }
+        });
+    }
+
+    pub fn record_failure(&self, id: &Uuid, item: &str, error: String) {
+        self.update(id, |p| {
+            p.failed += 1;
+            if p.errors.len() < MAX_RECORDED_ERRORS {
+                p.errors.push(format!("{}: {}", item, error));
+            }
+            if p.items.len() < MAX_RECORDED_ITEMS {
+                let status = ItemStatus {
+                    state: ProgressState::Failed,
+                    error: Some(error),
+                    job_id: None,
+                    finished: OffsetDateTime::now_utc(),
+                };
+                p.items.insert(item.to_string(), status);
+            }
+        });
+    }
+
+    /// Records an error that isn't tied to a single item, such as a failed listing.
+    pub fn record_error(&self, id: &Uuid, error: String) {
+        self.update(id, |p| {
+            if p.errors.len() < MAX_RECORDED_ERRORS {
+                p.errors.push(error);
+            }
+        });
+    }
+
+    pub fn finish(&self, id: &Uuid, state: ProgressState) {
+        self.update(id, |p| {
+            p.state = state;
+            p.finished_at = Some(Instant::now());
+        });
+    }
+}
+
+/// Drops the operations that ended longer ago than the retention period.
+fn evict(operations: &mut HashMap<Uuid, Progress>) {
+    let retention = retention();
+    operations.retain(|_, progress| progress.finished_at.is_none_or(|at| at.elapsed() < retention));
+}
+
+/// Replies with an operation's counts and errors and one page of its per-item outcomes, ordered
+/// by blob name or by when each item finished.
+pub fn status_reply(progress: Progress, page: PageQuery) -> Result<impl Reply, Rejection> {
+    let page = paging::parse(&page, &ITEM_PAGING)?;
+    let items: Vec<(String, ItemStatus)> = progress.items.clone().into_iter().collect();
+    let items = page.paginate(items, |(name, status)| paging::sort_key(page.order_by, name, 0, Some(status.finished)));
+
+    let mut body = serde_json::to_value(&progress).expect("Failed to serialize progress");
+    body["items"] = items
+        .items
+        .into_iter()
+        .map(|(name, status)| serde_json::json!({ "name": name, "state": status.state, "error": status.error, "job_id": status.job_id }))
+        .collect();
+    if let Some(cursor) = items.next_cursor {
+        body["next_cursor"] = cursor.into();
+    }
+    Ok(warp::reply::json(&body))
+}
+
+/// `GET /operations/{id}`: any operation of the registry, as its own status route would answer.
+pub async fn operation_status(id: Uuid, tenant: Option<Tenant>, page: PageQuery, registry: ProgressRegistry) -> Result<impl Reply, Rejection> {
+    let tenant_id = tenant.map(|tenant| tenant.id);
+    match registry.get(&id) {
+        Some(progress) if progress.owner.is_none() || progress.owner == tenant_id => status_reply(progress, page),
+        _ => Err(warp::reject::custom(ApiError::new(StatusCode::NOT_FOUND, "Unknown operation id"))),
+    }
+}
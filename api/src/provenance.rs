@@ -0,0 +1,61 @@
+// api/src/provenance.rs
+
+//! `GET /images/{name}/provenance`: the derivation tree of an original, from the provenance the
+//! worker records for each rendition it writes, see `core/src/job_status.rs`. Renditions made from
+//! the original directly are at the root, each listing those derived from it, e.g. the published
+//! copies of the resized rendition. A rendition whose parent is no longer recorded, its record
+//! purged by retention, is listed at the root.
+
+use image_resize_core::{
+    filenames,
+    job_status::{self, Provenance},
+    models::{DerivedRendition, ProvenanceTree},
+};
+use warp::{http::StatusCode, Rejection, Reply};
+use tracing::error;
+
+use crate::{error::ApiError, report, tenant::Tenant};
+
+pub async fn get_provenance(name: String, tenant: Option<Tenant>) -> Result<impl Reply, Rejection> {
+    let name = filenames::from_path(&name)
+        .ok_or_else(|| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, "Invalid blob name")))?;
+    let (container_client, properties) = report::find_original(&name, tenant.as_ref()).await?;
+
+    let container = container_client.container_name();
+    let mut records = job_status::provenance(container, &name).await.map_err(|e| {
+        error!("Error reading the provenance of {}: {:?}", name, e);
+        warp::reject::custom(ApiError::storage(&e, "Failed to reach table storage"))
+    })?;
+    records.sort_by(|a, b| (&a.container, &a.blob).cmp(&(&b.container, &b.blob)));
+
+    let etag = properties.blob.properties.etag.to_string();
+    // the records a parent is found among, the rest being roots
+    let recorded: Vec<(String, String)> = records.iter().map(|r| (r.container.clone(), r.blob.clone())).collect();
+    let (roots, mut rest): (Vec<Provenance>, Vec<Provenance>) = records.into_iter().partition(|record| {
+        record.parent.is_empty() || !recorded.iter().any(|(c, b)| c == container && *b == record.parent)
+    });
+    let renditions = roots
+        .into_iter()
+        .map(|record| derive(record, container, &etag, &mut rest))
+        .collect();
+    Ok(warp::reply::json(&ProvenanceTree { name, etag, renditions }))
+}
+
+/// `record` with the renditions derived from it, taken out of `rest` so each is listed once.
+fn derive(record: Provenance, container: &str, etag: &str, rest: &mut Vec<Provenance>) -> DerivedRendition {
+    let (children, others): (Vec<Provenance>, Vec<Provenance>) = std::mem::take(rest)
+        .into_iter()
+        .partition(|child| record.container == container && child.parent == record.blob);
+    *rest = others;
+    let derived = children.into_iter().map(|child| derive(child, container, etag, rest)).collect();
+    DerivedRendition {
+        up_to_date: record.source_etag == etag,
+        container: record.container,
+        blob: record.blob,
+        preset: record.preset,
+        pipeline_version: record.pipeline_version,
+        source_etag: record.source_etag,
+        created_at: record.created_at,
+        derived,
+    }
+}
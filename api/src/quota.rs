@@ -0,0 +1,131 @@
+// api/src/quota.rs
+
+//! Per-tenant quotas on uploaded bytes and on upload requests per calendar month (UTC), set in the
+//! tenant's policy and counted by `core/src/usage_store.rs`. An upload that would take a meter past
+//! its limit is rejected; before that, the tenant is warned as usage crosses each of the store's
+//! `ALERT_PERCENTS`: by email through the notifier, by a POST to the quota's `alert_webhook` signed
+//! like completion webhooks with `QUOTA_WEBHOOK_SECRET`, and with `QuotaWarning` telemetry.
+
+use image_resize_core::{
+    telemetry,
+    usage_store::{self, Charged},
+    webhook,
+};
+use serde::{Deserialize, Serialize};
+use std::{env, sync::OnceLock};
+use time::OffsetDateTime;
+use tracing::{error, warn};
+use warp::{http::StatusCode, Rejection};
+
+use crate::{error::ApiError, notify::Notifier, tenant::Tenant};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Quota {
+    /// Bytes of originals the tenant may upload in total.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_storage_bytes: Option<u64>,
+    /// Upload requests the tenant may make per calendar month.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_monthly_requests: Option<u64>,
+    /// Addresses warned as usage nears a limit, on top of `NOTIFY_EMAIL_TO`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alert_emails: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alert_webhook: Option<String>,
+}
+
+/// The meter counting this month's requests.
+fn requests_meter() -> String {
+    let now = OffsetDateTime::now_utc();
+    format!("requests:{}-{:02}", now.year(), now.month() as u8)
+}
+
+fn webhook_client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(reqwest::Client::new)
+}
+
+/// Warns the tenant that `meter` has crossed `threshold` percent of its limit.
+async fn alert(tenant: &Tenant, quota: &Quota, meter: &str, threshold: i64, charged: &Charged, notifier: &Notifier) {
+    warn!("Tenant {} at {}% of its {} quota", tenant.id, threshold, meter);
+    telemetry::track_event(
+        "QuotaWarning",
+        &[("tenant", tenant.id.clone()), ("meter", meter.to_string()), ("percent", threshold.to_string())],
+    );
+    notifier
+        .quota_warning(&tenant.id, meter, threshold, charged.used, charged.limit, &quota.alert_emails)
+        .await;
+
+    if let Some(url) = &quota.alert_webhook {
+        let payload = serde_json::json!({
+            "tenant": tenant.id,
+            "meter": meter,
+            "percent": threshold,
+            "used": charged.used,
+            "limit": charged.limit,
+        });
+        let payload = serde_json::to_vec(&payload).expect("Failed to serialize quota alert");
+        let mut request = webhook_client().post(url).header("content-type", "application/json");
+        if let Ok(secret) = env::var("QUOTA_WEBHOOK_SECRET") {
+            let signed = webhook::sign(secret.as_bytes(), &payload);
+            request = request
+                .header(webhook::SIGNATURE_HEADER, signed.signature)
+                .header(webhook::TIMESTAMP_HEADER, signed.timestamp.to_string())
+                .header(webhook::NONCE_HEADER, signed.nonce);
+        }
+        if let Err(e) = request.body(payload).send().await.and_then(|response| response.error_for_status()) {
+            error!("Failed to post quota alert for {}: {:?}", tenant.id, e);
+        }
+    }
+}
+
+/// Charges an upload of `bytes` against the tenant's quota, counting one request when `request`
+/// is set, and rejects it if either would go over its limit.
+pub async fn charge(tenant: Option<&Tenant>, request: bool, bytes: u64, notifier: &Notifier) -> Result<(), Rejection> {
+    let Some((tenant, quota)) = tenant.and_then(|tenant| Some((tenant, tenant.policy.quota.as_ref()?))) else {
+        return Ok(());
+    };
+    let meters = [
+        (requests_meter(), quota.max_monthly_requests.filter(|_| request), 1, StatusCode::TOO_MANY_REQUESTS),
+        ("storage".to_string(), quota.max_storage_bytes.filter(|_| bytes > 0), bytes as i64, StatusCode::INSUFFICIENT_STORAGE),
+    ];
+    for (meter, limit, amount, status) in meters {
+        let Some(limit) = limit else {
+            continue;
+        };
+        let limit = limit as i64;
+        let charged = usage_store::charge(&tenant.id, &meter, amount, limit).await.map_err(|e| {
+            error!("Error updating quota usage of {}: {:?}", tenant.id, e);
+            warp::reject::custom(ApiError::storage(&e, "Failed to reach table storage"))
+        })?;
+        let Some(charged) = charged else {
+            telemetry::track_event("QuotaExceeded", &[("tenant", tenant.id.clone()), ("meter", meter.clone())]);
+            return Err(warp::reject::custom(ApiError::new(
+                status,
+                format!("The tenant's {} quota of {} is used up", meter, limit),
+            )));
+        };
+        telemetry::track_metric(
+            "QuotaUsage",
+            charged.percent(),
+            &[("tenant", tenant.id.clone()), ("meter", meter.clone())],
+        );
+        if let Some(threshold) = charged.crossed {
+            alert(tenant, quota, &meter, threshold, &charged, notifier).await;
+        }
+    }
+    Ok(())
+}
+
+/// Gives back `bytes` charged to the tenant's storage quota for originals removed again, see `atomic.rs`.
+pub async fn refund(tenant: Option<&Tenant>, bytes: u64) {
+    let Some((tenant, limit)) = tenant.and_then(|tenant| Some((tenant, tenant.policy.quota.as_ref()?.max_storage_bytes?))) else {
+        return;
+    };
+    if bytes == 0 {
+        return;
+    }
+    if let Err(e) = usage_store::charge(&tenant.id, "storage", -(bytes as i64), limit as i64).await {
+        error!("Error refunding {} bytes of quota to {}: {:?}", bytes, tenant.id, e);
+    }
+}
@@ -0,0 +1,165 @@
+// api/src/reconcile.rs
+
+//! A periodic sweep closing the gap a lost queue message leaves: an original that was stored but
+//! whose job never reached the worker would otherwise never be processed. Every
+//! `RECONCILE_INTERVAL_SECS` (unset, the default, leaves the sweep off) the default container is
+//! listed, and each original stored more than `RECONCILE_GRACE_SECS` ago (default 3600) without a
+//! successful run in the job status store, see `core/src/job_status.rs`, is queued with the
+//! default options, as `/admin/backfill` does, under a job of its own.
+//!
+//! Left alone are worker output, templates and presets, originals held for review, and those
+//! whose jobs were cancelled or expired. An original the sweep queued is stamped with the job's id
+//! under [`JOB_KEY`] and queued again only while that job is still `queued` past the grace period,
+//! its message lost too; an original that failed is left for `POST /process`. At most
+//! `RECONCILE_MAX_JOBS` (default 100) are queued per sweep, and they're counted in
+//! `reconciled_jobs_total`.
+
+use azure_core::request_options::Metadata;
+use azure_storage_blobs::{blob::Blob, prelude::ContainerClient};
+use futures::StreamExt;
+use image_resize_core::{
+    failover::Location,
+    job_status::{self, JobState},
+    metrics, pipeline, telemetry,
+};
+use std::{collections::HashSet, time::Duration};
+use time::OffsetDateTime;
+use tracing::{error, info, warn};
+
+use crate::{
+    container_client,
+    limit,
+    metadata::{self, TENANT_KEY},
+    send_message_to_queue, ImageMessage, Stage,
+};
+
+/// Metadata key of the job the sweep queued for an original.
+pub const JOB_KEY: &str = "reconcile_job";
+
+const TEMPLATE_PREFIX: &str = "templates/";
+const DEFAULT_GRACE_SECS: u64 = 3600;
+const DEFAULT_MAX_JOBS: usize = 100;
+
+struct Reconciler {
+    container_client: ContainerClient,
+    grace: Duration,
+    max_jobs: usize,
+}
+
+/// Whether `blob` is an original rather than worker output, a template or a preset, for the sweep
+/// and template batches. Needs the blob listed with its metadata.
+pub fn is_original(blob: &Blob) -> bool {
+    let worker_output = blob.metadata.as_ref().is_some_and(|metadata| metadata.contains_key("worker_version"));
+    !worker_output
+        && ![TEMPLATE_PREFIX, pipeline::PRESETS_PREFIX, pipeline::STAGING_PREFIX, pipeline::FAILED_PREFIX]
+            .iter()
+            .any(|prefix| blob.name.starts_with(prefix))
+}
+
+impl Reconciler {
+    /// Whether `blob`, stored before `cutoff`, never got a job through.
+    async fn is_unprocessed(&self, blob: &Blob, cutoff: OffsetDateTime) -> azure_core::Result<bool> {
+        let container = self.container_client.container_name();
+        if blob.properties.last_modified > cutoff || job_status::last_success(container, &blob.name).await?.is_some() {
+            return Ok(false);
+        }
+        if let Some(job_id) = blob.metadata.as_ref().and_then(|metadata| metadata.get(JOB_KEY)) {
+            // queued by an earlier sweep, and lost again only if it never left the queue
+            let lost = job_status::job(job_id).await?.is_some_and(|job| {
+                job.state == JobState::Queued
+                    && azure_core::date::parse_rfc3339(&job.updated_at).is_ok_and(|updated_at| updated_at <= cutoff)
+            });
+            return Ok(lost);
+        }
+        Ok(job_status::cancellation(container, &blob.name).await?.is_none()
+            && job_status::last_expiry(container, &blob.name).await?.is_none())
+    }
+
+    /// Queues `blob` with the default options and stamps it with the job's id.
+    async fn queue(&self, blob: &Blob) -> azure_core::Result<()> {
+        let blob_metadata = blob.metadata.clone().unwrap_or_default();
+        let (tags, user_metadata) = metadata::user_metadata(&blob_metadata);
+        let image = ImageMessage {
+            filename: blob.name.clone(),
+            image_container: self.container_client.container_name().to_string(),
+            stage: Stage::Resize,
+            tenant: blob_metadata.get(TENANT_KEY).cloned(),
+            tags,
+            metadata: user_metadata,
+            storage: Location::Primary,
+            ..Default::default()
+        };
+        let job_id = send_message_to_queue(image).await?;
+
+        let mut stamped = Metadata::new();
+        for (key, value) in &blob_metadata {
+            stamped.insert(key.clone(), value.clone());
+        }
+        stamped.insert(JOB_KEY, job_id.to_string());
+        // without the stamp, the next sweep would queue it again while this job runs
+        if let Err(e) = self.container_client.blob_client(&blob.name).set_metadata().metadata(stamped).await {
+            warn!("Failed to stamp {} with job {}: {:?}", blob.name, job_id, e);
+        }
+        info!("Queued {} as job {}, its upload never got a job through", blob.name, job_id);
+        telemetry::track_event("ImageReconciled", &[("filename", blob.name.clone()), ("job_id", job_id.to_string())]);
+        metrics::increment("reconciled_jobs_total", &[]);
+        Ok(())
+    }
+
+    async fn sweep(&self) -> azure_core::Result<usize> {
+        let cutoff = OffsetDateTime::now_utc() - self.grace;
+        let held: HashSet<(String, String)> =
+            job_status::reviews().await?.into_iter().map(|review| (review.container, review.blob)).collect();
+        let container = self.container_client.container_name().to_string();
+        let mut queued = 0;
+        let mut pages = self.container_client.list_blobs().include_metadata(true).into_stream();
+        while let Some(page) = pages.next().await {
+            for blob in page?.blobs.blobs() {
+                if queued >= self.max_jobs {
+                    info!("Reconciliation queued {} jobs, leaving the rest to the next sweep", queued);
+                    return Ok(queued);
+                }
+                if !is_original(blob) || held.contains(&(container.clone(), blob.name.clone())) {
+                    continue;
+                }
+                match self.is_unprocessed(blob, cutoff).await {
+                    Ok(true) => {}
+                    Ok(false) => continue,
+                    Err(e) => {
+                        warn!("Failed to read the job status of {}: {:?}", blob.name, e);
+                        continue;
+                    }
+                }
+                match self.queue(blob).await {
+                    Ok(()) => queued += 1,
+                    Err(e) => error!("Failed to queue {}: {:?}", blob.name, e),
+                }
+            }
+        }
+        Ok(queued)
+    }
+}
+
+/// Starts the sweep on `RECONCILE_INTERVAL_SECS`, if set.
+pub fn spawn() {
+    let interval = limit::env_or("RECONCILE_INTERVAL_SECS", 0);
+    if interval == 0 {
+        return;
+    }
+    let reconciler = Reconciler {
+        container_client: container_client(),
+        grace: Duration::from_secs(limit::env_or("RECONCILE_GRACE_SECS", DEFAULT_GRACE_SECS)),
+        max_jobs: limit::env_or("RECONCILE_MAX_JOBS", DEFAULT_MAX_JOBS),
+    };
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(interval));
+        loop {
+            interval.tick().await;
+            match reconciler.sweep().await {
+                Ok(0) => {}
+                Ok(queued) => info!("Reconciliation queued {} originals", queued),
+                Err(e) => error!("Reconciliation failed: {:?}", e),
+            }
+        }
+    });
+}
@@ -0,0 +1,235 @@
+// api/src/regenerate.rs
+
+//! `POST /admin/regenerate`: finds renditions of a preset whose pipeline version (see
+//! `core/src/pipeline.rs`) differs from the current one, because the worker's processing or the
+//! template changed since they were made, and enqueues their originals again. At most `limit`
+//! renditions are regenerated per run; regenerated ones carry the current version, so running it
+//! again picks up where the last run stopped.
+
+use azure_storage_blobs::prelude::ContainerClient;
+use futures::StreamExt;
+use image_resize_core::{blob_tags, crop::FocalPoint, failover::Location, naming, pipeline};
+use serde::Deserialize;
+use std::{collections::HashSet, sync::Arc};
+use uuid::Uuid;
+use warp::{http::StatusCode, Rejection, Reply};
+use tracing::{error, info};
+
+use crate::{
+    container_client,
+    error::ApiError,
+    metadata::{self, TENANT_KEY},
+    notify::Notifier,
+    paging::PageQuery,
+    progress::{self, ProgressRegistry, ProgressState},
+    read_blob, send_message_to_queue, ImageMessage, Stage, DEFAULT_SIZE,
+};
+
+const TEMPLATE_PREFIX: &str = "templates/";
+const DEFAULT_LIMIT: usize = 500;
+const MAX_LIMIT: usize = 10_000;
+
+#[derive(Deserialize, Debug)]
+pub struct RegenerateRequest {
+    /// `resize` or `render:<template>`.
+    preset: String,
+    /// Only renditions of originals whose name starts with this prefix are considered.
+    #[serde(default)]
+    prefix: String,
+    /// Renditions regenerated by this run, 500 by default.
+    limit: Option<usize>,
+    /// Extra addresses emailed when the operation finishes or starts failing.
+    #[serde(default)]
+    notify: Vec<String>,
+}
+
+/// What a run regenerates: renditions named `<rendition_prefix><original>` not at `version`.
+struct Target {
+    stage: Stage,
+    rendition_prefix: String,
+    version: String,
+    limit: usize,
+}
+
+/// A rendition made by an older pipeline, with what the queue message for its original needs.
+struct Stale {
+    original: String,
+    image: ImageMessage,
+}
+
+fn bad_request(message: impl Into<String>) -> Rejection {
+    warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, message))
+}
+
+pub async fn start_regenerate(request: RegenerateRequest, registry: ProgressRegistry, notifier: Arc<Notifier>) -> Result<impl Reply, Rejection> {
+    let stage: Stage = request.preset.parse().map_err(bad_request)?;
+    let limit = request.limit.unwrap_or(DEFAULT_LIMIT);
+    if limit == 0 || limit > MAX_LIMIT {
+        return Err(bad_request(format!("limit must be between 1 and {}", MAX_LIMIT)));
+    }
+    let container_client = container_client();
+
+    // renditions are named `<prefix><original>`
+    let (rendition_prefix, version) = match &stage {
+        Stage::Resize => {
+            let definition = match read_blob(&container_client.blob_client(pipeline::RESIZE_PRESET)).await {
+                Ok(definition) => definition,
+                Err(e) if e.as_http_error().is_some_and(|e| e.status() == azure_core::StatusCode::NotFound) => Vec::new(),
+                Err(e) => {
+                    error!("Error reading the resize preset: {:?}", e);
+                    return Err(warp::reject::custom(ApiError::storage(&e, "Failed to reach blob storage")));
+                }
+            };
+            (naming::RESIZED_PREFIX.to_string(), pipeline::version(blob_tags::RESIZED, &definition))
+        }
+        Stage::Render { template } => {
+            let template_blob = format!("{}{}.json", TEMPLATE_PREFIX, template);
+            let definition = match read_blob(&container_client.blob_client(&template_blob)).await {
+                Ok(definition) => definition,
+                Err(e) if e.as_http_error().is_some_and(|e| e.status() == azure_core::StatusCode::NotFound) => {
+                    return Err(warp::reject::custom(ApiError::new(
+                        StatusCode::NOT_FOUND,
+                        format!("Unknown template: {}", template),
+                    )))
+                }
+                Err(e) => {
+                    error!("Error reading template {}: {:?}", template_blob, e);
+                    return Err(warp::reject::custom(ApiError::storage(&e, "Failed to reach blob storage")));
+                }
+            };
+            (format!("{}_", template), pipeline::version(&blob_tags::render_preset(template), &definition))
+        }
+        Stage::Publish { .. } => {
+            return Err(bad_request("Published copies follow their resized rendition, regenerate resize instead"))
+        }
+        Stage::Pages { .. } => return Err(bad_request("PDF pages can't be regenerated, backfill them instead")),
+    };
+
+    let id = registry.start("regenerate");
+    info!("Starting regeneration {} of {} renditions older than {}", id, request.preset, version);
+
+    let reply = serde_json::json!({ "id": id, "pipeline_version": version });
+    let target = Target {
+        stage,
+        rendition_prefix,
+        version,
+        limit,
+    };
+    tokio::spawn(run_regenerate(id, container_client, target, request, registry, notifier));
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&reply),
+        StatusCode::ACCEPTED,
+    ))
+}
+
+pub async fn regenerate_status(id: Uuid, page: PageQuery, registry: ProgressRegistry) -> Result<impl Reply, Rejection> {
+    match registry.get(&id) {
+        Some(progress) => progress::status_reply(progress, page),
+        None => Err(warp::reject::custom(ApiError::new(StatusCode::NOT_FOUND, "Unknown regeneration id"))),
+    }
+}
+
+/// Renditions under `rendition_prefix` not stamped with `version` whose original still exists.
+/// Renditions made before versions were stamped count as stale.
+async fn find_stale(container_client: &ContainerClient, target: &Target, original_prefix: &str) -> azure_core::Result<Vec<Stale>> {
+    let mut names = HashSet::new();
+    let mut stale = Vec::new();
+    let mut pages = container_client.list_blobs().include_metadata(true).into_stream();
+    while let Some(page) = pages.next().await {
+        for blob in page?.blobs.blobs() {
+            names.insert(blob.name.clone());
+            let Some(original) = blob.name.strip_prefix(&target.rendition_prefix) else {
+                continue;
+            };
+            let blob_metadata = blob.metadata.clone().unwrap_or_default();
+            if !original.starts_with(original_prefix)
+                || !blob_metadata.contains_key("worker_version")
+                || blob_metadata.get(pipeline::VERSION_KEY).map(String::as_str) == Some(target.version.as_str())
+            {
+                continue;
+            }
+            let (tags, user_metadata) = metadata::user_metadata(&blob_metadata);
+            let size = |key: &str| blob_metadata.get(key).and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_SIZE);
+            stale.push(Stale {
+                original: original.to_string(),
+                image: ImageMessage {
+                    filename: original.to_string(),
+                    image_container: container_client.container_name().to_string(),
+                    auto_enhance: blob_metadata.get(pipeline::ENHANCE_KEY).is_some_and(|v| v == "true"),
+                    stage: target.stage.clone(),
+                    then: Vec::new(),
+                    width: size(pipeline::WIDTH_KEY),
+                    height: size(pipeline::HEIGHT_KEY),
+                    tenant: blob_metadata.get(TENANT_KEY).cloned(),
+                    tags,
+                    metadata: user_metadata,
+                    target_size: blob_metadata.get(pipeline::TARGET_SIZE_KEY).and_then(|v| v.parse().ok()),
+                    resize: blob_metadata.get(pipeline::RESIZE_KEY).and_then(|v| serde_json::from_str(v).ok()),
+                    fit: blob_metadata.get(pipeline::FIT_KEY).and_then(|v| serde_json::from_str(v).ok()),
+                    filter: blob_metadata.get(pipeline::FILTER_KEY).and_then(|v| serde_json::from_str(v).ok()),
+                    variants: blob_metadata
+                        .get(pipeline::VARIANTS_KEY)
+                        .and_then(|v| serde_json::from_str(v).ok())
+                        .unwrap_or_default(),
+                    output_format: blob_metadata.get(pipeline::OUTPUT_FORMAT_KEY).and_then(|v| serde_json::from_str(v).ok()),
+                    crop: blob_metadata.get(pipeline::CROP_KEY).and_then(|v| serde_json::from_str(v).ok()),
+                    focal_point: blob_metadata.get(pipeline::FOCAL_POINT_KEY).and_then(|v| FocalPoint::parse(v).ok()),
+                    operations: blob_metadata
+                        .get(pipeline::OPERATIONS_KEY)
+                        .and_then(|v| serde_json::from_str(v).ok())
+                        .unwrap_or_default(),
+                    storage: Location::Primary,
+                    queued_at: None,
+                    job_id: None,
+                    ..Default::default()
+                },
+            });
+        }
+    }
+    stale.retain(|s| names.contains(&s.original));
+    Ok(stale)
+}
+
+async fn run_regenerate(id: Uuid, container_client: ContainerClient, target: Target, request: RegenerateRequest, registry: ProgressRegistry, notifier: Arc<Notifier>) {
+    let mut stale = match find_stale(&container_client, &target, &request.prefix).await {
+        Ok(stale) => stale,
+        Err(e) => {
+            error!("Regeneration {} failed to list blobs: {:?}", id, e);
+            registry.record_error(&id, format!("Listing blobs failed: {}", e));
+            registry.finish(&id, ProgressState::Failed);
+            if let Some(progress) = registry.get(&id) {
+                notifier.operation_finished(&progress, &request.notify).await;
+            }
+            return;
+        }
+    };
+    info!(
+        "Regeneration {} found {} stale {} renditions, regenerating up to {}",
+        id,
+        stale.len(),
+        request.preset,
+        target.limit
+    );
+    stale.truncate(target.limit);
+    registry.add_discovered(&id, stale.len());
+
+    for Stale { original, image } in stale {
+        match send_message_to_queue(image).await {
+            Ok(_) => registry.record_success(&id, &original),
+            Err(e) => {
+                error!("Regeneration {} failed to enqueue {}: {:?}", id, original, e);
+                registry.record_failure(&id, &original, e.to_string());
+                if let Some(progress) = registry.get(&id) {
+                    notifier.check_failure_rate(&progress, &request.notify).await;
+                }
+            }
+        }
+    }
+
+    info!("Regeneration {} finished", id);
+    registry.finish(&id, ProgressState::Completed);
+    if let Some(progress) = registry.get(&id) {
+        notifier.operation_finished(&progress, &request.notify).await;
+    }
+}
@@ -0,0 +1,104 @@
+// api/src/report.rs
+
+//! `GET /images/{name}/report`: the processing report the worker keeps next to each original, see
+//! `functions/src/report.rs`. It lists every stage run for the image with its inputs, outputs,
+//! encoder settings, durations and warnings.
+//!
+//! `GET /images/{name}/status`: the outcome of the image's last successful processing from the job
+//! status table, with its warnings, and when a later job was skipped as stale.
+
+use azure_core::date;
+use azure_storage_blobs::{blob::operations::GetPropertiesResponse, prelude::ContainerClient};
+use image_resize_core::{filenames, job_status, models::JobStatus};
+use serde_json::Value;
+use warp::{http::StatusCode, Rejection, Reply};
+use tracing::error;
+
+use crate::{container_client_holding, error::ApiError, metadata::TENANT_KEY, read_blob, tenant::Tenant};
+
+pub async fn get_report(name: String, tenant: Option<Tenant>) -> Result<impl Reply, Rejection> {
+    let name = filenames::from_path(&name)
+        .ok_or_else(|| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, "Invalid blob name")))?;
+    let not_found = || warp::reject::custom(ApiError::new(StatusCode::NOT_FOUND, format!("No report for {}", name)));
+
+    let blob_client = container_client_holding(&name).await.blob_client(format!("{}.report.json", name));
+    let report: Value = match read_blob(&blob_client).await {
+        Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| {
+            error!("Invalid report for {}: {:?}", name, e);
+            warp::reject::custom(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Stored report is not valid JSON"))
+        })?,
+        Err(e) if e.as_http_error().is_some_and(|e| e.status() == azure_core::StatusCode::NotFound) => {
+            return Err(not_found())
+        }
+        Err(e) => {
+            error!("Error reading the report of {}: {:?}", name, e);
+            return Err(warp::reject::custom(ApiError::storage(&e, "Failed to reach blob storage")));
+        }
+    };
+
+    if report.get("tenant").and_then(Value::as_str) != tenant.as_ref().map(|t| t.id.as_str()) {
+        return Err(not_found());
+    }
+    Ok(warp::reply::json(&report))
+}
+
+/// The tenant's original `name` with its properties, or a 404 rejection.
+pub async fn find_original(name: &str, tenant: Option<&Tenant>) -> Result<(ContainerClient, GetPropertiesResponse), Rejection> {
+    let not_found = || warp::reject::custom(ApiError::new(StatusCode::NOT_FOUND, format!("No image named {}", name)));
+
+    let container_client = container_client_holding(name).await;
+    let properties = match container_client.blob_client(name).get_properties().await {
+        Ok(properties) => properties,
+        Err(e) if e.as_http_error().is_some_and(|e| e.status() == azure_core::StatusCode::NotFound) => {
+            return Err(not_found())
+        }
+        Err(e) => {
+            error!("Error reading properties of {}: {:?}", name, e);
+            return Err(warp::reject::custom(ApiError::storage(&e, "Failed to reach blob storage")));
+        }
+    };
+    let owner = properties.blob.metadata.as_ref().and_then(|m| m.get(TENANT_KEY));
+    if owner != tenant.map(|t| &t.id) {
+        return Err(not_found());
+    }
+    Ok((container_client, properties))
+}
+
+pub async fn get_status(name: String, tenant: Option<Tenant>) -> Result<impl Reply, Rejection> {
+    let name = filenames::from_path(&name)
+        .ok_or_else(|| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, "Invalid blob name")))?;
+    let (container_client, properties) = find_original(&name, tenant.as_ref()).await?;
+
+    let container = container_client.container_name();
+    let table_error = |e| {
+        error!("Error reading the job status of {}: {:?}", name, e);
+        warp::reject::custom(ApiError::storage(&e, "Failed to reach table storage"))
+    };
+    let last = job_status::last_success(container, &name).await.map_err(table_error)?;
+    let expiry = job_status::last_expiry(container, &name).await.map_err(table_error)?;
+    // an expiry only matters if no run succeeded since
+    let expired_at = expiry.map(|expiry| expiry.expired_at).filter(|expired_at| {
+        last.as_ref().is_none_or(|last| {
+            date::parse_rfc3339(expired_at).ok() > date::parse_rfc3339(&last.processed_at).ok()
+        })
+    });
+    let etag = properties.blob.properties.etag.to_string();
+    Ok(warp::reply::json(&match last {
+        Some(last) => JobStatus {
+            name,
+            processed: true,
+            up_to_date: last.etag == etag,
+            warnings: last.warnings(),
+            processed_at: Some(last.processed_at),
+            expired_at,
+        },
+        None => JobStatus {
+            name,
+            processed: false,
+            up_to_date: false,
+            processed_at: None,
+            expired_at,
+            warnings: Vec::new(),
+        },
+    }))
+}
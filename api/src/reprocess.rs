@@ -0,0 +1,105 @@
+// api/src/reprocess.rs
+
+//! `POST /process`: queues an already stored original for processing again, with the same
+//! options as `/upload`. With `if_changed`, nothing is queued when the blob's etag still matches
+//! the one recorded by the worker's last successful resize, so resubmitting an unchanged blob
+//! costs a properties read instead of a pipeline run. With `dry_run`, the outputs are described
+//! instead of queued, see `dry_run.rs`.
+
+use image_resize_core::{failover, filenames, job_status};
+use serde::Deserialize;
+use tracing::{error, info};
+use warp::{http::StatusCode, Rejection, Reply};
+
+use crate::{container_client, container_client_at, dry_run, error::ApiError, metadata, plan_upload, send_message_to_queue, tenant::Tenant, UploadOptions};
+
+#[derive(Deserialize, Debug)]
+pub struct ProcessRequest {
+    blob: String,
+    /// Skip the blob if it hasn't changed since it was last processed successfully.
+    #[serde(default)]
+    if_changed: bool,
+}
+
+fn storage_error(e: &azure_core::Error, service: &str) -> Rejection {
+    warp::reject::custom(ApiError::storage(e, &format!("Failed to reach {}", service)))
+}
+
+pub async fn process(options: UploadOptions, tenant: Option<Tenant>, mut request: ProcessRequest) -> Result<impl Reply, Rejection> {
+    let plan = plan_upload(&options, tenant.as_ref()).await?;
+    request.blob = filenames::nfc(&request.blob);
+    let container_name = container_client().container_name().to_string();
+    let location = failover::location_of(&container_name, &request.blob).await;
+    let container_client = container_client_at(&container_name, location);
+    let not_found = || warp::reject::custom(ApiError::new(StatusCode::NOT_FOUND, format!("No image named {}", request.blob)));
+
+    let properties = match container_client.blob_client(&request.blob).get_properties().await {
+        Ok(properties) => properties,
+        Err(e) if e.as_http_error().is_some_and(|e| e.status() == azure_core::StatusCode::NotFound) => {
+            return Err(not_found())
+        }
+        Err(e) => {
+            error!("Error reading properties of {}: {:?}", request.blob, e);
+            return Err(storage_error(&e, "blob storage"));
+        }
+    };
+    let owner = properties.blob.metadata.as_ref().and_then(|m| m.get(metadata::TENANT_KEY));
+    if owner != tenant.as_ref().map(|t| &t.id) {
+        return Err(not_found());
+    }
+    let etag = properties.blob.properties.etag.to_string();
+
+    if request.if_changed {
+        let last = job_status::last_success(&container_name, &request.blob).await.map_err(|e| {
+            error!("Error reading the job status of {}: {:?}", request.blob, e);
+            storage_error(&e, "table storage")
+        })?;
+        if let Some(last) = last.filter(|last| last.etag == etag) {
+            info!("Skipping {}, unchanged since etag {}", request.blob, etag);
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({
+                    "blob": request.blob,
+                    "queued": false,
+                    "reason": "unchanged",
+                    "etag": etag,
+                    "warnings": last.warnings(),
+                })),
+                StatusCode::OK,
+            ));
+        }
+    }
+
+    if options.dry_run {
+        let header = dry_run::read_header(&container_client.blob_client(&request.blob)).await.map_err(|e| {
+            error!("Error reading the header of {}: {:?}", request.blob, e);
+            storage_error(&e, "blob storage")
+        })?;
+        let estimate = dry_run::estimate(
+            &plan,
+            &container_client,
+            &request.blob,
+            &header,
+            properties.blob.properties.content_length,
+        )
+        .await?;
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({ "dry_run": true, "queued": false, "plan": estimate })),
+            StatusCode::OK,
+        ));
+    }
+
+    send_message_to_queue(plan.message(request.blob.clone(), container_name, location))
+        .await
+        .map_err(|e| {
+            error!("Error queueing {}: {:?}", request.blob, e);
+            storage_error(&e, "the queue")
+        })?;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({
+            "blob": request.blob,
+            "queued": true,
+            "etag": etag,
+        })),
+        StatusCode::ACCEPTED,
+    ))
+}
@@ -0,0 +1,98 @@
+// api/src/retention.rs
+
+//! Retention of the job status table, see `core/src/job_status.rs`. Each kind of record is kept
+//! for the days given by its setting, and forever when that isn't set: `JOB_RETENTION_DAYS` for
+//! the jobs served on `GET /jobs/{id}`, `PROCESSED_RETENTION_DAYS` for the last successful
+//! processing of each blob, `CANCELLATION_RETENTION_DAYS` and `EXPIRY_RETENTION_DAYS` for
+//! cancellations and stale jobs skipped, `PROVENANCE_RETENTION_DAYS` for the provenance of
+//! renditions. Age counts from a record's last write.
+//!
+//! With any of them set, the API purges every `RETENTION_INTERVAL_SECS` (default 86400), starting
+//! right after startup, and counts what it deleted in `records_purged_total` by kind. Purging a
+//! blob's processing record means an unchanged resubmission of it is processed again.
+
+use image_resize_core::{
+    job_status::{self, RecordKind},
+    metrics, telemetry,
+};
+use std::{env, time::Duration};
+use time::OffsetDateTime;
+use tracing::{error, info};
+
+use crate::limit;
+
+const DEFAULT_INTERVAL_SECS: u64 = 86400;
+
+pub struct RetentionPolicy {
+    jobs: Option<u64>,
+    processed: Option<u64>,
+    cancellations: Option<u64>,
+    expiries: Option<u64>,
+    provenance: Option<u64>,
+}
+
+fn days(key: &str) -> Option<u64> {
+    let value = env::var(key).ok()?;
+    let days = value.parse().unwrap_or_else(|_| panic!("Invalid {} '{}', expected a number of days", key, value));
+    Some(days)
+}
+
+impl RetentionPolicy {
+    pub fn from_env() -> Self {
+        RetentionPolicy {
+            jobs: days("JOB_RETENTION_DAYS"),
+            processed: days("PROCESSED_RETENTION_DAYS"),
+            cancellations: days("CANCELLATION_RETENTION_DAYS"),
+            expiries: days("EXPIRY_RETENTION_DAYS"),
+            provenance: days("PROVENANCE_RETENTION_DAYS"),
+        }
+    }
+
+    fn days(&self, kind: RecordKind) -> Option<u64> {
+        match kind {
+            RecordKind::Job => self.jobs,
+            RecordKind::Processed => self.processed,
+            RecordKind::Cancellation => self.cancellations,
+            RecordKind::Expiry => self.expiries,
+            RecordKind::Provenance => self.provenance,
+            // held uploads go once decided or expired, see `review.rs`
+            RecordKind::Review => None,
+        }
+    }
+
+    fn is_set(&self) -> bool {
+        RecordKind::ALL.into_iter().any(|kind| self.days(kind).is_some())
+    }
+
+    /// Deletes the records older than their retention once.
+    async fn purge(&self) {
+        let now = OffsetDateTime::now_utc();
+        let cutoff = |kind| self.days(kind).map(|days| now - Duration::from_secs(days * 86400));
+        match job_status::purge(cutoff).await {
+            Ok(purged) => {
+                for (kind, count) in &purged {
+                    metrics::add("records_purged_total", &[("kind", kind.name())], *count as f64);
+                }
+                let total: u64 = purged.iter().map(|(_, count)| count).sum();
+                info!("Purged {} job status records: {:?}", total, purged);
+                telemetry::track_event("RecordsPurged", &[("count", total.to_string())]);
+            }
+            Err(e) => error!("Failed to purge job status records: {:?}", e),
+        }
+    }
+}
+
+/// Starts purging on `RETENTION_INTERVAL_SECS` when any retention is set.
+pub fn spawn(policy: RetentionPolicy) {
+    if !policy.is_set() {
+        return;
+    }
+    let interval = Duration::from_secs(limit::env_or("RETENTION_INTERVAL_SECS", DEFAULT_INTERVAL_SECS).max(1));
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            policy.purge().await;
+        }
+    });
+}
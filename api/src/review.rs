@@ -0,0 +1,239 @@
+// api/src/review.rs
+
+//! Pending review for the uploads of tenants whose policy sets `review`. Files sent to `/upload`,
+//! `/upload/zip` and tus are checked and stored as usual, but their messages are held in the job
+//! status table instead of being queued, and the job stays `pending_review`, see
+//! `core/src/job_status.rs`. An upload leaves review one of three ways:
+//!
+//! - an admin decides: `POST /admin/reviews/{job_id}/approve` queues the held message, and
+//!   `POST /admin/reviews/{job_id}/reject` deletes the original and fails the job.
+//!   `GET /admin/reviews` lists what's waiting, `?tenant=` narrowing it to one tenant;
+//! - the moderation pass approves it: with `MODERATION_URL` set and the `moderation` feature flag
+//!   on for the tenant, the sweep posts each waiting upload's `job_id`, `container`, `blob`,
+//!   `tenant` and a read `url` there, expecting `{"flagged": bool}`. Uploads not flagged are
+//!   queued; flagged ones wait for an admin, and the service failing leaves them for the next sweep;
+//! - it expires: an upload not approved within the policy's `expire_after_hours` (default 72) is
+//!   deleted by the sweep, and its job fails.
+//!
+//! The sweep runs every `REVIEW_INTERVAL_SECS` (default 300). Held uploads, and how each review
+//! ended, are counted in `reviews_total`.
+
+use image_resize_core::{
+    features,
+    job_status::{self, JobState, Review},
+    message::ImageMessage,
+    metrics,
+    models::{PendingUpload, ReviewDecision, ReviewOutcome},
+    sas, storage, tables, telemetry,
+};
+use serde::{Deserialize, Serialize};
+use std::{env, sync::OnceLock, time::Duration};
+use time::OffsetDateTime;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+use warp::{http::StatusCode, Rejection, Reply};
+
+use crate::{container_client_at, error::ApiError, limit, send_job};
+
+const DEFAULT_EXPIRE_AFTER_HOURS: u64 = 72;
+const DEFAULT_INTERVAL_SECS: u64 = 300;
+
+/// How long a tenant's uploads may wait for approval.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct ReviewPolicy {
+    #[serde(default = "default_expire_after_hours")]
+    pub expire_after_hours: u64,
+}
+
+fn default_expire_after_hours() -> u64 {
+    DEFAULT_EXPIRE_AFTER_HOURS
+}
+
+#[derive(Deserialize)]
+pub struct ReviewQuery {
+    tenant: Option<String>,
+}
+
+/// Holds `image`, already stored, as job `job_id` until it's approved.
+pub async fn hold(image: ImageMessage, job_id: Uuid, policy: &ReviewPolicy) -> azure_core::Result<Uuid> {
+    let expires_at = OffsetDateTime::now_utc() + Duration::from_secs(policy.expire_after_hours * 3600);
+    job_status::hold_for_review(&job_id.to_string(), &image, expires_at).await?;
+    metrics::increment("reviews_total", &[("outcome", "held")]);
+    info!("Holding {} for review as job {}", image.filename, job_id);
+    Ok(job_id)
+}
+
+fn table_error(e: azure_core::Error) -> Rejection {
+    error!("Error reading uploads held for review: {:?}", e);
+    warp::reject::custom(ApiError::storage(&e, "Failed to reach table storage"))
+}
+
+/// The upload held as `job_id` and its message, or 404.
+async fn waiting(job_id: Uuid) -> Result<(Review, ImageMessage), Rejection> {
+    let review = job_status::review(&job_id.to_string()).await.map_err(table_error)?;
+    let unknown = || warp::reject::custom(ApiError::new(StatusCode::NOT_FOUND, "No upload is waiting for review as this job"));
+    let review = review.ok_or_else(unknown)?;
+    let image = review.message().ok_or_else(|| {
+        error!("The held message of job {} doesn't parse", review.id);
+        warp::reject::custom(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error"))
+    })?;
+    Ok((review, image))
+}
+
+/// Queues the held message of `review`.
+async fn approve(review: &Review, image: ImageMessage, outcome: &str) -> azure_core::Result<()> {
+    let job_id = Uuid::parse_str(&review.id).unwrap_or_else(|_| Uuid::new_v4());
+    send_job(image, job_id).await?;
+    job_status::end_review(&review.id).await?;
+    metrics::increment("reviews_total", &[("outcome", outcome)]);
+    telemetry::track_event("UploadApproved", &[("filename", review.blob.clone()), ("by", outcome.to_string())]);
+    info!("Approved {} of job {} ({})", review.blob, review.id, outcome);
+    Ok(())
+}
+
+/// Deletes the original of `review` and fails its job with `reason`.
+async fn discard(review: &Review, image: &ImageMessage, reason: &str, outcome: &str) -> azure_core::Result<()> {
+    let backend = storage::from_env(&container_client_at(&review.container, image.storage));
+    if let Err(e) = backend.delete(&review.blob).await {
+        // already gone is as good as deleted; anything else is retried by the next sweep or request
+        if !tables::is_not_found(&e) {
+            return Err(e);
+        }
+    }
+    job_status::update_job(&review.id, JobState::Failed, &[], Some(reason), None).await?;
+    job_status::end_review(&review.id).await?;
+    metrics::increment("reviews_total", &[("outcome", outcome)]);
+    telemetry::track_event("UploadDiscarded", &[("filename", review.blob.clone()), ("reason", reason.to_string())]);
+    info!("Discarded {} of job {}: {}", review.blob, review.id, reason);
+    Ok(())
+}
+
+/// `GET /admin/reviews`: the uploads waiting for review, oldest first.
+pub async fn list_reviews(query: ReviewQuery) -> Result<impl Reply, Rejection> {
+    let mut reviews = job_status::reviews().await.map_err(table_error)?;
+    reviews.retain(|review| query.tenant.is_none() || review.tenant == query.tenant);
+    reviews.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+    let pending: Vec<PendingUpload> = reviews
+        .into_iter()
+        .map(|review| PendingUpload {
+            job_id: review.id,
+            container: review.container,
+            filename: review.blob,
+            tenant: review.tenant,
+            flagged: review.flagged,
+            created_at: review.created_at,
+            expires_at: review.expires_at,
+        })
+        .collect();
+    Ok(warp::reply::json(&pending))
+}
+
+/// `POST /admin/reviews/{job_id}/approve` and `/reject`.
+pub async fn decide(job_id: Uuid, decision: ReviewDecision) -> Result<impl Reply, Rejection> {
+    let (review, image) = waiting(job_id).await?;
+    let decided = match decision {
+        ReviewDecision::Approved => approve(&review, image, "approved").await,
+        ReviewDecision::Rejected => discard(&review, &image, "Rejected in review", "rejected").await,
+    };
+    decided.map_err(|e| {
+        error!("Error deciding the review of job {}: {:?}", job_id, e);
+        warp::reject::custom(ApiError::storage(&e, "Failed to reach storage"))
+    })?;
+    Ok(warp::reply::json(&ReviewOutcome {
+        job_id: review.id,
+        filename: review.blob,
+        decision,
+    }))
+}
+
+fn moderation_client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(reqwest::Client::new)
+}
+
+#[derive(Deserialize)]
+struct Verdict {
+    flagged: bool,
+}
+
+/// Asks the moderation service at `url` whether `review` is flagged.
+async fn moderate(url: &str, review: &Review, image: &ImageMessage) -> Result<bool, String> {
+    let blob_client = container_client_at(&review.container, image.storage).blob_client(&review.blob);
+    let read_url = sas::read_url(&blob_client).await.map_err(|e| format!("Failed to sign a read URL: {}", e))?;
+    let request = serde_json::json!({
+        "job_id": review.id,
+        "container": review.container,
+        "blob": review.blob,
+        "tenant": review.tenant,
+        "url": read_url,
+    });
+    let response = moderation_client()
+        .post(url)
+        .json(&request)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?;
+    let verdict: Verdict = response.json().await.map_err(|e| e.to_string())?;
+    Ok(verdict.flagged)
+}
+
+/// Expires the uploads past their time and runs the moderation pass over the others, once.
+async fn sweep(moderation_url: Option<&str>) {
+    let reviews = match job_status::reviews().await {
+        Ok(reviews) => reviews,
+        Err(e) => {
+            error!("Failed to read the uploads held for review: {:?}", e);
+            return;
+        }
+    };
+    let now = OffsetDateTime::now_utc();
+    for review in reviews {
+        let Some(image) = review.message() else {
+            warn!("Skipping job {}, whose held message doesn't parse", review.id);
+            continue;
+        };
+        let expired = azure_core::date::parse_rfc3339(&review.expires_at).is_ok_and(|expires_at| expires_at <= now);
+        if expired {
+            let reason = format!("Not approved before {}", review.expires_at);
+            if let Err(e) = discard(&review, &image, &reason, "expired").await {
+                error!("Failed to discard {} of job {}: {:?}", review.blob, review.id, e);
+            }
+            continue;
+        }
+        let Some(url) = moderation_url.filter(|_| !review.flagged) else {
+            continue;
+        };
+        if !features::is_enabled(features::MODERATION, review.tenant.as_deref()).await {
+            continue;
+        }
+        let result = match moderate(url, &review, &image).await {
+            Ok(false) => approve(&review, image, "moderated").await,
+            Ok(true) => {
+                info!("Moderation flagged {} of job {}, leaving it to an admin", review.blob, review.id);
+                metrics::increment("reviews_total", &[("outcome", "flagged")]);
+                job_status::flag_review(review.clone()).await
+            }
+            Err(e) => {
+                warn!("Moderation of {} of job {} failed, retrying next sweep: {}", review.blob, review.id, e);
+                continue;
+            }
+        };
+        if let Err(e) = result {
+            error!("Failed to record the moderation of job {}: {:?}", review.id, e);
+        }
+    }
+}
+
+/// Starts sweeping the uploads held for review every `REVIEW_INTERVAL_SECS`.
+pub fn spawn() {
+    let interval = Duration::from_secs(limit::env_or("REVIEW_INTERVAL_SECS", DEFAULT_INTERVAL_SECS).max(1));
+    let moderation_url = env::var("MODERATION_URL").ok();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            sweep(moderation_url.as_deref()).await;
+        }
+    });
+}
@@ -0,0 +1,147 @@
+// api/src/search.rs
+
+//! `GET /search?q=`: text search over the image index's filenames, upload tags and analysis
+//! captions. Every word of the query must match some word of an image, exactly, as a prefix, or
+//! within a small edit distance to tolerate typos. Table Storage has no text search, so the
+//! caller's slice of the index is scanned and ranked here.
+
+use image_resize_core::{
+    config,
+    image_index::{self, ImageRecord, SearchFilter},
+};
+use serde::Deserialize;
+use warp::{http::StatusCode, Rejection, Reply};
+use tracing::error;
+
+use crate::{
+    error::ApiError,
+    paging::{self, Defaults, OrderBy, Page, PageQuery},
+    tenant::Tenant,
+};
+
+const PAGING: Defaults = Defaults {
+    limit: 20,
+    max_limit: 100,
+    orderings: &[OrderBy::Relevance, OrderBy::Name, OrderBy::Size, OrderBy::Modified],
+};
+/// Index entries read per search, bounding the cost of a scan.
+const MAX_SCANNED: usize = 5000;
+const MAX_QUERY_WORDS: usize = 8;
+
+const EXACT_SCORE: u32 = 3;
+const PREFIX_SCORE: u32 = 2;
+const FUZZY_SCORE: u32 = 1;
+
+#[derive(Deserialize, Debug, Default)]
+pub struct SearchQuery {
+    q: String,
+    /// Defaults to `AZURE_STORAGE_CONTAINER`.
+    container: Option<String>,
+}
+
+/// Lowercased words of `text`, split on anything that isn't a letter or digit.
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// Edits allowed for a query word of this many characters.
+fn max_edits(len: usize) -> usize {
+    match len {
+        0..=3 => 0,
+        4..=7 => 1,
+        _ => 2,
+    }
+}
+
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Best score of `term` against any of `candidates`, or `None` when nothing matches.
+fn score_term(term: &str, candidates: &[String]) -> Option<u32> {
+    let term_chars: Vec<char> = term.chars().collect();
+    let allowed = max_edits(term_chars.len());
+    candidates
+        .iter()
+        .filter_map(|word| {
+            if word == term {
+                Some(EXACT_SCORE)
+            } else if word.starts_with(term) {
+                Some(PREFIX_SCORE)
+            } else if allowed > 0 && edit_distance(&term_chars, &word.chars().collect::<Vec<_>>()) <= allowed {
+                Some(FUZZY_SCORE)
+            } else {
+                None
+            }
+        })
+        .max()
+}
+
+fn score(terms: &[String], record: &ImageRecord) -> Option<u32> {
+    let candidates: Vec<String> = words(&record.blob)
+        .chain(words(&record.tags))
+        .chain(record.caption.iter().flat_map(|c| words(c)))
+        .collect();
+    terms.iter().map(|term| score_term(term, &candidates)).sum()
+}
+
+pub async fn search(tenant: Option<Tenant>, query: SearchQuery, page: PageQuery) -> Result<impl Reply, Rejection> {
+    let page = paging::parse(&page, &PAGING)?;
+    let terms: Vec<String> = words(&query.q).collect();
+    if terms.is_empty() || terms.len() > MAX_QUERY_WORDS {
+        return Err(warp::reject::custom(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("q must contain between 1 and {} words", MAX_QUERY_WORDS),
+        )));
+    }
+    let container = query
+        .container
+        .unwrap_or_else(|| config::get().container().to_string());
+    let filter = SearchFilter {
+        tenant: tenant.map(|t| t.id),
+        ..Default::default()
+    };
+
+    let records = image_index::search(&container, &filter, MAX_SCANNED).await.map_err(|e| {
+        error!("Error reading the image index: {:?}", e);
+        warp::reject::custom(ApiError::storage(&e, "Failed to reach table storage"))
+    })?;
+
+    let hits: Vec<(u32, ImageRecord)> = records
+        .into_iter()
+        .filter_map(|record| score(&terms, &record).map(|score| (score, record)))
+        .collect();
+    let hits = page.paginate(hits, |(score, record)| match page.order_by {
+        OrderBy::Relevance => (*score as i128, record.blob.clone()),
+        order_by => paging::sort_key(order_by, &record.blob, record.size.unwrap_or_default(), record.indexed_at()),
+    });
+
+    let results: Vec<_> = hits
+        .items
+        .into_iter()
+        .map(|(score, record)| {
+            serde_json::json!({
+                "container": record.container,
+                "blob": record.blob,
+                "score": score,
+                "tags": words(&record.tags).collect::<Vec<_>>(),
+                "caption": record.caption,
+            })
+        })
+        .collect();
+    Ok(warp::reply::json(&Page {
+        items: results,
+        next_cursor: hits.next_cursor,
+    }))
+}
@@ -0,0 +1,271 @@
+// api/src/tenant.rs
+
+use image_resize_core::{
+    operations::Operation, pdf, resize_spec::ResizeSpec, svg, tiers::{self, Tier}, variants::Variant, video::VideoFormat,
+};
+use serde::{Deserialize, Serialize};
+use std::{env, sync::Arc, sync::RwLock};
+use warp::{http::StatusCode, Rejection, Reply};
+use tracing::{error, info};
+
+use crate::{auth, error::ApiError, quota::Quota, review::ReviewPolicy, Stage};
+
+/// Limits an admin places on what a tenant's uploads may ask for. Unset fields are unrestricted.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TransformPolicy {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_height: Option<u32>,
+    /// Source formats the tenant may upload, by extension, e.g. `["jpeg", "png"]`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_formats: Option<Vec<String>>,
+    /// Every upload must be rendered through this template, which carries the tenant's watermark.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub watermark_template: Option<String>,
+    /// What happens to an upload identical to an original that was already processed.
+    #[serde(default, skip_serializing_if = "DuplicatePolicy::is_process")]
+    pub duplicates: DuplicatePolicy,
+    /// Limits on the tenant's uploads, see `quota.rs`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota: Option<Quota>,
+    /// Holds uploads until they're approved, see `review.rs`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub review: Option<ReviewPolicy>,
+    /// Tier the tenant's jobs are held to, see `core/src/tiers.rs`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tier: Option<String>,
+    /// Signs the callbacks of the tenant's uploads, see `core/src/webhook.rs`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook_secret: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePolicy {
+    /// Stored and processed again like any upload.
+    #[default]
+    Process,
+    /// Not stored; answered with `200` and the existing original and renditions.
+    Existing,
+    /// Not stored; rejected with `409` and the existing original and renditions.
+    Conflict,
+}
+
+impl DuplicatePolicy {
+    fn is_process(&self) -> bool {
+        *self == DuplicatePolicy::Process
+    }
+}
+
+impl TransformPolicy {
+    /// The tenant's tier, if it has one.
+    pub fn tier(&self) -> Option<&'static Tier> {
+        self.tier.as_deref().and_then(tiers::get)
+    }
+
+    /// Checks the policy names a tier that exists.
+    fn check_tier(&self) -> Result<(), String> {
+        match &self.tier {
+            Some(name) if tiers::get(name).is_none() => {
+                let known: Vec<&str> = tiers::tiers().keys().map(String::as_str).collect();
+                Err(format!("Unknown tier {}, tiers are {}", name, known.join(", ")))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the requested output size and stages, before anything is read or stored.
+    pub fn check_request(&self, width: u32, height: u32, resize: Option<ResizeSpec>, then: &[Stage]) -> Result<(), String> {
+        // a longest edge bounds both sides; the other specs depend on the source's size
+        let (width, height) = match resize {
+            None => (width, height),
+            Some(ResizeSpec::LongestEdge(pixels)) => (pixels, pixels),
+            Some(_) if self.max_width.is_some() || self.max_height.is_some() => {
+                return Err("Only width and height or longest_edge can be checked against the size limits".to_string());
+            }
+            Some(_) => (0, 0),
+        };
+        if let Some(max_width) = self.max_width.filter(|max| width > *max) {
+            return Err(format!("Width {} exceeds the maximum of {}", width, max_width));
+        }
+        if let Some(max_height) = self.max_height.filter(|max| height > *max) {
+            return Err(format!("Height {} exceeds the maximum of {}", height, max_height));
+        }
+        if let Some(required) = &self.watermark_template {
+            let rendered = then
+                .iter()
+                .any(|stage| matches!(stage, Stage::Render { template } if template == required));
+            if !rendered {
+                return Err(format!("Uploads must be rendered with render:{}", required));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the widths of the size variants against the maximum width.
+    pub fn check_variants(&self, variants: &[Variant]) -> Result<(), String> {
+        let Some(max_width) = self.max_width else {
+            return Ok(());
+        };
+        match variants.iter().find(|variant| variant.width > max_width) {
+            Some(variant) => Err(format!(
+                "Variant {} of width {} exceeds the maximum of {}",
+                variant.name, variant.width, max_width
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks the sizes a list of operations resizes to against the maximum width and height.
+    pub fn check_operations(&self, operations: &[Operation]) -> Result<(), String> {
+        for operation in operations {
+            let Operation::Resize { w, h } = operation else {
+                continue;
+            };
+            // a single side leaves the other to the source's aspect ratio, which isn't known here
+            let unbounded = |side: Option<u32>, max: Option<u32>| side.is_none() && max.is_some();
+            if unbounded(*w, self.max_width) || unbounded(*h, self.max_height) {
+                return Err("Resize operations must give both w and h to be checked against the size limits".to_string());
+            }
+            if let Some(max_width) = self.max_width.filter(|max| w.is_some_and(|w| w > *max)) {
+                return Err(format!("Resize to width {} exceeds the maximum of {}", w.unwrap_or_default(), max_width));
+            }
+            if let Some(max_height) = self.max_height.filter(|max| h.is_some_and(|h| h > *max)) {
+                return Err(format!("Resize to height {} exceeds the maximum of {}", h.unwrap_or_default(), max_height));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the format of one uploaded file by sniffing its content.
+    pub fn check_format(&self, filename: &str, bytes: &[u8]) -> Result<(), String> {
+        let Some(allowed) = &self.allowed_formats else {
+            return Ok(());
+        };
+        let is_allowed = |extension: &str| allowed.iter().any(|a| a.eq_ignore_ascii_case(extension));
+        let (format, permitted) = match VideoFormat::sniff(bytes) {
+            Some(video) => (format!("{:?}", video), is_allowed(video.extension())),
+            None if pdf::is_pdf(bytes) => ("a PDF".to_string(), is_allowed(pdf::EXTENSION)),
+            None if svg::is_svg(bytes) => ("an SVG".to_string(), is_allowed(svg::EXTENSION)),
+            None => {
+                let format = image::guess_format(bytes).map_err(|_| format!("'{}' is not a recognised image", filename))?;
+                (format!("{:?}", format), format.extensions_str().iter().any(|extension| is_allowed(extension)))
+            }
+        };
+        if permitted {
+            Ok(())
+        } else {
+            Err(format!(
+                "'{}' is {}, allowed formats are {}",
+                filename,
+                format,
+                allowed.join(", ")
+            ))
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Tenant {
+    pub id: String,
+    /// Keys the tenant's clients send in `X-Api-Key`.
+    #[serde(default)]
+    api_keys: Vec<String>,
+    #[serde(default)]
+    pub policy: TransformPolicy,
+}
+
+impl Tenant {
+    pub fn new(id: String, policy: TransformPolicy) -> Self {
+        Tenant {
+            id,
+            api_keys: Vec::new(),
+            policy,
+        }
+    }
+}
+
+/// Tenants and their policies, loaded from the JSON array in `TENANTS_FILE` and written back
+/// to it when an admin changes a policy.
+#[derive(Debug, Default)]
+pub struct TenantStore {
+    path: Option<String>,
+    tenants: RwLock<Vec<Tenant>>,
+}
+
+impl TenantStore {
+    pub fn from_env() -> Self {
+        let Ok(path) = env::var("TENANTS_FILE") else {
+            return TenantStore::default();
+        };
+        let tenants: Vec<Tenant> = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| panic!("Invalid TENANTS_FILE {}: {}", path, e)),
+            // start empty, the first policy update creates the file
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => panic!("Failed to read TENANTS_FILE {}: {}", path, e),
+        };
+        if let Some(Err(e)) = tenants.iter().map(|tenant| tenant.policy.check_tier()).find(Result::is_err) {
+            panic!("Invalid TENANTS_FILE {}: {}", path, e);
+        }
+        info!("Loaded {} tenants from {}", tenants.len(), path);
+        TenantStore {
+            path: Some(path),
+            tenants: RwLock::new(tenants),
+        }
+    }
+
+    /// The tenant owning `key`, every tenant's keys being compared, see `auth.rs`.
+    pub fn by_api_key(&self, key: &str) -> Option<Tenant> {
+        self.tenants
+            .read()
+            .unwrap()
+            .iter()
+            .fold(None, |found, tenant| match auth::any_key_matches(&tenant.api_keys, key) {
+                true => found.or(Some(tenant)),
+                false => found,
+            })
+            .cloned()
+    }
+
+    fn policy(&self, id: &str) -> Option<TransformPolicy> {
+        self.tenants.read().unwrap().iter().find(|t| t.id == id).map(|t| t.policy.clone())
+    }
+
+    fn set_policy(&self, id: &str, policy: TransformPolicy) -> std::io::Result<()> {
+        let snapshot = {
+            let mut tenants = self.tenants.write().unwrap();
+            match tenants.iter_mut().find(|t| t.id == id) {
+                Some(tenant) => tenant.policy = policy,
+                None => tenants.push(Tenant::new(id.to_string(), policy)),
+            }
+            serde_json::to_vec_pretty(&*tenants).expect("Failed to serialize tenants")
+        };
+        match &self.path {
+            Some(path) => std::fs::write(path, snapshot),
+            None => Ok(()),
+        }
+    }
+}
+
+pub async fn get_policy(id: String, store: Arc<TenantStore>) -> Result<impl Reply, Rejection> {
+    match store.policy(&id) {
+        Some(policy) => Ok(warp::reply::json(&policy)),
+        None => Err(warp::reject::custom(ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("Unknown tenant {}", id),
+        ))),
+    }
+}
+
+pub async fn put_policy(id: String, policy: TransformPolicy, store: Arc<TenantStore>) -> Result<impl Reply, Rejection> {
+    policy
+        .check_tier()
+        .map_err(|e| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, e)))?;
+    store.set_policy(&id, policy.clone()).map_err(|e| {
+        error!("Failed to persist tenant policies: {:?}", e);
+        warp::reject::custom(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Failed to save policy"))
+    })?;
+    info!("Updated transformation policy for tenant {}", id);
+    Ok(warp::reply::json(&policy))
+}
@@ -0,0 +1,36 @@
+// api/src/timeout.rs
+
+use std::{env, future::Future, time::Duration};
+use warp::{http::StatusCode, Rejection};
+use tracing::error;
+
+use crate::error::ApiError;
+
+const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 30;
+
+/// Overall time a handler may take, from `REQUEST_TIMEOUT_SECS` (default 30s).
+pub fn request_timeout() -> Duration {
+    let secs = env::var("REQUEST_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_REQUEST_TIMEOUT_SECS);
+    Duration::from_secs(secs)
+}
+
+/// Runs `handler` with a deadline. On expiry the handler future is dropped, which cancels
+/// any in-flight storage or queue call and frees the buffers it was holding.
+pub async fn with_timeout<T>(
+    limit: Duration,
+    handler: impl Future<Output = Result<T, Rejection>>,
+) -> Result<T, Rejection> {
+    match tokio::time::timeout(limit, handler).await {
+        Ok(result) => result,
+        Err(_) => {
+            error!("Request timed out after {:?}", limit);
+            Err(warp::reject::custom(ApiError::new(
+                StatusCode::GATEWAY_TIMEOUT,
+                "Request timed out",
+            )))
+        }
+    }
+}
@@ -0,0 +1,457 @@
+// api/src/tus.rs
+
+//! The core tus.io 1.0.0 resumable upload protocol plus the `creation` extension, so stock tus
+//! clients can upload to `/files`. Each `PATCH` is staged as one uncommitted block of the target
+//! blob; the block list is committed and the image enqueued once `Upload-Offset` reaches
+//! `Upload-Length`. An upload no `PATCH` has reached for `UPLOAD_GC_AGE_SECS` is forgotten, see
+//! `upload_gc.rs`.
+//!
+//! The blob is named when the upload is created, as `/upload` names a part it stages, and the
+//! block list is only committed where nothing is stored yet unless names may be replaced, see
+//! `naming.rs`. Content-addressed names can't be given before the content is read, so tus uploads
+//! are refused with `BLOB_NAMING=content`.
+//!
+//! Processing options travel in `Upload-Metadata` under the same names as the `/upload` query
+//! parameters (`enhance`, `then`, `width`, `height`, `scale`, `longest_edge`, `shortest_edge`, `crop`,
+//! `crop_normalized`, `focal_point`, `tags`, `metadata`, `incremental`), next to the usual `filename`.
+
+use azure_core::base64;
+use azure_storage_blobs::prelude::{BlobBlockType, BlockId, BlockList, ContainerClient};
+use bytes::Bytes;
+use image_resize_core::{
+    customer_keys,
+    failover::{self, Location},
+    filenames,
+    image_checks::{self, Invalid},
+    storage,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+use warp::{
+    http::{Response, StatusCode},
+    hyper::Body,
+    Rejection, Reply,
+};
+use tracing::{error, info};
+
+use crate::{
+    container_client, container_client_at, count_upload, error::ApiError, limit::BodyLimits, naming, notify::Notifier, original_content_type,
+    plan_upload, queue_upload, quota, tenant::Tenant, upload_token::UploadClaims, UploadOptions, UploadPlan,
+};
+
+pub const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation";
+/// Azure refuses block lists longer than this.
+const MAX_BLOCKS: usize = 50_000;
+
+struct TusUpload {
+    container_client: ContainerClient,
+    filename: String,
+    /// Name of the blob the blocks are staged in.
+    blob: String,
+    length: u64,
+    offset: u64,
+    blocks: Vec<BlockId>,
+    plan: UploadPlan,
+    tenant: Option<Tenant>,
+    /// Content type to commit the blob with, known from the first chunk.
+    content_type: &'static str,
+    /// Storage account the blocks are staged in.
+    location: Location,
+    /// Set while a `PATCH` is being staged, so a retried request can't interleave with it.
+    busy: bool,
+    /// When the upload was created or last had a chunk staged.
+    touched: Instant,
+}
+
+/// Uploads that have been created but not yet completed, by id.
+#[derive(Clone, Default)]
+pub struct TusRegistry {
+    uploads: Arc<Mutex<HashMap<Uuid, TusUpload>>>,
+}
+
+impl TusRegistry {
+    /// Forgets the uploads idle for `max_idle`, returning how many.
+    pub fn expire(&self, max_idle: Duration) -> usize {
+        let mut uploads = self.uploads.lock().unwrap();
+        let before = uploads.len();
+        uploads.retain(|id, upload| {
+            let keep = upload.busy || upload.touched.elapsed() < max_idle;
+            if !keep {
+                info!("Forgetting tus upload {} of {}, idle since {:?}", id, upload.filename, upload.touched.elapsed());
+            }
+            keep
+        });
+        before - uploads.len()
+    }
+
+    /// The account, container and name of the blob of each upload in progress.
+    pub fn blobs(&self) -> HashSet<(Location, String, String)> {
+        self.uploads
+            .lock()
+            .unwrap()
+            .values()
+            .map(|upload| (upload.location, upload.container_client.container_name().to_string(), upload.blob.clone()))
+            .collect()
+    }
+}
+
+/// Clears an upload's `busy` flag when its `PATCH` ends however it ends, the request timing out or
+/// its client going away dropping it halfway through as well.
+struct BusyGuard {
+    registry: TusRegistry,
+    id: Uuid,
+}
+
+impl Drop for BusyGuard {
+    fn drop(&mut self) {
+        if let Ok(mut uploads) = self.registry.uploads.lock() {
+            if let Some(upload) = uploads.get_mut(&self.id) {
+                upload.busy = false;
+            }
+        }
+    }
+}
+
+fn reject(status: StatusCode, message: impl Into<String>) -> Rejection {
+    warp::reject::custom(ApiError::new(status, message))
+}
+
+fn require_version(tus_resumable: Option<String>) -> Result<(), Rejection> {
+    match tus_resumable.as_deref() {
+        Some(TUS_VERSION) => Ok(()),
+        _ => Err(reject(
+            StatusCode::PRECONDITION_FAILED,
+            format!("Tus-Resumable {} is required", TUS_VERSION),
+        )),
+    }
+}
+
+fn response(status: StatusCode) -> warp::http::response::Builder {
+    Response::builder().status(status).header("tus-resumable", TUS_VERSION)
+}
+
+/// Parses `key base64value,key2 base64value2`; keys may also appear without a value.
+fn parse_metadata(header: &str) -> Result<HashMap<String, String>, Rejection> {
+    header
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once(' ') {
+            Some((key, value)) => base64::decode(value.trim())
+                .ok()
+                .and_then(|bytes| String::from_utf8(bytes).ok())
+                .map(|value| (key.to_string(), value))
+                .ok_or_else(|| reject(StatusCode::BAD_REQUEST, format!("Invalid Upload-Metadata value for {}", key))),
+            None => Ok((pair.to_string(), String::new())),
+        })
+        .collect()
+}
+
+fn upload_options(metadata: &HashMap<String, String>) -> Result<UploadOptions, Rejection> {
+    let number = |key: &str| -> Result<Option<u32>, Rejection> {
+        metadata
+            .get(key)
+            .map(|v| v.parse().map_err(|_| reject(StatusCode::BAD_REQUEST, format!("Invalid {} in Upload-Metadata", key))))
+            .transpose()
+    };
+    Ok(UploadOptions {
+        enhance: metadata.get("enhance").is_some_and(|v| v.is_empty() || v == "true" || v == "1"),
+        then: metadata.get("then").cloned(),
+        width: number("width")?,
+        height: number("height")?,
+        scale: metadata
+            .get("scale")
+            .map(|v| v.parse().map_err(|_| reject(StatusCode::BAD_REQUEST, "Invalid scale in Upload-Metadata")))
+            .transpose()?,
+        longest_edge: number("longest_edge")?,
+        shortest_edge: number("shortest_edge")?,
+        fit: metadata.get("fit").cloned(),
+        filter: metadata.get("filter").cloned(),
+        variants: metadata.get("variants").cloned(),
+        output_format: metadata.get("output_format").cloned(),
+        crop: metadata.get("crop").cloned(),
+        focal_point: metadata.get("focal_point").cloned(),
+        crop_normalized: metadata.get("crop_normalized").is_some_and(|v| v.is_empty() || v == "true" || v == "1"),
+        tags: metadata.get("tags").cloned(),
+        target_size: metadata.get("target_size").cloned(),
+        metadata: metadata.get("metadata").cloned(),
+        callback_url: metadata.get("callback_url").cloned(),
+        priority: metadata.get("priority").cloned(),
+        dry_run: false,
+        atomic: false,
+        incremental: metadata.get("incremental").is_some_and(|v| v.is_empty() || v == "true" || v == "1"),
+    })
+}
+
+/// `OPTIONS /files`: advertises the protocol version, extensions and size limit.
+pub fn capabilities(limits: BodyLimits) -> impl Reply {
+    response(StatusCode::NO_CONTENT)
+        .header("tus-version", TUS_VERSION)
+        .header("tus-extension", TUS_EXTENSIONS)
+        .header("tus-max-size", limits.max_part_bytes.to_string())
+        .body(Body::empty())
+        .expect("Failed to build response")
+}
+
+/// `POST /files`: reserves an upload of `Upload-Length` bytes.
+#[allow(clippy::too_many_arguments)]
+pub async fn create(
+    tenant: Option<Tenant>,
+    token: Option<UploadClaims>,
+    tus_resumable: Option<String>,
+    upload_length: Option<u64>,
+    upload_metadata: Option<String>,
+    limits: BodyLimits,
+    registry: TusRegistry,
+    notifier: Arc<Notifier>,
+) -> Result<impl Reply, Rejection> {
+    require_version(tus_resumable)?;
+    let tenant = tenant.or_else(|| token.as_ref().map(UploadClaims::as_tenant));
+
+    let length = upload_length.ok_or_else(|| reject(StatusCode::BAD_REQUEST, "Upload-Length is required"))?;
+    let max_bytes = match &token {
+        Some(token) => limits.max_part_bytes.min(token.max_bytes),
+        None => limits.max_part_bytes,
+    } as u64;
+    if length == 0 || length > max_bytes {
+        return Err(reject(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("Upload-Length must be between 1 and {} bytes", max_bytes),
+        ));
+    }
+
+    let metadata = parse_metadata(upload_metadata.as_deref().unwrap_or_default())?;
+    let filename = metadata
+        .get("filename")
+        .filter(|name| !name.is_empty())
+        .cloned()
+        .ok_or_else(|| reject(StatusCode::BAD_REQUEST, "Upload-Metadata must include a filename"))?;
+    let filename = filenames::normalize(&filename).map_err(|reason| reject(StatusCode::BAD_REQUEST, reason))?;
+    if naming::content_addressed() {
+        return Err(reject(
+            StatusCode::NOT_IMPLEMENTED,
+            "Resumable uploads can't be named by their content, use /upload",
+        ));
+    }
+    let plan = plan_upload(&upload_options(&metadata)?, tenant.as_ref()).await?;
+    // chunks are staged as blocks, which can't be written with a key, see `core/src/customer_keys.rs`
+    if customer_keys::customer_key(plan.tenant.as_deref()).is_some() {
+        return Err(reject(
+            StatusCode::NOT_IMPLEMENTED,
+            "Resumable uploads can't be encrypted with the tenant's own key, use /upload",
+        ));
+    }
+    quota::charge(tenant.as_ref(), true, length, &notifier).await?;
+
+    // an upload stays in the account it started in, its blocks being staged there
+    let location = failover::write_location();
+    let container_client = match &token {
+        Some(token) => container_client_at(&token.container, location),
+        None => container_client_at(container_client().container_name(), location),
+    };
+    let blob = naming::original_name(&container_client, &filename, None)
+        .await
+        .map_err(warp::reject::custom)?;
+
+    let id = Uuid::new_v4();
+    registry.uploads.lock().unwrap().insert(
+        id,
+        TusUpload {
+            container_client,
+            filename: filename.clone(),
+            blob,
+            length,
+            offset: 0,
+            blocks: Vec::new(),
+            plan,
+            tenant,
+            content_type: "image/jpeg",
+            location,
+            busy: false,
+            touched: Instant::now(),
+        },
+    );
+    info!("Created tus upload {} for {} ({} bytes)", id, filename, length);
+
+    Ok(response(StatusCode::CREATED)
+        .header("location", format!("/files/{}", id))
+        .body(Body::empty())
+        .expect("Failed to build response"))
+}
+
+/// `HEAD /files/{id}`: reports how far the upload has got, so a client can resume.
+pub async fn status(id: Uuid, tus_resumable: Option<String>, registry: TusRegistry) -> Result<impl Reply, Rejection> {
+    require_version(tus_resumable)?;
+    let uploads = registry.uploads.lock().unwrap();
+    let upload = uploads.get(&id).ok_or_else(|| reject(StatusCode::NOT_FOUND, "Unknown upload"))?;
+
+    Ok(response(StatusCode::OK)
+        .header("upload-offset", upload.offset.to_string())
+        .header("upload-length", upload.length.to_string())
+        .header("cache-control", "no-store")
+        .body(Body::empty())
+        .expect("Failed to build response"))
+}
+
+/// `PATCH /files/{id}`: stages the body at `Upload-Offset`, committing the blob when it is complete.
+pub async fn append(
+    id: Uuid,
+    tus_resumable: Option<String>,
+    content_type: Option<String>,
+    upload_offset: Option<u64>,
+    chunk: Bytes,
+    registry: TusRegistry,
+) -> Result<impl Reply, Rejection> {
+    require_version(tus_resumable)?;
+    if content_type.as_deref() != Some("application/offset+octet-stream") {
+        return Err(reject(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Content-Type must be application/offset+octet-stream",
+        ));
+    }
+    let offset = upload_offset.ok_or_else(|| reject(StatusCode::BAD_REQUEST, "Upload-Offset is required"))?;
+
+    let busy;
+    let (blob_client, block_id, location) = {
+        let mut uploads = registry.uploads.lock().unwrap();
+        let upload = uploads.get_mut(&id).ok_or_else(|| reject(StatusCode::NOT_FOUND, "Unknown upload"))?;
+        if upload.busy {
+            return Err(reject(StatusCode::LOCKED, "Another PATCH for this upload is in progress"));
+        }
+        if offset != upload.offset {
+            return Err(reject(
+                StatusCode::CONFLICT,
+                format!("Upload-Offset {} does not match the current offset {}", offset, upload.offset),
+            ));
+        }
+        if upload.offset + chunk.len() as u64 > upload.length {
+            return Err(reject(StatusCode::BAD_REQUEST, "Chunk extends past Upload-Length"));
+        }
+        if upload.blocks.len() >= MAX_BLOCKS {
+            return Err(reject(StatusCode::BAD_REQUEST, "Too many chunks for one upload"));
+        }
+        if offset == 0 {
+            // the first chunk holds the header, which is checked as `/upload` checks a part's first block
+            image_checks::check(&upload.filename, &chunk).map_err(|e| {
+                let status = match e {
+                    Invalid::Unsupported(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    Invalid::TooLarge(_) | Invalid::Empty(_) | Invalid::Truncated(_) => StatusCode::UNPROCESSABLE_ENTITY,
+                };
+                reject(status, e.to_string())
+            })?;
+            if let Some(tenant) = &upload.tenant {
+                tenant
+                    .policy
+                    .check_format(&upload.filename, &chunk)
+                    .map_err(|e| reject(StatusCode::UNSUPPORTED_MEDIA_TYPE, e))?;
+            }
+            upload.content_type = original_content_type(&chunk);
+        }
+        upload.busy = true;
+        busy = BusyGuard {
+            registry: registry.clone(),
+            id,
+        };
+        (
+            upload.container_client.blob_client(&upload.blob),
+            BlockId::new(format!("{:010}", upload.blocks.len())),
+            upload.location,
+        )
+    };
+
+    let chunk_len = chunk.len() as u64;
+    let staged = blob_client.put_block(block_id.clone(), chunk).await;
+    failover::record_write(location, staged.is_ok());
+
+    let completed = {
+        let mut uploads = registry.uploads.lock().unwrap();
+        let upload = uploads.get_mut(&id).ok_or_else(|| reject(StatusCode::NOT_FOUND, "Unknown upload"))?;
+        if let Err(e) = staged {
+            error!("Error staging block for tus upload {}: {:?}", id, e);
+            return Err(warp::reject::custom(ApiError::storage(&e, "Failed to store chunk")));
+        }
+        upload.blocks.push(block_id);
+        upload.offset += chunk_len;
+        upload.touched = Instant::now();
+        if upload.offset == upload.length {
+            uploads.remove(&id)
+        } else {
+            None
+        }
+    };
+
+    let offset = match completed {
+        Some(upload) => {
+            let offset = upload.offset;
+            if let Err((rejection, upload)) = commit(id, upload).await {
+                // back to before the last chunk, which the client retries, unless the name was taken
+                if let Some(mut upload) = upload {
+                    upload.blocks.pop();
+                    upload.offset -= chunk_len;
+                    registry.uploads.lock().unwrap().insert(id, upload);
+                }
+                return Err(rejection);
+            }
+            offset
+        }
+        None => offset + chunk_len,
+    };
+    drop(busy);
+
+    Ok(response(StatusCode::NO_CONTENT)
+        .header("upload-offset", offset.to_string())
+        .body(Body::empty())
+        .expect("Failed to build response"))
+}
+
+/// Commits the blocks of a complete upload and enqueues it, handing the upload back on failure so
+/// the client can retry, unless its name was taken meanwhile, which a retry can't change.
+async fn commit(id: Uuid, upload: TusUpload) -> Result<(), (Rejection, Option<TusUpload>)> {
+    let container_name = upload.container_client.container_name().to_string();
+    let blob_client = upload.container_client.blob_client(&upload.blob);
+    let block_list = BlockList {
+        blocks: upload.blocks.iter().cloned().map(BlobBlockType::new_uncommitted).collect(),
+    };
+    let mut metadata = upload.plan.blob_metadata();
+    if upload.blob != upload.filename {
+        naming::stamp(&mut metadata, &upload.filename);
+    }
+    let mut commit = blob_client
+        .put_block_list(block_list)
+        .content_type(upload.content_type)
+        .metadata(metadata)
+        .tags(upload.plan.blob_tags());
+    if naming::exclusive() {
+        commit = commit.if_match(storage::if_none());
+    }
+    let committed = commit.await;
+    let taken = committed.as_ref().is_err_and(storage::is_taken);
+    failover::record_write(upload.location, committed.is_ok() || taken);
+    if taken {
+        let message = format!("'{}' was taken by another upload while '{}' was uploading", upload.blob, upload.filename);
+        return Err((reject(StatusCode::CONFLICT, message), None));
+    }
+    if let Err(e) = committed {
+        error!("Error committing tus upload {}: {:?}", id, e);
+        return Err((warp::reject::custom(ApiError::storage(&e, "Failed to commit upload")), Some(upload)));
+    }
+    if let Err(e) = failover::record(&container_name, &upload.blob, upload.location).await {
+        error!("Error recording the location of tus upload {}: {:?}", id, e);
+        return Err((reject(StatusCode::BAD_GATEWAY, "Failed to record the upload's location"), Some(upload)));
+    }
+
+    let image = upload.plan.message(upload.blob.clone(), container_name, upload.location);
+    if let Err(e) = queue_upload(&upload.plan, image, Uuid::new_v4()).await {
+        error!("Error enqueueing tus upload {}: {:?}", id, e);
+        return Err((reject(StatusCode::BAD_GATEWAY, "Failed to queue the image for processing"), Some(upload)));
+    }
+    count_upload("files", upload.length);
+
+    info!("Completed tus upload {} of {} as {}", id, upload.filename, upload.blob);
+    Ok(())
+}
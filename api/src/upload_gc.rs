@@ -0,0 +1,166 @@
+// api/src/upload_gc.rs
+
+//! Cleanup of abandoned chunked uploads. A large `/upload` part is staged as blocks before it's
+//! committed, see `part_stream.rs`, and a tus upload stages one block per `PATCH`, see `tus.rs`. A
+//! client that goes away midway leaves those blocks uncommitted. Storage discards them only after
+//! a week, and a tus upload stays in the API's memory until then.
+//!
+//! Every `UPLOAD_GC_INTERVAL_SECS` (default 3600, `0` turns the cleanup off), the API forgets tus
+//! uploads that no `PATCH` has reached for `UPLOAD_GC_AGE_SECS` (default 86400). It then discards
+//! the blobs that have only uncommitted blocks and were last written that long ago, in the source
+//! container and `UPLOAD_TOKEN_CONTAINERS` of each account. A blob is found by comparing the
+//! listings with and without uncommitted blobs. Its blocks are dropped by committing an empty
+//! block list, on condition that no blob was committed under the name meanwhile, and the empty blob
+//! is then deleted by its ETag. The age should exceed `REQUEST_TIMEOUT_SECS`, so no `/upload` still running
+//! loses its blocks. Both are counted in `abandoned_uploads_total` by kind.
+
+use azure_core::{request_options::IfMatchCondition, StatusCode};
+use azure_storage_blobs::prelude::{BlockList, ContainerClient};
+use futures::StreamExt;
+use image_resize_core::{config, failover, metrics, telemetry};
+use std::{
+    collections::{BTreeSet, HashSet},
+    env,
+    time::Duration,
+};
+use time::OffsetDateTime;
+use tracing::{error, info, warn};
+
+use crate::{container_client_at, limit, tus::TusRegistry};
+
+const DEFAULT_INTERVAL_SECS: u64 = 3600;
+const DEFAULT_AGE_SECS: u64 = 86400;
+
+pub struct UploadGc {
+    interval: Duration,
+    max_age: Duration,
+    containers: Vec<String>,
+}
+
+impl UploadGc {
+    pub fn from_env() -> Self {
+        let mut containers = vec![config::get().container().to_string()];
+        containers.extend(
+            env::var("UPLOAD_TOKEN_CONTAINERS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(str::to_string),
+        );
+        containers.sort();
+        containers.dedup();
+        UploadGc {
+            interval: Duration::from_secs(limit::env_or("UPLOAD_GC_INTERVAL_SECS", DEFAULT_INTERVAL_SECS)),
+            max_age: Duration::from_secs(limit::env_or("UPLOAD_GC_AGE_SECS", DEFAULT_AGE_SECS)),
+            containers,
+        }
+    }
+
+    /// Cleans up once.
+    async fn collect(&self, registry: &TusRegistry) {
+        let expired = registry.expire(self.max_age);
+        if expired > 0 {
+            metrics::add("abandoned_uploads_total", &[("kind", "tus_session")], expired as f64);
+        }
+
+        let cutoff = OffsetDateTime::now_utc() - self.max_age;
+        let mut discarded = 0;
+        for location in failover::locations() {
+            for container in &self.containers {
+                let container_client = container_client_at(container, location);
+                // taken per container, so uploads created meanwhile are skipped too
+                let live: HashSet<String> = registry
+                    .blobs()
+                    .into_iter()
+                    .filter(|(at, holder, _)| *at == location && holder == container)
+                    .map(|(_, _, blob)| blob)
+                    .collect();
+                match abandoned(&container_client, cutoff).await {
+                    Ok(blobs) => {
+                        for blob in blobs.into_iter().filter(|blob| !live.contains(blob)) {
+                            if discard(&container_client, &blob).await {
+                                discarded += 1;
+                            }
+                        }
+                    }
+                    Err(e) => error!("Error listing the uncommitted blobs of {}: {:?}", container, e),
+                }
+            }
+        }
+        if discarded > 0 {
+            metrics::add("abandoned_uploads_total", &[("kind", "uncommitted_blob")], discarded as f64);
+        }
+        if expired > 0 || discarded > 0 {
+            info!("Cleaned up {} abandoned tus uploads and {} uncommitted blobs", expired, discarded);
+            telemetry::track_event(
+                "AbandonedUploadsCleaned",
+                &[("tus_sessions", expired.to_string()), ("uncommitted_blobs", discarded.to_string())],
+            );
+        }
+    }
+}
+
+/// The blobs of the container that have only uncommitted blocks, last written before `cutoff`.
+async fn abandoned(container_client: &ContainerClient, cutoff: OffsetDateTime) -> azure_core::Result<Vec<String>> {
+    let mut candidates = BTreeSet::new();
+    let mut pages = container_client.list_blobs().include_uncommitted_blobs(true).into_stream();
+    while let Some(page) = pages.next().await {
+        for blob in page?.blobs.blobs() {
+            if blob.properties.last_modified < cutoff {
+                candidates.insert(blob.name.clone());
+            }
+        }
+    }
+    if candidates.is_empty() {
+        return Ok(Vec::new());
+    }
+    // those that also have committed content aren't abandoned uploads
+    let mut pages = container_client.list_blobs().into_stream();
+    while let Some(page) = pages.next().await {
+        for blob in page?.blobs.blobs() {
+            candidates.remove(&blob.name);
+        }
+    }
+    Ok(candidates.into_iter().collect())
+}
+
+/// Drops the uncommitted blocks of `blob`, returning whether it did.
+async fn discard(container_client: &ContainerClient, blob: &str) -> bool {
+    let blob_client = container_client.blob_client(blob);
+    let committed = blob_client
+        .put_block_list(BlockList { blocks: Vec::new() })
+        .if_match(IfMatchCondition::NotMatch("*".to_string()))
+        .await;
+    let etag = match committed {
+        Ok(response) => response.etag,
+        // committed by its upload since the listing
+        Err(e) if e.as_http_error().is_some_and(|e| e.status() == StatusCode::PreconditionFailed || e.status() == StatusCode::Conflict) => {
+            return false
+        }
+        Err(e) => {
+            warn!("Failed to discard the uncommitted blocks of {}: {:?}", blob, e);
+            return false;
+        }
+    };
+    // only the empty blob, not one an upload committed since
+    if let Err(e) = blob_client.delete().if_match(IfMatchCondition::Match(etag)).await {
+        warn!("Failed to delete the emptied blob {}: {:?}", blob, e);
+    }
+    info!("Discarded the uncommitted blocks of {}", blob);
+    true
+}
+
+/// Starts cleaning up on `UPLOAD_GC_INTERVAL_SECS` unless it is 0.
+pub fn spawn(gc: UploadGc, registry: TusRegistry) {
+    if gc.interval.is_zero() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(gc.interval);
+        loop {
+            interval.tick().await;
+            gc.collect(&registry).await;
+        }
+    });
+}
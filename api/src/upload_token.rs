@@ -0,0 +1,235 @@
+// api/src/upload_token.rs
+
+use azure_core::base64;
+use hmac::{Hmac, Mac};
+use image_resize_core::config;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    env,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+use tracing::{info, warn};
+
+use crate::{error::ApiError, limit::BodyLimits, tenant::{Tenant, TransformPolicy}};
+
+const DEFAULT_TTL_SECS: u64 = 300;
+const MAX_TTL_SECS: u64 = 3600;
+const DEFAULT_FORMATS: &[&str] = &["jpeg", "png", "webp", "gif"];
+
+/// What a browser holding the token may upload. Signed, so the browser can't widen it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UploadClaims {
+    pub container: String,
+    pub max_bytes: usize,
+    /// Unix time after which the token is refused.
+    pub expires: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// Snapshot of the issuing tenant's policy, with `allowed_formats` narrowed to the token's formats.
+    pub policy: TransformPolicy,
+}
+
+impl UploadClaims {
+    /// The tenant the upload is attributed to and checked against.
+    pub fn as_tenant(&self) -> Tenant {
+        Tenant::new(self.tenant.clone().unwrap_or_default(), self.policy.clone())
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct TokenRequest {
+    /// Defaults to `AZURE_STORAGE_CONTAINER`; must be one of `UPLOAD_TOKEN_CONTAINERS`.
+    container: Option<String>,
+    /// Per-file limit, at most `MAX_PART_BYTES`.
+    max_bytes: Option<usize>,
+    /// Allowed source formats by extension, narrowed further by the tenant's policy.
+    formats: Option<Vec<String>>,
+    ttl_secs: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct TokenResponse {
+    token: String,
+    expires: u64,
+}
+
+/// Signs and checks upload tokens with `UPLOAD_TOKEN_SECRET`. Without it tokens are disabled.
+pub struct TokenIssuer {
+    secret: Vec<u8>,
+    containers: Vec<String>,
+    default_container: String,
+}
+
+impl TokenIssuer {
+    pub fn from_env() -> Option<Self> {
+        let secret = env::var("UPLOAD_TOKEN_SECRET").ok()?.into_bytes();
+        let default_container = config::get().container().to_string();
+        let mut containers: Vec<String> = env::var("UPLOAD_TOKEN_CONTAINERS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+            .collect();
+        if containers.is_empty() {
+            containers.push(default_container.clone());
+        }
+        Some(TokenIssuer {
+            secret,
+            containers,
+            default_container,
+        })
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts any key length")
+    }
+
+    fn encode(&self, claims: &UploadClaims) -> String {
+        let payload = base64::encode_url_safe(serde_json::to_vec(claims).expect("Failed to serialize claims"));
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        format!("{}.{}", payload, base64::encode_url_safe(mac.finalize().into_bytes()))
+    }
+
+    fn decode(&self, token: &str) -> Result<UploadClaims, &'static str> {
+        let (payload, signature) = token.split_once('.').ok_or("Malformed upload token")?;
+        let signature = base64::decode_url_safe(signature).map_err(|_| "Malformed upload token")?;
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&signature).map_err(|_| "Invalid upload token")?;
+
+        let claims: UploadClaims = base64::decode_url_safe(payload)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or("Malformed upload token")?;
+        if claims.expires < now() {
+            return Err("Upload token has expired");
+        }
+        Ok(claims)
+    }
+}
+
+/// Lets the frontends in `CORS_ALLOWED_ORIGINS` (comma separated) call the API from the browser;
+/// `None` when none are set, since the filter refuses every request with an `Origin` it doesn't list.
+pub fn cors() -> Option<warp::cors::Builder> {
+    let origins: Vec<String> = env::var("CORS_ALLOWED_ORIGINS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect();
+    if origins.is_empty() {
+        return None;
+    }
+    let cors = warp::cors()
+        .allow_origins(origins.iter().map(String::as_str))
+        .allow_methods(["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"])
+        .allow_headers([
+            "content-type",
+            "x-upload-token",
+            "x-api-key",
+            "authorization",
+            "tus-resumable",
+            "upload-length",
+            "upload-metadata",
+            "upload-offset",
+            "range",
+            "if-none-match",
+            "traceparent",
+        ])
+        .expose_headers(["location", "tus-resumable", "upload-offset", "upload-length", "etag", "accept-ranges", "content-range"]);
+    Some(cors)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+/// Decodes `X-Upload-Token` when present; a bad or expired token is refused outright.
+pub fn claims(issuer: Option<Arc<TokenIssuer>>) -> impl Filter<Extract = (Option<UploadClaims>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("x-upload-token").and_then(move |token: Option<String>| {
+        let issuer = issuer.clone();
+        async move {
+            let Some(token) = token else {
+                return Ok(None);
+            };
+            let Some(issuer) = issuer else {
+                return Err(warp::reject::custom(ApiError::new(StatusCode::UNAUTHORIZED, "Upload tokens are not enabled")));
+            };
+            issuer.decode(&token).map(Some).map_err(|e| {
+                warn!("Refused upload token: {}", e);
+                warp::reject::custom(ApiError::new(StatusCode::UNAUTHORIZED, e))
+            })
+        }
+    })
+}
+
+/// Issues a token to a caller authenticated with a tenant API key, to hand to its frontend.
+pub async fn issue_token(
+    tenant: Option<Tenant>,
+    request: TokenRequest,
+    issuer: Option<Arc<TokenIssuer>>,
+    limits: BodyLimits,
+) -> Result<impl Reply, Rejection> {
+    let Some(issuer) = issuer else {
+        return Err(warp::reject::custom(ApiError::new(StatusCode::NOT_FOUND, "Upload tokens are not enabled")));
+    };
+    let Some(tenant) = tenant else {
+        return Err(warp::reject::custom(ApiError::new(StatusCode::UNAUTHORIZED, "An API key is required")));
+    };
+    let bad_request = |message: String| warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, message));
+
+    let container = request.container.unwrap_or_else(|| issuer.default_container.clone());
+    if !issuer.containers.contains(&container) {
+        return Err(bad_request(format!("Uploads to container {} are not allowed", container)));
+    }
+
+    let max_bytes = request.max_bytes.unwrap_or(limits.max_part_bytes);
+    if max_bytes == 0 || max_bytes > limits.max_part_bytes {
+        return Err(bad_request(format!("max_bytes must be between 1 and {}", limits.max_part_bytes)));
+    }
+
+    let ttl = request.ttl_secs.unwrap_or(DEFAULT_TTL_SECS);
+    if ttl == 0 || ttl > MAX_TTL_SECS {
+        return Err(bad_request(format!("ttl_secs must be between 1 and {}", MAX_TTL_SECS)));
+    }
+
+    let requested = request
+        .formats
+        .unwrap_or_else(|| DEFAULT_FORMATS.iter().map(|f| f.to_string()).collect());
+    // a token never allows more than the tenant's own policy does
+    let formats: Vec<String> = match &tenant.policy.allowed_formats {
+        Some(allowed) => requested
+            .into_iter()
+            .filter(|f| allowed.iter().any(|a| a.eq_ignore_ascii_case(f)))
+            .collect(),
+        None => requested,
+    };
+    if formats.is_empty() {
+        return Err(bad_request("None of the requested formats are allowed".to_string()));
+    }
+
+    let claims = UploadClaims {
+        container,
+        max_bytes,
+        expires: now() + ttl,
+        tenant: Some(tenant.id.clone()),
+        policy: TransformPolicy {
+            allowed_formats: Some(formats),
+            // tokens are readable by whoever holds them
+            webhook_secret: None,
+            ..tenant.policy
+        },
+    };
+    info!("Issued upload token for tenant {} into {}", tenant.id, claims.container);
+
+    Ok(warp::reply::json(&TokenResponse {
+        token: issuer.encode(&claims),
+        expires: claims.expires,
+    }))
+}
@@ -0,0 +1,152 @@
+// api/src/usage.rs
+
+//! Usage per API key, see `core/src/key_usage.rs`. Requests made with an API key are counted in
+//! memory, along with the bytes their `Content-Length` declares. The counts are added to the table
+//! every `USAGE_FLUSH_SECS` (default 60), so a crash loses at most that much. Requests made with a
+//! bearer token, with only an upload token or with no credentials aren't counted.
+//!
+//! `GET /admin/usage?from=YYYY-MM-DD&to=YYYY-MM-DD` reports the UTC days from `from` to `to`, both
+//! included. For each key it gives the totals and the days with any use. `to` defaults to today and
+//! `from` to the start of the 30 days ending with `to`. At most [`MAX_DAYS`] days are reported at once.
+
+use image_resize_core::key_usage::{self, Counts, DailyUsage};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::Duration,
+};
+use time::{format_description::FormatItem, macros::format_description, Date, OffsetDateTime};
+use warp::{http::StatusCode, Rejection, Reply};
+use tracing::{error, warn};
+
+use crate::{error::ApiError, limit};
+
+const DEFAULT_FLUSH_SECS: u64 = 60;
+const DEFAULT_DAYS: i64 = 30;
+const MAX_DAYS: i64 = 366;
+const DATE_FORMAT: &[FormatItem<'static>] = format_description!("[year]-[month]-[day]");
+
+/// Counts not yet added to the table, by day and key.
+static PENDING: Mutex<BTreeMap<(String, String), Counts>> = Mutex::new(BTreeMap::new());
+
+/// Counts a request made with the key `key_id`, sending `bytes` in its body.
+pub fn record(key_id: String, bytes: u64) {
+    let day = key_usage::day(OffsetDateTime::now_utc());
+    let mut pending = PENDING.lock().unwrap();
+    let counts = pending.entry((day, key_id)).or_default();
+    counts.requests += 1;
+    counts.bytes += bytes as i64;
+}
+
+/// Adds the pending counts to the table, keeping those it failed to add for the next flush.
+async fn flush() {
+    let pending = std::mem::take(&mut *PENDING.lock().unwrap());
+    for ((day, key), counts) in pending {
+        if let Err(e) = key_usage::add(&day, &key, &counts).await {
+            warn!("Failed to record the usage of {} on {}: {:?}", key, day, e);
+            PENDING.lock().unwrap().entry((day, key)).or_default().add(&counts);
+        }
+    }
+}
+
+/// Starts adding the counts to the table every `USAGE_FLUSH_SECS`.
+pub fn spawn() {
+    let interval = Duration::from_secs(limit::env_or("USAGE_FLUSH_SECS", DEFAULT_FLUSH_SECS).max(1));
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            flush().await;
+        }
+    });
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct UsageQuery {
+    /// First day, `YYYY-MM-DD`.
+    from: Option<String>,
+    /// Last day, included.
+    to: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct DayUsage {
+    day: String,
+    #[serde(flatten)]
+    counts: Counts,
+}
+
+#[derive(Serialize, Debug)]
+struct KeyUsage {
+    key: String,
+    #[serde(flatten)]
+    total: Counts,
+    days: Vec<DayUsage>,
+}
+
+#[derive(Serialize, Debug)]
+struct UsageReport {
+    from: String,
+    to: String,
+    total: Counts,
+    keys: Vec<KeyUsage>,
+}
+
+fn bad_request(message: String) -> Rejection {
+    warp::reject::custom(ApiError::new(StatusCode::BAD_REQUEST, message))
+}
+
+fn parse_day(name: &str, value: &str) -> Result<Date, Rejection> {
+    Date::parse(value, DATE_FORMAT).map_err(|_| bad_request(format!("{} must be YYYY-MM-DD", name)))
+}
+
+/// Groups the table's rows by key, keys and days in order.
+fn report(from: String, to: String, usage: Vec<DailyUsage>) -> UsageReport {
+    let mut by_key: HashMap<String, Vec<DayUsage>> = HashMap::new();
+    for row in usage {
+        by_key.entry(row.key).or_default().push(DayUsage {
+            day: row.day,
+            counts: row.counts,
+        });
+    }
+    let mut total = Counts::default();
+    let mut keys: Vec<KeyUsage> = by_key
+        .into_iter()
+        .map(|(key, mut days)| {
+            days.sort_by(|a, b| a.day.cmp(&b.day));
+            let mut key_total = Counts::default();
+            for day in &days {
+                key_total.add(&day.counts);
+            }
+            total.add(&key_total);
+            KeyUsage { key, total: key_total, days }
+        })
+        .collect();
+    keys.sort_by(|a, b| a.key.cmp(&b.key));
+    UsageReport { from, to, total, keys }
+}
+
+/// `GET /admin/usage`: what each API key used per day.
+pub async fn get_usage(query: UsageQuery) -> Result<impl Reply, Rejection> {
+    let to = match &query.to {
+        Some(to) => parse_day("to", to)?,
+        None => OffsetDateTime::now_utc().date(),
+    };
+    let from = match &query.from {
+        Some(from) => parse_day("from", from)?,
+        None => to - time::Duration::days(DEFAULT_DAYS - 1),
+    };
+    if from > to {
+        return Err(bad_request("from must not be after to".to_string()));
+    }
+    if (to - from).whole_days() >= MAX_DAYS {
+        return Err(bad_request(format!("At most {} days are reported at once", MAX_DAYS)));
+    }
+    let (from, to) = (key_usage::day(from.midnight().assume_utc()), key_usage::day(to.midnight().assume_utc()));
+    let usage = key_usage::between(&from, &to).await.map_err(|e| {
+        error!("Error reading key usage: {:?}", e);
+        warp::reject::custom(ApiError::storage(&e, "Failed to reach table storage"))
+    })?;
+    Ok(warp::reply::json(&report(from, to, usage)))
+}
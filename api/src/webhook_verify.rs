@@ -0,0 +1,55 @@
+// api/src/webhook_verify.rs
+
+//! `POST /webhooks/verify`: checks a completion callback for receivers that can't use
+//! `core/src/webhook.rs`. The receiver posts the delivery as it got it, the body unchanged and its
+//! `X-Webhook-Signature`, `X-Webhook-Timestamp` and `X-Webhook-Nonce` headers, and is answered
+//! `{"valid": true}`, or `{"valid": false, "error": ...}` saying what is wrong. The signature is
+//! checked against the `webhook_secret` of the caller's tenant, or against `CALLBACK_SECRET`, which
+//! the API needs as well as the worker, for callers without a tenant, and the timestamp
+//! must be within `tolerance_secs` of now (default 300). Nonces aren't remembered, so a receiver
+//! still has to turn away those it has seen.
+
+use bytes::Bytes;
+use image_resize_core::webhook::{self, SignedHeaders};
+use serde::{Deserialize, Serialize};
+use std::env;
+use warp::{
+    http::{HeaderMap, StatusCode},
+    Rejection, Reply,
+};
+
+use crate::{error::ApiError, tenant::Tenant};
+
+/// Largest delivery body accepted.
+pub const MAX_BODY_BYTES: u64 = 1024 * 1024;
+
+#[derive(Deserialize, Debug, Default)]
+pub struct VerifyQuery {
+    tolerance_secs: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+struct Verdict {
+    valid: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+pub async fn verify(tenant: Option<Tenant>, query: VerifyQuery, headers: HeaderMap, body: Bytes) -> Result<impl Reply, Rejection> {
+    let secret = match &tenant {
+        Some(tenant) => tenant.policy.webhook_secret.clone().ok_or_else(|| {
+            warp::reject::custom(ApiError::new(StatusCode::CONFLICT, "Your tenant has no webhook_secret, its callbacks aren't signed"))
+        })?,
+        None => env::var("CALLBACK_SECRET").map_err(|_| {
+            warp::reject::custom(ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "Callbacks aren't signed in this environment"))
+        })?,
+    };
+    let tolerance = query.tolerance_secs.unwrap_or(webhook::DEFAULT_TOLERANCE_SECS);
+    let verified = SignedHeaders::from_headers(|name| headers.get(name).and_then(|v| v.to_str().ok()))
+        .and_then(|signed| webhook::verify(secret.as_bytes(), &signed, &body, tolerance));
+    let verdict = Verdict {
+        valid: verified.is_ok(),
+        error: verified.err().map(|e| e.to_string()),
+    };
+    Ok(warp::reply::json(&verdict))
+}
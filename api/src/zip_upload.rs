@@ -0,0 +1,213 @@
+// api/src/zip_upload.rs
+
+//! `POST /upload/zip`: a ZIP archive of images uploaded in one request, with the same query
+//! options as `/upload`. The archive is checked up front and expanded in the background, each
+//! image stored as an original under its path in the archive and enqueued as its own job; the reply
+//! carries a batch id whose progress, per image, is on `GET /batch/{id}`.
+
+use async_zip::base::read::mem::ZipFileReader;
+use azure_storage_blobs::prelude::ContainerClient;
+use bytes::Bytes;
+use futures::AsyncReadExt;
+use image_resize_core::{customer_keys, failover, filenames, image_checks, output_format::OutputFormat, storage, telemetry, trailing_data};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use uuid::Uuid;
+use warp::{http::StatusCode, Rejection, Reply};
+use tracing::{error, info};
+
+use crate::{
+    container_client, container_client_at, container_client_for, duplicates,
+    error::ApiError,
+    fan_out,
+    limit::BodyLimits,
+    naming,
+    notify::Notifier,
+    original_content_type, plan_upload,
+    progress::{ProgressRegistry, ProgressState},
+    quota,
+    count_upload, queue_upload,
+    tenant::Tenant,
+    upload_token::{self, UploadClaims},
+    UploadOptions, UploadPlan,
+};
+
+fn reject(status: StatusCode, message: impl Into<String>) -> Rejection {
+    warp::reject::custom(ApiError::new(status, message))
+}
+
+/// The blob name for an archive entry: its path with empty and `.` segments dropped, or `None`
+/// for directories, hidden files, macOS resource forks, paths escaping the archive and names
+/// [`filenames::normalize`] refuses.
+fn blob_name(path: &str) -> Option<String> {
+    let mut segments = Vec::new();
+    for segment in path.split(['/', '\\']) {
+        match segment {
+            "" | "." => continue,
+            ".." | "__MACOSX" => return None,
+            hidden if hidden.starts_with('.') => return None,
+            segment => segments.push(segment),
+        }
+    }
+    if segments.is_empty() || path.ends_with('/') {
+        return None;
+    }
+    filenames::normalize(&segments.join("/")).ok()
+}
+
+pub async fn upload_zip(
+    options: UploadOptions,
+    tenant: Option<Tenant>,
+    token: Option<UploadClaims>,
+    mut limits: BodyLimits,
+    body: Bytes,
+    registry: ProgressRegistry,
+    notifier: Arc<Notifier>,
+) -> Result<impl Reply, Rejection> {
+    let tenant = tenant.or_else(|| token.as_ref().map(upload_token::UploadClaims::as_tenant));
+    if let Some(token) = &token {
+        limits.max_part_bytes = limits.max_part_bytes.min(token.max_bytes);
+    }
+    if options.dry_run {
+        return Err(reject(StatusCode::BAD_REQUEST, "Dry runs are not supported for ZIP uploads"));
+    }
+    let plan = plan_upload(&options, tenant.as_ref()).await?;
+    quota::charge(tenant.as_ref(), true, body.len() as u64, &notifier).await?;
+
+    let archive = ZipFileReader::new(body.to_vec())
+        .await
+        .map_err(|e| reject(StatusCode::BAD_REQUEST, format!("Not a readable ZIP archive: {}", e)))?;
+    let entries: Vec<(usize, String)> = archive
+        .file()
+        .entries()
+        .iter()
+        .enumerate()
+        .filter_map(|(index, entry)| Some((index, blob_name(entry.filename().as_str().ok()?)?)))
+        .collect();
+    if entries.is_empty() {
+        return Err(reject(StatusCode::BAD_REQUEST, "The archive holds no files"));
+    }
+    if entries.len() > limits.max_zip_entries {
+        return Err(reject(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("The archive holds {} files, at most {} are accepted", entries.len(), limits.max_zip_entries),
+        ));
+    }
+
+    let container_client = match &token {
+        Some(token) => container_client_for(&token.container),
+        None => container_client(),
+    };
+    let id = registry.start_for("zip_upload", tenant.as_ref().map(|tenant| tenant.id.clone()));
+    registry.add_discovered(&id, entries.len());
+    info!("Expanding ZIP upload {} of {} files", id, entries.len());
+    let reply = serde_json::json!({ "id": id, "files": entries.len() });
+
+    tokio::spawn(async move {
+        for (index, name) in entries {
+            match store_entry(&archive, index, &name, &plan, tenant.as_ref(), &container_client, limits).await {
+                Ok(()) => registry.record_success(&id, &name),
+                Err(e) => {
+                    error!("ZIP upload {} failed to store {}: {}", id, name, e);
+                    registry.record_failure(&id, &name, e);
+                }
+            }
+        }
+        info!("ZIP upload {} finished", id);
+        registry.finish(&id, ProgressState::Completed);
+    });
+
+    Ok(warp::reply::with_status(warp::reply::json(&reply), StatusCode::ACCEPTED))
+}
+
+/// Reads one entry, checks it like a part of `/upload`, stores it and enqueues its job.
+async fn store_entry(
+    archive: &ZipFileReader,
+    index: usize,
+    name: &str,
+    plan: &UploadPlan,
+    tenant: Option<&Tenant>,
+    container_client: &ContainerClient,
+    limits: BodyLimits,
+) -> Result<(), String> {
+    // declared sizes can lie, so the read itself is capped
+    let mut bytes = Vec::new();
+    let mut reader = archive.reader_without_entry(index).await.map_err(|e| e.to_string())?;
+    (&mut reader)
+        .take(limits.max_part_bytes as u64 + 1)
+        .read_to_end(&mut bytes)
+        .await
+        .map_err(|e| format!("Failed to extract: {}", e))?;
+    if bytes.len() > limits.max_part_bytes {
+        return Err(format!("Larger than the limit of {} bytes", limits.max_part_bytes));
+    }
+    let dimensions = image_checks::check(name, &bytes).map_err(|e| e.to_string())?;
+    image_checks::check_complete(name, &bytes).map_err(|e| e.to_string())?;
+    if let Some(tenant) = tenant {
+        tenant.policy.check_format(name, &bytes)?;
+    }
+    trailing_data::check(name, &mut bytes)?;
+    image_checks::strip_exif(&mut bytes);
+
+    // named by content, an original that was already processed is left as it is, see `naming.rs`
+    let mut metadata = plan.blob_metadata();
+    let mut content_hash = None;
+    if naming::content_addressed() {
+        let hash = hex::encode(Sha256::digest(&bytes));
+        if let Some(existing) = duplicates::find(container_client, &hash, &plan.then).await {
+            info!("{} duplicates {}", name, existing.original.blob);
+            telemetry::track_event("DuplicateUpload", &[("filename", name.to_string())]);
+            return Ok(());
+        }
+        duplicates::stamp(&mut metadata, &hash);
+        content_hash = Some(hash);
+    }
+    let blob_name = naming::original_name(container_client, name, content_hash.as_deref())
+        .await
+        .map_err(|e| e.message)?;
+
+    let container_name = container_client.container_name().to_string();
+    let customer_key = customer_keys::customer_key(tenant.map(|tenant| tenant.id.as_str()));
+    let (blob_name, (_, location)) = naming::store(container_client, name, content_hash.as_deref(), blob_name, |blob_name, exclusive| {
+        let mut metadata = metadata.clone();
+        if blob_name != name {
+            naming::stamp(&mut metadata, name);
+        }
+        let (container_name, bytes, customer_key) = (&container_name, &bytes, &customer_key);
+        failover::write(move |location| {
+            let mut upload = container_client_at(container_name, location)
+                .blob_client(&blob_name)
+                .put_block_blob(bytes.clone())
+                .content_type(original_content_type(bytes))
+                .metadata(metadata.clone())
+                .tags(plan.blob_tags());
+            if let Some(customer_key) = customer_key {
+                upload = upload.encryption_key(customer_key.clone());
+            }
+            if exclusive {
+                upload = upload.if_match(storage::if_none());
+            }
+            let upload = upload.into_future();
+            telemetry::dependency("Azure blob", container_name, "put_block_blob", upload)
+        })
+    })
+    .await
+    .map_err(|e| e.message)?;
+    failover::record(&container_name, &blob_name, location)
+        .await
+        .map_err(|e| format!("Failed to record its location: {}", e))?;
+    if let Some(hash) = &content_hash {
+        duplicates::record(&container_name, hash, &blob_name).await;
+    }
+
+    let mut image = plan.message(blob_name, container_name, location);
+    image.pixels = dimensions.map(|(width, height)| width as u64 * height as u64);
+    image.content_hash = content_hash;
+    fan_out(&mut image, OutputFormat::of_source(&bytes));
+    queue_upload(plan, image, Uuid::new_v4())
+        .await
+        .map_err(|e| format!("Failed to enqueue: {}", e))?;
+    telemetry::track_event("ImageUploaded", &[("filename", name.to_string())]);
+    count_upload("upload/zip", bytes.len() as u64);
+    Ok(())
+}
@@ -0,0 +1,27 @@
+// core/build.rs
+
+//! Stamps the git SHA and build time into the crate for `build_info`.
+
+use std::{
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+fn main() {
+    // CI images often build without the .git directory, so let them pass the SHA in
+    let git_sha = std::env::var("GIT_SHA").ok().or_else(|| {
+        Command::new("git")
+            .args(["rev-parse", "--short=12", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    });
+    let built_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+
+    println!("cargo:rustc-env=BUILD_GIT_SHA={}", git_sha.unwrap_or_else(|| "unknown".to_string()));
+    println!("cargo:rustc-env=BUILD_UNIX_TIME={}", built_at);
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs/heads");
+}
@@ -0,0 +1,62 @@
+// core/src/account_keys.rs
+
+//! Storage account keys, re-read while running so a key can be rotated without a restart. With
+//! `AZURE_STORAGE_KEYS_FILE` set, keys come from that JSON file, `{"<account>": "<key>"}`, re-read
+//! every `STORAGE_KEYS_REFRESH_SECS` (default 60); accounts it doesn't name, or every account
+//! without it, use `AZURE_STORAGE_ACCESS_KEY` and `AZURE_STORAGE_FAILOVER_ACCESS_KEY`. None of this
+//! applies when authenticating with an identity, see `identity.rs`.
+//!
+//! Each account has a primary and a secondary key, so rotating one goes: write the other key to
+//! the file, wait for the refresh and for SAS URLs signed with the old key to expire (see
+//! `sas.rs`), then regenerate the old key in Azure.
+
+use std::{
+    collections::HashMap,
+    env, fs,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+const DEFAULT_REFRESH_SECS: u64 = 60;
+
+struct Loaded {
+    at: Instant,
+    keys: HashMap<String, String>,
+}
+
+static KEYS: Mutex<Option<Loaded>> = Mutex::new(None);
+
+fn refresh_interval() -> Duration {
+    let secs = env::var("STORAGE_KEYS_REFRESH_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_REFRESH_SECS);
+    Duration::from_secs(secs)
+}
+
+/// The key of `account` from `AZURE_STORAGE_KEYS_FILE`, `None` without the file or an entry for
+/// the account. A file that can't be read keeps the keys last read from it.
+pub fn access_key(account: &str) -> Option<String> {
+    let path = env::var("AZURE_STORAGE_KEYS_FILE").ok()?;
+    let mut loaded = KEYS.lock().expect("Account keys lock poisoned");
+    if loaded.as_ref().is_none_or(|loaded| loaded.at.elapsed() >= refresh_interval()) {
+        let read = fs::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| serde_json::from_slice::<HashMap<String, String>>(&bytes).map_err(|e| e.to_string()));
+        let keys = match read {
+            Ok(keys) => {
+                if loaded.as_ref().is_some_and(|loaded| loaded.keys != keys) {
+                    info!("Reloaded storage account keys from {}", path);
+                }
+                keys
+            }
+            Err(e) => {
+                warn!("Failed to read storage account keys from {}: {}", path, e);
+                loaded.take().map(|loaded| loaded.keys).unwrap_or_default()
+            }
+        };
+        *loaded = Some(Loaded { at: Instant::now(), keys });
+    }
+    loaded.as_ref().and_then(|loaded| loaded.keys.get(account).cloned())
+}
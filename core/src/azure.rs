@@ -0,0 +1,294 @@
+// core/src/azure.rs
+
+//! Client plumbing for the Azure SDK. With `AZURE_SDK_TRACE` set to `1`/`true`, every request the
+//! storage pipeline or the Service Bus client sends is wrapped in an `azure.request` span carrying
+//! its method, URL path, status, retry count and latency, so throttling and 5xx storms show up in
+//! the logs. Query strings are left out since they carry SAS signatures.
+//!
+//! Both kinds of client also retry 408/429/5xx responses, waiting exactly as long as the service
+//! asks through `Retry-After` (capped at `AZURE_RETRY_MAX_DELAY_SECS`, default 60) and falling
+//! back to exponential backoff otherwise. `AZURE_RETRY_MAX` (default 5) bounds the retries, and
+//! every throttled response is counted in the `AzureThrottled` metric.
+
+use async_trait::async_trait;
+use azure_core::{
+    date,
+    error::ErrorKind,
+    headers::{Headers, RETRY_AFTER, RETRY_AFTER_MS, X_MS_RETRY_AFTER_MS},
+    ClientOptions, Context, Error, HttpClient, Policy, PolicyResult, Request, Response, RetryOptions, RetryPolicy,
+    StatusCode,
+};
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+use time::OffsetDateTime;
+
+use crate::telemetry;
+use tracing::{field, info, info_span, warn, Instrument, Span};
+
+/// Whether SDK request tracing was switched on through `AZURE_SDK_TRACE`.
+pub fn sdk_tracing_enabled() -> bool {
+    static ENABLED: OnceLock<bool> = OnceLock::new();
+    *ENABLED.get_or_init(|| {
+        env::var("AZURE_SDK_TRACE")
+            .map(|v| matches!(v.trim().to_ascii_lowercase().as_str(), "1" | "true" | "on"))
+            .unwrap_or(false)
+    })
+}
+
+/// Options for storage clients: the throttling-aware retry policy, plus the tracing policies when enabled.
+pub fn client_options() -> ClientOptions {
+    let mut options = ClientOptions::default().retry(RetryOptions::custom(Arc::new(ThrottleAwareRetry::from_env())));
+    if sdk_tracing_enabled() {
+        options.per_call_policies_mut().push(Arc::new(RequestTracePolicy));
+        options.per_retry_policies_mut().push(Arc::new(AttemptCountPolicy));
+    }
+    options
+}
+
+/// The HTTP client handed to clients that take one directly, such as the Service Bus queue client.
+pub fn http_client() -> Arc<dyn HttpClient> {
+    let mut inner = azure_core::new_http_client();
+    if sdk_tracing_enabled() {
+        inner = Arc::new(TracingHttpClient { inner });
+    }
+    // outermost, so each attempt gets its own span
+    Arc::new(RetryingHttpClient {
+        inner,
+        policy: ThrottleAwareRetry::from_env(),
+    })
+}
+
+fn request_span(request: &Request) -> Span {
+    info_span!(
+        "azure.request",
+        method = %request.method(),
+        host = request.url().host_str().unwrap_or_default(),
+        path = request.url().path(),
+        status = field::Empty,
+        retries = field::Empty,
+        latency_ms = field::Empty,
+    )
+}
+
+/// Records the outcome on the span and logs it, loudly when the service is throttling or failing.
+fn record_outcome(span: &Span, status: Option<StatusCode>, retries: u32, started: Instant) {
+    let latency_ms = started.elapsed().as_millis() as u64;
+    span.record("retries", retries);
+    span.record("latency_ms", latency_ms);
+    let _entered = span.enter();
+    match status {
+        Some(status) => {
+            span.record("status", u16::from(status));
+            if status == StatusCode::TooManyRequests || u16::from(status) >= 500 {
+                warn!("Azure request failed with {}", u16::from(status));
+            } else {
+                info!("Azure request completed");
+            }
+        }
+        None => warn!("Azure request failed without a response"),
+    }
+}
+
+fn status_of(result: &azure_core::Result<Response>) -> Option<StatusCode> {
+    match result {
+        Ok(response) => Some(response.status()),
+        Err(e) => match e.kind() {
+            ErrorKind::HttpResponse { status, .. } => Some(*status),
+            _ => None,
+        },
+    }
+}
+
+/// Number of times the request went out, shared between the two policies through the context.
+#[derive(Debug, Default)]
+struct Attempts(AtomicU32);
+
+/// Per-call policy: one span around the whole call, retries included.
+#[derive(Debug)]
+struct RequestTracePolicy;
+
+#[async_trait]
+impl Policy for RequestTracePolicy {
+    async fn send(&self, ctx: &Context, request: &mut Request, next: &[Arc<dyn Policy>]) -> PolicyResult {
+        let mut ctx = ctx.clone();
+        ctx.insert(Attempts::default());
+
+        let span = request_span(request);
+        let started = Instant::now();
+        let result = next[0].send(&ctx, request, &next[1..]).instrument(span.clone()).await;
+
+        let attempts = ctx.get::<Attempts>().map(|a| a.0.load(Ordering::Relaxed)).unwrap_or(1);
+        record_outcome(&span, status_of(&result), attempts.saturating_sub(1), started);
+        result
+    }
+}
+
+/// Per-retry policy: counts attempts for [`RequestTracePolicy`].
+#[derive(Debug)]
+struct AttemptCountPolicy;
+
+#[async_trait]
+impl Policy for AttemptCountPolicy {
+    async fn send(&self, ctx: &Context, request: &mut Request, next: &[Arc<dyn Policy>]) -> PolicyResult {
+        if let Some(attempts) = ctx.get::<Attempts>() {
+            attempts.0.fetch_add(1, Ordering::Relaxed);
+        }
+        next[0].send(ctx, request, &next[1..]).await
+    }
+}
+
+/// Wraps a transport so clients that bypass the policy pipeline are traced as well.
+#[derive(Debug)]
+struct TracingHttpClient {
+    inner: Arc<dyn HttpClient>,
+}
+
+#[async_trait]
+impl HttpClient for TracingHttpClient {
+    async fn execute_request(&self, request: &Request) -> azure_core::Result<Response> {
+        let span = request_span(request);
+        let started = Instant::now();
+        let result = self.inner.execute_request(request).instrument(span.clone()).await;
+        record_outcome(&span, status_of(&result), 0, started);
+        result
+    }
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .map(|v| v.parse().unwrap_or_else(|_| panic!("Invalid {} env var", name)))
+        .unwrap_or(default)
+}
+
+/// Statuses worth another attempt; anything else is returned to the caller as is.
+fn is_retryable(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::RequestTimeout
+            | StatusCode::TooManyRequests
+            | StatusCode::InternalServerError
+            | StatusCode::BadGateway
+            | StatusCode::ServiceUnavailable
+            | StatusCode::GatewayTimeout
+    )
+}
+
+/// The delay the service asked for, in the order the Azure SDKs look for it.
+fn retry_after(headers: &Headers) -> Option<Duration> {
+    for name in [RETRY_AFTER_MS, X_MS_RETRY_AFTER_MS] {
+        if let Some(ms) = headers.get_optional_str(&name).and_then(|v| v.trim().parse().ok()) {
+            return Some(Duration::from_millis(ms));
+        }
+    }
+    let value = headers.get_optional_str(&RETRY_AFTER)?.trim();
+    // either delta-seconds or an HTTP date
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = date::parse_rfc1123(value).ok()?;
+    Some((at - OffsetDateTime::now_utc()).try_into().unwrap_or_default())
+}
+
+/// Counts a throttled response so sustained throttling is visible on a dashboard.
+fn record_throttled(status: StatusCode, retry_after: Option<Duration>) {
+    warn!(
+        "Azure throttled the request with {}, retrying after {:?}",
+        u16::from(status),
+        retry_after
+    );
+    telemetry::track_metric(
+        "AzureThrottled",
+        1.0,
+        &[
+            ("status", u16::from(status).to_string()),
+            ("retry_after_ms", retry_after.map(|d| d.as_millis().to_string()).unwrap_or_default()),
+        ],
+    );
+}
+
+/// Retries on exponential backoff, except that a server-provided `Retry-After` is followed exactly.
+#[derive(Debug, Clone)]
+struct ThrottleAwareRetry {
+    max_retries: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+}
+
+impl ThrottleAwareRetry {
+    fn from_env() -> Self {
+        Self {
+            max_retries: env_or("AZURE_RETRY_MAX", 5),
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(env_or("AZURE_RETRY_MAX_DELAY_SECS", 60)),
+        }
+    }
+
+    fn delay(&self, retry_count: u32, retry_after: Option<Duration>) -> Duration {
+        retry_after.unwrap_or_else(|| self.sleep_duration(retry_count)).min(self.max_delay)
+    }
+}
+
+#[async_trait]
+impl RetryPolicy for ThrottleAwareRetry {
+    fn is_expired(&self, _duration_since_start: Duration, retry_count: u32) -> bool {
+        retry_count >= self.max_retries
+    }
+
+    fn sleep_duration(&self, retry_count: u32) -> Duration {
+        let exponent = retry_count.saturating_sub(1).min(16);
+        self.base_delay.saturating_mul(1 << exponent).min(self.max_delay)
+    }
+
+    async fn wait(&self, error: &Error, retry_count: u32, retry_after: Option<Duration>) {
+        if let ErrorKind::HttpResponse { status, .. } = error.kind() {
+            if matches!(status, StatusCode::TooManyRequests | StatusCode::ServiceUnavailable) {
+                record_throttled(*status, retry_after);
+            }
+        }
+        azure_core::sleep(self.delay(retry_count, retry_after)).await;
+    }
+}
+
+/// Retries for clients without a policy pipeline; the Service Bus client sends each request once.
+#[derive(Debug)]
+struct RetryingHttpClient {
+    inner: Arc<dyn HttpClient>,
+    policy: ThrottleAwareRetry,
+}
+
+#[async_trait]
+impl HttpClient for RetryingHttpClient {
+    async fn execute_request(&self, request: &Request) -> azure_core::Result<Response> {
+        let mut retry_count = 0;
+        loop {
+            let result = self.inner.execute_request(request).await;
+            let retry_after = match &result {
+                Ok(response) if is_retryable(response.status()) => {
+                    let status = response.status();
+                    match status {
+                        StatusCode::TooManyRequests | StatusCode::ServiceUnavailable => {
+                            let retry_after = retry_after(response.headers());
+                            record_throttled(status, retry_after);
+                            retry_after
+                        }
+                        _ => None,
+                    }
+                }
+                Err(e) if e.kind() == &ErrorKind::Io => None,
+                _ => return result,
+            };
+            if self.policy.is_expired(Duration::ZERO, retry_count) {
+                return result;
+            }
+            retry_count += 1;
+            azure_core::sleep(self.policy.delay(retry_count, retry_after)).await;
+        }
+    }
+}
@@ -0,0 +1,70 @@
+// core/src/blob_tags.rs
+
+//! Blob index tags written with every original and rendition, so listings can be filtered by the
+//! storage service with `FindBlobsByTags` rather than by scanning whole containers.
+//!
+//! - `tenant`: the uploading tenant, empty for uploads made without one;
+//! - `preset`: what the blob is, [`ORIGINAL`], [`RESIZED`], [`ANALYSIS`], [`PUBLISHED`],
+//!   [`REPORT`], [`PAGE`], [`PAGES`], [`ARCHIVED`], `render:<template>` or `variant:<name>`;
+//! - `status`: [`UPLOADED`] or [`PROCESSED`] for originals, [`READY`] for worker output and
+//!   [`STAGED`] for worker output not yet published.
+
+use azure_storage_blobs::prelude::Tags;
+
+pub const TENANT: &str = "tenant";
+pub const PRESET: &str = "preset";
+pub const STATUS: &str = "status";
+
+pub const ORIGINAL: &str = "original";
+pub const RESIZED: &str = "resized";
+pub const ANALYSIS: &str = "analysis";
+pub const PUBLISHED: &str = "published";
+pub const REPORT: &str = "report";
+/// A rendered PDF page.
+pub const PAGE: &str = "page";
+/// The manifest listing a PDF's rendered pages.
+pub const PAGES: &str = "pages";
+/// An original as uploaded, kept when the stored one was normalized.
+pub const ARCHIVED: &str = "archived";
+
+pub const UPLOADED: &str = "uploaded";
+pub const PROCESSED: &str = "processed";
+pub const READY: &str = "ready";
+pub const STAGED: &str = "staged";
+
+/// Tag values may only hold letters, digits and ` +-.:=_/`, up to 256 characters.
+fn tag_value(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || " +-.:=_/".contains(c) { c } else { '_' })
+        .take(256)
+        .collect()
+}
+
+pub fn render_preset(template: &str) -> String {
+    format!("render:{}", template)
+}
+
+/// A size variant of the resized rendition, see `variants.rs`.
+pub fn variant_preset(name: &str) -> String {
+    format!("variant:{}", name)
+}
+
+pub fn tags(tenant: Option<&str>, preset: &str, status: &str) -> Tags {
+    let mut tags = Tags::new();
+    tags.insert(TENANT, tag_value(tenant.unwrap_or_default()));
+    tags.insert(PRESET, tag_value(preset));
+    tags.insert(STATUS, tag_value(status));
+    tags
+}
+
+/// A `FindBlobsByTags` expression selecting blobs in `container` with the given tag values.
+pub fn expression(container: &str, filters: &[(&str, &str)]) -> String {
+    let mut clauses = vec![format!("@container = '{}'", container)];
+    clauses.extend(
+        filters
+            .iter()
+            .map(|(key, value)| format!("\"{}\" = '{}'", key, tag_value(value))),
+    );
+    clauses.join(" AND ")
+}
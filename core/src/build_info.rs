@@ -0,0 +1,47 @@
+// core/src/build_info.rs
+
+//! What is running: reported by the API's `/version` and logged and stamped by the worker.
+
+use image::ImageFormat;
+use serde::Serialize;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::cpu_features::{self, CpuInfo};
+
+#[derive(Serialize, Debug, Clone)]
+pub struct BuildInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub git_sha: &'static str,
+    /// RFC 3339 time the shared crate was compiled.
+    pub built_at: String,
+    /// Image formats this build can write.
+    pub formats: Vec<String>,
+    /// Services and optional integrations the binary talks to.
+    pub backends: Vec<&'static str>,
+    /// CPU features found at startup and the resize and encode implementation picked for them.
+    pub cpu: &'static CpuInfo,
+}
+
+/// Build info for a binary; pass its own `CARGO_PKG_NAME` and `CARGO_PKG_VERSION`.
+pub fn build_info(name: &'static str, version: &'static str, backends: Vec<&'static str>) -> BuildInfo {
+    let built_at = env!("BUILD_UNIX_TIME")
+        .parse::<i64>()
+        .ok()
+        .and_then(|secs| OffsetDateTime::from_unix_timestamp(secs).ok())
+        .and_then(|at| at.format(&Rfc3339).ok())
+        .unwrap_or_default();
+
+    BuildInfo {
+        name,
+        version,
+        git_sha: env!("BUILD_GIT_SHA"),
+        built_at,
+        formats: ImageFormat::all()
+            .filter(|format| format.writing_enabled())
+            .map(|format| format!("{:?}", format).to_lowercase())
+            .collect(),
+        backends,
+        cpu: cpu_features::get(),
+    }
+}
@@ -0,0 +1,230 @@
+// core/src/capabilities.rs
+
+//! What this build takes in and puts out, served by the API on `GET /capabilities` so clients can
+//! adapt to a deployment instead of hard-coding it. It's the registry validation reads, so the two
+//! can't disagree: an uploaded image must be in a format listed here, see `image_checks.rs`, and
+//! `output_format` and `CONTENT_ROUTES` take the names of the output formats and pipelines here.
+//!
+//! Image formats are those the `image` crate was compiled to read, which the API and the worker
+//! share. SVGs are listed only while `CONTENT_ROUTES` has them rasterized. The dimensions are the
+//! `MAX_IMAGE_*` limits in force.
+
+use image::ImageFormat;
+use serde::Serialize;
+
+use crate::{
+    config,
+    image_checks::ImageLimits,
+    operations::{self, MAX_OPERATIONS},
+    output_format::OutputFormat,
+    pdf,
+    routing::Pipeline,
+    svg,
+    video::VideoFormat,
+};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum InputKind {
+    Image,
+    Vector,
+    Document,
+    Video,
+}
+
+/// A format uploads may be in.
+#[derive(Serialize, Debug, Clone)]
+pub struct InputFormat {
+    pub name: &'static str,
+    pub content_type: &'static str,
+    pub kind: InputKind,
+}
+
+/// A format renditions may be encoded in.
+#[derive(Serialize, Debug, Clone)]
+pub struct EncodedFormat {
+    pub name: &'static str,
+    pub content_type: &'static str,
+    pub lossless: bool,
+}
+
+/// Reply of `GET /capabilities`.
+#[derive(Serialize, Debug, Clone)]
+pub struct Capabilities {
+    pub input_formats: Vec<InputFormat>,
+    pub output_formats: Vec<EncodedFormat>,
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_pixels: u64,
+    /// Pipelines `CONTENT_ROUTES` may send sources through, see `routing.rs`.
+    pub pipelines: Vec<&'static str>,
+    /// Operations an upload's `operations` may list, see `operations.rs`.
+    pub operations: Vec<&'static str>,
+    pub max_operations: usize,
+}
+
+/// Whether uploads in the image format `format` are accepted.
+pub fn reads(format: ImageFormat) -> bool {
+    format.reading_enabled()
+}
+
+/// The formats uploads are accepted in.
+pub fn input_formats() -> Vec<InputFormat> {
+    let images = ImageFormat::all().filter(|format| reads(*format)).map(|format| InputFormat {
+        name: format.extensions_str().first().copied().unwrap_or_default(),
+        content_type: format.to_mime_type(),
+        kind: InputKind::Image,
+    });
+    let svgs = (config::get().pipeline_for(svg::EXTENSION) == Pipeline::Rasterize).then_some(InputFormat {
+        name: svg::EXTENSION,
+        content_type: svg::CONTENT_TYPE,
+        kind: InputKind::Vector,
+    });
+    let pdfs = InputFormat {
+        name: pdf::EXTENSION,
+        content_type: pdf::CONTENT_TYPE,
+        kind: InputKind::Document,
+    };
+    let videos = VideoFormat::ALL.into_iter().map(|format| InputFormat {
+        name: format.extension(),
+        content_type: format.content_type(),
+        kind: InputKind::Video,
+    });
+    images.chain(svgs).chain([pdfs]).chain(videos).collect()
+}
+
+/// The formats renditions are encoded in.
+pub fn output_formats() -> Vec<EncodedFormat> {
+    OutputFormat::ALL
+        .into_iter()
+        .map(|format| EncodedFormat {
+            name: format.name(),
+            content_type: format.content_type(),
+            lossless: format.lossless(),
+        })
+        .collect()
+}
+
+/// Everything [`Capabilities`] reports, as configured now.
+pub fn current() -> Capabilities {
+    let limits = ImageLimits::from_env();
+    Capabilities {
+        input_formats: input_formats(),
+        output_formats: output_formats(),
+        max_width: limits.max_width,
+        max_height: limits.max_height,
+        max_pixels: limits.max_pixels,
+        pipelines: Pipeline::ALL.map(Pipeline::name).to_vec(),
+        operations: operations::NAMES.to_vec(),
+        max_operations: MAX_OPERATIONS,
+    }
+}
+
+/// `names` listed for an error message.
+///
+/// ```
+/// use image_resize_core::capabilities::one_of;
+///
+/// assert_eq!(one_of(["jpeg", "png", "webp"]), "jpeg, png or webp");
+/// assert_eq!(one_of(["jpeg"]), "jpeg");
+/// ```
+pub fn one_of<const N: usize>(names: [&str; N]) -> String {
+    match names.split_last() {
+        Some((last, [])) => last.to_string(),
+        Some((last, rest)) => format!("{} or {}", rest.join(", "), last),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_checks::{self, Invalid};
+    use image::{DynamicImage, RgbImage};
+    use std::io::Cursor;
+
+    /// A 2x2 image encoded in `format`.
+    fn encoded(format: ImageFormat) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(2, 2))
+            .write_to(&mut Cursor::new(&mut bytes), format)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn supported_inputs_give_their_default_outputs() {
+        let cases = [
+            (ImageFormat::Jpeg, OutputFormat::Jpeg),
+            (ImageFormat::Png, OutputFormat::Png),
+            (ImageFormat::Gif, OutputFormat::Png),
+            (ImageFormat::WebP, OutputFormat::Webp),
+            (ImageFormat::Bmp, OutputFormat::Jpeg),
+            (ImageFormat::Tiff, OutputFormat::Jpeg),
+        ];
+        for (input, output) in cases {
+            assert!(reads(input), "{:?}", input);
+            let bytes = encoded(input);
+            assert!(matches!(image_checks::check("upload", &bytes), Ok(Some((2, 2)))), "{:?}", input);
+            assert_eq!(OutputFormat::of_source(&bytes), output, "{:?}", input);
+        }
+        // documents and videos are taken without decoding, their pages and poster frames rendered as JPEG
+        let pdf = b"%PDF-1.7\n".to_vec();
+        let mp4 = [&[0, 0, 0, 24][..], b"ftypisom", &[0; 12]].concat();
+        let webm = [0x1a, 0x45, 0xdf, 0xa3, 0, 0, 0, 0];
+        for bytes in [&pdf[..], &mp4, &webm] {
+            assert!(matches!(image_checks::check("upload", bytes), Ok(None)));
+            assert_eq!(OutputFormat::of_source(bytes), OutputFormat::Jpeg);
+        }
+    }
+
+    #[test]
+    fn unsupported_inputs_are_refused() {
+        for bytes in [&b"plain text"[..], b"\x00\x01\x02\x03\x04\x05\x06\x07", b"PK\x03\x04zip"] {
+            assert!(matches!(image_checks::check("upload", bytes), Err(Invalid::Unsupported(_))));
+        }
+    }
+
+    #[test]
+    fn every_output_format_is_listed_and_requestable() {
+        let listed = output_formats();
+        assert_eq!(listed.len(), OutputFormat::ALL.len());
+        for (format, listed) in OutputFormat::ALL.into_iter().zip(&listed) {
+            assert_eq!(listed.name.parse::<OutputFormat>(), Ok(format));
+            assert_eq!(listed.content_type, format.content_type());
+            assert_eq!(listed.lossless, format != OutputFormat::Jpeg);
+        }
+        assert_eq!("JPG".parse::<OutputFormat>(), Ok(OutputFormat::Jpeg));
+    }
+
+    #[test]
+    fn unsupported_outputs_are_refused() {
+        for name in ["gif", "avif", "bmp", "tiff", "svg", ""] {
+            let error = name.parse::<OutputFormat>().unwrap_err();
+            assert!(error.ends_with("use jpeg, png or webp"), "{}: {}", name, error);
+        }
+    }
+
+    #[test]
+    fn pipelines_take_only_their_inputs() {
+        let cases = [
+            (Pipeline::Standard, "jpeg", true),
+            (Pipeline::Standard, "gif", true),
+            (Pipeline::Standard, "pdf", true),
+            (Pipeline::Standard, "svg", false),
+            (Pipeline::Animation, "gif", true),
+            (Pipeline::Animation, "png", false),
+            (Pipeline::Animation, "svg", false),
+            (Pipeline::Rasterize, "svg", true),
+            (Pipeline::Rasterize, "png", false),
+            (Pipeline::Rasterize, "gif", false),
+        ];
+        for (pipeline, kind, accepted) in cases {
+            assert_eq!(pipeline.accepts(kind), accepted, "{:?} taking {}", pipeline, kind);
+        }
+        for pipeline in Pipeline::ALL {
+            assert_eq!(pipeline.name().parse::<Pipeline>(), Ok(pipeline));
+        }
+        assert!("thumbnail".parse::<Pipeline>().is_err());
+    }
+}
@@ -0,0 +1,160 @@
+// core/src/chaos.rs
+
+//! Fault injection, to check in integration tests and staging that retries, dead-lettering and
+//! the storage circuit breaker behave as intended. It's only built with `--features chaos`; other
+//! builds ignore the settings below and never inject anything.
+//!
+//! - `CHAOS_STORAGE_ERROR_RATE`: the share, from 0 to 1, of reads and writes on the primary storage
+//!   account failing with a `503`, before they're sent. The secondary is left alone, so failover
+//!   and the read fallback have somewhere healthy to go.
+//! - `CHAOS_QUEUE_ERROR_RATE`: the share of Service Bus sends, receives and settlements failing
+//!   the same way.
+//! - `CHAOS_LATENCY_MS`: a delay of up to that many milliseconds, picked at random, added to each
+//!   of those calls.
+//! - `CHAOS_PARTIAL_READ_RATE`: the share of the worker's and the API's whole-blob downloads cut
+//!   short at a random length, as when a connection drops mid-body without an error.
+//!
+//! Each injected fault is counted in `chaos_faults_injected_total`, by target, operation and fault.
+
+use azure_core::{
+    error::{Error, ErrorKind},
+    StatusCode,
+};
+use std::{env, fmt, sync::OnceLock, time::Duration};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::metrics;
+
+/// The error code of an injected failure.
+pub const FAULT_CODE: &str = "InjectedFault";
+
+/// What a fault is injected into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Storage,
+    Queue,
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Target::Storage => "storage",
+            Target::Queue => "queue",
+        })
+    }
+}
+
+/// The faults to inject, from the `CHAOS_*` variables.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Faults {
+    pub storage_error_rate: f64,
+    pub queue_error_rate: f64,
+    pub latency: Duration,
+    pub partial_read_rate: f64,
+}
+
+impl Faults {
+    fn from_env() -> Self {
+        let rate = |key: &str| {
+            env::var(key)
+                .ok()
+                .map(|v| v.trim().parse::<f64>().unwrap_or_else(|_| panic!("Invalid {}: {}", key, v)))
+                .map_or(0.0, |rate| rate.clamp(0.0, 1.0))
+        };
+        Faults {
+            storage_error_rate: rate("CHAOS_STORAGE_ERROR_RATE"),
+            queue_error_rate: rate("CHAOS_QUEUE_ERROR_RATE"),
+            latency: Duration::from_millis(env::var("CHAOS_LATENCY_MS").ok().and_then(|v| v.parse().ok()).unwrap_or(0)),
+            partial_read_rate: rate("CHAOS_PARTIAL_READ_RATE"),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.storage_error_rate == 0.0 && self.queue_error_rate == 0.0 && self.latency.is_zero() && self.partial_read_rate == 0.0
+    }
+
+    fn error_rate(&self, target: Target) -> f64 {
+        match target {
+            Target::Storage => self.storage_error_rate,
+            Target::Queue => self.queue_error_rate,
+        }
+    }
+}
+
+/// The faults to inject, read once; `None` without the `chaos` feature or when none are set.
+pub fn faults() -> Option<&'static Faults> {
+    static FAULTS: OnceLock<Option<Faults>> = OnceLock::new();
+    FAULTS
+        .get_or_init(|| {
+            let faults = Faults::from_env();
+            if faults.is_empty() {
+                None
+            } else if cfg!(feature = "chaos") {
+                warn!("Injecting faults: {:?}", faults);
+                Some(faults)
+            } else {
+                warn!("CHAOS_* is set but this build has no chaos feature, no faults are injected");
+                None
+            }
+        })
+        .as_ref()
+}
+
+/// Whether an event of probability `rate` happens this time.
+///
+/// ```
+/// use image_resize_core::chaos::roll;
+///
+/// assert!(!roll(0.0));
+/// assert!(roll(1.0));
+/// ```
+pub fn roll(rate: f64) -> bool {
+    rate > 0.0 && fraction() < rate
+}
+
+/// A random number in `[0, 1)`.
+fn fraction() -> f64 {
+    // the top 48 bits of a v4 UUID are random
+    (Uuid::new_v4().as_u128() >> 80) as f64 / (1u64 << 48) as f64
+}
+
+fn count(target: Target, operation: &str, fault: &str) {
+    let target = target.to_string();
+    metrics::increment("chaos_faults_injected_total", &[("target", &target), ("operation", operation), ("fault", fault)]);
+}
+
+/// Delays a call to `target` and fails it, as the faults say. Call it right before sending the
+/// request, so a failure leaves nothing behind.
+pub async fn inject(target: Target, operation: &str) -> azure_core::Result<()> {
+    let Some(faults) = faults() else {
+        return Ok(());
+    };
+    if !faults.latency.is_zero() {
+        count(target, operation, "latency");
+        tokio::time::sleep(faults.latency.mul_f64(fraction())).await;
+    }
+    if !roll(faults.error_rate(target)) {
+        return Ok(());
+    }
+    debug!("Injecting a {} fault into {}", target, operation);
+    count(target, operation, "error");
+    Err(Error::message(
+        ErrorKind::http_response(StatusCode::ServiceUnavailable, Some(FAULT_CODE.to_string())),
+        format!("Injected {} fault in {}", target, operation),
+    ))
+}
+
+/// Cuts a downloaded body short at a random length, as the faults say.
+pub fn truncate(bytes: &mut Vec<u8>, operation: &str) {
+    let Some(faults) = faults() else {
+        return;
+    };
+    if bytes.is_empty() || !roll(faults.partial_read_rate) {
+        return;
+    }
+    let len = (fraction() * bytes.len() as f64) as usize;
+    debug!("Cutting a {} byte read in {} short at {} bytes", bytes.len(), operation, len);
+    count(Target::Storage, operation, "partial_read");
+    bytes.truncate(len);
+}
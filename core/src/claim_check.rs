@@ -0,0 +1,113 @@
+// core/src/claim_check.rs
+
+//! Claim checks for messages too large for the queue. A job with a long list of operations,
+//! variants or metadata can outgrow what Service Bus takes in one message (256 KB on the standard
+//! tier), so a message body over `QUEUE_CLAIM_CHECK_BYTES` (default 196608) is stored as the blob
+//! `<message id>.json` in `CLAIM_CHECK_CONTAINER` (default `claim-checks`), which must exist, and
+//! the queue gets only a reference to it:
+//!
+//! ```json
+//! {"schema_version": 4, "priority": "high", "claim_check": {"container": "claim-checks", "blob": "3f9a….json"}}
+//! ```
+//!
+//! The reference keeps the message's `schema_version` and `priority`, which the worker reads before
+//! parsing a message, and the worker resolves it to the stored body before anything else. Once the
+//! message is completed its blob is deleted; a copy dropped by duplicate detection shared it.
+
+use azure_core::error::{Error, ErrorKind};
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, env};
+use tracing::{info, warn};
+
+use crate::{
+    clients,
+    failover::Location,
+    message::{self, Priority},
+    storage::{AzureBlob, StorageBackend},
+    telemetry,
+};
+
+const DEFAULT_THRESHOLD_BYTES: usize = 192 * 1024;
+const DEFAULT_CONTAINER: &str = "claim-checks";
+
+/// Where the body of a message sent as a claim check is stored.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClaimCheck {
+    pub container: String,
+    pub blob: String,
+}
+
+/// What's queued in place of a large message.
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    schema_version: u32,
+    #[serde(default)]
+    priority: Priority,
+    claim_check: ClaimCheck,
+}
+
+/// Largest body sent as it is, from `QUEUE_CLAIM_CHECK_BYTES`.
+fn threshold() -> usize {
+    env::var("QUEUE_CLAIM_CHECK_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_THRESHOLD_BYTES)
+}
+
+fn backend(container: &str) -> AzureBlob {
+    AzureBlob(clients::container_client(container, Location::Primary))
+}
+
+/// The claim check `body` is, if it's one.
+pub fn claim_check(body: &str) -> Option<ClaimCheck> {
+    serde_json::from_str::<Envelope>(body).ok().map(|envelope| envelope.claim_check)
+}
+
+/// `body` as it should be queued: itself, or a claim check once it's over the threshold, the body
+/// being stored first under `message_id`.
+pub async fn check_in<'a>(message_id: &str, body: &'a str) -> azure_core::Result<Cow<'a, str>> {
+    if body.len() <= threshold() {
+        return Ok(Cow::Borrowed(body));
+    }
+    let claim_check = ClaimCheck {
+        container: env::var("CLAIM_CHECK_CONTAINER").unwrap_or_else(|_| DEFAULT_CONTAINER.to_string()),
+        blob: format!("{}.json", message_id),
+    };
+    let backend = backend(&claim_check.container);
+    let put = backend.put(&claim_check.blob, body.as_bytes().to_vec(), "application/json");
+    telemetry::dependency("Azure blob", &claim_check.container, "put_block_blob", put).await?;
+    info!("Sending a message of {} bytes as a claim check on {}", body.len(), claim_check.blob);
+    let envelope = Envelope {
+        schema_version: message::schema_version(body).unwrap_or(message::SCHEMA_VERSION),
+        priority: message::priority(body),
+        claim_check,
+    };
+    Ok(Cow::Owned(serde_json::to_string(&envelope).expect("Failed to serialize claim check")))
+}
+
+/// The message `body` stands for: the stored body of a claim check, or `body` itself. A claim
+/// check whose blob is gone fails with a 404.
+pub async fn resolve(body: &str) -> azure_core::Result<Cow<'_, str>> {
+    let Some(claim_check) = claim_check(body) else {
+        return Ok(Cow::Borrowed(body));
+    };
+    let backend = backend(&claim_check.container);
+    let get = backend.get(&claim_check.blob);
+    let bytes = telemetry::dependency("Azure blob", &claim_check.container, "get", get).await?;
+    let stored = String::from_utf8(bytes)
+        .map_err(|e| Error::full(ErrorKind::DataConversion, e, format!("Claim check {} isn't UTF-8", claim_check.blob)))?;
+    Ok(Cow::Owned(stored))
+}
+
+/// Deletes the stored body of a claim check once its message is completed. Failures are only
+/// logged, leaving the blob behind.
+pub async fn release(body: &str) {
+    let Some(claim_check) = claim_check(body) else {
+        return;
+    };
+    let backend = backend(&claim_check.container);
+    let delete = backend.delete(&claim_check.blob);
+    if let Err(e) = telemetry::dependency("Azure blob", &claim_check.container, "delete", delete).await {
+        warn!("Failed to delete claim check {}: {:?}", claim_check.blob, e);
+    }
+}
@@ -0,0 +1,33 @@
+// core/src/clients.rs
+
+//! Clients for storage and the queue, built from [`crate::config`] with the retry and tracing
+//! options of `azure.rs`. Storage clients are cheap and built per use, so each one gets the
+//! account key current at the time, or the identity's credentials, see `identity.rs`.
+
+use azure_storage_blobs::prelude::{BlobServiceClient, ClientBuilder, ContainerClient};
+
+use crate::{
+    azure,
+    failover::{self, Location},
+    queue::QueueReceiver,
+};
+
+fn builder(location: Location) -> ClientBuilder {
+    let (storage_account, storage_credentials) = failover::credentials(location);
+    ClientBuilder::new(storage_account, storage_credentials).client_options(azure::client_options())
+}
+
+/// A client for the primary or the failover storage account.
+pub fn blob_service_client(location: Location) -> BlobServiceClient {
+    builder(location).blob_service_client()
+}
+
+/// A client for a container in the primary or the failover storage account.
+pub fn container_client(container_name: &str, location: Location) -> ContainerClient {
+    builder(location).container_client(container_name.to_string())
+}
+
+/// A client receiving from the job queue.
+pub fn queue_client() -> QueueReceiver {
+    QueueReceiver::from_env()
+}
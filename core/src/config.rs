@@ -0,0 +1,159 @@
+// core/src/config.rs
+
+//! Settings the API and the worker both need, read from the environment once. Both load it at
+//! startup, so a missing variable stops the process there rather than on the first request or
+//! message. Storage account keys aren't part of it: they're looked up whenever a client is built,
+//! so a rotated key is picked up, see `account_keys.rs`. Whether keys are used at all is up to
+//! `AZURE_AUTH_MODE`, see `identity.rs`.
+
+use std::{env, sync::OnceLock};
+
+use crate::{
+    identity::{self, AuthMode},
+    output_format::OutputFormat,
+    routing::Pipeline,
+    shards::{self, ShardKey},
+};
+
+#[derive(Debug)]
+pub struct Config {
+    /// `AZURE_STORAGE_ACCOUNT`.
+    pub storage_account: String,
+    /// `AZURE_STORAGE_FAILOVER_ACCOUNT`, see `failover.rs`.
+    pub failover_account: Option<String>,
+    /// `AZURE_AUTH_MODE`, `None` to pick by the keys that are set.
+    pub auth_mode: Option<AuthMode>,
+    /// How storage is reached, `auth_mode` or keys when they are set.
+    pub storage_auth: AuthMode,
+    /// `AZURE_STORAGE_CONTAINER`, where the API stores originals; the worker goes by its messages.
+    container: Option<String>,
+    pub service_bus: ServiceBusConfig,
+    /// `CONTENT_ROUTES`, pipelines taking sources of a type instead of the standard one.
+    pub content_routes: Vec<(String, Pipeline)>,
+}
+
+/// The queue jobs go through and the shared access policy used to reach it, if any.
+#[derive(Debug)]
+pub struct ServiceBusConfig {
+    /// `AZURE_SERVICE_BUS_NAMESPACE`.
+    pub namespace: String,
+    /// `AZURE_QUEUE_NAME`.
+    pub queue_name: String,
+    /// `AZURE_POLICY_NAME` and `AZURE_POLICY_KEY`, `None` when authenticating with an identity.
+    pub policy: Option<(String, String)>,
+    /// `FORMAT_QUEUES`, queues taking the jobs of one output format instead of `queue_name`.
+    pub format_queues: Vec<(OutputFormat, String)>,
+    /// `QUEUE_SHARDS`, the queues each of the above is split into, see `shards.rs`.
+    pub shards: u32,
+    /// `SHARD_KEY`, what picks the shard of a job.
+    pub shard_key: ShardKey,
+}
+
+impl ServiceBusConfig {
+    /// The queue for jobs rendering `format`, `queue_name` unless `FORMAT_QUEUES` names one.
+    pub fn queue_for(&self, format: Option<OutputFormat>) -> &str {
+        self.format_queues
+            .iter()
+            .find(|(queue_format, _)| Some(*queue_format) == format)
+            .map_or(&self.queue_name, |(_, queue)| queue)
+    }
+}
+
+/// Parses `FORMAT_QUEUES`, a comma separated list of `<format>=<queue>`.
+fn format_queues() -> Vec<(OutputFormat, String)> {
+    env::var("FORMAT_QUEUES")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (format, queue) = entry
+                .split_once('=')
+                .unwrap_or_else(|| panic!("Invalid FORMAT_QUEUES entry '{}', use e.g. webp=images-webp", entry));
+            let format = format.parse().unwrap_or_else(|e| panic!("Invalid FORMAT_QUEUES: {}", e));
+            (format, queue.trim().to_string())
+        })
+        .collect()
+}
+
+/// Parses `CONTENT_ROUTES`, a comma separated list of `<type>=<pipeline>` where the type is that
+/// sniffed from a source's content, e.g. `gif=animation,svg=rasterize`.
+fn content_routes() -> Vec<(String, Pipeline)> {
+    env::var("CONTENT_ROUTES")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (kind, pipeline) = entry
+                .split_once('=')
+                .unwrap_or_else(|| panic!("Invalid CONTENT_ROUTES entry '{}', use e.g. gif=animation", entry));
+            let kind = kind.trim().to_ascii_lowercase();
+            let pipeline: Pipeline = pipeline.parse().unwrap_or_else(|e| panic!("Invalid CONTENT_ROUTES: {}", e));
+            if !pipeline.accepts(&kind) {
+                panic!("Invalid CONTENT_ROUTES: the {:?} pipeline can't take {} sources", pipeline, kind);
+            }
+            (kind, pipeline)
+        })
+        .collect()
+}
+
+/// The shared access policy, required in `key` mode, ignored in the identity modes and used when
+/// set otherwise.
+fn policy(auth_mode: Option<AuthMode>) -> Option<(String, String)> {
+    if auth_mode.is_some_and(|mode| mode != AuthMode::Key) {
+        return None;
+    }
+    match (env::var("AZURE_POLICY_NAME"), env::var("AZURE_POLICY_KEY")) {
+        (Ok(name), Ok(key)) => Some((name, key)),
+        (Err(_), Err(_)) if auth_mode.is_none() => None,
+        _ => panic!("Missing AZURE_POLICY_NAME or AZURE_POLICY_KEY env var"),
+    }
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+fn required(key: &str) -> String {
+    env::var(key).unwrap_or_else(|_| panic!("Missing {} env var", key))
+}
+
+impl Config {
+    fn from_env() -> Self {
+        let auth_mode = AuthMode::from_env();
+        Config {
+            storage_account: required("AZURE_STORAGE_ACCOUNT"),
+            failover_account: env::var("AZURE_STORAGE_FAILOVER_ACCOUNT").ok(),
+            auth_mode,
+            storage_auth: identity::storage_mode(auth_mode),
+            container: env::var("AZURE_STORAGE_CONTAINER").ok(),
+            service_bus: ServiceBusConfig {
+                namespace: required("AZURE_SERVICE_BUS_NAMESPACE"),
+                queue_name: required("AZURE_QUEUE_NAME"),
+                policy: policy(auth_mode),
+                format_queues: format_queues(),
+                shards: shards::count_from_env(),
+                shard_key: shards::key_from_env(),
+            },
+            content_routes: content_routes(),
+        }
+    }
+
+    /// The pipeline for sources of `kind`, e.g. `gif`, the standard one unless `CONTENT_ROUTES`
+    /// names another.
+    pub fn pipeline_for(&self, kind: &str) -> Pipeline {
+        self.content_routes
+            .iter()
+            .find(|(route_kind, _)| route_kind == kind)
+            .map_or(Pipeline::Standard, |(_, pipeline)| *pipeline)
+    }
+
+    /// The container originals are stored in; panics where it isn't set.
+    pub fn container(&self) -> &str {
+        self.container.as_deref().expect("Missing AZURE_STORAGE_CONTAINER env var")
+    }
+}
+
+/// The configuration, read on the first call.
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::from_env)
+}
@@ -0,0 +1,165 @@
+// core/src/config_check.rs
+
+//! `--check-config`, which both binaries take to validate their configuration and exit instead of
+//! serving or consuming anything, as a gate before a deploy. Every setting they read at startup is
+//! parsed, and the storage accounts and queues it names must answer, each binary adding checks of
+//! its own. The report is printed one check per line, or as JSON with `--json`, and the process
+//! exits with status 1 if any check failed.
+
+use serde::Serialize;
+use std::{
+    any::Any,
+    fmt::Display,
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    time::Instant,
+};
+
+use crate::{
+    clients, config, customer_keys,
+    failover::{self, Location},
+    health::{self, Check},
+    queue::QueueSender,
+    storage,
+};
+
+/// The argument asking for the check.
+pub const FLAG: &str = "--check-config";
+const JSON_FLAG: &str = "--json";
+
+/// Whether the process was started with [`FLAG`].
+pub fn requested(args: &[String]) -> bool {
+    args.iter().any(|arg| arg == FLAG)
+}
+
+/// The arguments besides the flags, e.g. the containers the worker should check.
+pub fn operands(args: &[String]) -> Vec<String> {
+    args.iter().filter(|arg| *arg != FLAG && *arg != JSON_FLAG).cloned().collect()
+}
+
+#[derive(Serialize, Debug)]
+struct Entry {
+    name: String,
+    #[serde(flatten)]
+    check: Check,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ConfigReport {
+    passed: bool,
+    checks: Vec<Entry>,
+}
+
+/// The message a panic was raised with.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "Panicked".to_string())
+}
+
+impl ConfigReport {
+    /// An empty report. Settings report a bad value by panicking, so the default panic output is
+    /// silenced from here on; the report carries the message instead.
+    pub fn new() -> Self {
+        panic::set_hook(Box::new(|_| {}));
+        ConfigReport {
+            passed: true,
+            checks: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, name: impl Into<String>, check: Check) {
+        self.passed &= check.ok;
+        self.checks.push(Entry { name: name.into(), check });
+    }
+
+    /// Checks a setting by reading it with `read`, which panics on a bad value like the reads made
+    /// at startup do. Returns whether it passed.
+    pub fn setting(&mut self, name: impl Into<String>, read: impl FnOnce()) -> bool {
+        let started = Instant::now();
+        let error = panic::catch_unwind(AssertUnwindSafe(read)).err().map(|payload| panic_message(&*payload));
+        let ok = error.is_none();
+        self.add(
+            name,
+            Check {
+                ok,
+                error,
+                duration_ms: started.elapsed().as_millis() as u64,
+            },
+        );
+        ok
+    }
+
+    /// Checks a service answers `call`, within the time the readiness probes allow.
+    pub async fn service<T, E: Display>(&mut self, name: impl Into<String>, call: impl Future<Output = Result<T, E>>) {
+        let check = health::check(call).await;
+        self.add(name, check);
+    }
+
+    pub fn passed(&self) -> bool {
+        self.passed
+    }
+
+    /// Prints the report, as JSON if `args` ask for it.
+    pub fn print(&self, args: &[String]) {
+        if args.iter().any(|arg| arg == JSON_FLAG) {
+            println!("{}", serde_json::to_string_pretty(self).expect("Failed to serialize config report"));
+            return;
+        }
+        for entry in &self.checks {
+            let status = if entry.check.ok { "ok  " } else { "FAIL" };
+            match &entry.check.error {
+                Some(error) => println!("{} {}: {}", status, entry.name, error),
+                None => println!("{} {} ({} ms)", status, entry.name, entry.check.duration_ms),
+            }
+        }
+        let failed = self.checks.iter().filter(|entry| !entry.check.ok).count();
+        println!("{} checks, {} failed", self.checks.len(), failed);
+    }
+
+    /// Prints the report and exits, with status 1 if a check failed.
+    pub fn exit(self, args: &[String]) -> ! {
+        self.print(args);
+        std::process::exit(if self.passed { 0 } else { 1 })
+    }
+}
+
+impl Default for ConfigReport {
+    fn default() -> Self {
+        ConfigReport::new()
+    }
+}
+
+/// Checks the settings of `config.rs` and the storage accounts and queues they name. Returns false
+/// if the settings can't be read, in which case nothing else is checked.
+pub async fn check_shared(report: &mut ConfigReport) -> bool {
+    if !report.setting("settings", || {
+        config::get();
+    }) {
+        return false;
+    }
+    report.setting("storage backend", || {
+        storage::kind();
+    });
+    report.setting("encryption keys", || {
+        customer_keys::keys();
+    });
+
+    for location in [Location::Primary, Location::Secondary] {
+        let Some(account) = failover::account(location) else {
+            continue;
+        };
+        let blob_client = clients::blob_service_client(location);
+        report.service(format!("storage account {}", account), blob_client.get_account_information().into_future()).await;
+    }
+
+    let service_bus = &config::get().service_bus;
+    let formats = std::iter::once(None).chain(service_bus.format_queues.iter().map(|(format, _)| Some(*format)));
+    for format in formats {
+        let sender = QueueSender::for_format(format);
+        report.service(format!("queue {}", sender.queue_name()), sender.probe()).await;
+    }
+    true
+}
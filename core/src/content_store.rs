@@ -0,0 +1,160 @@
+// core/src/content_store.rs
+
+//! Which renditions hold each distinct content, by its `pipeline::content_hash`, so the worker
+//! can copy a rendition identical to one already stored server-side instead of uploading the bytes
+//! again. Kept in the table named by `CONTENT_TABLE` (default `contenthashes`), one entity per
+//! hash, partitioned by container, with a reference count; the entity goes once nothing holds that
+//! content any more, so a blob is only ever offered as a copy source while it still has it. The API
+//! tracks originals the same way, under [`originals_partition`], to spot duplicate uploads.
+
+use azure_core::{
+    error::{Error, ErrorKind},
+    StatusCode,
+};
+use azure_data_tables::{
+    prelude::{EntityClient, TableClient},
+    IfMatchCondition,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::tables;
+
+const DEFAULT_TABLE: &str = "contenthashes";
+/// Tries at a read-modify-write before giving up on concurrent writers.
+const MAX_ATTEMPTS: usize = 5;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ContentEntry {
+    #[serde(rename = "PartitionKey")]
+    pub container: String,
+    #[serde(rename = "RowKey")]
+    pub hash: String,
+    /// Blob names holding the content as a JSON array, tables having no array type.
+    pub blobs: String,
+    pub ref_count: i64,
+}
+
+impl ContentEntry {
+    pub fn blobs(&self) -> Vec<String> {
+        serde_json::from_str(&self.blobs).unwrap_or_default()
+    }
+
+    fn set_blobs(&mut self, blobs: &[String]) {
+        self.blobs = serde_json::to_string(blobs).expect("Failed to serialize blob names");
+        self.ref_count = blobs.len() as i64;
+    }
+}
+
+fn table_client() -> TableClient {
+    tables::table_client("CONTENT_TABLE", DEFAULT_TABLE)
+}
+
+fn entity_client(table_client: &TableClient, container: &str, hash: &str) -> EntityClient {
+    table_client.partition_key_client(container).entity_client(hash)
+}
+
+/// Whether a conditional write lost to another writer.
+fn is_conflict(e: &Error) -> bool {
+    e.as_http_error()
+        .is_some_and(|e| e.status() == StatusCode::PreconditionFailed || e.status() == StatusCode::Conflict)
+}
+
+fn contended(hash: &str) -> Error {
+    Error::with_message(ErrorKind::Other, || format!("Too many concurrent updates of content {}", hash))
+}
+
+/// Partition of originals, tracked apart from renditions for duplicate upload detection; `:` can't
+/// occur in container names, so it never collides with a container's renditions.
+pub fn originals_partition(container: &str) -> String {
+    format!("{}:originals", container)
+}
+
+/// The blobs recorded as holding the content hashing to `hash`, without changing anything.
+pub async fn holders(container: &str, hash: &str) -> azure_core::Result<Vec<String>> {
+    let table_client = table_client();
+    match entity_client(&table_client, container, hash).get::<ContentEntry>().await {
+        Ok(response) => Ok(response.entity.blobs()),
+        Err(e) if tables::is_not_found(&e) => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Records that `blob` holds the content hashing to `hash`, and returns another blob already
+/// holding it to copy from, if there is one.
+pub async fn add_reference(container: &str, hash: &str, blob: &str) -> azure_core::Result<Option<String>> {
+    let table_client = table_client();
+    tables::create_if_missing(&table_client).await?;
+    let entity_client = entity_client(&table_client, container, hash);
+
+    for _ in 0..MAX_ATTEMPTS {
+        match entity_client.get::<ContentEntry>().await {
+            Ok(response) => {
+                let mut entry = response.entity;
+                let mut blobs = entry.blobs();
+                let source = blobs.iter().find(|b| *b != blob).cloned();
+                if !blobs.iter().any(|b| b == blob) {
+                    blobs.push(blob.to_string());
+                    entry.set_blobs(&blobs);
+                    match entity_client.update(&entry, IfMatchCondition::Etag(response.etag))?.await {
+                        Ok(_) => {}
+                        Err(e) if is_conflict(&e) => continue,
+                        Err(e) => return Err(e),
+                    }
+                }
+                return Ok(source);
+            }
+            Err(e) if tables::is_not_found(&e) => {
+                let mut entry = ContentEntry {
+                    container: container.to_string(),
+                    hash: hash.to_string(),
+                    ..Default::default()
+                };
+                entry.set_blobs(&[blob.to_string()]);
+                match table_client.insert::<_, ContentEntry>(&entry)?.await {
+                    Ok(_) => return Ok(None),
+                    Err(e) if is_conflict(&e) => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(contended(hash))
+}
+
+/// Records that `blob` no longer holds the content hashing to `hash`, and returns how many blobs
+/// still do.
+pub async fn release(container: &str, hash: &str, blob: &str) -> azure_core::Result<usize> {
+    let table_client = table_client();
+    let entity_client = entity_client(&table_client, container, hash);
+
+    for _ in 0..MAX_ATTEMPTS {
+        let response = match entity_client.get::<ContentEntry>().await {
+            Ok(response) => response,
+            Err(e) if tables::is_not_found(&e) => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        let mut entry = response.entity;
+        let mut blobs = entry.blobs();
+        let before = blobs.len();
+        blobs.retain(|b| b != blob);
+        if blobs.len() == before {
+            return Ok(blobs.len());
+        }
+        let result = if blobs.is_empty() {
+            entity_client.delete().if_match(IfMatchCondition::Etag(response.etag)).await.map(|_| ())
+        } else {
+            entry.set_blobs(&blobs);
+            entity_client
+                .update(&entry, IfMatchCondition::Etag(response.etag))?
+                .await
+                .map(|_| ())
+        };
+        match result {
+            Ok(()) => return Ok(blobs.len()),
+            Err(e) if is_conflict(&e) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(contended(hash))
+}
@@ -0,0 +1,111 @@
+// core/src/cpu_features.rs
+
+//! The CPU features the resize and encode code could use, detected once at startup. The
+//! implementation picked is the best one this build has code for that the CPU supports: AVX2 on
+//! x86-64, NEON on ARM, and the portable scalar code otherwise. Every build has only the scalar
+//! code for now, since the JPEG encoders are built without SIMD so an encode doesn't depend on the
+//! CPU it runs on, see `functions/Cargo.toml`; a SIMD backend joins [`BACKENDS`] once it's added.
+//!
+//! Both processes log the architecture, the features found and the implementation picked, and
+//! `build_info.rs` reports them under `cpu`, on the API's `/version` among others.
+
+use serde::Serialize;
+use std::{env, fmt, sync::OnceLock};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Implementation {
+    Scalar,
+    Avx2,
+    Neon,
+}
+
+impl Implementation {
+    /// The CPU feature the implementation needs, `None` for the scalar code that runs anywhere.
+    fn feature(self) -> Option<&'static str> {
+        match self {
+            Implementation::Scalar => None,
+            Implementation::Avx2 => Some("avx2"),
+            Implementation::Neon => Some("neon"),
+        }
+    }
+}
+
+impl fmt::Display for Implementation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Implementation::Scalar => "scalar",
+            Implementation::Avx2 => "avx2",
+            Implementation::Neon => "neon",
+        })
+    }
+}
+
+/// The implementations this build has code for, best first; the scalar one is always last.
+const BACKENDS: &[Implementation] = &[Implementation::Scalar];
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CpuInfo {
+    /// Target architecture of the binary, e.g. `x86_64` or `aarch64`.
+    pub arch: &'static str,
+    /// Features found among those an implementation could use.
+    pub features: Vec<&'static str>,
+    pub implementation: Implementation,
+}
+
+impl fmt::Display for CpuInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} implementation on {}", self.implementation, self.arch)?;
+        if !self.features.is_empty() {
+            write!(f, " with {}", self.features.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn detect() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if is_x86_feature_detected!("sse4.1") {
+        features.push("sse4.1");
+    }
+    if is_x86_feature_detected!("avx2") {
+        features.push("avx2");
+    }
+    if is_x86_feature_detected!("avx512f") {
+        features.push("avx512f");
+    }
+    features
+}
+
+#[cfg(target_arch = "aarch64")]
+fn detect() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if std::arch::is_aarch64_feature_detected!("neon") {
+        features.push("neon");
+    }
+    features
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+fn detect() -> Vec<&'static str> {
+    Vec::new()
+}
+
+/// The features of the CPU this process runs on and the implementation picked for them.
+pub fn get() -> &'static CpuInfo {
+    static CPU: OnceLock<CpuInfo> = OnceLock::new();
+    CPU.get_or_init(|| {
+        let features = detect();
+        let implementation = BACKENDS
+            .iter()
+            .copied()
+            .find(|implementation| implementation.feature().is_none_or(|feature| features.contains(&feature)))
+            .unwrap_or(Implementation::Scalar);
+        CpuInfo {
+            arch: env::consts::ARCH,
+            features,
+            implementation,
+        }
+    })
+}
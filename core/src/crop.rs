@@ -0,0 +1,96 @@
+// core/src/crop.rs
+
+//! A region of interest picked by the user, e.g. in a frontend cropper, that the worker cuts out
+//! of the source before resizing it. Coordinates are pixels of the source, or fractions of its
+//! width and height when `normalized`. A [`FocalPoint`] instead keeps the whole source but says
+//! where renditions that have to cut something off should stay centered.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Crop {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+    #[serde(default)]
+    pub normalized: bool,
+}
+
+impl Crop {
+    /// Parses `x,y,w,h`.
+    pub fn parse(value: &str, normalized: bool) -> Result<Self, String> {
+        let invalid = || format!("Invalid crop '{}', use x,y,width,height", value);
+        let numbers = value
+            .split(',')
+            .map(|n| n.trim().parse::<f64>().map_err(|_| invalid()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let [x, y, width, height] = numbers[..] else {
+            return Err(invalid());
+        };
+        let crop = Crop {
+            x,
+            y,
+            width,
+            height,
+            normalized,
+        };
+        crop.validate()?;
+        Ok(crop)
+    }
+
+    /// Checks the rectangle isn't empty and, when normalized, lies within the unit square.
+    pub fn validate(&self) -> Result<(), String> {
+        let finite = [self.x, self.y, self.width, self.height].iter().all(|n| n.is_finite());
+        if !finite || self.x < 0.0 || self.y < 0.0 || self.width <= 0.0 || self.height <= 0.0 {
+            return Err("Crop coordinates must be non-negative, with a positive width and height".to_string());
+        }
+        if self.normalized && (self.x + self.width > 1.0 || self.y + self.height > 1.0) {
+            return Err("Normalized crops must lie within 0 and 1".to_string());
+        }
+        Ok(())
+    }
+
+    /// The rectangle in pixels of a `width` x `height` source as `(x, y, width, height)`, clipped to
+    /// the source, or `None` if nothing of it is left.
+    pub fn pixels(&self, width: u32, height: u32) -> Option<(u32, u32, u32, u32)> {
+        let (scale_x, scale_y) = if self.normalized {
+            (width as f64, height as f64)
+        } else {
+            (1.0, 1.0)
+        };
+        let left = (self.x * scale_x).round().min(width as f64) as u32;
+        let top = (self.y * scale_y).round().min(height as f64) as u32;
+        let right = ((self.x + self.width) * scale_x).round().min(width as f64) as u32;
+        let bottom = ((self.y + self.height) * scale_y).round().min(height as f64) as u32;
+        (right > left && bottom > top).then(|| (left, top, right - left, bottom - top))
+    }
+}
+
+/// The point of an image that matters most, as percentages of its width and height from the top
+/// left. Renditions that fill their box and so lose part of the image are centered on it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct FocalPoint {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl FocalPoint {
+    /// Parses `x,y`, both between 0 and 100.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid focal point '{}', use x,y as percentages", value);
+        let (x, y) = value.split_once(',').ok_or_else(invalid)?;
+        let x: f32 = x.trim().parse().map_err(|_| invalid())?;
+        let y: f32 = y.trim().parse().map_err(|_| invalid())?;
+        if !(0.0..=100.0).contains(&x) || !(0.0..=100.0).contains(&y) {
+            return Err(invalid());
+        }
+        Ok(FocalPoint { x, y })
+    }
+}
+
+impl std::fmt::Display for FocalPoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{},{}", self.x, self.y)
+    }
+}
@@ -0,0 +1,52 @@
+// core/src/customer_keys.rs
+
+//! Customer-provided encryption keys (CPK) for the originals of tenants whose data at rest must be
+//! encrypted with their own key. `TENANT_ENCRYPTION_KEYS_FILE` names a JSON file of
+//! `{"<tenant>": "<base64 AES-256 key>"}`; a tenant it names has its originals written with its key,
+//! and the worker reads them with it. Storage keeps only the key's SHA-256, so a blob can't be read,
+//! nor its properties or metadata, without the key, and losing it loses the blob.
+//!
+//! Only whole-blob writes and reads take a key in the storage SDK, so these tenants' uploads are
+//! buffered (`MAX_PART_BYTES`) rather than staged as blocks, their tus uploads, which can only be
+//! staged, are refused, and renditions, which the worker stages and then copies, are encrypted with
+//! the account's own keys. The file is read once, at startup; changing a tenant's key means
+//! rewriting its originals.
+
+use azure_storage_blobs::prelude::CPKInfo;
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, env, fs, sync::OnceLock};
+use tracing::info;
+
+static KEYS: OnceLock<HashMap<String, CPKInfo>> = OnceLock::new();
+
+fn load() -> HashMap<String, CPKInfo> {
+    let Ok(path) = env::var("TENANT_ENCRYPTION_KEYS_FILE") else {
+        return HashMap::new();
+    };
+    let bytes = fs::read(&path).unwrap_or_else(|e| panic!("Failed to read TENANT_ENCRYPTION_KEYS_FILE {}: {}", path, e));
+    let keys: HashMap<String, String> =
+        serde_json::from_slice(&bytes).unwrap_or_else(|e| panic!("Invalid TENANT_ENCRYPTION_KEYS_FILE {}: {}", path, e));
+    info!("Loaded encryption keys of {} tenants from {}", keys.len(), path);
+    keys.into_iter()
+        .map(|(tenant, key)| {
+            let raw = azure_core::base64::decode(&key)
+                .unwrap_or_else(|e| panic!("Encryption key of tenant {} isn't base64: {}", tenant, e));
+            if raw.len() != 32 {
+                panic!("Encryption key of tenant {} is {} bytes, AES-256 needs 32", tenant, raw.len());
+            }
+            let key_sha256 = azure_core::base64::encode(Sha256::digest(&raw));
+            (tenant, CPKInfo::new(key, key_sha256, None))
+        })
+        .collect()
+}
+
+/// The keys by tenant, read on the first call, which the API and the worker make at startup so a
+/// missing or invalid file stops them there.
+pub fn keys() -> &'static HashMap<String, CPKInfo> {
+    KEYS.get_or_init(load)
+}
+
+/// The key `tenant`'s originals are encrypted with, `None` for tenants using the account's keys.
+pub fn customer_key(tenant: Option<&str>) -> Option<CPKInfo> {
+    keys().get(tenant?).cloned()
+}
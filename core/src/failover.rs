@@ -0,0 +1,254 @@
+// core/src/failover.rs
+
+//! Write failover to a secondary storage account, `AZURE_STORAGE_FAILOVER_ACCOUNT` with the key in
+//! `AZURE_STORAGE_FAILOVER_ACCESS_KEY` unless authenticating with an identity. Writes go to the primary account until
+//! `STORAGE_FAILOVER_THRESHOLD` (default 5) of them fail in a row, which opens the circuit: for
+//! the next `STORAGE_FAILOVER_COOLDOWN_SECS` (default 60) writes go to the secondary, after which
+//! the primary is tried again and the circuit closes on its first success.
+//!
+//! A blob written to the secondary is recorded in the table named by `BLOB_LOCATION_TABLE`
+//! (default `bloblocations`), kept in the secondary account since the primary may be down, and its
+//! queue message carries the location, so both the API and the worker find it there.
+
+use azure_storage::StorageCredentials;
+use azure_data_tables::prelude::TableClient;
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    future::Future,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+use crate::{
+    account_keys,
+    chaos::{self, Target},
+    config,
+    identity::{self, AuthMode},
+    pipeline, storage, tables, telemetry,
+};
+
+const DEFAULT_THRESHOLD: u32 = 5;
+const DEFAULT_COOLDOWN_SECS: u64 = 60;
+const DEFAULT_TABLE: &str = "bloblocations";
+
+/// The storage account holding a blob.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Location {
+    #[default]
+    Primary,
+    Secondary,
+}
+
+impl Location {
+    pub fn is_primary(&self) -> bool {
+        *self == Location::Primary
+    }
+}
+
+/// The account name for `location`, `None` for an unconfigured secondary.
+pub fn account(location: Location) -> Option<String> {
+    match location {
+        Location::Primary => Some(config::get().storage_account.clone()),
+        Location::Secondary => config::get().failover_account.clone(),
+    }
+}
+
+/// The locations with an account: the primary, and the secondary when configured.
+pub fn locations() -> Vec<Location> {
+    let mut locations = vec![Location::Primary];
+    if account(Location::Secondary).is_some() {
+        locations.push(Location::Secondary);
+    }
+    locations
+}
+
+/// The account name and credentials for `location`; panics for an unconfigured secondary. With
+/// keys, they're looked up on every call, so clients built afterwards use a rotated key, see
+/// `account_keys.rs`; otherwise the credentials are the identity's, see `identity.rs`.
+pub fn credentials(location: Location) -> (String, StorageCredentials) {
+    let storage_account = account(location).expect("Missing AZURE_STORAGE_FAILOVER_ACCOUNT env var");
+    if config::get().storage_auth != AuthMode::Key {
+        return (storage_account, StorageCredentials::token_credential(identity::credential()));
+    }
+    let key_var = match location {
+        Location::Primary => "AZURE_STORAGE_ACCESS_KEY",
+        Location::Secondary => "AZURE_STORAGE_FAILOVER_ACCESS_KEY",
+    };
+    let storage_access_key = account_keys::access_key(&storage_account)
+        .unwrap_or_else(|| env::var(key_var).unwrap_or_else(|_| panic!("Missing {} env var", key_var)));
+    let storage_credentials = StorageCredentials::access_key(storage_account.clone(), storage_access_key);
+    (storage_account, storage_credentials)
+}
+
+fn enabled() -> bool {
+    config::get().failover_account.is_some()
+}
+
+fn threshold() -> u32 {
+    env::var("STORAGE_FAILOVER_THRESHOLD")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_THRESHOLD)
+        .max(1)
+}
+
+fn cooldown() -> Duration {
+    let secs = env::var("STORAGE_FAILOVER_COOLDOWN_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_COOLDOWN_SECS);
+    Duration::from_secs(secs)
+}
+
+/// Consecutive failed writes to the primary, and when they last opened the circuit.
+struct Circuit {
+    failures: AtomicU32,
+    opened_at: Mutex<Option<Instant>>,
+}
+
+static CIRCUIT: Circuit = Circuit {
+    failures: AtomicU32::new(0),
+    opened_at: Mutex::new(None),
+};
+
+/// Where the next write should go.
+pub fn write_location() -> Location {
+    if !enabled() {
+        return Location::Primary;
+    }
+    match *CIRCUIT.opened_at.lock().unwrap() {
+        Some(opened_at) if opened_at.elapsed() < cooldown() => Location::Secondary,
+        _ => Location::Primary,
+    }
+}
+
+/// Counts the outcome of a write to `location` towards opening or closing the circuit.
+pub fn record_write(location: Location, success: bool) {
+    if !location.is_primary() || !enabled() {
+        return;
+    }
+    if success {
+        CIRCUIT.failures.store(0, Ordering::Relaxed);
+        if CIRCUIT.opened_at.lock().unwrap().take().is_some() {
+            info!("Primary storage account is accepting writes again");
+            telemetry::track_event("StorageFailback", &[]);
+        }
+        return;
+    }
+    let failures = CIRCUIT.failures.fetch_add(1, Ordering::Relaxed) + 1;
+    if failures >= threshold() {
+        let mut opened_at = CIRCUIT.opened_at.lock().unwrap();
+        if opened_at.is_none_or(|at| at.elapsed() >= cooldown()) {
+            warn!("{} writes to the primary storage account failed in a row, failing over to the secondary", failures);
+            telemetry::track_event("StorageFailover", &[("failures", failures.to_string())]);
+            *opened_at = Some(Instant::now());
+        }
+    }
+}
+
+/// Runs `write` against the account the circuit points at, and again against the secondary when
+/// that failure opened the circuit. Returns where the write landed.
+pub async fn write<T, F, Fut>(mut write: F) -> azure_core::Result<(T, Location)>
+where
+    F: FnMut(Location) -> Fut,
+    Fut: Future<Output = azure_core::Result<T>>,
+{
+    let location = write_location();
+    // injected faults only hit the primary, so there's somewhere to fail over to
+    let injected = match location {
+        Location::Primary => chaos::inject(Target::Storage, "write").await,
+        Location::Secondary => Ok(()),
+    };
+    let attempt = match injected {
+        Ok(()) => write(location).await,
+        Err(e) => Err(e),
+    };
+    match attempt {
+        Ok(value) => {
+            record_write(location, true);
+            Ok((value, location))
+        }
+        // a condition that doesn't hold says nothing of the account's health
+        Err(e) if storage::is_taken(&e) => {
+            record_write(location, true);
+            Err(e)
+        }
+        Err(e) => {
+            record_write(location, false);
+            if !location.is_primary() || write_location().is_primary() {
+                return Err(e);
+            }
+            warn!("Write to the primary storage account failed, retrying on the secondary: {:?}", e);
+            let value = write(Location::Secondary).await?;
+            Ok((value, Location::Secondary))
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct LocationEntry {
+    #[serde(rename = "PartitionKey")]
+    container: String,
+    /// Hash of the blob name, since names may hold characters row keys can't.
+    #[serde(rename = "RowKey")]
+    key: String,
+    blob: String,
+    location: Location,
+}
+
+fn table_client() -> Option<TableClient> {
+    account(Location::Secondary)?;
+    Some(tables::table_client_in(Location::Secondary, "BLOB_LOCATION_TABLE", DEFAULT_TABLE))
+}
+
+fn row_key(blob: &str) -> String {
+    pipeline::content_hash(blob.as_bytes())
+}
+
+/// Records that `blob` in `container` was last written to `location`. Does nothing unless a
+/// secondary account is configured.
+pub async fn record(container: &str, blob: &str, location: Location) -> azure_core::Result<()> {
+    let Some(table_client) = table_client() else {
+        return Ok(());
+    };
+    let entity_client = table_client.partition_key_client(container).entity_client(row_key(blob));
+    if location.is_primary() {
+        return match entity_client.delete().await {
+            Ok(_) => Ok(()),
+            Err(e) if tables::is_not_found(&e) => Ok(()),
+            Err(e) => Err(e),
+        };
+    }
+    tables::create_if_missing(&table_client).await?;
+    let entry = LocationEntry {
+        container: container.to_string(),
+        key: row_key(blob),
+        blob: blob.to_string(),
+        location,
+    };
+    entity_client.insert_or_replace(&entry)?.await?;
+    Ok(())
+}
+
+/// Where `blob` in `container` was last written; the primary unless recorded otherwise, or when
+/// the record can't be read.
+pub async fn location_of(container: &str, blob: &str) -> Location {
+    let Some(table_client) = table_client() else {
+        return Location::Primary;
+    };
+    let entity_client = table_client.partition_key_client(container).entity_client(row_key(blob));
+    match entity_client.get::<LocationEntry>().await {
+        Ok(response) => response.entity.location,
+        Err(e) if tables::is_not_found(&e) => Location::Primary,
+        Err(e) => {
+            warn!("Failed to look up the location of {}/{}: {:?}", container, blob, e);
+            Location::Primary
+        }
+    }
+}
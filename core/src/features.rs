@@ -0,0 +1,271 @@
+// core/src/features.rs
+
+//! Runtime switches for expensive pipeline stages, so they can be turned on or off per environment
+//! or per tenant without a redeploy.
+//!
+//! Flags are layered, later sources winning:
+//! 1. the built-in [`DEFAULTS`];
+//! 2. `FEATURE_FLAGS`, e.g. `avif=on,moderation=off`;
+//! 3. the JSON file named by `FEATURE_FLAGS_FILE`: `{"flags": {..}, "tenants": {"acme": {..}}}`;
+//! 4. Azure App Configuration feature flags when `APP_CONFIG_CONNECTION_STRING` is set, where an
+//!    unlabelled flag applies everywhere and a flag labelled with a tenant id only to that tenant.
+//!
+//! Sources are re-read every `FEATURE_FLAGS_REFRESH_SECS` (default 30).
+
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
+};
+use time::OffsetDateTime;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::telemetry::parse_connection_string;
+
+pub const ANALYSIS: &str = "analysis";
+pub const AUTO_ENHANCE: &str = "auto_enhance";
+pub const RENDER: &str = "render";
+pub const AVIF: &str = "avif";
+pub const MODERATION: &str = "moderation";
+pub const FACE_DETECTION: &str = "face_detection";
+pub const IMAGE_INDEX: &str = "image_index";
+pub const QUALITY_CHECK: &str = "quality_check";
+pub const OUTPUT_DEDUP: &str = "output_dedup";
+pub const VIDEO_POSTER: &str = "video_poster";
+pub const PDF_PAGES: &str = "pdf_pages";
+pub const NORMALIZE: &str = "normalize";
+
+/// Flags not set by any source fall back to these; unknown flags are off.
+pub const DEFAULTS: &[(&str, bool)] = &[
+    (ANALYSIS, true),
+    (AUTO_ENHANCE, true),
+    (RENDER, true),
+    (AVIF, false),
+    (MODERATION, false),
+    (FACE_DETECTION, false),
+    (IMAGE_INDEX, true),
+    (QUALITY_CHECK, false),
+    (OUTPUT_DEDUP, false),
+    (VIDEO_POSTER, false),
+    (PDF_PAGES, false),
+    (NORMALIZE, false),
+];
+
+const DEFAULT_REFRESH_SECS: u64 = 30;
+const APP_CONFIG_API_VERSION: &str = "1.0";
+const APP_CONFIG_FLAG_PREFIX: &str = ".appconfig.featureflag/";
+
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct FeatureFlags {
+    #[serde(default)]
+    flags: HashMap<String, bool>,
+    #[serde(default)]
+    tenants: HashMap<String, HashMap<String, bool>>,
+}
+
+impl FeatureFlags {
+    /// Tenant override first, then the environment-wide value, then the default.
+    pub fn is_enabled(&self, flag: &str, tenant: Option<&str>) -> bool {
+        tenant
+            .and_then(|tenant| self.tenants.get(tenant))
+            .and_then(|flags| flags.get(flag))
+            .or_else(|| self.flags.get(flag))
+            .copied()
+            .unwrap_or_else(|| DEFAULTS.iter().any(|(name, on)| *name == flag && *on))
+    }
+
+    fn merge(&mut self, other: FeatureFlags) {
+        self.flags.extend(other.flags);
+        for (tenant, flags) in other.tenants {
+            self.tenants.entry(tenant).or_default().extend(flags);
+        }
+    }
+}
+
+/// The last loaded flags and when they were loaded.
+type Cached = Option<(Instant, Arc<FeatureFlags>)>;
+
+/// The current flags, reloading them when the refresh interval has passed.
+pub async fn current() -> Arc<FeatureFlags> {
+    static CACHE: OnceLock<RwLock<Cached>> = OnceLock::new();
+    let cache = CACHE.get_or_init(|| RwLock::new(None));
+    let refresh = Duration::from_secs(
+        env::var("FEATURE_FLAGS_REFRESH_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_REFRESH_SECS),
+    );
+
+    if let Some((loaded_at, flags)) = cache.read().await.as_ref() {
+        if loaded_at.elapsed() < refresh {
+            return flags.clone();
+        }
+    }
+
+    let mut cached = cache.write().await;
+    // another caller may have reloaded while we waited for the lock
+    if let Some((loaded_at, flags)) = cached.as_ref() {
+        if loaded_at.elapsed() < refresh {
+            return flags.clone();
+        }
+    }
+    let flags = Arc::new(load().await);
+    *cached = Some((Instant::now(), flags.clone()));
+    flags
+}
+
+/// Shorthand for `current().await.is_enabled(flag, tenant)`.
+pub async fn is_enabled(flag: &str, tenant: Option<&str>) -> bool {
+    current().await.is_enabled(flag, tenant)
+}
+
+async fn load() -> FeatureFlags {
+    let mut flags = FeatureFlags::default();
+
+    if let Ok(list) = env::var("FEATURE_FLAGS") {
+        flags.merge(FeatureFlags {
+            flags: parse_flag_list(&list),
+            tenants: HashMap::new(),
+        });
+    }
+
+    if let Ok(path) = env::var("FEATURE_FLAGS_FILE") {
+        match tokio::fs::read(&path).await.map(|bytes| serde_json::from_slice::<FeatureFlags>(&bytes)) {
+            Ok(Ok(file_flags)) => flags.merge(file_flags),
+            Ok(Err(e)) => warn!("Ignoring invalid feature flag file {}: {:?}", path, e),
+            Err(e) => warn!("Failed to read feature flag file {}: {:?}", path, e),
+        }
+    }
+
+    if let Ok(connection_string) = env::var("APP_CONFIG_CONNECTION_STRING") {
+        match load_app_configuration(&connection_string).await {
+            Ok(remote) => flags.merge(remote),
+            // keep going on the local sources rather than failing every request
+            Err(e) => warn!("Failed to load feature flags from App Configuration: {}", e),
+        }
+    }
+
+    info!("Loaded feature flags: {:?}", flags);
+    flags
+}
+
+/// Parses `name=on,other=off`; a bare name means on.
+fn parse_flag_list(list: &str) -> HashMap<String, bool> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| match entry.split_once('=') {
+            Some((name, value)) => (
+                name.trim().to_string(),
+                matches!(value.trim().to_ascii_lowercase().as_str(), "on" | "true" | "1"),
+            ),
+            None => (entry.to_string(), true),
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+struct KeyValuePage {
+    items: Vec<KeyValue>,
+    #[serde(rename = "@nextLink")]
+    next_link: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct KeyValue {
+    key: String,
+    label: Option<String>,
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct FeatureFlagValue {
+    enabled: bool,
+}
+
+async fn load_app_configuration(connection_string: &str) -> Result<FeatureFlags, String> {
+    let settings = parse_connection_string(connection_string);
+    let endpoint = settings.get("endpoint").ok_or("missing Endpoint")?.trim_end_matches('/').to_string();
+    let id = settings.get("id").ok_or("missing Id")?;
+    let secret = azure_core::base64::decode(settings.get("secret").ok_or("missing Secret")?)
+        .map_err(|e| format!("invalid Secret: {}", e))?;
+
+    let client = reqwest::Client::new();
+    let mut flags = FeatureFlags::default();
+    let mut path = format!(
+        "/kv?key={}*&api-version={}",
+        APP_CONFIG_FLAG_PREFIX.replace('/', "%2F"),
+        APP_CONFIG_API_VERSION
+    );
+
+    loop {
+        let url = format!("{}{}", endpoint, path);
+        let host = reqwest::Url::parse(&url).map_err(|e| e.to_string())?.host_str().unwrap_or_default().to_string();
+        let page: KeyValuePage = client
+            .get(&url)
+            .headers(app_configuration_auth(&host, &path, id, &secret)?)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        for item in page.items {
+            let Some(name) = item.key.strip_prefix(APP_CONFIG_FLAG_PREFIX) else {
+                continue;
+            };
+            let Ok(value) = serde_json::from_str::<FeatureFlagValue>(&item.value) else {
+                warn!("Ignoring malformed feature flag {}", item.key);
+                continue;
+            };
+            match item.label.filter(|label| !label.is_empty()) {
+                Some(tenant) => flags.tenants.entry(tenant).or_default().insert(name.to_string(), value.enabled),
+                None => flags.flags.insert(name.to_string(), value.enabled),
+            };
+        }
+
+        match page.next_link {
+            Some(next) => path = next,
+            None => return Ok(flags),
+        }
+    }
+}
+
+/// App Configuration's HMAC-SHA256 access key scheme.
+fn app_configuration_auth(
+    host: &str,
+    path_and_query: &str,
+    id: &str,
+    secret: &[u8],
+) -> Result<reqwest::header::HeaderMap, String> {
+    let date = azure_core::date::to_rfc1123(&OffsetDateTime::now_utc());
+    let content_hash = azure_core::base64::encode(Sha256::digest(b""));
+    let string_to_sign = format!("GET\n{}\n{};{};{}", path_and_query, date, host, content_hash);
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).map_err(|e| e.to_string())?;
+    mac.update(string_to_sign.as_bytes());
+    let signature = azure_core::base64::encode(mac.finalize().into_bytes());
+
+    let mut headers = reqwest::header::HeaderMap::new();
+    let mut insert = |name: &'static str, value: String| -> Result<(), String> {
+        headers.insert(name, value.parse().map_err(|_| format!("invalid {} header", name))?);
+        Ok(())
+    };
+    insert("x-ms-date", date)?;
+    insert("x-ms-content-sha256", content_hash)?;
+    insert(
+        "authorization",
+        format!(
+            "HMAC-SHA256 Credential={}&SignedHeaders=x-ms-date;host;x-ms-content-sha256&Signature={}",
+            id, signature
+        ),
+    )?;
+    Ok(headers)
+}
@@ -0,0 +1,95 @@
+// core/src/filenames.rs
+
+//! Names of originals as clients give them. Every way into the API (`/upload` parts, zip entries,
+//! tus uploads, S3 keys and copies) passes the name through [`normalize`] before it names a blob,
+//! and the `/images/{name}/...` routes read theirs with [`from_path`]. Names are kept in Unicode
+//! NFC, so `café.jpg` typed on macOS, which decomposes the accent, names the same blob as on other
+//! systems, and the renditions named after it stay readable. Otherwise names are stored as given,
+//! CJK, emoji and right-to-left scripts included; blob URLs percent-encode them, see
+//! `BlobClient::url`.
+
+use unicode_normalization::UnicodeNormalization;
+
+/// Longest blob name Azure allows, in characters.
+pub const MAX_LEN: usize = 1024;
+
+/// Characters overriding the direction text is shown in, which could make `photo\u{202E}gnp.exe`
+/// read as `photoexe.png`. Right-to-left names don't need them.
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+/// `name` in NFC, as stored names are.
+pub fn nfc(name: &str) -> String {
+    name.nfc().collect()
+}
+
+/// `filename` in NFC, or why it can't name a blob.
+///
+/// ```
+/// use image_resize_core::filenames::normalize;
+///
+/// // decomposed, as macOS writes them
+/// assert_eq!(normalize("cafe\u{301}.jpg").unwrap(), "caf\u{E9}.jpg");
+/// assert_eq!(normalize("\u{1112}\u{1161}\u{11AB}\u{AE00}.png").unwrap(), "한글.png");
+/// // already composed
+/// assert_eq!(normalize("東京タワー.jpg").unwrap(), "東京タワー.jpg");
+/// assert_eq!(normalize("👩‍👩‍👧 🎉.png").unwrap(), "👩‍👩‍👧 🎉.png");
+/// assert_eq!(normalize("صورة العائلة.webp").unwrap(), "صورة العائلة.webp");
+/// assert_eq!(normalize("albums/שלום.gif").unwrap(), "albums/שלום.gif");
+///
+/// assert!(normalize("").is_err());
+/// assert!(normalize("photo\u{202E}gnp.exe").is_err());
+/// assert!(normalize("line\nbreak.jpg").is_err());
+/// assert!(normalize("trailing.").is_err());
+/// ```
+pub fn normalize(filename: &str) -> Result<String, String> {
+    let name = nfc(filename);
+    if name.is_empty() {
+        return Err("The filename is empty".to_string());
+    }
+    if name.chars().count() > MAX_LEN {
+        return Err(format!("The filename is longer than {} characters", MAX_LEN));
+    }
+    if let Some(c) = name.chars().find(|c| c.is_control() || is_bidi_control(*c)) {
+        return Err(format!("'{}' contains the control character U+{:04X}", name.escape_debug(), c as u32));
+    }
+    if name.ends_with(['.', '/']) {
+        return Err(format!("'{}' may not end with '.' or '/'", name));
+    }
+    Ok(name)
+}
+
+/// Decodes `%XX` escapes, `None` when they don't make UTF-8.
+pub fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+/// The name a percent-encoded path segment asks for, in NFC like stored names; `None` when it
+/// doesn't decode.
+///
+/// ```
+/// use image_resize_core::filenames::from_path;
+///
+/// assert_eq!(from_path("%E5%86%99%E7%9C%9F.jpg").as_deref(), Some("写真.jpg"));
+/// assert_eq!(from_path("cafe%CC%81.jpg").as_deref(), Some("caf\u{E9}.jpg"));
+/// assert_eq!(from_path("albums%2F2024.png").as_deref(), Some("albums/2024.png"));
+/// assert_eq!(from_path("%F0%9F%8E%89"), Some("🎉".to_string()));
+/// assert_eq!(from_path("%FF.jpg"), None);
+/// ```
+pub fn from_path(segment: &str) -> Option<String> {
+    percent_decode(segment).map(|name| nfc(&name))
+}
@@ -0,0 +1,89 @@
+// core/src/geo_read.rs
+
+//! Read fallback for read-access geo-redundant (RA-GRS) storage accounts. With
+//! `AZURE_STORAGE_READ_FALLBACK` set to `1`/`true`, a download that fails on an account's primary
+//! endpoint, other than with a client error such as 404, is retried on its read-only secondary
+//! endpoint, `<account>-secondary.blob.core.windows.net`. The secondary lags the primary by
+//! replication, so a blob written moments ago may not be there yet. Every fallback is counted in
+//! the `StorageReadFallback` metric, with its outcome.
+
+use azure_core::StatusCode;
+use azure_storage::CloudLocation;
+use azure_storage_blobs::prelude::{BlobClient, ClientBuilder};
+use std::{env, future::Future, sync::OnceLock};
+use tracing::warn;
+
+use crate::{
+    azure,
+    chaos::{self, Target},
+    failover::{self, Location},
+    telemetry,
+};
+
+/// Whether the fallback was switched on through `AZURE_STORAGE_READ_FALLBACK`.
+pub fn enabled() -> bool {
+    static ENABLED: OnceLock<bool> = OnceLock::new();
+    *ENABLED.get_or_init(|| {
+        env::var("AZURE_STORAGE_READ_FALLBACK")
+            .map(|v| matches!(v.trim().to_ascii_lowercase().as_str(), "1" | "true" | "on"))
+            .unwrap_or(false)
+    })
+}
+
+/// Whether a failed read may succeed on the secondary: anything but a client error, which the
+/// secondary would answer the same way.
+fn worth_retrying(e: &azure_core::Error) -> bool {
+    match e.as_http_error().map(|e| e.status()) {
+        Some(StatusCode::RequestTimeout | StatusCode::TooManyRequests) | None => true,
+        Some(status) => !status.is_client_error(),
+    }
+}
+
+/// The same blob on its account's secondary endpoint, for the accounts this service is configured with.
+pub fn secondary_blob_client(blob_client: &BlobClient) -> Option<BlobClient> {
+    let container_client = blob_client.container_client();
+    let account = container_client.service_client().account().to_string();
+    let location = [Location::Primary, Location::Secondary]
+        .into_iter()
+        .find(|location| failover::account(*location).is_some_and(|storage_account| storage_account == account))?;
+    let (_, storage_credentials) = failover::credentials(location);
+    let uri = format!("https://{}-secondary.blob.core.windows.net", account);
+    let client = ClientBuilder::with_location(CloudLocation::Custom { account, uri }, storage_credentials)
+        .client_options(azure::client_options())
+        .blob_client(container_client.container_name(), blob_client.blob_name());
+    Some(client)
+}
+
+/// Runs `read` against `blob_client`, and once more against the secondary endpoint when that
+/// fails and the fallback is on. The primary's error is returned if both fail.
+pub async fn read<T, F, Fut>(blob_client: &BlobClient, read: F) -> azure_core::Result<T>
+where
+    F: Fn(BlobClient) -> Fut,
+    Fut: Future<Output = azure_core::Result<T>>,
+{
+    let primary = match chaos::inject(Target::Storage, "read").await {
+        Ok(()) => read(blob_client.clone()).await,
+        Err(e) => Err(e),
+    };
+    let e = match primary {
+        Ok(value) => return Ok(value),
+        Err(e) => e,
+    };
+    if !enabled() || !worth_retrying(&e) {
+        return Err(e);
+    }
+    let Some(secondary) = secondary_blob_client(blob_client) else {
+        return Err(e);
+    };
+
+    let container = blob_client.container_client().container_name().to_string();
+    warn!("Reading {}/{} from the secondary endpoint after: {:?}", container, blob_client.blob_name(), e);
+    let result = read(secondary).await;
+    let outcome = if result.is_ok() { "success" } else { "failure" };
+    telemetry::track_metric(
+        "StorageReadFallback",
+        1.0,
+        &[("container", container), ("outcome", outcome.to_string())],
+    );
+    result.or(Err(e))
+}
@@ -0,0 +1,74 @@
+// core/src/health.rs
+
+//! Readiness of a binary to take work, for `/readyz`: blob storage and the queue must both answer.
+//! Each is checked afresh on every call with a short timeout, so a probe reflects the moment it's
+//! made. Liveness (`/healthz`) needs no check beyond the process answering at all.
+
+use serde::Serialize;
+use std::{collections::BTreeMap, fmt::Display, future::Future, time::{Duration, Instant}};
+
+use crate::{clients, failover::Location, queue::QueueSender};
+
+/// Longest a single check may take before it counts as failed.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Debug)]
+pub struct Check {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub duration_ms: u64,
+}
+
+impl Check {
+    pub fn failed(error: impl Into<String>) -> Self {
+        Check {
+            ok: false,
+            error: Some(error.into()),
+            duration_ms: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+impl Readiness {
+    /// Adds a check, the whole being ready only while every check passes.
+    pub fn insert(&mut self, name: &'static str, check: Check) {
+        self.ready &= check.ok;
+        self.checks.insert(name, check);
+    }
+}
+
+/// Times `call`, failing it if it errs or takes longer than [`CHECK_TIMEOUT`].
+pub async fn check<T, E: Display>(call: impl Future<Output = Result<T, E>>) -> Check {
+    let started = Instant::now();
+    let error = match tokio::time::timeout(CHECK_TIMEOUT, call).await {
+        Ok(Ok(_)) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_) => Some(format!("No answer within {:?}", CHECK_TIMEOUT)),
+    };
+    Check {
+        ok: error.is_none(),
+        error,
+        duration_ms: started.elapsed().as_millis() as u64,
+    }
+}
+
+/// Checks the primary storage account and the default queue, see `FORMAT_QUEUES` in `config.rs`.
+pub async fn readiness() -> Readiness {
+    let blob_client = clients::blob_service_client(Location::Primary);
+    let sender = QueueSender::from_env();
+    let (blob, queue) = futures::future::join(check(blob_client.get_account_information().into_future()), check(sender.probe())).await;
+    let mut readiness = Readiness {
+        ready: true,
+        checks: BTreeMap::new(),
+    };
+    readiness.insert("blob", blob);
+    readiness.insert("queue", queue);
+    readiness
+}
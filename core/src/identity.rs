@@ -0,0 +1,97 @@
+// core/src/identity.rs
+
+//! How the API and the worker authenticate to Azure, picked by `AZURE_AUTH_MODE`:
+//!
+//! - `key`: the storage account keys (`AZURE_STORAGE_ACCESS_KEY`, see `account_keys.rs`) and the
+//!   Service Bus shared access policy in `AZURE_POLICY_NAME` and `AZURE_POLICY_KEY`.
+//! - `managed_identity`: `DefaultAzureCredential`, which tries AKS workload identity and a service
+//!   principal from the environment, then the managed identity, then the Azure CLI.
+//! - `service_principal`: the client secret of `AZURE_CLIENT_ID` in `AZURE_TENANT_ID`, from
+//!   `AZURE_CLIENT_SECRET`.
+//!
+//! Unset, storage uses keys when `AZURE_STORAGE_ACCESS_KEY` or `AZURE_STORAGE_KEYS_FILE` is set
+//! and Service Bus when the policy is, each falling back to `managed_identity` otherwise.
+//!
+//! An identity needs the Storage Blob Data Contributor and Storage Table Data Contributor roles on
+//! the accounts and Azure Service Bus Data Sender and Receiver on the namespace. Blob URLs handed
+//! to clients are then user delegation SASes rather than being signed with a key, see `sas.rs`.
+
+use azure_core::auth::TokenCredential;
+use azure_identity::{ClientSecretCredential, DefaultAzureCredentialBuilder, TokenCredentialOptions};
+use std::{
+    env,
+    str::FromStr,
+    sync::{Arc, OnceLock},
+};
+
+use crate::{azure, config};
+
+/// The scope of tokens for Service Bus.
+pub const SERVICE_BUS_SCOPE: &str = "https://servicebus.azure.net/.default";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthMode {
+    Key,
+    ManagedIdentity,
+    ServicePrincipal,
+}
+
+impl AuthMode {
+    /// `AZURE_AUTH_MODE`, `None` when unset.
+    pub fn from_env() -> Option<Self> {
+        let mode = env::var("AZURE_AUTH_MODE").ok().filter(|mode| !mode.trim().is_empty())?;
+        Some(mode.parse().unwrap_or_else(|e| panic!("Invalid AZURE_AUTH_MODE: {}", e)))
+    }
+}
+
+impl FromStr for AuthMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "key" => Ok(AuthMode::Key),
+            "managed_identity" => Ok(AuthMode::ManagedIdentity),
+            "service_principal" => Ok(AuthMode::ServicePrincipal),
+            _ => Err(format!("Unknown auth mode '{}', use key, managed_identity or service_principal", s)),
+        }
+    }
+}
+
+/// How storage is reached: `AZURE_AUTH_MODE`, or keys when any are configured.
+pub fn storage_mode(auth_mode: Option<AuthMode>) -> AuthMode {
+    auth_mode.unwrap_or_else(|| {
+        if env::var("AZURE_STORAGE_ACCESS_KEY").is_ok() || env::var("AZURE_STORAGE_KEYS_FILE").is_ok() {
+            AuthMode::Key
+        } else {
+            AuthMode::ManagedIdentity
+        }
+    })
+}
+
+/// The credential tokens are requested with, built on first use. Panics if the mode's
+/// variables are missing.
+pub fn credential() -> Arc<dyn TokenCredential> {
+    static CREDENTIAL: OnceLock<Arc<dyn TokenCredential>> = OnceLock::new();
+    CREDENTIAL
+        .get_or_init(|| {
+            let options = TokenCredentialOptions::from(azure::http_client());
+            match config::get().auth_mode {
+                Some(AuthMode::ServicePrincipal) => Arc::new(
+                    ClientSecretCredential::create(options).unwrap_or_else(|e| panic!("Invalid service principal: {}", e)),
+                ),
+                _ => Arc::new(
+                    DefaultAzureCredentialBuilder::new()
+                        .with_options(options)
+                        .build()
+                        .unwrap_or_else(|e| panic!("No Azure identity available: {}", e)),
+                ),
+            }
+        })
+        .clone()
+}
+
+/// An `authorization` header value carrying a token for `scope`.
+pub async fn bearer(scope: &str) -> azure_core::Result<String> {
+    let token = credential().get_token(&[scope]).await?;
+    Ok(format!("Bearer {}", token.token.secret()))
+}
@@ -0,0 +1,219 @@
+// core/src/image_checks.rs
+
+//! Checks on uploaded originals before they're stored, repeated by the worker before it decodes
+//! one. A file has to be an image in a format `capabilities.rs` lists, going by its magic bytes
+//! rather than its name or content type, or a video, PDF or SVG, which are sniffed the same way; SVGs only
+//! where `CONTENT_ROUTES` has them rasterized, see `routing.rs`. An image's
+//! dimensions are read from its header and held to `MAX_IMAGE_WIDTH`, `MAX_IMAGE_HEIGHT` and
+//! `MAX_IMAGE_PIXELS`, so a decompression bomb, a small file of enormous dimensions, is refused
+//! before anything allocates its pixels. The size an upload asks its rendition to be is held to
+//! the same limits, see [`check_output`].
+//!
+//! An empty file is refused, and so is an image cut short: one whose header ends early, and,
+//! checked by [`check_complete`] once the whole file is in memory, a JPEG, PNG, GIF or WebP whose
+//! end isn't reached, see `trailing_data.rs`. Either would otherwise be stored only to fail in the
+//! worker's decode.
+//!
+//! The EXIF of JPEG originals is cut down before they're stored to the fields the pipeline reads:
+//! the orientation, which the worker applies to renditions, and the capture time and camera
+//! indexed by the worker, see `image_index.rs`. GPS coordinates are dropped unless
+//! `IMAGE_INDEX_GPS` keeps them in the index. The XMP packet, which repeats EXIF, is dropped whole.
+
+use exif::{experimental::Writer, In, Reader, Tag};
+use image::{ImageError, ImageReader};
+use std::{env, fmt, io::Cursor};
+
+use crate::{capabilities, config, image_index, pdf, resize_spec::ResizeSpec, routing::Pipeline, svg, trailing_data, video::VideoFormat};
+
+const DEFAULT_MAX_DIMENSION: u32 = 16384;
+const DEFAULT_MAX_PIXELS: u64 = 100_000_000;
+
+const APP1: u8 = 0xE1;
+const SOS: u8 = 0xDA;
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+/// EXIF fields kept in stored originals.
+const KEPT_TAGS: &[Tag] = &[Tag::Orientation, Tag::DateTimeOriginal, Tag::DateTime, Tag::Make, Tag::Model];
+const GPS_TAGS: &[Tag] = &[Tag::GPSLatitude, Tag::GPSLatitudeRef, Tag::GPSLongitude, Tag::GPSLongitudeRef];
+
+/// Why an upload was refused.
+#[derive(Debug)]
+pub enum Invalid {
+    /// Not an image, video or PDF the pipeline reads.
+    Unsupported(String),
+    /// An image larger than the configured limits.
+    TooLarge(String),
+    /// A file without any content.
+    Empty(String),
+    /// An image whose data ends before its header or its last block does.
+    Truncated(String),
+}
+
+impl fmt::Display for Invalid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Invalid::Unsupported(message) | Invalid::TooLarge(message) | Invalid::Empty(message) | Invalid::Truncated(message) => {
+                f.write_str(message)
+            }
+        }
+    }
+}
+
+/// Largest image the pipeline accepts.
+#[derive(Debug, Clone, Copy)]
+pub struct ImageLimits {
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_pixels: u64,
+}
+
+impl ImageLimits {
+    pub fn from_env() -> Self {
+        let env_or = |key: &str, default: u64| env::var(key).ok().and_then(|v| v.parse().ok()).unwrap_or(default);
+        ImageLimits {
+            max_width: env_or("MAX_IMAGE_WIDTH", DEFAULT_MAX_DIMENSION as u64) as u32,
+            max_height: env_or("MAX_IMAGE_HEIGHT", DEFAULT_MAX_DIMENSION as u64) as u32,
+            max_pixels: env_or("MAX_IMAGE_PIXELS", DEFAULT_MAX_PIXELS),
+        }
+    }
+
+    /// Checks the dimensions of an image named `name`.
+    pub fn check(&self, name: &str, width: u32, height: u32) -> Result<(), String> {
+        if width > self.max_width || height > self.max_height {
+            return Err(format!(
+                "{} is {}x{}, at most {}x{} is accepted",
+                name, width, height, self.max_width, self.max_height
+            ));
+        }
+        let pixels = width as u64 * height as u64;
+        if pixels > self.max_pixels {
+            return Err(format!("{} has {} pixels, at most {} are accepted", name, pixels, self.max_pixels));
+        }
+        Ok(())
+    }
+}
+
+/// Checks the rendition an upload asks for, a `width` x `height` box or `resize`, against the limits
+/// on sources, so the worker is never asked for an output larger than an image it would accept. An
+/// edge length stands for a square of that side, the largest output it can give.
+///
+/// ```
+/// use image_resize_core::{image_checks::check_output, resize_spec::ResizeSpec};
+///
+/// assert!(check_output(1920, 1080, None).is_ok());
+/// assert!(check_output(100_000, 100_000, None).is_err());
+/// assert!(check_output(800, 800, Some(ResizeSpec::LongestEdge(2000))).is_ok());
+/// assert!(check_output(800, 800, Some(ResizeSpec::ShortestEdge(50_000))).is_err());
+/// ```
+pub fn check_output(width: u32, height: u32, resize: Option<ResizeSpec>) -> Result<(), String> {
+    let limits = ImageLimits::from_env();
+    limits.check("The requested size", width, height)?;
+    match resize {
+        Some(ResizeSpec::LongestEdge(edge) | ResizeSpec::ShortestEdge(edge)) => limits.check("The requested edge", edge, edge),
+        _ => Ok(()),
+    }
+}
+
+/// Checks an uploaded file by its leading bytes, enough of it to hold the image's header,
+/// returning the image's dimensions, `None` for videos, PDFs and SVGs.
+pub fn check(filename: &str, bytes: &[u8]) -> Result<Option<(u32, u32)>, Invalid> {
+    if bytes.is_empty() {
+        return Err(Invalid::Empty(format!("'{}' is empty", filename)));
+    }
+    if VideoFormat::sniff(bytes).is_some() || pdf::is_pdf(bytes) {
+        return Ok(None);
+    }
+    // the worker reads SVGs only when they're routed to be rasterized, see `routing.rs`
+    if svg::is_svg(bytes) {
+        return match config::get().pipeline_for(svg::EXTENSION) {
+            Pipeline::Rasterize => Ok(None),
+            _ => Err(Invalid::Unsupported(format!("'{}' is an SVG, which this service doesn't rasterize", filename))),
+        };
+    }
+    let unsupported = || Invalid::Unsupported(format!("'{}' is not a supported image", filename));
+    let reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format().map_err(|_| unsupported())?;
+    if !reader.format().is_some_and(capabilities::reads) {
+        return Err(unsupported());
+    }
+    // the format is known from the magic bytes, so a header that can't be read was cut short
+    let (width, height) = reader.into_dimensions().map_err(|e| match e {
+        ImageError::Unsupported(_) => unsupported(),
+        _ => Invalid::Truncated(format!("'{}' is truncated, its header is incomplete", filename)),
+    })?;
+    ImageLimits::from_env()
+        .check(&format!("'{}'", filename), width, height)
+        .map_err(Invalid::TooLarge)?;
+    Ok(Some((width, height)))
+}
+
+/// Checks a whole uploaded file for content and, if it's a JPEG, PNG, GIF or WebP, for the end of
+/// its image data.
+pub fn check_complete(filename: &str, bytes: &[u8]) -> Result<(), Invalid> {
+    if bytes.is_empty() {
+        return Err(Invalid::Empty(format!("'{}' is empty", filename)));
+    }
+    if !trailing_data::is_complete(bytes) {
+        return Err(Invalid::Truncated(format!("'{}' is truncated, its image data ends early", filename)));
+    }
+    Ok(())
+}
+
+/// Cuts the EXIF of a JPEG down to the kept fields and drops its XMP. Other formats, and a JPEG
+/// whose metadata runs past the end of `bytes`, are left as they are.
+pub fn strip_exif(bytes: &mut Vec<u8>) {
+    if !bytes.starts_with(&[0xFF, 0xD8]) {
+        return;
+    }
+    let mut stripped = Vec::with_capacity(bytes.len());
+    stripped.extend_from_slice(&bytes[..2]);
+    let mut position = 2;
+    while position + 4 <= bytes.len() && bytes[position] == 0xFF {
+        let marker = bytes[position + 1];
+        if marker == SOS {
+            break;
+        }
+        let length = u16::from_be_bytes([bytes[position + 2], bytes[position + 3]]) as usize;
+        let Some(payload) = bytes.get(position + 4..position + 2 + length) else {
+            return;
+        };
+        match marker {
+            APP1 if payload.starts_with(EXIF_HEADER) => {
+                if let Some(exif) = reduced_exif(&payload[EXIF_HEADER.len()..]) {
+                    let segment_length = (2 + EXIF_HEADER.len() + exif.len()) as u16;
+                    stripped.extend_from_slice(&[0xFF, APP1]);
+                    stripped.extend_from_slice(&segment_length.to_be_bytes());
+                    stripped.extend_from_slice(EXIF_HEADER);
+                    stripped.extend_from_slice(&exif);
+                }
+            }
+            APP1 if payload.starts_with(XMP_HEADER) => {}
+            _ => stripped.extend_from_slice(&bytes[position..position + 2 + length]),
+        }
+        position += 2 + length;
+    }
+    stripped.extend_from_slice(&bytes[position..]);
+    *bytes = stripped;
+}
+
+/// The kept fields of a TIFF-structured EXIF block, `None` when it has none of them or can't be
+/// parsed, in which case there's nothing to keep.
+fn reduced_exif(tiff: &[u8]) -> Option<Vec<u8>> {
+    let exif = Reader::new().read_raw(tiff.to_vec()).ok()?;
+    let gps: &[Tag] = if image_index::retain_gps() { GPS_TAGS } else { &[] };
+    let fields: Vec<_> = KEPT_TAGS
+        .iter()
+        .chain(gps)
+        .filter_map(|tag| exif.get_field(*tag, In::PRIMARY))
+        .collect();
+    if fields.is_empty() {
+        return None;
+    }
+    let mut writer = Writer::new();
+    for field in fields {
+        writer.push_field(field);
+    }
+    let mut reduced = Cursor::new(Vec::new());
+    writer.write(&mut reduced, exif.little_endian()).ok()?;
+    Some(reduced.into_inner())
+}
@@ -0,0 +1,150 @@
+// core/src/image_index.rs
+
+//! Capture details read from the EXIF of uploaded originals, along with their tags and analysis
+//! caption, indexed in Azure Table Storage so stored images can be searched by date, camera and
+//! text. The worker writes one entity per original,
+//! partitioned by container, to the table named by `IMAGE_INDEX_TABLE` (default `images`) in the
+//! `AZURE_STORAGE_ACCOUNT` the blobs live in; the API queries it.
+
+use azure_core::{base64, date};
+use azure_data_tables::prelude::TableClient;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::env;
+
+use crate::tables;
+
+const DEFAULT_TABLE: &str = "images";
+
+/// One indexed original. Fields the image's EXIF didn't carry are left out of the entity.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ImageRecord {
+    #[serde(rename = "PartitionKey")]
+    pub container: String,
+    /// The blob name, URL-safe base64 encoded since row keys can't contain `/`, `\`, `#` or `?`.
+    #[serde(rename = "RowKey")]
+    pub row_key: String,
+    pub blob: String,
+    /// Empty for uploads made without a tenant, so they can be filtered on too.
+    #[serde(default)]
+    pub tenant: String,
+    /// `DateTimeOriginal` as `YYYY-MM-DDTHH:MM:SS`, in the camera's local time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub captured_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub camera_make: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub camera_model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latitude: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub longitude: Option<f64>,
+    /// Tags given on upload, space separated.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub tags: String,
+    /// Short description derived from the image analysis, e.g. `bright sharp landscape`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub caption: Option<String>,
+    /// Size of the original in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// When the entity was last written, RFC 3339; maintained by the table service.
+    #[serde(rename = "Timestamp", default, skip_serializing)]
+    pub timestamp: Option<String>,
+}
+
+impl ImageRecord {
+    pub fn new(container: &str, blob: &str, tenant: Option<&str>) -> Self {
+        ImageRecord {
+            container: container.to_string(),
+            row_key: base64::encode_url_safe(blob),
+            blob: blob.to_string(),
+            tenant: tenant.unwrap_or_default().to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn indexed_at(&self) -> Option<time::OffsetDateTime> {
+        self.timestamp.as_deref().and_then(|t| date::parse_rfc3339(t).ok())
+    }
+}
+
+/// Filters for [`search`]; capture times compare as `YYYY-MM-DDTHH:MM:SS` strings, so a bare date
+/// works as a lower bound.
+#[derive(Debug, Default)]
+pub struct SearchFilter {
+    pub tenant: Option<String>,
+    pub from: Option<String>,
+    /// Exclusive upper bound on `captured_at`.
+    pub until: Option<String>,
+    pub camera_model: Option<String>,
+}
+
+impl SearchFilter {
+    fn to_odata(&self, container: &str) -> String {
+        let mut clauses = vec![
+            format!("PartitionKey eq {}", quote(container)),
+            format!("tenant eq {}", quote(self.tenant.as_deref().unwrap_or_default())),
+        ];
+        if let Some(from) = &self.from {
+            clauses.push(format!("captured_at ge {}", quote(from)));
+        }
+        if let Some(until) = &self.until {
+            clauses.push(format!("captured_at lt {}", quote(until)));
+        }
+        if let Some(camera) = &self.camera_model {
+            clauses.push(format!("camera_model eq {}", quote(camera)));
+        }
+        clauses.join(" and ")
+    }
+}
+
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Whether GPS coordinates should be kept in the index, from `IMAGE_INDEX_GPS` (off by default).
+pub fn retain_gps() -> bool {
+    env::var("IMAGE_INDEX_GPS")
+        .map(|v| matches!(v.trim().to_ascii_lowercase().as_str(), "1" | "true" | "on"))
+        .unwrap_or(false)
+}
+
+fn table_client() -> TableClient {
+    tables::table_client("IMAGE_INDEX_TABLE", DEFAULT_TABLE)
+}
+
+/// Writes `record`, replacing any earlier entry for the same blob and creating the table on first use.
+pub async fn upsert(record: &ImageRecord) -> azure_core::Result<()> {
+    let table_client = table_client();
+    tables::create_if_missing(&table_client).await?;
+    table_client
+        .partition_key_client(&record.container)
+        .entity_client(&record.row_key)
+        .insert_or_replace(record)?
+        .await?;
+    Ok(())
+}
+
+/// Indexed images in `container` matching `filter`, at most `limit` of them.
+pub async fn search(container: &str, filter: &SearchFilter, limit: usize) -> azure_core::Result<Vec<ImageRecord>> {
+    let mut records = Vec::new();
+    let mut pages = table_client()
+        .query()
+        .filter(filter.to_odata(container))
+        .into_stream::<ImageRecord>();
+    while let Some(page) = pages.next().await {
+        let page = match page {
+            Ok(page) => page,
+            // nothing has been indexed yet
+            Err(e) if tables::is_not_found(&e) => break,
+            Err(e) => return Err(e),
+        };
+        records.extend(page.entities);
+        if records.len() >= limit {
+            records.truncate(limit);
+            break;
+        }
+    }
+    Ok(records)
+}
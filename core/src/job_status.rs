@@ -0,0 +1,569 @@
+// core/src/job_status.rs
+
+//! The etag each source blob had when it was last processed successfully, so a resubmission of
+//! an unchanged blob can be skipped. Kept in the table named by `JOB_STATUS_TABLE` (default
+//! `jobstatus`), one entity per source blob, partitioned by container.
+//!
+//! Cancellations requested through `DELETE /jobs/{name}` live in the same table, partitioned by
+//! `cancelled-<container>`. A cancellation covers every message for the blob queued before it, so
+//! the worker abandons those while jobs queued later, e.g. by a reupload, run as usual.
+//!
+//! Jobs the worker skipped for having waited longer than `JOB_MAX_AGE_SECS` are recorded under
+//! `expired-<container>`, so the status endpoint can tell them from jobs still waiting.
+//!
+//! Every message the API queues starts a job with its own id, recorded under `jobs` with its state
+//! and the blobs it wrote so far, and served on `GET /jobs/{id}`. A job whose resize stage ran out
+//! of time also records which of its renditions are made and which are still pending, and stays
+//! `partially_complete` rather than `done` until none are pending. The job's chain of stages is
+//! recorded with it, the one queued or running, those still to run after it and those done.
+//!
+//! Each rendition the worker writes records its provenance under `provenance-<container>`, the
+//! container of its original: the original and its etag, the preset and pipeline version that made
+//! it, and the rendition it was derived from, if any, so an original's derivation tree can be
+//! served on `GET /images/{name}/provenance`.
+//!
+//! Uploads of tenants whose policy asks for review are stored but held back from the queue until
+//! they're approved, see `api/src/review.rs`. Their messages wait under `review`, one record per
+//! job, and the job stays `pending_review` meanwhile.
+//!
+//! Records of each kind can be kept for a limited time, see [`purge`]. The table's schema version
+//! lives in a row of its own, see `migrations.rs`. The records are read and written through the
+//! store `STATUS_STORE` picks, see `status_store.rs`; the layout above is the table's.
+
+use azure_core::{base64, date};
+use azure_data_tables::prelude::TableClient;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use time::OffsetDateTime;
+
+use crate::{
+    message::{ImageMessage, Stage},
+    migrations,
+    models::Job,
+    status_store, tables,
+    warnings::Warning,
+};
+
+const DEFAULT_TABLE: &str = "jobstatus";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct JobStatus {
+    #[serde(rename = "PartitionKey")]
+    pub container: String,
+    /// The blob name, URL-safe base64 encoded like in the image index.
+    #[serde(rename = "RowKey")]
+    pub row_key: String,
+    pub blob: String,
+    /// Etag of the source blob as the worker read it.
+    pub etag: String,
+    /// RFC 3339.
+    pub processed_at: String,
+    /// Warnings of the successful run as a JSON array, tables having no array type.
+    #[serde(default)]
+    pub warnings: String,
+}
+
+impl JobStatus {
+    pub fn warnings(&self) -> Vec<Warning> {
+        serde_json::from_str(&self.warnings).unwrap_or_default()
+    }
+}
+
+/// A cancelled job, see [`cancel`].
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Cancellation {
+    #[serde(rename = "PartitionKey")]
+    pub partition: String,
+    #[serde(rename = "RowKey")]
+    pub row_key: String,
+    pub blob: String,
+    /// RFC 3339.
+    pub cancelled_at: String,
+}
+
+impl Cancellation {
+    /// Whether a message queued at `queued_at` (RFC 3339) is cancelled; messages queued before
+    /// messages carried the time are.
+    pub fn covers(&self, queued_at: Option<&str>) -> bool {
+        let Some(queued_at) = queued_at else {
+            return true;
+        };
+        match (date::parse_rfc3339(queued_at), date::parse_rfc3339(&self.cancelled_at)) {
+            (Ok(queued_at), Ok(cancelled_at)) => queued_at <= cancelled_at,
+            _ => true,
+        }
+    }
+}
+
+pub(crate) fn table_client() -> TableClient {
+    tables::table_client("JOB_STATUS_TABLE", DEFAULT_TABLE)
+}
+
+/// Records that `blob` was processed successfully as it was at `etag`, with the run's warnings.
+pub async fn record_success(container: &str, blob: &str, etag: &str, warnings: &[Warning]) -> azure_core::Result<()> {
+    let status = JobStatus {
+        container: container.to_string(),
+        row_key: base64::encode_url_safe(blob),
+        blob: blob.to_string(),
+        etag: etag.to_string(),
+        processed_at: date::to_rfc3339(&OffsetDateTime::now_utc()),
+        warnings: serde_json::to_string(warnings).expect("Failed to serialize warnings"),
+    };
+    status_store::get().put_success(&status).await
+}
+
+/// The last successful processing of `blob`, if there was one.
+pub async fn last_success(container: &str, blob: &str) -> azure_core::Result<Option<JobStatus>> {
+    status_store::get().last_success(container, blob).await
+}
+
+pub(crate) fn cancellation_partition(container: &str) -> String {
+    format!("cancelled-{}", container)
+}
+
+/// Cancels the jobs queued so far for `blob`.
+pub async fn cancel(container: &str, blob: &str) -> azure_core::Result<Cancellation> {
+    let cancellation = Cancellation {
+        partition: cancellation_partition(container),
+        row_key: base64::encode_url_safe(blob),
+        blob: blob.to_string(),
+        cancelled_at: date::to_rfc3339(&OffsetDateTime::now_utc()),
+    };
+    status_store::get().put_cancellation(&cancellation).await?;
+    Ok(cancellation)
+}
+
+/// The latest cancellation of `blob`'s jobs, if there was one.
+pub async fn cancellation(container: &str, blob: &str) -> azure_core::Result<Option<Cancellation>> {
+    status_store::get().cancellation(container, blob).await
+}
+
+/// A job skipped for being stale, see [`record_expired`].
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Expiry {
+    #[serde(rename = "PartitionKey")]
+    pub partition: String,
+    #[serde(rename = "RowKey")]
+    pub row_key: String,
+    pub blob: String,
+    /// RFC 3339, when the expired message was queued.
+    pub queued_at: String,
+    /// RFC 3339.
+    pub expired_at: String,
+}
+
+pub(crate) fn expiry_partition(container: &str) -> String {
+    format!("expired-{}", container)
+}
+
+/// Records that a job for `blob` queued at `queued_at` was skipped as stale.
+pub async fn record_expired(container: &str, blob: &str, queued_at: &str) -> azure_core::Result<()> {
+    let expiry = Expiry {
+        partition: expiry_partition(container),
+        row_key: base64::encode_url_safe(blob),
+        blob: blob.to_string(),
+        queued_at: queued_at.to_string(),
+        expired_at: date::to_rfc3339(&OffsetDateTime::now_utc()),
+    };
+    status_store::get().put_expiry(&expiry).await
+}
+
+/// The latest stale job skipped for `blob`, if there was one.
+pub async fn last_expiry(container: &str, blob: &str) -> azure_core::Result<Option<Expiry>> {
+    status_store::get().last_expiry(container, blob).await
+}
+
+/// Where a job stands. It stays `processing` between its stages.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    #[default]
+    Queued,
+    /// Stored, but not queued until the upload is approved, see [`hold_for_review`].
+    PendingReview,
+    Processing,
+    Done,
+    /// Every stage ran, but some renditions were left for a later run, see [`JobRecord::renditions`].
+    PartiallyComplete,
+    Failed,
+}
+
+/// Where a rendition of a job stands, once the job recorded its renditions.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RenditionState {
+    Done,
+    Pending,
+}
+
+/// A job as stored, see [`create_job`].
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct JobRecord {
+    #[serde(rename = "PartitionKey")]
+    pub partition: String,
+    #[serde(rename = "RowKey")]
+    pub id: String,
+    pub container: String,
+    pub blob: String,
+    #[serde(default)]
+    pub tenant: Option<String>,
+    pub state: JobState,
+    /// URLs of the blobs written so far as a JSON array, tables having no array type.
+    #[serde(default)]
+    pub outputs: String,
+    /// The state of each rendition by blob name as a JSON object, empty unless the job recorded them.
+    #[serde(default)]
+    pub renditions: String,
+    /// Why the last run failed, empty otherwise.
+    #[serde(default)]
+    pub error: String,
+    /// The stage queued or running as JSON, empty once the chain is done or if it wasn't recorded.
+    #[serde(default)]
+    pub stage: String,
+    /// Stages still to run after it as a JSON array.
+    #[serde(default)]
+    pub then: String,
+    /// Stages already run, oldest first, as a JSON array.
+    #[serde(default)]
+    pub completed: String,
+    /// RFC 3339.
+    pub created_at: String,
+    /// RFC 3339.
+    pub updated_at: String,
+}
+
+impl JobRecord {
+    pub fn outputs(&self) -> Vec<String> {
+        serde_json::from_str(&self.outputs).unwrap_or_default()
+    }
+
+    pub fn renditions(&self) -> BTreeMap<String, RenditionState> {
+        serde_json::from_str(&self.renditions).unwrap_or_default()
+    }
+
+    pub fn stage(&self) -> Option<Stage> {
+        serde_json::from_str(&self.stage).ok()
+    }
+
+    pub fn then(&self) -> Vec<Stage> {
+        serde_json::from_str(&self.then).unwrap_or_default()
+    }
+
+    pub fn completed(&self) -> Vec<Stage> {
+        serde_json::from_str(&self.completed).unwrap_or_default()
+    }
+
+    /// Records where `image`, a message of the job, is in its chain; a job `done` has run it all.
+    fn set_chain(&mut self, image: &ImageMessage) {
+        let mut completed = image.completed.clone();
+        let stage = match self.state {
+            JobState::Done | JobState::PartiallyComplete => {
+                completed.push(image.stage.clone());
+                None
+            }
+            _ => Some(&image.stage),
+        };
+        self.stage = stage.map(|stage| serde_json::to_string(stage).expect("Failed to serialize stage")).unwrap_or_default();
+        self.then = serde_json::to_string(&image.then).expect("Failed to serialize stages");
+        self.completed = serde_json::to_string(&completed).expect("Failed to serialize stages");
+    }
+
+    pub fn to_job(&self) -> Job {
+        Job {
+            id: self.id.clone(),
+            container: self.container.clone(),
+            filename: self.blob.clone(),
+            state: self.state,
+            outputs: self.outputs(),
+            renditions: self.renditions(),
+            error: (!self.error.is_empty()).then(|| self.error.clone()),
+            stage: self.stage(),
+            then: self.then(),
+            completed: self.completed(),
+            created_at: self.created_at.clone(),
+            updated_at: self.updated_at.clone(),
+        }
+    }
+}
+
+pub(crate) const JOBS_PARTITION: &str = "jobs";
+
+/// Records job `id` as queued for `image`.
+pub async fn create_job(id: &str, image: &ImageMessage) -> azure_core::Result<()> {
+    put_new_job(id, image, JobState::Queued).await
+}
+
+async fn put_new_job(id: &str, image: &ImageMessage, state: JobState) -> azure_core::Result<()> {
+    let now = date::to_rfc3339(&OffsetDateTime::now_utc());
+    let mut record = JobRecord {
+        partition: JOBS_PARTITION.to_string(),
+        id: id.to_string(),
+        container: image.image_container.clone(),
+        blob: image.filename.clone(),
+        tenant: image.tenant.clone(),
+        state,
+        outputs: "[]".to_string(),
+        renditions: String::new(),
+        error: String::new(),
+        stage: String::new(),
+        then: String::new(),
+        completed: String::new(),
+        created_at: now.clone(),
+        updated_at: now,
+    };
+    record.set_chain(image);
+    status_store::get().put_job(&record).await
+}
+
+/// Moves job `id` to `state`, adding `outputs` to the blobs it wrote. `error` replaces the last
+/// one, so a retry that succeeds clears it. A job done with renditions still pending is
+/// `partially_complete` instead. `image`, the message the update comes from, records where the
+/// job is in its chain. Jobs never recorded are left alone.
+pub async fn update_job(id: &str, state: JobState, outputs: &[String], error: Option<&str>, image: Option<&ImageMessage>) -> azure_core::Result<()> {
+    let Some(mut record) = job(id).await? else {
+        return Ok(());
+    };
+    let mut all_outputs = record.outputs();
+    for output in outputs {
+        if !all_outputs.contains(output) {
+            all_outputs.push(output.clone());
+        }
+    }
+    let pending = record.renditions().values().any(|state| *state == RenditionState::Pending);
+    record.state = if state == JobState::Done && pending { JobState::PartiallyComplete } else { state };
+    record.outputs = serde_json::to_string(&all_outputs).expect("Failed to serialize outputs");
+    record.error = error.unwrap_or_default().to_string();
+    if let Some(image) = image {
+        record.set_chain(image);
+    }
+    record.updated_at = date::to_rfc3339(&OffsetDateTime::now_utc());
+    status_store::get().put_job(&record).await
+}
+
+/// Adds `output` to the blobs job `id` wrote, leaving its state as it is.
+pub async fn add_output(id: &str, output: &str) -> azure_core::Result<()> {
+    let Some(mut record) = job(id).await? else {
+        return Ok(());
+    };
+    let mut outputs = record.outputs();
+    if outputs.iter().any(|existing| existing == output) {
+        return Ok(());
+    }
+    outputs.push(output.to_string());
+    record.outputs = serde_json::to_string(&outputs).expect("Failed to serialize outputs");
+    record.updated_at = date::to_rfc3339(&OffsetDateTime::now_utc());
+    status_store::get().put_job(&record).await
+}
+
+/// Records the renditions of job `id`, by blob name, that are now `done` and those still `pending`.
+pub async fn update_renditions(id: &str, done: &[String], pending: &[String]) -> azure_core::Result<()> {
+    let Some(mut record) = job(id).await? else {
+        return Ok(());
+    };
+    let mut renditions = record.renditions();
+    renditions.extend(done.iter().map(|blob| (blob.clone(), RenditionState::Done)));
+    renditions.extend(pending.iter().map(|blob| (blob.clone(), RenditionState::Pending)));
+    record.renditions = serde_json::to_string(&renditions).expect("Failed to serialize renditions");
+    record.updated_at = date::to_rfc3339(&OffsetDateTime::now_utc());
+    status_store::get().put_job(&record).await
+}
+
+/// Job `id`, if it was recorded.
+pub async fn job(id: &str) -> azure_core::Result<Option<JobRecord>> {
+    status_store::get().job(id).await
+}
+
+/// How a rendition was made, see [`record_provenance`].
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Provenance {
+    #[serde(rename = "PartitionKey")]
+    pub partition: String,
+    /// `<container>/<blob>` of the rendition, URL-safe base64 encoded.
+    #[serde(rename = "RowKey")]
+    pub row_key: String,
+    pub container: String,
+    pub blob: String,
+    /// The original, in the partition's container.
+    pub source: String,
+    /// Etag of the original as the worker read it.
+    pub source_etag: String,
+    /// As in `blob_tags`.
+    pub preset: String,
+    /// See `pipeline::version`.
+    pub pipeline_version: String,
+    /// The rendition, in the partition's container, this one was derived from; empty when it was
+    /// made from the original.
+    #[serde(default)]
+    pub parent: String,
+    /// RFC 3339.
+    pub created_at: String,
+}
+
+pub(crate) fn provenance_partition(container: &str) -> String {
+    format!("provenance-{}", container)
+}
+
+/// A rendition about to be recorded, see [`record_provenance`].
+pub struct Derivation<'a> {
+    pub container: &'a str,
+    pub blob: &'a str,
+    pub preset: &'a str,
+    pub pipeline_version: &'a str,
+    pub parent: Option<&'a str>,
+}
+
+/// Records that `derivation` was made from `source` in `source_container` as it was at `source_etag`.
+pub async fn record_provenance(
+    source_container: &str,
+    source: &str,
+    source_etag: &str,
+    derivation: Derivation<'_>,
+) -> azure_core::Result<()> {
+    let provenance = Provenance {
+        partition: provenance_partition(source_container),
+        row_key: base64::encode_url_safe(format!("{}/{}", derivation.container, derivation.blob)),
+        container: derivation.container.to_string(),
+        blob: derivation.blob.to_string(),
+        source: source.to_string(),
+        source_etag: source_etag.to_string(),
+        preset: derivation.preset.to_string(),
+        pipeline_version: derivation.pipeline_version.to_string(),
+        parent: derivation.parent.unwrap_or_default().to_string(),
+        created_at: date::to_rfc3339(&OffsetDateTime::now_utc()),
+    };
+    status_store::get().put_provenance(&provenance).await
+}
+
+/// The renditions recorded as made from `source`, directly or through other renditions.
+pub async fn provenance(container: &str, source: &str) -> azure_core::Result<Vec<Provenance>> {
+    status_store::get().provenance(container, source).await
+}
+
+/// An upload held back from the queue, see [`hold_for_review`].
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Review {
+    #[serde(rename = "PartitionKey")]
+    pub partition: String,
+    /// The id of the job the upload's reply named.
+    #[serde(rename = "RowKey")]
+    pub id: String,
+    pub container: String,
+    pub blob: String,
+    #[serde(default)]
+    pub tenant: Option<String>,
+    /// The message to queue once approved, as JSON.
+    pub message: String,
+    /// Whether the moderation pass flagged the upload, leaving it to an admin.
+    #[serde(default)]
+    pub flagged: bool,
+    /// RFC 3339.
+    pub created_at: String,
+    /// RFC 3339; an upload not approved by then is discarded.
+    pub expires_at: String,
+}
+
+impl Review {
+    pub fn message(&self) -> Option<ImageMessage> {
+        serde_json::from_str(&self.message).ok()
+    }
+}
+
+pub(crate) const REVIEW_PARTITION: &str = "review";
+
+/// Records job `id` as `pending_review`, holding `image` until [`end_review`] or `expires_at`.
+pub async fn hold_for_review(id: &str, image: &ImageMessage, expires_at: OffsetDateTime) -> azure_core::Result<()> {
+    let review = Review {
+        partition: REVIEW_PARTITION.to_string(),
+        id: id.to_string(),
+        container: image.image_container.clone(),
+        blob: image.filename.clone(),
+        tenant: image.tenant.clone(),
+        message: serde_json::to_string(image).expect("Failed to serialize image"),
+        flagged: false,
+        created_at: date::to_rfc3339(&OffsetDateTime::now_utc()),
+        expires_at: date::to_rfc3339(&expires_at),
+    };
+    status_store::get().put_review(&review).await?;
+    put_new_job(id, image, JobState::PendingReview).await
+}
+
+/// Every upload held for review.
+pub async fn reviews() -> azure_core::Result<Vec<Review>> {
+    status_store::get().reviews().await
+}
+
+/// The upload held as job `id`, if it's still waiting.
+pub async fn review(id: &str) -> azure_core::Result<Option<Review>> {
+    status_store::get().review(id).await
+}
+
+/// Marks a held upload as flagged by the moderation pass.
+pub async fn flag_review(mut review: Review) -> azure_core::Result<()> {
+    review.flagged = true;
+    status_store::get().put_review(&review).await
+}
+
+/// Stops holding job `id`, once it's been approved, rejected or expired.
+pub async fn end_review(id: &str) -> azure_core::Result<()> {
+    status_store::get().delete_review(id).await
+}
+
+/// The kinds of record the table holds, told apart by their partition.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordKind {
+    /// The last successful processing of a blob, see [`record_success`].
+    Processed,
+    Cancellation,
+    Expiry,
+    Job,
+    Provenance,
+    /// An upload held for review, removed once decided.
+    Review,
+}
+
+impl RecordKind {
+    pub const ALL: [RecordKind; 6] = [
+        RecordKind::Processed,
+        RecordKind::Cancellation,
+        RecordKind::Expiry,
+        RecordKind::Job,
+        RecordKind::Provenance,
+        RecordKind::Review,
+    ];
+
+    /// The kind of the records in `partition`, `None` for the schema version's.
+    pub(crate) fn of(partition: &str) -> Option<Self> {
+        if partition == migrations::SCHEMA_PARTITION {
+            None
+        } else if partition == JOBS_PARTITION {
+            Some(RecordKind::Job)
+        } else if partition == REVIEW_PARTITION {
+            Some(RecordKind::Review)
+        } else if partition.starts_with("cancelled-") {
+            Some(RecordKind::Cancellation)
+        } else if partition.starts_with("expired-") {
+            Some(RecordKind::Expiry)
+        } else if partition.starts_with("provenance-") {
+            Some(RecordKind::Provenance)
+        } else {
+            Some(RecordKind::Processed)
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RecordKind::Processed => "processed",
+            RecordKind::Cancellation => "cancellation",
+            RecordKind::Expiry => "expiry",
+            RecordKind::Job => "job",
+            RecordKind::Provenance => "provenance",
+            RecordKind::Review => "review",
+        }
+    }
+}
+
+/// Deletes the records last written before the cutoff `cutoff` gives for their kind, `None`
+/// keeping every record of a kind. Returns how many records of each kind were deleted.
+pub async fn purge(cutoff: impl Fn(RecordKind) -> Option<OffsetDateTime> + Send + Sync) -> azure_core::Result<Vec<(RecordKind, u64)>> {
+    status_store::get().purge(&cutoff).await
+}
@@ -0,0 +1,124 @@
+// core/src/key_usage.rs
+
+//! Daily usage per API key, reported on the API's `GET /admin/usage`. It is kept in the table named
+//! by `KEY_USAGE_TABLE` (default `keyusage`), one entity per UTC day and key: partition
+//! `YYYY-MM-DD`, row the key's id. A tenant's key is `tenant:<id>`, and a key of `API_KEYS` is
+//! `key:` and the first 12 hex digits of its SHA-256, so the table never holds a key itself.
+//!
+//! The API counts requests and the bytes they send, see `api/src/usage.rs`. The worker counts how
+//! the jobs end, but only for jobs uploaded with a tenant's key, since the message names the tenant
+//! and not the key.
+
+use azure_core::error::{Error, ErrorKind};
+use azure_data_tables::{prelude::TableClient, IfMatchCondition};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+
+use crate::tables;
+
+const DEFAULT_TABLE: &str = "keyusage";
+/// Tries at a read-modify-write before giving up on concurrent writers.
+const MAX_ATTEMPTS: usize = 5;
+
+/// What a key used in a day.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Counts {
+    pub requests: i64,
+    /// Bytes of request bodies, as declared by their `Content-Length`.
+    pub bytes: i64,
+    pub jobs_completed: i64,
+    pub jobs_failed: i64,
+    pub jobs_cancelled: i64,
+}
+
+impl Counts {
+    pub fn add(&mut self, other: &Counts) {
+        self.requests += other.requests;
+        self.bytes += other.bytes;
+        self.jobs_completed += other.jobs_completed;
+        self.jobs_failed += other.jobs_failed;
+        self.jobs_cancelled += other.jobs_cancelled;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DailyUsage {
+    #[serde(rename = "PartitionKey")]
+    pub day: String,
+    #[serde(rename = "RowKey")]
+    pub key: String,
+    #[serde(flatten)]
+    pub counts: Counts,
+}
+
+fn table_client() -> TableClient {
+    tables::table_client("KEY_USAGE_TABLE", DEFAULT_TABLE)
+}
+
+/// The id usage of one of `API_KEYS` is recorded under.
+pub fn api_key_id(key: &str) -> String {
+    format!("key:{}", &hex::encode(Sha256::digest(key.as_bytes()))[..12])
+}
+
+/// The id usage of a tenant's key is recorded under.
+pub fn tenant_key_id(tenant: &str) -> String {
+    format!("tenant:{}", tenant)
+}
+
+/// The UTC day of `at`, as the table's partitions name it.
+pub fn day(at: OffsetDateTime) -> String {
+    format!("{}-{:02}-{:02}", at.year(), at.month() as u8, at.day())
+}
+
+/// Adds `counts` to what `key` used on `day`.
+pub async fn add(day: &str, key: &str, counts: &Counts) -> azure_core::Result<()> {
+    let table_client = table_client();
+    tables::create_if_missing(&table_client).await?;
+    let entity_client = table_client.partition_key_client(day).entity_client(key);
+
+    for _ in 0..MAX_ATTEMPTS {
+        let (mut entry, etag) = match entity_client.get::<DailyUsage>().await {
+            Ok(response) => (response.entity, Some(response.etag)),
+            Err(e) if tables::is_not_found(&e) => {
+                let entry = DailyUsage {
+                    day: day.to_string(),
+                    key: key.to_string(),
+                    counts: Counts::default(),
+                };
+                (entry, None)
+            }
+            Err(e) => return Err(e),
+        };
+        entry.counts.add(counts);
+        let written = match etag {
+            Some(etag) => entity_client.update(&entry, IfMatchCondition::Etag(etag))?.await.map(|_| ()),
+            None => table_client.insert::<_, DailyUsage>(&entry)?.await.map(|_| ()),
+        };
+        match written {
+            Ok(()) => return Ok(()),
+            Err(e) if tables::is_conflict(&e) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(Error::with_message(ErrorKind::Other, || {
+        format!("Too many concurrent updates of the usage of {} on {}", key, day)
+    }))
+}
+
+/// The usage recorded from day `from` to day `to`, both included.
+pub async fn between(from: &str, to: &str) -> azure_core::Result<Vec<DailyUsage>> {
+    let filter = format!("PartitionKey ge '{}' and PartitionKey le '{}'", from, to);
+    let mut usage = Vec::new();
+    let mut pages = table_client().query().filter(filter).into_stream::<DailyUsage>();
+    while let Some(page) = pages.next().await {
+        match page {
+            Ok(page) => usage.extend(page.entities),
+            // nothing was recorded yet
+            Err(e) if tables::is_not_found(&e) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(usage)
+}
@@ -0,0 +1,57 @@
+// core/src/lib.rs
+
+//! Code shared between the upload API, the worker and consumers of their webhooks.
+
+pub mod account_keys;
+pub mod azure;
+pub mod blob_tags;
+pub mod build_info;
+pub mod capabilities;
+pub mod chaos;
+pub mod claim_check;
+pub mod client;
+pub mod clients;
+pub mod config;
+pub mod config_check;
+pub mod cpu_features;
+pub mod content_store;
+pub mod crop;
+pub mod customer_keys;
+pub mod failover;
+pub mod features;
+pub mod filenames;
+pub mod geo_read;
+pub mod health;
+pub mod identity;
+pub mod image_checks;
+pub mod image_index;
+pub mod job_status;
+pub mod key_usage;
+pub mod logging;
+pub mod message;
+pub mod metrics;
+pub mod migrations;
+pub mod models;
+pub mod naming;
+pub mod operations;
+pub mod output_format;
+pub mod pdf;
+pub mod pipeline;
+pub mod queue;
+pub mod resize_spec;
+pub mod routing;
+pub mod sas;
+pub mod shards;
+pub mod status_store;
+pub mod storage;
+pub mod svg;
+pub mod tables;
+pub mod telemetry;
+pub mod tiers;
+pub mod trace;
+pub mod trailing_data;
+pub mod usage_store;
+pub mod variants;
+pub mod video;
+pub mod warnings;
+pub mod webhook;
@@ -0,0 +1,136 @@
+// core/src/logging.rs
+
+//! Log setup shared by both binaries: every formatted line passes through [`redact`] before it
+//! reaches stdout, and DEBUG/TRACE events are sampled once they exceed a per-second budget.
+//!
+//! The level comes from `LOG_LEVEL` (an `EnvFilter` directive, default `info`); the sampling
+//! budget from `LOG_DEBUG_BUDGET` (events per second, default 100) and `LOG_DEBUG_SAMPLE`
+//! (keep one in N past the budget, default 100).
+
+use regex::Regex;
+use std::{
+    borrow::Cow,
+    env,
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{Level, Metadata};
+
+use crate::trace::TraceLayer;
+use tracing_subscriber::{filter::{self, LevelFilter}, fmt::MakeWriter, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+
+const DEFAULT_DEBUG_BUDGET: u64 = 100;
+const DEFAULT_DEBUG_SAMPLE: u64 = 100;
+const REDACTED: &str = "[REDACTED]";
+
+fn patterns() -> &'static [Regex] {
+    static PATTERNS: OnceLock<Vec<Regex>> = OnceLock::new();
+    PATTERNS.get_or_init(|| {
+        [
+            // SAS signatures, account/policy keys and secrets in query strings or connection strings
+            r#"(?i)((?:sig|signature|secret|password|token|key|code)=)[^&;\s"']+"#,
+            // credentials in headers
+            r#"(?i)((?:authorization|x-api-key|x-upload-token)"?\s*[:=]\s*"?(?:bearer\s+)?)[^\s",]+"#,
+            // bare JWTs
+            r#"()eyJ[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+"#,
+        ]
+        .iter()
+        .map(|pattern| Regex::new(pattern).expect("Invalid redaction pattern"))
+        .collect()
+    })
+}
+
+/// Masks credentials (SAS signatures, access keys, bearer tokens, secrets in callback URLs) in `text`.
+pub fn redact(text: &str) -> Cow<'_, str> {
+    let mut result = Cow::Borrowed(text);
+    for pattern in patterns() {
+        if pattern.is_match(&result) {
+            result = Cow::Owned(pattern.replace_all(&result, format!("${{1}}{}", REDACTED)).into_owned());
+        }
+    }
+    result
+}
+
+/// Writes each formatted log line to stdout after redacting it.
+#[derive(Clone, Copy, Default)]
+struct RedactingStdout;
+
+struct RedactingWriter(io::Stdout);
+
+impl Write for RedactingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        self.0.write_all(redact(&text).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for RedactingStdout {
+    type Writer = RedactingWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactingWriter(io::stdout())
+    }
+}
+
+/// Lets every DEBUG/TRACE event through until `budget` have been seen in the current second,
+/// then only one in `keep_one_in`. Higher levels are never sampled.
+struct DebugSampler {
+    budget: u64,
+    keep_one_in: u64,
+    second: AtomicU64,
+    count: AtomicU64,
+}
+
+impl DebugSampler {
+    fn allows(&self, metadata: &Metadata<'_>) -> bool {
+        if !metadata.is_event() || *metadata.level() <= Level::INFO {
+            return true;
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        if self.second.swap(now, Ordering::Relaxed) != now {
+            self.count.store(0, Ordering::Relaxed);
+        }
+
+        let seen = self.count.fetch_add(1, Ordering::Relaxed);
+        seen < self.budget || (seen - self.budget).is_multiple_of(self.keep_one_in)
+    }
+}
+
+fn env_or(key: &str, default: u64) -> u64 {
+    env::var(key).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+/// Installs the global subscriber. Call once, first thing in `main`.
+pub fn init() {
+    let level = EnvFilter::try_from_env("LOG_LEVEL").unwrap_or_else(|_| EnvFilter::new("info"));
+    let sampler = DebugSampler {
+        budget: env_or("LOG_DEBUG_BUDGET", DEFAULT_DEBUG_BUDGET),
+        keep_one_in: env_or("LOG_DEBUG_SAMPLE", DEFAULT_DEBUG_SAMPLE).max(1),
+        second: AtomicU64::new(0),
+        count: AtomicU64::new(0),
+    };
+
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(RedactingStdout)
+        .with_filter(filter::filter_fn(move |metadata| sampler.allows(metadata)))
+        .with_filter(level);
+
+    // spans get their trace ids whether or not they're exported, see `trace.rs`
+    tracing_subscriber::registry()
+        .with(layer)
+        .with(TraceLayer.with_filter(LevelFilter::INFO))
+        .init();
+}
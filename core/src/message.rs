@@ -0,0 +1,325 @@
+// core/src/message.rs
+
+//! The queue message the API sends for each job and the worker consumes, one stage at a time. Every
+//! message carries its [`SCHEMA_VERSION`]. A change to the layout that an older worker would
+//! misread bumps it, and a worker leaves messages newer than its own version on the queue for an
+//! upgraded worker to pick up. Messages from before the field was added have the version 1 layout;
+//! version 2 added `operations`, version 3 `variants_only`, and a version 4 message may come as a
+//! claim check, see `claim_check.rs`.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+use crate::{
+    crop::{Crop, FocalPoint},
+    failover::Location,
+    operations::Operation,
+    output_format::OutputFormat,
+    resize_spec::{Filter, Fit, ResizeSpec},
+    variants::Variant,
+};
+
+/// Version of the message layout this build reads and writes.
+pub const SCHEMA_VERSION: u32 = 4;
+
+/// Size of the resized rendition when the upload doesn't ask for one.
+pub const DEFAULT_SIZE: u32 = 100;
+
+fn legacy_version() -> u32 {
+    1
+}
+
+fn default_size() -> u32 {
+    DEFAULT_SIZE
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImageMessage {
+    #[serde(default = "legacy_version")]
+    pub schema_version: u32,
+    pub filename: String,
+    pub image_container: String,
+    #[serde(default)]
+    pub auto_enhance: bool,
+    /// Stage this message asks the worker to run.
+    #[serde(default)]
+    pub stage: Stage,
+    /// Follow-up stages, enqueued one at a time as each stage succeeds.
+    #[serde(default)]
+    pub then: Vec<Stage>,
+    /// Stages already run for this image, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub completed: Vec<Stage>,
+    /// Size of the resized rendition.
+    #[serde(default = "default_size")]
+    pub width: u32,
+    #[serde(default = "default_size")]
+    pub height: u32,
+    /// Tenant the upload came from, for per-tenant feature flags.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// Search tags given on upload.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Custom metadata given on upload, copied onto every output.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+    /// Largest acceptable size of each rendition in bytes, met by lowering the JPEG quality.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_size: Option<u64>,
+    /// Sizes the resized rendition instead of `width` and `height`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resize: Option<ResizeSpec>,
+    /// How the resized rendition fills its box, overriding the resize preset's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fit: Option<Fit>,
+    /// Resampling filter of the resized rendition.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<Filter>,
+    /// Format of the renditions, the source's where possible when not given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_format: Option<OutputFormat>,
+    /// Sizes made alongside the resized rendition, see `variants.rs`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub variants: Vec<Variant>,
+    /// Whether only `variants` are left to make, the rest of the resize stage having been done by a
+    /// run that ran out of time; see `functions/src/deadline.rs`.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub variants_only: bool,
+    /// Region of the source every rendition is made from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crop: Option<Crop>,
+    /// Point fill renditions are centered on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub focal_point: Option<FocalPoint>,
+    /// Edits applied to the source in order before it's scaled, see `operations.rs`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub operations: Vec<Operation>,
+    /// Storage account the original was written to, its renditions going to the same one.
+    #[serde(default, skip_serializing_if = "Location::is_primary")]
+    pub storage: Location,
+    /// When the API queued the job, RFC 3339, set as the message is sent and carried over to
+    /// follow-up stages; cancellations cover messages queued before them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queued_at: Option<String>,
+    /// Job the message starts, set as it is sent and carried over to follow-up stages; see
+    /// `GET /jobs/{id}`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<String>,
+    /// Pixels of the source as its header gave them at upload, the worker's estimate of the cost
+    /// of decoding it; unknown for videos, PDFs and messages not sent by `/upload`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pixels: Option<u64>,
+    /// SHA-256 of the stored original as the API hashed it, hex encoded, when it did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
+    /// URL notified when the job ends, carried over to follow-up stages; see `functions/src/callback.rs`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub callback_url: Option<String>,
+    /// Times the worker put this stage off while it was saturated, see `functions/src/shed.rs`.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub deferrals: u32,
+    /// W3C trace context of the span that queued the message, which the stage continues; see
+    /// `core/src/trace.rs`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traceparent: Option<String>,
+    /// Order the worker starts prefetched messages in, carried over to follow-up stages.
+    #[serde(default, skip_serializing_if = "Priority::is_normal")]
+    pub priority: Priority,
+    /// Tier of the tenant, which the worker holds the job to, see `tiers.rs`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tier: Option<String>,
+    /// Whether each rendition is added to the job's outputs as soon as it's stored, the variants
+    /// smallest first, rather than all of them once the stage ends.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub incremental: bool,
+}
+
+fn is_false(b: &bool) -> bool {
+    !b
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+impl Default for ImageMessage {
+    fn default() -> Self {
+        ImageMessage {
+            schema_version: SCHEMA_VERSION,
+            filename: String::new(),
+            image_container: String::new(),
+            auto_enhance: false,
+            stage: Stage::default(),
+            then: Vec::new(),
+            completed: Vec::new(),
+            width: DEFAULT_SIZE,
+            height: DEFAULT_SIZE,
+            tenant: None,
+            tags: Vec::new(),
+            metadata: BTreeMap::new(),
+            target_size: None,
+            resize: None,
+            fit: None,
+            filter: None,
+            output_format: None,
+            variants: Vec::new(),
+            variants_only: false,
+            crop: None,
+            focal_point: None,
+            operations: Vec::new(),
+            storage: Location::Primary,
+            queued_at: None,
+            job_id: None,
+            pixels: None,
+            content_hash: None,
+            callback_url: None,
+            deferrals: 0,
+            traceparent: None,
+            priority: Priority::Normal,
+            tier: None,
+            incremental: false,
+        }
+    }
+}
+
+impl ImageMessage {
+    /// The Service Bus `MessageId` of this message: a SHA-256 of its content, hex encoded, leaving
+    /// out `queued_at` and `traceparent`, which change on every send. A message sent twice, by a
+    /// retried request or a stage redelivered after it queued its follow-up, keeps its id, and
+    /// duplicate detection on the queue drops the copy; see `queue.rs`.
+    pub fn message_id(&self) -> String {
+        let mut value = serde_json::to_value(self).expect("Failed to serialize image");
+        if let Some(fields) = value.as_object_mut() {
+            fields.remove("queued_at");
+            fields.remove("traceparent");
+        }
+        hex::encode(Sha256::digest(value.to_string()))
+    }
+}
+
+/// The schema version of a raw message, read on its own so a message too new to parse isn't taken
+/// for a malformed one; `None` if it isn't a JSON object.
+pub fn schema_version(message: &str) -> Option<u32> {
+    #[derive(Deserialize)]
+    struct Versioned {
+        #[serde(default = "legacy_version")]
+        schema_version: u32,
+    }
+    serde_json::from_str::<Versioned>(message).ok().map(|versioned| versioned.schema_version)
+}
+
+/// The priority of a raw message, read on its own so a worker can order messages before parsing
+/// them; `normal` if it has none or isn't a JSON object.
+pub fn priority(message: &str) -> Priority {
+    #[derive(Deserialize)]
+    struct Prioritized {
+        #[serde(default)]
+        priority: Priority,
+    }
+    serde_json::from_str::<Prioritized>(message).map(|p| p.priority).unwrap_or_default()
+}
+
+/// How urgent a job is. A worker with messages prefetched starts the most urgent first, see
+/// `functions/src/main.rs`; it doesn't reorder the queue itself.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+impl Priority {
+    pub fn name(self) -> &'static str {
+        match self {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+            Priority::Low => "low",
+        }
+    }
+
+    fn is_normal(&self) -> bool {
+        *self == Priority::Normal
+    }
+}
+
+impl std::str::FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "high" => Ok(Priority::High),
+            "normal" => Ok(Priority::Normal),
+            "low" => Ok(Priority::Low),
+            _ => Err(format!("Unknown priority '{}', use high, normal or low", s)),
+        }
+    }
+}
+
+/// A pipeline stage run by the worker.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Stage {
+    #[default]
+    Resize,
+    /// Copy the resized rendition into another container, e.g. one fronted by a CDN.
+    Publish { container: String },
+    /// Render the image through a stored template (watermark, text and output size).
+    Render { template: String },
+    /// Render pages `first` to `last` of a PDF, or as many as the preset allows without `last`.
+    Pages {
+        first: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        last: Option<u32>,
+    },
+}
+
+/// Parses the page range of a `pages` stage: `3`, `2-5`, or `2-` for every page from the second.
+fn page_range(range: &str) -> Result<(u32, Option<u32>), String> {
+    let invalid = || format!("Invalid page range '{}', use e.g. 3, 2-5 or 2-", range);
+    let page = |n: &str| n.trim().parse::<u32>().ok().filter(|n| *n > 0).ok_or_else(invalid);
+    let (first, last) = match range.split_once('-') {
+        Some((first, "")) => (page(first)?, None),
+        Some((first, last)) => (page(first)?, Some(page(last)?)),
+        None => (page(range)?, Some(page(range)?)),
+    };
+    if last.is_some_and(|last| last < first) {
+        return Err(invalid());
+    }
+    Ok((first, last))
+}
+
+impl Stage {
+    /// The kind of stage, e.g. `resize`, as a label for metrics and spans.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Resize => "resize",
+            Stage::Publish { .. } => "publish",
+            Stage::Render { .. } => "render",
+            Stage::Pages { .. } => "pages",
+        }
+    }
+}
+
+impl std::str::FromStr for Stage {
+    type Err = String;
+
+    /// Parses `resize`, `publish:<container>`, `render:<template>` or `pages[:<range>]`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "resize" => Ok(Stage::Resize),
+            None if s == "pages" => Ok(Stage::Pages { first: 1, last: None }),
+            Some(("pages", range)) => page_range(range).map(|(first, last)| Stage::Pages { first, last }),
+            Some(("publish", container)) if !container.is_empty() => Ok(Stage::Publish {
+                container: container.to_string(),
+            }),
+            Some(("render", template)) if !template.is_empty() => Ok(Stage::Render {
+                template: template.to_string(),
+            }),
+            _ => Err(format!("Unknown stage: {}", s)),
+        }
+    }
+}
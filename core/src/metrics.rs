@@ -0,0 +1,143 @@
+// core/src/metrics.rs
+
+//! Counters and histograms kept in memory and rendered in the Prometheus text format, served on
+//! the API's `GET /metrics` and by the worker's metrics server. Unlike the App Insights metrics in
+//! `telemetry.rs`, which are only sent when a connection string is set, these are always recorded
+//! and left for Prometheus to scrape. Durations are histograms in seconds.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+/// Upper bounds of the histogram buckets, in seconds.
+const BUCKETS: [f64; 14] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0];
+
+/// Help text of every metric, which is also the list of names [`increment`] and [`observe`] take.
+const HELP: &[(&str, &str)] = &[
+    ("http_requests_total", "HTTP requests answered, by method, route and status"),
+    ("http_request_duration_seconds", "Time to answer an HTTP request, by method and route"),
+    ("uploaded_files_total", "Files stored by an upload, by route"),
+    ("uploaded_bytes_total", "Bytes of the files stored by an upload, by route"),
+    ("jobs_queued_total", "Messages sent to a queue, by queue"),
+    ("queue_send_failures_total", "Messages that failed to be sent to a queue, by queue"),
+    ("reconciled_jobs_total", "Originals queued by the reconciliation sweep, their upload's job never having gone through"),
+    ("queue_claim_checks_total", "Messages too large for the queue sent as a claim check, by queue"),
+    ("chaos_faults_injected_total", "Faults injected by a chaos build, by target, operation and fault"),
+    ("queue_duplicate_sends_total", "Messages sent again within the duplicate detection window, by queue"),
+    ("messages_total", "Messages received by the worker, by queue and outcome"),
+    ("queue_latency_seconds", "Time a message waited before the worker started it, in its queue and prefetched, by queue and priority"),
+    ("stage_duration_seconds", "Time to run a stage, retries included, by stage and outcome"),
+    ("stage_failures_total", "Stages that failed, by stage and whether the failure was permanent"),
+    ("decode_duration_seconds", "Time to decode a source, by source format"),
+    ("resize_duration_seconds", "Time to scale a source to its rendition"),
+    ("encode_duration_seconds", "Time to encode an output, by output format"),
+    ("records_purged_total", "Job status records deleted for being older than their retention, by kind"),
+    ("abandoned_uploads_total", "Abandoned chunked uploads cleaned up, by kind"),
+    ("uploads_shed_total", "Uploads refused while the API was short of memory, by reason"),
+    ("reviews_total", "Uploads held for review, and how each review ended, by outcome"),
+];
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative; the last counts those above every bound.
+    buckets: [u64; BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Default)]
+struct Registry {
+    counters: BTreeMap<(&'static str, String), f64>,
+    histograms: BTreeMap<(&'static str, String), Histogram>,
+}
+
+fn registry() -> &'static Mutex<Registry> {
+    static REGISTRY: OnceLock<Mutex<Registry>> = OnceLock::new();
+    REGISTRY.get_or_init(Mutex::default)
+}
+
+/// Renders labels as `key="value",...`, escaping values as the text format needs.
+fn label_set(labels: &[(&str, &str)]) -> String {
+    let mut rendered = String::new();
+    for (index, (key, value)) in labels.iter().enumerate() {
+        if index > 0 {
+            rendered.push(',');
+        }
+        let value = value.replace('\\', r"\\").replace('"', "\\\"").replace('\n', r"\n");
+        let _ = write!(rendered, "{}=\"{}\"", key, value);
+    }
+    rendered
+}
+
+/// Adds one to the counter `name` with `labels`.
+pub fn increment(name: &'static str, labels: &[(&str, &str)]) {
+    add(name, labels, 1.0);
+}
+
+/// Adds `value` to the counter `name` with `labels`.
+pub fn add(name: &'static str, labels: &[(&str, &str)], value: f64) {
+    debug_assert!(HELP.iter().any(|(known, _)| *known == name), "Undeclared metric {}", name);
+    *registry().lock().unwrap().counters.entry((name, label_set(labels))).or_default() += value;
+}
+
+/// Records `duration` in the histogram `name` with `labels`.
+pub fn observe(name: &'static str, labels: &[(&str, &str)], duration: Duration) {
+    debug_assert!(HELP.iter().any(|(known, _)| *known == name), "Undeclared metric {}", name);
+    let seconds = duration.as_secs_f64();
+    let mut registry = registry().lock().unwrap();
+    let histogram = registry.histograms.entry((name, label_set(labels))).or_default();
+    let bucket = BUCKETS.iter().position(|bound| seconds <= *bound).unwrap_or(BUCKETS.len());
+    histogram.buckets[bucket] += 1;
+    histogram.sum += seconds;
+    histogram.count += 1;
+}
+
+fn header(output: &mut String, name: &str, kind: &str) {
+    let help = HELP.iter().find(|(known, _)| *known == name).map_or("", |(_, help)| help);
+    let _ = writeln!(output, "# HELP {} {}", name, help);
+    let _ = writeln!(output, "# TYPE {} {}", name, kind);
+}
+
+/// Joins a label set with one more label.
+fn with_label(labels: &str, extra: &str) -> String {
+    if labels.is_empty() {
+        extra.to_string()
+    } else {
+        format!("{},{}", labels, extra)
+    }
+}
+
+/// Everything recorded so far, in the Prometheus text exposition format.
+pub fn render() -> String {
+    let registry = registry().lock().unwrap();
+    let mut output = String::new();
+
+    let mut last = None;
+    for ((name, labels), value) in &registry.counters {
+        if last != Some(*name) {
+            header(&mut output, name, "counter");
+            last = Some(*name);
+        }
+        let _ = writeln!(output, "{}{{{}}} {}", name, labels, value);
+    }
+
+    let mut last = None;
+    for ((name, labels), histogram) in &registry.histograms {
+        if last != Some(*name) {
+            header(&mut output, name, "histogram");
+            last = Some(*name);
+        }
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(histogram.buckets) {
+            cumulative += count;
+            let _ = writeln!(output, "{}_bucket{{{}}} {}", name, with_label(labels, &format!("le=\"{}\"", bound)), cumulative);
+        }
+        let _ = writeln!(output, "{}_bucket{{{}}} {}", name, with_label(labels, "le=\"+Inf\""), histogram.count);
+        let _ = writeln!(output, "{}_sum{{{}}} {}", name, labels, histogram.sum);
+        let _ = writeln!(output, "{}_count{{{}}} {}", name, labels, histogram.count);
+    }
+    output
+}
@@ -0,0 +1,132 @@
+// core/src/migrations.rs
+
+//! Schema migrations of the job status table, see `job_status.rs`. Fields added to its records
+//! read with defaults either way, but a migration brings the stored records up to the current
+//! layout, so tools querying the table directly see the same fields on old and new records. The
+//! version the table is at is kept in partition `$schema`, row `version`, which no container name
+//! can clash with.
+//!
+//! The API and the worker both call [`run`] at startup. It applies the migrations newer than the
+//! table's version in order, recording the version after each, so a failed run picks up where it
+//! stopped. Replicas starting together may run the same migration at once, so each must be
+//! idempotent. A build finding the table at a newer version than its own leaves it as it is. A
+//! record a migration changes counts as written then for retention, see `api/src/retention.rs`.
+
+use azure_core::{date, StatusCode};
+use azure_data_tables::{prelude::TableClient, IfMatchCondition};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use time::OffsetDateTime;
+use tracing::{info, warn};
+
+use crate::{
+    job_status::{self, RecordKind},
+    tables,
+};
+
+pub(crate) const SCHEMA_PARTITION: &str = "$schema";
+const VERSION_ROW: &str = "version";
+
+/// What each migration does, oldest first; the first takes the table to version 1.
+const MIGRATIONS: &[&str] = &["Add `warnings` to processing records and `renditions` to jobs written before them"];
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SchemaVersion {
+    #[serde(rename = "PartitionKey")]
+    partition: String,
+    #[serde(rename = "RowKey")]
+    row_key: String,
+    version: u32,
+    /// RFC 3339.
+    migrated_at: String,
+}
+
+/// The version the table is at, 0 before any migration ran.
+async fn version(table_client: &TableClient) -> azure_core::Result<u32> {
+    let entity_client = table_client.partition_key_client(SCHEMA_PARTITION).entity_client(VERSION_ROW);
+    match entity_client.get::<SchemaVersion>().await {
+        Ok(response) => Ok(response.entity.version),
+        Err(e) if tables::is_not_found(&e) => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+async fn record_version(table_client: &TableClient, version: u32) -> azure_core::Result<()> {
+    let entry = SchemaVersion {
+        partition: SCHEMA_PARTITION.to_string(),
+        row_key: VERSION_ROW.to_string(),
+        version,
+        migrated_at: date::to_rfc3339(&OffsetDateTime::now_utc()),
+    };
+    table_client
+        .partition_key_client(SCHEMA_PARTITION)
+        .entity_client(VERSION_ROW)
+        .insert_or_replace(&entry)?
+        .await?;
+    Ok(())
+}
+
+/// Runs the migration to `version`, returning how many records it changed.
+async fn apply(version: u32, table_client: &TableClient) -> azure_core::Result<u64> {
+    match version {
+        1 => {
+            backfill(table_client, |kind| match kind {
+                RecordKind::Processed => Some(("warnings", Value::from("[]"))),
+                RecordKind::Job => Some(("renditions", Value::from(""))),
+                RecordKind::Cancellation | RecordKind::Expiry | RecordKind::Provenance | RecordKind::Review => None,
+            })
+            .await
+        }
+        _ => unreachable!("No migration to version {}", version),
+    }
+}
+
+/// Adds the property `field` gives records of their kind to those lacking it.
+async fn backfill(table_client: &TableClient, field: impl Fn(RecordKind) -> Option<(&'static str, Value)>) -> azure_core::Result<u64> {
+    let mut changed = 0;
+    let mut pages = table_client.query().into_stream::<Map<String, Value>>();
+    while let Some(page) = pages.next().await {
+        for entity in page?.entities {
+            let text = |key: &str| entity.get(key).and_then(Value::as_str).map(str::to_string);
+            let (Some(partition), Some(row_key), Some(etag)) = (text("PartitionKey"), text("RowKey"), text("odata.etag")) else {
+                continue;
+            };
+            let Some((name, value)) = RecordKind::of(&partition).and_then(&field) else {
+                continue;
+            };
+            if entity.contains_key(name) {
+                continue;
+            }
+            let patch = Map::from_iter([(name.to_string(), value)]);
+            let entity_client = table_client.partition_key_client(&partition).entity_client(&row_key);
+            match entity_client.merge(&patch, IfMatchCondition::Etag(etag.into()))?.await {
+                Ok(_) => changed += 1,
+                // rewritten or deleted meanwhile, by code that writes every field
+                Err(e) if e.as_http_error().is_some_and(|e| e.status() == StatusCode::PreconditionFailed) => {}
+                Err(e) if tables::is_not_found(&e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(changed)
+}
+
+/// Brings the job status table up to the latest schema version.
+pub async fn run() -> azure_core::Result<()> {
+    let table_client = job_status::table_client();
+    tables::create_if_missing(&table_client).await?;
+    let current = version(&table_client).await?;
+    let latest = MIGRATIONS.len() as u32;
+    if current > latest {
+        warn!("The job status table is at schema version {}, newer than this build's {}", current, latest);
+        return Ok(());
+    }
+    for version in current + 1..=latest {
+        info!("Migrating the job status table to version {}: {}", version, MIGRATIONS[version as usize - 1]);
+        let changed = apply(version, &table_client).await?;
+        record_version(&table_client, version).await?;
+        info!("Migrated the job status table to version {}, {} records changed", version, changed);
+    }
+    Ok(())
+}
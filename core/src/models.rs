@@ -0,0 +1,339 @@
+// core/src/models.rs
+
+//! Request and response bodies of the upload API, shared by the server and [`crate::client`] so
+//! the two can't drift apart.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::{job_status::{JobState, RenditionState}, message::Stage, warnings::Warning};
+
+/// Per-upload processing options, passed as query parameters on `/upload`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UploadOptions {
+    /// Run levels, white balance and gamma correction before resizing.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub enhance: bool,
+    /// Comma separated follow-up stages to run after the resize, e.g. `publish:cdn`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub then: Option<String>,
+    /// Size of the resized rendition, 100x100 by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    /// Size the resized rendition by percentage instead, e.g. `50`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scale: Option<f32>,
+    /// Size the resized rendition by the pixel length of its longest or shortest edge instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub longest_edge: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shortest_edge: Option<u32>,
+    /// How the resized rendition fills its box: `contain`, `cover` or `exact`; the resize preset's by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fit: Option<String>,
+    /// Resampling filter: `nearest`, `triangle` (the default) or `lanczos3`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+    /// Size variants made alongside the resized rendition as `name:width`, e.g. `thumb:128,large:1024`;
+    /// `RESIZE_VARIANTS` by default, none if empty.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variants: Option<String>,
+    /// Format of the renditions, `jpeg`, `png` or `webp`; the source's where possible by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_format: Option<String>,
+    /// Region to make the renditions from, as `x,y,width,height` in pixels of the source.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crop: Option<String>,
+    /// Read `crop` as fractions of the source's width and height instead.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub crop_normalized: bool,
+    /// Point fill renditions are centered on, as `x,y` percentages of the source, e.g. `30,40`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub focal_point: Option<String>,
+    /// Comma separated search tags, e.g. `beach,summer`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tags: Option<String>,
+    /// Custom metadata as a JSON object of strings, e.g. `{"project":"spring"}`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<String>,
+    /// Largest acceptable size of each rendition, in bytes or with a `KB`/`MB` suffix, e.g. `150KB`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_size: Option<String>,
+    /// URL the worker posts a signed notice to when the job ends, see `core/src/webhook.rs`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub callback_url: Option<String>,
+    /// `high`, `normal` (the default) or `low`, the order a worker starts the jobs it has prefetched in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<String>,
+    /// Validate and describe the outputs without storing or queueing anything.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub dry_run: bool,
+    /// Store and queue the files of a multi-file `/upload` all together or not at all.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub atomic: bool,
+    /// Add each rendition to the job as soon as it's stored, smallest first, for `GET /jobs/{id}`
+    /// and `GET /jobs/{id}/events` to show before the whole set is done.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub incremental: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+/// A blob holding an upload's content, or a rendition made of it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExistingBlob {
+    pub blob: String,
+    pub url: String,
+}
+
+/// An original already holding an upload's content, with the renditions made of it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Existing {
+    pub original: ExistingBlob,
+    pub renditions: Vec<ExistingBlob>,
+}
+
+/// A file of an upload that duplicates an original already processed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Duplicate {
+    pub filename: String,
+    pub existing: Existing,
+}
+
+/// Answer to an upload: the files stored with the id of the job processing each, and those that
+/// duplicated an original when the tenant skips duplicates.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UploadReport {
+    pub uploaded: Vec<String>,
+    pub duplicates: Vec<Duplicate>,
+    /// Job id per stored file, to poll on `GET /jobs/{id}`.
+    #[serde(default)]
+    pub jobs: BTreeMap<String, String>,
+    /// Blob each file was stored as, where that isn't its filename, see `BLOB_NAMING` and `naming.rs`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub blobs: BTreeMap<String, String>,
+    /// Renditions each file's job will make, for files the upload gave hints for.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub renditions: BTreeMap<String, Vec<PredictedRendition>>,
+    /// What was found and done for each file part, in the order of the request.
+    #[serde(default)]
+    pub parts: Vec<PartReport>,
+}
+
+/// One file part of an upload as the API took it in. Only what was read of the part is reported,
+/// never its content.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PartReport {
+    pub filename: String,
+    /// Blob holding the file: the one it was stored as, the one a repeat of the request shares, or
+    /// the existing original it duplicates.
+    pub blob: String,
+    /// Size of the part as received.
+    pub bytes: u64,
+    /// Content type detected from the file's signature, not the one the client sent.
+    pub content_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    /// Job processing the file, none for a duplicate of an earlier upload.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<String>,
+    /// Changes made to the file before it was stored, see `warnings.rs`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<Warning>,
+}
+
+/// What became of one file of an atomic upload that failed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FileOutcome {
+    /// Stored, and removed again once a later file failed.
+    RolledBack,
+    /// The file the upload failed on.
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileReport {
+    pub filename: String,
+    pub outcome: FileOutcome,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Answer to an atomic upload that failed: nothing of it was kept, and jobs queued before a send
+/// failed were cancelled. Files after the one
+/// that failed weren't read and aren't listed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RolledBackUpload {
+    pub error: String,
+    pub files: Vec<FileReport>,
+}
+
+/// A rendition a job is expected to make, answered before the worker has written it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PredictedRendition {
+    /// `resize`, `variant:<name>`, `publish:<container>` or `render:<template>`.
+    pub stage: String,
+    pub container: String,
+    pub blob: String,
+    /// Reads the rendition once it's written, 404 until then.
+    pub url: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// A stored original, as listed by `GET /images`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImageSummary {
+    pub name: String,
+    pub content_type: String,
+    pub size: u64,
+    /// RFC 3339.
+    pub last_modified: String,
+    pub tags: Vec<String>,
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// Processing state of an original, from `GET /images/{name}/status`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JobStatus {
+    pub name: String,
+    pub processed: bool,
+    /// Whether the last successful run saw the original as it is now.
+    pub up_to_date: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub processed_at: Option<String>,
+    /// When the worker last skipped a job for the original as stale, if that was after the last
+    /// successful run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expired_at: Option<String>,
+    pub warnings: Vec<Warning>,
+}
+
+/// The renditions made from an original, from `GET /images/{name}/provenance`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProvenanceTree {
+    pub name: String,
+    /// Etag of the original as it is now.
+    pub etag: String,
+    /// The renditions made from the original directly, each with those derived from it.
+    pub renditions: Vec<DerivedRendition>,
+}
+
+/// A rendition in a [`ProvenanceTree`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DerivedRendition {
+    pub container: String,
+    pub blob: String,
+    /// As in `blob_tags`.
+    pub preset: String,
+    pub pipeline_version: String,
+    /// Etag of the original the rendition was made from.
+    pub source_etag: String,
+    /// Whether it was made from the original as it is now.
+    pub up_to_date: bool,
+    /// RFC 3339.
+    pub created_at: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub derived: Vec<DerivedRendition>,
+}
+
+/// A job processing one queued image, from `GET /jobs/{id}`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Job {
+    pub id: String,
+    pub container: String,
+    pub filename: String,
+    pub state: JobState,
+    /// URLs of the blobs the job wrote so far.
+    pub outputs: Vec<String>,
+    /// The state of each rendition by blob name, when the job's resize stage ran out of time.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub renditions: BTreeMap<String, RenditionState>,
+    /// Why the last attempt failed; a failed job may still be retried from the queue.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The stage queued or running, or the one that failed; none once the chain is done.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stage: Option<Stage>,
+    /// Stages still to run after it, in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub then: Vec<Stage>,
+    /// Stages already run, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub completed: Vec<Stage>,
+    /// RFC 3339.
+    pub created_at: String,
+    /// RFC 3339.
+    pub updated_at: String,
+}
+
+/// An upload held for review, from `GET /admin/reviews`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingUpload {
+    pub job_id: String,
+    pub container: String,
+    pub filename: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// Whether the moderation pass flagged it, so only an admin can approve it.
+    pub flagged: bool,
+    /// RFC 3339.
+    pub created_at: String,
+    /// RFC 3339; it's discarded if not approved by then.
+    pub expires_at: String,
+}
+
+/// What an admin decided for an upload held for review.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReviewDecision {
+    Approved,
+    Rejected,
+}
+
+/// Reply of `POST /admin/reviews/{job_id}/approve` and `/reject`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReviewOutcome {
+    pub job_id: String,
+    pub filename: String,
+    pub decision: ReviewDecision,
+}
+
+/// Reply of `DELETE /jobs/{name}`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JobCancellation {
+    pub name: String,
+    /// RFC 3339; jobs for the original queued up to then are abandoned.
+    pub cancelled_at: String,
+}
+
+/// Paging parameters every list endpoint takes, see `api/src/paging.rs`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PageQuery {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    /// `name`, `size` or `modified`, where the endpoint has them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order_by: Option<String>,
+    /// `asc` or `desc`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub direction: Option<String>,
+    /// The `next_cursor` of the previous page.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+}
+
+/// One page of a listing.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
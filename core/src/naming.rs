@@ -0,0 +1,301 @@
+// core/src/naming.rs
+
+//! Names originals are stored under, by the strategy `BLOB_NAMING` picks:
+//!
+//! - `original` (the default): the filename they were uploaded with, so two uploads of
+//!   `photo.jpg` to one container get the same name;
+//! - `uuid`: a random UUID keeping the filename's extension, `<uuid>.jpg`, which never collides;
+//! - `content`: the SHA-256 of the content keeping the extension, `<sha256>.jpg`, so identical
+//!   content always lands on the same blob and a second upload of it is a duplicate rather than a
+//!   collision;
+//! - `date`: the filename under the folder of the day it was uploaded (UTC), `2026/10/15/photo.jpg`.
+//!
+//! With `original` and `date`, `BLOB_NAME_COLLISIONS` says what happens to an upload whose name is
+//! taken: `replace` (the default) overwrites the original, `suffix` tries `photo-1.jpg`,
+//! `photo-2.jpg` and so on, see [`suffixed`], and `reject` refuses it. Renditions are named after
+//! the original whatever the strategy, see [`rendition`], so the worker, the dedup logic and the
+//! routes finding them need no setting of their own. Another scheme can be added by implementing
+//! [`NamingStrategy`] and naming it in [`from_name`].
+
+use std::{env, fmt, sync::OnceLock};
+use time::{Date, OffsetDateTime};
+use uuid::Uuid;
+
+/// Prefix of an original's resized rendition, `resized_<original>`.
+pub const RESIZED_PREFIX: &str = "resized_";
+/// Most suffixes tried for a name that's taken, see [`Collisions::Suffix`].
+pub const MAX_SUFFIX: u32 = 100;
+/// Longest extension kept on a generated name.
+const MAX_EXTENSION_LEN: usize = 8;
+
+/// How originals are named.
+pub trait NamingStrategy: Send + Sync + fmt::Debug {
+    /// The value of `BLOB_NAMING` picking it.
+    fn name(&self) -> &'static str;
+
+    /// The name of an original uploaded as `filename`. `sha256`, the hash of its content, is
+    /// given when [`needs_content`](Self::needs_content) says so.
+    fn original(&self, filename: &str, sha256: Option<&str>) -> String;
+
+    /// Whether the name is made from the content, which must then be hashed before it's named.
+    fn needs_content(&self) -> bool {
+        false
+    }
+
+    /// Whether two different originals may be given the same name.
+    fn may_collide(&self) -> bool {
+        true
+    }
+}
+
+/// The extension of `filename` in lowercase, if it's short and plain enough to keep.
+///
+/// ```
+/// use image_resize_core::naming::extension;
+///
+/// assert_eq!(extension("Photo.JPG").as_deref(), Some("jpg"));
+/// assert_eq!(extension("albums/photo.tar.gz").as_deref(), Some("gz"));
+/// assert_eq!(extension("photo"), None);
+/// assert_eq!(extension("photo.<script>"), None);
+/// ```
+pub fn extension(filename: &str) -> Option<String> {
+    filename
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase())
+        .filter(|extension| {
+            !extension.is_empty() && extension.len() <= MAX_EXTENSION_LEN && extension.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+}
+
+/// `stem` with the extension of `filename`.
+fn with_extension(stem: &str, filename: &str) -> String {
+    match extension(filename) {
+        Some(extension) => format!("{}.{}", stem, extension),
+        None => stem.to_string(),
+    }
+}
+
+/// The filename as it was uploaded.
+#[derive(Debug)]
+pub struct Original;
+
+impl NamingStrategy for Original {
+    fn name(&self) -> &'static str {
+        "original"
+    }
+
+    fn original(&self, filename: &str, _: Option<&str>) -> String {
+        filename.to_string()
+    }
+}
+
+/// A random UUID with the filename's extension.
+#[derive(Debug)]
+pub struct Random;
+
+impl NamingStrategy for Random {
+    fn name(&self) -> &'static str {
+        "uuid"
+    }
+
+    fn original(&self, filename: &str, _: Option<&str>) -> String {
+        with_extension(&Uuid::new_v4().to_string(), filename)
+    }
+
+    fn may_collide(&self) -> bool {
+        false
+    }
+}
+
+/// The SHA-256 of the content with the filename's extension.
+///
+/// ```
+/// use image_resize_core::naming::{ContentHash, NamingStrategy};
+///
+/// assert_eq!(ContentHash.original("Photo.JPG", Some("3a7b")), "3a7b.jpg");
+/// assert_eq!(ContentHash.original("README", Some("3a7b")), "3a7b");
+/// // identical content shares a name, so it's never a collision
+/// assert_eq!(ContentHash.original("a.png", Some("3a7b")), ContentHash.original("b.png", Some("3a7b")));
+/// assert!(!ContentHash.may_collide());
+/// ```
+#[derive(Debug)]
+pub struct ContentHash;
+
+impl NamingStrategy for ContentHash {
+    fn name(&self) -> &'static str {
+        "content"
+    }
+
+    fn original(&self, filename: &str, sha256: Option<&str>) -> String {
+        with_extension(sha256.expect("Content-addressed names need the content's hash"), filename)
+    }
+
+    fn needs_content(&self) -> bool {
+        true
+    }
+
+    fn may_collide(&self) -> bool {
+        false
+    }
+}
+
+/// The filename under the folder of the day, `<year>/<month>/<day>/<filename>`.
+#[derive(Debug)]
+pub struct DateFolder;
+
+impl DateFolder {
+    /// The name of `filename` uploaded on `date`.
+    ///
+    /// ```
+    /// use image_resize_core::naming::DateFolder;
+    /// use time::{Date, Month};
+    ///
+    /// let date = Date::from_calendar_date(2026, Month::March, 5).unwrap();
+    /// assert_eq!(DateFolder::name_on("photo.jpg", date), "2026/03/05/photo.jpg");
+    /// assert_eq!(DateFolder::name_on("albums/photo.jpg", date), "2026/03/05/albums/photo.jpg");
+    /// ```
+    pub fn name_on(filename: &str, date: Date) -> String {
+        format!("{:04}/{:02}/{:02}/{}", date.year(), u8::from(date.month()), date.day(), filename)
+    }
+}
+
+impl NamingStrategy for DateFolder {
+    fn name(&self) -> &'static str {
+        "date"
+    }
+
+    fn original(&self, filename: &str, _: Option<&str>) -> String {
+        DateFolder::name_on(filename, OffsetDateTime::now_utc().date())
+    }
+}
+
+/// The strategy called `name`.
+///
+/// ```
+/// use image_resize_core::naming::from_name;
+///
+/// for name in ["original", "uuid", "content", "date"] {
+///     assert_eq!(from_name(name).unwrap().name(), name);
+/// }
+/// assert!(from_name("sequential").is_none());
+/// // a random name doesn't repeat, keeping the extension
+/// let uuid = from_name("uuid").unwrap();
+/// let (a, b) = (uuid.original("photo.jpg", None), uuid.original("photo.jpg", None));
+/// assert_ne!(a, b);
+/// assert!(a.ends_with(".jpg") && a.len() == 40);
+/// ```
+pub fn from_name(name: &str) -> Option<Box<dyn NamingStrategy>> {
+    match name {
+        "original" => Some(Box::new(Original)),
+        "uuid" => Some(Box::new(Random)),
+        "content" => Some(Box::new(ContentHash)),
+        "date" => Some(Box::new(DateFolder)),
+        _ => None,
+    }
+}
+
+/// The strategy `BLOB_NAMING` picks, read once.
+pub fn strategy() -> &'static dyn NamingStrategy {
+    static STRATEGY: OnceLock<Box<dyn NamingStrategy>> = OnceLock::new();
+    STRATEGY
+        .get_or_init(|| match env::var("BLOB_NAMING") {
+            Ok(name) => from_name(&name).unwrap_or_else(|| panic!("Invalid BLOB_NAMING: {}", name)),
+            Err(_) => Box::new(Original),
+        })
+        .as_ref()
+}
+
+/// What happens to an upload whose name is taken by another original.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Collisions {
+    /// The upload overwrites it.
+    Replace,
+    /// The upload takes the first free name of [`suffixed`].
+    Suffix,
+    /// The upload is refused.
+    Reject,
+}
+
+/// The collision handling `BLOB_NAME_COLLISIONS` picks, read once.
+pub fn collisions() -> Collisions {
+    static COLLISIONS: OnceLock<Collisions> = OnceLock::new();
+    *COLLISIONS.get_or_init(|| match env::var("BLOB_NAME_COLLISIONS").as_deref() {
+        Err(_) | Ok("replace") => Collisions::Replace,
+        Ok("suffix") => Collisions::Suffix,
+        Ok("reject") => Collisions::Reject,
+        Ok(other) => panic!("Invalid BLOB_NAME_COLLISIONS: {}", other),
+    })
+}
+
+/// `name` with `-<n>` before its extension, the `n`th name tried when `name` is taken.
+///
+/// ```
+/// use image_resize_core::naming::suffixed;
+///
+/// assert_eq!(suffixed("photo.jpg", 1), "photo-1.jpg");
+/// assert_eq!(suffixed("photo.tar.gz", 2), "photo.tar-2.gz");
+/// assert_eq!(suffixed("2026/03/05/photo.jpg", 3), "2026/03/05/photo-3.jpg");
+/// // a dot in a folder or leading a name isn't an extension
+/// assert_eq!(suffixed("v1.2/photo", 1), "v1.2/photo-1");
+/// assert_eq!(suffixed(".profile", 1), ".profile-1");
+/// ```
+pub fn suffixed(name: &str, n: u32) -> String {
+    let (folder, base) = match name.rsplit_once('/') {
+        Some((folder, base)) => (Some(folder), base),
+        None => (None, name),
+    };
+    let base = match base.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => format!("{}-{}.{}", stem, n, extension),
+        _ => format!("{}-{}", base, n),
+    };
+    match folder {
+        Some(folder) => format!("{}/{}", folder, base),
+        None => base,
+    }
+}
+
+/// The first name of `name` and its [`suffixed`] forms that `is_taken` says is free, or `None`
+/// when [`MAX_SUFFIX`] of them are taken.
+///
+/// ```
+/// use image_resize_core::naming::free_name;
+/// use std::collections::HashSet;
+///
+/// # tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(async {
+/// let taken: HashSet<&str> = ["photo.jpg", "photo-1.jpg"].into();
+/// let free = free_name("photo.jpg", |name| {
+///     let taken = taken.contains(name.as_str());
+///     async move { Ok::<_, ()>(taken) }
+/// })
+/// .await;
+/// assert_eq!(free, Ok(Some("photo-2.jpg".to_string())));
+///
+/// let untaken = free_name("new.jpg", |_| async { Ok::<_, ()>(false) }).await;
+/// assert_eq!(untaken, Ok(Some("new.jpg".to_string())));
+///
+/// let full = free_name("photo.jpg", |_| async { Ok::<_, ()>(true) }).await;
+/// assert_eq!(full, Ok(None));
+/// # });
+/// ```
+pub async fn free_name<F, Fut, E>(name: &str, mut is_taken: F) -> Result<Option<String>, E>
+where
+    F: FnMut(String) -> Fut,
+    Fut: std::future::Future<Output = Result<bool, E>>,
+{
+    for candidate in std::iter::once(name.to_string()).chain((1..=MAX_SUFFIX).map(|n| suffixed(name, n))) {
+        if !is_taken(candidate.clone()).await? {
+            return Ok(Some(candidate));
+        }
+    }
+    Ok(None)
+}
+
+/// The name of the rendition of `original` made with `prefix`, e.g. `resized_photo.jpg`.
+pub fn rendition(prefix: &str, original: &str) -> String {
+    format!("{}{}", prefix, original)
+}
+
+/// The name of the resized rendition of `original`.
+pub fn resized(original: &str) -> String {
+    rendition(RESIZED_PREFIX, original)
+}
@@ -0,0 +1,137 @@
+// core/src/operations.rs
+
+//! An ordered list of edits an upload asks for, carried in the queue message and applied by the
+//! worker to the source one after the other before it's scaled and encoded, e.g.
+//! `[{"op":"crop","x":0,"y":0,"w":800,"h":600},{"op":"rotate","deg":90},{"op":"resize","w":400},
+//! {"op":"watermark","blob":"logo.png","pos":"br"}]`. Coordinates are pixels of the image as the
+//! operations before have left it. A `resize` in the list replaces the sizing of the resized
+//! rendition, which is then the list's output as it is.
+
+use serde::{Deserialize, Serialize};
+
+use crate::image_checks::ImageLimits;
+
+/// Most operations accepted in one list.
+pub const MAX_OPERATIONS: usize = 16;
+
+/// The `op` of each [`Operation`], see `capabilities.rs`.
+pub const NAMES: [&str; 5] = ["crop", "rotate", "grayscale", "resize", "watermark"];
+
+/// Gap between an overlay and the image edge, in pixels.
+const MARGIN: i64 = 10;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    /// Cuts out the `w` x `h` region at `x`, `y`.
+    Crop { x: u32, y: u32, w: u32, h: u32 },
+    /// Turns the image clockwise by a multiple of 90 degrees, negative turning it counterclockwise.
+    Rotate { deg: i32 },
+    Grayscale,
+    /// Scales the image to `w` wide or `h` high keeping its aspect ratio, or to fit within both.
+    Resize {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        w: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        h: Option<u32>,
+    },
+    /// Draws the image stored as `blob` in the image's container over it.
+    Watermark {
+        blob: String,
+        #[serde(default)]
+        pos: Position,
+        /// Watermark width as a fraction of the image width.
+        #[serde(default = "default_scale")]
+        scale: f32,
+        #[serde(default = "default_opacity")]
+        opacity: f32,
+    },
+}
+
+fn default_scale() -> f32 {
+    0.25
+}
+
+fn default_opacity() -> f32 {
+    0.8
+}
+
+/// Where an overlay is drawn on an image.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum Position {
+    #[serde(rename = "tl")]
+    TopLeft,
+    #[serde(rename = "tr")]
+    TopRight,
+    #[serde(rename = "bl")]
+    BottomLeft,
+    #[default]
+    #[serde(rename = "br")]
+    BottomRight,
+    #[serde(rename = "center")]
+    Center,
+}
+
+impl Position {
+    /// Top-left coordinate for an overlay of `(width, height)` on a canvas of `(canvas_width, canvas_height)`.
+    pub fn origin(self, canvas: (u32, u32), overlay: (u32, u32)) -> (i64, i64) {
+        let (cw, ch) = (canvas.0 as i64, canvas.1 as i64);
+        let (w, h) = (overlay.0 as i64, overlay.1 as i64);
+        match self {
+            Position::TopLeft => (MARGIN, MARGIN),
+            Position::TopRight => (cw - w - MARGIN, MARGIN),
+            Position::BottomLeft => (MARGIN, ch - h - MARGIN),
+            Position::BottomRight => (cw - w - MARGIN, ch - h - MARGIN),
+            Position::Center => ((cw - w) / 2, (ch - h) / 2),
+        }
+    }
+}
+
+impl Operation {
+    /// Checks the operation's parameters, `limits` bounding the size a `resize` may ask for.
+    pub fn validate(&self, limits: &ImageLimits) -> Result<(), String> {
+        match self {
+            Operation::Crop { w, h, .. } if *w == 0 || *h == 0 => Err("A crop needs a positive w and h".to_string()),
+            Operation::Rotate { deg } if deg % 90 != 0 => {
+                Err(format!("Cannot rotate by {} degrees, use a multiple of 90", deg))
+            }
+            Operation::Resize { w: None, h: None } => Err("A resize needs w, h or both".to_string()),
+            Operation::Resize { w, h } => {
+                if *w == Some(0) || *h == Some(0) {
+                    return Err("A resize needs a positive w and h".to_string());
+                }
+                limits.check("A resize", w.unwrap_or(1), h.unwrap_or(1))
+            }
+            Operation::Watermark { blob, .. } if blob.is_empty() || blob.starts_with('/') || blob.contains("..") => {
+                Err(format!("Invalid watermark blob '{}'", blob))
+            }
+            Operation::Watermark { scale, .. } if !(*scale > 0.0 && *scale <= 1.0) => {
+                Err("A watermark's scale must be above 0 and at most 1".to_string())
+            }
+            Operation::Watermark { opacity, .. } if !(0.0..=1.0).contains(opacity) => {
+                Err("A watermark's opacity must be between 0 and 1".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Parses and checks a JSON list of operations.
+pub fn parse(json: &str) -> Result<Vec<Operation>, String> {
+    let operations: Vec<Operation> = serde_json::from_str(json).map_err(|e| format!("Invalid operations: {}", e))?;
+    if operations.len() > MAX_OPERATIONS {
+        return Err(format!("At most {} operations are allowed", MAX_OPERATIONS));
+    }
+    let limits = ImageLimits::from_env();
+    for (index, operation) in operations.iter().enumerate() {
+        operation
+            .validate(&limits)
+            .map_err(|e| format!("Operation #{}: {}", index + 1, e))?;
+    }
+    Ok(operations)
+}
+
+/// Whether the list sizes the image itself.
+pub fn resizes(operations: &[Operation]) -> bool {
+    operations.iter().any(|operation| matches!(operation, Operation::Resize { .. }))
+}
@@ -0,0 +1,85 @@
+// core/src/output_format.rs
+
+//! Formats renditions are encoded in. By default a rendition keeps the format of its source where
+//! the worker can write it: PNG and GIF sources give PNG renditions, keeping their transparency,
+//! WebP sources give WebP, rasterized SVGs give PNG, and everything else, video poster frames and PDF pages included, gives
+//! JPEG. Uploads may ask for one with `output_format`. PNG and WebP renditions are lossless, the
+//! image crate having no lossy WebP encoder.
+
+use image::ImageFormat;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+use crate::{capabilities, svg};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    Jpeg,
+    Png,
+    Webp,
+}
+
+impl OutputFormat {
+    /// Every format renditions can be encoded in, see `capabilities.rs`.
+    pub const ALL: [OutputFormat; 3] = [OutputFormat::Jpeg, OutputFormat::Png, OutputFormat::Webp];
+
+    /// The name `output_format` takes.
+    pub fn name(self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "jpeg",
+            OutputFormat::Png => "png",
+            OutputFormat::Webp => "webp",
+        }
+    }
+
+    pub fn lossless(self) -> bool {
+        self != OutputFormat::Jpeg
+    }
+
+    /// The format renditions of `source` keep by default.
+    pub fn of_source(source: &[u8]) -> Self {
+        match image::guess_format(source) {
+            Ok(ImageFormat::Png | ImageFormat::Gif) => OutputFormat::Png,
+            Ok(ImageFormat::WebP) => OutputFormat::Webp,
+            _ if svg::is_svg(source) => OutputFormat::Png,
+            _ => OutputFormat::Jpeg,
+        }
+    }
+
+    pub fn image_format(self) -> ImageFormat {
+        match self {
+            OutputFormat::Jpeg => ImageFormat::Jpeg,
+            OutputFormat::Png => ImageFormat::Png,
+            OutputFormat::Webp => ImageFormat::WebP,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        self.image_format().to_mime_type()
+    }
+
+    /// The content type of an encoded rendition, known from its magic bytes.
+    pub fn content_type_of(encoded: &[u8]) -> &'static str {
+        match image::guess_format(encoded) {
+            Ok(ImageFormat::Png) => OutputFormat::Png.content_type(),
+            Ok(ImageFormat::WebP) => OutputFormat::Webp.content_type(),
+            // animated renditions, see `functions/src/animation.rs`
+            Ok(ImageFormat::Gif) => ImageFormat::Gif.to_mime_type(),
+            _ => OutputFormat::Jpeg.content_type(),
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.trim().to_ascii_lowercase();
+        let name = if name == "jpg" { "jpeg" } else { name.as_str() };
+        OutputFormat::ALL
+            .into_iter()
+            .find(|format| format.name() == name)
+            .ok_or_else(|| format!("Unknown output_format '{}', use {}", s, capabilities::one_of(OutputFormat::ALL.map(OutputFormat::name))))
+    }
+}
@@ -0,0 +1,16 @@
+// core/src/pdf.rs
+
+//! PDF uploads, whose pages the worker renders to images with poppler's `pdftoppm` behind the
+//! `pdf_pages` feature: the first page for the usual renditions, a requested page range for the
+//! `pages` stage. Like videos, they are told apart from images by their leading bytes.
+
+pub const CONTENT_TYPE: &str = "application/pdf";
+pub const EXTENSION: &str = "pdf";
+/// Configuration of the `pages` stage, `{"dpi": 150, "max_pages": 50, "jpeg": {..}}`; optional.
+pub const PAGES_PRESET: &str = "presets/pages.json";
+
+const MAGIC: &[u8] = b"%PDF-";
+
+pub fn is_pdf(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
@@ -0,0 +1,85 @@
+// core/src/pipeline.rs
+
+//! Pipeline versions stamped on renditions, so renditions made by older processing code or from
+//! an older preset definition can be found and regenerated. A version hashes [`REVISION`], the
+//! preset and the preset's definition (the template JSON for `render:<template>`, the
+//! [`RESIZE_PRESET`] blob, or nothing without one, for `resized`); the worker writes it under
+//! [`VERSION_KEY`] and the API recomputes it to compare.
+
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+
+/// Bump whenever a change to the worker alters what a preset produces.
+pub const REVISION: u32 = 5;
+
+/// Preset configuration blobs live under this prefix in the image's container.
+pub const PRESETS_PREFIX: &str = "presets/";
+/// Renditions of a set are written under this prefix until the whole set is published.
+pub const STAGING_PREFIX: &str = "staging/";
+/// Messages whose stage failed for good are recorded under this prefix, see the worker's `dead_letter.rs`.
+pub const FAILED_PREFIX: &str = "failed/";
+/// Configuration of the `resize` preset, `{"jpeg": {..}, "match_orientation": bool, "fit": ..}`; optional.
+pub const RESIZE_PRESET: &str = "presets/resize.json";
+
+/// Blob metadata keys on renditions.
+pub const VERSION_KEY: &str = "pipeline_version";
+/// Parameters of the request that produced a resized rendition, reused when regenerating it.
+pub const WIDTH_KEY: &str = "rendition_width";
+pub const HEIGHT_KEY: &str = "rendition_height";
+pub const ENHANCE_KEY: &str = "rendition_enhance";
+pub const TARGET_SIZE_KEY: &str = "rendition_target_size";
+/// A `resize_spec::ResizeSpec` as JSON, when one replaced the width and height.
+pub const RESIZE_KEY: &str = "rendition_resize";
+/// The upload's `resize_spec::Fit` and `resize_spec::Filter`, when it gave them.
+pub const FIT_KEY: &str = "rendition_fit";
+pub const FILTER_KEY: &str = "rendition_filter";
+/// The `variants::Variant`s made alongside as JSON, when there were any.
+pub const VARIANTS_KEY: &str = "rendition_variants";
+/// The `output_format::OutputFormat` the upload asked for as JSON, when it did.
+pub const OUTPUT_FORMAT_KEY: &str = "rendition_output_format";
+/// A `crop::Crop` as JSON, when the upload asked for one.
+pub const CROP_KEY: &str = "rendition_crop";
+/// A `crop::FocalPoint` as `x,y`, when the upload gave one.
+pub const FOCAL_POINT_KEY: &str = "rendition_focal_point";
+/// The `operations::Operation`s the upload asked for as JSON, when there were any.
+pub const OPERATIONS_KEY: &str = "rendition_operations";
+/// Where a rendition came from, see `job_status::Provenance`: the original's name, percent-encoded
+/// by [`metadata_name`], and the etag it had when read.
+pub const SOURCE_KEY: &str = "source_blob";
+pub const SOURCE_ETAG_KEY: &str = "source_etag";
+/// The preset (as in `blob_tags`) that made the rendition.
+pub const PRESET_KEY: &str = "rendition_preset";
+/// The rendition this one was derived from, percent-encoded like [`SOURCE_KEY`], when it wasn't
+/// made from the original directly, e.g. the resized rendition a published copy is of.
+pub const PARENT_KEY: &str = "parent_rendition";
+/// SHA-256 of the rendition's bytes. Encodes are deterministic, so identical inputs under the same
+/// version give identical hashes.
+pub const CONTENT_HASH_KEY: &str = "content_sha256";
+
+/// `name` percent-encoded where it isn't printable ASCII, metadata travelling as HTTP headers.
+pub fn metadata_name(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        if (byte.is_ascii_graphic() && byte != b'%') || byte == b' ' {
+            encoded.push(byte as char);
+        } else {
+            let _ = write!(encoded, "%{:02X}", byte);
+        }
+    }
+    encoded
+}
+
+/// Hex SHA-256 of a rendition's bytes, see [`CONTENT_HASH_KEY`].
+pub fn content_hash(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+/// Version of `preset` (as in `blob_tags`) under `definition`, as 16 hex digits.
+pub fn version(preset: &str, definition: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(REVISION.to_be_bytes());
+    hasher.update(preset.as_bytes());
+    hasher.update([0]);
+    hasher.update(definition);
+    hex::encode(&hasher.finalize()[..8])
+}
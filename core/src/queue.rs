@@ -0,0 +1,342 @@
+// core/src/queue.rs
+
+//! Sending jobs to the Service Bus queue and receiving them. The SDK's `QueueClient` can't set
+//! broker properties and only signs with a shared access policy, so messages are posted and
+//! received here, signed the same way or carrying a token of the identity, see `identity.rs`.
+//!
+//! With `QUEUE_MESSAGE_TTL_SECS` set, each message carries that `TimeToLive`. Service Bus drops a
+//! message, or dead-letters it if the queue is set up to, once it has waited that long without
+//! being received. [`QueueSender::send_after`] sets a `ScheduledEnqueueTimeUtc`, keeping the
+//! message invisible until then. With `QUEUE_SHARDS` set, messages are sent to the shard of their
+//! image, see [`QueueSender::for_image`] and `shards.rs`.
+//!
+//! Every message carries a `MessageId` derived from its content, see
+//! [`ImageMessage::message_id`], so a queue created with duplicate detection drops a second copy
+//! sent within its detection window, e.g. when a send that timed out after reaching the broker is
+//! retried. The broker accepts the copy all the same, so the drop can't be seen in the reply.
+//! Instead each process remembers the ids it sent within `QUEUE_DUPLICATE_WINDOW_SECS` (default
+//! 600, the queue's default window), logs a resend as a duplicate and counts it in
+//! `queue_duplicate_sends_total`. A message too large for the queue is sent as a claim check, see
+//! `claim_check.rs`.
+
+use azure_core::{
+    auth::Secret,
+    base64, date,
+    headers::{HeaderName, LOCATION},
+    HttpClient, Method, Request, StatusCode, Url,
+};
+use azure_messaging_servicebus::service_bus::BrokerProperties;
+use hmac::{Hmac, Mac};
+use serde_json::{json, Map, Value};
+use sha2::Sha256;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    env,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+use time::OffsetDateTime;
+use tracing::{debug, warn};
+
+use crate::{
+    azure,
+    chaos::{self, Target},
+    claim_check, config, identity,
+    message::ImageMessage,
+    metrics,
+    output_format::OutputFormat,
+    shards,
+};
+
+/// How long a signature stays valid, as in the SDK.
+const SAS_LIFETIME_SECS: i64 = 3600;
+/// Duplicate detection history of a queue unless it was set up otherwise.
+const DEFAULT_DUPLICATE_WINDOW_SECS: u64 = 600;
+
+/// Time to live of enqueued messages, from `QUEUE_MESSAGE_TTL_SECS`.
+pub fn message_ttl() -> Option<Duration> {
+    env::var("QUEUE_MESSAGE_TTL_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs)
+}
+
+/// The ids of messages this process sent within the duplicate detection window, with when.
+struct SentIds {
+    window: Duration,
+    sent: Mutex<HashMap<String, Instant>>,
+}
+
+impl SentIds {
+    fn get() -> &'static SentIds {
+        static SENT: OnceLock<SentIds> = OnceLock::new();
+        SENT.get_or_init(|| SentIds {
+            window: Duration::from_secs(
+                env::var("QUEUE_DUPLICATE_WINDOW_SECS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(DEFAULT_DUPLICATE_WINDOW_SECS),
+            ),
+            sent: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Records `id` as sent now, returning how long ago it was last sent within the window.
+    fn record(&self, id: &str) -> Option<Duration> {
+        let now = Instant::now();
+        let mut sent = self.sent.lock().unwrap();
+        sent.retain(|_, at| now.duration_since(*at) < self.window);
+        sent.insert(id.to_string(), now).map(|at| now.duration_since(at))
+    }
+}
+
+/// Percent-encodes everything but unreserved characters.
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Signs requests with the shared access policy, or authorizes them with the identity's token.
+#[derive(Clone)]
+struct Authorizer {
+    http_client: Arc<dyn HttpClient>,
+    policy: Option<(String, Secret)>,
+}
+
+impl Authorizer {
+    fn from_config() -> Self {
+        Authorizer {
+            http_client: azure::http_client(),
+            policy: config::get().service_bus.policy.clone().map(|(name, key)| (name, Secret::new(key))),
+        }
+    }
+
+    /// A shared access signature for `url`.
+    fn signature(url: &str, policy_name: &str, policy_key: &Secret) -> String {
+        let resource = encode(url);
+        let expiry = OffsetDateTime::now_utc().unix_timestamp() + SAS_LIFETIME_SECS;
+        let mut mac = Hmac::<Sha256>::new_from_slice(policy_key.secret().as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(format!("{}\n{}", resource, expiry).as_bytes());
+        let signature = base64::encode(mac.finalize().into_bytes());
+        format!(
+            "SharedAccessSignature sr={}&sig={}&se={}&skn={}",
+            resource,
+            encode(&signature),
+            expiry,
+            policy_name
+        )
+    }
+
+    /// A request to `url` carrying its `authorization` header.
+    async fn request(&self, url: &str, method: Method) -> azure_core::Result<Request> {
+        let authorization = match &self.policy {
+            Some((policy_name, policy_key)) => Authorizer::signature(url, policy_name, policy_key),
+            None => identity::bearer(identity::SERVICE_BUS_SCOPE).await?,
+        };
+        let mut request = Request::new(Url::parse(url)?, method);
+        request.insert_header("authorization", authorization);
+        Ok(request)
+    }
+
+    /// Sends a request to `url` without a body, as Service Bus wants for settling messages.
+    async fn execute(&self, url: &str, method: Method) -> azure_core::Result<()> {
+        chaos::inject(Target::Queue, "settle").await?;
+        let mut request = self.request(url, method).await?;
+        request.insert_header("content-length", "0");
+        self.http_client.execute_request_check_status(&request).await?;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct QueueSender {
+    authorizer: Authorizer,
+    namespace: String,
+    /// The queue before sharding.
+    base: String,
+    /// The queue messages are posted to, `base` or one of its shards.
+    queue: String,
+    ttl: Option<Duration>,
+}
+
+impl QueueSender {
+    /// A sender for the queue named in the `AZURE_*` variables the API and worker share.
+    pub fn from_env() -> Self {
+        QueueSender::for_format(None)
+    }
+
+    /// A sender for the queue of jobs rendering `format`, see `FORMAT_QUEUES` in `config.rs`.
+    pub fn for_format(format: Option<OutputFormat>) -> Self {
+        let service_bus = &config::get().service_bus;
+        let queue = service_bus.queue_for(format).to_string();
+        QueueSender {
+            authorizer: Authorizer::from_config(),
+            namespace: service_bus.namespace.clone(),
+            base: queue.clone(),
+            queue,
+            ttl: message_ttl(),
+        }
+    }
+
+    /// This sender, posting to the shard of the queue `image` belongs to when jobs are sharded.
+    /// Every message about an image should be sent through it.
+    pub fn for_image(&self, image: &ImageMessage) -> Self {
+        QueueSender {
+            queue: shards::queue_name(&self.base, shards::shard_of(image)),
+            ..self.clone()
+        }
+    }
+
+    pub fn queue_name(&self) -> &str {
+        &self.queue
+    }
+
+    /// Reads the description of the queue, or of each of its shards, to check it can be reached.
+    /// A policy or role with only send rights may not read it, so being refused still counts as
+    /// reachable; a missing queue doesn't.
+    pub async fn probe(&self) -> azure_core::Result<()> {
+        for queue in shards::queue_names(&self.base) {
+            let url = format!("https://{}.servicebus.windows.net/{}", self.namespace, queue);
+            let request = self.authorizer.request(&url, Method::Get).await?;
+            match self.authorizer.http_client.execute_request_check_status(&request).await {
+                Err(e) if e.as_http_error().is_some_and(|e| matches!(e.status(), StatusCode::Unauthorized | StatusCode::Forbidden)) => {}
+                result => {
+                    result?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends `body`, the serialized `image`, under the message id of `image`.
+    pub async fn send(&self, image: &ImageMessage, body: &str) -> azure_core::Result<()> {
+        self.post(&image.message_id(), body, None).await
+    }
+
+    /// Sends `body`, the serialized `image`, to be received no sooner than `delay` from now.
+    pub async fn send_after(&self, image: &ImageMessage, body: &str, delay: Duration) -> azure_core::Result<()> {
+        self.post(&image.message_id(), body, Some(OffsetDateTime::now_utc() + delay)).await
+    }
+
+    async fn post(&self, message_id: &str, body: &str, scheduled: Option<OffsetDateTime>) -> azure_core::Result<()> {
+        let url = format!("https://{}.servicebus.windows.net/{}/messages", self.namespace, self.queue);
+        let mut request = self.authorizer.request(&url, Method::Post).await?;
+        request.insert_header("content-type", "application/json");
+        let mut properties = Map::new();
+        properties.insert("MessageId".to_string(), json!(message_id));
+        if let Some(ttl) = self.ttl {
+            properties.insert("TimeToLive".to_string(), json!(ttl.as_secs()));
+        }
+        if let Some(scheduled) = scheduled {
+            properties.insert("ScheduledEnqueueTimeUtc".to_string(), json!(date::to_rfc1123(&scheduled)));
+        }
+        request.insert_header("brokerproperties", Value::Object(properties).to_string());
+        let body = claim_check::check_in(message_id, body).await?;
+        if matches!(body, Cow::Owned(_)) {
+            metrics::increment("queue_claim_checks_total", &[("queue", &self.queue)]);
+        }
+        request.set_body(body.into_owned());
+        chaos::inject(Target::Queue, "send").await?;
+        self.authorizer.http_client.execute_request_check_status(&request).await?;
+        match SentIds::get().record(message_id) {
+            Some(since) => {
+                warn!(
+                    "Message {} was already sent to {} {:?} ago, duplicate detection drops this copy",
+                    message_id, self.queue, since
+                );
+                metrics::increment("queue_duplicate_sends_total", &[("queue", &self.queue)]);
+            }
+            None => debug!("Sent message {} to {}", message_id, self.queue),
+        }
+        Ok(())
+    }
+}
+
+/// Receives messages from the job queue under a peek-lock.
+pub struct QueueReceiver {
+    authorizer: Authorizer,
+    namespace: String,
+    queue: String,
+}
+
+impl QueueReceiver {
+    /// A receiver for the queue named in `AZURE_QUEUE_NAME`, or its shard in `WORKER_SHARD`.
+    pub fn from_env() -> Self {
+        let service_bus = &config::get().service_bus;
+        QueueReceiver {
+            authorizer: Authorizer::from_config(),
+            namespace: service_bus.namespace.clone(),
+            queue: shards::queue_name(&service_bus.queue_name, shards::worker_shard()),
+        }
+    }
+
+    pub fn queue_name(&self) -> &str {
+        &self.queue
+    }
+
+    /// Locks the next message, waiting up to `timeout` for one; `None` if none arrived.
+    pub async fn peek_lock(&self, timeout: Duration) -> azure_core::Result<Option<LockedMessage>> {
+        let url = format!(
+            "https://{}.servicebus.windows.net/{}/messages/head?timeout={}",
+            self.namespace,
+            self.queue,
+            timeout.as_secs()
+        );
+        chaos::inject(Target::Queue, "receive").await?;
+        let mut request = self.authorizer.request(&url, Method::Post).await?;
+        request.insert_header("content-length", "0");
+        let response = self.authorizer.http_client.execute_request_check_status(&request).await?;
+        if *response.status() == StatusCode::NoContent {
+            return Ok(None);
+        }
+        let broker_properties = response.headers().get_optional_as(&HeaderName::from("brokerproperties"))?;
+        let lock_location = response.headers().get_optional_string(&LOCATION).unwrap_or_default();
+        let body = String::from_utf8_lossy(response.body()).into_owned();
+        Ok(Some(LockedMessage {
+            authorizer: self.authorizer.clone(),
+            body,
+            broker_properties,
+            lock_location,
+        }))
+    }
+}
+
+/// A message received by [`QueueReceiver::peek_lock`], locked until settled or the lock expires.
+pub struct LockedMessage {
+    authorizer: Authorizer,
+    body: String,
+    broker_properties: Option<BrokerProperties>,
+    lock_location: String,
+}
+
+impl LockedMessage {
+    pub fn body(&self) -> String {
+        self.body.clone()
+    }
+
+    pub fn broker_properties(&self) -> Option<BrokerProperties> {
+        self.broker_properties.clone()
+    }
+
+    /// Completes the message, removing it from the queue.
+    pub async fn delete_message(&self) -> azure_core::Result<()> {
+        self.authorizer.execute(&self.lock_location, Method::Delete).await
+    }
+
+    /// Abandons the message, releasing its lock for another delivery.
+    pub async fn unlock_message(&self) -> azure_core::Result<()> {
+        self.authorizer.execute(&self.lock_location, Method::Put).await
+    }
+
+    pub async fn renew_message_lock(&self) -> azure_core::Result<()> {
+        self.authorizer.execute(&self.lock_location, Method::Post).await
+    }
+}
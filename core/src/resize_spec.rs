@@ -0,0 +1,110 @@
+// core/src/resize_spec.rs
+
+//! Ways of sizing a rendition other than fitting it into a `width` x `height` box: by a
+//! percentage of the source, or by the pixel length of its longest or shortest edge. Uploads pass
+//! one as `scale`, `longest_edge` or `shortest_edge`, templates as `"resize": {"longest_edge": 800}`;
+//! the aspect ratio is kept either way. Uploads may also pick how a box is filled with `fit` and
+//! the resampling filter with `filter`, overriding the resize preset.
+
+use image::imageops::FilterType;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Largest scale accepted, so a typo can't ask the worker for a gigapixel output.
+pub const MAX_SCALE_PERCENT: f32 = 400.0;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResizeSpec {
+    /// Both sides scaled to this percentage of the source.
+    Scale(f32),
+    /// The longer side made this many pixels long.
+    LongestEdge(u32),
+    /// The shorter side made this many pixels long.
+    ShortestEdge(u32),
+}
+
+impl ResizeSpec {
+    /// Checks the value is positive and, for `scale`, at most [`MAX_SCALE_PERCENT`].
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            ResizeSpec::Scale(percent) if !(percent > 0.0 && percent <= MAX_SCALE_PERCENT) => {
+                Err(format!("Scale must be above 0 and at most {}%", MAX_SCALE_PERCENT))
+            }
+            ResizeSpec::LongestEdge(0) | ResizeSpec::ShortestEdge(0) => Err("Edge lengths must be positive".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Size of the rendition of a `width` x `height` source, at least 1x1.
+    pub fn dimensions(&self, width: u32, height: u32) -> (u32, u32) {
+        let ratio = match *self {
+            ResizeSpec::Scale(percent) => percent as f64 / 100.0,
+            ResizeSpec::LongestEdge(pixels) => pixels as f64 / width.max(height) as f64,
+            ResizeSpec::ShortestEdge(pixels) => pixels as f64 / width.min(height) as f64,
+        };
+        let scale = |side: u32| ((side as f64 * ratio).round() as u32).max(1);
+        (scale(width), scale(height))
+    }
+}
+
+/// How a rendition is fitted into its `width` x `height` box.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Fit {
+    /// Scaled to fit inside the box, keeping the whole image.
+    #[default]
+    Contain,
+    /// Scaled to fill the box, cutting off what sticks out around the image's focal point.
+    Cover,
+    /// Stretched to the box, ignoring the aspect ratio.
+    Exact,
+}
+
+impl FromStr for Fit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "contain" => Ok(Fit::Contain),
+            "cover" => Ok(Fit::Cover),
+            "exact" => Ok(Fit::Exact),
+            _ => Err(format!("Unknown fit '{}', use contain, cover or exact", s)),
+        }
+    }
+}
+
+/// Resampling filter used to scale renditions.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Filter {
+    Nearest,
+    /// Bilinear, fast and soft; what renditions were always scaled with.
+    #[default]
+    Triangle,
+    /// Sharpest and slowest.
+    Lanczos3,
+}
+
+impl Filter {
+    pub fn filter_type(self) -> FilterType {
+        match self {
+            Filter::Nearest => FilterType::Nearest,
+            Filter::Triangle => FilterType::Triangle,
+            Filter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "nearest" => Ok(Filter::Nearest),
+            "triangle" => Ok(Filter::Triangle),
+            "lanczos3" => Ok(Filter::Lanczos3),
+            _ => Err(format!("Unknown filter '{}', use nearest, triangle or lanczos3", s)),
+        }
+    }
+}
@@ -0,0 +1,53 @@
+// core/src/routing.rs
+
+//! Pipelines the worker runs a source through, picked by the type sniffed from its content, see
+//! `CONTENT_ROUTES` in `config.rs`. `standard` decodes the source and makes the usual renditions,
+//! `animation` scales every frame of an animated GIF into an animated GIF rendition, and
+//! `rasterize` renders an SVG to pixels before the standard pipeline takes over.
+
+use std::str::FromStr;
+
+use crate::{capabilities, svg};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Pipeline {
+    #[default]
+    Standard,
+    Animation,
+    Rasterize,
+}
+
+impl Pipeline {
+    /// Every pipeline, see `capabilities.rs`.
+    pub const ALL: [Pipeline; 3] = [Pipeline::Standard, Pipeline::Animation, Pipeline::Rasterize];
+
+    /// The name `CONTENT_ROUTES` takes.
+    pub fn name(self) -> &'static str {
+        match self {
+            Pipeline::Standard => "standard",
+            Pipeline::Animation => "animation",
+            Pipeline::Rasterize => "rasterize",
+        }
+    }
+
+    /// Whether the pipeline can take sources of `kind`, e.g. `gif`.
+    pub fn accepts(self, kind: &str) -> bool {
+        match self {
+            Pipeline::Standard => kind != svg::EXTENSION,
+            Pipeline::Animation => kind == "gif",
+            Pipeline::Rasterize => kind == svg::EXTENSION,
+        }
+    }
+}
+
+impl FromStr for Pipeline {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.trim().to_ascii_lowercase();
+        Pipeline::ALL
+            .into_iter()
+            .find(|pipeline| pipeline.name() == name)
+            .ok_or_else(|| format!("Unknown pipeline '{}', use {}", s, capabilities::one_of(Pipeline::ALL.map(Pipeline::name))))
+    }
+}
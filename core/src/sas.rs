@@ -0,0 +1,121 @@
+// core/src/sas.rs
+
+//! Read-only URLs for blobs handed to clients, since the containers holding originals and
+//! renditions are private. Each is a service SAS scoped to the one blob, granting `read` only over
+//! HTTPS and expiring after `SAS_EXPIRY_SECS` (default 900, at most a day). They're signed with the
+//! account key current at the time, see `account_keys.rs`, so signing the URL as it's served
+//! rather than storing it keeps them valid across key rotations.
+//!
+//! Without keys, see `identity.rs`, they're user delegation SASes instead. The SDK expires those
+//! with the delegation key, so a key is requested per account to last `SAS_EXPIRY_SECS` and
+//! replaced once half of that has passed: URLs then stay valid for between half and all of it.
+
+use azure_storage::shared_access_signature::{
+    service_sas::{BlobSasPermissions, UserDeligationKey},
+    SasProtocol,
+};
+use azure_storage_blobs::prelude::{BlobClient, ClientBuilder};
+use azure_core::{
+    error::{Error, ErrorKind},
+    Url,
+};
+use std::{collections::HashMap, env, sync::Mutex};
+use time::{Duration, OffsetDateTime};
+
+use crate::{
+    azure, config,
+    failover::{self, Location},
+    identity::AuthMode,
+};
+
+const DEFAULT_EXPIRY_SECS: i64 = 900;
+const MAX_EXPIRY_SECS: i64 = 86_400;
+
+/// Allowance for clocks running ahead of storage's, for the start of delegation keys.
+const CLOCK_SKEW: Duration = Duration::minutes(5);
+
+fn lifetime() -> Duration {
+    let secs = env::var("SAS_EXPIRY_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_EXPIRY_SECS)
+        .clamp(1, MAX_EXPIRY_SECS);
+    Duration::seconds(secs)
+}
+
+fn expiry() -> OffsetDateTime {
+    OffsetDateTime::now_utc() + lifetime()
+}
+
+/// Delegation keys by account.
+static DELEGATION_KEYS: Mutex<Option<HashMap<String, UserDeligationKey>>> = Mutex::new(None);
+
+/// A delegation key of `blob_client`'s account with at least half of [`lifetime`] left.
+async fn delegation_key(blob_client: &BlobClient) -> azure_core::Result<UserDeligationKey> {
+    let service_client = blob_client.container_client().service_client();
+    let account = service_client.account().to_string();
+    let now = OffsetDateTime::now_utc();
+    let cached = DELEGATION_KEYS.lock().unwrap().get_or_insert_with(HashMap::new).get(&account).cloned();
+    if let Some(key) = cached.filter(|key| key.signed_expiry - now >= lifetime() / 2) {
+        return Ok(key);
+    }
+    let key = service_client
+        .get_user_deligation_key(now - CLOCK_SKEW, now + lifetime())
+        .await?
+        .user_deligation_key;
+    DELEGATION_KEYS.lock().unwrap().get_or_insert_with(HashMap::new).insert(account, key.clone());
+    Ok(key)
+}
+
+/// A URL reading `blob_client`'s blob, which must have been built by `clients.rs`.
+pub async fn read_url(blob_client: &BlobClient) -> azure_core::Result<String> {
+    let permissions = BlobSasPermissions {
+        read: true,
+        ..Default::default()
+    };
+    let signature = if config::get().storage_auth == AuthMode::Key {
+        blob_client.shared_access_signature(permissions, expiry()).await?
+    } else {
+        let key = delegation_key(blob_client).await?;
+        blob_client.user_delegation_shared_access_signature(permissions, &key).await?
+    };
+    Ok(blob_client.generate_signed_blob_url(&signature.protocol(SasProtocol::Https))?.to_string())
+}
+
+/// Signs the plain URL of a blob in the primary or failover account, as stored by the worker.
+pub async fn sign_url(url: &str) -> azure_core::Result<String> {
+    let invalid = || Error::with_message(ErrorKind::DataConversion, || format!("Not a blob URL: {}", url));
+    let parsed = Url::parse(url)?;
+    let account = parsed.host_str().and_then(|host| host.split('.').next()).ok_or_else(invalid)?;
+    let location = [Location::Primary, Location::Secondary]
+        .into_iter()
+        .find(|location| failover::account(*location).is_some_and(|name| name == account))
+        .ok_or_else(invalid)?;
+    let mut segments = parsed.path_segments().ok_or_else(invalid)?;
+    let container = segments.next().filter(|s| !s.is_empty()).ok_or_else(invalid)?;
+    let blob = decode(&segments.collect::<Vec<_>>().join("/")).ok_or_else(invalid)?;
+
+    let (storage_account, storage_credentials) = failover::credentials(location);
+    let blob_client = ClientBuilder::new(storage_account, storage_credentials)
+        .client_options(azure::client_options())
+        .blob_client(container, blob);
+    read_url(&blob_client).await
+}
+
+/// Percent-decodes a URL path.
+fn decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
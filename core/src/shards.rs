@@ -0,0 +1,129 @@
+// core/src/shards.rs
+
+//! Sharding of jobs over queues, for deployments with many workers. With `QUEUE_SHARDS` above 1
+//! every queue, `AZURE_QUEUE_NAME` and each of `FORMAT_QUEUES`, is split into the queues
+//! `<queue>-0` to `<queue>-<QUEUE_SHARDS - 1>`, which must exist. Each message goes to the shard
+//! its key hashes to, and follow-up stages go to the same one, so the jobs of one image, or of one
+//! tenant, are all taken by the same workers. The key is picked by `SHARD_KEY`: `image` (the
+//! default, its container and blob name) or `tenant` (the image's for uploads without a tenant).
+//!
+//! Keys are spread by jump consistent hashing, so raising `QUEUE_SHARDS` from N to N + 1 moves
+//! about one key in N + 1 to the new shard and leaves the others where they were. Drain the
+//! shards before lowering it, since the messages left on a dropped shard aren't received anymore.
+//!
+//! A worker receives from the shard in `WORKER_SHARD`, required with more than one shard. With
+//! `WORKER_SHARD=ordinal` it is taken from the number ending the host name, as a StatefulSet's
+//! pods are named, modulo the shard count, so a set larger than the shard count shares them out.
+
+use sha2::{Digest, Sha256};
+use std::{env, str::FromStr};
+
+use crate::{config, message::ImageMessage};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ShardKey {
+    #[default]
+    Image,
+    Tenant,
+}
+
+impl FromStr for ShardKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "image" => Ok(ShardKey::Image),
+            "tenant" => Ok(ShardKey::Tenant),
+            _ => Err(format!("Unknown shard key '{}', use image or tenant", s)),
+        }
+    }
+}
+
+/// `QUEUE_SHARDS`, 1 when unset. Panics if it isn't a positive number.
+pub(crate) fn count_from_env() -> u32 {
+    let Ok(value) = env::var("QUEUE_SHARDS") else {
+        return 1;
+    };
+    value
+        .trim()
+        .parse()
+        .ok()
+        .filter(|count| *count > 0)
+        .unwrap_or_else(|| panic!("Invalid QUEUE_SHARDS '{}', expected a positive number", value))
+}
+
+/// `SHARD_KEY`, `image` when unset.
+pub(crate) fn key_from_env() -> ShardKey {
+    env::var("SHARD_KEY")
+        .ok()
+        .map(|v| v.parse().unwrap_or_else(|e| panic!("Invalid SHARD_KEY: {}", e)))
+        .unwrap_or_default()
+}
+
+/// The bucket of `key` among `buckets`, by Lamping and Veach's jump consistent hash.
+fn jump(mut key: u64, buckets: u32) -> u32 {
+    let (mut bucket, mut next) = (-1i64, 0i64);
+    while next < i64::from(buckets) {
+        bucket = next;
+        key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+        next = ((bucket + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    bucket as u32
+}
+
+/// The shard `image` belongs to, `None` when jobs aren't sharded.
+pub fn shard_of(image: &ImageMessage) -> Option<u32> {
+    let service_bus = &config::get().service_bus;
+    if service_bus.shards <= 1 {
+        return None;
+    }
+    let key = match (service_bus.shard_key, &image.tenant) {
+        (ShardKey::Tenant, Some(tenant)) => format!("tenant/{}", tenant),
+        _ => format!("image/{}/{}", image.image_container, image.filename),
+    };
+    let digest = Sha256::digest(key.as_bytes());
+    let hash = u64::from_be_bytes(digest[..8].try_into().expect("A SHA-256 digest has 8 bytes to spare"));
+    Some(jump(hash, service_bus.shards))
+}
+
+/// The name of `shard` of `queue`, `queue` itself without one.
+pub fn queue_name(queue: &str, shard: Option<u32>) -> String {
+    match shard {
+        Some(shard) => format!("{}-{}", queue, shard),
+        None => queue.to_string(),
+    }
+}
+
+/// Every queue `queue` is split into, just `queue` when jobs aren't sharded.
+pub fn queue_names(queue: &str) -> Vec<String> {
+    match config::get().service_bus.shards {
+        1 => vec![queue.to_string()],
+        shards => (0..shards).map(|shard| queue_name(queue, Some(shard))).collect(),
+    }
+}
+
+/// The shard this worker receives from, from `WORKER_SHARD`; `None` when jobs aren't sharded.
+/// Panics if it is missing or out of range.
+pub fn worker_shard() -> Option<u32> {
+    let shards = config::get().service_bus.shards;
+    if shards <= 1 {
+        return None;
+    }
+    let value = env::var("WORKER_SHARD").unwrap_or_else(|_| panic!("Missing WORKER_SHARD env var, required with QUEUE_SHARDS"));
+    if value.trim() == "ordinal" {
+        let host = env::var("HOSTNAME").unwrap_or_default();
+        let ordinal: u32 = host
+            .rsplit('-')
+            .next()
+            .and_then(|ordinal| ordinal.parse().ok())
+            .unwrap_or_else(|| panic!("WORKER_SHARD=ordinal but the host name '{}' doesn't end in a number", host));
+        return Some(ordinal % shards);
+    }
+    let shard = value
+        .trim()
+        .parse()
+        .ok()
+        .filter(|shard| *shard < shards)
+        .unwrap_or_else(|| panic!("Invalid WORKER_SHARD '{}', expected ordinal or a number below {}", value, shards));
+    Some(shard)
+}
@@ -0,0 +1,390 @@
+// core/src/status_store.rs
+
+//! Where the records of `job_status.rs` are kept, behind [`StatusStore`], so a deployment can back
+//! them with another database without touching the code reading and writing them. A store only
+//! gets, puts and purges whole records by their keys; what the records mean stays in
+//! `job_status.rs`. `STATUS_STORE` picks the store:
+//!
+//! - `table` (the default): the Table Storage table named by `JOB_STATUS_TABLE`, see [`TableStore`];
+//! - `memory`: maps in the process, see [`MemoryStore`]. The API and the worker don't share them,
+//!   so jobs never leave `queued` as seen from the API; it's meant for running one process alone,
+//!   e.g. `handler simulate` or local development, and everything is lost on exit.
+//!
+//! Another store implements [`StatusStore`] and is added to [`StatusStoreKind`].
+
+use async_trait::async_trait;
+use azure_core::{base64, date};
+use azure_data_tables::prelude::{EntityClient, TableClient};
+use futures::StreamExt;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Mutex, OnceLock},
+};
+use time::OffsetDateTime;
+
+use crate::{
+    job_status::{self, Cancellation, Expiry, JobRecord, JobStatus, Provenance, RecordKind, Review},
+    tables,
+};
+
+/// Picks the records to purge: the cutoff for each kind, `None` keeping every record of it.
+pub type Cutoff<'a> = &'a (dyn Fn(RecordKind) -> Option<OffsetDateTime> + Send + Sync);
+
+#[async_trait]
+pub trait StatusStore: Send + Sync {
+    async fn last_success(&self, container: &str, blob: &str) -> azure_core::Result<Option<JobStatus>>;
+    async fn put_success(&self, status: &JobStatus) -> azure_core::Result<()>;
+    async fn cancellation(&self, container: &str, blob: &str) -> azure_core::Result<Option<Cancellation>>;
+    async fn put_cancellation(&self, cancellation: &Cancellation) -> azure_core::Result<()>;
+    async fn last_expiry(&self, container: &str, blob: &str) -> azure_core::Result<Option<Expiry>>;
+    async fn put_expiry(&self, expiry: &Expiry) -> azure_core::Result<()>;
+    async fn job(&self, id: &str) -> azure_core::Result<Option<JobRecord>>;
+    async fn put_job(&self, record: &JobRecord) -> azure_core::Result<()>;
+    /// The provenance of every rendition made from `source` in `container`.
+    async fn provenance(&self, container: &str, source: &str) -> azure_core::Result<Vec<Provenance>>;
+    async fn put_provenance(&self, provenance: &Provenance) -> azure_core::Result<()>;
+    async fn review(&self, id: &str) -> azure_core::Result<Option<Review>>;
+    /// Every upload held for review.
+    async fn reviews(&self) -> azure_core::Result<Vec<Review>>;
+    async fn put_review(&self, review: &Review) -> azure_core::Result<()>;
+    /// Deletes the review of job `id`, which needn't exist.
+    async fn delete_review(&self, id: &str) -> azure_core::Result<()>;
+    /// Deletes the records last written before their kind's cutoff, returning how many of each
+    /// kind were deleted.
+    async fn purge(&self, cutoff: Cutoff<'_>) -> azure_core::Result<Vec<(RecordKind, u64)>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StatusStoreKind {
+    Table,
+    Memory,
+}
+
+/// The store `STATUS_STORE` asks for.
+pub fn kind() -> StatusStoreKind {
+    match env::var("STATUS_STORE").as_deref() {
+        Err(_) | Ok("table") => StatusStoreKind::Table,
+        Ok("memory") => StatusStoreKind::Memory,
+        Ok(other) => panic!("Unknown STATUS_STORE '{}', use table or memory", other),
+    }
+}
+
+/// The configured store, made on first use.
+pub fn get() -> &'static dyn StatusStore {
+    static STORE: OnceLock<Box<dyn StatusStore>> = OnceLock::new();
+    STORE
+        .get_or_init(|| match kind() {
+            StatusStoreKind::Table => Box::new(TableStore),
+            StatusStoreKind::Memory => Box::new(MemoryStore::default()),
+        })
+        .as_ref()
+}
+
+/// The records in the job status table, one partition per kind and container, see `job_status.rs`.
+pub struct TableStore;
+
+fn entity_client(table_client: &TableClient, partition: &str, blob: &str) -> EntityClient {
+    table_client
+        .partition_key_client(partition)
+        .entity_client(base64::encode_url_safe(blob))
+}
+
+async fn get_entity<T: DeserializeOwned + Send + Sync>(entity_client: EntityClient) -> azure_core::Result<Option<T>> {
+    match entity_client.get::<T>().await {
+        Ok(response) => Ok(Some(response.entity)),
+        Err(e) if tables::is_not_found(&e) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+async fn put_entity<T: Serialize + Send + Sync>(partition: &str, row_key: &str, entity: &T) -> azure_core::Result<()> {
+    let table_client = job_status::table_client();
+    tables::create_if_missing(&table_client).await?;
+    table_client
+        .partition_key_client(partition)
+        .entity_client(row_key)
+        .insert_or_replace(entity)?
+        .await?;
+    Ok(())
+}
+
+/// Every record `filter` matches.
+async fn query_entities<T: DeserializeOwned + Send + Sync>(filter: String) -> azure_core::Result<Vec<T>> {
+    let mut pages = job_status::table_client().query().filter(filter).into_stream::<T>();
+    let mut records = Vec::new();
+    while let Some(page) = pages.next().await {
+        match page {
+            Ok(page) => records.extend(page.entities),
+            // nothing has been recorded yet
+            Err(e) if tables::is_not_found(&e) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(records)
+}
+
+/// The keys of any record, and when it was last written.
+#[derive(Deserialize)]
+struct RecordKey {
+    #[serde(rename = "PartitionKey")]
+    partition: String,
+    #[serde(rename = "RowKey")]
+    row_key: String,
+    /// RFC 3339; maintained by the table service.
+    #[serde(rename = "Timestamp")]
+    timestamp: String,
+}
+
+#[async_trait]
+impl StatusStore for TableStore {
+    async fn last_success(&self, container: &str, blob: &str) -> azure_core::Result<Option<JobStatus>> {
+        get_entity(entity_client(&job_status::table_client(), container, blob)).await
+    }
+
+    async fn put_success(&self, status: &JobStatus) -> azure_core::Result<()> {
+        put_entity(&status.container, &status.row_key, status).await
+    }
+
+    async fn cancellation(&self, container: &str, blob: &str) -> azure_core::Result<Option<Cancellation>> {
+        let partition = job_status::cancellation_partition(container);
+        get_entity(entity_client(&job_status::table_client(), &partition, blob)).await
+    }
+
+    async fn put_cancellation(&self, cancellation: &Cancellation) -> azure_core::Result<()> {
+        put_entity(&cancellation.partition, &cancellation.row_key, cancellation).await
+    }
+
+    async fn last_expiry(&self, container: &str, blob: &str) -> azure_core::Result<Option<Expiry>> {
+        let partition = job_status::expiry_partition(container);
+        get_entity(entity_client(&job_status::table_client(), &partition, blob)).await
+    }
+
+    async fn put_expiry(&self, expiry: &Expiry) -> azure_core::Result<()> {
+        put_entity(&expiry.partition, &expiry.row_key, expiry).await
+    }
+
+    async fn job(&self, id: &str) -> azure_core::Result<Option<JobRecord>> {
+        let entity_client = job_status::table_client().partition_key_client(job_status::JOBS_PARTITION).entity_client(id);
+        get_entity(entity_client).await
+    }
+
+    async fn put_job(&self, record: &JobRecord) -> azure_core::Result<()> {
+        put_entity(&record.partition, &record.id, record).await
+    }
+
+    async fn provenance(&self, container: &str, source: &str) -> azure_core::Result<Vec<Provenance>> {
+        let filter = format!(
+            "PartitionKey eq '{}' and source eq '{}'",
+            tables::escape(&job_status::provenance_partition(container)),
+            tables::escape(source)
+        );
+        query_entities(filter).await
+    }
+
+    async fn put_provenance(&self, provenance: &Provenance) -> azure_core::Result<()> {
+        put_entity(&provenance.partition, &provenance.row_key, provenance).await
+    }
+
+    async fn review(&self, id: &str) -> azure_core::Result<Option<Review>> {
+        let entity_client = job_status::table_client().partition_key_client(job_status::REVIEW_PARTITION).entity_client(id);
+        get_entity(entity_client).await
+    }
+
+    async fn reviews(&self) -> azure_core::Result<Vec<Review>> {
+        query_entities(format!("PartitionKey eq '{}'", job_status::REVIEW_PARTITION)).await
+    }
+
+    async fn put_review(&self, review: &Review) -> azure_core::Result<()> {
+        put_entity(&review.partition, &review.id, review).await
+    }
+
+    async fn delete_review(&self, id: &str) -> azure_core::Result<()> {
+        let entity_client = job_status::table_client().partition_key_client(job_status::REVIEW_PARTITION).entity_client(id);
+        match entity_client.delete().await {
+            Ok(_) => Ok(()),
+            Err(e) if tables::is_not_found(&e) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn purge(&self, cutoff: Cutoff<'_>) -> azure_core::Result<Vec<(RecordKind, u64)>> {
+        let mut purged: Vec<(RecordKind, u64)> = RecordKind::ALL.iter().map(|kind| (*kind, 0)).collect();
+        // one query for the latest cutoff, each record then checked against its own kind's
+        let Some(latest) = RecordKind::ALL.into_iter().filter_map(cutoff).max() else {
+            return Ok(purged);
+        };
+        let table_client = job_status::table_client();
+        let filter = format!("Timestamp lt datetime'{}'", date::to_rfc3339(&latest));
+        let mut pages = table_client.query().filter(filter).into_stream::<RecordKey>();
+        while let Some(page) = pages.next().await {
+            let page = match page {
+                Ok(page) => page,
+                // nothing has been recorded yet
+                Err(e) if tables::is_not_found(&e) => break,
+                Err(e) => return Err(e),
+            };
+            for record in page.entities {
+                let Some(kind) = RecordKind::of(&record.partition) else {
+                    continue;
+                };
+                let expired = match (cutoff(kind), date::parse_rfc3339(&record.timestamp)) {
+                    (Some(cutoff), Ok(written)) => written < cutoff,
+                    _ => false,
+                };
+                if !expired {
+                    continue;
+                }
+                let entity_client = table_client.partition_key_client(&record.partition).entity_client(&record.row_key);
+                match entity_client.delete().await {
+                    Ok(_) => {}
+                    // deleted meanwhile, e.g. by another replica's run
+                    Err(e) if tables::is_not_found(&e) => continue,
+                    Err(e) => return Err(e),
+                }
+                if let Some((_, count)) = purged.iter_mut().find(|(purged_kind, _)| *purged_kind == kind) {
+                    *count += 1;
+                }
+            }
+        }
+        Ok(purged)
+    }
+}
+
+/// A record kept by [`MemoryStore`], with when it was written.
+struct Stored<T> {
+    record: T,
+    written: OffsetDateTime,
+}
+
+/// Records of one kind, keyed by partition and row like in the table.
+type Records<T> = Mutex<HashMap<(String, String), Stored<T>>>;
+
+/// The records in maps of the process.
+#[derive(Default)]
+pub struct MemoryStore {
+    successes: Records<JobStatus>,
+    cancellations: Records<Cancellation>,
+    expiries: Records<Expiry>,
+    jobs: Records<JobRecord>,
+    provenance: Records<Provenance>,
+    reviews: Records<Review>,
+}
+
+fn get_record<T: Clone>(records: &Records<T>, partition: &str, row_key: &str) -> Option<T> {
+    let key = (partition.to_string(), row_key.to_string());
+    records.lock().unwrap().get(&key).map(|stored| stored.record.clone())
+}
+
+fn put_record<T: Clone>(records: &Records<T>, partition: &str, row_key: &str, record: &T) {
+    let stored = Stored {
+        record: record.clone(),
+        written: OffsetDateTime::now_utc(),
+    };
+    records.lock().unwrap().insert((partition.to_string(), row_key.to_string()), stored);
+}
+
+/// Drops the records written before `cutoff`, returning how many.
+fn purge_records<T>(records: &Records<T>, cutoff: Option<OffsetDateTime>) -> u64 {
+    let Some(cutoff) = cutoff else {
+        return 0;
+    };
+    let mut records = records.lock().unwrap();
+    let before = records.len();
+    records.retain(|_, stored| stored.written >= cutoff);
+    (before - records.len()) as u64
+}
+
+#[async_trait]
+impl StatusStore for MemoryStore {
+    async fn last_success(&self, container: &str, blob: &str) -> azure_core::Result<Option<JobStatus>> {
+        Ok(get_record(&self.successes, container, &base64::encode_url_safe(blob)))
+    }
+
+    async fn put_success(&self, status: &JobStatus) -> azure_core::Result<()> {
+        put_record(&self.successes, &status.container, &status.row_key, status);
+        Ok(())
+    }
+
+    async fn cancellation(&self, container: &str, blob: &str) -> azure_core::Result<Option<Cancellation>> {
+        let partition = job_status::cancellation_partition(container);
+        Ok(get_record(&self.cancellations, &partition, &base64::encode_url_safe(blob)))
+    }
+
+    async fn put_cancellation(&self, cancellation: &Cancellation) -> azure_core::Result<()> {
+        put_record(&self.cancellations, &cancellation.partition, &cancellation.row_key, cancellation);
+        Ok(())
+    }
+
+    async fn last_expiry(&self, container: &str, blob: &str) -> azure_core::Result<Option<Expiry>> {
+        let partition = job_status::expiry_partition(container);
+        Ok(get_record(&self.expiries, &partition, &base64::encode_url_safe(blob)))
+    }
+
+    async fn put_expiry(&self, expiry: &Expiry) -> azure_core::Result<()> {
+        put_record(&self.expiries, &expiry.partition, &expiry.row_key, expiry);
+        Ok(())
+    }
+
+    async fn job(&self, id: &str) -> azure_core::Result<Option<JobRecord>> {
+        Ok(get_record(&self.jobs, job_status::JOBS_PARTITION, id))
+    }
+
+    async fn put_job(&self, record: &JobRecord) -> azure_core::Result<()> {
+        put_record(&self.jobs, &record.partition, &record.id, record);
+        Ok(())
+    }
+
+    async fn provenance(&self, container: &str, source: &str) -> azure_core::Result<Vec<Provenance>> {
+        let partition = job_status::provenance_partition(container);
+        let records = self.provenance.lock().unwrap();
+        Ok(records
+            .iter()
+            .filter(|((record_partition, _), stored)| *record_partition == partition && stored.record.source == source)
+            .map(|(_, stored)| stored.record.clone())
+            .collect())
+    }
+
+    async fn put_provenance(&self, provenance: &Provenance) -> azure_core::Result<()> {
+        put_record(&self.provenance, &provenance.partition, &provenance.row_key, provenance);
+        Ok(())
+    }
+
+    async fn review(&self, id: &str) -> azure_core::Result<Option<Review>> {
+        Ok(get_record(&self.reviews, job_status::REVIEW_PARTITION, id))
+    }
+
+    async fn reviews(&self) -> azure_core::Result<Vec<Review>> {
+        Ok(self.reviews.lock().unwrap().values().map(|stored| stored.record.clone()).collect())
+    }
+
+    async fn put_review(&self, review: &Review) -> azure_core::Result<()> {
+        put_record(&self.reviews, &review.partition, &review.id, review);
+        Ok(())
+    }
+
+    async fn delete_review(&self, id: &str) -> azure_core::Result<()> {
+        let key = (job_status::REVIEW_PARTITION.to_string(), id.to_string());
+        self.reviews.lock().unwrap().remove(&key);
+        Ok(())
+    }
+
+    async fn purge(&self, cutoff: Cutoff<'_>) -> azure_core::Result<Vec<(RecordKind, u64)>> {
+        Ok(RecordKind::ALL
+            .into_iter()
+            .map(|kind| {
+                let cutoff = cutoff(kind);
+                let count = match kind {
+                    RecordKind::Processed => purge_records(&self.successes, cutoff),
+                    RecordKind::Cancellation => purge_records(&self.cancellations, cutoff),
+                    RecordKind::Expiry => purge_records(&self.expiries, cutoff),
+                    RecordKind::Job => purge_records(&self.jobs, cutoff),
+                    RecordKind::Provenance => purge_records(&self.provenance, cutoff),
+                    RecordKind::Review => purge_records(&self.reviews, cutoff),
+                };
+                (kind, count)
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,389 @@
+// core/src/storage.rs
+
+//! Storage of originals behind [`StorageBackend`], so environments without Azure access can keep
+//! them in S3 or a local directory. `STORAGE_BACKEND` picks the backend:
+//!
+//! - `azure` (the default): the container, as everywhere else;
+//! - `s3`: the bucket named like the container at `S3_STORAGE_ENDPOINT` (default AWS in
+//!   `S3_STORAGE_REGION`, `us-east-1` if unset), addressed path-style and signed with SigV4 using
+//!   `S3_STORAGE_ACCESS_KEY_ID` and `S3_STORAGE_SECRET_ACCESS_KEY`;
+//! - `local`: files under `LOCAL_STORAGE_DIR/<container>/`, with `file://` URLs.
+//!
+//! [`StorageBackend::put_new`] writes only where nothing is stored yet, failing with `409` or
+//! `412` otherwise, see [`is_taken`], so uploads racing for a name don't overwrite each other.
+//!
+//! The worker reads originals through the backend too, so it needs the same `STORAGE_BACKEND`
+//! and, with `local`, the same directory. Its renditions and the other routes rely on blob
+//! features these backends don't share, so they still need Azure.
+
+use async_trait::async_trait;
+use azure_core::{
+    error::{Error, ErrorKind},
+    request_options::IfMatchCondition,
+    StatusCode,
+};
+use azure_storage_blobs::prelude::ContainerClient;
+use futures::StreamExt;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::{env, path::PathBuf, sync::OnceLock};
+use time::OffsetDateTime;
+use tokio::io::AsyncWriteExt;
+
+const DEFAULT_S3_REGION: &str = "us-east-1";
+
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    async fn get(&self, name: &str) -> azure_core::Result<Vec<u8>>;
+    async fn put(&self, name: &str, bytes: Vec<u8>, content_type: &str) -> azure_core::Result<()>;
+    /// Like `put`, but only if nothing is stored under `name`.
+    async fn put_new(&self, name: &str, bytes: Vec<u8>, content_type: &str) -> azure_core::Result<()>;
+    fn url(&self, name: &str) -> azure_core::Result<String>;
+    async fn exists(&self, name: &str) -> azure_core::Result<bool>;
+    async fn delete(&self, name: &str) -> azure_core::Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BackendKind {
+    Azure,
+    S3,
+    Local,
+}
+
+/// The backend `STORAGE_BACKEND` asks for, read on the first call, which the API and the worker
+/// make at startup so an unknown backend stops them there.
+pub fn kind() -> BackendKind {
+    static KIND: OnceLock<BackendKind> = OnceLock::new();
+    *KIND.get_or_init(|| match env::var("STORAGE_BACKEND").as_deref() {
+        Err(_) | Ok("azure") => BackendKind::Azure,
+        Ok("s3") => BackendKind::S3,
+        Ok("local") => BackendKind::Local,
+        Ok(other) => panic!("Unknown STORAGE_BACKEND '{}', use azure, s3 or local", other),
+    })
+}
+
+/// The condition of a write that mustn't replace a blob, `If-None-Match: *`.
+pub fn if_none() -> IfMatchCondition {
+    IfMatchCondition::NotMatch("*".to_string())
+}
+
+/// Whether a conditional write failed because something was stored under the name already.
+pub fn is_taken(e: &Error) -> bool {
+    matches!(e.kind(), ErrorKind::HttpResponse { status: StatusCode::Conflict | StatusCode::PreconditionFailed, .. })
+}
+
+fn taken(name: &str) -> Error {
+    Error::with_message(ErrorKind::http_response(StatusCode::Conflict, Some("BlobAlreadyExists".to_string())), || {
+        format!("{} already exists", name)
+    })
+}
+
+/// The configured backend for `container_client`'s container.
+pub fn from_env(container_client: &ContainerClient) -> Box<dyn StorageBackend> {
+    let container = container_client.container_name();
+    match kind() {
+        BackendKind::Azure => Box::new(AzureBlob(container_client.clone())),
+        BackendKind::S3 => Box::new(S3::from_env(container)),
+        BackendKind::Local => Box::new(LocalFs::from_env(container)),
+    }
+}
+
+pub struct AzureBlob(pub ContainerClient);
+
+#[async_trait]
+impl StorageBackend for AzureBlob {
+    async fn get(&self, name: &str) -> azure_core::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        let mut stream = self.0.blob_client(name).get().into_stream();
+        while let Some(value) = stream.next().await {
+            bytes.extend(&value?.data.collect().await?);
+        }
+        Ok(bytes)
+    }
+
+    async fn put(&self, name: &str, bytes: Vec<u8>, content_type: &str) -> azure_core::Result<()> {
+        self.0
+            .blob_client(name)
+            .put_block_blob(bytes)
+            .content_type(content_type.to_string())
+            .await?;
+        Ok(())
+    }
+
+    async fn put_new(&self, name: &str, bytes: Vec<u8>, content_type: &str) -> azure_core::Result<()> {
+        self.0
+            .blob_client(name)
+            .put_block_blob(bytes)
+            .content_type(content_type.to_string())
+            .if_match(if_none())
+            .await?;
+        Ok(())
+    }
+
+    fn url(&self, name: &str) -> azure_core::Result<String> {
+        Ok(self.0.blob_client(name).url()?.to_string())
+    }
+
+    async fn exists(&self, name: &str) -> azure_core::Result<bool> {
+        self.0.blob_client(name).exists().await
+    }
+
+    async fn delete(&self, name: &str) -> azure_core::Result<()> {
+        self.0.blob_client(name).delete().await?;
+        Ok(())
+    }
+}
+
+pub struct S3 {
+    http_client: reqwest::Client,
+    endpoint: String,
+    region: String,
+    bucket: String,
+    access_key_id: String,
+    secret_access_key: String,
+}
+
+fn s3_error(e: impl std::error::Error + Send + Sync + 'static) -> Error {
+    Error::new(ErrorKind::Io, e)
+}
+
+/// Percent-encodes everything but unreserved characters and, in keys, `/`.
+fn sigv4_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+impl S3 {
+    pub fn from_env(bucket: &str) -> Self {
+        let region = env::var("S3_STORAGE_REGION").unwrap_or_else(|_| DEFAULT_S3_REGION.to_string());
+        S3 {
+            http_client: reqwest::Client::new(),
+            endpoint: env::var("S3_STORAGE_ENDPOINT")
+                .unwrap_or_else(|_| format!("https://s3.{}.amazonaws.com", region))
+                .trim_end_matches('/')
+                .to_string(),
+            region,
+            bucket: bucket.to_string(),
+            access_key_id: env::var("S3_STORAGE_ACCESS_KEY_ID").expect("Please set S3_STORAGE_ACCESS_KEY_ID env variable first!"),
+            secret_access_key: env::var("S3_STORAGE_SECRET_ACCESS_KEY")
+                .expect("Please set S3_STORAGE_SECRET_ACCESS_KEY env variable first!"),
+        }
+    }
+
+    fn path(&self, name: &str) -> String {
+        format!("/{}/{}", sigv4_encode(&self.bucket), sigv4_encode(name))
+    }
+
+    /// A request for `name` signed with SigV4 over its method, path, host, date and payload hash.
+    fn request(&self, method: reqwest::Method, name: &str, body: &[u8]) -> azure_core::Result<reqwest::RequestBuilder> {
+        let path = self.path(name);
+        let url = reqwest::Url::parse(&format!("{}{}", self.endpoint, path))?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(Error::message(ErrorKind::DataConversion, "S3_STORAGE_ENDPOINT has no host")),
+        };
+
+        let now = OffsetDateTime::now_utc();
+        let date = format!("{:04}{:02}{:02}", now.year(), now.month() as u8, now.day());
+        let amz_date = format!("{}T{:02}{:02}{:02}Z", date, now.hour(), now.minute(), now.second());
+        let payload_hash = hex::encode(Sha256::digest(body));
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, host, payload_hash, amz_date, signed_headers, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let key = [date.as_str(), self.region.as_str(), "s3", "aws4_request"]
+            .iter()
+            .fold(format!("AWS4{}", self.secret_access_key).into_bytes(), |key, part| hmac(&key, part));
+        let signature = hex::encode(hmac(&key, &string_to_sign));
+
+        Ok(self
+            .http_client
+            .request(method, url)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date)
+            .header(
+                "authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                    self.access_key_id, scope, signed_headers, signature
+                ),
+            ))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3 {
+    async fn get(&self, name: &str) -> azure_core::Result<Vec<u8>> {
+        let response = self.request(reqwest::Method::GET, name, &[])?.send().await.map_err(s3_error)?;
+        let response = response.error_for_status().map_err(s3_error)?;
+        Ok(response.bytes().await.map_err(s3_error)?.to_vec())
+    }
+
+    async fn put(&self, name: &str, bytes: Vec<u8>, content_type: &str) -> azure_core::Result<()> {
+        self.request(reqwest::Method::PUT, name, &bytes)?
+            .header("content-type", content_type)
+            .body(bytes)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(s3_error)?;
+        Ok(())
+    }
+
+    async fn put_new(&self, name: &str, bytes: Vec<u8>, content_type: &str) -> azure_core::Result<()> {
+        let response = self
+            .request(reqwest::Method::PUT, name, &bytes)?
+            .header("content-type", content_type)
+            .header("if-none-match", "*")
+            .body(bytes)
+            .send()
+            .await
+            .map_err(s3_error)?;
+        match response.status() {
+            reqwest::StatusCode::CONFLICT | reqwest::StatusCode::PRECONDITION_FAILED => Err(taken(name)),
+            _ => response.error_for_status().map(|_| ()).map_err(s3_error),
+        }
+    }
+
+    fn url(&self, name: &str) -> azure_core::Result<String> {
+        Ok(format!("{}{}", self.endpoint, self.path(name)))
+    }
+
+    async fn exists(&self, name: &str) -> azure_core::Result<bool> {
+        let response = self.request(reqwest::Method::HEAD, name, &[])?.send().await.map_err(s3_error)?;
+        match response.status() {
+            reqwest::StatusCode::NOT_FOUND => Ok(false),
+            _ => response.error_for_status().map(|_| true).map_err(s3_error),
+        }
+    }
+
+    async fn delete(&self, name: &str) -> azure_core::Result<()> {
+        self.request(reqwest::Method::DELETE, name, &[])?
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(s3_error)?;
+        Ok(())
+    }
+}
+
+pub struct LocalFs {
+    dir: PathBuf,
+}
+
+impl LocalFs {
+    pub fn from_env(container: &str) -> Self {
+        let root = env::var("LOCAL_STORAGE_DIR").expect("Please set LOCAL_STORAGE_DIR env variable first!");
+        LocalFs {
+            dir: PathBuf::from(root).join(container),
+        }
+    }
+
+    /// The file of `name`, whose `/`s make subdirectories; `..` can't climb out of the container.
+    fn path(&self, name: &str) -> azure_core::Result<PathBuf> {
+        if name.split('/').any(|part| part.is_empty() || part == "." || part == "..") {
+            return Err(Error::with_message(ErrorKind::DataConversion, || format!("Invalid blob name '{}'", name)));
+        }
+        Ok(self.dir.join(name))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalFs {
+    async fn get(&self, name: &str) -> azure_core::Result<Vec<u8>> {
+        Ok(tokio::fs::read(self.path(name)?).await?)
+    }
+
+    async fn put(&self, name: &str, bytes: Vec<u8>, _content_type: &str) -> azure_core::Result<()> {
+        let path = self.path(name)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(path, bytes).await?;
+        Ok(())
+    }
+
+    async fn put_new(&self, name: &str, bytes: Vec<u8>, _content_type: &str) -> azure_core::Result<()> {
+        let path = self.path(name)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut file = match tokio::fs::OpenOptions::new().write(true).create_new(true).open(path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Err(taken(name)),
+            Err(e) => return Err(e.into()),
+        };
+        file.write_all(&bytes).await?;
+        Ok(())
+    }
+
+    fn url(&self, name: &str) -> azure_core::Result<String> {
+        let path = std::path::absolute(self.path(name)?)?;
+        Ok(reqwest::Url::from_file_path(&path)
+            .map_err(|()| Error::with_message(ErrorKind::DataConversion, || format!("No URL for {}", path.display())))?
+            .to_string())
+    }
+
+    async fn exists(&self, name: &str) -> azure_core::Result<bool> {
+        Ok(tokio::fs::try_exists(self.path(name)?).await?)
+    }
+
+    async fn delete(&self, name: &str) -> azure_core::Result<()> {
+        Ok(tokio::fs::remove_file(self.path(name)?).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn writes_new_local_files_only_once() {
+        let dir = env::temp_dir().join(format!("storage-test-{}", Uuid::new_v4()));
+        let local = LocalFs { dir: dir.clone() };
+
+        tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(async {
+            local.put_new("albums/photo.jpg", b"first".to_vec(), "image/jpeg").await.unwrap();
+            let e = local.put_new("albums/photo.jpg", b"second".to_vec(), "image/jpeg").await.unwrap_err();
+            assert!(is_taken(&e), "{:?}", e);
+            assert_eq!(local.get("albums/photo.jpg").await.unwrap(), b"first");
+
+            // a plain write replaces it
+            local.put("albums/photo.jpg", b"third".to_vec(), "image/jpeg").await.unwrap();
+            assert_eq!(local.get("albums/photo.jpg").await.unwrap(), b"third");
+            assert!(local.put_new("../escape.jpg", Vec::new(), "image/jpeg").await.is_err());
+        });
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn tells_lost_names_from_other_failures() {
+        let status = |status| Error::message(ErrorKind::http_response(status, None), "refused");
+        assert!(is_taken(&status(StatusCode::Conflict)));
+        assert!(is_taken(&status(StatusCode::PreconditionFailed)));
+        assert!(!is_taken(&status(StatusCode::NotFound)));
+        assert!(!is_taken(&status(StatusCode::ServiceUnavailable)));
+        assert!(!is_taken(&Error::message(ErrorKind::Io, "connection reset")));
+    }
+}
@@ -0,0 +1,18 @@
+// core/src/svg.rs
+
+//! SVG uploads, which the worker rasterizes with librsvg's `rsvg-convert` once `CONTENT_ROUTES`
+//! sends them to the `rasterize` pipeline; without that route they're refused like any other file
+//! the pipeline can't read. Being XML, they are told apart by an `<svg` element near their start.
+
+pub const CONTENT_TYPE: &str = "image/svg+xml";
+pub const EXTENSION: &str = "svg";
+
+/// How far into a file its root element is looked for, past an XML declaration, comments and a
+/// doctype.
+const SNIFF_LEN: usize = 1024;
+
+pub fn is_svg(bytes: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(&bytes[..bytes.len().min(SNIFF_LEN)]);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+    head.starts_with('<') && head.contains("<svg") && !head.contains("<html")
+}
@@ -0,0 +1,53 @@
+// core/src/tables.rs
+
+//! Table Storage plumbing for the tables kept next to the blobs in `AZURE_STORAGE_ACCOUNT`.
+
+use azure_core::StatusCode;
+use azure_data_tables::{clients::TableServiceClientBuilder, prelude::TableClient};
+use std::env;
+
+use crate::{
+    azure,
+    failover::{self, Location},
+};
+
+/// A client for `table`, or for the table named by the `env_name` variable when it is set.
+pub fn table_client(env_name: &str, table: &str) -> TableClient {
+    table_client_in(Location::Primary, env_name, table)
+}
+
+/// Like [`table_client`], for a table in the storage account at `location`.
+pub fn table_client_in(location: Location, env_name: &str, table: &str) -> TableClient {
+    let table = env::var(env_name).unwrap_or_else(|_| table.to_string());
+
+    let (storage_account, storage_credentials) = failover::credentials(location);
+    TableServiceClientBuilder::new(storage_account, storage_credentials)
+        .client_options(azure::client_options())
+        .build()
+        .table_client(table)
+}
+
+/// Creates the table, treating one that already exists as success.
+pub async fn create_if_missing(table_client: &TableClient) -> azure_core::Result<()> {
+    match table_client.create().await {
+        Ok(_) => Ok(()),
+        Err(e) if e.as_http_error().is_some_and(|e| e.status() == StatusCode::Conflict) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether a conditional write lost to another writer.
+pub fn is_conflict(e: &azure_core::Error) -> bool {
+    e.as_http_error()
+        .is_some_and(|e| e.status() == StatusCode::PreconditionFailed || e.status() == StatusCode::Conflict)
+}
+
+/// Whether `e` says the table or entity doesn't exist.
+pub fn is_not_found(e: &azure_core::Error) -> bool {
+    e.as_http_error().is_some_and(|e| e.status() == StatusCode::NotFound)
+}
+
+/// `value` quoted for a string literal of a query filter.
+pub fn escape(value: &str) -> String {
+    value.replace('\'', "''")
+}
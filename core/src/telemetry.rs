@@ -0,0 +1,219 @@
+// core/src/telemetry.rs
+
+//! Minimal Azure Application Insights exporter.
+//!
+//! Set `APPLICATIONINSIGHTS_CONNECTION_STRING` and call [`init`] once at startup; every `track_*`
+//! function is a no-op otherwise. Items are buffered and posted to the ingestion endpoint's
+//! `/v2/track` API in the background. Short-lived processes should call [`flush`] before exiting.
+
+use serde_json::{json, Map, Value};
+use std::{
+    collections::HashMap,
+    env,
+    future::Future,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::logging::redact;
+use tracing::{info, warn};
+
+const DEFAULT_INGESTION_ENDPOINT: &str = "https://dc.services.visualstudio.com";
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+/// Flush early once this many items are buffered.
+const MAX_BUFFERED: usize = 100;
+
+static TELEMETRY: OnceLock<Telemetry> = OnceLock::new();
+
+struct Telemetry {
+    instrumentation_key: String,
+    track_url: String,
+    role: String,
+    client: reqwest::Client,
+    buffer: Mutex<Vec<Value>>,
+}
+
+/// Parses `Key=Value;Key=Value` connection strings.
+pub(crate) fn parse_connection_string(connection_string: &str) -> HashMap<String, String> {
+    connection_string
+        .split(';')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (key.trim().to_lowercase(), value.trim().to_string()))
+        .collect()
+}
+
+/// Starts the exporter for `role` (shown as the cloud role name) if a connection string is configured.
+/// Must be called from within a Tokio runtime.
+pub fn init(role: &str) {
+    let Ok(connection_string) = env::var("APPLICATIONINSIGHTS_CONNECTION_STRING") else {
+        return;
+    };
+    let settings = parse_connection_string(&connection_string);
+    let Some(instrumentation_key) = settings.get("instrumentationkey").cloned() else {
+        warn!("APPLICATIONINSIGHTS_CONNECTION_STRING has no InstrumentationKey, telemetry disabled");
+        return;
+    };
+    let endpoint = settings
+        .get("ingestionendpoint")
+        .map(String::as_str)
+        .unwrap_or(DEFAULT_INGESTION_ENDPOINT)
+        .trim_end_matches('/');
+
+    let telemetry = Telemetry {
+        instrumentation_key,
+        track_url: format!("{}/v2/track", endpoint),
+        role: role.to_string(),
+        client: reqwest::Client::new(),
+        buffer: Mutex::new(Vec::new()),
+    };
+    if TELEMETRY.set(telemetry).is_ok() {
+        tokio::spawn(async {
+            let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+            loop {
+                interval.tick().await;
+                flush().await;
+            }
+        });
+        info!("Application Insights telemetry enabled for {}", role);
+    }
+}
+
+/// Whether [`init`] found a connection string and is exporting.
+pub fn enabled() -> bool {
+    TELEMETRY.get().is_some()
+}
+
+/// Sends everything buffered so far.
+pub async fn flush() {
+    let Some(telemetry) = TELEMETRY.get() else {
+        return;
+    };
+    let items = std::mem::take(&mut *telemetry.buffer.lock().unwrap());
+    if items.is_empty() {
+        return;
+    }
+
+    let result = telemetry
+        .client
+        .post(&telemetry.track_url)
+        .json(&items)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    if let Err(e) = result {
+        warn!("Failed to export {} telemetry items: {:?}", items.len(), e);
+    }
+}
+
+/// App Insights expects durations as `hh:mm:ss.fffffff`.
+fn format_duration(duration: Duration) -> String {
+    let total = duration.as_secs();
+    format!(
+        "{:02}:{:02}:{:02}.{:07}",
+        total / 3600,
+        (total / 60) % 60,
+        total % 60,
+        duration.subsec_nanos() / 100
+    )
+}
+
+fn track(kind: &str, base_type: &str, base_data: Value) {
+    let Some(telemetry) = TELEMETRY.get() else {
+        return;
+    };
+    let envelope = json!({
+        "name": format!("Microsoft.ApplicationInsights.{}", kind),
+        "time": OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default(),
+        "iKey": telemetry.instrumentation_key,
+        "tags": { "ai.cloud.role": telemetry.role },
+        "data": { "baseType": base_type, "baseData": base_data },
+    });
+
+    let should_flush = {
+        let mut buffer = telemetry.buffer.lock().unwrap();
+        buffer.push(envelope);
+        buffer.len() >= MAX_BUFFERED
+    };
+    if should_flush {
+        tokio::spawn(flush());
+    }
+}
+
+/// An incoming request (an HTTP call on the API, a queue message on the worker).
+pub fn track_request(name: &str, url: &str, duration: Duration, response_code: &str, success: bool) {
+    track(
+        "Request",
+        "RequestData",
+        json!({
+            "ver": 2,
+            "id": uuid::Uuid::new_v4().to_string(),
+            "name": name,
+            "url": url,
+            "duration": format_duration(duration),
+            "responseCode": response_code,
+            "success": success,
+        }),
+    );
+}
+
+/// An outgoing call, `kind` being e.g. `Azure blob` or `Azure Service Bus`.
+pub fn track_dependency(kind: &str, target: &str, name: &str, duration: Duration, success: bool) {
+    track(
+        "RemoteDependency",
+        "RemoteDependencyData",
+        json!({
+            "ver": 2,
+            "id": uuid::Uuid::new_v4().to_string(),
+            "type": kind,
+            "target": target,
+            "name": name,
+            "duration": format_duration(duration),
+            "success": success,
+        }),
+    );
+}
+
+pub fn track_exception(type_name: &str, message: &str) {
+    track(
+        "Exception",
+        "ExceptionData",
+        json!({
+            "ver": 2,
+            "exceptions": [{ "typeName": type_name, "message": redact(message), "hasFullStack": false }],
+        }),
+    );
+}
+
+pub fn track_event(name: &str, properties: &[(&str, String)]) {
+    let properties: Map<String, Value> = properties
+        .iter()
+        .map(|(key, value)| (key.to_string(), Value::String(value.clone())))
+        .collect();
+    track("Event", "EventData", json!({ "ver": 2, "name": name, "properties": properties }));
+}
+
+/// A single measurement, e.g. a throttled call or a queue depth, aggregated by App Insights.
+pub fn track_metric(name: &str, value: f64, properties: &[(&str, String)]) {
+    let properties: Map<String, Value> = properties
+        .iter()
+        .map(|(key, value)| (key.to_string(), Value::String(value.clone())))
+        .collect();
+    track(
+        "Metric",
+        "MetricData",
+        json!({
+            "ver": 2,
+            "metrics": [{ "name": name, "value": value, "count": 1 }],
+            "properties": properties,
+        }),
+    );
+}
+
+/// Awaits `call` and records it as a dependency, successful when it returns `Ok`.
+pub async fn dependency<T, E>(kind: &str, target: &str, name: &str, call: impl Future<Output = Result<T, E>>) -> Result<T, E> {
+    let started = Instant::now();
+    let result = call.await;
+    track_dependency(kind, target, name, started.elapsed(), result.is_ok());
+    result
+}
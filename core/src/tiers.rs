@@ -0,0 +1,150 @@
+// core/src/tiers.rs
+
+//! Tenant tiers: named pipeline configurations a tenant's policy points to with `tier`, so what a
+//! tenant's jobs cost to run follows what it pays for. A tier limits the formats renditions are
+//! encoded in and the sizes made of an upload, the resized rendition plus its variants, and may
+//! set the priority its jobs are queued at. Two are built in:
+//!
+//! - `free`: JPEG only, 2 sizes, `low` priority;
+//! - `pro`: JPEG, PNG and WebP, 5 sizes, `high` priority.
+//!
+//! `TENANT_TIERS`, a JSON object of tiers by name, replaces them, e.g.
+//! `{"free": {"output_formats": ["jpeg"], "max_sizes": 2, "priority": "low"}}`. AVIF isn't an
+//! output format, see `output_format.rs`, so no tier can offer it.
+//!
+//! The API refuses an upload asking for more than its tier allows and stamps the tier on the
+//! message. The worker applies the tier again as it takes the message, see [`apply`], so messages
+//! queued before a tier changed stay within it, and encodes in the tier's first format a rendition
+//! whose source's default format the tier doesn't offer.
+
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, env, sync::OnceLock};
+
+use crate::{
+    message::{ImageMessage, Priority},
+    output_format::OutputFormat,
+};
+
+/// What a tenant's jobs may ask for.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Tier {
+    /// Formats renditions may be encoded in, the first standing in for the others.
+    #[serde(default = "all_formats")]
+    pub output_formats: Vec<OutputFormat>,
+    /// Most sizes made of an upload, the resized rendition included.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_sizes: Option<usize>,
+    /// Priority of the tier's jobs, whatever the upload asks for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<Priority>,
+}
+
+fn all_formats() -> Vec<OutputFormat> {
+    OutputFormat::ALL.to_vec()
+}
+
+fn built_in() -> BTreeMap<String, Tier> {
+    BTreeMap::from([
+        (
+            "free".to_string(),
+            Tier {
+                output_formats: vec![OutputFormat::Jpeg],
+                max_sizes: Some(2),
+                priority: Some(Priority::Low),
+            },
+        ),
+        (
+            "pro".to_string(),
+            Tier {
+                output_formats: all_formats(),
+                max_sizes: Some(5),
+                priority: Some(Priority::High),
+            },
+        ),
+    ])
+}
+
+/// The tiers, from `TENANT_TIERS` or built in.
+pub fn tiers() -> &'static BTreeMap<String, Tier> {
+    static TIERS: OnceLock<BTreeMap<String, Tier>> = OnceLock::new();
+    TIERS.get_or_init(|| match env::var("TENANT_TIERS") {
+        Ok(json) => {
+            let tiers: BTreeMap<String, Tier> = serde_json::from_str(&json).unwrap_or_else(|e| panic!("Invalid TENANT_TIERS: {}", e));
+            if let Some((name, _)) = tiers.iter().find(|(_, tier)| tier.output_formats.is_empty() || tier.max_sizes == Some(0)) {
+                panic!("Invalid TENANT_TIERS: tier {} allows no output format or no size", name);
+            }
+            tiers
+        }
+        Err(_) => built_in(),
+    })
+}
+
+/// The tier named `name`.
+pub fn get(name: &str) -> Option<&'static Tier> {
+    tiers().get(name)
+}
+
+/// The tier of the job `image` belongs to, if it has one.
+fn of(image: &ImageMessage) -> Option<&'static Tier> {
+    image.tier.as_deref().and_then(get)
+}
+
+impl Tier {
+    pub fn allows(&self, format: OutputFormat) -> bool {
+        self.output_formats.contains(&format)
+    }
+
+    /// `format`, or the tier's first format if it doesn't offer it.
+    pub fn output_format(&self, format: OutputFormat) -> OutputFormat {
+        if self.allows(format) {
+            format
+        } else {
+            self.output_formats.first().copied().unwrap_or(format)
+        }
+    }
+
+    /// Checks an upload asking for `output_format` and `sizes` sizes.
+    pub fn check(&self, output_format: Option<OutputFormat>, sizes: usize) -> Result<(), String> {
+        if let Some(format) = output_format.filter(|format| !self.allows(*format)) {
+            let offered: Vec<&str> = self.output_formats.iter().map(|format| format.name()).collect();
+            return Err(format!("The tenant's tier doesn't offer {}, only {}", format.name(), offered.join(", ")));
+        }
+        if let Some(max_sizes) = self.max_sizes.filter(|max| sizes > *max) {
+            return Err(format!("The tenant's tier makes at most {} sizes, {} were asked for", max_sizes, sizes));
+        }
+        Ok(())
+    }
+}
+
+/// Brings `image` within its tier: an output format the tier doesn't offer is replaced, variants
+/// past its sizes are dropped and its priority is set. Returns what was changed, for the log.
+pub fn apply(image: &mut ImageMessage) -> Vec<String> {
+    let Some(tier) = of(image) else {
+        return Vec::new();
+    };
+    let mut changes = Vec::new();
+    if let Some(format) = image.output_format.filter(|format| !tier.allows(*format)) {
+        let replacement = tier.output_format(format);
+        changes.push(format!("{} renditions are made as {}", format.name(), replacement.name()));
+        image.output_format = Some(replacement);
+    }
+    // the resized rendition is one of the sizes
+    let max_variants = tier.max_sizes.map(|max| max.saturating_sub(1));
+    if let Some(max_variants) = max_variants.filter(|max| image.variants.len() > *max) {
+        changes.push(format!("{} variants past the tier's sizes were dropped", image.variants.len() - max_variants));
+        image.variants.truncate(max_variants);
+    }
+    if let Some(priority) = tier.priority {
+        image.priority = priority;
+    }
+    changes
+}
+
+/// The format renditions of `image` are encoded in when its source's default is `default`.
+pub fn output_format(image: &ImageMessage, default: OutputFormat) -> OutputFormat {
+    match of(image) {
+        Some(tier) => tier.output_format(default),
+        None => default,
+    }
+}
@@ -0,0 +1,318 @@
+// core/src/trace.rs
+
+//! Trace context followed from an HTTP request through the queue to every stage the worker runs
+//! for it, and an OpenTelemetry exporter for the spans.
+//!
+//! The API starts a span per request with [`span`], continuing the trace of a W3C `traceparent`
+//! header when the client sends one, and stamps the message it queues with [`traceparent`]; the
+//! worker continues that trace for each stage. Every log line within a span shows its `trace_id`,
+//! so logs of both binaries can be correlated without an exporter.
+//!
+//! Set `OTEL_EXPORTER_OTLP_ENDPOINT` (or `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` for the full URL)
+//! and call [`init`] once at startup to post finished spans to an OTLP/HTTP collector as JSON,
+//! buffered and sent in the background like `telemetry.rs` does. `OTEL_SERVICE_NAME` overrides
+//! the service name and `OTEL_EXPORTER_OTLP_HEADERS` (`key=value,...`) adds headers, e.g. an API
+//! key. Short-lived processes should call [`flush`] before exiting.
+
+use serde_json::{json, Value};
+use std::{
+    env,
+    fmt::Debug,
+    sync::{Mutex, OnceLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+    Event, Level, Span, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer, Registry};
+use tracing::{info, warn};
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+/// Spans finished past this many waiting to be sent are dropped, so an unreachable collector
+/// can't exhaust memory.
+const MAX_BUFFERED: usize = 4096;
+
+/// The W3C trace context header.
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+/// The trace a span belongs to and its own id, as hex.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: String,
+    pub span_id: String,
+}
+
+impl TraceContext {
+    /// Parses a `traceparent`, e.g. `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`.
+    pub fn parse(traceparent: &str) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+        let (version, trace_id, span_id, flags) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        let is_id = |id: &str, len: usize| id.len() == len && id.bytes().all(|b| b.is_ascii_hexdigit()) && id.bytes().any(|b| b != b'0');
+        if version.len() != 2 || version == "ff" || flags.len() != 2 || !is_id(trace_id, 32) || !is_id(span_id, 16) {
+            return None;
+        }
+        Some(TraceContext {
+            trace_id: trace_id.to_ascii_lowercase(),
+            span_id: span_id.to_ascii_lowercase(),
+        })
+    }
+
+    /// As a `traceparent`, sampled.
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-01", self.trace_id, self.span_id)
+    }
+}
+
+/// A random id of `len` hex digits, at most 32.
+fn random_id(len: usize) -> String {
+    uuid::Uuid::new_v4().simple().to_string()[..len].to_string()
+}
+
+/// A span for `name`, e.g. `POST /upload` or `process resize`, continuing the trace of
+/// `traceparent` or starting a new one without it.
+pub fn span(name: &str, traceparent: Option<&str>) -> Span {
+    let parent = traceparent.and_then(TraceContext::parse);
+    let trace_id = parent.as_ref().map_or_else(|| random_id(32), |parent| parent.trace_id.clone());
+    let span = tracing::info_span!("trace", otel.name = name, trace_id = %trace_id, parent_span_id = tracing::field::Empty);
+    if let Some(parent) = parent {
+        span.record("parent_span_id", parent.span_id);
+    }
+    span
+}
+
+/// The trace context of the current span, if it or one of its parents is traced.
+pub fn current() -> Option<TraceContext> {
+    Span::current()
+        .with_subscriber(|(id, dispatch)| {
+            let registry = dispatch.downcast_ref::<Registry>()?;
+            registry.span(id)?.scope().find_map(|span| {
+                span.extensions().get::<SpanData>().map(|data| TraceContext {
+                    trace_id: data.trace_id.clone(),
+                    span_id: data.span_id.clone(),
+                })
+            })
+        })
+        .flatten()
+}
+
+/// The `traceparent` of the current span, for a message or request continuing its trace.
+pub fn traceparent() -> Option<String> {
+    current().map(|context| context.traceparent())
+}
+
+/// What is kept of a span until it closes.
+struct SpanData {
+    trace_id: String,
+    span_id: String,
+    parent_span_id: Option<String>,
+    name: String,
+    /// Started by [`span`] for a request or message, rather than within one.
+    entry: bool,
+    started: SystemTime,
+    attributes: Vec<(String, String)>,
+    /// An error was logged within the span.
+    failed: bool,
+}
+
+impl SpanData {
+    /// The span in the OTLP JSON encoding.
+    fn to_otlp(&self, ended: SystemTime) -> Value {
+        let nanos = |time: SystemTime| time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string();
+        let attributes: Vec<Value> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+            .collect();
+        json!({
+            "traceId": self.trace_id,
+            "spanId": self.span_id,
+            "parentSpanId": self.parent_span_id.clone().unwrap_or_default(),
+            "name": self.name,
+            // server for requests and messages, internal otherwise
+            "kind": if self.entry { 2 } else { 1 },
+            "startTimeUnixNano": nanos(self.started),
+            "endTimeUnixNano": nanos(ended),
+            "attributes": attributes,
+            "status": { "code": if self.failed { 2 } else { 0 } },
+        })
+    }
+}
+
+/// Fields of a span, the ones [`span`] sets apart from its attributes.
+#[derive(Default)]
+struct Fields {
+    name: Option<String>,
+    trace_id: Option<String>,
+    parent_span_id: Option<String>,
+    attributes: Vec<(String, String)>,
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "otel.name" => self.name = Some(value.to_string()),
+            "trace_id" => self.trace_id = Some(value.to_string()),
+            "parent_span_id" => self.parent_span_id = Some(value.to_string()),
+            name => self.attributes.push((name.to_string(), value.to_string())),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+}
+
+/// Gives every span a trace and span id, and hands spans to the exporter as they close.
+pub struct TraceLayer;
+
+impl<S> Layer<S> for TraceLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attributes: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = Fields::default();
+        attributes.record(&mut fields);
+        let parent = span
+            .parent()
+            .and_then(|parent| parent.extensions().get::<SpanData>().map(|data| (data.trace_id.clone(), data.span_id.clone())));
+        let entry = fields.trace_id.is_some();
+        let (trace_id, parent_span_id) = match (fields.trace_id, parent) {
+            (Some(trace_id), _) => (trace_id, fields.parent_span_id),
+            (None, Some((trace_id, span_id))) => (trace_id, Some(span_id)),
+            (None, None) => (random_id(32), None),
+        };
+        let data = SpanData {
+            trace_id,
+            span_id: random_id(16),
+            parent_span_id,
+            name: fields.name.unwrap_or_else(|| attributes.metadata().name().to_string()),
+            entry,
+            started: SystemTime::now(),
+            attributes: fields.attributes,
+            failed: false,
+        };
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = Fields::default();
+        values.record(&mut fields);
+        let mut extensions = span.extensions_mut();
+        if let Some(data) = extensions.get_mut::<SpanData>() {
+            if fields.parent_span_id.is_some() {
+                data.parent_span_id = fields.parent_span_id;
+            }
+            data.attributes.extend(fields.attributes);
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if *event.metadata().level() != Level::ERROR {
+            return;
+        }
+        if let Some(span) = ctx.event_span(event) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                data.failed = true;
+            };
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(exporter) = EXPORTER.get() else {
+            return;
+        };
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(data) = span.extensions_mut().remove::<SpanData>() else {
+            return;
+        };
+        let mut buffer = exporter.buffer.lock().unwrap();
+        if buffer.len() < MAX_BUFFERED {
+            buffer.push(data.to_otlp(SystemTime::now()));
+        }
+    }
+}
+
+static EXPORTER: OnceLock<Exporter> = OnceLock::new();
+
+struct Exporter {
+    url: String,
+    headers: Vec<(String, String)>,
+    service: String,
+    client: reqwest::Client,
+    buffer: Mutex<Vec<Value>>,
+}
+
+/// Starts exporting spans as `service` if an OTLP endpoint is configured. Must be called from
+/// within a Tokio runtime.
+pub fn init(service: &str) {
+    let url = match (env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"), env::var("OTEL_EXPORTER_OTLP_ENDPOINT")) {
+        (Ok(url), _) => url,
+        (_, Ok(endpoint)) => format!("{}/v1/traces", endpoint.trim_end_matches('/')),
+        _ => return,
+    };
+    let headers = env::var("OTEL_EXPORTER_OTLP_HEADERS")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect();
+    let exporter = Exporter {
+        url,
+        headers,
+        service: env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| service.to_string()),
+        client: reqwest::Client::new(),
+        buffer: Mutex::new(Vec::new()),
+    };
+    let url = exporter.url.clone();
+    if EXPORTER.set(exporter).is_ok() {
+        tokio::spawn(async {
+            let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+            loop {
+                interval.tick().await;
+                flush().await;
+            }
+        });
+        info!("Exporting traces of {} to {}", service, url);
+    }
+}
+
+/// Whether [`init`] found an endpoint and is exporting.
+pub fn enabled() -> bool {
+    EXPORTER.get().is_some()
+}
+
+/// Sends every span finished so far.
+pub async fn flush() {
+    let Some(exporter) = EXPORTER.get() else {
+        return;
+    };
+    let spans = std::mem::take(&mut *exporter.buffer.lock().unwrap());
+    if spans.is_empty() {
+        return;
+    }
+
+    let count = spans.len();
+    let body = json!({
+        "resourceSpans": [{
+            "resource": { "attributes": [{ "key": "service.name", "value": { "stringValue": exporter.service } }] },
+            "scopeSpans": [{ "scope": { "name": env!("CARGO_PKG_NAME") }, "spans": spans }],
+        }],
+    });
+    let mut request = exporter.client.post(&exporter.url).json(&body);
+    for (key, value) in &exporter.headers {
+        request = request.header(key, value);
+    }
+    if let Err(e) = request.send().await.and_then(|response| response.error_for_status()) {
+        warn!("Failed to export {} spans: {:?}", count, e);
+    }
+}
@@ -0,0 +1,182 @@
+// core/src/trailing_data.rs
+
+//! Detection of data appended after the end of an image, the trick behind polyglot files that are
+//! a valid JPEG to one reader and a ZIP or script to another. The end is found by walking the
+//! format's structure: JPEG segments up to the end-of-image marker, PNG chunks up to `IEND`, GIF
+//! blocks up to the trailer and the RIFF size of WebP. Other formats aren't checked. The same walk
+//! tells an upload cut short, whose end is never reached, see `image_checks.rs`.
+//!
+//! `TRAILING_DATA_MAX_BYTES` turns the check on, tolerating that many bytes after the image since
+//! some cameras append their own trailers. `TRAILING_DATA_ACTION` says what happens to an upload
+//! with more: `reject` (the default) refuses it, `strip` stores the original without them.
+//! Renditions are re-encoded from decoded pixels, so they never carry appended data either way.
+
+use std::env;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Reject,
+    Strip,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TrailingDataPolicy {
+    pub max_bytes: usize,
+    pub action: Action,
+}
+
+impl TrailingDataPolicy {
+    /// The configured policy, `None` when `TRAILING_DATA_MAX_BYTES` isn't set.
+    pub fn from_env() -> Option<Self> {
+        let max_bytes = env::var("TRAILING_DATA_MAX_BYTES").ok()?.parse().ok()?;
+        let action = match env::var("TRAILING_DATA_ACTION").as_deref() {
+            Err(_) | Ok("reject") => Action::Reject,
+            Ok("strip") => Action::Strip,
+            Ok(other) => panic!("Unknown TRAILING_DATA_ACTION '{}', use reject or strip", other),
+        };
+        Some(TrailingDataPolicy { max_bytes, action })
+    }
+
+    /// Refuses `bytes` or strips them down to the image when more than `max_bytes` follow it.
+    pub fn apply(&self, filename: &str, bytes: &mut Vec<u8>) -> Result<(), String> {
+        let Some(end) = image_end(bytes) else {
+            return Ok(());
+        };
+        let trailing = bytes.len() - end;
+        if trailing <= self.max_bytes {
+            return Ok(());
+        }
+        match self.action {
+            Action::Reject => Err(format!(
+                "'{}' has {} bytes of unexpected data after the image, at most {} are accepted",
+                filename, trailing, self.max_bytes
+            )),
+            Action::Strip => {
+                bytes.truncate(end);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Applies the configured policy to one uploaded file, accepting everything when it's off.
+pub fn check(filename: &str, bytes: &mut Vec<u8>) -> Result<(), String> {
+    match TrailingDataPolicy::from_env() {
+        Some(policy) => policy.apply(filename, bytes),
+        None => Ok(()),
+    }
+}
+
+/// Offset just past the image data of a JPEG, PNG, GIF or WebP file, or `None` for other formats
+/// and files too truncated to tell.
+pub fn image_end(bytes: &[u8]) -> Option<usize> {
+    end_walker(bytes).and_then(|walk| walk(bytes))
+}
+
+/// Whether the image data of a JPEG, PNG, GIF or WebP file ends within `bytes`. Other formats
+/// aren't walked and count as complete.
+pub fn is_complete(bytes: &[u8]) -> bool {
+    end_walker(bytes).is_none_or(|walk| walk(bytes).is_some())
+}
+
+/// Offset just past the image data, `None` when it isn't reached.
+type EndWalk = fn(&[u8]) -> Option<usize>;
+
+/// The walk finding the end of the format `bytes` start with, `None` for formats without one.
+fn end_walker(bytes: &[u8]) -> Option<EndWalk> {
+    if bytes.starts_with(&[0xFF, 0xD8]) {
+        Some(jpeg_end)
+    } else if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some(png_end)
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some(gif_end)
+    } else if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WEBP") {
+        Some(webp_end)
+    } else {
+        None
+    }
+}
+
+/// Walks the segments, skipping over entropy-coded data after each scan, to the EOI marker.
+/// Thumbnails embedded in APP segments are skipped whole, so their EOI isn't mistaken for the end.
+fn jpeg_end(bytes: &[u8]) -> Option<usize> {
+    let mut i = 2;
+    loop {
+        if *bytes.get(i)? != 0xFF {
+            return None;
+        }
+        let marker = *bytes.get(i + 1)?;
+        match marker {
+            // fill byte before a marker
+            0xFF => i += 1,
+            0xD9 => return Some(i + 2),
+            0x01 | 0xD0..=0xD7 => i += 2,
+            _ => {
+                let length = u16::from_be_bytes([*bytes.get(i + 2)?, *bytes.get(i + 3)?]) as usize;
+                i += 2 + length;
+                if marker == 0xDA {
+                    // scan data runs to the next marker other than a stuffed 0xFF or a restart
+                    loop {
+                        i += bytes.get(i..)?.iter().position(|&b| b == 0xFF)?;
+                        match *bytes.get(i + 1)? {
+                            0x00 | 0xD0..=0xD7 | 0xFF => i += 1,
+                            _ => break,
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn png_end(bytes: &[u8]) -> Option<usize> {
+    let mut i = 8;
+    loop {
+        let header = bytes.get(i..i + 8)?;
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        // length, type, data and CRC
+        let end = i.checked_add(12)?.checked_add(length)?;
+        if end > bytes.len() {
+            return None;
+        }
+        if &header[4..8] == b"IEND" {
+            return Some(end);
+        }
+        i = end;
+    }
+}
+
+fn gif_end(bytes: &[u8]) -> Option<usize> {
+    let color_table = |flags: u8| if flags & 0x80 != 0 { 3 << ((flags & 0x07) + 1) } else { 0 };
+    let mut i = 13 + color_table(*bytes.get(10)?);
+    loop {
+        match *bytes.get(i)? {
+            0x3B => return Some(i + 1),
+            // extension: introducer, label, sub-blocks
+            0x21 => i = skip_sub_blocks(bytes, i + 2)?,
+            // image: descriptor, local color table, LZW code size, sub-blocks
+            0x2C => {
+                let flags = *bytes.get(i + 9)?;
+                i = skip_sub_blocks(bytes, i + 10 + color_table(flags) + 1)?;
+            }
+            _ => return None,
+        }
+    }
+}
+
+fn skip_sub_blocks(bytes: &[u8], mut i: usize) -> Option<usize> {
+    loop {
+        let length = *bytes.get(i)? as usize;
+        i += 1 + length;
+        if length == 0 {
+            return Some(i);
+        }
+    }
+}
+
+fn webp_end(bytes: &[u8]) -> Option<usize> {
+    let size = u32::from_le_bytes(bytes.get(4..8)?.try_into().ok()?) as usize;
+    // chunks are padded to an even size
+    let end = 8 + size + (size & 1);
+    (end <= bytes.len()).then_some(end)
+}
@@ -0,0 +1,101 @@
+// core/src/usage_store.rs
+
+//! Tenant usage counted against quotas, kept in the table named by `QUOTA_TABLE` (default
+//! `tenantusage`), one entity per tenant and meter. Each entity also remembers the highest alert
+//! threshold already crossed, so a tenant is warned once per threshold rather than per upload.
+
+use azure_core::error::{Error, ErrorKind};
+use azure_data_tables::{prelude::TableClient, IfMatchCondition};
+use serde::{Deserialize, Serialize};
+
+use crate::tables;
+
+const DEFAULT_TABLE: &str = "tenantusage";
+/// Tries at a read-modify-write before giving up on concurrent writers.
+const MAX_ATTEMPTS: usize = 5;
+/// Shares of a limit, in percent, at which the tenant is warned.
+pub const ALERT_PERCENTS: [i64; 2] = [80, 95];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct UsageEntry {
+    #[serde(rename = "PartitionKey")]
+    tenant: String,
+    #[serde(rename = "RowKey")]
+    meter: String,
+    used: i64,
+    /// Highest of [`ALERT_PERCENTS`] already crossed.
+    alerted_percent: i64,
+}
+
+/// A meter's usage after a charge, with the alert threshold the charge crossed, if any.
+#[derive(Debug, Clone, Copy)]
+pub struct Charged {
+    pub used: i64,
+    pub limit: i64,
+    pub crossed: Option<i64>,
+}
+
+impl Charged {
+    pub fn percent(&self) -> f64 {
+        self.used as f64 * 100.0 / self.limit.max(1) as f64
+    }
+}
+
+fn table_client() -> TableClient {
+    tables::table_client("QUOTA_TABLE", DEFAULT_TABLE)
+}
+
+/// Adds `amount` to the tenant's `meter`, or returns `None` without changing it when that would
+/// take it past `limit`.
+pub async fn charge(tenant: &str, meter: &str, amount: i64, limit: i64) -> azure_core::Result<Option<Charged>> {
+    let table_client = table_client();
+    tables::create_if_missing(&table_client).await?;
+    let entity_client = table_client.partition_key_client(tenant).entity_client(meter);
+
+    for _ in 0..MAX_ATTEMPTS {
+        let (mut entry, etag) = match entity_client.get::<UsageEntry>().await {
+            Ok(response) => (response.entity, Some(response.etag)),
+            Err(e) if tables::is_not_found(&e) => {
+                let entry = UsageEntry {
+                    tenant: tenant.to_string(),
+                    meter: meter.to_string(),
+                    ..Default::default()
+                };
+                (entry, None)
+            }
+            Err(e) => return Err(e),
+        };
+        if entry.used + amount > limit {
+            return Ok(None);
+        }
+        entry.used += amount;
+        let percent = entry.used * 100 / limit.max(1);
+        let crossed = ALERT_PERCENTS
+            .iter()
+            .rev()
+            .copied()
+            .find(|threshold| percent >= *threshold && *threshold > entry.alerted_percent);
+        if let Some(threshold) = crossed {
+            entry.alerted_percent = threshold;
+        }
+
+        let written = match etag {
+            Some(etag) => entity_client.update(&entry, IfMatchCondition::Etag(etag))?.await.map(|_| ()),
+            None => table_client.insert::<_, UsageEntry>(&entry)?.await.map(|_| ()),
+        };
+        match written {
+            Ok(()) => {
+                return Ok(Some(Charged {
+                    used: entry.used,
+                    limit,
+                    crossed,
+                }))
+            }
+            Err(e) if tables::is_conflict(&e) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(Error::with_message(ErrorKind::Other, || {
+        format!("Too many concurrent updates of {}'s {} usage", tenant, meter)
+    }))
+}
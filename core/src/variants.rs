@@ -0,0 +1,76 @@
+// core/src/variants.rs
+
+//! Extra sizes of the resized rendition for responsive frontends, e.g. `thumb:128,medium:512,large:1024`.
+//! Each variant is scaled from the same decoded source to its width, keeping the aspect ratio and
+//! never upscaling, and stored as `<name>_<filename>`. The API reads the default list from
+//! `RESIZE_VARIANTS` at startup, refusing to start if it doesn't parse; uploads override it with
+//! `variants`, an empty value asking for none.
+
+use serde::{Deserialize, Serialize};
+use std::{env, sync::OnceLock};
+
+use crate::naming;
+
+/// At most this many variants per upload, each one being another encode and blob.
+pub const MAX_VARIANTS: usize = 8;
+const MAX_NAME_LEN: usize = 16;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Variant {
+    /// Prefix of the variant's blob name, made of lowercase letters and digits.
+    pub name: String,
+    pub width: u32,
+}
+
+impl Variant {
+    /// Blob name of the variant of `filename`.
+    pub fn blob_name(&self, filename: &str) -> String {
+        naming::rendition(&format!("{}_", self.name), filename)
+    }
+
+    /// Size of the variant of a `width` x `height` source, at least 1x1 and never larger.
+    pub fn dimensions(&self, width: u32, height: u32) -> (u32, u32) {
+        if self.width >= width {
+            return (width, height);
+        }
+        let ratio = self.width as f64 / width as f64;
+        (self.width, ((height as f64 * ratio).round() as u32).max(1))
+    }
+}
+
+/// Parses a comma separated list of `name:width`.
+pub fn parse(list: &str) -> Result<Vec<Variant>, String> {
+    let mut variants: Vec<Variant> = Vec::new();
+    for entry in list.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let invalid = || format!("Invalid variant '{}', use e.g. thumb:128", entry);
+        let (name, width) = entry.split_once(':').ok_or_else(invalid)?;
+        let name = name.trim();
+        let width = width.trim().parse::<u32>().ok().filter(|width| *width > 0).ok_or_else(invalid)?;
+        if name.is_empty() || name.len() > MAX_NAME_LEN || !name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit()) {
+            return Err(format!(
+                "Invalid variant name '{}', use up to {} lowercase letters or digits",
+                name, MAX_NAME_LEN
+            ));
+        }
+        if variants.iter().any(|variant| variant.name == name) {
+            return Err(format!("Variant '{}' is given twice", name));
+        }
+        variants.push(Variant {
+            name: name.to_string(),
+            width,
+        });
+    }
+    if variants.len() > MAX_VARIANTS {
+        return Err(format!("At most {} variants are allowed", MAX_VARIANTS));
+    }
+    Ok(variants)
+}
+
+/// The default variants from `RESIZE_VARIANTS`, none if unset, parsed on the first call.
+pub fn from_env() -> &'static [Variant] {
+    static VARIANTS: OnceLock<Vec<Variant>> = OnceLock::new();
+    VARIANTS.get_or_init(|| match env::var("RESIZE_VARIANTS") {
+        Ok(list) => parse(&list).unwrap_or_else(|e| panic!("Invalid RESIZE_VARIANTS: {}", e)),
+        Err(_) => Vec::new(),
+    })
+}
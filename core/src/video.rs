@@ -0,0 +1,55 @@
+// core/src/video.rs
+
+//! Short videos accepted as uploads. The worker doesn't resize the video itself but a poster frame
+//! taken from it with ffmpeg, behind the `video_poster` feature; the API only needs to tell videos
+//! apart from images, which is done from their leading bytes.
+
+/// `ftyp` brands of HEIF and AVIF images, which share the MP4 container.
+const IMAGE_BRANDS: &[&[u8]] = &[b"heic", b"heix", b"mif1", b"msf1", b"avif", b"avis"];
+/// Magic number of EBML, the container format of WebM (and Matroska).
+const EBML_MAGIC: [u8; 4] = [0x1A, 0x45, 0xDF, 0xA3];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoFormat {
+    Mp4,
+    Webm,
+}
+
+impl VideoFormat {
+    pub const ALL: [VideoFormat; 2] = [VideoFormat::Mp4, VideoFormat::Webm];
+
+    /// Recognizes a video from its first bytes, images in the same containers excluded.
+    pub fn sniff(bytes: &[u8]) -> Option<Self> {
+        if image::guess_format(bytes).is_ok() {
+            return None;
+        }
+        if bytes.get(4..8) == Some(b"ftyp") {
+            let brand = bytes.get(8..12)?;
+            return (!IMAGE_BRANDS.contains(&brand)).then_some(VideoFormat::Mp4);
+        }
+        bytes.starts_with(&EBML_MAGIC).then_some(VideoFormat::Webm)
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            VideoFormat::Mp4 => "mp4",
+            VideoFormat::Webm => "webm",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            VideoFormat::Mp4 => "video/mp4",
+            VideoFormat::Webm => "video/webm",
+        }
+    }
+}
+
+/// Content type to store an original under: its video type, or that of the image format its
+/// magic bytes show, `image/jpeg` when they show none.
+pub fn content_type(bytes: &[u8]) -> &'static str {
+    match VideoFormat::sniff(bytes) {
+        Some(format) => format.content_type(),
+        None => image::guess_format(bytes).map_or("image/jpeg", |format| format.to_mime_type()),
+    }
+}
@@ -0,0 +1,61 @@
+// core/src/warnings.rs
+
+//! Non-fatal issues met while processing an image. Unlike errors they don't fail the stage; the
+//! worker lists them in the image's report and its job status, and counts them in telemetry under
+//! `ProcessingWarnings`, so the API can surface them next to the outputs they concern.
+
+use serde::{Deserialize, Serialize};
+
+/// The source carried an ICC color profile that can't be applied, so its colors were taken as sRGB.
+pub const ICC_PROFILE_DROPPED: &str = "icc_profile_dropped";
+/// The output is larger than the source in at least one dimension.
+pub const UPSCALED: &str = "upscaled";
+/// The source has an EXIF segment that couldn't be parsed, so nothing was indexed from it.
+pub const EXIF_UNREADABLE: &str = "exif_unreadable";
+/// No quality tried brought the output's SSIM against its source up to the threshold.
+pub const QUALITY_BELOW_THRESHOLD: &str = "quality_below_threshold";
+/// Even the lowest quality tried is larger than the upload's target size.
+pub const TARGET_SIZE_EXCEEDED: &str = "target_size_exceeded";
+/// The preset asked for a JPEG encoder the worker wasn't built with, so the built-in one was used.
+pub const ENCODER_UNAVAILABLE: &str = "encoder_unavailable";
+/// The requested crop lies entirely outside the source, so the whole source was used.
+pub const CROP_OUTSIDE_IMAGE: &str = "crop_outside_image";
+/// An operation of the upload's list couldn't be applied to the image, e.g. a crop outside it, so
+/// the list went on without it.
+pub const OPERATION_SKIPPED: &str = "operation_skipped";
+/// A WASM plugin failed or ran out of its limits, so the image went on without its transform.
+pub const PLUGIN_FAILED: &str = "plugin_failed";
+/// The external transformer failed, timed out or exceeded the size limit, so the image went on
+/// without its transform.
+pub const TRANSFORMER_FAILED: &str = "transformer_failed";
+/// A PDF page range was cut short by the pages preset's `max_pages`.
+pub const PAGES_LIMITED: &str = "pages_limited";
+/// The job was cancelled while the stage ran, so its outputs and later stages were abandoned.
+pub const JOB_CANCELLED: &str = "job_cancelled";
+/// Options of the upload that the pipeline its source was routed to doesn't apply, e.g. a crop of an
+/// animated GIF, were left out.
+pub const OPTIONS_IGNORED: &str = "options_ignored";
+/// The resize stage ran out of time before making some variants, which were queued for a later run.
+pub const RENDITIONS_DEFERRED: &str = "renditions_deferred";
+/// A requested stage was skipped, e.g. because its feature is disabled.
+pub const STAGE_SKIPPED: &str = "stage_skipped";
+/// Bytes after the end of the uploaded image were dropped before it was stored, see `TRAILING_DATA`.
+pub const TRAILING_DATA_STRIPPED: &str = "trailing_data_stripped";
+/// The EXIF segment of the uploaded JPEG, GPS position included, was removed before it was stored.
+pub const EXIF_STRIPPED: &str = "exif_stripped";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Warning {
+    /// One of the constants of this module.
+    pub code: String,
+    pub message: String,
+}
+
+impl Warning {
+    pub fn new(code: &str, message: impl Into<String>) -> Self {
+        Warning {
+            code: code.to_string(),
+            message: message.into(),
+        }
+    }
+}
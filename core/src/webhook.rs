@@ -0,0 +1,334 @@
+// core/src/webhook.rs
+
+//! Signing and verification of completion webhooks.
+//!
+//! The signature is an HMAC-SHA256 over `"{timestamp}.{nonce}.{body}"` keyed with the tenant's
+//! webhook secret, sent hex encoded as `sha256=<hex>` in [`SIGNATURE_HEADER`]. Receivers should
+//! read the headers with [`SignedHeaders::from_headers`], call [`verify`] and keep a
+//! [`ReplayGuard`] to reject resent deliveries. Those that can't use this crate can post a delivery
+//! to the API's `POST /webhooks/verify` instead.
+//!
+//! A tenant's deliveries are keyed with the `webhook_secret` of its policy in `TENANTS_FILE`, so
+//! one tenant's receiver can't be sent forgeries signed with a secret another tenant knows, and
+//! only uploads without a tenant are keyed with `CALLBACK_SECRET`; see [`callback_secret`].
+//!
+//! Uploads may name a `callback_url` the worker posts to when their job ends. It has to be HTTPS,
+//! or HTTP with `CALLBACK_ALLOW_HTTP=true`, and on a host listed in `CALLBACK_ALLOWED_HOSTS`
+//! (comma separated, subdomains included) when that is set; see [`check_callback_url`]. Since
+//! uploaders may be anonymous, a URL reaching anything but the public internet is refused: an IP
+//! literal when checked, and a name when it's resolved before each delivery, see
+//! [`resolve_callback_url`], so a callback can't be pointed at services next to the worker.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use reqwest::Url;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    env, fmt, fs,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-Webhook-Timestamp";
+pub const NONCE_HEADER: &str = "X-Webhook-Nonce";
+
+/// How far a delivery's timestamp may be from the receiver's clock by default, in seconds.
+pub const DEFAULT_TOLERANCE_SECS: u64 = 300;
+
+const SIGNATURE_PREFIX: &str = "sha256=";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The parts of a `TENANTS_FILE` entry the secret is read from.
+#[derive(Deserialize)]
+struct TenantEntry {
+    id: String,
+    #[serde(default)]
+    policy: TenantSecrets,
+}
+
+#[derive(Deserialize, Default)]
+struct TenantSecrets {
+    webhook_secret: Option<String>,
+}
+
+/// The secret callbacks for `tenant`'s uploads are signed with: the `webhook_secret` of its policy,
+/// or `CALLBACK_SECRET` for uploads without a tenant. `None` when it isn't set, and callbacks aren't
+/// sent. The tenants file is read each time, so a policy the API changes applies to the next one.
+pub fn callback_secret(tenant: Option<&str>) -> Result<Option<String>, String> {
+    let Some(tenant) = tenant else {
+        return Ok(env::var("CALLBACK_SECRET").ok());
+    };
+    let Ok(path) = env::var("TENANTS_FILE") else {
+        return Ok(None);
+    };
+    let bytes = match fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Failed to read TENANTS_FILE {}: {}", path, e)),
+    };
+    let tenants: Vec<TenantEntry> = serde_json::from_slice(&bytes).map_err(|e| format!("Invalid TENANTS_FILE {}: {}", path, e))?;
+    Ok(tenants.into_iter().find(|entry| entry.id == tenant).and_then(|entry| entry.policy.webhook_secret))
+}
+
+/// Longest callback URL accepted.
+const MAX_CALLBACK_URL_LEN: usize = 2048;
+
+/// Whether `ip` is a public internet address rather than a loopback, private, link-local, shared
+/// or otherwise special one.
+///
+/// ```
+/// use image_resize_core::webhook::is_public;
+///
+/// for public in ["93.184.215.14", "2606:2800:21f:cb07:6820:80da:af6b:8b2c"] {
+///     assert!(is_public(public.parse().unwrap()), "{}", public);
+/// }
+/// for internal in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0", "::1", "fd00::1", "fe80::1", "::ffff:10.0.0.1"] {
+///     assert!(!is_public(internal.parse().unwrap()), "{}", internal);
+/// }
+/// ```
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            let shared = first == 100 && (second & 0xc0) == 64;
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || first == 0
+                || shared)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                let unique_local = (first & 0xfe00) == 0xfc00;
+                let link_local = (first & 0xffc0) == 0xfe80;
+                !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || unique_local || link_local)
+            }
+        },
+    }
+}
+
+/// Checks a `callback_url` given with an upload.
+pub fn check_callback_url(url: &str) -> Result<(), String> {
+    let invalid = |reason: &str| format!("Invalid callback_url '{}': {}", url, reason);
+    if url.len() > MAX_CALLBACK_URL_LEN {
+        return Err(invalid(&format!("longer than {} characters", MAX_CALLBACK_URL_LEN)));
+    }
+    let parsed = Url::parse(url).map_err(|e| invalid(&e.to_string()))?;
+    let allow_http = env::var("CALLBACK_ALLOW_HTTP").is_ok_and(|v| v == "true");
+    match parsed.scheme() {
+        "https" => {}
+        "http" if allow_http => {}
+        _ => return Err(invalid("use https")),
+    }
+    let host = parsed.host_str().ok_or_else(|| invalid("no host"))?;
+    if let Ok(allowed) = env::var("CALLBACK_ALLOWED_HOSTS") {
+        let permitted = allowed
+            .split(',')
+            .map(str::trim)
+            .filter(|allowed| !allowed.is_empty())
+            .any(|allowed| host == allowed || host.ends_with(&format!(".{}", allowed)));
+        if !permitted {
+            return Err(invalid("host not allowed"));
+        }
+    }
+    if ip_literal(&parsed).is_some_and(|ip| !is_public(ip)) {
+        return Err(invalid("not a public address"));
+    }
+    Ok(())
+}
+
+/// The address a URL's host is, if it's an IP literal.
+fn ip_literal(url: &Url) -> Option<IpAddr> {
+    url.host_str()?.trim_start_matches('[').trim_end_matches(']').parse().ok()
+}
+
+/// Checks `url` as [`check_callback_url`] does and resolves its host, refusing it unless every
+/// address is public. Returns the host and its addresses, to be connected to as they are so a
+/// second lookup can't answer differently.
+pub async fn resolve_callback_url(url: &str) -> Result<(String, Vec<SocketAddr>), String> {
+    check_callback_url(url)?;
+    let invalid = |reason: &str| format!("Invalid callback_url '{}': {}", url, reason);
+    let parsed = Url::parse(url).map_err(|e| invalid(&e.to_string()))?;
+    let port = parsed.port_or_known_default().ok_or_else(|| invalid("no port"))?;
+    let host = parsed.host_str().ok_or_else(|| invalid("no host"))?.to_string();
+    let addrs: Vec<SocketAddr> = match ip_literal(&parsed) {
+        Some(ip) => vec![SocketAddr::new(ip, port)],
+        None => tokio::net::lookup_host((host.as_str(), port))
+            .await
+            .map_err(|e| invalid(&format!("lookup failed: {}", e)))?
+            .collect(),
+    };
+    if addrs.is_empty() {
+        return Err(invalid("the host has no address"));
+    }
+    if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+        return Err(invalid(&format!("resolves to {}, not a public address", addr.ip())));
+    }
+    Ok((host, addrs))
+}
+
+/// Values to send in the three webhook headers alongside a body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedHeaders {
+    pub timestamp: u64,
+    pub nonce: String,
+    pub signature: String,
+}
+
+impl SignedHeaders {
+    /// Reads the three headers of a delivery, `header` looking one up by name.
+    pub fn from_headers<'a>(header: impl Fn(&str) -> Option<&'a str>) -> Result<Self, VerifyError> {
+        let get = |name: &'static str| header(name).map(str::trim).ok_or(VerifyError::MissingHeader(name));
+        let timestamp = get(TIMESTAMP_HEADER)?.parse().map_err(|_| VerifyError::MalformedTimestamp)?;
+        Ok(SignedHeaders {
+            timestamp,
+            nonce: get(NONCE_HEADER)?.to_string(),
+            signature: get(SIGNATURE_HEADER)?.to_string(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    MissingHeader(&'static str),
+    MalformedTimestamp,
+    MalformedSignature,
+    BadSignature,
+    /// The timestamp is outside the tolerance window, in either direction.
+    Expired,
+    /// The nonce was already seen within the tolerance window.
+    Replayed,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            VerifyError::MissingHeader(name) => return write!(f, "missing {} header", name),
+            VerifyError::MalformedTimestamp => "malformed timestamp header",
+            VerifyError::MalformedSignature => "malformed signature header",
+            VerifyError::BadSignature => "signature does not match",
+            VerifyError::Expired => "timestamp outside the allowed window",
+            VerifyError::Replayed => "nonce already used",
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn mac(secret: &[u8], timestamp: u64, nonce: &str, body: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(nonce.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    mac
+}
+
+/// Signs `body` with a fresh timestamp and random nonce.
+pub fn sign(secret: &[u8], body: &[u8]) -> SignedHeaders {
+    let timestamp = unix_now();
+    let nonce = uuid::Uuid::new_v4().simple().to_string();
+    let signature = sign_with(secret, timestamp, &nonce, body);
+    SignedHeaders {
+        timestamp,
+        nonce,
+        signature,
+    }
+}
+
+/// Computes the signature header value for the given timestamp and nonce.
+pub fn sign_with(secret: &[u8], timestamp: u64, nonce: &str, body: &[u8]) -> String {
+    let digest = mac(secret, timestamp, nonce, body).finalize().into_bytes();
+    format!("{}{}", SIGNATURE_PREFIX, hex::encode(digest))
+}
+
+/// Checks the signature in constant time and that `timestamp` is within `tolerance_secs` of now.
+/// This does not detect replays on its own, see [`ReplayGuard`].
+pub fn verify(
+    secret: &[u8],
+    headers: &SignedHeaders,
+    body: &[u8],
+    tolerance_secs: u64,
+) -> Result<(), VerifyError> {
+    verify_at(secret, headers, body, tolerance_secs, unix_now())
+}
+
+fn verify_at(
+    secret: &[u8],
+    headers: &SignedHeaders,
+    body: &[u8],
+    tolerance_secs: u64,
+    now: u64,
+) -> Result<(), VerifyError> {
+    let expected = headers
+        .signature
+        .strip_prefix(SIGNATURE_PREFIX)
+        .and_then(|hex_digest| hex::decode(hex_digest).ok())
+        .ok_or(VerifyError::MalformedSignature)?;
+
+    mac(secret, headers.timestamp, &headers.nonce, body)
+        .verify_slice(&expected)
+        .map_err(|_| VerifyError::BadSignature)?;
+
+    if now.abs_diff(headers.timestamp) > tolerance_secs {
+        return Err(VerifyError::Expired);
+    }
+
+    Ok(())
+}
+
+/// Remembers nonces for the tolerance window so a captured delivery can't be replayed.
+pub struct ReplayGuard {
+    tolerance_secs: u64,
+    seen: Mutex<HashMap<String, u64>>,
+}
+
+impl ReplayGuard {
+    pub fn new(tolerance_secs: u64) -> Self {
+        ReplayGuard {
+            tolerance_secs,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Verifies `headers` against `body` and records the nonce, failing if it was seen before.
+    pub fn verify(&self, secret: &[u8], headers: &SignedHeaders, body: &[u8]) -> Result<(), VerifyError> {
+        let now = unix_now();
+        verify_at(secret, headers, body, self.tolerance_secs, now)?;
+
+        let mut seen = self.seen.lock().unwrap();
+        // anything older than the window would fail the timestamp check anyway
+        seen.retain(|_, timestamp| now.abs_diff(*timestamp) <= self.tolerance_secs);
+        if seen.contains_key(&headers.nonce) {
+            return Err(VerifyError::Replayed);
+        }
+        seen.insert(headers.nonce.clone(), headers.timestamp);
+
+        Ok(())
+    }
+}
+
+impl Default for ReplayGuard {
+    fn default() -> Self {
+        ReplayGuard::new(DEFAULT_TOLERANCE_SECS)
+    }
+}
@@ -0,0 +1,126 @@
+// functions/src/alert.rs
+
+use std::{
+    collections::VecDeque,
+    env,
+    future::Future,
+    pin::Pin,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::{error, warn};
+
+const DEFAULT_ERROR_RATE_THRESHOLD: f64 = 0.5;
+const DEFAULT_WINDOW_SECS: u64 = 300;
+/// The error rate is only judged once the window holds this many outcomes.
+const MIN_EVENTS_IN_WINDOW: usize = 5;
+
+/// Destination for operator alerts.
+pub trait AlertSink: Send + Sync {
+    fn send<'a>(&'a self, text: &'a str) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+}
+
+/// Slack and Teams incoming webhooks both accept a JSON body with a `text` field.
+pub struct WebhookSink {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl AlertSink for WebhookSink {
+    fn send<'a>(&'a self, text: &'a str) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        Box::pin(async move {
+            let result = self
+                .client
+                .post(&self.url)
+                .json(&serde_json::json!({ "text": text }))
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            if let Err(e) = result {
+                error!("Failed to post alert: {:?}", e);
+            }
+        })
+    }
+}
+
+/// Used when no webhook is configured, so alerts at least reach the logs.
+pub struct LogSink;
+
+impl AlertSink for LogSink {
+    fn send<'a>(&'a self, text: &'a str) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        Box::pin(async move { warn!("ALERT: {}", text) })
+    }
+}
+
+/// Picks a sink from `ALERT_WEBHOOK_URL` (a Slack or Teams incoming webhook).
+pub fn sink_from_env() -> Box<dyn AlertSink> {
+    match env::var("ALERT_WEBHOOK_URL") {
+        Ok(url) => Box::new(WebhookSink {
+            url,
+            client: reqwest::Client::new(),
+        }),
+        Err(_) => Box::new(LogSink),
+    }
+}
+
+/// Tracks job outcomes over a rolling window and reports when the error rate crosses the threshold.
+pub struct ErrorRateMonitor {
+    window: Duration,
+    threshold: f64,
+    events: Mutex<VecDeque<(Instant, bool)>>,
+    /// Set while the rate is above the threshold so one incident produces one alert.
+    alerting: Mutex<bool>,
+}
+
+impl ErrorRateMonitor {
+    /// Window from `ALERT_WINDOW_SECS` and threshold from `ALERT_ERROR_RATE`.
+    pub fn from_env() -> Self {
+        let window = env::var("ALERT_WINDOW_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_WINDOW_SECS);
+        let threshold = env::var("ALERT_ERROR_RATE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_ERROR_RATE_THRESHOLD);
+
+        ErrorRateMonitor {
+            window: Duration::from_secs(window),
+            threshold,
+            events: Mutex::new(VecDeque::new()),
+            alerting: Mutex::new(false),
+        }
+    }
+
+    /// Records an outcome and returns an alert message when the rate has just crossed the threshold.
+    pub fn record(&self, success: bool) -> Option<String> {
+        let now = Instant::now();
+        let mut events = self.events.lock().unwrap();
+        events.push_back((now, success));
+        while let Some((at, _)) = events.front() {
+            if now.duration_since(*at) > self.window {
+                events.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        let failures = events.iter().filter(|(_, success)| !success).count();
+        let rate = failures as f64 / events.len() as f64;
+        let over = events.len() >= MIN_EVENTS_IN_WINDOW && rate > self.threshold;
+
+        let mut alerting = self.alerting.lock().unwrap();
+        let crossed = over && !*alerting;
+        *alerting = over;
+
+        crossed.then(|| {
+            format!(
+                "Worker error rate at {:.0}% ({} of {} jobs) over the last {}s",
+                rate * 100.0,
+                failures,
+                events.len(),
+                self.window.as_secs()
+            )
+        })
+    }
+}
@@ -0,0 +1,95 @@
+// functions/src/analysis.rs
+
+use image::DynamicImage;
+use serde::Serialize;
+
+/// Per-channel histograms and summary statistics for a source image.
+#[derive(Serialize, Debug)]
+pub struct Analysis {
+    pub width: u32,
+    pub height: u32,
+    pub luma_histogram: Vec<u32>,
+    pub red_histogram: Vec<u32>,
+    pub green_histogram: Vec<u32>,
+    pub blue_histogram: Vec<u32>,
+    /// Mean luma in 0..=255.
+    pub mean_brightness: f64,
+    /// Variance of the Laplacian over the luma channel, low values indicate a blurry image.
+    pub sharpness: f64,
+}
+
+impl Analysis {
+    /// A few words describing the image for text search, e.g. `dark blurry portrait`.
+    pub fn caption(&self) -> String {
+        let exposure = match self.mean_brightness {
+            b if b < 64.0 => "dark",
+            b if b > 192.0 => "bright",
+            _ => "balanced",
+        };
+        let focus = if self.sharpness < BLURRY_BELOW { "blurry" } else { "sharp" };
+        let shape = match (self.width as f64, self.height as f64) {
+            (w, h) if w > h * 1.2 => "landscape",
+            (w, h) if h > w * 1.2 => "portrait",
+            _ => "square",
+        };
+        format!("{} {} {}", exposure, focus, shape)
+    }
+}
+
+/// Laplacian variance under which an image reads as out of focus.
+const BLURRY_BELOW: f64 = 100.0;
+
+pub fn analyze(img: &DynamicImage) -> Analysis {
+    let rgb = img.to_rgb8();
+    let luma = img.to_luma8();
+
+    let mut red_histogram = vec![0u32; 256];
+    let mut green_histogram = vec![0u32; 256];
+    let mut blue_histogram = vec![0u32; 256];
+    for pixel in rgb.pixels() {
+        red_histogram[pixel[0] as usize] += 1;
+        green_histogram[pixel[1] as usize] += 1;
+        blue_histogram[pixel[2] as usize] += 1;
+    }
+
+    let mut luma_histogram = vec![0u32; 256];
+    let mut luma_sum = 0u64;
+    for pixel in luma.pixels() {
+        luma_histogram[pixel[0] as usize] += 1;
+        luma_sum += pixel[0] as u64;
+    }
+    let pixel_count = (luma.width() as u64 * luma.height() as u64).max(1);
+
+    Analysis {
+        width: img.width(),
+        height: img.height(),
+        luma_histogram,
+        red_histogram,
+        green_histogram,
+        blue_histogram,
+        mean_brightness: luma_sum as f64 / pixel_count as f64,
+        sharpness: laplacian_variance(&luma),
+    }
+}
+
+fn laplacian_variance(luma: &image::GrayImage) -> f64 {
+    let (width, height) = luma.dimensions();
+    if width < 3 || height < 3 {
+        return 0.0;
+    }
+
+    let at = |x: u32, y: u32| luma.get_pixel(x, y)[0] as f64;
+    let mut sum = 0.0;
+    let mut sum_sq = 0.0;
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let laplacian = at(x - 1, y) + at(x + 1, y) + at(x, y - 1) + at(x, y + 1) - 4.0 * at(x, y);
+            sum += laplacian;
+            sum_sq += laplacian * laplacian;
+        }
+    }
+
+    let n = ((width - 2) * (height - 2)) as f64;
+    let mean = sum / n;
+    sum_sq / n - mean * mean
+}
@@ -0,0 +1,65 @@
+// functions/src/animation.rs
+
+//! The `animation` pipeline for GIFs, see `core/src/routing.rs`: each frame of an animated GIF is
+//! scaled as a still would be, and the frames are encoded as an animated GIF again with their
+//! delays, looping forever. A GIF of a single frame is a still and takes the standard pipeline.
+
+use image::{
+    codecs::gif::{GifDecoder, GifEncoder, Repeat},
+    AnimationDecoder, DynamicImage, Frame, ImageError, ImageResult,
+};
+use std::io::Cursor;
+
+/// Most frames read from a GIF, the rest being dropped so a long animation can't exhaust memory.
+pub const MAX_FRAMES: usize = 500;
+/// NeuQuant sampling factor from 1 (best) to 30 (fastest) for the palette of each frame.
+const ENCODE_SPEED: i32 = 10;
+
+/// An animated GIF scaled frame by frame.
+pub struct Animation {
+    pub encoded: Vec<u8>,
+    /// Of the source's frames.
+    pub source_size: (u32, u32),
+    pub size: (u32, u32),
+    pub frames: usize,
+    /// Frames past [`MAX_FRAMES`] were dropped.
+    pub truncated: bool,
+}
+
+/// Every frame of the GIF in `bytes` passed through `scale` and encoded again, or `None` for a
+/// GIF of a single frame.
+pub fn resize(bytes: &[u8], scale: impl Fn(&DynamicImage) -> DynamicImage) -> ImageResult<Option<Animation>> {
+    let mut frames = GifDecoder::new(Cursor::new(bytes))?
+        .into_frames()
+        .take(MAX_FRAMES + 1)
+        .collect::<Result<Vec<Frame>, ImageError>>()?;
+    if frames.len() < 2 {
+        return Ok(None);
+    }
+    let truncated = frames.len() > MAX_FRAMES;
+    frames.truncate(MAX_FRAMES);
+
+    let source_size = frames[0].buffer().dimensions();
+    let mut size = source_size;
+    let count = frames.len();
+    let scaled = frames.into_iter().map(|frame| {
+        let delay = frame.delay();
+        // frames come composited onto the full canvas, so each is scaled whole
+        let scaled = scale(&DynamicImage::ImageRgba8(frame.into_buffer())).into_rgba8();
+        size = scaled.dimensions();
+        Frame::from_parts(scaled, 0, 0, delay)
+    });
+    let mut encoded = Vec::new();
+    {
+        let mut encoder = GifEncoder::new_with_speed(&mut encoded, ENCODE_SPEED);
+        encoder.set_repeat(Repeat::Infinite)?;
+        encoder.encode_frames(scaled)?;
+    }
+    Ok(Some(Animation {
+        encoded,
+        source_size,
+        size,
+        frames: count,
+        truncated,
+    }))
+}
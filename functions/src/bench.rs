@@ -0,0 +1,74 @@
+// functions/src/bench.rs
+
+//! `handler bench-encoders <image>...`: encodes each image with every JPEG encoder the worker was
+//! built with, at a few qualities, and prints the output size, encode time and SSIM against the
+//! source, to weigh mozjpeg's smaller files against its slower encodes before a preset switches.
+
+use image::imageops;
+use std::{fs, time::Instant};
+
+use crate::{
+    decode,
+    quality::{self, JpegEncoder, JpegOptions},
+};
+
+const QUALITIES: &[u8] = &[60, quality::DEFAULT_QUALITY, 90];
+
+fn encoders() -> Vec<JpegEncoder> {
+    let mut encoders = vec![JpegEncoder::Builtin];
+    if cfg!(feature = "mozjpeg") {
+        encoders.push(JpegEncoder::Mozjpeg);
+    }
+    encoders
+}
+
+pub fn run(paths: &[String]) {
+    if paths.is_empty() {
+        eprintln!("Usage: handler bench-encoders <image>...");
+        return;
+    }
+    if !cfg!(feature = "mozjpeg") {
+        eprintln!("Built without the mozjpeg feature, only the built-in encoder is measured");
+    }
+
+    println!("{:<32} {:<8} {:>7} {:>10} {:>8} {:>6}", "image", "encoder", "quality", "bytes", "ms", "ssim");
+    for path in paths {
+        let img = match fs::read(path).map_err(|e| e.to_string()).and_then(|bytes| decode::load(&bytes).map_err(|e| e.to_string())) {
+            Ok(img) => img.to_rgb8(),
+            Err(e) => {
+                eprintln!("Skipping {}: {}", path, e);
+                continue;
+            }
+        };
+        let luma = imageops::grayscale(&img);
+        for encoder in encoders() {
+            let options = JpegOptions {
+                encoder,
+                ..JpegOptions::default()
+            };
+            for &quality in QUALITIES {
+                let started = Instant::now();
+                let measured = quality::encode_jpeg(&img, quality, &options).and_then(|encoded| {
+                    let elapsed = started.elapsed();
+                    Ok((quality::verify(&luma, &encoded)?, encoded, elapsed))
+                });
+                let (score, encoded, elapsed) = match measured {
+                    Ok(measured) => measured,
+                    Err(e) => {
+                        eprintln!("Skipping {} at quality {}: {}", path, quality, e);
+                        continue;
+                    }
+                };
+                println!(
+                    "{:<32} {:<8} {:>7} {:>10} {:>8.1} {:>6.3}",
+                    path,
+                    format!("{:?}", encoder).to_lowercase(),
+                    quality,
+                    encoded.len(),
+                    elapsed.as_secs_f64() * 1000.0,
+                    score,
+                );
+            }
+        }
+    }
+}
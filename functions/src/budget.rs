@@ -0,0 +1,62 @@
+// functions/src/budget.rs
+
+//! Budgets of concurrent encodes per output format within one worker, so the slowest encoder
+//! can't take every `WORKER_CONCURRENCY` slot while cheaper jobs wait behind it. `ENCODE_CONCURRENCY`
+//! lists them as `<format>=<count>`, e.g. `png=2,webp=1,jpeg=8`; formats not listed are bounded
+//! only by the worker's concurrency. An encode over its budget waits for one of its format to
+//! finish, and the wait is reported as `EncodeBudgetWait` in milliseconds.
+
+use image_resize_core::{output_format::OutputFormat, telemetry};
+use std::{
+    env,
+    sync::{Arc, OnceLock},
+    time::Instant,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::info;
+
+fn budgets() -> &'static [(OutputFormat, Arc<Semaphore>)] {
+    static BUDGETS: OnceLock<Vec<(OutputFormat, Arc<Semaphore>)>> = OnceLock::new();
+    BUDGETS.get_or_init(|| {
+        env::var("ENCODE_CONCURRENCY")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (format, count) = entry
+                    .split_once('=')
+                    .unwrap_or_else(|| panic!("Invalid ENCODE_CONCURRENCY entry '{}', use e.g. webp=2", entry));
+                let format = format.trim().parse().unwrap_or_else(|e| panic!("Invalid ENCODE_CONCURRENCY: {}", e));
+                let count = count
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|count| *count > 0)
+                    .unwrap_or_else(|| panic!("Invalid ENCODE_CONCURRENCY count in '{}'", entry));
+                (format, Arc::new(Semaphore::new(count)))
+            })
+            .collect()
+    })
+}
+
+/// Reads `ENCODE_CONCURRENCY`, so a bad value stops the worker at startup.
+pub fn init() {
+    for (format, semaphore) in budgets() {
+        info!("Encoding up to {} {:?} renditions at once", semaphore.available_permits(), format);
+    }
+}
+
+/// Waits for a slot in the budget of `format`, held until the permit is dropped; `None` for
+/// formats without a budget.
+pub async fn acquire(format: OutputFormat) -> Option<OwnedSemaphorePermit> {
+    let (_, semaphore) = budgets().iter().find(|(budgeted, _)| *budgeted == format)?;
+    let started = Instant::now();
+    let permit = semaphore.clone().acquire_owned().await.expect("The semaphore is never closed");
+    telemetry::track_metric(
+        "EncodeBudgetWait",
+        started.elapsed().as_millis() as f64,
+        &[("format", format!("{:?}", format).to_lowercase())],
+    );
+    Some(permit)
+}
@@ -0,0 +1,177 @@
+// functions/src/callback.rs
+
+//! Notices posted to the `callback_url` of an upload when its job ends: done after its last stage,
+//! failed for good, cancelled or expired. A job that ran out of time is reported partially
+//! complete, followed by done once its missing renditions are made, see `deadline.rs`. The JSON body carries the job id, its status, the
+//! original and the blobs the job wrote, and when it was queued and finished. It is signed with the
+//! `webhook_secret` of the upload's tenant, read from `TENANTS_FILE`, or with `CALLBACK_SECRET` for
+//! uploads without a tenant, see `core/src/webhook.rs`, and nothing is sent without a secret. A delivery is tried `CALLBACK_RETRIES` more times (default 3), with
+//! doubling delays from a second, in the background so the queue isn't held up by a slow receiver.
+//! The host is resolved first and the notice sent to those addresses only if they're all public,
+//! and redirects aren't followed, so a callback can't reach services inside the network.
+
+use azure_storage_blobs::prelude::BlobServiceClient;
+use azure_core::date;
+use image_resize_core::{job_status, naming, telemetry, webhook};
+use serde::Serialize;
+use std::{env, net::SocketAddr, time::Duration};
+use time::OffsetDateTime;
+use tracing::{error, info, warn};
+
+use crate::ImageMessage;
+
+const DEFAULT_RETRIES: u32 = 3;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Done,
+    PartiallyComplete,
+    Failed,
+    Cancelled,
+    Expired,
+}
+
+#[derive(Serialize, Debug)]
+struct Notice {
+    job_id: Option<String>,
+    status: Status,
+    container: String,
+    filename: String,
+    /// URL of the original.
+    original: Option<String>,
+    /// URL of the resized rendition, when the job wrote one.
+    resized: Option<String>,
+    /// URLs of every blob the job wrote.
+    outputs: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// RFC 3339.
+    queued_at: Option<String>,
+    finished_at: String,
+    /// From queued to finished.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<i64>,
+}
+
+/// A client connecting to `host` only at `addrs`, the addresses checked for it.
+fn client(host: &str, addrs: &[SocketAddr]) -> reqwest::Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .timeout(TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .resolve_to_addrs(host, addrs)
+        .build()
+}
+
+/// Notifies the image's callback URL, if it has one, that its job ended with `status`. `outputs`
+/// are the URLs the last stage wrote, added to those recorded for the job.
+pub async fn notify(image: &ImageMessage, status: Status, outputs: &[String], error: Option<&str>, service_client: &BlobServiceClient) {
+    let Some(url) = image.callback_url.clone() else {
+        return;
+    };
+    let secret = match webhook::callback_secret(image.tenant.as_deref()) {
+        Ok(Some(secret)) => secret,
+        Ok(None) => {
+            match &image.tenant {
+                Some(tenant) => warn!("Tenant {} has no webhook_secret, not notifying {} of {}", tenant, url, image.filename),
+                None => warn!("CALLBACK_SECRET isn't set, not notifying {} of {}", url, image.filename),
+            }
+            return;
+        }
+        Err(e) => {
+            error!("Not notifying {} of {}: {}", url, image.filename, e);
+            return;
+        }
+    };
+    // the API checked it, but the message may come from elsewhere
+    if let Err(e) = webhook::check_callback_url(&url) {
+        warn!("Not notifying {}", e);
+        return;
+    }
+
+    let mut all_outputs = match &image.job_id {
+        Some(job_id) => match job_status::job(job_id).await {
+            Ok(record) => record.map(|record| record.outputs()).unwrap_or_default(),
+            Err(e) => {
+                warn!("Failed to read the outputs of job {}: {:?}", job_id, e);
+                Vec::new()
+            }
+        },
+        None => Vec::new(),
+    };
+    for output in outputs {
+        if !all_outputs.contains(output) {
+            all_outputs.push(output.clone());
+        }
+    }
+    let container_client = service_client.container_client(&image.image_container);
+    let blob_url = |name: &str| container_client.blob_client(name).url().ok().map(|url| url.to_string());
+    let resized = blob_url(&naming::resized(&image.filename)).filter(|url| all_outputs.contains(url));
+    let now = OffsetDateTime::now_utc();
+    let notice = Notice {
+        job_id: image.job_id.clone(),
+        status,
+        container: image.image_container.clone(),
+        filename: image.filename.clone(),
+        original: blob_url(&image.filename),
+        resized,
+        outputs: all_outputs,
+        error: error.map(str::to_string),
+        queued_at: image.queued_at.clone(),
+        finished_at: date::to_rfc3339(&now),
+        duration_ms: image
+            .queued_at
+            .as_deref()
+            .and_then(|queued_at| date::parse_rfc3339(queued_at).ok())
+            .map(|queued_at| (now - queued_at).whole_milliseconds() as i64),
+    };
+    let body = serde_json::to_vec(&notice).expect("Failed to serialize callback");
+    let filename = image.filename.clone();
+    tokio::spawn(async move { deliver(&url, &secret, body, &filename).await });
+}
+
+async fn deliver(url: &str, secret: &str, body: Vec<u8>, filename: &str) {
+    let retries = env::var("CALLBACK_RETRIES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_RETRIES);
+    let client = match webhook::resolve_callback_url(url).await {
+        Ok((host, addrs)) => client(&host, &addrs).expect("Failed to build HTTP client"),
+        Err(e) => {
+            error!("Not notifying {} of {}: {}", url, filename, e);
+            telemetry::track_event("CallbackFailed", &[("filename", filename.to_string())]);
+            return;
+        }
+    };
+    let mut delay = Duration::from_secs(1);
+    for attempt in 0..=retries {
+        // signed afresh each time, so a retry isn't taken for a replay
+        let signed = webhook::sign(secret.as_bytes(), &body);
+        let result = client
+            .post(url)
+            .header("content-type", "application/json")
+            .header(webhook::SIGNATURE_HEADER, signed.signature)
+            .header(webhook::TIMESTAMP_HEADER, signed.timestamp.to_string())
+            .header(webhook::NONCE_HEADER, signed.nonce)
+            .body(body.clone())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match result {
+            Ok(_) => {
+                info!("Notified {} of {}", url, filename);
+                return;
+            }
+            Err(e) if attempt < retries => {
+                warn!("Callback to {} failed, retrying in {:?}: {:?}", url, delay, e);
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            Err(e) => {
+                error!("Callback to {} for {} failed for good: {:?}", url, filename, e);
+                telemetry::track_event("CallbackFailed", &[("filename", filename.to_string())]);
+            }
+        }
+    }
+}
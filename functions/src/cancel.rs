@@ -0,0 +1,49 @@
+// functions/src/cancel.rs
+
+//! Jobs cancelled through the API's `DELETE /jobs/{name}`, see `core/src/job_status.rs`. The worker
+//! checks before running a stage, at [`checkpoint`]s within one and before enqueuing the next, and
+//! abandons messages queued before the cancellation. An abandoned message is completed like a
+//! processed one, so it isn't delivered again.
+
+use azure_core::error::{Error, ErrorKind};
+use image_resize_core::job_status;
+use std::fmt;
+use tracing::warn;
+
+use crate::ImageMessage;
+
+/// The error a [`checkpoint`] stops a stage with.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The job was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Whether the image's job was cancelled after its message was queued. Lookup errors count as
+/// not cancelled, so a table outage doesn't stall processing.
+pub async fn is_cancelled(image: &ImageMessage) -> bool {
+    match job_status::cancellation(&image.image_container, &image.filename).await {
+        Ok(cancellation) => cancellation.is_some_and(|c| c.covers(image.queued_at.as_deref())),
+        Err(e) => {
+            warn!("Failed to look up cancellation of {}: {:?}", image.filename, e);
+            false
+        }
+    }
+}
+
+/// Stops the stage with [`Cancelled`] if the job was cancelled in the meantime.
+pub async fn checkpoint(image: &ImageMessage) -> azure_core::Result<()> {
+    if is_cancelled(image).await {
+        return Err(Error::new(ErrorKind::Other, Cancelled));
+    }
+    Ok(())
+}
+
+pub fn is_cancellation(e: &azure_core::Error) -> bool {
+    e.downcast_ref::<Cancelled>().is_some()
+}
@@ -0,0 +1,61 @@
+// functions/src/capture.rs
+
+use exif::{DateTime, Exif, In, Reader, Tag, Value};
+use image_resize_core::image_index::ImageRecord;
+use std::io::Cursor;
+
+/// Fills the capture details of `record` from the EXIF in `bytes`; images without EXIF are left as
+/// is. Fails when there is EXIF but it can't be parsed.
+pub fn read_into(bytes: &[u8], record: &mut ImageRecord, retain_gps: bool) -> Result<(), exif::Error> {
+    let exif = match Reader::new().read_from_container(&mut Cursor::new(bytes)) {
+        Ok(exif) => exif,
+        Err(exif::Error::NotFound(_)) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    record.captured_at = [Tag::DateTimeOriginal, Tag::DateTime]
+        .into_iter()
+        .find_map(|tag| ascii(&exif, tag))
+        .and_then(|s| DateTime::from_ascii(s.as_bytes()).ok())
+        .map(|dt| {
+            format!(
+                "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+                dt.year, dt.month, dt.day, dt.hour, dt.minute, dt.second
+            )
+        });
+    record.camera_make = ascii(&exif, Tag::Make);
+    record.camera_model = ascii(&exif, Tag::Model);
+
+    if retain_gps {
+        record.latitude = coordinate(&exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, "S");
+        record.longitude = coordinate(&exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, "W");
+    }
+    Ok(())
+}
+
+fn ascii(exif: &Exif, tag: Tag) -> Option<String> {
+    let field = exif.get_field(tag, In::PRIMARY)?;
+    let Value::Ascii(values) = &field.value else {
+        return None;
+    };
+    let value = String::from_utf8_lossy(values.first()?).trim_matches(|c: char| c == '\0' || c.is_whitespace()).to_string();
+    (!value.is_empty()).then_some(value)
+}
+
+/// Degrees, minutes and seconds as signed decimal degrees, negative towards `negative_ref`.
+fn coordinate(exif: &Exif, tag: Tag, ref_tag: Tag, negative_ref: &str) -> Option<f64> {
+    let Value::Rational(parts) = &exif.get_field(tag, In::PRIMARY)?.value else {
+        return None;
+    };
+    let [degrees, minutes, seconds] = parts.as_slice() else {
+        return None;
+    };
+    let value = degrees.to_f64() + minutes.to_f64() / 60.0 + seconds.to_f64() / 3600.0;
+    if !value.is_finite() {
+        return None;
+    }
+    match ascii(exif, ref_tag) {
+        Some(r) if r.eq_ignore_ascii_case(negative_ref) => Some(-value),
+        _ => Some(value),
+    }
+}
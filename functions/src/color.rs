@@ -0,0 +1,365 @@
+// functions/src/color.rs
+
+//! Output color spaces. Pixels travel through the pipeline in the source's color space, described
+//! by its embedded ICC profile (sRGB when it has none), and are converted to the preset's
+//! `color_space` when encoded. `srgb` output is written without a profile, as browsers assume it;
+//! `display-p3` output embeds a Display P3 profile so wide-gamut displays show its full range.
+//!
+//! Only matrix/TRC RGB profiles, which cameras and phones embed, are applied; sources with any
+//! other profile are treated as sRGB and flagged with `icc_profile_dropped`.
+
+use image::{DynamicImage, ImageDecoder, ImageReader, Rgba, RgbImage, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::{io::Cursor, sync::OnceLock};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ColorSpace {
+    #[default]
+    Srgb,
+    DisplayP3,
+}
+
+/// Columns are the red, green and blue primaries in the ICC connection space (XYZ, D50).
+type Matrix = [[f64; 3]; 3];
+
+const SRGB: Matrix = [
+    [0.436_074_7, 0.385_064_9, 0.143_080_4],
+    [0.222_504_5, 0.716_878_6, 0.060_616_9],
+    [0.013_932_2, 0.097_104_5, 0.714_173_3],
+];
+const DISPLAY_P3: Matrix = [
+    [0.515_102, 0.291_965, 0.157_153],
+    [0.241_182, 0.692_236, 0.066_581],
+    [-0.001_049, 0.041_882, 0.784_378],
+];
+/// Bradford adaptation from D65 to D50, for the `chad` tag of the embedded profile.
+const D65_TO_D50: Matrix = [
+    [1.047_882, 0.022_918, -0.050_217],
+    [0.029_586, 0.990_478, -0.017_075],
+    [-0.009_247, 0.015_068, 0.751_709],
+];
+const D50: [f64; 3] = [0.9642, 1.0, 0.8249];
+/// Closest two matrices may be while still counting as the same primaries.
+const TOLERANCE: f64 = 0.002;
+
+/// A tone response curve, from encoded values to linear light.
+#[derive(Debug, Clone)]
+enum Curve {
+    Gamma(f64),
+    Table(Vec<f64>),
+    /// ICC parametric curve of type 0 to 4, as `[g, a, b, c, d, e, f]`.
+    Parametric(u16, [f64; 7]),
+}
+
+impl Curve {
+    fn srgb() -> Self {
+        Curve::Parametric(3, [2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045, 0.0, 0.0])
+    }
+
+    fn linear(&self, x: f64) -> f64 {
+        match self {
+            Curve::Gamma(g) => x.powf(*g),
+            Curve::Table(table) => {
+                let position = x * (table.len() - 1) as f64;
+                let i = (position.floor() as usize).min(table.len() - 2);
+                let t = position - i as f64;
+                table[i] + (table[i + 1] - table[i]) * t
+            }
+            Curve::Parametric(kind, [g, a, b, c, d, e, f]) => match kind {
+                0 => x.powf(*g),
+                1 if x >= -b / a => (a * x + b).powf(*g),
+                1 => 0.0,
+                2 if x >= -b / a => (a * x + b).powf(*g) + c,
+                2 => *c,
+                3 if x >= *d => (a * x + b).powf(*g),
+                3 => c * x,
+                _ if x >= *d => (a * x + b).powf(*g) + e,
+                _ => c * x + f,
+            },
+        }
+    }
+}
+
+/// sRGB's encoding of linear light, which Display P3 shares.
+fn srgb_encode(linear: f64) -> f64 {
+    if linear <= 0.003_130_8 {
+        12.92 * linear
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// A matrix/TRC RGB profile read from a source.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    matrix: Matrix,
+    curves: [Curve; 3],
+}
+
+impl Profile {
+    fn srgb() -> Self {
+        Profile {
+            matrix: SRGB,
+            curves: [Curve::srgb(), Curve::srgb(), Curve::srgb()],
+        }
+    }
+
+    /// Whether the profile is `space`, give or take rounding in how it was written.
+    fn is(&self, space: ColorSpace) -> bool {
+        let target = target_matrix(space);
+        let same_primaries = (0..3).all(|i| (0..3).all(|j| (self.matrix[i][j] - target[i][j]).abs() < TOLERANCE));
+        let srgb = Curve::srgb();
+        let same_curves = self.curves.iter().all(|curve| {
+            (0..=16).all(|step| {
+                let x = step as f64 / 16.0;
+                (curve.linear(x) - srgb.linear(x)).abs() < TOLERANCE
+            })
+        });
+        same_primaries && same_curves
+    }
+}
+
+fn target_matrix(space: ColorSpace) -> Matrix {
+    match space {
+        ColorSpace::Srgb => SRGB,
+        ColorSpace::DisplayP3 => DISPLAY_P3,
+    }
+}
+
+fn read_u16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn read_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn read_s15_16(data: &[u8], at: usize) -> Option<f64> {
+    Some(read_u32(data, at)? as i32 as f64 / 65536.0)
+}
+
+/// The data of the tag with signature `name`.
+fn tag<'a>(icc: &'a [u8], name: &[u8; 4]) -> Option<&'a [u8]> {
+    let count = read_u32(icc, 128)? as usize;
+    (0..count.min(1024)).find_map(|i| {
+        let entry = 132 + i * 12;
+        if icc.get(entry..entry + 4)? != name {
+            return None;
+        }
+        let offset = read_u32(icc, entry + 4)? as usize;
+        let size = read_u32(icc, entry + 8)? as usize;
+        icc.get(offset..offset.checked_add(size)?)
+    })
+}
+
+fn read_xyz(icc: &[u8], name: &[u8; 4]) -> Result<[f64; 3], String> {
+    let data = tag(icc, name).ok_or_else(|| format!("no {} tag", String::from_utf8_lossy(name)))?;
+    if data.get(..4) != Some(b"XYZ ") {
+        return Err(format!("{} is not an XYZ tag", String::from_utf8_lossy(name)));
+    }
+    let value = |i: usize| read_s15_16(data, 8 + i * 4).ok_or("truncated XYZ tag");
+    Ok([value(0)?, value(1)?, value(2)?])
+}
+
+fn read_curve(icc: &[u8], name: &[u8; 4]) -> Result<Curve, String> {
+    let data = tag(icc, name).ok_or_else(|| format!("no {} tag", String::from_utf8_lossy(name)))?;
+    match data.get(..4) {
+        Some(b"curv") => {
+            let count = read_u32(data, 8).ok_or("truncated curve")? as usize;
+            match count {
+                0 => Ok(Curve::Gamma(1.0)),
+                1 => Ok(Curve::Gamma(read_u16(data, 12).ok_or("truncated curve")? as f64 / 256.0)),
+                _ => (0..count)
+                    .map(|i| read_u16(data, 12 + i * 2).map(|v| v as f64 / 65535.0))
+                    .collect::<Option<Vec<_>>>()
+                    .map(Curve::Table)
+                    .ok_or_else(|| "truncated curve".to_string()),
+            }
+        }
+        Some(b"para") => {
+            let kind = read_u16(data, 8).ok_or("truncated curve")?;
+            let count = match kind {
+                0 => 1,
+                1 => 3,
+                2 => 4,
+                3 => 5,
+                4 => 7,
+                _ => return Err(format!("unknown parametric curve type {}", kind)),
+            };
+            let mut params = [0.0; 7];
+            for (i, param) in params.iter_mut().enumerate().take(count) {
+                *param = read_s15_16(data, 12 + i * 4).ok_or("truncated curve")?;
+            }
+            Ok(Curve::Parametric(kind, params))
+        }
+        _ => Err(format!("unsupported {} curve", String::from_utf8_lossy(name))),
+    }
+}
+
+fn parse(icc: &[u8]) -> Result<Profile, String> {
+    if icc.get(16..20) != Some(b"RGB ") || icc.get(20..24) != Some(b"XYZ ") {
+        return Err("not an RGB profile with an XYZ connection space".to_string());
+    }
+    let [red, green, blue] = [read_xyz(icc, b"rXYZ")?, read_xyz(icc, b"gXYZ")?, read_xyz(icc, b"bXYZ")?];
+    let matrix = [0, 1, 2].map(|row| [red[row], green[row], blue[row]]);
+    let curves = [read_curve(icc, b"rTRC")?, read_curve(icc, b"gTRC")?, read_curve(icc, b"bTRC")?];
+    Ok(Profile { matrix, curves })
+}
+
+/// The color profile embedded in a source: `Ok(None)` without one, or if the source isn't an
+/// image the decoders know, and `Err` for a profile that can't be applied.
+pub fn source_profile(bytes: &[u8]) -> Result<Option<Profile>, String> {
+    let icc = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_decoder().ok())
+        .and_then(|mut decoder| decoder.icc_profile().ok().flatten());
+    icc.map(|icc| parse(&icc)).transpose()
+}
+
+fn invert(m: &Matrix) -> Matrix {
+    let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1]) - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    let cofactor = |r0: usize, r1: usize, c0: usize, c1: usize| m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0];
+    [
+        [cofactor(1, 2, 1, 2) / det, -cofactor(0, 2, 1, 2) / det, cofactor(0, 1, 1, 2) / det],
+        [-cofactor(1, 2, 0, 2) / det, cofactor(0, 2, 0, 2) / det, -cofactor(0, 1, 0, 2) / det],
+        [cofactor(1, 2, 0, 1) / det, -cofactor(0, 2, 0, 1) / det, cofactor(0, 1, 0, 1) / det],
+    ]
+}
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    [0, 1, 2].map(|i| [0, 1, 2].map(|j| (0..3).map(|k| a[i][k] * b[k][j]).sum()))
+}
+
+/// Converts `rgb` from the `source` profile, sRGB when there is none, to `target`.
+pub fn convert(rgb: &mut RgbImage, source: Option<&Profile>, target: ColorSpace) {
+    let srgb = Profile::srgb();
+    let source = source.unwrap_or(&srgb);
+    if source.is(target) {
+        return;
+    }
+
+    let matrix = multiply(&invert(&target_matrix(target)), &source.matrix);
+    let decode: [Vec<f64>; 3] = [0, 1, 2].map(|c| (0..=255).map(|v| source.curves[c].linear(v as f64 / 255.0)).collect());
+    const STEPS: usize = 4096;
+    let encode: Vec<u8> = (0..STEPS)
+        .map(|i| (srgb_encode(i as f64 / (STEPS - 1) as f64) * 255.0).round() as u8)
+        .collect();
+
+    for pixel in rgb.pixels_mut() {
+        let linear = [0, 1, 2].map(|c| decode[c][pixel[c] as usize]);
+        for (c, row) in matrix.iter().enumerate() {
+            let value = (row[0] * linear[0] + row[1] * linear[1] + row[2] * linear[2]).clamp(0.0, 1.0);
+            pixel[c] = encode[(value * (STEPS - 1) as f64).round() as usize];
+        }
+    }
+}
+
+/// `img` converted like [`convert`], 8 bits per channel, keeping its alpha channel.
+pub fn convert_image(img: &DynamicImage, source: Option<&Profile>, target: ColorSpace) -> DynamicImage {
+    let mut rgb = img.to_rgb8();
+    convert(&mut rgb, source, target);
+    if !img.color().has_alpha() {
+        return DynamicImage::ImageRgb8(rgb);
+    }
+    let rgba = img.to_rgba8();
+    DynamicImage::ImageRgba8(RgbaImage::from_fn(rgb.width(), rgb.height(), |x, y| {
+        let [r, g, b] = rgb.get_pixel(x, y).0;
+        Rgba([r, g, b, rgba.get_pixel(x, y)[3]])
+    }))
+}
+
+/// The ICC profile to embed for `space`, `None` for sRGB.
+pub fn icc_profile(space: ColorSpace) -> Option<&'static [u8]> {
+    static DISPLAY_P3_PROFILE: OnceLock<Vec<u8>> = OnceLock::new();
+    match space {
+        ColorSpace::Srgb => None,
+        ColorSpace::DisplayP3 => Some(DISPLAY_P3_PROFILE.get_or_init(|| build_profile("Display P3", &DISPLAY_P3))),
+    }
+}
+
+fn s15_16(value: f64) -> [u8; 4] {
+    ((value * 65536.0).round() as i32).to_be_bytes()
+}
+
+fn xyz_tag(xyz: [f64; 3]) -> Vec<u8> {
+    let mut data = b"XYZ \0\0\0\0".to_vec();
+    xyz.iter().for_each(|v| data.extend(s15_16(*v)));
+    data
+}
+
+fn text_tag(text: &str) -> Vec<u8> {
+    let utf16: Vec<u8> = text.encode_utf16().flat_map(u16::to_be_bytes).collect();
+    let mut data = b"mluc\0\0\0\0".to_vec();
+    data.extend(1u32.to_be_bytes());
+    data.extend(12u32.to_be_bytes());
+    data.extend(b"enUS");
+    data.extend((utf16.len() as u32).to_be_bytes());
+    data.extend(28u32.to_be_bytes());
+    data.extend(utf16);
+    data
+}
+
+/// A version 4 display profile with sRGB's tone curve and the given primaries, dated at a fixed
+/// time so renditions stay reproducible.
+fn build_profile(description: &str, primaries: &Matrix) -> Vec<u8> {
+    let Curve::Parametric(_, params) = Curve::srgb() else {
+        unreachable!()
+    };
+    let mut curve = b"para\0\0\0\0".to_vec();
+    curve.extend(3u16.to_be_bytes());
+    curve.extend([0, 0]);
+    params[..5].iter().for_each(|v| curve.extend(s15_16(*v)));
+    let mut chad = b"sf32\0\0\0\0".to_vec();
+    D65_TO_D50.iter().flatten().for_each(|v| chad.extend(s15_16(*v)));
+
+    let column = |c: usize| [primaries[0][c], primaries[1][c], primaries[2][c]];
+    let tags: Vec<(&[u8; 4], Vec<u8>)> = vec![
+        (b"desc", text_tag(description)),
+        (b"cprt", text_tag("No copyright, use freely")),
+        (b"wtpt", xyz_tag(D50)),
+        (b"chad", chad),
+        (b"rXYZ", xyz_tag(column(0))),
+        (b"gXYZ", xyz_tag(column(1))),
+        (b"bXYZ", xyz_tag(column(2))),
+        (b"rTRC", curve.clone()),
+        (b"gTRC", curve.clone()),
+        (b"bTRC", curve),
+    ];
+
+    let mut table = Vec::new();
+    let mut data = Vec::new();
+    let mut offset = 128 + 4 + tags.len() * 12;
+    for (name, tag) in &tags {
+        table.extend(*name);
+        table.extend((offset as u32).to_be_bytes());
+        table.extend((tag.len() as u32).to_be_bytes());
+        data.extend(tag);
+        // tags start on four byte boundaries
+        let padded = tag.len().next_multiple_of(4);
+        data.resize(data.len() + padded - tag.len(), 0);
+        offset += padded;
+    }
+
+    let mut header = vec![0u8; 128];
+    header[0..4].copy_from_slice(&(offset as u32).to_be_bytes());
+    header[8..12].copy_from_slice(&0x0430_0000u32.to_be_bytes());
+    header[12..16].copy_from_slice(b"mntr");
+    header[16..20].copy_from_slice(b"RGB ");
+    header[20..24].copy_from_slice(b"XYZ ");
+    for (i, part) in [2024u16, 1, 1, 0, 0, 0].iter().enumerate() {
+        header[24 + i * 2..26 + i * 2].copy_from_slice(&part.to_be_bytes());
+    }
+    header[36..40].copy_from_slice(b"acsp");
+    for (i, v) in D50.iter().enumerate() {
+        header[68 + i * 4..72 + i * 4].copy_from_slice(&s15_16(*v));
+    }
+
+    let mut profile = header;
+    profile.extend((tags.len() as u32).to_be_bytes());
+    profile.extend(table);
+    profile.extend(data);
+    profile
+}
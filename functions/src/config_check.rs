@@ -0,0 +1,65 @@
+// functions/src/config_check.rs
+
+//! The worker's `--check-config`, see `core/src/config_check.rs`. Besides the shared settings,
+//! accounts and queues, it reads the worker's own settings and checks each container given as an
+//! argument, or `AZURE_STORAGE_CONTAINER` without any: the container answers, its
+//! `presets/resize.json` parses and every template under `templates/` loads with the watermark,
+//! font and plugins it names.
+
+use image_resize_core::{
+    clients, config,
+    config_check::{self, ConfigReport},
+    failover::Location,
+    health,
+    shards,
+};
+
+use crate::{alert, budget, normalize, resize, seen, shed, template};
+
+pub async fn run(args: &[String]) -> ! {
+    let mut report = ConfigReport::new();
+    if config_check::check_shared(&mut report).await {
+        report.setting("encode budgets", budget::init);
+        report.setting("normalize", normalize::init);
+        report.setting("alerts", || {
+            alert::sink_from_env();
+            alert::ErrorRateMonitor::from_env();
+        });
+        report.setting("load shedding", || {
+            shed::LoadShedder::from_env();
+        });
+        report.setting("duplicate deliveries", || {
+            seen::SeenMessages::from_env();
+        });
+        report.setting("queue shard", || {
+            shards::worker_shard();
+        });
+
+        let mut containers = config_check::operands(args);
+        if containers.is_empty() {
+            containers.push(config::get().container().to_string());
+        }
+        for container in containers {
+            check_container(&mut report, &container).await;
+        }
+    }
+    report.exit(args)
+}
+
+async fn check_container(report: &mut ConfigReport, container: &str) {
+    let service_client = clients::blob_service_client(Location::Primary);
+    let container_client = service_client.container_client(container);
+    report.service(format!("container {}", container), container_client.get_properties().into_future()).await;
+    report.service(format!("preset of {}", container), resize::check_preset(&service_client, container)).await;
+
+    let names = match template::names(&container_client).await {
+        Ok(names) => names,
+        Err(e) => {
+            report.add(format!("templates of {}", container), health::Check::failed(e.to_string()));
+            return;
+        }
+    };
+    for name in names {
+        report.service(format!("template {} of {}", name, container), template::check(&name, &container_client)).await;
+    }
+}
@@ -0,0 +1,56 @@
+// functions/src/dead_letter.rs
+
+//! Messages whose stage failed permanently, see `error.rs`. Each is written with its error to
+//! `failed/<filename>/<time>.json` (`pipeline::FAILED_PREFIX`) in the image's container and then
+//! completed, so a bad input neither crashes the worker nor comes back until Service Bus gives up
+//! on it. Replaying one is a matter of fixing the input and sending its `message` again.
+
+use azure_core::date;
+use azure_storage_blobs::prelude::BlobServiceClient;
+use image_resize_core::{pipeline, telemetry};
+use serde::Serialize;
+use time::OffsetDateTime;
+use tracing::warn;
+
+use crate::ImageMessage;
+
+#[derive(Serialize)]
+struct DeadLetter<'a> {
+    message: &'a ImageMessage,
+    error: String,
+    /// Runs of the stage on this delivery, retries included.
+    attempts: u32,
+    /// RFC 3339.
+    failed_at: String,
+}
+
+/// Writes the image's message and `error` under the failed prefix. An error means the record
+/// wasn't written and the message should be abandoned rather than completed.
+pub async fn record(
+    image: &ImageMessage,
+    error: &azure_core::Error,
+    attempts: u32,
+    service_client: &BlobServiceClient,
+) -> azure_core::Result<()> {
+    let now = OffsetDateTime::now_utc();
+    let name = format!("{}{}/{}.json", pipeline::FAILED_PREFIX, image.filename, now.unix_timestamp_nanos());
+    let letter = DeadLetter {
+        message: image,
+        error: error.to_string(),
+        attempts,
+        failed_at: date::to_rfc3339(&now),
+    };
+    let body = serde_json::to_vec_pretty(&letter)?;
+    let upload = service_client
+        .container_client(&image.image_container)
+        .blob_client(&name)
+        .put_block_blob(body)
+        .content_type("application/json")
+        .into_future();
+    if let Err(e) = telemetry::dependency("Azure blob", &image.image_container, "put_block_blob", upload).await {
+        warn!("Failed to dead-letter {:?} of {}: {:?}", image.stage, image.filename, e);
+        return Err(e);
+    }
+    telemetry::track_event("MessageDeadLettered", &[("filename", image.filename.clone()), ("blob", name)]);
+    Ok(())
+}
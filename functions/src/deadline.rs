@@ -0,0 +1,76 @@
+// functions/src/deadline.rs
+
+//! Time-boxed resize stages. With `JOB_DEADLINE_SECS` set, a resize stage that has run that long
+//! starts no more variants: the renditions made so far are stored and the job goes on to its next
+//! stage with them, while the missing variants are queued as a message of their own that makes only
+//! those. The job's record then lists each rendition as done or pending and the job stays
+//! `partially_complete`, see `core/src/job_status.rs`, until the last of them is made.
+//!
+//! The deadline is checked between renditions, so one already being encoded is finished, and every
+//! run makes at least one rendition, the resized one unless only variants are left. The queued
+//! message keeps the job's `queued_at`, so `JOB_MAX_AGE_SECS` should leave room for it.
+
+use image_resize_core::{job_status, naming, queue::QueueSender, telemetry, trace, variants::Variant};
+use std::{
+    env,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+use crate::ImageMessage;
+
+/// When a resize stage starting now should stop starting renditions, `None` without a deadline.
+pub fn from_now() -> Option<Instant> {
+    env::var("JOB_DEADLINE_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|secs| *secs > 0)
+        .map(|secs| Instant::now() + Duration::from_secs(secs))
+}
+
+pub fn passed(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+/// Records which of the image's renditions its resize stage made and which it left in `deferred`,
+/// when it left any or was making ones left before. Failures are only logged.
+pub async fn record_renditions(image: &ImageMessage, deferred: &[Variant]) {
+    let Some(job_id) = &image.job_id else {
+        return;
+    };
+    if deferred.is_empty() && !image.variants_only {
+        return;
+    }
+    let blob_name = |variant: &Variant| variant.blob_name(&image.filename);
+    let mut done: Vec<String> = image.variants.iter().filter(|variant| !deferred.contains(variant)).map(blob_name).collect();
+    if !image.variants_only {
+        done.insert(0, naming::resized(&image.filename));
+    }
+    let pending: Vec<String> = deferred.iter().map(blob_name).collect();
+    if let Err(e) = job_status::update_renditions(job_id, &done, &pending).await {
+        warn!("Failed to record the renditions of job {} of {}: {:?}", job_id, image.filename, e);
+    }
+}
+
+/// Queues a resize stage making only the `deferred` variants of `image`. An error means it wasn't
+/// sent, and the received message should be abandoned rather than completed.
+pub async fn requeue(image: &ImageMessage, deferred: Vec<Variant>, sender: &QueueSender) -> azure_core::Result<()> {
+    let mut rest = image.clone();
+    rest.variants = deferred;
+    rest.variants_only = true;
+    // the follow-up stages go on with the renditions already made
+    rest.then = Vec::new();
+    rest.deferrals = 0;
+    rest.traceparent = trace::traceparent().or(rest.traceparent);
+
+    let sender = sender.for_image(&rest);
+    let message = serde_json::to_string(&rest).expect("Failed to serialize image");
+    telemetry::dependency("Azure Service Bus", sender.queue_name(), "send_message", sender.send(&rest, &message)).await?;
+
+    info!("Out of time, queued {} variants of {} for a later run", rest.variants.len(), image.filename);
+    telemetry::track_event(
+        "RenditionsDeferred",
+        &[("filename", image.filename.clone()), ("variants", rest.variants.len().to_string())],
+    );
+    Ok(())
+}
@@ -0,0 +1,155 @@
+// functions/src/decode.rs
+
+//! Decoding of source images. Four-component JPEGs from print workflows need care: the Adobe
+//! APP14 segment says how the components were stored, `0` for CMYK and `2` for YCCK, both written
+//! inverted by Photoshop, and the `image` crate decodes those correctly. Without an APP14 segment
+//! the components are plain, non-inverted CMYK, which would come out as an inverted-color image,
+//! so those are converted here instead.
+//!
+//! Sources are held to the limits of `image_checks.rs` before they're decoded, in case one got past
+//! the API's checks, and are turned upright by their EXIF orientation, which renditions don't carry.
+//!
+//! Video, PDF and SVG sources are processed through an image taken from them, see [`load_source`].
+
+use azure_core::error::{Error, ErrorKind};
+use image::{
+    error::{LimitError, LimitErrorKind},
+    DynamicImage, ImageDecoder, ImageError, ImageReader, ImageResult, RgbImage,
+};
+use image_resize_core::{config, features, image_checks::ImageLimits, routing::Pipeline, svg, video::VideoFormat};
+use std::io::{self, Cursor};
+use tracing::info;
+use zune_jpeg::{
+    zune_core::{bytestream::ZCursor, colorspace::ColorSpace, options::DecoderOptions},
+    JpegDecoder,
+};
+
+use crate::{error::StageError, pdf, rasterize, video};
+
+const SOI: [u8; 2] = [0xFF, 0xD8];
+const APP14: u8 = 0xEE;
+const SOS: u8 = 0xDA;
+
+/// Color layout of a JPEG as declared by its headers.
+struct JpegColor {
+    components: u8,
+    /// Color transform of the Adobe APP14 segment, if there is one.
+    adobe_transform: Option<u8>,
+}
+
+/// Reads the component count and Adobe transform from the segments before the scan data.
+fn inspect(bytes: &[u8]) -> Option<JpegColor> {
+    if !bytes.starts_with(&SOI) {
+        return None;
+    }
+    let mut components = None;
+    let mut adobe_transform = None;
+    let mut position = 2;
+    while position + 4 <= bytes.len() && bytes[position] == 0xFF {
+        let marker = bytes[position + 1];
+        if marker == SOS {
+            break;
+        }
+        let length = u16::from_be_bytes([bytes[position + 2], bytes[position + 3]]) as usize;
+        let payload = bytes.get(position + 4..position + 2 + length)?;
+        match marker {
+            // "Adobe", version, two flag words, then the transform
+            APP14 if payload.starts_with(b"Adobe") => adobe_transform = payload.get(11).copied(),
+            // start of frame markers, except DHT (C4), JPG (C8) and DAC (CC)
+            0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => components = payload.get(5).copied(),
+            _ => {}
+        }
+        position += 2 + length;
+    }
+    Some(JpegColor {
+        components: components?,
+        adobe_transform,
+    })
+}
+
+/// Decodes a plain CMYK JPEG to RGB.
+fn decode_plain_cmyk(bytes: &[u8]) -> Result<DynamicImage, String> {
+    let options = DecoderOptions::default()
+        .set_strict_mode(false)
+        .jpeg_set_out_colorspace(ColorSpace::CMYK);
+    let mut decoder = JpegDecoder::new_with_options(ZCursor::new(bytes), options);
+    let cmyk = decoder.decode().map_err(|e| format!("{:?}", e))?;
+    let (width, height) = decoder.dimensions().ok_or("Missing JPEG dimensions")?;
+
+    let rgb: Vec<u8> = cmyk
+        .chunks_exact(4)
+        .flat_map(|pixel| {
+            let key = 255 - pixel[3] as u16;
+            [0, 1, 2].map(|channel| ((255 - pixel[channel] as u16) * key / 255) as u8)
+        })
+        .collect();
+    RgbImage::from_raw(width as u32, height as u32, rgb)
+        .map(DynamicImage::ImageRgb8)
+        .ok_or_else(|| "CMYK data doesn't match the JPEG dimensions".to_string())
+}
+
+/// Loads a source image, whether it is in a format the `image` crate handles as is or a plain
+/// CMYK JPEG, and turns it upright.
+pub fn load(bytes: &[u8]) -> ImageResult<DynamicImage> {
+    let mut decoder = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?.into_decoder()?;
+    let (width, height) = decoder.dimensions();
+    if let Err(e) = ImageLimits::from_env().check("The source", width, height) {
+        info!("{}", e);
+        return Err(ImageError::Limits(LimitError::from_kind(LimitErrorKind::DimensionError)));
+    }
+    let orientation = decoder.orientation()?;
+    let mut img = match inspect(bytes) {
+        Some(JpegColor {
+            components: 4,
+            adobe_transform: None,
+        }) => decode_plain_cmyk(bytes)
+            .map_err(|e| ImageError::IoError(io::Error::new(io::ErrorKind::InvalidData, e)))?,
+        _ => DynamicImage::from_decoder(decoder)?,
+    };
+    img.apply_orientation(orientation);
+    Ok(img)
+}
+
+/// Name of the format of a source, e.g. `jpeg`, `mp4` or `pdf`, for reports and metrics, and the
+/// type `CONTENT_ROUTES` routes it by, see `core/src/routing.rs`.
+pub fn source_format(bytes: &[u8]) -> String {
+    if let Some(format) = VideoFormat::sniff(bytes) {
+        format.extension().to_string()
+    } else if image_resize_core::pdf::is_pdf(bytes) {
+        image_resize_core::pdf::EXTENSION.to_string()
+    } else if svg::is_svg(bytes) {
+        svg::EXTENSION.to_string()
+    } else {
+        image::guess_format(bytes).map_or_else(|_| "unknown".to_string(), |format| format!("{:?}", format).to_lowercase())
+    }
+}
+
+/// The image to process from a source blob: the image itself, the poster frame at `poster_at`
+/// seconds of a video, the first page of a PDF, or an SVG rasterized.
+pub async fn load_source(bytes: &[u8], poster_at: f64, tenant: Option<&str>) -> azure_core::Result<DynamicImage> {
+    let frame = if let Some(format) = VideoFormat::sniff(bytes) {
+        if !features::is_enabled(features::VIDEO_POSTER, tenant).await {
+            return Err(Error::message(ErrorKind::Other, "Video sources need the video_poster feature"));
+        }
+        info!("Taking the poster frame at {}s of a {:?} video", poster_at, format);
+        video::poster_frame(bytes, format, poster_at).await
+    } else if image_resize_core::pdf::is_pdf(bytes) {
+        if !features::is_enabled(features::PDF_PAGES, tenant).await {
+            return Err(Error::message(ErrorKind::Other, "PDF sources need the pdf_pages feature"));
+        }
+        info!("Rendering the first page of a PDF");
+        pdf::render(bytes, 1, 1, pdf::DEFAULT_DPI)
+            .await
+            .and_then(|pages| pages.into_iter().next().map(|(_, png)| png).ok_or_else(|| "The PDF has no pages".to_string()))
+    } else if svg::is_svg(bytes) {
+        if config::get().pipeline_for(svg::EXTENSION) != Pipeline::Rasterize {
+            return Err(Error::message(ErrorKind::Other, "SVG sources need CONTENT_ROUTES to send svg to rasterize"));
+        }
+        info!("Rasterizing an SVG");
+        rasterize::rasterize(bytes).await
+    } else {
+        return Ok(load(bytes).map_err(|source| StageError::Decode { what: "the source image".to_string(), source })?);
+    };
+    let frame = frame.map_err(|e| Error::with_message(ErrorKind::Io, || e))?;
+    Ok(load(&frame).map_err(|source| StageError::Decode { what: "the rendered frame".to_string(), source })?)
+}
@@ -0,0 +1,123 @@
+// functions/src/dedup.rs
+
+//! Storing renditions with the `output_dedup` flag on: when another rendition in the container
+//! already holds identical content, common with duplicate uploads, the rendition is made by a
+//! server-side copy of it rather than by uploading the bytes again. References are tracked by
+//! `core/src/content_store.rs`, and released when a rendition is overwritten with other content.
+
+use azure_core::request_options::Metadata;
+use azure_storage_blobs::prelude::{BlobClient, Tags};
+use image_resize_core::{content_store, features, output_format::OutputFormat, pipeline, tables, telemetry};
+use tracing::{info, warn};
+
+/// The content hash a rendition had before being overwritten, if it was stored with one.
+async fn previous_hash(blob_client: &BlobClient) -> Option<String> {
+    let properties = blob_client.get_properties().await.ok()?;
+    properties.blob.metadata?.get(pipeline::CONTENT_HASH_KEY).cloned()
+}
+
+/// Where a rendition's content comes from when no identical rendition can be copied.
+enum Content<'a> {
+    Bytes(Vec<u8>),
+    /// A blob written under the staging prefix, see `staging.rs`.
+    Staged(&'a BlobClient),
+}
+
+/// Writes a rendition under `blob_client` and returns the blob it was copied from, if any.
+pub async fn store(
+    blob_client: &BlobClient,
+    bytes: Vec<u8>,
+    content_hash: &str,
+    metadata: Metadata,
+    tags: Tags,
+    tenant: Option<&str>,
+) -> azure_core::Result<Option<String>> {
+    store_from(blob_client, Content::Bytes(bytes), content_hash, metadata, tags, tenant).await
+}
+
+/// Like [`store`], but copies the rendition from its staged blob rather than uploading it.
+pub async fn store_staged(
+    blob_client: &BlobClient,
+    staged: &BlobClient,
+    content_hash: &str,
+    metadata: Metadata,
+    tags: Tags,
+    tenant: Option<&str>,
+) -> azure_core::Result<Option<String>> {
+    store_from(blob_client, Content::Staged(staged), content_hash, metadata, tags, tenant).await
+}
+
+/// Copies `source` over `blob_client`, giving it `metadata` and `tags`.
+async fn copy(blob_client: &BlobClient, source: &BlobClient, metadata: Metadata, tags: Tags) -> azure_core::Result<()> {
+    blob_client.copy(source.url()?).await?;
+    // a copy carries the source's metadata and no index tags
+    blob_client.set_metadata().metadata(metadata).await?;
+    blob_client.set_tags(tags).await?;
+    Ok(())
+}
+
+async fn store_from(
+    blob_client: &BlobClient,
+    content: Content<'_>,
+    content_hash: &str,
+    metadata: Metadata,
+    tags: Tags,
+    tenant: Option<&str>,
+) -> azure_core::Result<Option<String>> {
+    let container_client = blob_client.container_client();
+    let container = container_client.container_name();
+    let name = blob_client.blob_name();
+
+    let mut source = None;
+    if features::is_enabled(features::OUTPUT_DEDUP, tenant).await {
+        if let Some(previous) = previous_hash(blob_client).await.filter(|previous| previous != content_hash) {
+            if let Err(e) = content_store::release(container, &previous, name).await {
+                warn!("Failed to release content {} of {}: {:?}", previous, name, e);
+            }
+        }
+        source = match content_store::add_reference(container, content_hash, name).await {
+            Ok(source) => source,
+            Err(e) => {
+                warn!("Failed to look up content {} for {}: {:?}", content_hash, name, e);
+                None
+            }
+        };
+    }
+
+    if let Some(source) = &source {
+        let source_client = container_client.blob_client(source);
+        let copied = copy(blob_client, &source_client, metadata.clone(), tags.clone());
+        match telemetry::dependency("Azure blob", container, "copy", copied).await {
+            Ok(_) => {
+                info!("Copied {} from {} with identical content", name, source);
+                return Ok(Some(source.clone()));
+            }
+            Err(e) => {
+                warn!("Failed to copy {} from {}, uploading instead: {:?}", name, source, e);
+                // the source is gone, so it can't be offered again
+                if tables::is_not_found(&e) {
+                    if let Err(e) = content_store::release(container, content_hash, source).await {
+                        warn!("Failed to release content {} of {}: {:?}", content_hash, source, e);
+                    }
+                }
+            }
+        }
+    }
+
+    match content {
+        Content::Bytes(bytes) => {
+            let content_type = OutputFormat::content_type_of(&bytes);
+            let upload = blob_client
+                .put_block_blob(bytes)
+                .content_type(content_type)
+                .metadata(metadata)
+                .tags(tags)
+                .into_future();
+            telemetry::dependency("Azure blob", container, "put_block_blob", upload).await?;
+        }
+        Content::Staged(staged) => {
+            telemetry::dependency("Azure blob", container, "copy", copy(blob_client, staged, metadata, tags)).await?;
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,87 @@
+// functions/src/drain.rs
+
+use std::{
+    env,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use tracing::{error, info};
+
+/// Tells the worker to stop taking messages, e.g. while a deployment rolls over.
+///
+/// Draining is requested by `WORKER_DRAIN=1`, by creating the file named in `WORKER_DRAIN_FILE`
+/// (so it can be flipped on a running instance), or by SIGTERM/Ctrl-C. A signal no longer kills
+/// the process outright: the messages in flight are finished and their next stages enqueued first.
+#[derive(Clone)]
+pub struct Drain {
+    signalled: Arc<AtomicBool>,
+    drain_file: Option<String>,
+}
+
+impl Drain {
+    pub fn install() -> Self {
+        let drain = Drain {
+            signalled: Arc::new(AtomicBool::new(false)),
+            drain_file: env::var("WORKER_DRAIN_FILE").ok(),
+        };
+
+        let signalled = drain.signalled.clone();
+        tokio::spawn(async move {
+            wait_for_shutdown_signal().await;
+            info!("Shutdown signal received, finishing the messages in flight before exiting");
+            signalled.store(true, Ordering::SeqCst);
+        });
+
+        drain
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.signalled.load(Ordering::SeqCst)
+            || env::var("WORKER_DRAIN").is_ok_and(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+            || self.drain_file.as_deref().is_some_and(|path| Path::new(path).exists())
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            tokio::select! {
+                _ = terminate.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+        }
+        Err(e) => {
+            error!("Failed to listen for SIGTERM: {:?}", e);
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_shutdown_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Records the last message finished before a drained exit, in `WORKER_CHECKPOINT_FILE` if set.
+pub async fn checkpoint(last_message: Option<&str>) {
+    let Ok(path) = env::var("WORKER_CHECKPOINT_FILE") else {
+        return;
+    };
+    let checkpoint = serde_json::json!({
+        "drained_at": std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+        "last_message": last_message,
+    });
+    match tokio::fs::write(&path, checkpoint.to_string()).await {
+        Ok(()) => info!("Wrote drain checkpoint to {}", path),
+        Err(e) => error!("Failed to write drain checkpoint to {}: {:?}", path, e),
+    }
+}
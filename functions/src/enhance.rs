@@ -0,0 +1,130 @@
+// functions/src/enhance.rs
+
+use image::{DynamicImage, RgbImage, RgbaImage};
+
+/// Fraction of pixels clipped at each end of the histogram before stretching.
+const CLIP_FRACTION: f64 = 0.005;
+/// Gamma correction is limited to this range so a nearly black image doesn't get blown out.
+const MIN_GAMMA: f64 = 0.5;
+const MAX_GAMMA: f64 = 2.0;
+
+/// Gray-world white balance, per-channel levels stretch and a gamma pull towards mid-gray,
+/// aimed at consistently underexposed user-generated content.
+pub fn auto_enhance(img: &DynamicImage) -> DynamicImage {
+    let mut rgb = img.to_rgb8();
+
+    white_balance(&mut rgb);
+    stretch_levels(&mut rgb);
+    correct_gamma(&mut rgb);
+
+    if img.color().has_alpha() {
+        // carry the original alpha channel over to the enhanced pixels
+        let alpha = img.to_rgba8();
+        let mut rgba = RgbaImage::new(rgb.width(), rgb.height());
+        for ((out, color), source) in rgba.pixels_mut().zip(rgb.pixels()).zip(alpha.pixels()) {
+            *out = image::Rgba([color[0], color[1], color[2], source[3]]);
+        }
+        DynamicImage::ImageRgba8(rgba)
+    } else {
+        DynamicImage::ImageRgb8(rgb)
+    }
+}
+
+fn channel_means(rgb: &RgbImage) -> [f64; 3] {
+    let mut sums = [0u64; 3];
+    for pixel in rgb.pixels() {
+        for c in 0..3 {
+            sums[c] += pixel[c] as u64;
+        }
+    }
+    let n = (rgb.width() as u64 * rgb.height() as u64).max(1) as f64;
+    [sums[0] as f64 / n, sums[1] as f64 / n, sums[2] as f64 / n]
+}
+
+fn apply_lut(rgb: &mut RgbImage, luts: &[[u8; 256]; 3]) {
+    for pixel in rgb.pixels_mut() {
+        for c in 0..3 {
+            pixel[c] = luts[c][pixel[c] as usize];
+        }
+    }
+}
+
+fn white_balance(rgb: &mut RgbImage) {
+    let means = channel_means(rgb);
+    let gray = (means[0] + means[1] + means[2]) / 3.0;
+    if gray < 1.0 {
+        return;
+    }
+
+    let mut luts = [[0u8; 256]; 3];
+    for c in 0..3 {
+        let gain = if means[c] > 0.0 { gray / means[c] } else { 1.0 };
+        for (v, out) in luts[c].iter_mut().enumerate() {
+            *out = (v as f64 * gain).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+    apply_lut(rgb, &luts);
+}
+
+fn stretch_levels(rgb: &mut RgbImage) {
+    let mut histograms = [[0u64; 256]; 3];
+    for pixel in rgb.pixels() {
+        for c in 0..3 {
+            histograms[c][pixel[c] as usize] += 1;
+        }
+    }
+    let clip = ((rgb.width() as u64 * rgb.height() as u64) as f64 * CLIP_FRACTION) as u64;
+
+    let mut luts = [[0u8; 256]; 3];
+    for c in 0..3 {
+        let (low, high) = percentile_bounds(&histograms[c], clip);
+        for (v, out) in luts[c].iter_mut().enumerate() {
+            *out = if high <= low {
+                v as u8
+            } else {
+                ((v as f64 - low as f64) * 255.0 / (high - low) as f64).round().clamp(0.0, 255.0) as u8
+            };
+        }
+    }
+    apply_lut(rgb, &luts);
+}
+
+fn percentile_bounds(histogram: &[u64; 256], clip: u64) -> (usize, usize) {
+    let mut seen = 0;
+    let mut low = 0;
+    for (v, count) in histogram.iter().enumerate() {
+        seen += count;
+        if seen > clip {
+            low = v;
+            break;
+        }
+    }
+
+    seen = 0;
+    let mut high = 255;
+    for (v, count) in histogram.iter().enumerate().rev() {
+        seen += count;
+        if seen > clip {
+            high = v;
+            break;
+        }
+    }
+
+    (low, high)
+}
+
+fn correct_gamma(rgb: &mut RgbImage) {
+    let means = channel_means(rgb);
+    let mean = (0.299 * means[0] + 0.587 * means[1] + 0.114 * means[2]) / 255.0;
+    if mean <= 0.0 || mean >= 1.0 {
+        return;
+    }
+
+    // solve mean^gamma = 0.5
+    let gamma = (0.5f64.ln() / mean.ln()).clamp(MIN_GAMMA, MAX_GAMMA);
+    let mut lut = [0u8; 256];
+    for (v, out) in lut.iter_mut().enumerate() {
+        *out = ((v as f64 / 255.0).powf(gamma) * 255.0).round() as u8;
+    }
+    apply_lut(rgb, &[lut; 3]);
+}
@@ -0,0 +1,81 @@
+// functions/src/error.rs
+
+//! Why a stage failed, and whether running it again could help. Stages return
+//! `azure_core::Result`, so a [`StageError`] travels inside an `azure_core::Error` the way
+//! `cancel::Cancelled` and `isolate::Panicked` do, and [`is_permanent`] looks for it there.
+//!
+//! Permanent failures, a source that won't decode or a preset that won't parse, fail the same way
+//! on every delivery, so the worker records them as dead letters (see `dead_letter.rs`) rather than
+//! abandoning them. Anything else, a storage outage or a connection dropped mid-download, is
+//! retried in place [`stage_retries`] times with a doubling delay before the message is abandoned
+//! for Service Bus to deliver again.
+
+use azure_core::{
+    error::{Error, ErrorKind},
+    StatusCode,
+};
+use std::{env, time::Duration};
+use thiserror::Error;
+
+use crate::isolate::Panicked;
+
+const DEFAULT_STAGE_RETRIES: u32 = 2;
+const DEFAULT_STAGE_RETRY_DELAY_MS: u64 = 1000;
+
+#[derive(Debug, Error)]
+pub enum StageError {
+    #[error("Failed to decode {what}: {source}")]
+    Decode {
+        what: String,
+        #[source]
+        source: image::ImageError,
+    },
+    #[error("Failed to load font {blob}: {reason}")]
+    Font { blob: String, reason: String },
+    #[error("Failed to load plugin {blob}: {reason}")]
+    Plugin { blob: String, reason: String },
+    #[error("Failed to encode the rendition: {0}")]
+    Encode(String),
+    #[error("Invalid {what}: {source}")]
+    Invalid {
+        what: String,
+        #[source]
+        source: serde_json::Error,
+    },
+}
+
+impl From<StageError> for Error {
+    fn from(e: StageError) -> Self {
+        Error::new(ErrorKind::DataConversion, e)
+    }
+}
+
+/// Whether `e` would fail the stage again however often it's retried.
+pub fn is_permanent(e: &Error) -> bool {
+    if e.downcast_ref::<StageError>().is_some() || e.downcast_ref::<Panicked>().is_some() {
+        return true;
+    }
+    match e.kind() {
+        // the blob is gone or the request itself is wrong
+        ErrorKind::HttpResponse { status, .. } => matches!(status, StatusCode::BadRequest | StatusCode::NotFound),
+        ErrorKind::DataConversion => true,
+        _ => false,
+    }
+}
+
+/// Retries of a stage failing transiently before its message is abandoned, from `STAGE_RETRIES`.
+pub fn stage_retries() -> u32 {
+    env::var("STAGE_RETRIES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_STAGE_RETRIES)
+}
+
+/// The wait before retry `attempt` (from 1), doubling from `STAGE_RETRY_DELAY_MS`.
+pub fn retry_delay(attempt: u32) -> Duration {
+    let base = env::var("STAGE_RETRY_DELAY_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_STAGE_RETRY_DELAY_MS);
+    Duration::from_millis(base.saturating_mul(1 << attempt.saturating_sub(1).min(16)))
+}
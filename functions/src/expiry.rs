@@ -0,0 +1,42 @@
+// functions/src/expiry.rs
+
+//! Stale jobs. With `JOB_MAX_AGE_SECS` set, a message queued longer ago than that is skipped
+//! rather than processed, so a large backlog doesn't burn compute on requests nobody waits for
+//! anymore, and recorded as expired in the job status table, see `core/src/job_status.rs`. The age
+//! counts from when the API queued the job, so follow-up stages of an old chain expire too.
+
+use azure_core::date;
+use image_resize_core::job_status;
+use std::{env, time::Duration};
+use time::OffsetDateTime;
+use tracing::warn;
+
+use crate::ImageMessage;
+
+fn max_age() -> Option<Duration> {
+    env::var("JOB_MAX_AGE_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs)
+}
+
+/// Whether the image's job waited longer than `JOB_MAX_AGE_SECS`; messages without a queue time
+/// are never stale.
+pub fn is_stale(image: &ImageMessage) -> bool {
+    let (Some(max_age), Some(queued_at)) = (max_age(), image.queued_at.as_deref()) else {
+        return false;
+    };
+    match date::parse_rfc3339(queued_at) {
+        Ok(queued_at) => OffsetDateTime::now_utc() - queued_at > max_age,
+        Err(_) => false,
+    }
+}
+
+/// Marks the image's job expired; failures are only logged, the job being dropped either way.
+pub async fn record(image: &ImageMessage) {
+    let queued_at = image.queued_at.as_deref().unwrap_or_default();
+    if let Err(e) = job_status::record_expired(&image.image_container, &image.filename, queued_at).await {
+        warn!("Failed to record the expiry of {}: {:?}", image.filename, e);
+    }
+}
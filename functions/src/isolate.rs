@@ -0,0 +1,45 @@
+// functions/src/isolate.rs
+
+//! Keeps a panic in one stage, e.g. a decoder bug tripped by a pathological image, from taking down
+//! the other jobs in flight. The stage fails with [`Panicked`] instead, is reported and counted by
+//! the error rate alert, and its message is dead-lettered as a permanent failure (see `error.rs`)
+//! rather than crashing every worker that receives it.
+
+use azure_core::error::{Error, ErrorKind};
+use futures::FutureExt;
+use image_resize_core::telemetry;
+use std::{any::Any, fmt, future::Future, panic::AssertUnwindSafe};
+use tracing::error;
+
+/// The error a panicking stage fails with.
+#[derive(Debug)]
+pub struct Panicked(String);
+
+impl fmt::Display for Panicked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The stage panicked: {}", self.0)
+    }
+}
+
+impl std::error::Error for Panicked {}
+
+fn message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+/// Runs `stage`, turning a panic into a [`Panicked`] error.
+pub async fn catch<T>(stage: impl Future<Output = azure_core::Result<T>>) -> azure_core::Result<T> {
+    match AssertUnwindSafe(stage).catch_unwind().await {
+        Ok(result) => result,
+        Err(payload) => {
+            let message = message(payload.as_ref());
+            error!("Stage panicked: {}", message);
+            telemetry::track_exception("StagePanicked", &message);
+            Err(Error::new(ErrorKind::Other, Panicked(message)))
+        }
+    }
+}
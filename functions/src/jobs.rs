@@ -0,0 +1,82 @@
+// functions/src/jobs.rs
+
+//! Updates of the job a message belongs to, see `core/src/job_status.rs`, so `GET /jobs/{id}`
+//! follows it from queued to done or failed, and through its chain of stages. Messages queued before jobs had ids have none to
+//! update, and failing to update one doesn't fail the stage.
+//!
+//! How a job ends is also counted in the daily usage of the tenant's key that uploaded it, see
+//! `core/src/key_usage.rs`.
+
+use azure_storage_blobs::prelude::BlobServiceClient;
+use image_resize_core::{
+    job_status::{self, JobState},
+    key_usage::{self, Counts},
+};
+use time::OffsetDateTime;
+use tracing::warn;
+
+use crate::{report::StageReport, ImageMessage};
+
+pub async fn update(image: &ImageMessage, state: JobState, outputs: &[String], error: Option<&str>) {
+    let Some(job_id) = &image.job_id else {
+        return;
+    };
+    // a message making deferred variants runs beside the job's chain, see `deadline.rs`
+    let chain = (!image.variants_only).then_some(image);
+    if let Err(e) = job_status::update_job(job_id, state, outputs, error, chain).await {
+        warn!("Failed to update job {} of {}: {:?}", job_id, image.filename, e);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Outcome {
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+/// Counts how the job of `image` ended against the key it was uploaded with.
+pub async fn count_outcome(image: &ImageMessage, outcome: Outcome) {
+    let Some(tenant) = &image.tenant else {
+        return;
+    };
+    let counts = match outcome {
+        Outcome::Completed => Counts { jobs_completed: 1, ..Default::default() },
+        Outcome::Failed => Counts { jobs_failed: 1, ..Default::default() },
+        Outcome::Cancelled => Counts { jobs_cancelled: 1, ..Default::default() },
+    };
+    let key = key_usage::tenant_key_id(tenant);
+    if let Err(e) = key_usage::add(&key_usage::day(OffsetDateTime::now_utc()), &key, &counts).await {
+        warn!("Failed to count the outcome of the job of {} for {}: {:?}", image.filename, key, e);
+    }
+}
+
+/// Adds the blob `stage` wrote last to the outputs of the job of `image`, if it asked for its
+/// renditions as they're stored.
+pub async fn add_latest_output(image: &ImageMessage, stage: &StageReport, service_client: &BlobServiceClient) {
+    let (Some(job_id), true) = (&image.job_id, image.incremental) else {
+        return;
+    };
+    let Some(output) = stage.outputs.last() else {
+        return;
+    };
+    let blob_client = service_client.container_client(&output.container).blob_client(&output.blob);
+    let Ok(url) = blob_client.url() else {
+        return;
+    };
+    if let Err(e) = job_status::add_output(job_id, url.as_str()).await {
+        warn!("Failed to add {} to job {}: {:?}", output.blob, job_id, e);
+    }
+}
+
+/// URLs of the blobs `stage` wrote.
+pub fn output_urls(stage: &StageReport, service_client: &BlobServiceClient) -> Vec<String> {
+    stage
+        .outputs
+        .iter()
+        .filter_map(|output| {
+            let blob_client = service_client.container_client(&output.container).blob_client(&output.blob);
+            blob_client.url().ok().map(|url| url.to_string())
+        })
+        .collect()
+}
@@ -1,106 +1,723 @@
 // functions/src/main.rs
 
-use azure_messaging_servicebus::service_bus::QueueClient;
-use azure_storage::StorageCredentials;
-use azure_storage_blobs::prelude::BlobServiceClient;
-use serde::{Deserialize, Serialize};
-use futures::StreamExt;
-use tracing::trace;
-use std::{env, io::Cursor};
+mod alert;
+mod analysis;
+mod animation;
+mod bench;
+mod budget;
+mod callback;
+mod cancel;
+mod capture;
+mod color;
+mod config_check;
+mod dead_letter;
+mod deadline;
+mod decode;
+mod dedup;
+mod detail;
+mod drain;
+mod enhance;
+mod error;
+mod expiry;
+mod isolate;
+mod jobs;
+mod normalize;
+mod operations;
+mod overlay;
+mod pages;
+mod pdf;
+mod plugin;
+mod probes;
+mod profiling;
+mod provenance;
+mod publish;
+mod quality;
+mod rasterize;
+mod report;
+mod resize;
+mod seen;
+mod shed;
+mod simulate;
+mod staging;
+mod temp;
+mod template;
+mod transformer;
+mod video;
 
-#[derive(Serialize, Deserialize, Debug)]
-struct ImageNode {
-    filename: String,
-    image_container: String,
-}
+use azure_storage_blobs::prelude::{BlobClient, BlobServiceClient, CPKInfo, Tags};
+use futures::StreamExt;
+use azure_core::request_options::Metadata;
+use image_resize_core::{
+    blob_tags, build_info, chaos, claim_check, clients, config, customer_keys, features, geo_read, job_status::JobState, logging,
+    message::{self, ImageMessage, Priority, Stage, SCHEMA_VERSION}, metrics, migrations, pipeline, queue::{LockedMessage, QueueReceiver, QueueSender}, storage, tables, telemetry, tiers, trace, warnings,
+};
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{sync::Semaphore, task::JoinSet};
+use time::OffsetDateTime;
+use tracing::{debug, error, info, warn, Instrument};
 
 #[tokio::main]
 async fn main() -> azure_core::Result<()> {
-    let service_bus_namespace = env::var("AZURE_SERVICE_BUS_NAMESPACE").expect("Please set AZURE_SERVICE_BUS_NAMESPACE env variable first!");
-    let queue_name = env::var("AZURE_QUEUE_NAME").expect("Please set AZURE_QUEUE_NAME env variable first!");
-    let policy_name = env::var("AZURE_POLICY_NAME").expect("Please set AZURE_POLICY_NAME env variable first!");
-    let policy_key = env::var("AZURE_POLICY_KEY").expect("Please set AZURE_POLICY_KEY env variable first!");
-    
-    let http_client = azure_core::new_http_client();
-
-    let client = QueueClient::new(
-        http_client, 
-        service_bus_namespace, 
-        queue_name, 
-        policy_name, 
-        policy_key
-    ).expect("Failed to create client");
-
-    let received_message = client
-        .receive_and_delete_message()
-        .await
-        .expect("Failed to receive message");
-
-    if received_message.is_empty() {
-        println!("No message received");
-        return Ok(())
-    }
-
-    println!("Received message: {:?}", received_message);
-
-    // grab the image from the message
-    match serde_json::from_str::<ImageNode>(&received_message) {
-        Ok(image) => {
-            println!("Deserialized image: {:?}", image);
-
-            // Azure Blob Storage credentials
-            let storage_account = env::var("AZURE_STORAGE_ACCOUNT").expect("Missing AZURE_STORAGE_ACCOUNT env var");
-            let storage_access_key = env::var("AZURE_STORAGE_ACCESS_KEY").expect("Missing AZURE_STORAGE_ACCESS_KEY env var");
-            let container_name = image.image_container;
-
-            let blob_name = &*image.filename; 
-
-            // create Azure Blob Storage client
-            let storage_credentials = StorageCredentials::access_key(storage_account.clone(), storage_access_key);
-            let service_client = BlobServiceClient::new(storage_account, storage_credentials);
-            let blob_client = service_client
-                .container_client(&container_name)
-                .blob_client(blob_name);
-
-            trace!("Requesting blob");
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("bench-encoders") {
+        bench::run(&args[1..]);
+        return Ok(());
+    }
+    if args.first().map(String::as_str) == Some("simulate") {
+        simulate::run(&args[1..]).await;
+        return Ok(());
+    }
+    if image_resize_core::config_check::requested(&args) {
+        config_check::run(&args).await;
+    }
+    logging::init();
+    telemetry::init("worker");
+    trace::init("worker");
+    config::get();
+    storage::kind();
+    customer_keys::keys();
+    chaos::faults();
+    migrations::run().await?;
+    let drain = drain::Drain::install();
+    probes::spawn(drain.clone());
+    temp::sweep();
+    let version = build_info();
+    info!(
+        "Starting {} {} ({}, built {}), formats {:?}, backends {:?}, {}",
+        version.name, version.version, version.git_sha, version.built_at, version.formats, version.backends, version.cpu
+    );
 
-            let mut bytes: Vec<u8> = Vec::new();
-            // stream a blob, 8KB at a time
-            let mut stream = blob_client.get().chunk_size(0x2000u64).into_stream();
-            while let Some(value) = stream.next().await {
-                let data = value?.data.collect().await?;
-                println!("received {:?} bytes", data.len());
-                bytes.extend(&data);
+    let result = if drain.is_draining() {
+        info!("Draining, not taking new messages");
+        drain::checkpoint(None).await;
+        Ok(())
+    } else {
+        consume(&drain).await
+    };
+
+    // the process exits right after, so push out whatever telemetry is still buffered
+    telemetry::flush().await;
+    trace::flush().await;
+    result
+}
+
+const DEFAULT_CONCURRENCY: usize = 4;
+/// How long one receive waits for a message before asking again, which also bounds how long a
+/// drain takes to be noticed while the queue is empty.
+const POLL_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_LOCK_RENEW_SECS: u64 = 20;
+/// Pause after a failed receive, so an unreachable queue isn't hammered.
+const RECEIVE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Messages processed at once, from `WORKER_CONCURRENCY`.
+fn worker_concurrency() -> usize {
+    env::var("WORKER_CONCURRENCY")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(DEFAULT_CONCURRENCY)
+}
+
+/// Messages locked ahead of a free slot, from `WORKER_PREFETCH`.
+fn worker_prefetch() -> usize {
+    env::var("WORKER_PREFETCH").ok().and_then(|v| v.parse().ok()).unwrap_or(0)
+}
+
+/// How often the lock of a message in flight is renewed, from `WORKER_LOCK_RENEW_SECS`; keep it
+/// well below the queue's lock duration.
+fn lock_renew_interval() -> Duration {
+    let secs = env::var("WORKER_LOCK_RENEW_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(DEFAULT_LOCK_RENEW_SECS);
+    Duration::from_secs(secs)
+}
+
+/// What every message handler shares.
+struct Worker {
+    client: QueueReceiver,
+    sender: QueueSender,
+    queue_name: String,
+    alert_sink: Box<dyn alert::AlertSink>,
+    error_rate: alert::ErrorRateMonitor,
+    seen: seen::SeenMessages,
+    shedder: Option<shed::LoadShedder>,
+    concurrency: usize,
+    /// Messages being handled, to tell when every slot is busy.
+    busy: AtomicUsize,
+}
+
+/// A message locked ahead of a free slot, started in order of priority and then of arrival.
+struct Prefetched {
+    priority: Priority,
+    arrival: u64,
+    message: LockedMessage,
+}
+
+/// Receives messages until the worker is drained, up to `WORKER_CONCURRENCY` of them in flight.
+/// Each is received under a peek-lock and only completed once its stage succeeded or was
+/// dead-lettered for failing permanently, see `error.rs`; one failing otherwise is abandoned so
+/// Service Bus delivers it again, dead-lettering it after the queue's max delivery count.
+///
+/// With `WORKER_PREFETCH` set, up to that many more messages are kept locked while every slot is
+/// busy, their locks renewed, and a freed slot takes the one of highest priority among them.
+async fn consume(drain: &drain::Drain) -> azure_core::Result<()> {
+    let client = clients::queue_client();
+    let queue_name = client.queue_name().to_string();
+    let concurrency = worker_concurrency();
+    let prefetch = worker_prefetch();
+    budget::init();
+    normalize::init();
+    let worker = Arc::new(Worker {
+        client,
+        sender: QueueSender::from_env(),
+        queue_name,
+        alert_sink: alert::sink_from_env(),
+        error_rate: alert::ErrorRateMonitor::from_env(),
+        seen: seen::SeenMessages::from_env(),
+        shedder: shed::LoadShedder::from_env(),
+        concurrency,
+        busy: AtomicUsize::new(0),
+    });
+
+    let slots = Arc::new(Semaphore::new(concurrency));
+    let mut in_flight = JoinSet::new();
+    let mut last_message = None;
+    let mut prefetched: Vec<Prefetched> = Vec::new();
+    let mut arrivals = 0;
+    let mut renew_prefetched = tokio::time::interval(lock_renew_interval());
+    info!(
+        "Consuming {} with up to {} messages in flight and {} prefetched",
+        worker.queue_name, concurrency, prefetch
+    );
+
+    while !drain.is_draining() {
+        let slot = tokio::select! {
+            slot = slots.clone().acquire_owned() => slot.expect("The semaphore is never closed"),
+            _ = renew_prefetched.tick(), if !prefetched.is_empty() => {
+                for prefetched in &prefetched {
+                    if let Err(e) = prefetched.message.renew_message_lock().await {
+                        warn!("Failed to renew the lock of a prefetched message: {:?}", e);
+                    }
+                }
+                continue;
+            }
+        };
+        while let Some(finished) = in_flight.try_join_next() {
+            match finished {
+                Ok(Some(message)) => last_message = Some(message),
+                Ok(None) => {}
+                Err(e) => error!("Message handler failed: {:?}", e),
             }
+        }
 
-            // load the image from the bytes
-            let img = image::load_from_memory(&bytes).expect("Failed to load image");
-            // resize the image
-            let resized_img = img.resize(100, 100, image::imageops::FilterType::Triangle);
-            // write the resized image to the buffer
-            let mut resized_bytes: Vec<u8> = Vec::new();
-            resized_img.write_to(&mut Cursor::new(&mut resized_bytes), image::ImageFormat::Jpeg).expect("Failed to write image");
+        // tops up the prefetched messages, only waiting for one while there are none
+        while prefetched.len() <= prefetch {
+            let timeout = if prefetched.is_empty() { POLL_TIMEOUT } else { Duration::ZERO };
+            let received = telemetry::dependency(
+                "Azure Service Bus",
+                &worker.queue_name,
+                "peek_lock_message",
+                worker.client.peek_lock(timeout),
+            )
+            .await;
+            match received {
+                Ok(Some(message)) => {
+                    arrivals += 1;
+                    prefetched.push(Prefetched {
+                        priority: message::priority(&message.body()),
+                        arrival: arrivals,
+                        message,
+                    });
+                }
+                // the poll timed out on an empty queue
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Failed to receive message: {:?}", e);
+                    if prefetched.is_empty() {
+                        tokio::time::sleep(RECEIVE_RETRY_DELAY).await;
+                    }
+                    break;
+                }
+            }
+        }
+        if drain.is_draining() {
+            break;
+        }
+        let Some(next) = prefetched.iter().enumerate().min_by_key(|(_, p)| (p.priority, p.arrival)).map(|(i, _)| i) else {
+            continue;
+        };
+        let message = prefetched.swap_remove(next).message;
 
-            // change the filename to include the word "resized"
-            let new_blob_name = format!("resized_{}", blob_name);
+        let worker = worker.clone();
+        in_flight.spawn(async move {
+            let _slot = slot;
+            worker.handle(message).await
+        });
+    }
 
-            let blob_client = service_client
-                .container_client(&container_name)
-                .blob_client(&new_blob_name);
+    // received after the drain began or never started, so they go straight back for another worker
+    for prefetched in prefetched {
+        if let Err(e) = prefetched.message.unlock_message().await {
+            warn!("Failed to abandon message: {:?}", e);
+        }
+    }
+    info!("Draining, finishing {} messages in flight", in_flight.len());
+    while let Some(finished) = in_flight.join_next().await {
+        match finished {
+            Ok(Some(message)) => last_message = Some(message),
+            Ok(None) => {}
+            Err(e) => error!("Message handler failed: {:?}", e),
+        }
+    }
+    drain::checkpoint(last_message.as_deref()).await;
+    Ok(())
+}
+
+impl Worker {
+    /// Processes a message, keeping its lock renewed meanwhile, and completes or abandons it.
+    /// Returns the message if it was completed.
+    async fn handle(&self, message: LockedMessage) -> Option<String> {
+        let body = message.body();
+        let broker_properties = message.broker_properties();
+        let delivery = broker_properties.as_ref().map(|p| p.delivery_count).unwrap_or(1);
+        info!("Received message (delivery {}): {:?}", delivery, body);
+        if let Some(enqueued) = broker_properties.and_then(|p| p.enqueued_time_utc) {
+            let waited = (OffsetDateTime::now_utc() - enqueued).try_into().unwrap_or_default();
+            let priority = message::priority(&body).name();
+            metrics::observe("queue_latency_seconds", &[("queue", &self.queue_name), ("priority", priority)], waited);
+        }
 
-            blob_client.put_block_blob(resized_bytes)
-                .content_type("image/jpeg")
-                .await
-                .expect("Failed to upload blob");
+        let renew = async {
+            let interval = lock_renew_interval();
+            loop {
+                tokio::time::sleep(interval).await;
+                if let Err(e) = message.renew_message_lock().await {
+                    warn!("Failed to renew message lock: {:?}", e);
+                }
+            }
+        };
+        self.busy.fetch_add(1, Ordering::Relaxed);
+        let result = tokio::select! {
+            result = self.process(&body) => result,
+            _ = renew => unreachable!("lock renewal never ends"),
+        };
+        self.busy.fetch_sub(1, Ordering::Relaxed);
 
-            println!("Resized image uploaded successfully");
+        match result {
+            Ok(()) => {
+                let complete = message.delete_message();
+                if let Err(e) = telemetry::dependency("Azure Service Bus", &self.queue_name, "delete_message", complete).await {
+                    // the lock was lost, so the message will be delivered again
+                    error!("Failed to complete message: {:?}", e);
+                    metrics::increment("messages_total", &[("queue", &self.queue_name), ("outcome", "lock_lost")]);
+                    return None;
+                }
+                metrics::increment("messages_total", &[("queue", &self.queue_name), ("outcome", "completed")]);
+                claim_check::release(&body).await;
+                Some(body)
+            }
+            Err(e) => {
+                warn!("Abandoning message after delivery {}: {:?}", delivery, e);
+                metrics::increment("messages_total", &[("queue", &self.queue_name), ("outcome", "abandoned")]);
+                if let Err(e) = message.unlock_message().await {
+                    warn!("Failed to abandon message: {:?}", e);
+                }
+                None
+            }
+        }
+    }
 
-        },
-        Err(e) => {
-            println!("Failed to deserialize image: {:?}", e);
-            return Ok(())
+    /// Runs the stage a message asks for and enqueues the next one. `Ok` settles the message:
+    /// besides success, that covers messages that can never succeed and jobs cancelled or expired.
+    async fn process(&self, received_message: &str) -> azure_core::Result<()> {
+        // a message from a newer API goes back on the queue for a worker that can read it
+        if let Some(version) = message::schema_version(received_message).filter(|v| *v > SCHEMA_VERSION) {
+            warn!("Leaving a version {} message to a newer worker, this one reads {}", version, SCHEMA_VERSION);
+            telemetry::track_event("UnsupportedMessageVersion", &[("version", version.to_string())]);
+            return Err(azure_core::Error::message(
+                azure_core::error::ErrorKind::DataConversion,
+                format!("Unsupported message schema version {}", version),
+            ));
         }
-    };
 
+        // a message too large for the queue was stored as a blob, see `core/src/claim_check.rs`
+        let received_message = match claim_check::resolve(received_message).await {
+            Ok(message) => message,
+            Err(e) if tables::is_not_found(&e) => {
+                error!("The claim check of a message is gone: {:?}", e);
+                telemetry::track_exception("InvalidMessage", &e.to_string());
+                let alert = format!("Dropped a message whose claim check is gone: {}", received_message);
+                self.alert_sink.send(&alert).await;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let received_message = received_message.as_ref();
+
+        // grab the image from the message
+        let mut image = match serde_json::from_str::<ImageMessage>(received_message) {
+            Ok(image) => image,
+            Err(e) => {
+                error!("Failed to deserialize image: {:?}", e);
+                // redelivering it wouldn't help, so it is removed like a dead letter
+                telemetry::track_exception("InvalidMessage", &e.to_string());
+                let alert = format!("Dropped undeliverable message: {} ({})", received_message, e);
+                self.alert_sink.send(&alert).await;
+                return Ok(());
+            }
+        };
+        info!("Deserialized image: {:?}", image);
+
+        // queued before its tenant's tier changed, or by a client around the API, see `core/src/tiers.rs`
+        for change in tiers::apply(&mut image) {
+            warn!("Held {} to tier {}: {}", image.filename, image.tier.as_deref().unwrap_or_default(), change);
+            telemetry::track_event("TierApplied", &[("filename", image.filename.clone()), ("change", change)]);
+        }
+
+        // an expensive job waits while cheap ones can use the slot, see `shed.rs`
+        if let Some(shedder) = &self.shedder {
+            if shedder.should_defer(&image, self.busy.load(Ordering::Relaxed) >= self.concurrency) {
+                return shedder.defer(image, &self.sender).await;
+            }
+        }
+
+        // a redelivery or double submission of a message in flight or just processed
+        let Some(key) = self.seen.claim(&image) else {
+            info!("Skipping duplicate {:?} of {}", image.stage, image.filename);
+            telemetry::track_event("DuplicateMessage", &[("filename", image.filename.clone())]);
+            return Ok(());
+        };
+        // continues the trace of whatever queued the message, see `core/src/trace.rs`
+        let span = trace::span(&format!("process {}", image.stage.name()), image.traceparent.as_deref());
+        let result = self.run(image).instrument(span).await;
+        self.seen.finish(key, result.is_ok());
+        result
+    }
+
+    async fn run(&self, image: ImageMessage) -> azure_core::Result<()> {
+        // of the failover account if the original was written there
+        let service_client = clients::blob_service_client(image.storage);
+
+        if cancel::is_cancelled(&image).await {
+            info!("The job of {} was cancelled, abandoning {:?}", image.filename, image.stage);
+            telemetry::track_event("JobCancelled", &[("filename", image.filename.clone())]);
+            jobs::update(&image, JobState::Failed, &[], Some("Cancelled")).await;
+            jobs::count_outcome(&image, jobs::Outcome::Cancelled).await;
+            callback::notify(&image, callback::Status::Cancelled, &[], None, &service_client).await;
+            return Ok(());
+        }
+        if expiry::is_stale(&image) {
+            info!("The job of {} was queued at {:?}, skipping it as stale", image.filename, image.queued_at);
+            telemetry::track_event("JobExpired", &[("filename", image.filename.clone())]);
+            expiry::record(&image).await;
+            jobs::update(&image, JobState::Failed, &[], Some("Expired before it was processed")).await;
+            jobs::count_outcome(&image, jobs::Outcome::Failed).await;
+            callback::notify(&image, callback::Status::Expired, &[], None, &service_client).await;
+            return Ok(());
+        }
+        jobs::update(&image, JobState::Processing, &[], None).await;
+
+        let started = Instant::now();
+        let mut stage_report = report::StageReport::new(&image.stage);
+        // a panic fails only this stage, see `isolate.rs`; transient failures are retried in place
+        let retries = error::stage_retries();
+        let mut attempts = 1;
+        let result = loop {
+            let result = isolate::catch(run_stage(&image, &service_client, &mut stage_report)).await;
+            match &result {
+                Err(e) if attempts <= retries && !error::is_permanent(e) && !cancel::is_cancellation(e) => {
+                    let delay = error::retry_delay(attempts);
+                    warn!("{:?} of {} failed, retrying in {:?}: {}", image.stage, image.filename, delay, e);
+                    tokio::time::sleep(delay).await;
+                    stage_report = report::StageReport::new(&image.stage);
+                    attempts += 1;
+                }
+                _ => break result,
+            }
+        };
+        // a cancellation midway isn't a failure, the rest of the job is just dropped
+        let cancelled = result.as_ref().err().is_some_and(cancel::is_cancellation);
+        let result = if cancelled {
+            stage_report.warn(warnings::JOB_CANCELLED, "The job was cancelled, the stage's outputs were abandoned");
+            Ok(())
+        } else {
+            result
+        };
+        stage_report.finish(started.elapsed(), result.as_ref().err());
+        let outcome = match &result {
+            Ok(()) if cancelled => "cancelled",
+            Ok(()) => "succeeded",
+            Err(_) => "failed",
+        };
+        metrics::observe("stage_duration_seconds", &[("stage", image.stage.name()), ("outcome", outcome)], started.elapsed());
+        if let Err(e) = &result {
+            let permanent = if error::is_permanent(e) { "true" } else { "false" };
+            metrics::increment("stage_failures_total", &[("stage", image.stage.name()), ("permanent", permanent)]);
+        }
+        if result.is_ok() && !cancelled {
+            stage_report.track_metrics();
+        }
+        let outputs = jobs::output_urls(&stage_report, &service_client);
+        let deferred = stage_report.deferred.clone();
+        report::append(&image, stage_report, &service_client).await;
+
+        telemetry::track_request(
+            &format!("process {:?}", image.stage),
+            &image.filename,
+            started.elapsed(),
+            if result.is_ok() { "200" } else { "500" },
+            result.is_ok(),
+        );
+        if let Err(e) = &result {
+            telemetry::track_exception("StageFailed", &e.to_string());
+        }
+
+        if let Some(alert) = self.error_rate.record(result.is_ok()) {
+            self.alert_sink.send(&alert).await;
+        }
+        if let Err(e) = &result {
+            // it would only fail again, so it's set aside instead of redelivered
+            if error::is_permanent(e) {
+                jobs::update(&image, JobState::Failed, &outputs, Some(&e.to_string())).await;
+                dead_letter::record(&image, e, attempts, &service_client).await?;
+                let alert = format!("Dead-lettered {:?} of {}: {}", image.stage, image.filename, e);
+                self.alert_sink.send(&alert).await;
+                jobs::count_outcome(&image, jobs::Outcome::Failed).await;
+                callback::notify(&image, callback::Status::Failed, &outputs, Some(&e.to_string()), &service_client).await;
+                return Ok(());
+            }
+            // the message is abandoned and redelivered, so the job is still in progress
+            jobs::update(&image, JobState::Processing, &outputs, Some(&e.to_string())).await;
+        }
+        result?;
+
+        if cancelled || (!image.then.is_empty() && cancel::is_cancelled(&image).await) {
+            info!("The job of {} was cancelled, dropping {:?}", image.filename, image.then);
+            telemetry::track_event("JobCancelled", &[("filename", image.filename.clone())]);
+            jobs::update(&image, JobState::Failed, &outputs, Some("Cancelled")).await;
+            jobs::count_outcome(&image, jobs::Outcome::Cancelled).await;
+            callback::notify(&image, callback::Status::Cancelled, &outputs, None, &service_client).await;
+            return Ok(());
+        }
+        // renditions the stage ran out of time for are made by a message of their own, see `deadline.rs`
+        deadline::record_renditions(&image, &deferred).await;
+        if image.then.is_empty() {
+            jobs::update(&image, JobState::Done, &outputs, None).await;
+        } else {
+            // the job records the next stage as queued
+            let mut next = image.clone();
+            advance(&mut next);
+            jobs::update(&next, JobState::Processing, &outputs, None).await;
+        }
+        if image.then.is_empty() {
+            // a message making deferred variants finishes a job already counted
+            if !image.variants_only {
+                jobs::count_outcome(&image, jobs::Outcome::Completed).await;
+            }
+            let status = if deferred.is_empty() { callback::Status::Done } else { callback::Status::PartiallyComplete };
+            callback::notify(&image, status, &outputs, None, &service_client).await;
+        }
+        if !deferred.is_empty() {
+            deadline::requeue(&image, deferred, &self.sender).await?;
+        }
+        enqueue_next_stage(image, &self.sender).await
+    }
+}
+
+/// Runs the stage the image's message asks for.
+async fn run_stage(
+    image: &ImageMessage,
+    service_client: &BlobServiceClient,
+    stage_report: &mut report::StageReport,
+) -> azure_core::Result<()> {
+    match &image.stage {
+        Stage::Resize => resize::resize_image(image, service_client, stage_report).await,
+        Stage::Publish { container } => publish::publish_rendition(image, container, service_client, stage_report).await,
+        Stage::Render { template } if features::is_enabled(features::RENDER, image.tenant.as_deref()).await => {
+            template::render_template(image, template, service_client, stage_report).await
+        }
+        Stage::Render { template } => {
+            stage_report.warn(
+                warnings::STAGE_SKIPPED,
+                format!("Rendering is disabled, template {} was skipped", template),
+            );
+            Ok(())
+        }
+        Stage::Pages { first, last } if features::is_enabled(features::PDF_PAGES, image.tenant.as_deref()).await => {
+            pages::render_pages(image, *first, *last, service_client, stage_report).await
+        }
+        Stage::Pages { .. } => {
+            stage_report.warn(warnings::STAGE_SKIPPED, "PDF rendering is disabled, pages were skipped");
+            Ok(())
+        }
+    }
+}
+
+fn build_info() -> build_info::BuildInfo {
+    let mut backends = vec!["azure_blob", "azure_service_bus"];
+    if telemetry::enabled() {
+        backends.push("application_insights");
+    }
+    if trace::enabled() {
+        backends.push("otlp");
+    }
+    if std::env::var("ALERT_WEBHOOK_URL").is_ok() {
+        backends.push("alert_webhook");
+    }
+    if cfg!(feature = "mozjpeg") {
+        backends.push("mozjpeg");
+    }
+    build_info::build_info(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), backends)
+}
+
+/// Metadata stamped on every blob the worker writes, so an output can be traced to the build that
+/// made it and listed per tenant in the API's feed. Upload tags and custom metadata are stored the
+/// way the API stores them on originals, see `api/src/metadata.rs`.
+fn output_metadata(image: &ImageMessage) -> Metadata {
+    let version = build_info();
+    let mut metadata = Metadata::new();
+    metadata.insert("worker_version", version.version);
+    metadata.insert("worker_git_sha", version.git_sha);
+    if let Some(tenant) = &image.tenant {
+        metadata.insert("tenant", tenant.clone());
+    }
+    if !image.tags.is_empty() {
+        metadata.insert("tags", image.tags.join(","));
+    }
+    for (key, value) in &image.metadata {
+        metadata.insert(format!("meta_{}", key), value.clone());
+    }
+    metadata
+}
+
+/// Metadata for a rendition of `preset`, adding where it came from, the pipeline version it was made
+/// with, the hash of its content and the parameters needed to make it again.
+fn rendition_metadata(image: &ImageMessage, preset: &str, source: &provenance::Source, content_hash: &str) -> Metadata {
+    let mut metadata = output_metadata(image);
+    metadata.insert(pipeline::SOURCE_KEY, pipeline::metadata_name(&image.filename));
+    metadata.insert(pipeline::SOURCE_ETAG_KEY, source.etag.to_string());
+    metadata.insert(pipeline::PRESET_KEY, preset.to_string());
+    metadata.insert(pipeline::VERSION_KEY, pipeline::version(preset, source.definition));
+    metadata.insert(pipeline::CONTENT_HASH_KEY, content_hash.to_string());
+    metadata.insert(pipeline::WIDTH_KEY, image.width.to_string());
+    metadata.insert(pipeline::HEIGHT_KEY, image.height.to_string());
+    metadata.insert(pipeline::ENHANCE_KEY, image.auto_enhance.to_string());
+    if let Some(target_size) = image.target_size {
+        metadata.insert(pipeline::TARGET_SIZE_KEY, target_size.to_string());
+    }
+    if let Some(resize) = &image.resize {
+        metadata.insert(pipeline::RESIZE_KEY, serde_json::to_string(resize).expect("Failed to serialize resize spec"));
+    }
+    if let Some(fit) = &image.fit {
+        metadata.insert(pipeline::FIT_KEY, serde_json::to_string(fit).expect("Failed to serialize fit"));
+    }
+    if let Some(filter) = &image.filter {
+        metadata.insert(pipeline::FILTER_KEY, serde_json::to_string(filter).expect("Failed to serialize filter"));
+    }
+    if let Some(output_format) = &image.output_format {
+        metadata.insert(
+            pipeline::OUTPUT_FORMAT_KEY,
+            serde_json::to_string(output_format).expect("Failed to serialize output format"),
+        );
+    }
+    if !image.variants.is_empty() {
+        metadata.insert(pipeline::VARIANTS_KEY, serde_json::to_string(&image.variants).expect("Failed to serialize variants"));
+    }
+    if let Some(crop) = &image.crop {
+        metadata.insert(pipeline::CROP_KEY, serde_json::to_string(crop).expect("Failed to serialize crop"));
+    }
+    if let Some(focal_point) = &image.focal_point {
+        metadata.insert(pipeline::FOCAL_POINT_KEY, focal_point.to_string());
+    }
+    if !image.operations.is_empty() {
+        metadata.insert(pipeline::OPERATIONS_KEY, serde_json::to_string(&image.operations).expect("Failed to serialize operations"));
+    }
+    metadata
+}
+
+/// Index tags for a blob the worker writes, `preset` saying which kind of output it is.
+fn output_tags(image: &ImageMessage, preset: &str) -> Tags {
+    blob_tags::tags(image.tenant.as_deref(), preset, blob_tags::READY)
+}
+
+/// Downloads a whole blob into memory, streaming it 8KB at a time.
+async fn read_blob(blob_client: &BlobClient) -> azure_core::Result<Vec<u8>> {
+    read_blob_with_etag(blob_client, None).await.map(|(bytes, _)| bytes)
+}
+
+/// Downloads the image's original, with its tenant's encryption key if it has one, or from the
+/// `STORAGE_BACKEND` it was stored in, see `core/src/storage.rs`.
+async fn read_original(image: &ImageMessage, blob_client: &BlobClient) -> azure_core::Result<(Vec<u8>, String)> {
+    if storage::kind() != storage::BackendKind::Azure {
+        let bytes = storage::from_env(blob_client.container_client()).get(&image.filename).await?;
+        // the other backends have no etags, so the content's hash stands in for one
+        let etag = format!("\"{}\"", pipeline::content_hash(&bytes));
+        return Ok((bytes, etag));
+    }
+    read_blob_with_etag(blob_client, customer_keys::customer_key(image.tenant.as_deref())).await
+}
+
+/// Reads a blob along with the etag it had when the download started, from the secondary endpoint
+/// if the primary fails.
+async fn read_blob_with_etag(blob_client: &BlobClient, customer_key: Option<CPKInfo>) -> azure_core::Result<(Vec<u8>, String)> {
+    let download = geo_read::read(blob_client, |blob_client| {
+        let customer_key = customer_key.clone();
+        async move {
+            let mut bytes: Vec<u8> = Vec::new();
+            let mut etag = String::new();
+            let mut get = blob_client.get().chunk_size(0x2000u64);
+            if let Some(customer_key) = customer_key {
+                get = get.encryption_key(customer_key);
+            }
+            let mut stream = get.into_stream();
+            while let Some(value) = stream.next().await {
+                let response = value?;
+                if etag.is_empty() {
+                    etag = response.blob.properties.etag.to_string();
+                }
+                let data = response.data.collect().await?;
+                debug!("received {:?} bytes", data.len());
+                bytes.extend(&data);
+            }
+            chaos::truncate(&mut bytes, "read");
+            Ok((bytes, etag))
+        }
+    });
+    telemetry::dependency("Azure blob", blob_client.container_client().container_name(), "get", download).await
+}
+
+/// Moves the image's message on to the first of its remaining `then` stages.
+fn advance(image: &mut ImageMessage) {
+    let next = image.then.remove(0);
+    let finished = std::mem::replace(&mut image.stage, next);
+    image.completed.push(finished);
+    image.deferrals = 0;
+}
+
+/// Sends the first of the remaining `then` stages back to the queue, carrying the rest of the chain along.
+async fn enqueue_next_stage(mut image: ImageMessage, sender: &QueueSender) -> azure_core::Result<()> {
+    if image.then.is_empty() {
+        info!("Chain complete: {:?} then {:?}", image.completed, image.stage);
+        return Ok(());
+    }
+
+    advance(&mut image);
+    // the next stage's span follows on from this one's
+    image.traceparent = trace::traceparent().or(image.traceparent);
+
+    let sender = sender.for_image(&image);
+    let message = serde_json::to_string(&image).expect("Failed to serialize image");
+    telemetry::dependency("Azure Service Bus", sender.queue_name(), "send_message", sender.send(&image, &message)).await?;
+
+    info!("Enqueued next stage {:?} after {:?}", image.stage, image.completed);
     Ok(())
-}
\ No newline at end of file
+}
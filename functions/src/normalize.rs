@@ -0,0 +1,168 @@
+// functions/src/normalize.rs
+
+//! Normalization of originals, with the `normalize` feature on. Before the first rendition is made
+//! from it, a still image original is rewritten upright, in sRGB without a profile, and in
+//! `NORMALIZE_FORMAT` (default `jpeg`, at `NORMALIZE_JPEG_QUALITY`, default 95), or PNG if it has
+//! transparency, so every later stage reads the same kind of source. The bytes as uploaded are
+//! first copied under the same name to `NORMALIZE_ARCHIVE_CONTAINER` (default `originals-archive`).
+//!
+//! An original that is already upright, in sRGB and in its canonical format is left alone, which
+//! makes this run once per upload. So are videos, PDFs, SVGs, GIFs routed to the `animation`
+//! pipeline, the originals of tenants with their own encryption key and those kept outside Azure,
+//! see `core/src/storage.rs`. The original is only replaced while it still has the etag it was
+//! read with.
+
+use azure_core::request_options::{IfMatchCondition, Metadata};
+use azure_storage_blobs::prelude::BlobServiceClient;
+use image::{metadata::Orientation, DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+use image_resize_core::{
+    blob_tags, config, customer_keys, features, output_format::OutputFormat, pdf, routing::Pipeline, storage, svg, telemetry,
+    video::VideoFormat, warnings,
+};
+use std::{env, io::Cursor, sync::OnceLock};
+use tracing::info;
+
+use crate::{
+    budget,
+    color::{self, ColorSpace},
+    decode,
+    error::StageError,
+    output_tags,
+    quality::{self, JpegOptions},
+    report::{BlobReport, StageReport},
+    ImageMessage,
+};
+
+const DEFAULT_ARCHIVE_CONTAINER: &str = "originals-archive";
+const DEFAULT_JPEG_QUALITY: u8 = 95;
+/// Metadata key of a normalized original, holding the format it was uploaded in.
+const NORMALIZED_FROM_KEY: &str = "normalized_from";
+
+fn canonical_format() -> OutputFormat {
+    static FORMAT: OnceLock<OutputFormat> = OnceLock::new();
+    *FORMAT.get_or_init(|| {
+        env::var("NORMALIZE_FORMAT")
+            .ok()
+            .map(|v| v.parse().unwrap_or_else(|e| panic!("Invalid NORMALIZE_FORMAT: {}", e)))
+            .unwrap_or(OutputFormat::Jpeg)
+    })
+}
+
+/// Reads `NORMALIZE_FORMAT`, so a bad value stops the worker at startup.
+pub fn init() {
+    canonical_format();
+}
+
+fn jpeg_quality() -> u8 {
+    env::var("NORMALIZE_JPEG_QUALITY")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_JPEG_QUALITY)
+        .clamp(1, 100)
+}
+
+fn archive_container() -> String {
+    env::var("NORMALIZE_ARCHIVE_CONTAINER").unwrap_or_else(|_| DEFAULT_ARCHIVE_CONTAINER.to_string())
+}
+
+/// The format to rewrite `bytes` in, `None` if it is no still image or is already normalized.
+fn target_format(bytes: &[u8]) -> Option<OutputFormat> {
+    if VideoFormat::sniff(bytes).is_some() || pdf::is_pdf(bytes) || svg::is_svg(bytes) {
+        return None;
+    }
+    let format = image::guess_format(bytes).ok()?;
+    if format == ImageFormat::Gif && config::get().pipeline_for("gif") == Pipeline::Animation {
+        return None;
+    }
+    let mut decoder = ImageReader::new(Cursor::new(bytes)).with_guessed_format().ok()?.into_decoder().ok()?;
+    let target = if decoder.color_type().has_alpha() { OutputFormat::Png } else { canonical_format() };
+    let upright = decoder.orientation().is_ok_and(|orientation| orientation == Orientation::NoTransforms);
+    let untagged = decoder.icc_profile().ok().flatten().is_none();
+    (format != target.image_format() || !upright || !untagged).then_some(target)
+}
+
+fn encode(img: &DynamicImage, format: OutputFormat) -> Result<Vec<u8>, StageError> {
+    if format == OutputFormat::Jpeg {
+        return quality::encode_jpeg(&img.to_rgb8(), jpeg_quality(), &JpegOptions::default());
+    }
+    let mut bytes = Vec::new();
+    img.write_to(&mut Cursor::new(&mut bytes), format.image_format())
+        .map_err(|e| StageError::Encode(e.to_string()))?;
+    Ok(bytes)
+}
+
+/// Normalizes the image's original, read as `bytes` with `etag`, and returns what the stage should
+/// go on with: the normalized bytes and their etag, or the original's when it was left alone.
+pub async fn normalize(
+    image: &ImageMessage,
+    bytes: Vec<u8>,
+    etag: String,
+    service_client: &BlobServiceClient,
+    report: &mut StageReport,
+) -> azure_core::Result<(Vec<u8>, String)> {
+    let tenant = image.tenant.as_deref();
+    if !features::is_enabled(features::NORMALIZE, tenant).await
+        || customer_keys::customer_key(tenant).is_some()
+        || storage::kind() != storage::BackendKind::Azure
+    {
+        return Ok((bytes, etag));
+    }
+    let Some(format) = target_format(&bytes) else {
+        return Ok((bytes, etag));
+    };
+
+    let source_format = decode::source_format(&bytes);
+    let img = decode::load(&bytes).map_err(|source| StageError::Decode { what: "the original".to_string(), source })?;
+    let profile = color::source_profile(&bytes).unwrap_or_else(|e| {
+        report.warn(warnings::ICC_PROFILE_DROPPED, format!("The original's color profile was dropped: {}", e));
+        None
+    });
+    let img = color::convert_image(&img, profile.as_ref(), ColorSpace::Srgb);
+    let normalized = {
+        let _permit = budget::acquire(format).await;
+        encode(&img, format)?
+    };
+
+    // the rewritten original keeps what was stored with it
+    let container_name = &image.image_container;
+    let blob_client = service_client.container_client(container_name).blob_client(&*image.filename);
+    let properties = blob_client.get_properties().await?.blob;
+    let tags = blob_client.get_tags().await?.tags;
+    let mut metadata = Metadata::new();
+    for (key, value) in properties.metadata.unwrap_or_default() {
+        metadata.insert(key, value);
+    }
+
+    let archive_container = archive_container();
+    let archived_size = bytes.len() as u64;
+    let archive = service_client
+        .container_client(&archive_container)
+        .blob_client(&*image.filename)
+        .put_block_blob(bytes)
+        .content_type(properties.properties.content_type)
+        .metadata(metadata.clone())
+        .tags(output_tags(image, blob_tags::ARCHIVED))
+        .into_future();
+    telemetry::dependency("Azure blob", &archive_container, "put_block_blob", archive).await?;
+
+    metadata.insert(NORMALIZED_FROM_KEY, source_format.clone());
+    let rewrite = blob_client
+        .put_block_blob(normalized.clone())
+        .content_type(format.content_type())
+        .metadata(metadata)
+        .tags(tags)
+        .if_match(IfMatchCondition::Match(etag))
+        .into_future();
+    let etag = telemetry::dependency("Azure blob", container_name, "put_block_blob", rewrite).await?.etag;
+
+    info!("Normalized {} from {} to {:?}, archived the upload in {}", image.filename, source_format, format, archive_container);
+    telemetry::track_event("OriginalNormalized", &[("filename", image.filename.clone()), ("from", source_format)]);
+    report.outputs.push(BlobReport {
+        container: archive_container,
+        blob: image.filename.clone(),
+        bytes: Some(archived_size),
+        sha256: image.content_hash.clone(),
+        ..Default::default()
+    });
+    Ok((normalized, etag))
+}
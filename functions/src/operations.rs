@@ -0,0 +1,84 @@
+// functions/src/operations.rs
+
+//! Applies the upload's list of operations, see `core/src/operations.rs`, to the source in order,
+//! after cropping and enhancing and before plugins and scaling. An operation that doesn't fit the
+//! image as the ones before left it, a crop outside it or a resize past `MAX_IMAGE_*`, is skipped
+//! with an `operation_skipped` warning; a watermark blob that can't be read fails the stage.
+
+use azure_storage_blobs::prelude::ContainerClient;
+use image::DynamicImage;
+use image_resize_core::{
+    image_checks::ImageLimits,
+    operations::Operation,
+    resize_spec::Filter,
+    warnings,
+};
+use tracing::info;
+
+use crate::{error::StageError, overlay, read_blob, report::StageReport, resize};
+
+/// Runs `operations` over `img`, resizing with `filter`.
+pub async fn apply(
+    mut img: DynamicImage,
+    operations: &[Operation],
+    filter: Filter,
+    container_client: &ContainerClient,
+    report: &mut StageReport,
+) -> azure_core::Result<DynamicImage> {
+    for (index, operation) in operations.iter().enumerate() {
+        info!("Applying operation #{}: {:?}", index + 1, operation);
+        img = match operation {
+            Operation::Crop { x, y, w, h } => {
+                let right = x.saturating_add(*w).min(img.width());
+                let bottom = y.saturating_add(*h).min(img.height());
+                if *x >= right || *y >= bottom {
+                    skip(report, index, format!("the crop lies outside the {}x{} image", img.width(), img.height()));
+                    continue;
+                }
+                img.crop_imm(*x, *y, right - x, bottom - y)
+            }
+            Operation::Rotate { deg } => match deg.rem_euclid(360) {
+                90 => img.rotate90(),
+                180 => img.rotate180(),
+                270 => img.rotate270(),
+                _ => img,
+            },
+            Operation::Grayscale => img.grayscale(),
+            Operation::Resize { w, h } => {
+                let (width, height) = fitted(img.width(), img.height(), *w, *h);
+                if let Err(e) = ImageLimits::from_env().check("The resized image", width, height) {
+                    skip(report, index, e);
+                    continue;
+                }
+                resize::resize_exact(&img, width, height, filter.filter_type())
+            }
+            Operation::Watermark { blob, pos, scale, opacity } => {
+                let watermark_bytes = read_blob(&container_client.blob_client(blob)).await?;
+                let watermark = image::load_from_memory(&watermark_bytes).map_err(|source| StageError::Decode {
+                    what: format!("watermark {}", blob),
+                    source,
+                })?;
+                let mut canvas = img.to_rgba8();
+                overlay::apply_watermark(&mut canvas, &watermark, *pos, *scale, *opacity);
+                DynamicImage::ImageRgba8(canvas)
+            }
+        };
+    }
+    Ok(img)
+}
+
+/// Size of a `width` x `height` image scaled to `w` wide or `h` high, or to fit within both.
+fn fitted(width: u32, height: u32, w: Option<u32>, h: Option<u32>) -> (u32, u32) {
+    let ratio = match (w, h) {
+        (Some(w), Some(h)) => f64::min(w as f64 / width as f64, h as f64 / height as f64),
+        (Some(w), None) => w as f64 / width as f64,
+        (None, Some(h)) => h as f64 / height as f64,
+        (None, None) => 1.0,
+    };
+    let scale = |side: u32| ((side as f64 * ratio).round() as u32).max(1);
+    (scale(width), scale(height))
+}
+
+fn skip(report: &mut StageReport, index: usize, reason: String) {
+    report.warn(warnings::OPERATION_SKIPPED, format!("Operation #{} was skipped: {}", index + 1, reason));
+}
@@ -0,0 +1,65 @@
+// functions/src/overlay.rs
+
+use ab_glyph::{Font, FontVec, PxScale, ScaleFont};
+use image::{imageops, DynamicImage, Rgba, RgbaImage};
+pub use image_resize_core::operations::Position;
+
+use crate::resize;
+
+/// Draws `watermark` onto `canvas`, scaled to `scale` of the canvas width and faded to `opacity`.
+pub fn apply_watermark(canvas: &mut RgbaImage, watermark: &DynamicImage, position: Position, scale: f32, opacity: f32) {
+    let target_width = ((canvas.width() as f32 * scale).round() as u32).max(1);
+    let target_height = ((watermark.height() as f32 * target_width as f32 / watermark.width().max(1) as f32).round() as u32).max(1);
+
+    let mut mark = resize::resize_exact(watermark, target_width, target_height, imageops::FilterType::Triangle).to_rgba8();
+    let opacity = opacity.clamp(0.0, 1.0);
+    for pixel in mark.pixels_mut() {
+        pixel[3] = (pixel[3] as f32 * opacity).round() as u8;
+    }
+
+    let (x, y) = position.origin(canvas.dimensions(), mark.dimensions());
+    imageops::overlay(canvas, &mark, x, y);
+}
+
+/// Renders a single line of `text` with `font` onto `canvas`.
+pub fn draw_text(canvas: &mut RgbaImage, font: &FontVec, text: &str, size: f32, color: Rgba<u8>, position: Position) {
+    let scaled = font.as_scaled(PxScale::from(size));
+
+    // lay the glyphs out on a baseline first so the block can be positioned as a whole
+    let mut caret = 0.0f32;
+    let mut previous = None;
+    let mut glyphs = Vec::new();
+    for c in text.chars() {
+        let id = scaled.glyph_id(c);
+        if let Some(previous) = previous {
+            caret += scaled.kern(previous, id);
+        }
+        glyphs.push(id.with_scale_and_position(scaled.scale(), ab_glyph::point(caret, scaled.ascent())));
+        caret += scaled.h_advance(id);
+        previous = Some(id);
+    }
+
+    let block = (caret.ceil() as u32, scaled.height().ceil() as u32);
+    let (origin_x, origin_y) = position.origin(canvas.dimensions(), block);
+
+    for glyph in glyphs {
+        let Some(outlined) = font.outline_glyph(glyph) else {
+            continue;
+        };
+        let bounds = outlined.px_bounds();
+        outlined.draw(|gx, gy, coverage| {
+            let x = origin_x + bounds.min.x as i64 + gx as i64;
+            let y = origin_y + bounds.min.y as i64 + gy as i64;
+            if x < 0 || y < 0 || x >= canvas.width() as i64 || y >= canvas.height() as i64 {
+                return;
+            }
+
+            let alpha = coverage * color[3] as f32 / 255.0;
+            let pixel = canvas.get_pixel_mut(x as u32, y as u32);
+            for c in 0..3 {
+                pixel[c] = (color[c] as f32 * alpha + pixel[c] as f32 * (1.0 - alpha)).round() as u8;
+            }
+            pixel[3] = pixel[3].max((alpha * 255.0).round() as u8);
+        });
+    }
+}
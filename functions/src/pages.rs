@@ -0,0 +1,209 @@
+// functions/src/pages.rs
+
+//! The `pages` stage: renders a range of a PDF's pages, each to its own `page<n>_<filename>`
+//! rendition, and lists them in a `pages_<filename>.json` manifest. Resolution, page limit and
+//! JPEG settings come from `presets/pages.json`. Pages are staged and published together, see
+//! `staging.rs`, so a failed run leaves no partial set behind.
+
+use azure_core::error::{Error, ErrorKind};
+use azure_storage_blobs::prelude::BlobServiceClient;
+use image_resize_core::{blob_tags, output_format::OutputFormat, pdf as pdf_source, pipeline, warnings};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    cancel, decode, error::StageError, output_metadata, output_tags, pdf,
+    provenance::{self, Source},
+    read_blob, read_original,
+    quality::{self, JpegOptions},
+    rendition_metadata,
+    report::{BlobReport, StageReport},
+    staging::Staging,
+    ImageMessage,
+};
+
+const DEFAULT_MAX_PAGES: u32 = 50;
+
+/// The `pages` stage's configuration, see `core/src/pdf.rs`.
+#[derive(Deserialize, Debug)]
+struct PagesPreset {
+    #[serde(default = "default_dpi")]
+    dpi: u32,
+    /// Most pages rendered for one request, however wide its range.
+    #[serde(default = "default_max_pages")]
+    max_pages: u32,
+    #[serde(default)]
+    jpeg: JpegOptions,
+}
+
+impl Default for PagesPreset {
+    fn default() -> Self {
+        PagesPreset {
+            dpi: default_dpi(),
+            max_pages: default_max_pages(),
+            jpeg: JpegOptions::default(),
+        }
+    }
+}
+
+fn default_dpi() -> u32 {
+    pdf::DEFAULT_DPI
+}
+
+fn default_max_pages() -> u32 {
+    DEFAULT_MAX_PAGES
+}
+
+#[derive(Serialize, Debug)]
+struct Manifest {
+    source: String,
+    dpi: u32,
+    pages: Vec<ManifestPage>,
+}
+
+#[derive(Serialize, Debug)]
+struct ManifestPage {
+    page: u32,
+    blob: String,
+    width: u32,
+    height: u32,
+    bytes: u64,
+}
+
+/// The preset and its raw definition, both empty when the container has no preset blob.
+async fn pages_preset(service_client: &BlobServiceClient, container_name: &str) -> azure_core::Result<(PagesPreset, Vec<u8>)> {
+    let blob_client = service_client.container_client(container_name).blob_client(pdf_source::PAGES_PRESET);
+    match read_blob(&blob_client).await {
+        Ok(definition) => {
+            let preset = serde_json::from_slice(&definition)
+                .map_err(|source| StageError::Invalid { what: pdf_source::PAGES_PRESET.to_string(), source })?;
+            Ok((preset, definition))
+        }
+        Err(e) if e.as_http_error().is_some_and(|e| e.status() == azure_core::StatusCode::NotFound) => {
+            Ok((PagesPreset::default(), Vec::new()))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Renders pages `first` to `last` of the image's PDF, at most the preset's `max_pages` of them.
+pub async fn render_pages(
+    image: &ImageMessage,
+    first: u32,
+    last: Option<u32>,
+    service_client: &BlobServiceClient,
+    report: &mut StageReport,
+) -> azure_core::Result<()> {
+    let container_name = &image.image_container;
+    let container_client = service_client.container_client(container_name);
+    let (bytes, etag) = read_original(image, &container_client.blob_client(&image.filename)).await?;
+    report.input = Some(BlobReport {
+        container: container_name.clone(),
+        blob: image.filename.clone(),
+        bytes: Some(bytes.len() as u64),
+        format: Some(decode::source_format(&bytes)),
+        ..Default::default()
+    });
+    if !pdf_source::is_pdf(&bytes) {
+        report.warn(warnings::STAGE_SKIPPED, format!("{} is not a PDF, pages were skipped", image.filename));
+        return Ok(());
+    }
+
+    let (preset, definition) = pages_preset(service_client, container_name).await?;
+    let source = Source { etag: &etag, definition: &definition };
+    if preset.dpi == 0 || preset.dpi > pdf::MAX_DPI {
+        return Err(Error::with_message(ErrorKind::Other, || {
+            format!("The pages preset's dpi must be between 1 and {}", pdf::MAX_DPI)
+        }));
+    }
+    let limit = first.saturating_add(preset.max_pages.max(1) - 1);
+    let until = last.map_or(limit, |last| last.min(limit));
+    let rendered = pdf::render(&bytes, first, until, preset.dpi)
+        .await
+        .map_err(|e| Error::with_message(ErrorKind::Io, || e))?;
+    if last.map_or(rendered.len() as u32 == preset.max_pages, |last| last > limit) {
+        report.warn(
+            warnings::PAGES_LIMITED,
+            format!("Only pages {} to {} were rendered, the preset allowing {}", first, until, preset.max_pages),
+        );
+    }
+
+    let mut manifest = Manifest {
+        source: image.filename.clone(),
+        dpi: preset.dpi,
+        pages: Vec::new(),
+    };
+    let mut outputs = Vec::new();
+    let mut staging = Staging::new(image, &container_client);
+    // PDFs have no image format to keep
+    let output_format = image.output_format.unwrap_or(OutputFormat::Jpeg);
+    for (page, png) in rendered {
+        if let Err(e) = cancel::checkpoint(image).await {
+            staging.discard().await;
+            return Err(e);
+        }
+        let img = decode::load(&png).map_err(|source| StageError::Decode { what: format!("page {}", page), source });
+        let encoded = match img {
+            Ok(img) => quality::encode(&img, None, output_format, image, &preset.jpeg, report).await.map(|encoded| (img, encoded)),
+            Err(e) => Err(e.into()),
+        };
+        let (img, (encoded, encoder)) = match encoded {
+            Ok(encoded) => encoded,
+            Err(e) => {
+                staging.discard().await;
+                return Err(e);
+            }
+        };
+        let content_hash = pipeline::content_hash(&encoded);
+        let blob = format!("page{}_{}", page, image.filename);
+        let size = encoded.len() as u64;
+        let metadata = rendition_metadata(image, blob_tags::PAGE, &source, &content_hash);
+        if let Err(e) = staging.put(&blob, encoded, &content_hash, metadata, blob_tags::PAGE).await {
+            staging.discard().await;
+            return Err(e);
+        }
+        outputs.push(BlobReport {
+            container: container_name.clone(),
+            blob: blob.clone(),
+            bytes: Some(size),
+            width: Some(img.width()),
+            height: Some(img.height()),
+            format: None,
+            encoder: Some(encoder),
+            sha256: Some(content_hash),
+            copied_from: None,
+        });
+        manifest.pages.push(ManifestPage {
+            page,
+            blob,
+            width: img.width(),
+            height: img.height(),
+            bytes: size,
+        });
+    }
+    // every page rendered, so the set is published and only then listed in the manifest
+    for (output, copied_from) in outputs.iter_mut().zip(staging.publish().await?) {
+        output.copied_from = copied_from;
+        provenance::record(image, &source, container_name, &output.blob, blob_tags::PAGE, None).await;
+    }
+    report.outputs.extend(outputs);
+
+    let manifest_json = serde_json::to_vec(&manifest).expect("Failed to serialize page manifest");
+    let manifest_name = format!("pages_{}.json", image.filename);
+    report.outputs.push(BlobReport {
+        container: container_name.clone(),
+        blob: manifest_name.clone(),
+        bytes: Some(manifest_json.len() as u64),
+        ..Default::default()
+    });
+    container_client
+        .blob_client(&manifest_name)
+        .put_block_blob(manifest_json)
+        .content_type("application/json")
+        .metadata(output_metadata(image))
+        .tags(output_tags(image, blob_tags::PAGES))
+        .await?;
+    info!("Rendered {} pages of {}", manifest.pages.len(), image.filename);
+
+    Ok(())
+}
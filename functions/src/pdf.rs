@@ -0,0 +1,73 @@
+// functions/src/pdf.rs
+
+//! Rendering PDF pages with poppler's `pdftoppm` (`PDFTOPPM_PATH`, default `pdftoppm` on the
+//! `PATH`), which the worker host needs installed for the `pdf_pages` feature. The document is
+//! written to a temporary directory along with the rendered pages, see `temp.rs`, and
+//! `pdftoppm` is killed after `PDF_RENDER_TIMEOUT_SECS` (default 120).
+
+use std::{env, process::Stdio, time::Duration};
+
+use tokio::process::Command;
+
+use crate::temp;
+
+const DEFAULT_TIMEOUT_SECS: u64 = 120;
+/// Resolution pages are rendered at when nothing else is configured.
+pub const DEFAULT_DPI: u32 = 150;
+/// Highest resolution accepted, an A4 page being about 5000x7000 pixels at it.
+pub const MAX_DPI: u32 = 600;
+
+fn timeout() -> Duration {
+    let secs = env::var("PDF_RENDER_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_TIMEOUT_SECS);
+    Duration::from_secs(secs)
+}
+
+/// Pages `first` to `last` of the PDF in `bytes` at `dpi`, as page numbers and PNGs in page
+/// order. Pages past the end of the document are left out.
+pub async fn render(bytes: &[u8], first: u32, last: u32, dpi: u32) -> Result<Vec<(u32, Vec<u8>)>, String> {
+    let dir = temp::path("pages");
+    tokio::fs::create_dir(&*dir)
+        .await
+        .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    render_in(&dir, bytes, first, last, dpi).await
+}
+
+async fn render_in(dir: &std::path::Path, bytes: &[u8], first: u32, last: u32, dpi: u32) -> Result<Vec<(u32, Vec<u8>)>, String> {
+    let input = dir.join("input.pdf");
+    tokio::fs::write(&input, bytes)
+        .await
+        .map_err(|e| format!("Failed to write the PDF to {}: {}", input.display(), e))?;
+
+    let pdftoppm = env::var("PDFTOPPM_PATH").unwrap_or_else(|_| "pdftoppm".to_string());
+    let output = Command::new(&pdftoppm)
+        .args(["-png", "-r", &dpi.to_string(), "-f", &first.to_string(), "-l", &last.to_string()])
+        .arg(&input)
+        .arg(dir.join("page"))
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(timeout(), output)
+        .await
+        .map_err(|_| format!("pdftoppm took longer than {:?}", timeout()))?
+        .map_err(|e| format!("Failed to run {}: {}", pdftoppm, e))?;
+    if !output.status.success() {
+        return Err(format!("pdftoppm failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+
+    // pages are written as `page-<n>.png`, `n` zero padded to the width of the page count
+    let mut pages = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await.map_err(|e| e.to_string())?;
+    while let Some(entry) = entries.next_entry().await.map_err(|e| e.to_string())? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some(page) = name.strip_prefix("page-").and_then(|n| n.strip_suffix(".png")).and_then(|n| n.parse().ok()) else {
+            continue;
+        };
+        let png = tokio::fs::read(entry.path()).await.map_err(|e| e.to_string())?;
+        pages.push((page, png));
+    }
+    pages.sort_by_key(|(page, _)| *page);
+    Ok(pages)
+}
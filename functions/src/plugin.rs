@@ -0,0 +1,115 @@
+// functions/src/plugin.rs
+
+//! Custom transforms as WebAssembly modules, listed by blob name under `plugins` in a template or
+//! `presets/resize.json` and run in order on the source after cropping and enhancing, before it is
+//! scaled. A module has no imports and exports:
+//!
+//! - `memory`, its linear memory;
+//! - `alloc(len: i32) -> i32`, returning the offset of `len` free bytes in it;
+//! - `transform(ptr: i32, width: i32, height: i32) -> i32`, editing the `width * height` RGBA8
+//!   pixels at `ptr` in place and returning 0 on success.
+//!
+//! Modules run sandboxed, with `PLUGIN_FUEL` (roughly instructions) and `PLUGIN_MAX_MEMORY_MB` as
+//! limits, and a module that fails or runs out of either leaves the image as it was, with a
+//! `plugin_failed` warning.
+
+use std::{env, sync::OnceLock};
+
+use azure_storage_blobs::prelude::ContainerClient;
+use image::{DynamicImage, RgbaImage};
+use image_resize_core::warnings;
+use tracing::info;
+use wasmtime::{Config, Engine, Instance, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use crate::{error::StageError, read_blob, report::StageReport};
+
+const DEFAULT_FUEL: u64 = 5_000_000_000;
+const DEFAULT_MAX_MEMORY_MB: usize = 512;
+
+fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    env::var(key).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+fn engine() -> &'static Engine {
+    static ENGINE: OnceLock<Engine> = OnceLock::new();
+    ENGINE.get_or_init(|| {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        Engine::new(&config).expect("Failed to create WASM engine")
+    })
+}
+
+/// Runs the module in `wasm` over the pixels of `img`.
+fn run(wasm: &[u8], img: &RgbaImage) -> wasmtime::Result<RgbaImage> {
+    let engine = engine();
+    let module = Module::new(engine, wasm)?;
+    let limits = StoreLimitsBuilder::new()
+        .memory_size(env_or("PLUGIN_MAX_MEMORY_MB", DEFAULT_MAX_MEMORY_MB) * 1024 * 1024)
+        .instances(1)
+        .build();
+    let mut store: Store<StoreLimits> = Store::new(engine, limits);
+    store.limiter(|limits| limits);
+    store.set_fuel(env_or("PLUGIN_FUEL", DEFAULT_FUEL))?;
+
+    let instance = Instance::new(&mut store, &module, &[])?;
+    let memory = instance
+        .get_memory(&mut store, "memory")
+        .ok_or_else(|| wasmtime::Error::msg("Module doesn't export its memory"))?;
+    let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+    let transform = instance.get_typed_func::<(i32, i32, i32), i32>(&mut store, "transform")?;
+
+    let len = i32::try_from(img.as_raw().len()).map_err(|_| wasmtime::Error::msg("Image too large for a plugin"))?;
+    let ptr = alloc.call(&mut store, len)?;
+    memory.write(&mut store, ptr as u32 as usize, img.as_raw())?;
+    let status = transform.call(&mut store, (ptr, img.width() as i32, img.height() as i32))?;
+    if status != 0 {
+        return Err(wasmtime::Error::msg(format!("transform returned {}", status)));
+    }
+
+    let mut pixels = vec![0; img.as_raw().len()];
+    memory.read(&store, ptr as u32 as usize, &mut pixels)?;
+    Ok(RgbaImage::from_raw(img.width(), img.height(), pixels).expect("Plugin output matches the input size"))
+}
+
+/// Runs each of `plugins`, blob names in `container_client`, over `img` in turn.
+pub async fn apply(
+    img: DynamicImage,
+    plugins: &[String],
+    container_client: &ContainerClient,
+    report: &mut StageReport,
+) -> azure_core::Result<DynamicImage> {
+    if plugins.is_empty() {
+        return Ok(img);
+    }
+    let mut pixels = img.to_rgba8();
+    for name in plugins {
+        let wasm = read_blob(&container_client.blob_client(name)).await?;
+        match run(&wasm, &pixels) {
+            Ok(transformed) => {
+                info!("Applied plugin {}", name);
+                pixels = transformed;
+            }
+            Err(e) => report.warn(warnings::PLUGIN_FAILED, format!("Plugin {} failed: {:#}", name, e)),
+        }
+    }
+    Ok(DynamicImage::ImageRgba8(pixels))
+}
+
+/// Compiles each of `plugins` and checks it exports what [`run`] calls, without running it.
+pub async fn check(plugins: &[String], container_client: &ContainerClient) -> azure_core::Result<()> {
+    for name in plugins {
+        let wasm = read_blob(&container_client.blob_client(name)).await?;
+        let module = Module::new(engine(), &wasm).map_err(|e| StageError::Plugin {
+            blob: name.clone(),
+            reason: format!("{:#}", e),
+        })?;
+        if let Some(missing) = ["memory", "alloc", "transform"].into_iter().find(|export| module.get_export(export).is_none()) {
+            return Err(StageError::Plugin {
+                blob: name.clone(),
+                reason: format!("Module doesn't export {}", missing),
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
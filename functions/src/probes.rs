@@ -0,0 +1,55 @@
+// functions/src/probes.rs
+
+//! A small HTTP server beside the queue consumer, on `WORKER_METRICS_PORT` (default 9090, `0`
+//! turns it off): `GET /metrics` in the Prometheus text format, see `core/src/metrics.rs`,
+//! `GET /healthz` for liveness and `GET /readyz`, which fails while blob storage or the queue
+//! can't be reached and once the worker is draining, so no new work is routed its way. With
+//! `WORKER_PROFILING=on` it also serves CPU profiles and memory figures, see `profiling.rs`.
+
+use image_resize_core::{health, metrics};
+use std::{env, net::SocketAddr};
+use tracing::info;
+use warp::{http::StatusCode, Filter};
+
+use crate::{drain::Drain, profiling};
+
+const DEFAULT_PORT: u16 = 9090;
+
+/// Starts the server in the background unless it's turned off.
+pub fn spawn(drain: Drain) {
+    let port = env::var("WORKER_METRICS_PORT")
+        .ok()
+        .map(|v| v.parse().expect("Invalid WORKER_METRICS_PORT"))
+        .unwrap_or(DEFAULT_PORT);
+    if port == 0 {
+        return;
+    }
+
+    let metrics_route = warp::path("metrics")
+        .and(warp::get())
+        .map(|| warp::reply::with_header(metrics::render(), "content-type", "text/plain; version=0.0.4"));
+    let healthz_route = warp::path("healthz")
+        .and(warp::get())
+        .map(|| warp::reply::json(&serde_json::json!({ "status": "ok" })));
+    let readyz_route = warp::path("readyz").and(warp::get()).then(move || {
+        let drain = drain.clone();
+        async move {
+            let mut readiness = health::readiness().await;
+            if drain.is_draining() {
+                readiness.insert("draining", health::Check::failed("The worker is draining"));
+            }
+            let status = if readiness.ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+            warp::reply::with_status(warp::reply::json(&readiness), status)
+        }
+    });
+
+    let address = SocketAddr::from(([0, 0, 0, 0], port));
+    info!("Serving metrics and probes on {}", address);
+    let routes = metrics_route.or(healthz_route).or(readyz_route);
+    if profiling::enabled() {
+        info!("Serving profiles on {}", address);
+        tokio::spawn(warp::serve(routes.or(profiling::routes())).run(address));
+    } else {
+        tokio::spawn(warp::serve(routes).run(address));
+    }
+}
@@ -0,0 +1,223 @@
+// functions/src/profiling.rs
+
+//! On-demand profiling of a running worker, served beside the probes (see `probes.rs`) when
+//! `WORKER_PROFILING=on`, so the hot spots of a pathological class of images can be captured where
+//! it shows up:
+//!
+//! - `GET /debug/pprof/profile?seconds=30` samples every thread's stack `PROFILING_FREQUENCY` times
+//!   a second (default 99) for `seconds` (at most 300) and answers with the profile in pprof's
+//!   protobuf format, for `go tool pprof`, or with `&format=flamegraph` as an SVG flame graph.
+//!   Decode and encode show up under their own functions, e.g. `decode::decode` or the encoders.
+//!   With `&upload=true` the capture is written to `<hostname>/<time>.pb` or `.svg` in the container
+//!   `PROFILE_CONTAINER` (default `profiles`), which must exist, and the answer names the blob.
+//!   One capture runs at a time, a second one getting 409;
+//! - `GET /debug/memory`: the process's resident set, its peak and its threads, read from `/proc`.
+//!
+//! Sampling costs a little CPU on every thread while it runs; nothing is sampled otherwise.
+
+use image_resize_core::{clients, failover::Location, telemetry};
+use pprof::protos::Message;
+use serde::{Deserialize, Serialize};
+use std::{
+    env, fs,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use time::OffsetDateTime;
+use tracing::{error, info};
+use warp::{
+    http::{Response, StatusCode},
+    hyper::Body,
+    Filter, Rejection, Reply,
+};
+
+const DEFAULT_SECONDS: u64 = 30;
+const MAX_SECONDS: u64 = 300;
+const DEFAULT_FREQUENCY: i32 = 99;
+const DEFAULT_CONTAINER: &str = "profiles";
+
+/// Set while a capture runs.
+static CAPTURING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Format {
+    #[default]
+    Pprof,
+    Flamegraph,
+}
+
+impl Format {
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Pprof => "application/octet-stream",
+            Format::Flamegraph => "image/svg+xml",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Pprof => "pb",
+            Format::Flamegraph => "svg",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ProfileQuery {
+    seconds: Option<u64>,
+    #[serde(default)]
+    format: Format,
+    #[serde(default)]
+    upload: bool,
+}
+
+/// Answer of a capture written to storage.
+#[derive(Serialize)]
+struct Uploaded {
+    container: String,
+    blob: String,
+    seconds: u64,
+    samples: isize,
+}
+
+#[derive(Serialize)]
+struct MemoryUsage {
+    rss_bytes: Option<u64>,
+    peak_rss_bytes: Option<u64>,
+    virtual_bytes: Option<u64>,
+    threads: Option<u64>,
+}
+
+/// Whether `WORKER_PROFILING` turns the routes on.
+pub fn enabled() -> bool {
+    env::var("WORKER_PROFILING").is_ok_and(|v| matches!(v.trim().to_ascii_lowercase().as_str(), "1" | "true" | "on"))
+}
+
+fn text(status: StatusCode, message: impl Into<String>) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(message.into()))
+        .expect("Failed to build response")
+}
+
+/// Samples for `seconds` and encodes the profile, blocking the thread meanwhile.
+fn capture(seconds: u64, format: Format) -> Result<(Vec<u8>, isize), String> {
+    let frequency = env::var("PROFILING_FREQUENCY")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|frequency| *frequency > 0)
+        .unwrap_or(DEFAULT_FREQUENCY);
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(frequency)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(|e| format!("Failed to start the profiler: {}", e))?;
+    std::thread::sleep(Duration::from_secs(seconds));
+    let report = guard.report().build().map_err(|e| format!("Failed to build the profile: {}", e))?;
+    drop(guard);
+    let samples = report.data.values().sum();
+    let mut bytes = Vec::new();
+    match format {
+        Format::Pprof => {
+            let profile = report.pprof().map_err(|e| format!("Failed to encode the profile: {}", e))?;
+            profile.encode(&mut bytes).map_err(|e| format!("Failed to encode the profile: {}", e))?;
+        }
+        Format::Flamegraph => report
+            .flamegraph(&mut bytes)
+            .map_err(|e| format!("Failed to draw the flame graph: {}", e))?,
+    }
+    Ok((bytes, samples))
+}
+
+/// Writes a capture to `PROFILE_CONTAINER`, returning its blob name.
+async fn upload(bytes: Vec<u8>, format: Format) -> azure_core::Result<(String, String)> {
+    let container = env::var("PROFILE_CONTAINER").unwrap_or_else(|_| DEFAULT_CONTAINER.to_string());
+    let host = env::var("HOSTNAME").unwrap_or_else(|_| "worker".to_string());
+    let now = OffsetDateTime::now_utc();
+    let blob = format!("{}/{}.{}", host, now.unix_timestamp_nanos(), format.extension());
+    let upload = clients::container_client(&container, Location::Primary)
+        .blob_client(&blob)
+        .put_block_blob(bytes)
+        .content_type(format.content_type())
+        .into_future();
+    telemetry::dependency("Azure blob", &container, "put_block_blob", upload).await?;
+    Ok((container, blob))
+}
+
+async fn profile(query: ProfileQuery) -> Result<Response<Body>, Rejection> {
+    let seconds = query.seconds.unwrap_or(DEFAULT_SECONDS);
+    if seconds == 0 || seconds > MAX_SECONDS {
+        return Ok(text(StatusCode::BAD_REQUEST, format!("seconds must be from 1 to {}", MAX_SECONDS)));
+    }
+    if CAPTURING.swap(true, Ordering::AcqRel) {
+        return Ok(text(StatusCode::CONFLICT, "A profile is already being captured"));
+    }
+    info!("Capturing a {} second profile", seconds);
+    let format = query.format;
+    let captured = tokio::task::spawn_blocking(move || capture(seconds, format)).await;
+    CAPTURING.store(false, Ordering::Release);
+    let (bytes, samples) = match captured {
+        Ok(Ok(captured)) => captured,
+        Ok(Err(e)) => {
+            error!("{}", e);
+            return Ok(text(StatusCode::INTERNAL_SERVER_ERROR, e));
+        }
+        Err(e) => {
+            error!("The profiler panicked: {:?}", e);
+            return Ok(text(StatusCode::INTERNAL_SERVER_ERROR, "The profiler panicked"));
+        }
+    };
+    telemetry::track_event("ProfileCaptured", &[("seconds", seconds.to_string()), ("samples", samples.to_string())]);
+    if !query.upload {
+        return Ok(Response::builder()
+            .header("content-type", format.content_type())
+            .body(Body::from(bytes))
+            .expect("Failed to build response"));
+    }
+    match upload(bytes, format).await {
+        Ok((container, blob)) => {
+            info!("Wrote a profile of {} samples to {}/{}", samples, container, blob);
+            let uploaded = Uploaded {
+                container,
+                blob,
+                seconds,
+                samples,
+            };
+            Ok(warp::reply::json(&uploaded).into_response())
+        }
+        Err(e) => {
+            error!("Failed to write the profile: {:?}", e);
+            Ok(text(StatusCode::BAD_GATEWAY, format!("Failed to write the profile: {}", e)))
+        }
+    }
+}
+
+/// A `/proc/self/status` field counted in kB, in bytes.
+fn status_field(status: &str, field: &str) -> Option<u64> {
+    let line = status.lines().find(|line| line.starts_with(field))?;
+    let value = line[field.len()..].trim().trim_end_matches("kB").trim().parse::<u64>().ok()?;
+    Some(if line.ends_with("kB") { value * 1024 } else { value })
+}
+
+fn memory() -> MemoryUsage {
+    let status = fs::read_to_string("/proc/self/status").unwrap_or_default();
+    MemoryUsage {
+        rss_bytes: status_field(&status, "VmRSS:"),
+        peak_rss_bytes: status_field(&status, "VmHWM:"),
+        virtual_bytes: status_field(&status, "VmSize:"),
+        threads: status_field(&status, "Threads:"),
+    }
+}
+
+/// The profiling routes.
+pub fn routes() -> impl Filter<Extract = (Response<Body>,), Error = Rejection> + Clone {
+    let profile_route = warp::path!("debug" / "pprof" / "profile")
+        .and(warp::get())
+        .and(warp::query::<ProfileQuery>())
+        .and_then(profile);
+    let memory_route = warp::path!("debug" / "memory")
+        .and(warp::get())
+        .map(|| warp::reply::json(&memory()).into_response());
+    profile_route.or(memory_route).unify()
+}
@@ -0,0 +1,51 @@
+// functions/src/provenance.rs
+
+//! Where each rendition came from. The worker stamps it on the rendition's metadata, see
+//! `rendition_metadata`, and records it in the job status table, see `core/src/job_status.rs`,
+//! where the API reads an original's derivation tree from.
+
+use image_resize_core::{
+    job_status::{self, Derivation},
+    pipeline,
+};
+use tracing::warn;
+
+use crate::ImageMessage;
+
+/// What a stage makes its renditions from: the original's etag as it was read and the raw
+/// definition of the stage's preset, see `core/src/pipeline.rs`.
+pub struct Source<'a> {
+    pub etag: &'a str,
+    pub definition: &'a [u8],
+}
+
+/// Records that `blob` in `container` was made by `preset` from the image's original, or from
+/// `parent` when it was derived from another rendition. Failures are only logged, the rendition
+/// being stored either way.
+pub async fn record(image: &ImageMessage, source: &Source<'_>, container: &str, blob: &str, preset: &str, parent: Option<&str>) {
+    let pipeline_version = pipeline::version(preset, source.definition);
+    record_version(image, source.etag, container, blob, preset, &pipeline_version, parent).await
+}
+
+/// Like [`record`], for a rendition whose pipeline version is already known, e.g. one copied from
+/// another.
+pub async fn record_version(
+    image: &ImageMessage,
+    source_etag: &str,
+    container: &str,
+    blob: &str,
+    preset: &str,
+    pipeline_version: &str,
+    parent: Option<&str>,
+) {
+    let derivation = Derivation {
+        container,
+        blob,
+        preset,
+        pipeline_version,
+        parent,
+    };
+    if let Err(e) = job_status::record_provenance(&image.image_container, &image.filename, source_etag, derivation).await {
+        warn!("Failed to record the provenance of {}: {:?}", blob, e);
+    }
+}
@@ -0,0 +1,66 @@
+// functions/src/publish.rs
+
+use azure_core::request_options::Metadata;
+use azure_storage_blobs::prelude::BlobServiceClient;
+use image_resize_core::{blob_tags, naming, pipeline};
+use tracing::info;
+
+use crate::{
+    output_tags, provenance,
+    report::{BlobReport, StageReport},
+    ImageMessage,
+};
+
+/// Copies the resized rendition into `target_container` with a server-side copy, keeping its
+/// metadata but recording it as the copy's parent.
+pub async fn publish_rendition(
+    image: &ImageMessage,
+    target_container: &str,
+    service_client: &BlobServiceClient,
+    report: &mut StageReport,
+) -> azure_core::Result<()> {
+    let rendition_name = naming::resized(&image.filename);
+
+    let source = service_client
+        .container_client(&image.image_container)
+        .blob_client(&rendition_name);
+    let source_metadata = source.get_properties().await?.blob.metadata.unwrap_or_default();
+    let mut metadata = Metadata::new();
+    for (key, value) in &source_metadata {
+        metadata.insert(key.clone(), value.clone());
+    }
+    metadata.insert(pipeline::PRESET_KEY, blob_tags::PUBLISHED);
+    metadata.insert(pipeline::PARENT_KEY, pipeline::metadata_name(&rendition_name));
+
+    let target = service_client
+        .container_client(target_container)
+        .blob_client(&rendition_name);
+    target.copy(source.url()?).metadata(metadata).await?;
+    // a copy doesn't carry the source's index tags
+    target.set_tags(output_tags(image, blob_tags::PUBLISHED)).await?;
+
+    report.input = Some(BlobReport {
+        container: image.image_container.clone(),
+        blob: rendition_name.clone(),
+        ..Default::default()
+    });
+    report.outputs.push(BlobReport {
+        container: target_container.to_string(),
+        blob: rendition_name.clone(),
+        ..Default::default()
+    });
+    let metadata_value = |key| source_metadata.get(key).map(String::as_str).unwrap_or_default();
+    provenance::record_version(
+        image,
+        metadata_value(pipeline::SOURCE_ETAG_KEY),
+        target_container,
+        &rendition_name,
+        blob_tags::PUBLISHED,
+        metadata_value(pipeline::VERSION_KEY),
+        Some(&rendition_name),
+    )
+    .await;
+    info!("Published {} to container {}", rendition_name, target_container);
+
+    Ok(())
+}
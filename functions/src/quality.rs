@@ -0,0 +1,469 @@
+// functions/src/quality.rs
+
+//! JPEG encoding of renditions, optionally verified. With the `quality_check` flag on, each encode
+//! is decoded again and compared with the image it was made from by SSIM (structural similarity,
+//! 1.0 meaning identical); below `QUALITY_SSIM_THRESHOLD` (default 0.9) it is redone at the next
+//! of [`RETRY_QUALITIES`], and if none reaches the threshold the best one is kept and flagged.
+//!
+//! An upload's `target_size` instead binary searches the quality for the highest one whose output
+//! fits the budget, in at most [`MAX_SEARCH_STEPS`] encodes.
+//!
+//! Chroma subsampling, progressive scans and restart intervals come from the preset's
+//! [`JpegOptions`]: the `jpeg` section of a template, or of `presets/resize.json` for `resize`.
+//! So does the background transparent pixels are flattened onto, JPEG having no alpha channel,
+//! and the output color space, see `color.rs`.
+//!
+//! PNG and WebP renditions, see `core/src/output_format.rs`, skip all of that: both are encoded
+//! lossless with their transparency, in the preset's color space.
+//!
+//! The preset's `encoder` picks the backend: the pure Rust `jpeg-encoder` crate, or mozjpeg when
+//! the worker is built with the `mozjpeg` feature. mozjpeg's trellis quantization and optimized
+//! Huffman tables give noticeably smaller files at the same quality for several times the encode
+//! time; `handler bench-encoders` measures the tradeoff on sample images, see `bench.rs`.
+
+use image::{
+    codecs::{png::PngEncoder, webp::WebPEncoder},
+    imageops, DynamicImage, GrayImage, ImageEncoder, ImageFormat, Rgb, RgbImage,
+};
+use image_resize_core::{features, metrics, output_format::OutputFormat, warnings};
+use jpeg_encoder::{ColorType, SamplingFactor};
+use serde::{Deserialize, Serialize};
+use std::{env, time::Instant};
+
+use crate::{
+    budget,
+    color::{self, ColorSpace, Profile},
+    error::StageError,
+    report::{Encoder, StageReport},
+    ImageMessage,
+};
+
+/// Quality renditions are encoded at, unless verification or a target size calls for another.
+pub const DEFAULT_QUALITY: u8 = 75;
+/// Qualities tried in turn when an encode falls below the threshold.
+const RETRY_QUALITIES: &[u8] = &[85, 95];
+const DEFAULT_THRESHOLD: f64 = 0.9;
+/// Bounds of the quality search for a target size.
+const MIN_QUALITY: u8 = 10;
+const MAX_QUALITY: u8 = 95;
+/// Enough to narrow [`MIN_QUALITY`]..=[`MAX_QUALITY`] down to a single quality.
+const MAX_SEARCH_STEPS: usize = 7;
+
+/// SSIM is computed over windows of this size, moved by half a window at a time.
+const WINDOW: u32 = 8;
+const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+fn threshold() -> f64 {
+    env::var("QUALITY_SSIM_THRESHOLD")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_THRESHOLD)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum Subsampling {
+    /// Chroma at half resolution both ways, the smallest output.
+    #[default]
+    #[serde(rename = "4:2:0")]
+    Half,
+    /// Chroma at half horizontal resolution.
+    #[serde(rename = "4:2:2")]
+    HalfHorizontal,
+    /// Full resolution chroma, for sharp colored edges such as text and line art.
+    #[serde(rename = "4:4:4")]
+    Full,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JpegEncoder {
+    #[default]
+    Builtin,
+    /// Needs the `mozjpeg` feature; workers built without it fall back to the built-in encoder.
+    /// Restart intervals aren't supported.
+    Mozjpeg,
+}
+
+/// JPEG encoder settings of a preset.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JpegOptions {
+    #[serde(default)]
+    pub encoder: JpegEncoder,
+    #[serde(default)]
+    pub subsampling: Subsampling,
+    /// Progressive scans, so browsers can show a coarse image while the rest loads.
+    #[serde(default)]
+    pub progressive: bool,
+    /// MCUs between restart markers, 0 for none; limits the damage of a corrupted byte.
+    #[serde(default)]
+    pub restart_interval: u16,
+    /// RGB color transparent pixels are blended onto.
+    #[serde(default = "default_background")]
+    pub background: [u8; 3],
+    #[serde(default)]
+    pub color_space: ColorSpace,
+}
+
+fn default_background() -> [u8; 3] {
+    [255, 255, 255]
+}
+
+impl Default for JpegOptions {
+    fn default() -> Self {
+        JpegOptions {
+            encoder: JpegEncoder::default(),
+            subsampling: Subsampling::default(),
+            progressive: false,
+            restart_interval: 0,
+            background: default_background(),
+            color_space: ColorSpace::default(),
+        }
+    }
+}
+
+/// `img` blended onto an opaque `background`.
+fn flatten(img: &DynamicImage, background: [u8; 3]) -> RgbImage {
+    if !img.color().has_alpha() {
+        return img.to_rgb8();
+    }
+    let rgba = img.to_rgba8();
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, alpha] = rgba.get_pixel(x, y).0;
+        let blend = |channel: u8, background: u8| {
+            ((channel as u32 * alpha as u32 + background as u32 * (255 - alpha as u32) + 127) / 255) as u8
+        };
+        Rgb([blend(r, background[0]), blend(g, background[1]), blend(b, background[2])])
+    })
+}
+
+/// Encodes an opaque image as a JPEG.
+pub fn encode_jpeg(rgb: &RgbImage, quality: u8, options: &JpegOptions) -> Result<Vec<u8>, StageError> {
+    match options.encoder {
+        #[cfg(feature = "mozjpeg")]
+        JpegEncoder::Mozjpeg => encode_mozjpeg(rgb, quality, options),
+        _ => encode_builtin(rgb, quality, options),
+    }
+}
+
+fn encode_builtin(rgb: &RgbImage, quality: u8, options: &JpegOptions) -> Result<Vec<u8>, StageError> {
+    let mut bytes: Vec<u8> = Vec::new();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut bytes, quality);
+    encoder.set_sampling_factor(match options.subsampling {
+        Subsampling::Half => SamplingFactor::R_4_2_0,
+        Subsampling::HalfHorizontal => SamplingFactor::R_4_2_2,
+        Subsampling::Full => SamplingFactor::R_4_4_4,
+    });
+    encoder.set_progressive(options.progressive);
+    encoder.set_restart_interval(options.restart_interval);
+    if let Some(profile) = color::icc_profile(options.color_space) {
+        encoder.add_icc_profile(profile).map_err(|e| StageError::Encode(e.to_string()))?;
+    }
+    encoder
+        .encode(rgb.as_raw(), rgb.width() as u16, rgb.height() as u16, ColorType::Rgb)
+        .map_err(|e| StageError::Encode(e.to_string()))?;
+    Ok(bytes)
+}
+
+#[cfg(feature = "mozjpeg")]
+fn encode_mozjpeg(rgb: &RgbImage, quality: u8, options: &JpegOptions) -> Result<Vec<u8>, StageError> {
+    let mut compress = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+    compress.set_size(rgb.width() as usize, rgb.height() as usize);
+    compress.set_quality(quality as f32);
+    let chroma = match options.subsampling {
+        Subsampling::Half => (2, 2),
+        Subsampling::HalfHorizontal => (2, 1),
+        Subsampling::Full => (1, 1),
+    };
+    compress.set_chroma_sampling_pixel_sizes(chroma, chroma);
+    if options.progressive {
+        compress.set_progressive_mode();
+    } else {
+        // mozjpeg's defaults write progressive scans
+        compress.set_optimize_scans(false);
+    }
+    let encode_error = |e: std::io::Error| StageError::Encode(e.to_string());
+    let mut started = compress.start_compress(Vec::new()).map_err(encode_error)?;
+    if let Some(profile) = color::icc_profile(options.color_space) {
+        started.write_icc_profile(profile);
+    }
+    started.write_scanlines(rgb.as_raw()).map_err(encode_error)?;
+    started.finish().map_err(encode_error)
+}
+
+/// `options` with an encoder this worker was built with.
+fn available(options: &JpegOptions, report: &mut StageReport) -> JpegOptions {
+    let mut options = options.clone();
+    if options.encoder == JpegEncoder::Mozjpeg && !cfg!(feature = "mozjpeg") {
+        report.warn(
+            warnings::ENCODER_UNAVAILABLE,
+            "The worker was built without mozjpeg, the built-in encoder was used",
+        );
+        options.encoder = JpegEncoder::Builtin;
+    }
+    options
+}
+
+/// Mean SSIM of the luma of two images of the same size.
+pub fn ssim(a: &GrayImage, b: &GrayImage) -> f64 {
+    let (width, height) = a.dimensions();
+    let window_width = WINDOW.min(width);
+    let window_height = WINDOW.min(height);
+    let step = |window: u32| (window / 2).max(1);
+
+    let mut total = 0.0;
+    let mut windows = 0;
+    for y in (0..=height - window_height).step_by(step(window_height) as usize) {
+        for x in (0..=width - window_width).step_by(step(window_width) as usize) {
+            let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+            for dy in 0..window_height {
+                for dx in 0..window_width {
+                    let pa = a.get_pixel(x + dx, y + dy)[0] as f64;
+                    let pb = b.get_pixel(x + dx, y + dy)[0] as f64;
+                    sum_a += pa;
+                    sum_b += pb;
+                    sum_aa += pa * pa;
+                    sum_bb += pb * pb;
+                    sum_ab += pa * pb;
+                }
+            }
+            let n = (window_width * window_height) as f64;
+            let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+            let variance_a = sum_aa / n - mean_a * mean_a;
+            let variance_b = sum_bb / n - mean_b * mean_b;
+            let covariance = sum_ab / n - mean_a * mean_b;
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (variance_a + variance_b + C2));
+            windows += 1;
+        }
+    }
+    total / windows as f64
+}
+
+/// SSIM of an encode against the image it was made from.
+pub fn verify(img: &GrayImage, encoded: &[u8]) -> Result<f64, StageError> {
+    let decoded = image::load_from_memory_with_format(encoded, ImageFormat::Jpeg).map_err(|source| StageError::Decode {
+        what: "the encoded rendition".to_string(),
+        source,
+    })?;
+    Ok(ssim(img, &decoded.to_luma8()))
+}
+
+/// The highest quality whose encode fits `target_size`, or the lowest one if none does.
+fn fit_target_size(
+    img: &RgbImage,
+    target_size: u64,
+    options: &JpegOptions,
+    report: &mut StageReport,
+) -> Result<(Vec<u8>, u8), StageError> {
+    let (mut low, mut high) = (MIN_QUALITY, MAX_QUALITY);
+    let mut best: Option<(Vec<u8>, u8)> = None;
+    for _ in 0..MAX_SEARCH_STEPS {
+        if low > high {
+            break;
+        }
+        let quality = low + (high - low).div_ceil(2);
+        let encoded = encode_jpeg(img, quality, options)?;
+        if encoded.len() as u64 <= target_size {
+            best = Some((encoded, quality));
+            low = quality + 1;
+        } else {
+            high = quality.saturating_sub(1);
+        }
+    }
+    if let Some(best) = best {
+        return Ok(best);
+    }
+    let encoded = encode_jpeg(img, MIN_QUALITY, options)?;
+    report.warn(
+        warnings::TARGET_SIZE_EXCEEDED,
+        format!(
+            "{} bytes at the lowest quality, {} is over the {} byte target",
+            encoded.len(),
+            MIN_QUALITY,
+            target_size
+        ),
+    );
+    Ok((encoded, MIN_QUALITY))
+}
+
+fn write_lossless<E: ImageEncoder>(
+    mut encoder: E,
+    img: &DynamicImage,
+    profile: Option<&[u8]>,
+    report: &mut StageReport,
+) -> Result<(), StageError> {
+    if let Some(profile) = profile {
+        if encoder.set_icc_profile(profile.to_vec()).is_err() {
+            report.warn(warnings::ICC_PROFILE_DROPPED, "The output format can't carry the color profile");
+        }
+    }
+    encoder
+        .write_image(img.as_bytes(), img.width(), img.height(), img.color().into())
+        .map_err(|e| StageError::Encode(e.to_string()))
+}
+
+/// Encodes a PNG or WebP rendition, keeping its alpha channel. There is no quality to pick, so a
+/// target size can only be flagged.
+fn encode_lossless(
+    img: &DynamicImage,
+    source: Option<&Profile>,
+    format: OutputFormat,
+    image: &ImageMessage,
+    options: &JpegOptions,
+    report: &mut StageReport,
+) -> Result<(Vec<u8>, Encoder), StageError> {
+    let converted = color::convert_image(img, source, options.color_space);
+
+    let profile = color::icc_profile(options.color_space);
+    let mut bytes: Vec<u8> = Vec::new();
+    match format {
+        OutputFormat::Png => write_lossless(PngEncoder::new(&mut bytes), &converted, profile, report)?,
+        OutputFormat::Webp => write_lossless(WebPEncoder::new_lossless(&mut bytes), &converted, profile, report)?,
+        OutputFormat::Jpeg => unreachable!("JPEG is encoded lossy"),
+    }
+    if let Some(target_size) = image.target_size.filter(|target_size| bytes.len() as u64 > *target_size) {
+        report.warn(
+            warnings::TARGET_SIZE_EXCEEDED,
+            format!("{} bytes of lossless {:?} are over the {} byte target", bytes.len(), format, target_size),
+        );
+    }
+    Ok((bytes, Encoder::lossless(format, options)))
+}
+
+/// Encodes a rendition in `format` and the preset's color space, converting it from `source`'s
+/// profile. JPEGs are kept within the image's target size if it has one, and verified when the
+/// tenant has `quality_check` on. Waits for the format's encode budget first, see `budget.rs`.
+pub async fn encode(
+    img: &DynamicImage,
+    source: Option<&Profile>,
+    format: OutputFormat,
+    image: &ImageMessage,
+    options: &JpegOptions,
+    report: &mut StageReport,
+) -> azure_core::Result<(Vec<u8>, Encoder)> {
+    let _permit = budget::acquire(format).await;
+    // timed once the permit is held, so waiting on the budget isn't counted
+    let format_name = format!("{:?}", format).to_lowercase();
+    let _timer = scopeguard::guard(Instant::now(), |started| {
+        metrics::observe("encode_duration_seconds", &[("format", &format_name)], started.elapsed());
+    });
+    if format != OutputFormat::Jpeg {
+        return Ok(encode_lossless(img, source, format, image, options, report)?);
+    }
+    let check = features::is_enabled(features::QUALITY_CHECK, image.tenant.as_deref()).await;
+    let options = &available(options, report);
+    let mut flattened = flatten(img, options.background);
+    color::convert(&mut flattened, source, options.color_space);
+    let img = &flattened;
+
+    if let Some(target_size) = image.target_size {
+        let (encoded, quality) = fit_target_size(img, target_size, options, report)?;
+        // a higher quality would break the budget, so a low score can only be flagged
+        let score = if check { Some(verify(&imageops::grayscale(img), &encoded)?) } else { None };
+        if let Some(score) = score.filter(|score| *score < threshold()) {
+            report.warn(
+                warnings::QUALITY_BELOW_THRESHOLD,
+                format!("SSIM {:.3} at quality {} is below the {} threshold", score, quality, threshold()),
+            );
+        }
+        return Ok((encoded, Encoder::jpeg(quality, options, score)));
+    }
+
+    if !check {
+        return Ok((encode_jpeg(img, DEFAULT_QUALITY, options)?, Encoder::jpeg(DEFAULT_QUALITY, options, None)));
+    }
+
+    let threshold = threshold();
+    let luma = imageops::grayscale(img);
+    let mut best: Option<(Vec<u8>, u8, f64)> = None;
+    for quality in std::iter::once(DEFAULT_QUALITY).chain(RETRY_QUALITIES.iter().copied()) {
+        let encoded = encode_jpeg(img, quality, options)?;
+        let score = verify(&luma, &encoded)?;
+        if score >= threshold {
+            return Ok((encoded, Encoder::jpeg(quality, options, Some(score))));
+        }
+        if best.as_ref().is_none_or(|(_, _, best_score)| score > *best_score) {
+            best = Some((encoded, quality, score));
+        }
+    }
+
+    let (encoded, quality, score) = best.expect("At least one quality is tried");
+    report.warn(
+        warnings::QUALITY_BELOW_THRESHOLD,
+        format!("SSIM {:.3} at quality {} is below the {} threshold", score, quality, threshold),
+    );
+    Ok((encoded, Encoder::jpeg(quality, options, Some(score))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+    use image_resize_core::{
+        message::Stage,
+        pipeline,
+        resize_spec::{Filter, Fit},
+    };
+
+    use crate::resize;
+
+    /// A source with detail in every channel, transparency included.
+    fn source() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(300, 200, |x, y| {
+            Rgba([(x * 7 % 256) as u8, (y * 5 % 256) as u8, ((x ^ y) % 256) as u8, (128 + (x + y) % 128) as u8])
+        }))
+    }
+
+    fn message(json: &str) -> ImageMessage {
+        serde_json::from_str(json).unwrap()
+    }
+
+    /// Scales a freshly made source and encodes it as a rendition of `image` in `format`.
+    async fn rendition(image: &ImageMessage, format: OutputFormat, options: &JpegOptions) -> Vec<u8> {
+        let scaled = resize::scale(&source(), None, (120, 120), false, Fit::Contain, Filter::default(), None);
+        let mut report = StageReport::new(&Stage::Resize);
+        encode(&scaled, None, format, image, options, &mut report).await.unwrap().0
+    }
+
+    #[tokio::test]
+    async fn renditions_are_byte_identical() {
+        let image = message(r#"{"filename": "photo.png", "image_container": "images"}"#);
+        let progressive = JpegOptions {
+            subsampling: Subsampling::Full,
+            progressive: true,
+            restart_interval: 4,
+            ..JpegOptions::default()
+        };
+        let cases = [
+            (OutputFormat::Jpeg, JpegOptions::default()),
+            (OutputFormat::Jpeg, progressive),
+            (OutputFormat::Png, JpegOptions::default()),
+            (OutputFormat::Webp, JpegOptions::default()),
+        ];
+        for (format, options) in cases {
+            let first = rendition(&image, format, &options).await;
+            let second = rendition(&image, format, &options).await;
+            assert!(!first.is_empty());
+            assert_eq!(first, second, "{:?} with {:?}", format, options);
+            assert_eq!(pipeline::content_hash(&first), pipeline::content_hash(&second));
+        }
+    }
+
+    #[tokio::test]
+    async fn target_size_searches_are_byte_identical() {
+        let image = message(r#"{"filename": "photo.png", "image_container": "images", "target_size": 3000}"#);
+        let first = rendition(&image, OutputFormat::Jpeg, &JpegOptions::default()).await;
+        let second = rendition(&image, OutputFormat::Jpeg, &JpegOptions::default()).await;
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn jpeg_encodes_are_byte_identical_across_qualities() {
+        let rgb = flatten(&source(), default_background());
+        for quality in [MIN_QUALITY, DEFAULT_QUALITY, MAX_QUALITY] {
+            let options = JpegOptions::default();
+            assert_eq!(encode_jpeg(&rgb, quality, &options).unwrap(), encode_jpeg(&rgb, quality, &options).unwrap());
+        }
+    }
+}
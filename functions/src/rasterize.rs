@@ -0,0 +1,54 @@
+// functions/src/rasterize.rs
+
+//! The `rasterize` pipeline's first step, rendering an SVG source to a PNG with librsvg's
+//! `rsvg-convert` (`RSVG_CONVERT_PATH`, default `rsvg-convert` on the `PATH`), which the worker
+//! host needs installed to take SVGs. The document is rendered to fit `SVG_RASTER_SIZE` pixels
+//! (default 2048) on its longest edge, keeping its aspect ratio, and the standard pipeline goes on
+//! from there. It's piped in, so it has no base directory and librsvg loads none of the files it
+//! may reference. `rsvg-convert` is killed after `SVG_RENDER_TIMEOUT_SECS` (default 30).
+
+use std::{env, process::Stdio, time::Duration};
+
+use tokio::{io::AsyncWriteExt, process::Command};
+
+const DEFAULT_SIZE: u32 = 2048;
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    env::var(key).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+/// The SVG in `bytes` as a PNG.
+pub async fn rasterize(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let size = env_or("SVG_RASTER_SIZE", DEFAULT_SIZE).to_string();
+    let timeout = Duration::from_secs(env_or("SVG_RENDER_TIMEOUT_SECS", DEFAULT_TIMEOUT_SECS));
+    let rsvg_convert = env::var("RSVG_CONVERT_PATH").unwrap_or_else(|_| "rsvg-convert".to_string());
+
+    let mut child = Command::new(&rsvg_convert)
+        .args(["--format", "png", "--keep-aspect-ratio", "--width", &size, "--height", &size])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("Failed to run {}: {}", rsvg_convert, e))?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let render = async {
+        // written alongside reading the output, so a large document can't fill both pipes
+        let write = async {
+            let result = stdin.write_all(bytes).await;
+            drop(stdin);
+            result
+        };
+        let (written, output) = tokio::join!(write, child.wait_with_output());
+        written.map_err(|e| format!("Failed to pass the SVG to {}: {}", rsvg_convert, e))?;
+        output.map_err(|e| format!("Failed to run {}: {}", rsvg_convert, e))
+    };
+    let output = tokio::time::timeout(timeout, render)
+        .await
+        .map_err(|_| format!("rsvg-convert took longer than {:?}", timeout))??;
+    if !output.status.success() {
+        return Err(format!("rsvg-convert failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(output.stdout)
+}
@@ -0,0 +1,255 @@
+// functions/src/report.rs
+
+//! A processing report per source image, stored next to it as `<name>.report.json` and served by
+//! the API on `GET /images/{name}/report`. Every stage run for the image appends what it read,
+//! what it wrote with which encoder settings, how long it took and anything worth a warning, so
+//! quality complaints can be traced without digging through logs.
+
+use azure_core::date;
+use azure_storage_blobs::prelude::BlobServiceClient;
+use image_resize_core::{
+    blob_tags,
+    output_format::OutputFormat,
+    telemetry,
+    variants::Variant,
+    warnings::{self, Warning},
+};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use time::OffsetDateTime;
+use tracing::warn;
+
+use crate::{
+    build_info,
+    color::{self, Profile},
+    output_metadata, output_tags,
+    quality::JpegOptions,
+    read_blob, ImageMessage, Stage,
+};
+
+/// Oldest stages are dropped past this, so an image reprocessed over and over keeps a bounded report.
+const MAX_STAGES: usize = 50;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Report {
+    pub filename: String,
+    pub container: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    pub stages: Vec<StageReport>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StageReport {
+    pub stage: Stage,
+    pub worker_version: String,
+    /// RFC 3339.
+    pub started_at: String,
+    pub duration_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input: Option<BlobReport>,
+    #[serde(default)]
+    pub outputs: Vec<BlobReport>,
+    #[serde(default)]
+    pub warnings: Vec<Warning>,
+    /// Variants left for a later run when the stage ran out of time, see `deadline.rs`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deferred: Vec<Variant>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct BlobReport {
+    pub container: String,
+    pub blob: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    /// Format of a source, going by its magic bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoder: Option<Encoder>,
+    /// Hex SHA-256 of the blob's content, for renditions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Blob with identical content this one was copied from instead of being uploaded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub copied_from: Option<String>,
+}
+
+/// Settings an output was encoded with.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Encoder {
+    pub format: String,
+    pub quality: u8,
+    /// Resampling filter used to scale the image.
+    pub filter: String,
+    #[serde(flatten)]
+    pub jpeg: JpegOptions,
+    /// SSIM against the unencoded image, when the encode was verified.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssim: Option<f64>,
+}
+
+impl Encoder {
+    pub fn jpeg(quality: u8, options: &JpegOptions, ssim: Option<f64>) -> Self {
+        Encoder {
+            format: "jpeg".to_string(),
+            quality,
+            filter: "triangle".to_string(),
+            jpeg: options.clone(),
+            ssim,
+        }
+    }
+
+    /// An animated GIF, scaled with `filter`, whose `jpeg` options don't apply.
+    pub fn animated(filter: &str, options: &JpegOptions) -> Self {
+        Encoder {
+            format: "gif".to_string(),
+            quality: 100,
+            filter: filter.to_string(),
+            jpeg: options.clone(),
+            ssim: None,
+        }
+    }
+
+    /// A lossless PNG or WebP encode, whose `jpeg` options only gave the color space.
+    pub fn lossless(format: OutputFormat, options: &JpegOptions) -> Self {
+        Encoder {
+            format: format!("{:?}", format).to_lowercase(),
+            quality: 100,
+            filter: "triangle".to_string(),
+            jpeg: options.clone(),
+            ssim: None,
+        }
+    }
+}
+
+impl StageReport {
+    pub fn new(stage: &Stage) -> Self {
+        StageReport {
+            stage: stage.clone(),
+            worker_version: build_info().version.to_string(),
+            started_at: date::to_rfc3339(&OffsetDateTime::now_utc()),
+            duration_ms: 0,
+            input: None,
+            outputs: Vec::new(),
+            warnings: Vec::new(),
+            deferred: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// Notes a non-fatal issue, see `core/src/warnings.rs`.
+    pub fn warn(&mut self, code: &str, message: impl Into<String>) {
+        let warning = Warning::new(code, message);
+        warn!("{}: {}", warning.code, warning.message);
+        telemetry::track_metric("ProcessingWarnings", 1.0, &[("code", warning.code.clone())]);
+        self.warnings.push(warning);
+    }
+
+    /// Warns about what re-encoding `source` at `output` dimensions loses or makes up, returning
+    /// the source's color profile for the encode to convert from.
+    pub fn check_conversion(
+        &mut self,
+        source: &[u8],
+        (width, height): (u32, u32),
+        (output_width, output_height): (u32, u32),
+    ) -> Option<Profile> {
+        let profile = color::source_profile(source).unwrap_or_else(|e| {
+            self.warn(
+                warnings::ICC_PROFILE_DROPPED,
+                format!("The source's ICC profile can't be applied ({}), its colors are taken as sRGB", e),
+            );
+            None
+        });
+        if output_width > width || output_height > height {
+            self.warn(
+                warnings::UPSCALED,
+                format!("Upscaled from {}x{} to {}x{}", width, height, output_width, output_height),
+            );
+        }
+        profile
+    }
+
+    /// Records what the stage read and how well its renditions compressed as metrics, one value
+    /// per image, for the distribution of the inputs the pipeline sees: `InputBytes`,
+    /// `InputWidth`, `InputHeight` and `InputMegapixels` by `format`, and `CompressionRatio`, the
+    /// rendition's size as 24-bit pixels over its encoded size, by output `format`.
+    pub fn track_metrics(&self) {
+        let stage = format!("{:?}", self.stage);
+        if let Some(input) = &self.input {
+            let format = input.format.clone().unwrap_or_else(|| "unknown".to_string());
+            let properties = [("format", format), ("stage", stage.clone())];
+            if let Some(bytes) = input.bytes {
+                telemetry::track_metric("InputBytes", bytes as f64, &properties);
+            }
+            if let (Some(width), Some(height)) = (input.width, input.height) {
+                telemetry::track_metric("InputWidth", width as f64, &properties);
+                telemetry::track_metric("InputHeight", height as f64, &properties);
+                telemetry::track_metric("InputMegapixels", width as f64 * height as f64 / 1e6, &properties);
+            }
+        }
+        for output in &self.outputs {
+            let (Some(bytes), Some(width), Some(height), Some(encoder)) = (output.bytes, output.width, output.height, &output.encoder) else {
+                continue;
+            };
+            if bytes == 0 {
+                continue;
+            }
+            let raw = width as f64 * height as f64 * 3.0;
+            let properties = [("format", encoder.format.clone()), ("quality", encoder.quality.to_string()), ("stage", stage.clone())];
+            telemetry::track_metric("CompressionRatio", raw / bytes as f64, &properties);
+        }
+    }
+
+    pub fn finish(&mut self, elapsed: Duration, error: Option<&azure_core::Error>) {
+        self.duration_ms = elapsed.as_millis() as u64;
+        self.error = error.map(|e| e.to_string());
+    }
+}
+
+fn report_name(filename: &str) -> String {
+    format!("{}.report.json", filename)
+}
+
+/// Appends `stage` to the image's report, creating the report on its first stage. Failing to
+/// write the report doesn't fail the stage.
+pub async fn append(image: &ImageMessage, stage: StageReport, service_client: &BlobServiceClient) {
+    let blob_client = service_client
+        .container_client(&image.image_container)
+        .blob_client(report_name(&image.filename));
+
+    let mut report = match read_blob(&blob_client).await {
+        Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_default(),
+        Err(e) if e.as_http_error().is_some_and(|e| e.status() == azure_core::StatusCode::NotFound) => Report::default(),
+        Err(e) => {
+            warn!("Failed to read the report of {}: {:?}", image.filename, e);
+            return;
+        }
+    };
+    report.filename = image.filename.clone();
+    report.container = image.image_container.clone();
+    report.tenant = image.tenant.clone();
+    report.stages.push(stage);
+    if report.stages.len() > MAX_STAGES {
+        let excess = report.stages.len() - MAX_STAGES;
+        report.stages.drain(..excess);
+    }
+
+    let body = serde_json::to_vec_pretty(&report).expect("Failed to serialize report");
+    if let Err(e) = blob_client
+        .put_block_blob(body)
+        .content_type("application/json")
+        .metadata(output_metadata(image))
+        .tags(output_tags(image, blob_tags::REPORT))
+        .await
+    {
+        warn!("Failed to write the report of {}: {:?}", image.filename, e);
+    }
+}
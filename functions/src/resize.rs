@@ -0,0 +1,527 @@
+// functions/src/resize.rs
+
+use azure_storage_blobs::prelude::BlobServiceClient;
+use image::{imageops::FilterType, DynamicImage, GenericImageView};
+use image_resize_core::{
+    blob_tags, config, crop::FocalPoint, features, image_index, job_status, metrics, naming, output_format::OutputFormat, pipeline,
+    routing::Pipeline,
+    resize_spec::{Filter, Fit, ResizeSpec},
+    telemetry, tiers,
+    pdf, variants::Variant, video::VideoFormat, warnings,
+};
+use serde::Deserialize;
+use std::{env, sync::OnceLock, time::Instant};
+use tracing::{info, info_span, trace, warn, Instrument};
+
+use crate::{
+    analysis, animation, cancel, capture,
+    color::Profile,
+    deadline, decode, dedup,
+    error::StageError,
+    detail::{self, Denoise, Sharpen},
+    enhance, jobs, normalize, operations, output_metadata, output_tags, plugin,
+    provenance::{self, Source},
+    quality::{self, JpegOptions},
+    read_blob, read_original, rendition_metadata,
+    report::{BlobReport, Encoder, StageReport},
+    transformer::{self, TransformerSpec},
+    ImageMessage,
+};
+
+/// The `resize` preset's configuration, see `core/src/pipeline.rs`.
+#[derive(Deserialize, Debug, Default)]
+struct ResizePreset {
+    #[serde(default)]
+    jpeg: JpegOptions,
+    #[serde(default)]
+    match_orientation: bool,
+    #[serde(default)]
+    fit: Fit,
+    /// WASM modules run before scaling, see `plugin.rs`.
+    #[serde(default)]
+    plugins: Vec<String>,
+    /// External HTTP transform run after the plugins, see `transformer.rs`.
+    transformer: Option<TransformerSpec>,
+    /// Seconds into a video source to take its poster frame from, see `video.rs`.
+    #[serde(default)]
+    poster_at: f64,
+    /// Filters run after scaling, see `detail.rs`.
+    sharpen: Option<Sharpen>,
+    denoise: Option<Denoise>,
+}
+
+const CENTER: FocalPoint = FocalPoint { x: 50.0, y: 50.0 };
+const DEFAULT_PRESHRINK_RATIO: u32 = 8;
+
+/// Reduction past which a downscale area-averages first, `0` to never.
+fn preshrink_ratio() -> u32 {
+    static RATIO: OnceLock<u32> = OnceLock::new();
+    *RATIO.get_or_init(|| {
+        env::var("DOWNSCALE_PRESHRINK_RATIO")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_PRESHRINK_RATIO)
+    })
+}
+
+/// Scales `img` to exactly `width` x `height` with `filter`. A reduction by more than
+/// `DOWNSCALE_PRESHRINK_RATIO` (default 8) on either side first area-averages the image down to
+/// twice the target there, since the filters sample too sparsely at such ratios and leave moiré in
+/// tiny thumbnails. The filter then makes the last step, keeping its look.
+pub fn resize_exact(img: &DynamicImage, width: u32, height: u32, filter: FilterType) -> DynamicImage {
+    let ratio = preshrink_ratio();
+    let reduced = |source: u32, target: u32| ratio > 0 && source as u64 > target as u64 * ratio as u64;
+    if !reduced(img.width(), width) && !reduced(img.height(), height) {
+        return img.resize_exact(width, height, filter);
+    }
+    let intermediate = |source: u32, target: u32| source.min(target.saturating_mul(2)).max(1);
+    img.thumbnail_exact(intermediate(img.width(), width), intermediate(img.height(), height))
+        .resize_exact(width, height, filter)
+}
+
+/// Scales `img` to fit within `width` x `height`, keeping its aspect ratio, as `DynamicImage::resize` does.
+fn contain(img: &DynamicImage, width: u32, height: u32, filter: FilterType) -> DynamicImage {
+    let ratio = f64::min(width as f64 / img.width() as f64, height as f64 / img.height() as f64);
+    let side = |source: u32| ((source as f64 * ratio).round() as u32).max(1);
+    if (side(img.width()), side(img.height())) == img.dimensions() {
+        return img.clone();
+    }
+    resize_exact(img, side(img.width()), side(img.height()), filter)
+}
+
+/// Cuts the image's crop out of `img`, if it asked for one that overlaps it.
+pub fn crop(img: DynamicImage, image: &ImageMessage, report: &mut StageReport) -> DynamicImage {
+    let Some(crop) = &image.crop else {
+        return img;
+    };
+    match crop.pixels(img.width(), img.height()) {
+        Some((x, y, width, height)) => img.crop_imm(x, y, width, height),
+        None => {
+            report.warn(
+                warnings::CROP_OUTSIDE_IMAGE,
+                format!("The crop {:?} lies outside the {}x{} source", crop, img.width(), img.height()),
+            );
+            img
+        }
+    }
+}
+
+/// The `width` x `height` box, turned to the source's orientation when `match_orientation` is set
+/// so that portraits aren't fitted into a landscape box or the other way round. Square sources and
+/// boxes are left alone.
+fn target_box(img: &DynamicImage, (width, height): (u32, u32), match_orientation: bool) -> (u32, u32) {
+    let portrait_source = img.height() > img.width();
+    let landscape_source = img.width() > img.height();
+    if match_orientation && ((portrait_source && width > height) || (landscape_source && height > width)) {
+        (height, width)
+    } else {
+        (width, height)
+    }
+}
+
+/// Scales `img` for a rendition with `filter`: as `spec` says if there is one, otherwise into the
+/// `width` x `height` box, as `fit` says.
+pub fn scale(
+    img: &DynamicImage,
+    spec: Option<ResizeSpec>,
+    target: (u32, u32),
+    match_orientation: bool,
+    fit: Fit,
+    filter: Filter,
+    focal_point: Option<FocalPoint>,
+) -> DynamicImage {
+    let filter = filter.filter_type();
+    if let Some(spec) = spec {
+        let (width, height) = spec.dimensions(img.width(), img.height());
+        return resize_exact(img, width, height, filter);
+    }
+    let (width, height) = target_box(img, target, match_orientation);
+    match fit {
+        Fit::Contain => contain(img, width, height, filter),
+        Fit::Cover => cover(img, width, height, filter, focal_point.unwrap_or(CENTER)),
+        Fit::Exact => resize_exact(img, width, height, filter),
+    }
+}
+
+/// Scales `img` to cover `width` x `height` and crops it to that box, keeping `focal_point` as
+/// close to the middle as the edges of the image allow.
+fn cover(img: &DynamicImage, width: u32, height: u32, filter: FilterType, focal_point: FocalPoint) -> DynamicImage {
+    let ratio = f64::max(width as f64 / img.width() as f64, height as f64 / img.height() as f64);
+    let scaled_width = ((img.width() as f64 * ratio).round() as u32).max(width);
+    let scaled_height = ((img.height() as f64 * ratio).round() as u32).max(height);
+    let scaled = resize_exact(img, scaled_width, scaled_height, filter);
+    let offset = |scaled: u32, side: u32, percent: f32| {
+        let center = scaled as f64 * percent as f64 / 100.0;
+        (center - side as f64 / 2.0).round().clamp(0.0, (scaled - side) as f64) as u32
+    };
+    scaled.crop_imm(
+        offset(scaled_width, width, focal_point.x),
+        offset(scaled_height, height, focal_point.y),
+        width,
+        height,
+    )
+}
+
+/// Scales, encodes and stores one size variant of `img`, see `core/src/variants.rs`.
+#[allow(clippy::too_many_arguments)]
+async fn store_variant(
+    image: &ImageMessage,
+    variant: &Variant,
+    img: &DynamicImage,
+    preset: &ResizePreset,
+    source: &Source<'_>,
+    profile: Option<&Profile>,
+    output_format: OutputFormat,
+    service_client: &BlobServiceClient,
+    report: &mut StageReport,
+) -> azure_core::Result<()> {
+    let (width, height) = variant.dimensions(img.width(), img.height());
+    let scaled = resize_exact(img, width, height, image.filter.unwrap_or_default().filter_type());
+    let scaled = detail::apply(scaled, preset.sharpen.as_ref(), preset.denoise.as_ref());
+    let (bytes, encoder) = quality::encode(&scaled, profile, output_format, image, &preset.jpeg, report)
+        .instrument(info_span!("encode", format = ?output_format, variant = %variant.name))
+        .await?;
+    let content_hash = pipeline::content_hash(&bytes);
+    let size = bytes.len() as u64;
+
+    let variant_preset = blob_tags::variant_preset(&variant.name);
+    let blob_name = variant.blob_name(&image.filename);
+    let copied_from = dedup::store(
+        &service_client.container_client(&image.image_container).blob_client(&blob_name),
+        bytes,
+        &content_hash,
+        rendition_metadata(image, &variant_preset, source, &content_hash),
+        output_tags(image, &variant_preset),
+        image.tenant.as_deref(),
+    )
+    .await?;
+    provenance::record(image, source, &image.image_container, &blob_name, &variant_preset, None).await;
+    report.outputs.push(BlobReport {
+        container: image.image_container.clone(),
+        blob: blob_name,
+        bytes: Some(size),
+        width: Some(scaled.width()),
+        height: Some(scaled.height()),
+        format: None,
+        encoder: Some(encoder),
+        sha256: Some(content_hash),
+        copied_from,
+    });
+    Ok(())
+}
+
+/// The preset and its raw definition, both empty when the container has no preset blob.
+async fn resize_preset(service_client: &BlobServiceClient, container_name: &str) -> azure_core::Result<(ResizePreset, Vec<u8>)> {
+    let blob_client = service_client.container_client(container_name).blob_client(pipeline::RESIZE_PRESET);
+    match read_blob(&blob_client).await {
+        Ok(definition) => {
+            let preset = serde_json::from_slice(&definition)
+                .map_err(|source| StageError::Invalid { what: pipeline::RESIZE_PRESET.to_string(), source })?;
+            Ok((preset, definition))
+        }
+        Err(e) if e.as_http_error().is_some_and(|e| e.status() == azure_core::StatusCode::NotFound) => {
+            Ok((ResizePreset::default(), Vec::new()))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads the container's preset and loads the plugins it lists, as the stage would.
+pub async fn check_preset(service_client: &BlobServiceClient, container_name: &str) -> azure_core::Result<()> {
+    let (preset, _) = resize_preset(service_client, container_name).await?;
+    plugin::check(&preset.plugins, &service_client.container_client(container_name)).await
+}
+
+pub async fn resize_image(image: &ImageMessage, service_client: &BlobServiceClient, report: &mut StageReport) -> azure_core::Result<()> {
+    let deadline = deadline::from_now();
+    let container_name = &image.image_container;
+    let blob_name = &*image.filename; 
+
+    let blob_client = service_client
+        .container_client(container_name)
+        .blob_client(blob_name);
+
+    trace!("Requesting blob");
+
+    let (bytes, etag) = read_original(image, &blob_client).await?;
+    let (bytes, etag) = normalize::normalize(image, bytes, etag, service_client, report).await?;
+    let (preset, definition) = resize_preset(service_client, container_name).await?;
+    let source = Source { etag: &etag, definition: &definition };
+
+    // some types have a pipeline of their own, see `CONTENT_ROUTES` in `core/src/config.rs`; an SVG
+    // routed to be rasterized is rendered as it's loaded, then goes on like any other source
+    let source_format = decode::source_format(&bytes);
+    if config::get().pipeline_for(&source_format) == Pipeline::Animation
+        && animate(image, &bytes, &source_format, &preset, &source, service_client, report).await?
+    {
+        return finish(image, &etag, service_client, report).await;
+    }
+
+    // load the image from the bytes, or the poster frame of a video
+    let started = Instant::now();
+    let img = decode::load_source(&bytes, preset.poster_at, image.tenant.as_deref())
+        .instrument(info_span!("decode", format = %source_format))
+        .await?;
+    metrics::observe("decode_duration_seconds", &[("format", &source_format)], started.elapsed());
+    report.input = Some(BlobReport {
+        container: container_name.clone(),
+        blob: blob_name.to_string(),
+        bytes: Some(bytes.len() as u64),
+        width: Some(img.width()),
+        height: Some(img.height()),
+        format: Some(source_format),
+        encoder: None,
+        sha256: image.content_hash.clone(),
+        copied_from: None,
+    });
+
+    // store histograms and brightness/sharpness stats next to the renditions
+    let mut caption = None;
+    if !image.variants_only && features::is_enabled(features::ANALYSIS, image.tenant.as_deref()).await {
+        let analysis = analysis::analyze(&img);
+        info!(
+            "Analysis: mean brightness {:.1}, sharpness {:.1}",
+            analysis.mean_brightness, analysis.sharpness
+        );
+        let analysis_json = serde_json::to_vec(&analysis).expect("Failed to serialize analysis");
+        let analysis_name = format!("analysis_{}.json", blob_name);
+        report.outputs.push(BlobReport {
+            container: container_name.clone(),
+            blob: analysis_name.clone(),
+            bytes: Some(analysis_json.len() as u64),
+            ..Default::default()
+        });
+        service_client
+            .container_client(container_name)
+            .blob_client(analysis_name)
+            .put_block_blob(analysis_json)
+            .content_type("application/json")
+            .metadata(output_metadata(image))
+            .tags(output_tags(image, blob_tags::ANALYSIS))
+            .await?;
+        caption = Some(analysis.caption());
+    }
+
+    // index capture details, tags and caption so originals can be found through the search endpoints
+    if !image.variants_only && features::is_enabled(features::IMAGE_INDEX, image.tenant.as_deref()).await {
+        let mut record = image_index::ImageRecord::new(container_name, blob_name, image.tenant.as_deref());
+        // videos and PDFs carry no EXIF
+        if VideoFormat::sniff(&bytes).is_none() && !pdf::is_pdf(&bytes) {
+            if let Err(e) = capture::read_into(&bytes, &mut record, image_index::retain_gps()) {
+                report.warn(warnings::EXIF_UNREADABLE, format!("EXIF could not be read: {}", e));
+            }
+        }
+        record.tags = image.tags.join(" ");
+        record.caption = caption;
+        record.size = Some(bytes.len() as u64);
+        if let Err(e) = image_index::upsert(&record).await {
+            warn!("Failed to index {}: {:?}", blob_name, e);
+        }
+    }
+
+    let img = crop(img, image, report);
+    let img = if image.auto_enhance && features::is_enabled(features::AUTO_ENHANCE, image.tenant.as_deref()).await {
+        info!("Applying auto-enhance");
+        enhance::auto_enhance(&img)
+    } else {
+        img
+    };
+
+    // the upload's own edits, see `operations.rs`
+    let container_client = service_client.container_client(container_name);
+    let img = operations::apply(img, &image.operations, image.filter.unwrap_or_default(), &container_client, report).await?;
+
+    // resize the image
+    let img = plugin::apply(img, &preset.plugins, &container_client, report).await?;
+    let img = transformer::apply(img, preset.transformer.as_ref(), report).await;
+    let output_format = image.output_format.unwrap_or_else(|| tiers::output_format(image, OutputFormat::of_source(&bytes)));
+    let profile = if image.variants_only {
+        // the resized rendition was made by the run that left these variants, see `deadline.rs`
+        report.check_conversion(&bytes, (img.width(), img.height()), (img.width(), img.height()))
+    } else {
+        // a resize among the operations already sized the rendition
+        let resized_img = if image_resize_core::operations::resizes(&image.operations) {
+            img.clone()
+        } else {
+            let started = Instant::now();
+            let scaled = info_span!("resize").in_scope(|| {
+                scale(
+                    &img,
+                    image.resize,
+                    (image.width, image.height),
+                    preset.match_orientation,
+                    image.fit.unwrap_or(preset.fit),
+                    image.filter.unwrap_or_default(),
+                    image.focal_point,
+                )
+            });
+            metrics::observe("resize_duration_seconds", &[], started.elapsed());
+            scaled
+        };
+        let resized_img = detail::apply(resized_img, preset.sharpen.as_ref(), preset.denoise.as_ref());
+        let profile = report.check_conversion(&bytes, (img.width(), img.height()), (resized_img.width(), resized_img.height()));
+        // write the resized image to the buffer
+        let (resized_bytes, encoder) = quality::encode(&resized_img, profile.as_ref(), output_format, image, &preset.jpeg, report)
+            .instrument(info_span!("encode", format = ?output_format))
+            .await?;
+        store_resized(image, resized_bytes, encoder, (resized_img.width(), resized_img.height()), &source, service_client, report).await?;
+        jobs::add_latest_output(image, report, service_client).await;
+        profile
+    };
+
+    // a job following its renditions as they're stored gets the smallest first
+    let mut variants = image.variants.clone();
+    if image.incremental {
+        variants.sort_by_key(|variant| variant.width);
+    }
+    // the variants are scaled from the same prepared source, as many as the deadline leaves time for
+    for (i, variant) in variants.iter().enumerate() {
+        if (i > 0 || !image.variants_only) && deadline::passed(deadline) {
+            report.deferred = variants[i..].to_vec();
+            report.warn(
+                warnings::RENDITIONS_DEFERRED,
+                format!("Ran out of time, {} variants were queued for a later run", report.deferred.len()),
+            );
+            break;
+        }
+        store_variant(image, variant, &img, &preset, &source, profile.as_ref(), output_format, service_client, report).await?;
+        jobs::add_latest_output(image, report, service_client).await;
+    }
+
+    finish(image, &etag, service_client, report).await
+}
+
+/// Stores the resized rendition of the image, `resized_<name>`.
+async fn store_resized(
+    image: &ImageMessage,
+    bytes: Vec<u8>,
+    encoder: Encoder,
+    (width, height): (u32, u32),
+    source: &Source<'_>,
+    service_client: &BlobServiceClient,
+    report: &mut StageReport,
+) -> azure_core::Result<()> {
+    let container_name = &image.image_container;
+    let content_hash = pipeline::content_hash(&bytes);
+
+    // change the filename to include the word "resized"
+    let new_blob_name = naming::resized(&image.filename);
+
+    let blob_client = service_client
+        .container_client(container_name)
+        .blob_client(&new_blob_name);
+    let resized_size = bytes.len() as u64;
+
+    // the job may have been cancelled while the image was processed
+    cancel::checkpoint(image).await?;
+    let copied_from = dedup::store(
+        &blob_client,
+        bytes,
+        &content_hash,
+        rendition_metadata(image, blob_tags::RESIZED, source, &content_hash),
+        output_tags(image, blob_tags::RESIZED),
+        image.tenant.as_deref(),
+    )
+    .await?;
+    provenance::record(image, source, container_name, &new_blob_name, blob_tags::RESIZED, None).await;
+    report.outputs.push(BlobReport {
+        container: container_name.clone(),
+        blob: new_blob_name,
+        bytes: Some(resized_size),
+        width: Some(width),
+        height: Some(height),
+        format: None,
+        encoder: Some(encoder),
+        sha256: Some(content_hash),
+        copied_from,
+    });
+
+    info!("Resized image uploaded successfully");
+    Ok(())
+}
+
+/// The `animation` pipeline: the resized rendition of an animated GIF as an animated GIF, see
+/// `animation.rs`. Returns false for a GIF of a single frame, which takes the standard pipeline.
+async fn animate(
+    image: &ImageMessage,
+    bytes: &[u8],
+    source_format: &str,
+    preset: &ResizePreset,
+    source: &Source<'_>,
+    service_client: &BlobServiceClient,
+    report: &mut StageReport,
+) -> azure_core::Result<bool> {
+    let filter = image.filter.unwrap_or_default();
+    let fit = image.fit.unwrap_or(preset.fit);
+    let animation = animation::resize(bytes, |frame| {
+        scale(frame, image.resize, (image.width, image.height), preset.match_orientation, fit, filter, image.focal_point)
+    })
+    .map_err(|source| StageError::Decode { what: "the animated GIF".to_string(), source })?;
+    let Some(animation) = animation else {
+        return Ok(false);
+    };
+    info!("Scaled {} frames of an animated GIF", animation.frames);
+    report.input = Some(BlobReport {
+        container: image.image_container.clone(),
+        blob: image.filename.clone(),
+        bytes: Some(bytes.len() as u64),
+        width: Some(animation.source_size.0),
+        height: Some(animation.source_size.1),
+        format: Some(source_format.to_string()),
+        encoder: None,
+        sha256: image.content_hash.clone(),
+        copied_from: None,
+    });
+    if animation.truncated {
+        report.warn(
+            warnings::OPTIONS_IGNORED,
+            format!("Only the first {} frames of the animation were kept", animation::MAX_FRAMES),
+        );
+    }
+    // the edits of the standard pipeline are made to stills
+    let ignored: Vec<&str> = [
+        (image.crop.is_some(), "crop"),
+        (!image.operations.is_empty(), "operations"),
+        (image.auto_enhance, "auto_enhance"),
+        (!image.variants.is_empty(), "variants"),
+        (image.output_format.is_some(), "output_format"),
+        (image.target_size.is_some(), "target_size"),
+        (!preset.plugins.is_empty(), "plugins"),
+        (preset.transformer.is_some(), "transformer"),
+    ]
+    .into_iter()
+    .filter_map(|(set, option)| set.then_some(option))
+    .collect();
+    if !ignored.is_empty() {
+        report.warn(
+            warnings::OPTIONS_IGNORED,
+            format!("The animation pipeline doesn't apply {}", ignored.join(", ")),
+        );
+    }
+
+    let encoder = Encoder::animated(&format!("{:?}", filter).to_lowercase(), &preset.jpeg);
+    store_resized(image, animation.encoded, encoder, animation.size, source, service_client, report).await?;
+    Ok(true)
+}
+
+/// Marks the original as processed once its renditions are stored.
+async fn finish(image: &ImageMessage, etag: &str, service_client: &BlobServiceClient, report: &StageReport) -> azure_core::Result<()> {
+    let container_name = &image.image_container;
+    let blob_name = &*image.filename;
+    let original_tags = blob_tags::tags(image.tenant.as_deref(), blob_tags::ORIGINAL, blob_tags::PROCESSED);
+    if let Err(e) = service_client
+        .container_client(container_name)
+        .blob_client(blob_name)
+        .set_tags(original_tags)
+        .await
+    {
+        warn!("Failed to mark {} as processed: {:?}", blob_name, e);
+    }
+    // remember which version of the source was processed, so unchanged resubmissions can be skipped
+    if let Err(e) = job_status::record_success(container_name, blob_name, etag, &report.warnings).await {
+        warn!("Failed to record job status for {}: {:?}", blob_name, e);
+    }
+    telemetry::track_event("ImageResized", &[("filename", blob_name.to_string())]);
+
+    Ok(())
+}
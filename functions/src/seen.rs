@@ -0,0 +1,81 @@
+// functions/src/seen.rs
+
+//! Suppression of duplicate messages within `MESSAGE_DEDUP_WINDOW_SECS` (default 60, 0 turns it
+//! off). Messages are keyed by a hash of what they ask for, leaving out when they were queued, so a
+//! redelivered message and the same upload submitted twice look alike. This complements the queue's
+//! own duplicate detection, which only knows message ids, and only spans one worker process.
+
+use image_resize_core::pipeline;
+use std::{
+    collections::HashMap,
+    env,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::ImageMessage;
+
+const DEFAULT_WINDOW_SECS: u64 = 60;
+
+enum Entry {
+    InFlight,
+    Done(Instant),
+}
+
+pub struct SeenMessages {
+    window: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl SeenMessages {
+    pub fn from_env() -> Self {
+        let secs = env::var("MESSAGE_DEDUP_WINDOW_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_WINDOW_SECS);
+        SeenMessages {
+            window: Duration::from_secs(secs),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn key(image: &ImageMessage) -> String {
+        let mut value = serde_json::to_value(image).expect("Failed to serialize image");
+        if let Some(fields) = value.as_object_mut() {
+            fields.remove("queued_at");
+        }
+        pipeline::content_hash(value.to_string().as_bytes())
+    }
+
+    /// Claims `image` for processing, returning the key to [`finish`](Self::finish) it with, or
+    /// `None` if the same message is in flight or succeeded within the window.
+    pub fn claim(&self, image: &ImageMessage) -> Option<String> {
+        let key = Self::key(image);
+        if self.window.is_zero() {
+            return Some(key);
+        }
+        let mut entries = self.entries.lock().expect("Seen messages lock poisoned");
+        entries.retain(|_, entry| match entry {
+            Entry::InFlight => true,
+            Entry::Done(at) => at.elapsed() < self.window,
+        });
+        if entries.contains_key(&key) {
+            return None;
+        }
+        entries.insert(key.clone(), Entry::InFlight);
+        Some(key)
+    }
+
+    /// Starts the window of a succeeded message; a failed one is forgotten so its redelivery runs.
+    pub fn finish(&self, key: String, succeeded: bool) {
+        if self.window.is_zero() {
+            return;
+        }
+        let mut entries = self.entries.lock().expect("Seen messages lock poisoned");
+        if succeeded {
+            entries.insert(key, Entry::Done(Instant::now()));
+        } else {
+            entries.remove(&key);
+        }
+    }
+}
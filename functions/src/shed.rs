@@ -0,0 +1,79 @@
+// functions/src/shed.rs
+
+//! Load shedding by the cost of a job, to keep the median latency down while the worker is
+//! saturated. `SHED_MIN_PIXELS` turns it on: while every slot is processing a message, a message
+//! whose source has at least that many pixels (`pixels`, read from the header at upload) goes back
+//! to the queue to be received `SHED_DELAY_SECS` (default 30) later, freeing its slot for the
+//! cheaper jobs behind it. A message is put off at most `SHED_MAX_DEFERRALS` times (default 3), so
+//! a large image is delayed, never starved.
+//!
+//! Sources of unknown size, videos, PDFs and messages not sent by an upload, are never put off, and
+//! neither are `publish` stages, which only copy a rendition. A deferred job keeps its `queued_at`,
+//! so `JOB_MAX_AGE_SECS` should leave room for its delays.
+
+use image_resize_core::{queue::QueueSender, telemetry};
+use std::{env, time::Duration};
+use tracing::info;
+
+use crate::{ImageMessage, Stage};
+
+const DEFAULT_DELAY_SECS: u64 = 30;
+const DEFAULT_MAX_DEFERRALS: u32 = 3;
+
+pub struct LoadShedder {
+    min_pixels: u64,
+    delay: Duration,
+    max_deferrals: u32,
+}
+
+impl LoadShedder {
+    /// The configured shedder, `None` when `SHED_MIN_PIXELS` isn't set.
+    pub fn from_env() -> Option<Self> {
+        let min_pixels = env::var("SHED_MIN_PIXELS").ok()?.parse().ok()?;
+        let delay = env::var("SHED_DELAY_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_DELAY_SECS);
+        let max_deferrals = env::var("SHED_MAX_DEFERRALS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_MAX_DEFERRALS);
+        Some(LoadShedder {
+            min_pixels,
+            delay: Duration::from_secs(delay),
+            max_deferrals,
+        })
+    }
+
+    /// Whether `image` should be put off, `saturated` telling whether every slot is busy.
+    pub fn should_defer(&self, image: &ImageMessage, saturated: bool) -> bool {
+        saturated
+            && !matches!(image.stage, Stage::Publish { .. })
+            && image.deferrals < self.max_deferrals
+            && image.pixels.is_some_and(|pixels| pixels >= self.min_pixels)
+    }
+
+    /// Sends `image` back to the queue to be received after the delay. An error means it wasn't
+    /// sent, and the received message should be abandoned rather than completed.
+    pub async fn defer(&self, mut image: ImageMessage, sender: &QueueSender) -> azure_core::Result<()> {
+        image.deferrals += 1;
+        let sender = sender.for_image(&image);
+        let message = serde_json::to_string(&image).expect("Failed to serialize image");
+        let send = sender.send_after(&image, &message, self.delay);
+        telemetry::dependency("Azure Service Bus", sender.queue_name(), "send_message", send).await?;
+
+        info!(
+            "Saturated, deferring {:?} of {} ({} pixels) by {:?}, deferral {}",
+            image.stage,
+            image.filename,
+            image.pixels.unwrap_or_default(),
+            self.delay,
+            image.deferrals
+        );
+        telemetry::track_event(
+            "JobDeferred",
+            &[("filename", image.filename.clone()), ("deferrals", image.deferrals.to_string())],
+        );
+        Ok(())
+    }
+}
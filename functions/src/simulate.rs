@@ -0,0 +1,221 @@
+// functions/src/simulate.rs
+
+//! `handler simulate [--rate <jobs/s>] [--duration <secs>]`: runs the resize stage on synthetic
+//! jobs, without the queue, storage or the API, to size a deployment. Sources are made at startup
+//! from the [`FIXTURES`], photo-like noise over a gradient in a few sizes and formats. Jobs arrive
+//! at `--rate` per second (default 5) for `--duration` seconds (default 60), each with a random
+//! source and box, and up to `WORKER_CONCURRENCY` are processed at once, like the worker does:
+//! decode, scale and encode in the source's format, under the `ENCODE_CONCURRENCY` budgets.
+//!
+//! Once the last job is done it prints the throughput and the percentiles of each job's latency,
+//! from its arrival to its end, and of its processing time alone. A latency far above the
+//! processing time means jobs queued up, i.e. the rate is more than the worker keeps up with.
+
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+use image_resize_core::{
+    message::{ImageMessage, Stage},
+    output_format::OutputFormat,
+    resize_spec::{Filter, Fit},
+};
+use std::{
+    io::Cursor,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::Semaphore, task::JoinSet};
+
+use crate::{decode, quality, report::StageReport, resize};
+
+const DEFAULT_RATE: f64 = 5.0;
+const DEFAULT_DURATION_SECS: u64 = 60;
+
+/// Sources jobs are drawn from: width, height and format.
+const FIXTURES: &[(u32, u32, ImageFormat)] = &[
+    (640, 480, ImageFormat::Jpeg),
+    (1280, 720, ImageFormat::Png),
+    (1920, 1080, ImageFormat::Jpeg),
+    (1080, 1350, ImageFormat::WebP),
+    (3024, 4032, ImageFormat::Jpeg),
+    (4000, 3000, ImageFormat::Png),
+];
+
+/// Boxes jobs ask for.
+const TARGETS: &[(u32, u32)] = &[(100, 100), (400, 300), (1200, 900)];
+
+/// A xorshift generator; the simulation needs variety, not quality.
+struct Rng(u64);
+
+impl Rng {
+    fn seeded() -> Self {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or_default();
+        Rng(nanos | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn pick<T>(&mut self, items: &[T]) -> usize {
+        (self.next() % items.len() as u64) as usize
+    }
+}
+
+/// A fixture's pixels: a gradient with noise on it, so it compresses about like a photo.
+fn fixture(width: u32, height: u32, rng: &mut Rng) -> RgbImage {
+    RgbImage::from_fn(width, height, |x, y| {
+        let noise = (rng.next() % 48) as u32;
+        Rgb([
+            ((x * 200 / width) + noise).min(255) as u8,
+            ((y * 200 / height) + noise).min(255) as u8,
+            (((x + y) * 100 / (width + height)) + noise * 2).min(255) as u8,
+        ])
+    })
+}
+
+/// The encoded sources, with a name for the report.
+fn fixtures(rng: &mut Rng) -> Vec<(String, Vec<u8>)> {
+    FIXTURES
+        .iter()
+        .map(|&(width, height, format)| {
+            let mut bytes = Vec::new();
+            DynamicImage::ImageRgb8(fixture(width, height, rng))
+                .write_to(&mut Cursor::new(&mut bytes), format)
+                .expect("Failed to encode a fixture");
+            let name = format!("{}x{} {}", width, height, format.extensions_str()[0]);
+            (name, bytes)
+        })
+        .collect()
+}
+
+/// Runs one job on `source`, as the resize stage would.
+async fn process(source: &[u8], target: (u32, u32)) -> Result<(), String> {
+    let img = decode::load(source).map_err(|e| e.to_string())?;
+    let scaled = resize::scale(&img, None, target, false, Fit::default(), Filter::default(), None);
+    let image = ImageMessage::default();
+    let mut report = StageReport::new(&Stage::Resize);
+    let options = quality::JpegOptions::default();
+    quality::encode(&scaled, None, OutputFormat::of_source(source), &image, &options, &mut report)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// The `percent` percentile of the sorted `durations`, in milliseconds.
+fn percentile(durations: &[Duration], percent: f64) -> f64 {
+    if durations.is_empty() {
+        return 0.0;
+    }
+    let index = ((durations.len() - 1) as f64 * percent / 100.0).round() as usize;
+    durations[index].as_secs_f64() * 1000.0
+}
+
+fn print_row(label: &str, durations: &mut [Duration]) {
+    durations.sort();
+    println!(
+        "{:<20} {:>10.1} {:>10.1} {:>10.1} {:>10.1}",
+        label,
+        percentile(durations, 50.0),
+        percentile(durations, 90.0),
+        percentile(durations, 99.0),
+        percentile(durations, 100.0),
+    );
+}
+
+fn parse_args(args: &[String]) -> Result<(f64, Duration), String> {
+    let mut rate = DEFAULT_RATE;
+    let mut duration = Duration::from_secs(DEFAULT_DURATION_SECS);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = args.next().ok_or_else(|| format!("{} needs a value", arg))?;
+        match arg.as_str() {
+            "--rate" => rate = value.parse().ok().filter(|rate: &f64| *rate > 0.0).ok_or("--rate must be positive")?,
+            "--duration" => duration = Duration::from_secs(value.parse().map_err(|_| "--duration must be whole seconds")?),
+            _ => return Err(format!("Unknown option {}", arg)),
+        }
+    }
+    Ok((rate, duration))
+}
+
+pub async fn run(args: &[String]) {
+    let (rate, duration) = match parse_args(args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("Usage: handler simulate [--rate <jobs/s>] [--duration <secs>]");
+            return;
+        }
+    };
+    let concurrency = crate::worker_concurrency();
+    crate::budget::init();
+    let mut rng = Rng::seeded();
+    let fixtures = Arc::new(fixtures(&mut rng));
+    println!(
+        "Simulating {} jobs/s for {}s, {} at once, from {} fixtures",
+        rate,
+        duration.as_secs(),
+        concurrency,
+        fixtures.len()
+    );
+
+    let slots = Arc::new(Semaphore::new(concurrency));
+    let mut jobs = JoinSet::new();
+    let started = Instant::now();
+    let mut arrivals = tokio::time::interval(Duration::from_secs_f64(1.0 / rate));
+    while started.elapsed() < duration {
+        arrivals.tick().await;
+        let fixture = rng.pick(&fixtures);
+        let target = TARGETS[rng.pick(TARGETS)];
+        let (fixtures, slots) = (fixtures.clone(), slots.clone());
+        jobs.spawn(async move {
+            let arrived = Instant::now();
+            let _slot = slots.acquire_owned().await.expect("The semaphore is never closed");
+            let processing = Instant::now();
+            let result = process(&fixtures[fixture].1, target).await;
+            (fixture, result, arrived.elapsed(), processing.elapsed())
+        });
+    }
+    let generated = jobs.len();
+
+    let mut latencies = Vec::new();
+    let mut processing = Vec::new();
+    let mut by_fixture = vec![Vec::new(); fixtures.len()];
+    let mut failed = 0;
+    while let Some(finished) = jobs.join_next().await {
+        match finished {
+            Ok((fixture, Ok(()), latency, took)) => {
+                latencies.push(latency);
+                processing.push(took);
+                by_fixture[fixture].push(took);
+            }
+            Ok((fixture, Err(e), _, _)) => {
+                eprintln!("A job on {} failed: {}", fixtures[fixture].0, e);
+                failed += 1;
+            }
+            Err(e) => {
+                eprintln!("A job panicked: {:?}", e);
+                failed += 1;
+            }
+        }
+    }
+    let elapsed = started.elapsed();
+
+    println!(
+        "{} jobs generated, {} completed, {} failed in {:.1}s: {:.2} jobs/s",
+        generated,
+        latencies.len(),
+        failed,
+        elapsed.as_secs_f64(),
+        latencies.len() as f64 / elapsed.as_secs_f64()
+    );
+    println!("{:<20} {:>10} {:>10} {:>10} {:>10}", "ms", "p50", "p90", "p99", "max");
+    print_row("latency", &mut latencies);
+    print_row("processing", &mut processing);
+    for ((name, _), durations) in fixtures.iter().zip(&mut by_fixture) {
+        if !durations.is_empty() {
+            print_row(name, durations);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+// functions/src/staging.rs
+
+//! All-or-nothing publishing of rendition sets. A stage making several renditions writes each under
+//! `staging/<filename>/<run>/` (`pipeline::STAGING_PREFIX`), tagged `status=staged`, and only once
+//! all of them succeeded copies them to their names and writes the set's manifest, which readers
+//! take as the set being complete. A failed run deletes what it staged, so consumers never see part
+//! of a set; staged blobs a crashed worker leaves behind can be expired by a lifecycle rule on the
+//! prefix.
+
+use azure_core::request_options::Metadata;
+use azure_storage_blobs::prelude::ContainerClient;
+use image_resize_core::{blob_tags, output_format::OutputFormat, pipeline, telemetry};
+use time::OffsetDateTime;
+use tracing::{info, warn};
+
+use crate::{dedup, output_tags, ImageMessage};
+
+struct Rendition {
+    name: String,
+    staged: String,
+    content_hash: String,
+    metadata: Metadata,
+    preset: String,
+}
+
+pub struct Staging<'a> {
+    image: &'a ImageMessage,
+    container_client: ContainerClient,
+    prefix: String,
+    renditions: Vec<Rendition>,
+}
+
+impl<'a> Staging<'a> {
+    pub fn new(image: &'a ImageMessage, container_client: &ContainerClient) -> Self {
+        let run = OffsetDateTime::now_utc().unix_timestamp_nanos();
+        Staging {
+            image,
+            container_client: container_client.clone(),
+            prefix: format!("{}{}/{}/", pipeline::STAGING_PREFIX, image.filename, run),
+            renditions: Vec::new(),
+        }
+    }
+
+    /// Writes a rendition under the staging prefix, to be published as `name`.
+    pub async fn put(
+        &mut self,
+        name: &str,
+        bytes: Vec<u8>,
+        content_hash: &str,
+        metadata: Metadata,
+        preset: &str,
+    ) -> azure_core::Result<()> {
+        let staged = format!("{}{}", self.prefix, name);
+        let content_type = OutputFormat::content_type_of(&bytes);
+        let upload = self
+            .container_client
+            .blob_client(&staged)
+            .put_block_blob(bytes)
+            .content_type(content_type)
+            .metadata(metadata.clone())
+            .tags(blob_tags::tags(self.image.tenant.as_deref(), preset, blob_tags::STAGED))
+            .into_future();
+        telemetry::dependency("Azure blob", self.container_client.container_name(), "put_block_blob", upload).await?;
+        self.renditions.push(Rendition {
+            name: name.to_string(),
+            staged,
+            content_hash: content_hash.to_string(),
+            metadata,
+            preset: preset.to_string(),
+        });
+        Ok(())
+    }
+
+    /// Copies every staged rendition to its name, returning for each, in the order they were
+    /// staged, the blob it was copied from when `dedup.rs` found identical content.
+    pub async fn publish(self) -> azure_core::Result<Vec<Option<String>>> {
+        let mut copied_from = Vec::new();
+        let mut result = Ok(());
+        for rendition in &self.renditions {
+            let stored = dedup::store_staged(
+                &self.container_client.blob_client(&rendition.name),
+                &self.container_client.blob_client(&rendition.staged),
+                &rendition.content_hash,
+                rendition.metadata.clone(),
+                output_tags(self.image, &rendition.preset),
+                self.image.tenant.as_deref(),
+            )
+            .await;
+            match stored {
+                Ok(source) => copied_from.push(source),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        self.discard().await;
+        result?;
+        info!("Published {} staged renditions of {}", copied_from.len(), self.image.filename);
+        Ok(copied_from)
+    }
+
+    /// Deletes whatever was staged; failures are only logged, the blobs never being read.
+    pub async fn discard(&self) {
+        for rendition in &self.renditions {
+            if let Err(e) = self.container_client.blob_client(&rendition.staged).delete().await {
+                warn!("Failed to delete staged {}: {:?}", rendition.staged, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,78 @@
+// functions/src/temp.rs
+
+//! Temporary files handed to external tools such as ffmpeg and pdftoppm. They live in an
+//! `image-resize-worker` directory under the system's temporary directory, named after the process
+//! that wrote them, and are removed by a guard once the job is done with them however it ends: an
+//! error, a panic or a dropped future all run the guard. Whatever a killed worker left behind is
+//! removed by [`sweep`] when the next one starts.
+
+use scopeguard::ScopeGuard;
+use std::{
+    env, fs, io,
+    path::PathBuf,
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use tracing::{info, warn};
+
+const DEFAULT_SWEEP_MIN_AGE_SECS: u64 = 3600;
+
+/// A temporary file or directory, removed when dropped.
+pub type TempPath = ScopeGuard<PathBuf, fn(PathBuf)>;
+
+fn dir() -> PathBuf {
+    env::temp_dir().join("image-resize-worker")
+}
+
+fn remove(path: PathBuf) {
+    let removed = if path.is_dir() {
+        fs::remove_dir_all(&path)
+    } else {
+        fs::remove_file(&path)
+    };
+    match removed {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => warn!("Failed to remove {}: {:?}", path.display(), e),
+        _ => {}
+    }
+}
+
+/// A path unique within the process for the caller to create a file or directory at, removed
+/// along with whatever is in it when the guard is dropped.
+pub fn path(name: &str) -> TempPath {
+    static NEXT_FILE: AtomicU64 = AtomicU64::new(0);
+    let number = NEXT_FILE.fetch_add(1, Ordering::Relaxed);
+    let dir = dir();
+    if let Err(e) = fs::create_dir_all(&dir) {
+        warn!("Failed to create {}: {:?}", dir.display(), e);
+    }
+    let path = dir.join(format!("{}-{}-{}", process::id(), number, name));
+    scopeguard::guard(path, remove as fn(PathBuf))
+}
+
+/// Removes temporary files of other processes untouched for `TEMP_SWEEP_MIN_AGE_SECS` (default
+/// 3600), long past any tool's timeout, so those of workers still running on the host are kept.
+pub fn sweep() {
+    let Ok(entries) = fs::read_dir(dir()) else {
+        return;
+    };
+    let min_age = env::var("TEMP_SWEEP_MIN_AGE_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map_or(Duration::from_secs(DEFAULT_SWEEP_MIN_AGE_SECS), Duration::from_secs);
+    let own = format!("{}-", process::id());
+    let mut swept = 0;
+    for entry in entries.flatten() {
+        if entry.file_name().to_string_lossy().starts_with(&own) {
+            continue;
+        }
+        let age = entry.metadata().and_then(|m| m.modified()).ok().and_then(|modified| modified.elapsed().ok());
+        if age.is_some_and(|age| age >= min_age) {
+            remove(entry.path());
+            swept += 1;
+        }
+    }
+    if swept > 0 {
+        info!("Swept {} orphaned temporary files", swept);
+    }
+}
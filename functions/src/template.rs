@@ -0,0 +1,264 @@
+// functions/src/template.rs
+
+use ab_glyph::FontVec;
+use azure_storage_blobs::prelude::{BlobServiceClient, ContainerClient};
+use futures::StreamExt;
+use image::{DynamicImage, Rgba};
+use image_resize_core::{
+    blob_tags,
+    output_format::OutputFormat,
+    pipeline,
+    resize_spec::{Filter, Fit, ResizeSpec},
+    tiers,
+};
+use serde::Deserialize;
+use tracing::info;
+
+use crate::{
+    cancel, decode, dedup,
+    error::StageError,
+    detail::{self, Denoise, Sharpen},
+    enhance,
+    overlay::{self, Position},
+    output_tags, plugin,
+    provenance::{self, Source},
+    read_blob, read_original, rendition_metadata,
+    quality::{self, JpegOptions},
+    report::{BlobReport, StageReport},
+    resize,
+    transformer::{self, TransformerSpec},
+    ImageMessage,
+};
+
+/// Templates live as JSON blobs under this prefix in the image's container.
+const TEMPLATE_PREFIX: &str = "templates/";
+
+#[derive(Deserialize, Debug)]
+struct Template {
+    #[serde(default = "default_size")]
+    width: u32,
+    #[serde(default = "default_size")]
+    height: u32,
+    #[serde(default)]
+    auto_enhance: bool,
+    /// Swap `width` and `height` for sources of the other orientation.
+    #[serde(default)]
+    match_orientation: bool,
+    #[serde(default)]
+    fit: Fit,
+    #[serde(default)]
+    filter: Filter,
+    /// Sizes the output instead of `width` and `height`.
+    resize: Option<ResizeSpec>,
+    /// WASM modules run before scaling, see `plugin.rs`.
+    #[serde(default)]
+    plugins: Vec<String>,
+    /// External HTTP transform run after the plugins, see `transformer.rs`.
+    transformer: Option<TransformerSpec>,
+    /// Seconds into a video source to take its poster frame from, see `video.rs`.
+    #[serde(default)]
+    poster_at: f64,
+    /// Filters run after scaling, see `detail.rs`.
+    sharpen: Option<Sharpen>,
+    denoise: Option<Denoise>,
+    #[serde(default)]
+    jpeg: JpegOptions,
+    watermark: Option<WatermarkSpec>,
+    text: Option<TextSpec>,
+}
+
+fn default_size() -> u32 {
+    100
+}
+
+#[derive(Deserialize, Debug)]
+struct WatermarkSpec {
+    /// Blob name of the watermark image, relative to the image's container.
+    blob: String,
+    #[serde(default)]
+    position: Position,
+    /// Watermark width as a fraction of the output width.
+    #[serde(default = "default_scale")]
+    scale: f32,
+    #[serde(default = "default_opacity")]
+    opacity: f32,
+}
+
+fn default_scale() -> f32 {
+    0.25
+}
+
+fn default_opacity() -> f32 {
+    0.8
+}
+
+#[derive(Deserialize, Debug)]
+struct TextSpec {
+    content: String,
+    /// Blob name of a TTF/OTF font.
+    font_blob: String,
+    #[serde(default = "default_font_size")]
+    size: f32,
+    /// RGBA color of the text.
+    #[serde(default = "default_color")]
+    color: [u8; 4],
+    #[serde(default)]
+    position: Position,
+}
+
+fn default_font_size() -> f32 {
+    16.0
+}
+
+fn default_color() -> [u8; 4] {
+    [255, 255, 255, 255]
+}
+
+/// The named template and its raw definition.
+async fn load(template_name: &str, container_client: &ContainerClient) -> azure_core::Result<(Template, Vec<u8>)> {
+    let template_bytes = read_blob(&container_client.blob_client(format!("{}{}.json", TEMPLATE_PREFIX, template_name))).await?;
+    let template = serde_json::from_slice(&template_bytes).map_err(|source| StageError::Invalid {
+        what: format!("template {}", template_name),
+        source,
+    })?;
+    Ok((template, template_bytes))
+}
+
+async fn load_watermark(spec: &WatermarkSpec, container_client: &ContainerClient) -> azure_core::Result<DynamicImage> {
+    let watermark_bytes = read_blob(&container_client.blob_client(&spec.blob)).await?;
+    let watermark = image::load_from_memory(&watermark_bytes).map_err(|source| StageError::Decode {
+        what: format!("watermark {}", spec.blob),
+        source,
+    })?;
+    Ok(watermark)
+}
+
+async fn load_font(spec: &TextSpec, container_client: &ContainerClient) -> azure_core::Result<FontVec> {
+    let font_bytes = read_blob(&container_client.blob_client(&spec.font_blob)).await?;
+    let font = FontVec::try_from_vec(font_bytes).map_err(|e| StageError::Font {
+        blob: spec.font_blob.clone(),
+        reason: e.to_string(),
+    })?;
+    Ok(font)
+}
+
+/// The names of the templates in the container.
+pub async fn names(container_client: &ContainerClient) -> azure_core::Result<Vec<String>> {
+    let mut names = Vec::new();
+    let mut pages = container_client.list_blobs().prefix(TEMPLATE_PREFIX).into_stream();
+    while let Some(page) = pages.next().await {
+        names.extend(
+            page?
+                .blobs
+                .blobs()
+                .filter_map(|blob| blob.name.strip_prefix(TEMPLATE_PREFIX)?.strip_suffix(".json").map(str::to_string)),
+        );
+    }
+    Ok(names)
+}
+
+/// Loads the named template with the watermark, font and plugins it uses, as rendering would,
+/// without rendering anything.
+pub async fn check(template_name: &str, container_client: &ContainerClient) -> azure_core::Result<()> {
+    let (template, _) = load(template_name, container_client).await?;
+    if let Some(spec) = &template.watermark {
+        load_watermark(spec, container_client).await?;
+    }
+    if let Some(spec) = &template.text {
+        load_font(spec, container_client).await?;
+    }
+    plugin::check(&template.plugins, container_client).await
+}
+
+/// Renders `image` through the named template and stores it as `<template>_<filename>`.
+pub async fn render_template(
+    image: &ImageMessage,
+    template_name: &str,
+    service_client: &BlobServiceClient,
+    report: &mut StageReport,
+) -> azure_core::Result<()> {
+    let container_client = service_client.container_client(&image.image_container);
+    let (template, template_bytes) = load(template_name, &container_client).await?;
+
+    let (bytes, etag) = read_original(image, &container_client.blob_client(&image.filename)).await?;
+    let source = Source { etag: &etag, definition: &template_bytes };
+    let img = decode::load_source(&bytes, template.poster_at, image.tenant.as_deref()).await?;
+    report.input = Some(BlobReport {
+        container: image.image_container.clone(),
+        blob: image.filename.clone(),
+        bytes: Some(bytes.len() as u64),
+        width: Some(img.width()),
+        height: Some(img.height()),
+        format: Some(decode::source_format(&bytes)),
+        encoder: None,
+        sha256: None,
+        copied_from: None,
+    });
+
+    let img = resize::crop(img, image, report);
+    let img = if template.auto_enhance {
+        enhance::auto_enhance(&img)
+    } else {
+        img
+    };
+    let img = plugin::apply(img, &template.plugins, &container_client, report).await?;
+    let img = transformer::apply(img, template.transformer.as_ref(), report).await;
+    let scaled = resize::scale(
+        &img,
+        template.resize,
+        (template.width, template.height),
+        template.match_orientation,
+        template.fit,
+        template.filter,
+        image.focal_point,
+    );
+    let mut canvas = detail::apply(scaled, template.sharpen.as_ref(), template.denoise.as_ref()).to_rgba8();
+
+    if let Some(spec) = &template.watermark {
+        let watermark = load_watermark(spec, &container_client).await?;
+        overlay::apply_watermark(&mut canvas, &watermark, spec.position, spec.scale, spec.opacity);
+    }
+
+    if let Some(spec) = &template.text {
+        let font = load_font(spec, &container_client).await?;
+        overlay::draw_text(&mut canvas, &font, &spec.content, spec.size, Rgba(spec.color), spec.position);
+    }
+
+    let (width, height) = canvas.dimensions();
+    let profile = report.check_conversion(&bytes, (img.width(), img.height()), (width, height));
+    let output_format = image.output_format.unwrap_or_else(|| tiers::output_format(image, OutputFormat::of_source(&bytes)));
+    let rendered = DynamicImage::ImageRgba8(canvas);
+    let (rendered_bytes, encoder) =
+        quality::encode(&rendered, profile.as_ref(), output_format, image, &template.jpeg, report).await?;
+    let content_hash = pipeline::content_hash(&rendered_bytes);
+
+    let rendered_name = format!("{}_{}", template_name, image.filename);
+    let preset = blob_tags::render_preset(template_name);
+    let rendered_size = rendered_bytes.len() as u64;
+    cancel::checkpoint(image).await?;
+    let copied_from = dedup::store(
+        &container_client.blob_client(&rendered_name),
+        rendered_bytes,
+        &content_hash,
+        rendition_metadata(image, &preset, &source, &content_hash),
+        output_tags(image, &preset),
+        image.tenant.as_deref(),
+    )
+    .await?;
+    provenance::record(image, &source, &image.image_container, &rendered_name, &preset, None).await;
+    report.outputs.push(BlobReport {
+        container: image.image_container.clone(),
+        blob: rendered_name.clone(),
+        bytes: Some(rendered_size),
+        width: Some(width),
+        height: Some(height),
+        format: None,
+        encoder: Some(encoder),
+        sha256: Some(content_hash),
+        copied_from,
+    });
+
+    info!("Rendered {} with template {}", rendered_name, template_name);
+
+    Ok(())
+}
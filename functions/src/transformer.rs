@@ -0,0 +1,92 @@
+// functions/src/transformer.rs
+
+//! Transforms hosted outside the worker, e.g. ML models behind an HTTP endpoint. A template or
+//! `presets/resize.json` with a `transformer` section has the image POSTed there as a PNG after
+//! plugins have run, and goes on with the image in the response body, in any format the worker
+//! decodes. Requests time out after the section's `timeout_secs`, and neither the request nor the
+//! response may exceed `TRANSFORMER_MAX_BYTES`; a transformer that fails leaves the image as it
+//! was, with a `transformer_failed` warning.
+
+use std::{env, io::Cursor, sync::OnceLock, time::Duration};
+
+use image::{DynamicImage, ImageFormat};
+use image_resize_core::warnings;
+use serde::Deserialize;
+use tracing::info;
+
+use crate::{decode, report::StageReport};
+
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_MAX_BYTES: usize = 50 * 1024 * 1024;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TransformerSpec {
+    url: String,
+    #[serde(default = "default_timeout_secs")]
+    timeout_secs: u64,
+}
+
+fn default_timeout_secs() -> u64 {
+    DEFAULT_TIMEOUT_SECS
+}
+
+fn max_bytes() -> usize {
+    env::var("TRANSFORMER_MAX_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_BYTES)
+}
+
+fn client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(reqwest::Client::new)
+}
+
+/// Posts `img` to the transformer and decodes what it sends back.
+async fn call(spec: &TransformerSpec, img: &DynamicImage) -> Result<DynamicImage, String> {
+    let limit = max_bytes();
+    let mut body = Vec::new();
+    img.write_to(&mut Cursor::new(&mut body), ImageFormat::Png)
+        .map_err(|e| format!("Failed to encode the image: {}", e))?;
+    if body.len() > limit {
+        return Err(format!("The image is {} bytes, above the limit of {}", body.len(), limit));
+    }
+
+    let mut response = client()
+        .post(&spec.url)
+        .header(reqwest::header::CONTENT_TYPE, "image/png")
+        .timeout(Duration::from_secs(spec.timeout_secs))
+        .body(body)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?;
+    if response.content_length().is_some_and(|length| length as usize > limit) {
+        return Err(format!("The response is above the limit of {} bytes", limit));
+    }
+    let mut bytes = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+        if bytes.len() + chunk.len() > limit {
+            return Err(format!("The response is above the limit of {} bytes", limit));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    decode::load(&bytes).map_err(|e| format!("Failed to decode the response: {}", e))
+}
+
+/// Runs `img` through the transformer, if there is one.
+pub async fn apply(img: DynamicImage, spec: Option<&TransformerSpec>, report: &mut StageReport) -> DynamicImage {
+    let Some(spec) = spec else {
+        return img;
+    };
+    match call(spec, &img).await {
+        Ok(transformed) => {
+            info!("Applied transformer {}", spec.url);
+            transformed
+        }
+        Err(e) => {
+            report.warn(warnings::TRANSFORMER_FAILED, format!("Transformer {} failed: {}", spec.url, e));
+            img
+        }
+    }
+}
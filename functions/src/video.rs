@@ -0,0 +1,52 @@
+// functions/src/video.rs
+
+//! Poster frames of video sources, taken with ffmpeg (`FFMPEG_PATH`, default `ffmpeg` on the
+//! `PATH`) at the preset's `poster_at` seconds and then processed like any uploaded image. Only
+//! done with the `video_poster` feature on, the worker host needing ffmpeg installed; the video is
+//! written to a temporary file first since MP4s can't be read from a pipe, see `temp.rs`, and
+//! ffmpeg is killed after `FFMPEG_TIMEOUT_SECS` (default 60).
+
+use std::{env, process::Stdio, time::Duration};
+
+use image_resize_core::video::VideoFormat;
+use tokio::process::Command;
+
+use crate::temp;
+
+const DEFAULT_TIMEOUT_SECS: u64 = 60;
+
+fn timeout() -> Duration {
+    let secs = env::var("FFMPEG_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_TIMEOUT_SECS);
+    Duration::from_secs(secs)
+}
+
+/// The frame of the video in `bytes` at `at` seconds, as a PNG.
+pub async fn poster_frame(bytes: &[u8], format: VideoFormat, at: f64) -> Result<Vec<u8>, String> {
+    let path = temp::path(&format!("poster.{}", format.extension()));
+    tokio::fs::write(&*path, bytes)
+        .await
+        .map_err(|e| format!("Failed to write the video to {}: {}", path.display(), e))?;
+
+    let ffmpeg = env::var("FFMPEG_PATH").unwrap_or_else(|_| "ffmpeg".to_string());
+    let output = Command::new(&ffmpeg)
+        .args(["-nostdin", "-v", "error", "-ss", &at.to_string(), "-i"])
+        .arg(&*path)
+        .args(["-frames:v", "1", "-f", "image2pipe", "-c:v", "png", "pipe:1"])
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(timeout(), output)
+        .await
+        .map_err(|_| format!("ffmpeg took longer than {:?}", timeout()))?
+        .map_err(|e| format!("Failed to run {}: {}", ffmpeg, e))?;
+    if !output.status.success() {
+        return Err(format!("ffmpeg failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    if output.stdout.is_empty() {
+        return Err(format!("The video has no frame at {}s", at));
+    }
+    Ok(output.stdout)
+}